@@ -0,0 +1,326 @@
+use actix_web::{delete, get, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::{AuditLogEntry, User};
+use crate::error::{AppError, AppResult, ErrorResponse};
+
+use super::auth::{erase_user, extract_user_from_request};
+use super::pagination::{PageQuery, Paginated};
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AuditLogResponse {
+    pub id: Uuid,
+    /// null si l'usuari s'ha esborrat
+    pub user_id: Option<Uuid>,
+    /// Clau d'API amb què s'ha fet la crida (null si s'ha fet amb el JWT)
+    pub api_key_id: Option<Uuid>,
+    pub method: String,
+    pub path: String,
+    /// Cos de la petició amb els secrets ocults. Si no era JSON o superava 8 KB només hi ha
+    /// `{"_not_json": true}` o `{"_truncated": true}`.
+    #[schema(value_type = Option<Object>)]
+    pub request_body: Option<serde_json::Value>,
+    pub response_status: i16,
+    pub duration_ms: i32,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<AuditLogEntry> for AuditLogResponse {
+    fn from(e: AuditLogEntry) -> Self {
+        Self {
+            id: e.id,
+            user_id: e.user_id,
+            api_key_id: e.api_key_id,
+            method: e.method,
+            path: e.path,
+            request_body: e.request_body,
+            response_status: e.response_status,
+            duration_ms: e.duration_ms,
+            created_at: e.created_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct AdminUserResponse {
+    pub id: Uuid,
+    pub email: String,
+    pub name: Option<String>,
+    pub is_admin: bool,
+    /// Dispositius actius (sense comptar els esborrats)
+    pub device_count: i64,
+    /// Regles actives dels seus dispositius i grups
+    pub enabled_rule_count: i64,
+    pub created_at: DateTime<Utc>,
+    /// Última vegada que ha iniciat sessió o actualitzat el perfil
+    pub updated_at: DateTime<Utc>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_audit_log)
+        .service(list_users)
+        .service(delete_user)
+        .service(purge_device);
+}
+
+/// Retorna l'usuari autenticat si és administrador
+async fn require_admin(req: &HttpRequest, pool: &PgPool, config: &Config) -> AppResult<User> {
+    let user = extract_user_from_request(req, pool, &config.jwt_secret).await?;
+    if !user.is_admin {
+        return Err(AppError::Forbidden("ADMIN_REQUIRED", "Admin access required".to_string()));
+    }
+    Ok(user)
+}
+
+/// GET /api/admin/audit-log
+/// Crides que han modificat dades, de la més recent a la més antiga
+#[utoipa::path(
+    tag = "admin",
+    params(PageQuery),
+    responses(
+        (status = 200, description = "Registre d'auditoria", body = Paginated<AuditLogResponse>),
+        (status = 400, description = "Paràmetres de paginació invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 403, description = "L'usuari no és administrador", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/admin/audit-log")]
+async fn list_audit_log(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    page: web::Query<PageQuery>,
+) -> AppResult<HttpResponse> {
+    require_admin(&req, &pool, &config).await?;
+    let page = page.page()?;
+
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_log")
+        .fetch_one(pool.get_ref())
+        .await?;
+
+    let entries = sqlx::query_as::<_, AuditLogEntry>(
+        "SELECT * FROM audit_log ORDER BY created_at DESC, id LIMIT $1 OFFSET $2"
+    )
+    .bind(page.limit)
+    .bind(page.offset)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let items: Vec<AuditLogResponse> = entries.into_iter().map(Into::into).collect();
+    Ok(HttpResponse::Ok().json(Paginated::new(items, total, page)))
+}
+
+/// GET /api/admin/users
+/// Usuaris de la instància, dels més nous als més antics
+#[utoipa::path(
+    tag = "admin",
+    params(PageQuery),
+    responses(
+        (status = 200, description = "Usuaris registrats", body = Paginated<AdminUserResponse>),
+        (status = 400, description = "Paràmetres de paginació invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 403, description = "L'usuari no és administrador", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/admin/users")]
+async fn list_users(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    page: web::Query<PageQuery>,
+) -> AppResult<HttpResponse> {
+    require_admin(&req, &pool, &config).await?;
+    let page = page.page()?;
+
+    let total: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(pool.get_ref())
+        .await?;
+
+    let users = sqlx::query_as::<_, AdminUserResponse>(
+        r#"
+        SELECT
+            u.id, u.email, u.name, u.is_admin, u.created_at, u.updated_at,
+            (SELECT COUNT(*) FROM devices d WHERE d.user_id = u.id AND d.deleted_at IS NULL) as device_count,
+            (
+                SELECT COUNT(*)
+                FROM rules r
+                LEFT JOIN devices d ON r.device_id = d.id
+                LEFT JOIN device_groups g ON r.device_group_id = g.id
+                WHERE COALESCE(d.user_id, g.user_id) = u.id AND r.is_enabled
+            ) as enabled_rule_count
+        FROM users u
+        ORDER BY u.created_at DESC, u.id
+        LIMIT $1 OFFSET $2
+        "#
+    )
+    .bind(page.limit)
+    .bind(page.offset)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(Paginated::new(users, total, page)))
+}
+
+/// DELETE /api/admin/users/{id}
+/// Esborra un usuari amb tots els seus dispositius, regles i accions, i les seves files de l'audit_log
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Id de l'usuari")),
+    responses(
+        (status = 204, description = "Usuari eliminat"),
+        (status = 400, description = "Un administrador no es pot esborrar a si mateix", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 403, description = "L'usuari no és administrador", body = ErrorResponse),
+        (status = 404, description = "Usuari no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[delete("/admin/users/{id}")]
+async fn delete_user(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let admin = require_admin(&req, &pool, &config).await?;
+    let user_id = path.into_inner();
+
+    // Així sempre queda com a mínim un administrador
+    if user_id == admin.id {
+        return Err(AppError::BadRequest("CANNOT_DELETE_SELF", "Admins cannot delete themselves".to_string()));
+    }
+
+    // Les mateixes dades que quan l'usuari esborra el compte, també l'audit_log
+    if !erase_user(&pool, user_id).await? {
+        return Err(AppError::NotFound("USER_NOT_FOUND", "User not found".to_string()));
+    }
+
+    tracing::info!("Usuari {} eliminat per l'administrador {}", user_id, admin.id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// DELETE /api/admin/devices/{id}
+/// Esborra definitivament un dispositiu (de qualsevol usuari) amb les seves regles i accions
+#[utoipa::path(
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Id del dispositiu")),
+    responses(
+        (status = 204, description = "Dispositiu eliminat definitivament"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 403, description = "L'usuari no és administrador", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[delete("/admin/devices/{id}")]
+async fn purge_device(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let admin = require_admin(&req, &pool, &config).await?;
+    let device_id = path.into_inner();
+
+    let result = sqlx::query("DELETE FROM devices WHERE id = $1")
+        .bind(device_id)
+        .execute(pool.get_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()));
+    }
+
+    tracing::info!("Dispositiu {} eliminat definitivament per l'administrador {}", device_id, admin.id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+
+    use crate::test_utils::{auth_header, create_device, create_user, test_config};
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_admin_users_require_admin(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "user").await;
+        let admin = create_user(&pool, "admin").await;
+        sqlx::query("UPDATE users SET is_admin = true WHERE id = $1")
+            .bind(admin.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        create_device(&pool, user.id, "Termo").await;
+        sqlx::query(
+            r#"
+            INSERT INTO audit_log (user_id, method, path, request_body, response_status, duration_ms)
+            VALUES ($1, 'PATCH', '/api/auth/me', '{"name": "Termo del pis"}', 200, 5)
+            "#
+        )
+        .bind(user.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(configure),
+        )
+        .await;
+
+        // Sense token, i amb un usuari normal
+        let resp = call_service(&app, TestRequest::get().uri("/admin/users").to_request()).await;
+        assert_eq!(resp.status(), 401);
+        let req = TestRequest::get()
+            .uri("/admin/users")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 403);
+        let req = TestRequest::delete()
+            .uri(&format!("/admin/users/{}", admin.id))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 403);
+
+        let req = TestRequest::get()
+            .uri("/admin/users")
+            .insert_header(auth_header(&admin, &config))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["total"], 2);
+        let listed = body["items"].as_array().unwrap().iter().find(|u| u["id"] == user.id.to_string()).unwrap();
+        assert_eq!(listed["device_count"], 1);
+        assert_eq!(listed["is_admin"], false);
+
+        let delete = |id: Uuid| {
+            TestRequest::delete()
+                .uri(&format!("/admin/users/{}", id))
+                .insert_header(auth_header(&admin, &config))
+                .to_request()
+        };
+        assert_eq!(call_service(&app, delete(admin.id)).await.status(), 400);
+        assert_eq!(call_service(&app, delete(user.id)).await.status(), 204);
+        assert_eq!(call_service(&app, delete(user.id)).await.status(), 404);
+
+        let devices: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM devices").fetch_one(&pool).await.unwrap();
+        assert_eq!(devices, 0);
+        // Com quan l'usuari esborra el compte: no en queda res a l'audit_log
+        let audit: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_log").fetch_one(&pool).await.unwrap();
+        assert_eq!(audit, 0);
+    }
+}
@@ -0,0 +1,318 @@
+//! Claus d'API per fer servir l'API des de scripts i tasques programades
+//!
+//! Una clau s'envia a la capçalera `X-API-Key` en lloc del JWT i autentica com l'usuari
+//! que l'ha creada. Es retorna en clar només quan es crea; a la BD se'n desa el hash
+//! SHA-256, com amb els refresh tokens: són 256 bits aleatoris, i un hash lent no hi
+//! afegiria res i en canvi s'hauria de calcular a cada petició.
+//!
+//! Les claus es gestionen només amb el JWT: una clau filtrada no pot crear-ne de noves
+//! ni revocar les altres claus de l'usuari.
+
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::User;
+use crate::error::{AppError, AppResult, ErrorResponse, FieldError};
+use crate::middleware::rate_limit::{rate_limited, RateKey, RateLimiter};
+
+use super::auth::extract_jwt_user_from_request;
+use super::validation::{FieldErrors, Validate, Validated};
+
+/// Capçalera amb la clau d'API
+pub const API_KEY_HEADER: &str = "X-API-Key";
+
+/// Prefix de les claus, perquè es reconeguin si acaben en un lloc on no toca
+const KEY_PREFIX: &str = "pvpc_";
+
+/// Longitud màxima del nom d'una clau
+const MAX_NAME_LEN: usize = 100;
+
+/// Peticions per clau d'API i minut
+static API_KEY_LIMITER: LazyLock<RateLimiter> = LazyLock::new(|| RateLimiter::new(120, Duration::from_secs(60)));
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateApiKeyRequest {
+    /// Per reconèixer la clau a la llista (p. ex. "cron del NAS")
+    pub name: String,
+}
+
+impl Validate for CreateApiKeyRequest {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        errors.check(validate_name(&self.name));
+        errors.into_result()
+    }
+}
+
+/// Clau d'API, sense el secret
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ApiKeyResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CreatedApiKeyResponse {
+    pub id: Uuid,
+    pub name: String,
+    /// Clau en clar per la capçalera `X-API-Key`. No es torna a mostrar mai més.
+    pub key: String,
+    pub created_at: DateTime<Utc>,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_api_key)
+        .service(list_api_keys)
+        .service(delete_api_key);
+}
+
+/// POST /api/auth/api-keys
+#[utoipa::path(
+    tag = "auth",
+    request_body = CreateApiKeyRequest,
+    responses(
+        (status = 201, description = "Clau creada (en clar només es retorna ara)", body = CreatedApiKeyResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 403, description = "Amb una clau d'API no es poden gestionar les claus", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/auth/api-keys")]
+async fn create_api_key(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: Validated<CreateApiKeyRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_jwt_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let key = format!("{}{}{}", KEY_PREFIX, Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    let created = sqlx::query!(
+        "INSERT INTO api_keys (user_id, key_hash, name) VALUES ($1, $2, $3) RETURNING id, name, created_at",
+        user.id,
+        hash_api_key(&key),
+        body.name.trim()
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    tracing::info!("Clau d'API {} creada per l'usuari {}", created.id, user.id);
+
+    Ok(HttpResponse::Created().json(CreatedApiKeyResponse {
+        id: created.id,
+        name: created.name,
+        key,
+        created_at: created.created_at,
+    }))
+}
+
+/// GET /api/auth/api-keys
+#[utoipa::path(
+    tag = "auth",
+    responses(
+        (status = 200, description = "Claus de l'usuari, sense el secret", body = Vec<ApiKeyResponse>),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 403, description = "Amb una clau d'API no es poden gestionar les claus", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/auth/api-keys")]
+async fn list_api_keys(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let user = extract_jwt_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let keys = sqlx::query_as!(
+        ApiKeyResponse,
+        "SELECT id, name, last_used_at, created_at FROM api_keys WHERE user_id = $1 ORDER BY created_at",
+        user.id
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(keys))
+}
+
+/// DELETE /api/auth/api-keys/{id}
+/// La clau deixa de funcionar immediatament
+#[utoipa::path(
+    tag = "auth",
+    params(("id" = Uuid, Path, description = "Id de la clau")),
+    responses(
+        (status = 204, description = "Clau eliminada"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 403, description = "Amb una clau d'API no es poden gestionar les claus", body = ErrorResponse),
+        (status = 404, description = "Clau no trobada", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[delete("/auth/api-keys/{id}")]
+async fn delete_api_key(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_jwt_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let result = sqlx::query!("DELETE FROM api_keys WHERE id = $1 AND user_id = $2", path.into_inner(), user.id)
+        .execute(pool.get_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("API_KEY_NOT_FOUND", "API key not found".to_string()));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Usuari d'una clau d'API. Apunta quan s'ha fet servir i aplica el límit de peticions de la clau.
+pub(super) async fn find_user_for_api_key(key: &str, pool: &PgPool) -> AppResult<User> {
+    let api_key = sqlx::query!(
+        "UPDATE api_keys SET last_used_at = NOW() WHERE key_hash = $1 RETURNING id, user_id",
+        hash_api_key(key)
+    )
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::Unauthorized("INVALID_API_KEY", "Invalid API key".to_string()))?;
+
+    if let Err(retry_after) = API_KEY_LIMITER.check(RateKey::ApiKey(api_key.id), Instant::now()) {
+        tracing::warn!("Massa peticions amb la clau d'API {}", api_key.id);
+        return Err(rate_limited(retry_after));
+    }
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(api_key.user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("USER_NOT_FOUND", "User not found".to_string()))?;
+
+    Ok(user)
+}
+
+/// Id i usuari d'una clau d'API, sense apuntar-ne l'ús ni aplicar-hi el límit de peticions
+/// (per atribuir les crides al registre d'auditoria)
+pub(crate) async fn api_key_owner(key: &str, pool: &PgPool) -> Result<Option<(Uuid, Uuid)>, sqlx::Error> {
+    let api_key = sqlx::query!("SELECT id, user_id FROM api_keys WHERE key_hash = $1", hash_api_key(key))
+        .fetch_optional(pool)
+        .await?;
+    Ok(api_key.map(|k| (k.id, k.user_id)))
+}
+
+/// Hash amb què es desa una clau d'API
+fn hash_api_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn validate_name(name: &str) -> Result<(), FieldError> {
+    if name.trim().is_empty() {
+        return Err(FieldError::new("name", "EMPTY", "name cannot be empty"));
+    }
+    if name.trim().chars().count() > MAX_NAME_LEN {
+        return Err(FieldError::new(
+            "name",
+            "TOO_LONG",
+            format!("name cannot be longer than {} characters", MAX_NAME_LEN),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    use crate::test_utils::{auth_header, create_user, test_config};
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_api_key_lifecycle(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "script").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(crate::api::auth::configure).configure(configure)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/auth/api-keys")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "name": "cron" }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let created: serde_json::Value = test::read_body_json(resp).await;
+        let key = created["key"].as_str().unwrap().to_string();
+        assert!(key.starts_with(KEY_PREFIX));
+
+        // La clau autentica sense JWT
+        let me = |key: &str| {
+            test::TestRequest::get()
+                .uri("/api/auth/me")
+                .insert_header((API_KEY_HEADER, key))
+                .to_request()
+        };
+        let resp = test::call_service(&app, me(&key)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["id"], user.id.to_string());
+
+        let resp = test::call_service(&app, me("pvpc_invalid")).await;
+        assert_eq!(resp.status(), 401);
+
+        // Amb la clau no es poden gestionar les claus
+        let req = test::TestRequest::post()
+            .uri("/api/auth/api-keys")
+            .insert_header((API_KEY_HEADER, key.as_str()))
+            .set_json(serde_json::json!({ "name": "leaked" }))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        let req = test::TestRequest::get()
+            .uri("/api/auth/api-keys")
+            .insert_header((API_KEY_HEADER, key.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/auth/api-keys/{}", created["id"].as_str().unwrap()))
+            .insert_header((API_KEY_HEADER, key.as_str()))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 403);
+
+        // La llista no inclou el secret, però sí quan s'ha fet servir
+        let req = test::TestRequest::get()
+            .uri("/api/auth/api-keys")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let keys: serde_json::Value = test::read_body_json(test::call_service(&app, req).await).await;
+        assert_eq!(keys.as_array().unwrap().len(), 1);
+        assert!(keys[0].get("key").is_none());
+        assert!(keys[0]["last_used_at"].is_string());
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/auth/api-keys/{}", created["id"].as_str().unwrap()))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+
+        let resp = test::call_service(&app, me(&key)).await;
+        assert_eq!(resp.status(), 401);
+    }
+}
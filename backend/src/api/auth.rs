@@ -1,14 +1,28 @@
-use actix_web::{get, post, web, HttpRequest, HttpResponse};
-use chrono::{Duration, Utc};
+use std::sync::LazyLock;
+
+use actix_web::http::header;
+use actix_web::{delete, get, patch, post, web, HttpRequest, HttpResponse};
+use chrono::{Duration, NaiveTime, Utc};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgConnection, PgPool};
+use utoipa::ToSchema;
 use uuid::Uuid;
 
 use crate::config::Config;
-use crate::db::models::User;
-use crate::error::{AppError, AppResult};
+use crate::api::validation::{FieldErrors, Validate, Validated};
+use crate::db::models::{IdentityProvider, User, UserPreferences};
+use crate::error::{AppError, AppResult, ErrorResponse};
+use crate::middleware::rate_limit::RateLimiter;
+use crate::services::energy_tariff::GeoZone;
+use crate::services::export::export_user_data;
+use crate::services::apple::AppleAuthService;
 use crate::services::google::GoogleAuthService;
+use crate::services::metrics;
+use crate::services::secrets::SecretBox;
+
+use super::api_keys::{find_user_for_api_key, API_KEY_HEADER};
 
 /// JWT Claims per tokens interns de l'aplicació
 #[derive(Debug, Serialize, Deserialize)]
@@ -19,36 +33,252 @@ pub struct Claims {
     pub iat: i64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct GoogleLoginRequest {
     pub id_token: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AppleLoginRequest {
+    /// `identityToken` que retorna "Sign in with Apple"
+    pub id_token: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteAccountRequest {
+    /// Token ID acabat d'obtenir del proveïdor del compte (Google o Apple), per confirmar
+    /// que qui esborra el compte n'és el titular
+    pub id_token: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct AuthResponse {
     pub access_token: String,
     pub token_type: String,
+    /// Segons fins que caduca l'access token
     pub expires_in: i64,
+    /// Token per obtenir un nou access token a `/api/auth/refresh`. Només es pot fer servir una vegada.
+    pub refresh_token: String,
+    /// Segons fins que caduca el refresh token
+    pub refresh_expires_in: i64,
     pub user: UserResponse,
 }
 
-#[derive(Debug, Serialize)]
+/// Refresh token desat (només el hash)
+#[derive(Debug, FromRow)]
+struct StoredRefreshToken {
+    id: Uuid,
+    user_id: Uuid,
+    expires_at: chrono::DateTime<Utc>,
+    revoked_at: Option<chrono::DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct UserResponse {
     pub id: Uuid,
+    /// Proveïdor amb què inicia sessió
+    pub provider: IdentityProvider,
     pub email: String,
     pub name: Option<String>,
     pub picture_url: Option<String>,
+    pub max_concurrent_devices: Option<i32>,
+    /// Si l'usuari ha desat el seu propi token de ESIOS (mai es retorna)
+    pub has_esios_token: bool,
+    /// Hora local del servidor a la qual es generen els seus schedules de demà
+    /// (null = l'hora per defecte del servidor)
+    #[schema(value_type = Option<String>, example = "19:30:00")]
+    pub schedule_gen_time: Option<NaiveTime>,
+    /// Recàrrec fix (€/kWh) que se suma al preu de ESIOS abans d'impostos
+    pub energy_surcharge_eur_kwh: f64,
+    /// Multiplicador dels impostos sobre el preu amb recàrrec (1 = sense impostos)
+    #[schema(example = 1.2718636)]
+    pub tax_multiplier: f64,
+}
+
+impl From<User> for UserResponse {
+    fn from(user: User) -> Self {
+        Self {
+            id: user.id,
+            provider: user.provider,
+            email: user.email,
+            name: user.name,
+            picture_url: user.picture_url,
+            max_concurrent_devices: user.max_concurrent_devices,
+            has_esios_token: user.esios_token.is_some(),
+            schedule_gen_time: user.schedule_gen_time,
+            energy_surcharge_eur_kwh: user.energy_surcharge_eur_kwh,
+            tax_multiplier: user.tax_multiplier,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateMeRequest {
+    /// Màxim de dispositius encesos alhora. `null` elimina el límit; absent, no el canvia.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<i32>)]
+    pub max_concurrent_devices: Option<Option<i32>>,
+    /// Token personal de ESIOS per obtenir els preus. `null` l'esborra (s'usa el global);
+    /// absent, no el canvia.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>)]
+    pub esios_token: Option<Option<String>>,
+    /// Hora de generació dels schedules de demà. `null` torna a la del servidor; absent, no la canvia.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>, example = "19:30:00")]
+    pub schedule_gen_time: Option<Option<NaiveTime>>,
+    /// Recàrrec fix (€/kWh) que se suma al preu de ESIOS abans d'impostos (p. ex. 0.02)
+    pub energy_surcharge_eur_kwh: Option<f64>,
+    /// Multiplicador dels impostos: amb l'impost elèctric (5,11%) i l'IVA (21%), 1.0511 × 1.21.
+    /// Els preus que es fan servir per programar i es mostren a l'usuari inclouen tots dos.
+    pub tax_multiplier: Option<f64>,
+}
+
+/// Valor màxim (en valor absolut) del recàrrec fix, en €/kWh
+const MAX_SURCHARGE_EUR_KWH: f64 = 1.0;
+
+/// Multiplicador màxim dels impostos
+const MAX_TAX_MULTIPLIER: f64 = 3.0;
+
+/// Longitud màxima acceptada d'un token de ESIOS
+const MAX_ESIOS_TOKEN_LEN: usize = 256;
+
+/// Idiomes acceptats a les preferències
+const LANGUAGES: [&str; 3] = ["ca", "es", "en"];
+
+/// Longitud màxima acceptada d'un token de notificacions push
+const MAX_PUSH_TOKEN_LEN: usize = 4096;
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PreferencesResponse {
+    /// Zona del peatge 2.0TD (null = la del servidor)
+    #[schema(example = "peninsula")]
+    pub preferred_geo_zone: Option<String>,
+    /// `max_hours` de les regles noves que no l'indiquen (null = el del servidor)
+    pub default_max_hours: Option<i32>,
+    /// `days_of_week` de les regles noves que no l'indiquen (bitmask, 127 = cada dia)
+    pub default_days_of_week: i32,
+    pub email_notifications: bool,
+    pub push_notification_token: Option<String>,
+    #[schema(example = "ca")]
+    pub language: String,
+    /// null si encara no s'han desat mai
+    pub updated_at: Option<chrono::DateTime<Utc>>,
+}
+
+impl From<UserPreferences> for PreferencesResponse {
+    fn from(prefs: UserPreferences) -> Self {
+        Self {
+            preferred_geo_zone: prefs.preferred_geo_zone,
+            default_max_hours: prefs.default_max_hours,
+            default_days_of_week: prefs.default_days_of_week,
+            email_notifications: prefs.email_notifications,
+            push_notification_token: prefs.push_notification_token,
+            language: prefs.language,
+            updated_at: prefs.updated_at,
+        }
+    }
+}
+
+/// Camps absents: no es canvien. Als camps que admeten null, `null` torna al valor per defecte.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdatePreferencesRequest {
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>, example = "canarias")]
+    pub preferred_geo_zone: Option<Option<String>>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<i32>)]
+    pub default_max_hours: Option<Option<i32>>,
+    pub default_days_of_week: Option<i32>,
+    pub email_notifications: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>)]
+    pub push_notification_token: Option<Option<String>>,
+    pub language: Option<String>,
+}
+
+impl Validate for UpdatePreferencesRequest {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        if let Some(Some(zone)) = &self.preferred_geo_zone
+            && zone.parse::<GeoZone>().is_err()
+        {
+            errors.add(
+                "preferred_geo_zone",
+                "INVALID_VALUE",
+                "preferred_geo_zone must be one of peninsula, baleares, canarias, ceuta or melilla",
+            );
+        }
+        if let Some(Some(max_hours)) = self.default_max_hours
+            && !(1..=24).contains(&max_hours)
+        {
+            errors.add("default_max_hours", "OUT_OF_RANGE", "default_max_hours must be between 1 and 24");
+        }
+        if let Some(days) = self.default_days_of_week
+            && !(1..=127).contains(&days)
+        {
+            errors.add("default_days_of_week", "OUT_OF_RANGE", "default_days_of_week must be between 1 and 127");
+        }
+        if let Some(Some(token)) = &self.push_notification_token
+            && (token.trim().is_empty() || token.len() > MAX_PUSH_TOKEN_LEN)
+        {
+            errors.add("push_notification_token", "OUT_OF_RANGE", format!(
+                "push_notification_token must be between 1 and {} characters",
+                MAX_PUSH_TOKEN_LEN
+            ));
+        }
+        if let Some(language) = &self.language
+            && !LANGUAGES.contains(&language.as_str())
+        {
+            errors.add("language", "INVALID_VALUE", format!("language must be one of {}", LANGUAGES.join(", ")));
+        }
+
+        errors.into_result()
+    }
+}
+
+/// Distingeix un camp absent (None) d'un camp a `null` (Some(None))
+pub(super) fn deserialize_some<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: serde::Deserializer<'de>,
+{
+    T::deserialize(deserializer).map(Some)
 }
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(google_login)
+        .service(apple_login)
         .service(refresh_token)
-        .service(get_me);
+        .service(get_me)
+        .service(update_me)
+        .service(get_preferences)
+        .service(update_preferences)
+        .service(export_data)
+        .service(delete_account);
 }
 
+/// Intents de login (Google o Apple) per IP i minut. Cada intent consulta el proveïdor i la BD.
+static LOGIN_LIMITER: LazyLock<RateLimiter> =
+    LazyLock::new(|| RateLimiter::new(10, std::time::Duration::from_secs(60)));
+
 /// POST /api/auth/google
 /// Login amb Google ID token
-#[post("/auth/google")]
+#[utoipa::path(
+    tag = "auth",
+    request_body = GoogleLoginRequest,
+    responses(
+        (status = 200, description = "Login correcte", body = AuthResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 429, description = "Massa intents des de la mateixa IP (veure Retry-After)", body = ErrorResponse),
+    ),
+)]
+#[post("/auth/google", wrap = "LOGIN_LIMITER.clone()")]
 async fn google_login(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
@@ -58,54 +288,174 @@ async fn google_login(
     // Validar el token de Google amb verificació de signatura
     let google_claims = google_auth
         .verify_id_token(&body.id_token, &config.google_client_id)
-        .await?;
+        .await;
+    metrics::record_auth("google", &google_claims);
+    let google_claims = google_claims?;
 
     // Buscar o crear usuari
-    let user = find_or_create_user(&pool, &google_claims).await?;
+    let is_admin = config.is_admin_email(&google_claims.email);
+    let user = find_or_create_user(&pool, &google_claims, is_admin).await?;
 
-    // Generar JWT
-    let (token, expires_in) = generate_jwt(&user, &config.jwt_secret)?;
+    // Generar JWT i refresh token
+    let mut tx = pool.begin().await?;
+    let response = auth_response(&mut tx, &config, user).await?;
+    tx.commit().await?;
 
-    Ok(HttpResponse::Ok().json(AuthResponse {
-        access_token: token,
-        token_type: "Bearer".to_string(),
-        expires_in,
-        user: UserResponse {
-            id: user.id,
-            email: user.email,
-            name: user.name,
-            picture_url: user.picture_url,
-        },
-    }))
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// POST /api/auth/apple
+/// Login amb el token ID de "Sign in with Apple"
+#[utoipa::path(
+    tag = "auth",
+    request_body = AppleLoginRequest,
+    responses(
+        (status = 200, description = "Login correcte", body = AuthResponse),
+        (status = 400, description = "Sign in with Apple no està configurat al servidor", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 429, description = "Massa intents des de la mateixa IP (veure Retry-After)", body = ErrorResponse),
+    ),
+)]
+#[post("/auth/apple", wrap = "LOGIN_LIMITER.clone()")]
+async fn apple_login(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    apple_auth: web::Data<AppleAuthService>,
+    body: web::Json<AppleLoginRequest>,
+) -> AppResult<HttpResponse> {
+    if config.apple_client_ids.is_empty() {
+        return Err(AppError::BadRequest("APPLE_LOGIN_DISABLED", "Sign in with Apple is not configured".to_string()));
+    }
+
+    let apple_claims = apple_auth.verify_id_token(&body.id_token, &config.apple_client_ids).await;
+    metrics::record_auth("apple", &apple_claims);
+    let apple_claims = apple_claims?;
+
+    let is_admin = config.is_admin_email(&apple_claims.email);
+    let user = find_or_create_user(&pool, &apple_claims, is_admin).await?;
+
+    let mut tx = pool.begin().await?;
+    let response = auth_response(&mut tx, &config, user).await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(response))
 }
 
 /// POST /api/auth/refresh
-/// Permet refresh de tokens expirats fins a 7 dies després de l'expiració
+/// Canvia un refresh token per un access token nou i un altre refresh token (rotació).
+/// Si es torna a presentar un refresh token ja utilitzat, es revoquen tots els de l'usuari.
+#[utoipa::path(
+    tag = "auth",
+    request_body = RefreshRequest,
+    responses(
+        (status = 200, description = "Token renovat", body = AuthResponse),
+        (status = 401, description = "Refresh token invàlid, caducat o ja utilitzat", body = ErrorResponse),
+    ),
+)]
 #[post("/auth/refresh")]
 async fn refresh_token(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
-    req: HttpRequest,
+    body: web::Json<RefreshRequest>,
 ) -> AppResult<HttpResponse> {
-    // Utilitzar validació especial per refresh que permet tokens expirats
-    let user = extract_user_for_refresh(&req, &pool, &config.jwt_secret).await?;
+    let response = rotate_refresh_token(&pool, &config, &body.refresh_token).await;
+    metrics::record_auth("refresh", &response);
+
+    Ok(HttpResponse::Ok().json(response?))
+}
+
+/// Revoca el refresh token i n'emet un de nou amb un access token nou
+async fn rotate_refresh_token(pool: &PgPool, config: &Config, token: &str) -> AppResult<AuthResponse> {
+    let mut tx = pool.begin().await?;
+
+    // FOR UPDATE: dues peticions amb el mateix token no el poden fer servir totes dues
+    let stored = sqlx::query_as::<_, StoredRefreshToken>(
+        "SELECT id, user_id, expires_at, revoked_at FROM refresh_tokens WHERE token_hash = $1 FOR UPDATE"
+    )
+    .bind(hash_refresh_token(token))
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::Unauthorized("INVALID_REFRESH_TOKEN", "Invalid refresh token".to_string()))?;
+
+    if stored.revoked_at.is_some() {
+        // Un token ja utilitzat pot haver estat robat: es tanquen totes les sessions de l'usuari
+        sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL")
+            .bind(stored.user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        tracing::warn!("Refresh token reutilitzat per l'usuari {}: sessions revocades", stored.user_id);
 
-    let (token, expires_in) = generate_jwt(&user, &config.jwt_secret)?;
+        return Err(AppError::Unauthorized(
+            "REFRESH_TOKEN_REUSED",
+            "Refresh token already used. Please login again.".to_string(),
+        ));
+    }
+
+    if stored.expires_at <= Utc::now() {
+        return Err(AppError::Unauthorized(
+            "TOKEN_EXPIRED",
+            "Refresh token expired. Please login again.".to_string(),
+        ));
+    }
+
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = NOW() WHERE id = $1")
+        .bind(stored.id)
+        .execute(&mut *tx)
+        .await?;
 
-    Ok(HttpResponse::Ok().json(AuthResponse {
-        access_token: token,
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(stored.user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("USER_NOT_FOUND", "User not found".to_string()))?;
+
+    let response = auth_response(&mut tx, config, user).await?;
+    tx.commit().await?;
+    Ok(response)
+}
+
+/// Genera l'access token i desa un refresh token nou per l'usuari
+async fn auth_response(conn: &mut PgConnection, config: &Config, user: User) -> AppResult<AuthResponse> {
+    let (access_token, expires_in) = generate_jwt(&user, config)?;
+
+    let new_refresh_token = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+    sqlx::query(
+        r#"
+        INSERT INTO refresh_tokens (user_id, token_hash, expires_at)
+        VALUES ($1, $2, NOW() + make_interval(secs => $3))
+        "#
+    )
+    .bind(user.id)
+    .bind(hash_refresh_token(&new_refresh_token))
+    .bind(config.jwt_refresh_ttl as f64)
+    .execute(conn)
+    .await?;
+
+    Ok(AuthResponse {
+        access_token,
         token_type: "Bearer".to_string(),
         expires_in,
-        user: UserResponse {
-            id: user.id,
-            email: user.email,
-            name: user.name,
-            picture_url: user.picture_url,
-        },
-    }))
+        refresh_token: new_refresh_token,
+        refresh_expires_in: config.jwt_refresh_ttl,
+        user: UserResponse::from(user),
+    })
+}
+
+/// Hash amb què es desa un refresh token
+fn hash_refresh_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
 }
 
 /// GET /api/auth/me
+#[utoipa::path(
+    tag = "auth",
+    responses(
+        (status = 200, description = "Usuari autenticat", body = UserResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[get("/auth/me")]
 async fn get_me(
     pool: web::Data<PgPool>,
@@ -114,25 +464,368 @@ async fn get_me(
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
 
-    Ok(HttpResponse::Ok().json(UserResponse {
-        id: user.id,
-        email: user.email,
-        name: user.name,
-        picture_url: user.picture_url,
-    }))
+    Ok(HttpResponse::Ok().json(UserResponse::from(user)))
+}
+
+/// PATCH /api/auth/me
+/// Actualitza les preferències de l'usuari
+#[utoipa::path(
+    tag = "auth",
+    request_body = UpdateMeRequest,
+    responses(
+        (status = 200, description = "Usuari actualitzat", body = UserResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[patch("/auth/me")]
+async fn update_me(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<UpdateMeRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let max_concurrent_devices = body
+        .max_concurrent_devices
+        .unwrap_or(user.max_concurrent_devices);
+
+    if let Some(max) = max_concurrent_devices
+        && max < 1
+    {
+        return Err(AppError::BadRequest(
+            "USER_VALIDATION_FAILED",
+            "max_concurrent_devices must be 1 or greater".to_string(),
+        ));
+    }
+
+    let esios_token = match &body.esios_token {
+        None => user.esios_token.clone(),
+        Some(None) => None,
+        Some(Some(token)) => Some(seal_esios_token(&config, token)?),
+    };
+
+    let schedule_gen_time = body.schedule_gen_time.unwrap_or(user.schedule_gen_time);
+
+    let surcharge = body.energy_surcharge_eur_kwh.unwrap_or(user.energy_surcharge_eur_kwh);
+    if !surcharge.is_finite() || surcharge.abs() > MAX_SURCHARGE_EUR_KWH {
+        return Err(AppError::BadRequest("USER_VALIDATION_FAILED", format!(
+            "energy_surcharge_eur_kwh must be between -{0} and {0}",
+            MAX_SURCHARGE_EUR_KWH
+        )));
+    }
+
+    let tax_multiplier = body.tax_multiplier.unwrap_or(user.tax_multiplier);
+    if !tax_multiplier.is_finite() || tax_multiplier <= 0.0 || tax_multiplier > MAX_TAX_MULTIPLIER {
+        return Err(AppError::BadRequest("USER_VALIDATION_FAILED", format!(
+            "tax_multiplier must be greater than 0 and at most {}",
+            MAX_TAX_MULTIPLIER
+        )));
+    }
+
+    let updated = sqlx::query_as::<_, User>(
+        r#"
+        UPDATE users
+        SET max_concurrent_devices = $1, esios_token = $3, schedule_gen_time = $4,
+            energy_surcharge_eur_kwh = $5, tax_multiplier = $6, updated_at = NOW()
+        WHERE id = $2
+        RETURNING *
+        "#,
+    )
+    .bind(max_concurrent_devices)
+    .bind(user.id)
+    .bind(esios_token)
+    .bind(schedule_gen_time)
+    .bind(surcharge)
+    .bind(tax_multiplier)
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(UserResponse::from(updated)))
+}
+
+/// GET /api/auth/preferences
+/// Preferències de l'usuari (els valors per defecte si no n'ha desat mai)
+#[utoipa::path(
+    tag = "auth",
+    responses(
+        (status = 200, description = "Preferències de l'usuari", body = PreferencesResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/auth/preferences")]
+async fn get_preferences(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let prefs = load_preferences(pool.get_ref(), user.id).await?;
+
+    Ok(HttpResponse::Ok().json(PreferencesResponse::from(prefs)))
+}
+
+/// PATCH /api/auth/preferences
+/// Actualitza les preferències indicades (la primera vegada, en crea la fila)
+#[utoipa::path(
+    tag = "auth",
+    request_body = UpdatePreferencesRequest,
+    responses(
+        (status = 200, description = "Preferències actualitzades", body = PreferencesResponse),
+        (status = 422, description = "Camps invàlids (un error per camp)", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[patch("/auth/preferences")]
+async fn update_preferences(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: Validated<UpdatePreferencesRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let mut tx = pool.begin().await?;
+    // Bloquejar la fila (si n'hi ha) perquè dos PATCH simultanis no es trepitgin
+    let current = sqlx::query_as::<_, UserPreferences>(
+        "SELECT * FROM user_preferences WHERE user_id = $1 FOR UPDATE"
+    )
+    .bind(user.id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .unwrap_or_else(|| UserPreferences::defaults(user.id));
+
+    let preferred_geo_zone = match &body.preferred_geo_zone {
+        None => current.preferred_geo_zone,
+        Some(zone) => zone.as_ref().map(|z| z.trim().to_ascii_lowercase()),
+    };
+    let push_notification_token = match &body.push_notification_token {
+        None => current.push_notification_token,
+        Some(token) => token.as_ref().map(|t| t.trim().to_string()),
+    };
+
+    let updated = sqlx::query_as::<_, UserPreferences>(
+        r#"
+        INSERT INTO user_preferences (user_id, preferred_geo_zone, default_max_hours, default_days_of_week,
+                                      email_notifications, push_notification_token, language)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        ON CONFLICT (user_id) DO UPDATE
+        SET preferred_geo_zone = EXCLUDED.preferred_geo_zone,
+            default_max_hours = EXCLUDED.default_max_hours,
+            default_days_of_week = EXCLUDED.default_days_of_week,
+            email_notifications = EXCLUDED.email_notifications,
+            push_notification_token = EXCLUDED.push_notification_token,
+            language = EXCLUDED.language,
+            updated_at = NOW()
+        RETURNING *
+        "#,
+    )
+    .bind(user.id)
+    .bind(preferred_geo_zone)
+    .bind(body.default_max_hours.unwrap_or(current.default_max_hours))
+    .bind(body.default_days_of_week.unwrap_or(current.default_days_of_week))
+    .bind(body.email_notifications.unwrap_or(current.email_notifications))
+    .bind(push_notification_token)
+    .bind(body.language.clone().unwrap_or(current.language))
+    .fetch_one(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(PreferencesResponse::from(updated)))
+}
+
+/// Preferències d'un usuari; si no n'ha desat mai, els valors per defecte
+pub async fn load_preferences(pool: &PgPool, user_id: Uuid) -> AppResult<UserPreferences> {
+    let prefs = sqlx::query_as::<_, UserPreferences>("SELECT * FROM user_preferences WHERE user_id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(prefs.unwrap_or_else(|| UserPreferences::defaults(user_id)))
+}
+
+/// Zona del peatge de l'usuari: la de les preferències o, si no n'ha triat, `default` (la del servidor)
+pub async fn user_geo_zone(pool: &PgPool, user_id: Uuid, default: GeoZone) -> AppResult<GeoZone> {
+    let prefs = load_preferences(pool, user_id).await?;
+    Ok(prefs.preferred_geo_zone.and_then(|zone| zone.parse().ok()).unwrap_or(default))
+}
+
+/// Valida i xifra un token de ESIOS per desar-lo
+fn seal_esios_token(config: &Config, token: &str) -> AppResult<Vec<u8>> {
+    let token = token.trim();
+    if token.is_empty() || token.len() > MAX_ESIOS_TOKEN_LEN {
+        return Err(AppError::BadRequest("USER_VALIDATION_FAILED", format!(
+            "esios_token must be between 1 and {} characters",
+            MAX_ESIOS_TOKEN_LEN
+        )));
+    }
+
+    let secrets = SecretBox::from_config(config).ok_or_else(|| {
+        AppError::BadRequest(
+            "ESIOS_TOKENS_DISABLED",
+            "Personal ESIOS tokens are not enabled on this server".to_string(),
+        )
+    })?;
+    Ok(secrets.encrypt(token))
+}
+
+/// Temps mínim entre dues exportacions de dades del mateix usuari
+const EXPORT_COOLDOWN_HOURS: i32 = 24;
+
+/// GET /api/auth/export-data
+/// Descarrega totes les dades de l'usuari (RGPD): un ZIP amb un JSON per taula
+#[utoipa::path(
+    tag = "auth",
+    responses(
+        (status = 200, description = "ZIP amb users.json, devices.json, rules.json i scheduled_actions.json",
+            content_type = "application/zip", body = Vec<u8>),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 429, description = "Ja s'ha fet una exportació les darreres 24 hores", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/auth/export-data")]
+async fn export_data(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    // Reservar l'exportació abans de començar: dues peticions simultànies no poden passar totes dues
+    let result = sqlx::query(
+        r#"
+        UPDATE users SET last_export_at = NOW()
+        WHERE id = $1
+          AND (last_export_at IS NULL OR last_export_at <= NOW() - make_interval(hours => $2))
+        "#
+    )
+    .bind(user.id)
+    .bind(EXPORT_COOLDOWN_HOURS)
+    .execute(pool.get_ref())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::TooManyRequests("EXPORT_RATE_LIMITED", format!(
+            "Data can only be exported once every {} hours",
+            EXPORT_COOLDOWN_HOURS
+        )));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"pvpc-data-{}.zip\"", user.id),
+        ))
+        .streaming(export_user_data(pool.get_ref().clone(), user.id)))
+}
+
+/// DELETE /api/auth/account
+/// Esborra el compte i totes les dades de l'usuari (RGPD). Cal confirmar-ho amb un token ID
+/// nou del mateix compte, del seu proveïdor (Google o Apple). Els JWT i refresh tokens que
+/// quedin deixen de ser vàlids perquè l'usuari ja no existeix.
+#[utoipa::path(
+    tag = "auth",
+    request_body = DeleteAccountRequest,
+    responses(
+        (status = 204, description = "Compte esborrat"),
+        (status = 401, description = "Token invàlid o absent, o token ID invàlid", body = ErrorResponse),
+        (status = 403, description = "El token ID és d'un altre compte", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[delete("/auth/account")]
+async fn delete_account(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    google_auth: web::Data<GoogleAuthService>,
+    apple_auth: web::Data<AppleAuthService>,
+    req: HttpRequest,
+    body: web::Json<DeleteAccountRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let claims = match user.provider {
+        IdentityProvider::Google => {
+            let claims = google_auth.verify_id_token(&body.id_token, &config.google_client_id).await;
+            metrics::record_auth("google", &claims);
+            claims
+        }
+        IdentityProvider::Apple => {
+            let claims = apple_auth.verify_id_token(&body.id_token, &config.apple_client_ids).await;
+            metrics::record_auth("apple", &claims);
+            claims
+        }
+    };
+    if claims?.sub != user.provider_subject {
+        return Err(AppError::Forbidden(
+            "ACCOUNT_MISMATCH",
+            "ID token belongs to a different account".to_string(),
+        ));
+    }
+
+    erase_user(&pool, user.id).await?;
+    tracing::info!("Compte de l'usuari {} esborrat a petició seva", user.id);
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Esborra l'usuari amb totes les seves dades en una sola transacció. La resta de taules
+/// (grups, finestres, webhooks...) cauen en cascada amb `users`. Les seves files de l'audit_log
+/// també s'esborren: els cossos de les peticions contenen noms i dades dels dispositius.
+///
+/// Retorna false si l'usuari no existeix.
+pub(crate) async fn erase_user(pool: &PgPool, user_id: Uuid) -> AppResult<bool> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "DELETE FROM scheduled_actions WHERE device_id IN (SELECT id FROM devices WHERE user_id = $1)",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM rules
+        WHERE device_id IN (SELECT id FROM devices WHERE user_id = $1)
+           OR device_group_id IN (SELECT id FROM device_groups WHERE user_id = $1)
+        "#,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!("DELETE FROM devices WHERE user_id = $1", user_id).execute(&mut *tx).await?;
+    sqlx::query!("DELETE FROM user_preferences WHERE user_id = $1", user_id).execute(&mut *tx).await?;
+    sqlx::query!("DELETE FROM api_keys WHERE user_id = $1", user_id).execute(&mut *tx).await?;
+    sqlx::query!("DELETE FROM refresh_tokens WHERE user_id = $1", user_id).execute(&mut *tx).await?;
+    sqlx::query!("DELETE FROM audit_log WHERE user_id = $1", user_id).execute(&mut *tx).await?;
+    let deleted = sqlx::query!("DELETE FROM users WHERE id = $1", user_id).execute(&mut *tx).await?;
+
+    tx.commit().await?;
+    Ok(deleted.rows_affected() > 0)
 }
 
-/// Claims validats del token de Google
-pub struct GoogleIdTokenClaims {
+/// Claims validats d'un token ID (de Google o d'Apple)
+pub struct IdTokenClaims {
+    pub provider: IdentityProvider,
     pub sub: String,
     pub email: String,
     pub name: Option<String>,
     pub picture: Option<String>,
 }
 
-async fn find_or_create_user(pool: &PgPool, claims: &GoogleIdTokenClaims) -> AppResult<User> {
+/// Si `promote_admin`, l'usuari passa a ser administrador (mai es treu el rol aquí)
+async fn find_or_create_user(pool: &PgPool, claims: &IdTokenClaims, promote_admin: bool) -> AppResult<User> {
     // Intentar trobar l'usuari existent
-    let existing = sqlx::query_as::<_, User>("SELECT * FROM users WHERE google_id = $1")
+    let existing = sqlx::query_as::<_, User>("SELECT * FROM users WHERE provider = $1 AND provider_subject = $2")
+        .bind(claims.provider)
         .bind(&claims.sub)
         .fetch_optional(pool)
         .await?;
@@ -142,8 +835,8 @@ async fn find_or_create_user(pool: &PgPool, claims: &GoogleIdTokenClaims) -> App
         let updated = sqlx::query_as::<_, User>(
             r#"
             UPDATE users
-            SET email = $1, name = $2, picture_url = $3, updated_at = NOW()
-            WHERE google_id = $4
+            SET email = $1, name = $2, picture_url = $3, is_admin = is_admin OR $5, updated_at = NOW()
+            WHERE provider = $6 AND provider_subject = $4
             RETURNING *
             "#,
         )
@@ -151,6 +844,8 @@ async fn find_or_create_user(pool: &PgPool, claims: &GoogleIdTokenClaims) -> App
         .bind(&claims.name)
         .bind(&claims.picture)
         .bind(&claims.sub)
+        .bind(promote_admin)
+        .bind(claims.provider)
         .fetch_one(pool)
         .await?;
 
@@ -159,15 +854,17 @@ async fn find_or_create_user(pool: &PgPool, claims: &GoogleIdTokenClaims) -> App
         // Crear nou usuari
         let new_user = sqlx::query_as::<_, User>(
             r#"
-            INSERT INTO users (google_id, email, name, picture_url)
-            VALUES ($1, $2, $3, $4)
+            INSERT INTO users (provider, provider_subject, email, name, picture_url, is_admin)
+            VALUES ($1, $2, $3, $4, $5, $6)
             RETURNING *
             "#,
         )
+        .bind(claims.provider)
         .bind(&claims.sub)
         .bind(&claims.email)
         .bind(&claims.name)
         .bind(&claims.picture)
+        .bind(promote_admin)
         .fetch_one(pool)
         .await?;
 
@@ -175,8 +872,8 @@ async fn find_or_create_user(pool: &PgPool, claims: &GoogleIdTokenClaims) -> App
     }
 }
 
-fn generate_jwt(user: &User, secret: &str) -> AppResult<(String, i64)> {
-    let expires_in = 3600 * 24; // 24 hores
+pub(crate) fn generate_jwt(user: &User, config: &Config) -> AppResult<(String, i64)> {
+    let expires_in = config.jwt_access_ttl;
     let now = Utc::now();
     let exp = now + Duration::seconds(expires_in);
 
@@ -190,26 +887,87 @@ fn generate_jwt(user: &User, secret: &str) -> AppResult<(String, i64)> {
     // Usar explícitament HS256
     let header = Header::new(Algorithm::HS256);
 
-    let token = encode(&header, &claims, &EncodingKey::from_secret(secret.as_bytes()))?;
+    let token = encode(&header, &claims, &EncodingKey::from_secret(config.jwt_secret.as_bytes()))?;
 
     Ok((token, expires_in))
 }
 
+/// Usuari de la petició: amb la clau d'API de `X-API-Key` si n'hi ha, si no amb el JWT de `Authorization`
 pub async fn extract_user_from_request(
     req: &HttpRequest,
     pool: &PgPool,
     jwt_secret: &str,
 ) -> AppResult<User> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+        let user = find_user_for_api_key(key, pool).await;
+        metrics::record_auth("api_key", &user);
+        return user;
+    }
+
+    let user = match bearer_token(req) {
+        Ok(token) => find_user_for_token(token, pool, jwt_secret).await,
+        Err(e) => Err(e),
+    };
+    metrics::record_auth("token", &user);
+    user
+}
+
+/// Usuari de la petició només amb el JWT de `Authorization`. Per les operacions que una clau
+/// d'API no pot fer (p. ex. gestionar les claus: una clau filtrada no n'ha de poder crear de noves).
+pub async fn extract_jwt_user_from_request(
+    req: &HttpRequest,
+    pool: &PgPool,
+    jwt_secret: &str,
+) -> AppResult<User> {
+    if req.headers().contains_key(API_KEY_HEADER) {
+        return Err(AppError::Forbidden(
+            "JWT_REQUIRED",
+            "This operation requires a session token, not an API key".to_string(),
+        ));
+    }
+
+    let user = match bearer_token(req) {
+        Ok(token) => find_user_for_token(token, pool, jwt_secret).await,
+        Err(e) => Err(e),
+    };
+    metrics::record_auth("token", &user);
+    user
+}
+
+/// Token de la capçalera `Authorization: Bearer ...`
+fn bearer_token(req: &HttpRequest) -> AppResult<&str> {
     let auth_header = req
         .headers()
         .get("Authorization")
         .and_then(|v| v.to_str().ok())
-        .ok_or_else(|| AppError::Unauthorized("Missing Authorization header".to_string()))?;
+        .ok_or_else(|| AppError::Unauthorized("MISSING_AUTH_HEADER", "Missing Authorization header".to_string()))?;
 
-    let token = auth_header
+    auth_header
         .strip_prefix("Bearer ")
-        .ok_or_else(|| AppError::Unauthorized("Invalid Authorization format".to_string()))?;
+        .ok_or_else(|| AppError::Unauthorized("INVALID_AUTH_HEADER", "Invalid Authorization format".to_string()))
+}
+
+/// Valida un JWT (p. ex. rebut per query string) i retorna l'usuari
+pub async fn extract_user_from_token(token: &str, pool: &PgPool, jwt_secret: &str) -> AppResult<User> {
+    let user = find_user_for_token(token, pool, jwt_secret).await;
+    metrics::record_auth("token", &user);
+    user
+}
+
+async fn find_user_for_token(token: &str, pool: &PgPool, jwt_secret: &str) -> AppResult<User> {
+    let user_id = decode_user_id(token, jwt_secret)?;
+
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::Unauthorized("USER_NOT_FOUND", "User not found".to_string()))?;
+
+    Ok(user)
+}
 
+/// Valida la signatura i l'expiració d'un JWT i retorna l'id de l'usuari, sense consultar la base de dades
+pub fn decode_user_id(token: &str, jwt_secret: &str) -> AppResult<Uuid> {
     // Validació estricta: només acceptar HS256
     let mut validation = Validation::new(Algorithm::HS256);
     validation.validate_exp = true;
@@ -220,71 +978,463 @@ pub async fn extract_user_from_request(
         &validation,
     )?;
 
-    let user_id: Uuid = token_data
+    token_data
         .claims
         .sub
         .parse()
-        .map_err(|_| AppError::Unauthorized("Invalid user ID in token".to_string()))?;
+        .map_err(|_| AppError::Unauthorized("INVALID_TOKEN", "Invalid user ID in token".to_string()))
+}
 
-    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
-        .bind(user_id)
-        .fetch_optional(pool)
-        .await?
-        .ok_or_else(|| AppError::Unauthorized("User not found".to_string()))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
 
-    Ok(user)
-}
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
 
-/// Extreu l'usuari d'un token per refresh, permetent tokens expirats fins a 7 dies
-/// Això és segur perquè:
-/// 1. La signatura del token encara es valida
-/// 2. L'usuari ha d'existir a la base de dades
-/// 3. Hi ha un límit de temps màxim (7 dies) per evitar refresh de tokens molt antics
-async fn extract_user_for_refresh(
-    req: &HttpRequest,
-    pool: &PgPool,
-    jwt_secret: &str,
-) -> AppResult<User> {
-    let auth_header = req
-        .headers()
-        .get("Authorization")
-        .and_then(|v| v.to_str().ok())
-        .ok_or_else(|| AppError::Unauthorized("Missing Authorization header".to_string()))?;
+    use crate::services::export::EXPORT_FILES;
+    use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
 
-    let token = auth_header
-        .strip_prefix("Bearer ")
-        .ok_or_else(|| AppError::Unauthorized("Invalid Authorization format".to_string()))?;
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_refresh_token_rotation_and_reuse(pool: PgPool) {
+        use actix_web::test::read_body_json;
 
-    // Validació sense expiració per permetre refresh de tokens expirats
-    let mut validation = Validation::new(Algorithm::HS256);
-    validation.validate_exp = false; // No validar expiració
+        let config = test_config();
+        let user = create_user(&pool, "refresh").await;
+        let mut conn = pool.acquire().await.unwrap();
+        let first = auth_response(&mut conn, &config, user).await.unwrap().refresh_token;
 
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(jwt_secret.as_bytes()),
-        &validation,
-    )?;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let refresh = |token: &str| {
+            TestRequest::post()
+                .uri("/api/auth/refresh")
+                .set_json(serde_json::json!({ "refresh_token": token }))
+                .to_request()
+        };
 
-    // Verificar que el token no és massa antic (màxim 7 dies després d'expirar)
-    let now = Utc::now().timestamp();
-    let max_refresh_window = 7 * 24 * 3600; // 7 dies en segons
-    if now - token_data.claims.exp > max_refresh_window {
-        return Err(AppError::Unauthorized(
-            "Token expired too long ago. Please login again.".to_string(),
-        ));
+        // Cada ús retorna un access token vàlid i un refresh token nou
+        let resp = call_service(&app, refresh(&first)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let second = body["refresh_token"].as_str().unwrap().to_string();
+        assert_ne!(second, first);
+        assert_eq!(body["expires_in"], config.jwt_access_ttl);
+        decode_user_id(body["access_token"].as_str().unwrap(), &config.jwt_secret).unwrap();
+
+        let resp = call_service(&app, refresh(&second)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let third = body["refresh_token"].as_str().unwrap().to_string();
+
+        // Reutilitzar un token ja rotat el rebutja i revoca també el vigent
+        let resp = call_service(&app, refresh(&first)).await;
+        assert_eq!(resp.status(), 401);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "REFRESH_TOKEN_REUSED");
+        assert_eq!(call_service(&app, refresh(&third)).await.status(), 401);
+
+        let resp = call_service(&app, refresh("desconegut")).await;
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "INVALID_REFRESH_TOKEN");
     }
 
-    let user_id: Uuid = token_data
-        .claims
-        .sub
-        .parse()
-        .map_err(|_| AppError::Unauthorized("Invalid user ID in token".to_string()))?;
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_preferences_upsert(pool: PgPool) {
+        use actix_web::test::read_body_json;
 
-    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
-        .bind(user_id)
-        .fetch_optional(pool)
-        .await?
-        .ok_or_else(|| AppError::Unauthorized("User not found".to_string()))?;
+        let config = test_config();
+        let user = create_user(&pool, "prefs").await;
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let patch = |body: serde_json::Value| {
+            TestRequest::patch()
+                .uri("/api/auth/preferences")
+                .insert_header(auth_header(&user, &config))
+                .set_json(body)
+                .to_request()
+        };
+        let rows = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM user_preferences WHERE user_id = $1")
+                .bind(user.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
 
-    Ok(user)
+        // Sense fila: valors per defecte, i consultar-los no la crea
+        let req = TestRequest::get()
+            .uri("/api/auth/preferences")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["default_days_of_week"], 127);
+        assert_eq!(body["language"], "ca");
+        assert!(body["updated_at"].is_null());
+        assert_eq!(rows().await, 0);
+
+        // El primer PATCH crea la fila amb els camps indicats i els valors per defecte
+        let resp = call_service(&app, patch(serde_json::json!({
+            "default_max_hours": 5,
+            "language": "es",
+            "preferred_geo_zone": "Canarias",
+        })))
+        .await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["default_max_hours"], 5);
+        assert_eq!(body["preferred_geo_zone"], "canarias");
+        assert_eq!(body["email_notifications"], true);
+        assert!(!body["updated_at"].is_null());
+        assert_eq!(rows().await, 1);
+
+        // El segon l'actualitza: els camps absents es mantenen i null torna al valor per defecte
+        let body: serde_json::Value = read_body_json(
+            call_service(&app, patch(serde_json::json!({ "email_notifications": false, "default_max_hours": null })))
+                .await,
+        )
+        .await;
+        assert_eq!(body["email_notifications"], false);
+        assert!(body["default_max_hours"].is_null());
+        assert_eq!(body["language"], "es");
+        assert_eq!(body["preferred_geo_zone"], "canarias");
+        assert_eq!(rows().await, 1);
+
+        let prefs = load_preferences(&pool, user.id).await.unwrap();
+        assert_eq!(prefs.language, "es");
+        assert!(!prefs.email_notifications);
+        assert_eq!(user_geo_zone(&pool, user.id, GeoZone::Peninsula).await.unwrap(), GeoZone::Canarias);
+
+        // Validació: tots els errors alhora, i no es desa res
+        let resp = call_service(&app, patch(serde_json::json!({
+            "language": "fr",
+            "default_days_of_week": 0,
+            "preferred_geo_zone": "portugal",
+        })))
+        .await;
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["errors"].as_array().unwrap().len(), 3);
+        assert_eq!(load_preferences(&pool, user.id).await.unwrap().language, "es");
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_export_data_zip(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "export").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, CURRENT_DATE, '02:00', '03:00')
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // Un altre usuari: les seves dades no han de sortir a l'exportació
+        let other = create_user(&pool, "other").await;
+        create_device(&pool, other.id, "Rentadora").await;
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/api/auth/export-data")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "application/zip");
+        assert_eq!(
+            resp.headers().get(header::CONTENT_DISPOSITION).unwrap(),
+            format!("attachment; filename=\"pvpc-data-{}.zip\"", user.id).as_str()
+        );
+
+        let body = read_body(resp).await;
+        let mut archive = zip::ZipArchive::new(Cursor::new(body.to_vec())).unwrap();
+        let names: Vec<&str> = archive.file_names().collect();
+        for file in EXPORT_FILES {
+            assert!(names.contains(&file), "falta {}", file);
+        }
+
+        let mut read_json = |name: &str| -> serde_json::Value {
+            let mut contents = String::new();
+            archive.by_name(name).unwrap().read_to_string(&mut contents).unwrap();
+            serde_json::from_str(&contents).unwrap()
+        };
+        assert_eq!(read_json("users.json")[0]["id"], user.id.to_string());
+        let devices = read_json("devices.json");
+        assert_eq!(devices.as_array().unwrap().len(), 1);
+        assert_eq!(devices[0]["name"], "Termo");
+        assert_eq!(read_json("rules.json")[0]["id"], rule.id.to_string());
+        assert_eq!(read_json("scheduled_actions.json").as_array().unwrap().len(), 1);
+
+        // Només una exportació cada 24 hores
+        let req = TestRequest::get()
+            .uri("/api/auth/export-data")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 429);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_erase_user_deletes_all_data(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "erase").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, CURRENT_DATE, '02:00', '03:00')
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO user_preferences (user_id) VALUES ($1)")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO api_keys (user_id, key_hash, name) VALUES ($1, 'hash', 'cron')")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO webhooks (user_id, url, secret, events) VALUES ($1, 'https://ha.local', 's', '{}')")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        auth_response(&mut conn, &config, user.clone()).await.unwrap();
+        drop(conn);
+
+        // Un altre usuari: les seves dades no es toquen
+        let other = create_user(&pool, "other").await;
+        let other_device = create_device(&pool, other.id, "Rentadora").await;
+        create_rule(&pool, other_device.id, "Nit", true).await;
+
+        for id in [user.id, other.id] {
+            sqlx::query(
+                r#"
+                INSERT INTO audit_log (user_id, method, path, request_body, response_status, duration_ms)
+                VALUES ($1, 'PATCH', '/api/auth/me', '{"name": "Termo del pis"}', 200, 5)
+                "#
+            )
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        erase_user(&pool, user.id).await.unwrap();
+
+        let count = |sql: &'static str, id: Uuid| {
+            let pool = pool.clone();
+            async move { sqlx::query_scalar::<_, i64>(sql).bind(id).fetch_one(&pool).await.unwrap() }
+        };
+        assert_eq!(count("SELECT COUNT(*) FROM users WHERE id = $1", user.id).await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM devices WHERE user_id = $1", user.id).await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM rules WHERE id = $1", rule.id).await, 0);
+        assert_eq!(count("SELECT COUNT(*) FROM scheduled_actions WHERE rule_id = $1", rule.id).await, 0);
+        for table in ["user_preferences", "api_keys", "refresh_tokens", "webhooks", "audit_log"] {
+            let sql = format!("SELECT COUNT(*) FROM {} WHERE user_id = $1", table);
+            let rows: i64 = sqlx::query_scalar(&sql).bind(user.id).fetch_one(&pool).await.unwrap();
+            assert_eq!(rows, 0, "queden files a {}", table);
+        }
+
+        let remaining: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM rules r JOIN devices d ON d.id = r.device_id WHERE d.user_id = $1"
+        )
+        .bind(other.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(remaining, 1);
+        assert_eq!(count("SELECT COUNT(*) FROM audit_log WHERE user_id = $1", other.id).await, 1);
+
+        // El JWT de l'usuari esborrat ja no serveix
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let req = TestRequest::get().uri("/api/auth/me").insert_header(auth_header(&user, &config)).to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_user_esios_token(pool: PgPool) {
+        use actix_web::test::read_body_json;
+        use wiremock::matchers::header as header_is;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        use crate::services::pvpc::PvpcClient;
+        use crate::services::secrets::SecretBox;
+
+        // ESIOS simulat que només accepta el token de l'usuari (i no té preus)
+        let esios = MockServer::start().await;
+        Mock::given(header_is("x-api-key", "user-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": [] } })))
+            .mount(&esios)
+            .await;
+
+        let config = test_config();
+        let user = create_user(&pool, "esios").await;
+        let pvpc = PvpcClient::with_token("global-token".to_string())
+            .with_base_url(esios.uri())
+            .with_retries(0, std::time::Duration::ZERO);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(pvpc))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let set_token = |token: serde_json::Value| {
+            TestRequest::patch()
+                .uri("/api/auth/me")
+                .insert_header(auth_header(&user, &config))
+                .set_json(serde_json::json!({ "esios_token": token }))
+                .to_request()
+        };
+        let explain = || {
+            TestRequest::post()
+                .uri("/api/schedule/explain")
+                .insert_header(auth_header(&user, &config))
+                .set_json(serde_json::json!({ "max_hours": 2 }))
+                .to_request()
+        };
+
+        // Amb el token global ESIOS rebutja la petició
+        assert_eq!(call_service(&app, explain()).await.status(), 502);
+
+        let body: serde_json::Value = read_body_json(call_service(&app, set_token("user-token".into())).await).await;
+        assert_eq!(body["has_esios_token"], true);
+        assert!(body.get("esios_token").is_none());
+
+        // Es desa xifrat
+        let sealed: Vec<u8> = sqlx::query_scalar("SELECT esios_token FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(SecretBox::from_config(&config).unwrap().decrypt(&sealed).as_deref(), Some("user-token"));
+
+        // Amb el token de l'usuari ESIOS respon (sense preus per la data)
+        assert_eq!(call_service(&app, explain()).await.status(), 422);
+
+        let body: serde_json::Value = read_body_json(call_service(&app, set_token(serde_json::Value::Null)).await).await;
+        assert_eq!(body["has_esios_token"], false);
+        assert_eq!(call_service(&app, explain()).await.status(), 502);
+
+        assert_eq!(call_service(&app, set_token("  ".into())).await.status(), 400);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_user_price_adjustment(pool: PgPool) {
+        use actix_web::test::read_body_json;
+        use wiremock::matchers::method;
+        use chrono::TimeZone;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        use crate::services::clock;
+        use crate::services::pvpc::PvpcClient;
+
+        // ESIOS: l'hora 3 és la més barata (50 €/MWh)
+        let config = test_config();
+        let today = clock::today(config.timezone);
+        let values: Vec<_> = (0..24)
+            .map(|hour| {
+                let local = today.and_hms_opt(hour, 0, 0).unwrap();
+                let datetime = config.timezone.from_local_datetime(&local).unwrap().to_rfc3339();
+                let value = if hour == 3 { 50.0 } else { 200.0 };
+                serde_json::json!({ "value": value, "datetime": datetime, "geo_id": 8741 })
+            })
+            .collect();
+        let esios = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": values } })),
+            )
+            .mount(&esios)
+            .await;
+
+        let user = create_user(&pool, "taxes").await;
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(esios.uri());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(pvpc))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let patch_me = |body: serde_json::Value| {
+            TestRequest::patch()
+                .uri("/api/auth/me")
+                .insert_header(auth_header(&user, &config))
+                .set_json(body)
+                .to_request()
+        };
+        let explained_price = || async {
+            let req = TestRequest::post()
+                .uri("/api/schedule/explain")
+                .insert_header(auth_header(&user, &config))
+                .set_json(serde_json::json!({ "max_hours": 1 }))
+                .to_request();
+            let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+            assert_eq!(body["optimal_hours"], serde_json::json!([3]));
+            body["total_price"].as_f64().unwrap()
+        };
+
+        assert!((explained_price().await - 0.05).abs() < 1e-9);
+
+        // 0,01 €/kWh de recàrrec i IVA del 21%
+        let adjustment = serde_json::json!({ "energy_surcharge_eur_kwh": 0.01, "tax_multiplier": 1.21 });
+        let resp = call_service(&app, patch_me(adjustment)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["tax_multiplier"], 1.21);
+        assert!((explained_price().await - 0.0726).abs() < 1e-9);
+
+        assert_eq!(call_service(&app, patch_me(serde_json::json!({ "tax_multiplier": 0.0 }))).await.status(), 400);
+        let resp = call_service(&app, patch_me(serde_json::json!({ "energy_surcharge_eur_kwh": 5.0 }))).await;
+        assert_eq!(resp.status(), 400);
+    }
 }
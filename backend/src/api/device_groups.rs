@@ -0,0 +1,422 @@
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgPool};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::DeviceGroup;
+use crate::error::{AppError, AppResult, ErrorResponse};
+
+use super::auth::extract_user_from_request;
+use super::validation::{Validate, Validated};
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateDeviceGroupRequest {
+    pub name: String,
+    /// Dispositius inicials del grup
+    pub device_ids: Option<Vec<Uuid>>,
+    /// Màxim de dispositius del grup encesos alhora: la generació reparteix les hores entre
+    /// els dispositius perquè no se superi (sense límit si no s'indica)
+    pub max_simultaneous: Option<i32>,
+}
+
+impl Validate for CreateDeviceGroupRequest {
+    fn validate(&self) -> AppResult<()> {
+        if self.name.trim().is_empty() {
+            return Err(AppError::BadRequest("DEVICE_GROUP_VALIDATION_FAILED", "name cannot be empty".to_string()));
+        }
+        if self.max_simultaneous.is_some_and(|max| max < 1) {
+            return Err(AppError::BadRequest(
+                "DEVICE_GROUP_VALIDATION_FAILED",
+                "max_simultaneous must be 1 or greater".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct AddMembersRequest {
+    pub device_ids: Vec<Uuid>,
+}
+
+impl Validate for AddMembersRequest {
+    fn validate(&self) -> AppResult<()> {
+        if self.device_ids.is_empty() {
+            return Err(AppError::BadRequest(
+                "DEVICE_GROUP_VALIDATION_FAILED",
+                "device_ids cannot be empty".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Grup amb els ids dels seus dispositius
+#[derive(Debug, FromRow)]
+struct DeviceGroupWithMembers {
+    #[sqlx(flatten)]
+    group: DeviceGroup,
+    device_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceGroupResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub device_ids: Vec<Uuid>,
+    pub max_simultaneous: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<DeviceGroupWithMembers> for DeviceGroupResponse {
+    fn from(g: DeviceGroupWithMembers) -> Self {
+        Self {
+            id: g.group.id,
+            name: g.group.name,
+            device_ids: g.device_ids,
+            max_simultaneous: g.group.max_simultaneous,
+            created_at: g.group.created_at,
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_device_groups)
+        .service(create_device_group)
+        .service(delete_device_group)
+        .service(add_device_group_members);
+}
+
+/// GET /api/device-groups
+#[utoipa::path(
+    tag = "device-groups",
+    responses(
+        (status = 200, description = "Grups de l'usuari amb els seus dispositius", body = Vec<DeviceGroupResponse>),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/device-groups")]
+async fn list_device_groups(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let groups = sqlx::query_as::<_, DeviceGroupWithMembers>(
+        r#"
+        SELECT g.*,
+               ARRAY(SELECT m.device_id FROM device_group_members m WHERE m.group_id = g.id ORDER BY m.device_id) as device_ids
+        FROM device_groups g
+        WHERE g.user_id = $1
+        ORDER BY g.name, g.id
+        "#
+    )
+    .bind(user.id)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let response: Vec<DeviceGroupResponse> = groups.into_iter().map(Into::into).collect();
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// POST /api/device-groups
+#[utoipa::path(
+    tag = "device-groups",
+    request_body = CreateDeviceGroupRequest,
+    responses(
+        (status = 201, description = "Grup creat", body = DeviceGroupResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/device-groups")]
+async fn create_device_group(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: Validated<CreateDeviceGroupRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let mut tx = pool.begin().await?;
+
+    let group = sqlx::query_as::<_, DeviceGroup>(
+        "INSERT INTO device_groups (user_id, name, max_simultaneous) VALUES ($1, $2, $3) RETURNING *"
+    )
+    .bind(user.id)
+    .bind(body.name.trim())
+    .bind(body.max_simultaneous)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let device_ids = body.device_ids.clone().unwrap_or_default();
+    add_members(&mut tx, group.id, user.id, &device_ids).await?;
+    let group = fetch_group(&mut tx, group.id).await?;
+
+    tx.commit().await?;
+
+    tracing::info!("Grup de dispositius {} creat per l'usuari {}", group.group.id, user.id);
+
+    Ok(HttpResponse::Created().json(DeviceGroupResponse::from(group)))
+}
+
+/// DELETE /api/device-groups/{id}
+/// Elimina el grup i les regles que l'utilitzen
+#[utoipa::path(
+    tag = "device-groups",
+    params(("id" = Uuid, Path, description = "Id del grup")),
+    responses(
+        (status = 204, description = "Grup eliminat"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Grup no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[delete("/device-groups/{id}")]
+async fn delete_device_group(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let group_id = path.into_inner();
+
+    let result = sqlx::query(
+        "DELETE FROM device_groups WHERE id = $1 AND user_id = $2"
+    )
+    .bind(group_id)
+    .bind(user.id)
+    .execute(pool.get_ref())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("DEVICE_GROUP_NOT_FOUND", "Device group not found".to_string()));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// POST /api/device-groups/{id}/members
+/// Afegeix dispositius al grup (els que ja hi són s'ignoren)
+#[utoipa::path(
+    tag = "device-groups",
+    params(("id" = Uuid, Path, description = "Id del grup")),
+    request_body = AddMembersRequest,
+    responses(
+        (status = 200, description = "Grup amb els dispositius afegits", body = DeviceGroupResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Grup o dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/device-groups/{id}/members")]
+async fn add_device_group_members(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    body: Validated<AddMembersRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let group_id = path.into_inner();
+
+    let mut tx = pool.begin().await?;
+
+    // Bloqueja el grup perquè no s'esborri mentre s'hi afegeixen dispositius
+    sqlx::query_scalar::<_, Uuid>(
+        "SELECT id FROM device_groups WHERE id = $1 AND user_id = $2 FOR UPDATE"
+    )
+    .bind(group_id)
+    .bind(user.id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("DEVICE_GROUP_NOT_FOUND", "Device group not found".to_string()))?;
+
+    add_members(&mut tx, group_id, user.id, &body.device_ids).await?;
+    let group = fetch_group(&mut tx, group_id).await?;
+
+    tx.commit().await?;
+
+    Ok(HttpResponse::Ok().json(DeviceGroupResponse::from(group)))
+}
+
+/// Afegeix dispositius de l'usuari a un grup. Falla si algun no existeix o és d'un altre usuari.
+async fn add_members(
+    conn: &mut PgConnection,
+    group_id: Uuid,
+    user_id: Uuid,
+    device_ids: &[Uuid],
+) -> AppResult<()> {
+    if device_ids.is_empty() {
+        return Ok(());
+    }
+
+    let owned: Vec<Uuid> = sqlx::query_scalar(
+        "SELECT id FROM devices WHERE id = ANY($1) AND user_id = $2 AND deleted_at IS NULL"
+    )
+    .bind(device_ids)
+    .bind(user_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if let Some(missing) = device_ids.iter().find(|id| !owned.contains(id)) {
+        return Err(AppError::NotFound("DEVICE_NOT_FOUND", format!("Device not found: {}", missing)));
+    }
+
+    sqlx::query(
+        r#"
+        INSERT INTO device_group_members (group_id, device_id)
+        SELECT $1, UNNEST($2::uuid[])
+        ON CONFLICT DO NOTHING
+        "#
+    )
+    .bind(group_id)
+    .bind(&owned)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+async fn fetch_group(conn: &mut PgConnection, group_id: Uuid) -> AppResult<DeviceGroupWithMembers> {
+    let group = sqlx::query_as::<_, DeviceGroupWithMembers>(
+        r#"
+        SELECT g.*,
+               ARRAY(SELECT m.device_id FROM device_group_members m WHERE m.group_id = g.id ORDER BY m.device_id) as device_ids
+        FROM device_groups g
+        WHERE g.id = $1
+        "#
+    )
+    .bind(group_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(group)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+
+    use crate::services::pvpc::PvpcClient;
+    use crate::services::schedule_events::ScheduleEvents;
+    use crate::services::webhooks::WebhookDispatcher;
+    use crate::test_utils::{auth_header, create_device, create_user, test_config};
+
+    #[test]
+    fn test_validate_device_group_requests() {
+        let create = |name: &str, max_simultaneous| CreateDeviceGroupRequest {
+            name: name.to_string(),
+            device_ids: None,
+            max_simultaneous,
+        };
+        assert!(create("Termos", None).validate().is_ok());
+        assert!(create("  ", None).validate().is_err());
+        assert!(create("Radiadors", Some(1)).validate().is_ok());
+        assert!(create("Radiadors", Some(0)).validate().is_err());
+
+        assert!(AddMembersRequest { device_ids: vec![Uuid::new_v4()] }.validate().is_ok());
+        assert!(AddMembersRequest { device_ids: vec![] }.validate().is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_device_groups_crud(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "groups").await;
+        let other = create_user(&pool, "other").await;
+        let first = create_device(&pool, user.id, "Termo 1").await;
+        let second = create_device(&pool, user.id, "Termo 2").await;
+        let foreign = create_device(&pool, other.id, "Termo aliè").await;
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(PvpcClient::with_token("test".to_string())))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/api/device-groups")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "name": "Termos", "device_ids": [first.id], "max_simultaneous": 1 }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let group: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(group["max_simultaneous"], 1);
+        let group_id: Uuid = group["id"].as_str().unwrap().parse().unwrap();
+
+        // Un dispositiu d'un altre usuari no es pot afegir
+        let req = TestRequest::post()
+            .uri(&format!("/api/device-groups/{}/members", group_id))
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "device_ids": [second.id, foreign.id] }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 404);
+
+        let req = TestRequest::post()
+            .uri(&format!("/api/device-groups/{}/members", group_id))
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "device_ids": [second.id, first.id] }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let group: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(group["device_ids"].as_array().unwrap().len(), 2);
+
+        let req = TestRequest::post()
+            .uri("/api/rules")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({
+                "device_group_id": group_id,
+                "name": "Termos nit",
+                "max_hours": 2,
+                "generate_now": false,
+            }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 201);
+        let rule: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(rule["device_group_name"], "Termos");
+        assert!(rule["device_id"].is_null());
+        let req = TestRequest::get()
+            .uri("/api/device-groups")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let groups: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(groups.as_array().unwrap().len(), 1);
+
+        // Esborrar el grup elimina les seves regles
+        let req = TestRequest::delete()
+            .uri(&format!("/api/device-groups/{}", group_id))
+            .insert_header(auth_header(&other, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 404);
+
+        let req = TestRequest::delete()
+            .uri(&format!("/api/device-groups/{}", group_id))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 204);
+
+        let rules: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rules").fetch_one(&pool).await.unwrap();
+        assert_eq!(rules, 0);
+    }
+}
@@ -1,35 +1,165 @@
-use actix_web::{delete, get, patch, post, web, HttpRequest, HttpResponse};
+use std::collections::{HashMap, HashSet};
+
+use actix_web::http::header::{EntityTag, IfNoneMatch, ETag};
+use actix_web::{delete, get, patch, post, web, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use shared::normalize_device_type;
+use sqlx::{FromRow, PgPool};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 use crate::config::Config;
 use crate::db::models::Device;
-use crate::error::{AppError, AppResult};
+use crate::error::{AppError, AppResult, ErrorResponse};
+use crate::services::clock;
+use crate::services::device_plan::{build_plan, PlanEntry, PlannedAction};
+use crate::services::scheduled_actions::{cancel_pending_actions, PendingScope};
 
 use super::auth::extract_user_from_request;
+use super::pagination::{PageQuery, Paginated};
+use super::rules::{RuleResponse, RuleWithDevice, ScheduleGenerationInfo};
+use super::validation::{FieldErrors, Validate, Validated};
+
+/// Dispositius que es poden sincronitzar d'una vegada
+pub const MAX_SYNC_DEVICES: usize = 500;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SyncDevicesRequest {
+    /// Com a màxim `MAX_SYNC_DEVICES` (500), sense repetir `google_device_id`
     pub devices: Vec<SyncDeviceItem>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct SyncDeviceItem {
     pub google_device_id: String,
+    pub name: String,
+    /// Es desa normalitzat: "switch", "thermostat", "light" o "other:<original>"
+    pub device_type: Option<String>,
+    pub room: Option<String>,
+    /// Capacitats del dispositiu a Google Home (p. ex. "OnOff"). Si falta, es deixa buida.
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Validate for SyncDevicesRequest {
+    fn validate(&self) -> AppResult<()> {
+        if self.devices.len() > MAX_SYNC_DEVICES {
+            return Err(AppError::BadRequest(
+                "TOO_MANY_DEVICES",
+                format!("Cannot sync more than {} devices at once", MAX_SYNC_DEVICES),
+            ));
+        }
+
+        let mut errors = FieldErrors::default();
+        let mut seen = HashSet::new();
+        for (i, device) in self.devices.iter().enumerate() {
+            if device.google_device_id.trim().is_empty() {
+                errors.add(format!("devices[{}].google_device_id", i), "EMPTY", "google_device_id cannot be empty");
+            } else if !seen.insert(device.google_device_id.as_str()) {
+                errors.add(
+                    format!("devices[{}].google_device_id", i),
+                    "DUPLICATE",
+                    "google_device_id appears more than once",
+                );
+            }
+            if device.name.trim().is_empty() {
+                errors.add(format!("devices[{}].name", i), "EMPTY", "name cannot be empty");
+            }
+        }
+
+        errors.into_result()
+    }
+}
+
+/// Metadades d'un sol dispositiu; el `google_device_id` va a la ruta
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ResyncDeviceRequest {
     pub name: String,
     pub device_type: Option<String>,
     pub room: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Validate for ResyncDeviceRequest {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        if self.name.trim().is_empty() {
+            errors.add("name", "EMPTY", "name cannot be empty");
+        }
+
+        errors.into_result()
+    }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct UpdateDeviceRequest {
     pub is_active: Option<bool>,
     pub name: Option<String>,
     pub google_device_id: Option<String>,
+    /// Potència nominal (W), necessària per les regles amb `max_cost_eur`
+    pub wattage_watts: Option<f64>,
+}
+
+/// Dies que cobreix el pla d'un dispositiu (avui i demà)
+const PLAN_DAYS: i64 = 2;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct DeviceListQuery {
+    /// Inclou els dispositius esborrats (per defecte, false)
+    pub include_deleted: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct DeviceRulesQuery {
+    /// Filtra per regles habilitades (true) o deshabilitades (false)
+    pub is_enabled: Option<bool>,
+}
+
+/// Dies que cobreix l'historial per defecte (fins avui)
+const DEFAULT_HISTORY_DAYS: i64 = 30;
+
+/// Estats pels quals es pot filtrar l'historial
+#[derive(Debug, Clone, Copy, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum HistoryStatus {
+    /// Qualsevol execució correcta (executed, executed_on, executed_off)
+    Executed,
+    Missed,
+    Cancelled,
 }
 
-#[derive(Debug, Serialize)]
+impl HistoryStatus {
+    /// Patró LIKE dels status de `scheduled_actions` que corresponen al filtre
+    fn status_pattern(self) -> &'static str {
+        match self {
+            HistoryStatus::Executed => "executed%",
+            HistoryStatus::Missed => "missed",
+            HistoryStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ScheduleHistoryQuery {
+    /// Primer dia (per defecte, fa 30 dies)
+    pub from: Option<NaiveDate>,
+    /// Darrer dia (per defecte, avui)
+    pub to: Option<NaiveDate>,
+    /// Només les accions amb aquest estat
+    pub status: Option<HistoryStatus>,
+}
+
+/// Schedules de l'última data generada per una regla
+#[derive(Debug, FromRow)]
+struct LastGeneration {
+    rule_id: Uuid,
+    scheduled_date: NaiveDate,
+    count: i64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct DeviceResponse {
     pub id: Uuid,
     pub google_device_id: String,
@@ -37,6 +167,70 @@ pub struct DeviceResponse {
     pub device_type: Option<String>,
     pub room: Option<String>,
     pub is_active: bool,
+    pub wattage_watts: Option<f64>,
+    pub capabilities: Vec<String>,
+    /// Només informat pels dispositius esborrats (`include_deleted=true`)
+    pub deleted_at: Option<DateTime<Utc>>,
+}
+
+/// Pla que ha d'executar el controlador d'un dispositiu
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DevicePlanResponse {
+    pub device_id: Uuid,
+    /// Augmenta cada cop que canvia alguna acció programada del dispositiu
+    pub plan_version: i64,
+    /// Primer dia del pla (avui); el pla cobreix avui i demà
+    pub from: NaiveDate,
+    /// Trams sense solapaments, ordenats i dividits a mitjanit
+    pub entries: Vec<PlanEntry>,
+}
+
+/// Accions d'un dispositiu, de la més recent a la més antiga
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeviceHistoryResponse {
+    pub device_id: Uuid,
+    pub device_name: String,
+    pub actions: Vec<ScheduleHistoryItem>,
+}
+
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct ScheduleHistoryItem {
+    pub id: Uuid,
+    pub date: NaiveDate,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub status: String,
+    /// Preu de la franja programada (€/kWh)
+    pub price_per_kwh: Option<f64>,
+    /// Regla que va programar l'acció
+    pub rule_name: String,
+}
+
+/// Resultat de la sincronització, separat segons el que ha canviat a la BD
+#[derive(Debug, Default, Serialize, ToSchema)]
+pub struct SyncDevicesResponse {
+    /// Dispositius nous
+    pub created: Vec<DeviceResponse>,
+    /// Dispositius existents amb nom, tipus o habitació canviats
+    pub updated: Vec<DeviceResponse>,
+    /// Dispositius existents sense cap canvi
+    pub unchanged: Vec<DeviceResponse>,
+}
+
+/// Fila retornada per l'upsert de la sincronització
+#[derive(Debug, FromRow)]
+struct UpsertedDevice {
+    #[sqlx(flatten)]
+    device: Device,
+    /// `xmax = 0`: la fila l'ha creat aquest INSERT (no és una actualització)
+    inserted: bool,
+}
+
+/// Resultat de l'upsert d'un dispositiu sincronitzat
+enum SyncOutcome {
+    Created(Device),
+    Updated(Device),
+    Unchanged(Device),
 }
 
 impl From<Device> for DeviceResponse {
@@ -48,6 +242,9 @@ impl From<Device> for DeviceResponse {
             device_type: d.device_type,
             room: d.room,
             is_active: d.is_active,
+            wattage_watts: d.wattage_watts,
+            capabilities: d.capabilities,
+            deleted_at: d.deleted_at,
         }
     }
 }
@@ -55,72 +252,237 @@ impl From<Device> for DeviceResponse {
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(list_devices)
         .service(sync_devices)
+        .service(resync_device)
         .service(update_device)
-        .service(delete_device);
+        .service(delete_device)
+        .service(cancel_pending_device_actions)
+        .service(list_device_rules)
+        .service(get_device_schedule_history)
+        .service(get_device_plan);
 }
 
 /// GET /api/devices
+#[utoipa::path(
+    tag = "devices",
+    params(DeviceListQuery, PageQuery),
+    responses(
+        (status = 200, description = "Dispositius de l'usuari", body = Paginated<DeviceResponse>),
+        (status = 400, description = "Paràmetres de paginació invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[get("/devices")]
 async fn list_devices(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
+    query: web::Query<DeviceListQuery>,
+    page: web::Query<PageQuery>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let page = page.page()?;
+    let include_deleted = query.include_deleted.unwrap_or(false);
 
-    let devices = sqlx::query_as::<_, Device>(
-        "SELECT * FROM devices WHERE user_id = $1 ORDER BY name"
+    let total: i64 = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!" FROM devices WHERE user_id = $1 AND ($2 OR deleted_at IS NULL)"#,
+        user.id,
+        include_deleted
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    let devices = sqlx::query_as!(
+        Device,
+        r#"
+        SELECT * FROM devices
+        WHERE user_id = $1 AND ($2 OR deleted_at IS NULL)
+        ORDER BY name, id
+        LIMIT $3 OFFSET $4
+        "#,
+        user.id,
+        include_deleted,
+        page.limit,
+        page.offset
     )
-    .bind(user.id)
     .fetch_all(pool.get_ref())
     .await?;
 
-    let response: Vec<DeviceResponse> = devices.into_iter().map(Into::into).collect();
-    Ok(HttpResponse::Ok().json(response))
+    let items: Vec<DeviceResponse> = devices.into_iter().map(Into::into).collect();
+    Ok(HttpResponse::Ok().json(Paginated::new(items, total, page)))
 }
 
 /// POST /api/devices/sync
 /// Sincronitza els dispositius des de l'app Android
+#[utoipa::path(
+    tag = "devices",
+    request_body = SyncDevicesRequest,
+    responses(
+        (status = 200, description = "Dispositius sincronitzats: nous, actualitzats i sense canvis", body = SyncDevicesResponse),
+        (status = 400, description = "Més de 500 dispositius", body = ErrorResponse),
+        (status = 422, description = "Dispositius amb camps invàlids o repetits", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[post("/devices/sync")]
 async fn sync_devices(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
-    body: web::Json<SyncDevicesRequest>,
+    body: Validated<SyncDevicesRequest>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
 
-    let mut synced_devices = Vec::new();
+    let mut response = SyncDevicesResponse::default();
 
-    for device_data in &body.devices {
-        // Upsert: insertar o actualitzar si ja existeix
-        let device = sqlx::query_as::<_, Device>(
-            r#"
-            INSERT INTO devices (user_id, google_device_id, name, device_type, room)
-            VALUES ($1, $2, $3, $4, $5)
-            ON CONFLICT (user_id, google_device_id)
-            DO UPDATE SET
-                name = EXCLUDED.name,
-                device_type = EXCLUDED.device_type,
-                room = EXCLUDED.room
-            RETURNING *
-            "#
-        )
-        .bind(user.id)
-        .bind(&device_data.google_device_id)
-        .bind(&device_data.name)
-        .bind(&device_data.device_type)
-        .bind(&device_data.room)
-        .fetch_one(pool.get_ref())
-        .await?;
+    for outcome in upsert_devices(pool.get_ref(), user.id, &body.devices).await? {
+        match outcome {
+            SyncOutcome::Created(device) => response.created.push(device.into()),
+            SyncOutcome::Updated(device) => response.updated.push(device.into()),
+            SyncOutcome::Unchanged(device) => response.unchanged.push(device.into()),
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// POST /api/devices/{google_device_id}/resync
+/// Sincronitza un sol dispositiu de l'usuari: el crea si és nou o n'actualitza les metadades
+#[utoipa::path(
+    tag = "devices",
+    params(("google_device_id" = String, Path, description = "Id del dispositiu a Google Home")),
+    request_body = ResyncDeviceRequest,
+    responses(
+        (status = 200, description = "Dispositiu existent, actualitzat o sense canvis", body = DeviceResponse),
+        (status = 201, description = "Dispositiu nou", body = DeviceResponse),
+        (status = 422, description = "Metadades invàlides", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/devices/{google_device_id}/resync")]
+async fn resync_device(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    body: Validated<ResyncDeviceRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let body = body.0;
 
-        synced_devices.push(DeviceResponse::from(device));
+    // L'upsert només toca els dispositius de l'usuari: el mateix id de Google d'un altre
+    // usuari és un altre dispositiu
+    let item = SyncDeviceItem {
+        google_device_id: path.into_inner(),
+        name: body.name,
+        device_type: body.device_type,
+        room: body.room,
+        capabilities: body.capabilities,
+    };
+
+    let outcome = upsert_devices(pool.get_ref(), user.id, std::slice::from_ref(&item)).await?.pop();
+    match outcome.ok_or_else(|| AppError::Internal("INTERNAL_ERROR", "Device upsert returned nothing".to_string()))? {
+        SyncOutcome::Created(device) => {
+            tracing::info!("Dispositiu {} creat per l'usuari {}", device.id, user.id);
+            Ok(HttpResponse::Created().json(DeviceResponse::from(device)))
+        }
+        SyncOutcome::Updated(device) | SyncOutcome::Unchanged(device) => {
+            Ok(HttpResponse::Ok().json(DeviceResponse::from(device)))
+        }
     }
+}
 
-    Ok(HttpResponse::Ok().json(synced_devices))
+/// Crea o actualitza dispositius de l'usuari a partir de les dades de Google, en un sol upsert
+///
+/// Els dispositius sense cap canvi no s'actualitzen. Un dispositiu esborrat que torna a
+/// sincronitzar-se es recupera. Retorna el resultat de cada dispositiu en el mateix ordre;
+/// els `google_device_id` no es poden repetir (una fila no es pot actualitzar dues vegades).
+async fn upsert_devices(pool: &PgPool, user_id: Uuid, items: &[SyncDeviceItem]) -> AppResult<Vec<SyncOutcome>> {
+    let google_ids: Vec<&str> = items.iter().map(|i| i.google_device_id.as_str()).collect();
+    let names: Vec<&str> = items.iter().map(|i| i.name.as_str()).collect();
+    let device_types: Vec<Option<String>> =
+        items.iter().map(|i| i.device_type.as_deref().map(normalize_device_type)).collect();
+    let rooms: Vec<Option<&str>> = items.iter().map(|i| i.room.as_deref()).collect();
+    // Les capacitats de cada dispositiu tenen mida diferent: no caben en un text[][]
+    let capabilities: Vec<serde_json::Value> = items.iter().map(|i| serde_json::json!(i.capabilities)).collect();
+
+    let upserted = sqlx::query_as::<_, UpsertedDevice>(
+        r#"
+        INSERT INTO devices (user_id, google_device_id, name, device_type, room, capabilities)
+        SELECT $1, u.google_device_id, u.name, u.device_type, u.room,
+               ARRAY(SELECT jsonb_array_elements_text(u.capabilities))
+        FROM UNNEST($2::text[], $3::text[], $4::text[], $5::text[], $6::jsonb[])
+            AS u(google_device_id, name, device_type, room, capabilities)
+        ON CONFLICT (user_id, google_device_id)
+        DO UPDATE SET
+            name = EXCLUDED.name,
+            device_type = EXCLUDED.device_type,
+            room = EXCLUDED.room,
+            capabilities = EXCLUDED.capabilities,
+            deleted_at = NULL
+        WHERE (devices.name, devices.device_type, devices.room, devices.capabilities, devices.deleted_at)
+            IS DISTINCT FROM (EXCLUDED.name, EXCLUDED.device_type, EXCLUDED.room, EXCLUDED.capabilities, NULL)
+        RETURNING *, (xmax = 0) AS inserted
+        "#
+    )
+    .bind(user_id)
+    .bind(&google_ids)
+    .bind(&names)
+    .bind(&device_types)
+    .bind(&rooms)
+    .bind(&capabilities)
+    .fetch_all(pool)
+    .await?;
+
+    let mut changed: HashMap<String, UpsertedDevice> =
+        upserted.into_iter().map(|u| (u.device.google_device_id.clone(), u)).collect();
+
+    // La resta ja existien sense canvis: l'upsert no les retorna
+    let unchanged_ids: Vec<&str> =
+        google_ids.iter().copied().filter(|id| !changed.contains_key(*id)).collect();
+    let mut unchanged: HashMap<String, Device> = if unchanged_ids.is_empty() {
+        HashMap::new()
+    } else {
+        sqlx::query_as!(
+            Device,
+            "SELECT * FROM devices WHERE user_id = $1 AND google_device_id = ANY($2)",
+            user_id,
+            &unchanged_ids as &[&str]
+        )
+        .fetch_all(pool)
+        .await?
+        .into_iter()
+        .map(|d| (d.google_device_id.clone(), d))
+        .collect()
+    };
+
+    let outcomes = google_ids
+        .iter()
+        .filter_map(|id| match changed.remove(*id) {
+            Some(UpsertedDevice { device, inserted: true }) => Some(SyncOutcome::Created(device)),
+            Some(UpsertedDevice { device, inserted: false }) => Some(SyncOutcome::Updated(device)),
+            None => unchanged.remove(*id).map(SyncOutcome::Unchanged),
+        })
+        .collect();
+
+    Ok(outcomes)
 }
 
 /// PATCH /api/devices/{id}
+#[utoipa::path(
+    tag = "devices",
+    params(("id" = Uuid, Path, description = "Id del dispositiu")),
+    request_body = UpdateDeviceRequest,
+    responses(
+        (status = 200, description = "Dispositiu actualitzat", body = DeviceResponse),
+        (status = 400, description = "Potència invàlida", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[patch("/devices/{id}")]
 async fn update_device(
     pool: web::Data<PgPool>,
@@ -132,33 +494,44 @@ async fn update_device(
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
     let device_id = path.into_inner();
 
+    if body.wattage_watts.is_some_and(|w| !w.is_finite() || w <= 0.0) {
+        return Err(AppError::BadRequest(
+            "DEVICE_VALIDATION_FAILED",
+            "wattage_watts must be greater than 0".to_string(),
+        ));
+    }
+
     // Verificar que el dispositiu pertany a l'usuari
-    let existing = sqlx::query_as::<_, Device>(
-        "SELECT * FROM devices WHERE id = $1 AND user_id = $2"
+    let existing = sqlx::query_as!(
+        Device,
+        "SELECT * FROM devices WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL",
+        device_id,
+        user.id
     )
-    .bind(device_id)
-    .bind(user.id)
     .fetch_optional(pool.get_ref())
     .await?
-    .ok_or_else(|| AppError::NotFound("Device not found".to_string()))?;
+    .ok_or_else(|| AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()))?;
 
     // Actualitzar només els camps proporcionats
     let new_name = body.name.as_ref().unwrap_or(&existing.name);
     let new_is_active = body.is_active.unwrap_or(existing.is_active);
     let new_google_device_id = body.google_device_id.as_ref().unwrap_or(&existing.google_device_id);
+    let new_wattage_watts = body.wattage_watts.or(existing.wattage_watts);
 
-    let updated = sqlx::query_as::<_, Device>(
+    let updated = sqlx::query_as!(
+        Device,
         r#"
         UPDATE devices
-        SET name = $1, is_active = $2, google_device_id = $3
-        WHERE id = $4
+        SET name = $1, is_active = $2, google_device_id = $3, wattage_watts = $4
+        WHERE id = $5
         RETURNING *
-        "#
+        "#,
+        new_name,
+        new_is_active,
+        new_google_device_id,
+        new_wattage_watts,
+        device_id
     )
-    .bind(new_name)
-    .bind(new_is_active)
-    .bind(new_google_device_id)
-    .bind(device_id)
     .fetch_one(pool.get_ref())
     .await?;
 
@@ -166,6 +539,17 @@ async fn update_device(
 }
 
 /// DELETE /api/devices/{id}
+/// Esborrat lògic: les regles i l'historial d'accions es conserven, però les accions pendents es cancel·len
+#[utoipa::path(
+    tag = "devices",
+    params(("id" = Uuid, Path, description = "Id del dispositiu")),
+    responses(
+        (status = 204, description = "Dispositiu eliminat"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[delete("/devices/{id}")]
 async fn delete_device(
     pool: web::Data<PgPool>,
@@ -176,17 +560,939 @@ async fn delete_device(
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
     let device_id = path.into_inner();
 
-    let result = sqlx::query(
-        "DELETE FROM devices WHERE id = $1 AND user_id = $2"
+    let mut tx = pool.begin().await?;
+
+    let result = sqlx::query!(
+        "UPDATE devices SET deleted_at = NOW() WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL",
+        device_id,
+        user.id
     )
-    .bind(device_id)
-    .bind(user.id)
-    .execute(pool.get_ref())
+    .execute(&mut *tx)
     .await?;
 
     if result.rows_affected() == 0 {
-        return Err(AppError::NotFound("Device not found".to_string()));
+        return Err(AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()));
     }
 
+    let cancelled = sqlx::query!(
+        "UPDATE scheduled_actions SET status = 'cancelled' WHERE device_id = $1 AND status = 'pending'",
+        device_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    tracing::info!(
+        "Dispositiu {} esborrat, {} accions pendents cancel·lades",
+        device_id,
+        cancelled.rows_affected()
+    );
+
     Ok(HttpResponse::NoContent().finish())
 }
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CancelPendingResponse {
+    pub device_id: Uuid,
+    /// Accions pendents cancel·lades
+    pub cancelled: u64,
+}
+
+/// POST /api/devices/{id}/cancel-pending
+/// Cancel·la totes les accions pendents del dispositiu que encara no han començat, de totes les seves regles
+#[utoipa::path(
+    tag = "devices",
+    params(("id" = Uuid, Path, description = "Id del dispositiu")),
+    responses(
+        (status = 200, description = "Accions pendents cancel·lades", body = CancelPendingResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/devices/{id}/cancel-pending")]
+async fn cancel_pending_device_actions(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let device_id = path.into_inner();
+
+    sqlx::query_as!(
+        Device,
+        "SELECT * FROM devices WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL",
+        device_id,
+        user.id
+    )
+    .fetch_optional(pool.get_ref())
+    .await?
+    .ok_or_else(|| AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()))?;
+
+    let cancelled = cancel_pending_actions(pool.get_ref(), PendingScope::Device(device_id), config.timezone).await?;
+
+    Ok(HttpResponse::Ok().json(CancelPendingResponse { device_id, cancelled }))
+}
+
+/// GET /api/devices/{id}/rules
+/// Regles d'un dispositiu, amb els schedules de l'última data generada per cada regla
+#[utoipa::path(
+    tag = "devices",
+    params(("id" = Uuid, Path, description = "Id del dispositiu"), DeviceRulesQuery, PageQuery),
+    responses(
+        (status = 200, description = "Regles del dispositiu", body = Paginated<RuleResponse>),
+        (status = 400, description = "Paràmetres de paginació invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/devices/{id}/rules")]
+async fn list_device_rules(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<DeviceRulesQuery>,
+    page: web::Query<PageQuery>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let device_id = path.into_inner();
+    let page = page.page()?;
+
+    // Verificar que el dispositiu pertany a l'usuari
+    sqlx::query_as!(
+        Device,
+        "SELECT * FROM devices WHERE id = $1 AND user_id = $2",
+        device_id,
+        user.id
+    )
+    .fetch_optional(pool.get_ref())
+    .await?
+    .ok_or_else(|| AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()))?;
+
+    let total: i64 = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!" FROM rules WHERE device_id = $1 AND ($2::boolean IS NULL OR is_enabled = $2)"#,
+        device_id,
+        query.is_enabled
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    let rules = sqlx::query_as::<_, RuleWithDevice>(
+        r#"
+        SELECT r.*, d.name as device_name, NULL::text as device_group_name, lp.weights as load_profile
+        FROM rules r
+        JOIN devices d ON r.device_id = d.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE d.id = $1 AND ($2::boolean IS NULL OR r.is_enabled = $2)
+        ORDER BY r.name, r.id
+        LIMIT $3 OFFSET $4
+        "#
+    )
+    .bind(device_id)
+    .bind(query.is_enabled)
+    .bind(page.limit)
+    .bind(page.offset)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let rule_ids: Vec<Uuid> = rules.iter().map(|r| r.rule.id).collect();
+
+    // Nombre de schedules de l'última data amb schedules de cada regla
+    let generations = sqlx::query_as!(
+        LastGeneration,
+        r#"
+        SELECT sa.rule_id, sa.scheduled_date, COUNT(*) as "count!"
+        FROM scheduled_actions sa
+        WHERE sa.rule_id = ANY($1)
+          AND sa.scheduled_date = (
+              SELECT MAX(s2.scheduled_date) FROM scheduled_actions s2 WHERE s2.rule_id = sa.rule_id
+          )
+        GROUP BY sa.rule_id, sa.scheduled_date
+        "#,
+        &rule_ids
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let items: Vec<RuleResponse> = rules
+        .into_iter()
+        .map(|rule| {
+            let generation = generations.iter().find(|g| g.rule_id == rule.rule.id);
+            let mut response = RuleResponse::from(rule);
+            response.schedule_info = Some(match generation {
+                Some(g) => ScheduleGenerationInfo {
+                    schedules_created: g.count as usize,
+                    message: format!("{} schedules generats per {}", g.count, g.scheduled_date),
+                },
+                None => ScheduleGenerationInfo {
+                    schedules_created: 0,
+                    message: "Encara no s'han generat schedules per aquesta regla".to_string(),
+                },
+            });
+            response
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(Paginated::new(items, total, page)))
+}
+
+/// GET /api/devices/{id}/schedule-history?from=&to=&status=
+/// Accions que ha tingut programades el dispositiu, de la més recent a la més antiga
+#[utoipa::path(
+    tag = "devices",
+    params(("id" = Uuid, Path, description = "Id del dispositiu"), ScheduleHistoryQuery, PageQuery),
+    responses(
+        (status = 200, description = "Historial del dispositiu", body = DeviceHistoryResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/devices/{id}/schedule-history")]
+async fn get_device_schedule_history(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<ScheduleHistoryQuery>,
+    page: web::Query<PageQuery>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let page = page.page()?;
+
+    let to = query.to.unwrap_or_else(|| clock::today(config.timezone));
+    let from = query.from.unwrap_or(to - chrono::Duration::days(DEFAULT_HISTORY_DAYS));
+    if from > to {
+        return Err(AppError::BadRequest("INVALID_DATE_RANGE", "from must be before or equal to to".to_string()));
+    }
+
+    // Els dispositius esborrats conserven l'historial
+    let device = sqlx::query_as!(
+        Device,
+        "SELECT * FROM devices WHERE id = $1 AND user_id = $2",
+        path.into_inner(),
+        user.id
+    )
+    .fetch_optional(pool.get_ref())
+    .await?
+    .ok_or_else(|| AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()))?;
+
+    let actions = sqlx::query_as!(
+        ScheduleHistoryItem,
+        r#"
+        SELECT sa.id, sa.scheduled_date as date, sa.start_time, sa.end_time, sa.status,
+               sa.price_per_kwh::float8 as price_per_kwh, r.name as rule_name
+        FROM scheduled_actions sa
+        JOIN rules r ON sa.rule_id = r.id
+        JOIN devices d ON sa.device_id = d.id
+        WHERE d.id = $1 AND d.user_id = $2
+          AND sa.scheduled_date BETWEEN $3 AND $4
+          AND ($5::text IS NULL OR sa.status LIKE $5)
+        ORDER BY sa.scheduled_date DESC, sa.start_time DESC, sa.id
+        LIMIT $6 OFFSET $7
+        "#,
+        device.id,
+        user.id,
+        from,
+        to,
+        query.status.map(HistoryStatus::status_pattern),
+        page.limit,
+        page.offset
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(DeviceHistoryResponse {
+        device_id: device.id,
+        device_name: device.name,
+        actions,
+    }))
+}
+
+/// GET /api/devices/{id}/plan
+/// Pla d'avui i demà que ha d'executar el controlador del dispositiu
+///
+/// L'ETag depèn de `plan_version` i del dia: amb `If-None-Match` es respon 304 sense
+/// llegir les accions si el pla no ha canviat.
+#[utoipa::path(
+    tag = "devices",
+    params(("id" = Uuid, Path, description = "Id del dispositiu")),
+    responses(
+        (status = 200, description = "Pla del dispositiu", body = DevicePlanResponse),
+        (status = 304, description = "El pla no ha canviat des de l'ETag indicat"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/devices/{id}/plan")]
+async fn get_device_plan(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let device_id = path.into_inner();
+    let today = clock::today(config.timezone);
+
+    // La versió es llegeix abans que les accions: si canvien entremig, el següent
+    // sondeig veurà una versió nova i tornarà a llegir el pla
+    let device = sqlx::query_as!(
+        Device,
+        "SELECT * FROM devices WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL",
+        device_id,
+        user.id
+    )
+    .fetch_optional(pool.get_ref())
+    .await?
+    .ok_or_else(|| AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()))?;
+
+    let etag = EntityTag::new_strong(format!("{}-{}", today, device.plan_version));
+    let unchanged = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    if unchanged {
+        return Ok(HttpResponse::NotModified().insert_header(ETag(etag)).finish());
+    }
+
+    // Inclou ahir per les accions que creuen mitjanit i acaben avui
+    let actions = sqlx::query_as!(
+        PlannedAction,
+        r#"
+        SELECT sa.id, sa.scheduled_date, sa.start_time, sa.end_time, sa.action, r.priority
+        FROM scheduled_actions sa
+        JOIN rules r ON sa.rule_id = r.id
+        WHERE sa.device_id = $1
+          AND sa.scheduled_date BETWEEN $2::date - 1 AND $2::date + ($3::int - 1)
+          AND sa.status NOT IN ('cancelled', 'missed', 'superseded')
+        ORDER BY sa.scheduled_date, sa.start_time
+        "#,
+        device.id,
+        today,
+        PLAN_DAYS as i32
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().insert_header(ETag(etag)).json(DevicePlanResponse {
+        device_id: device.id,
+        plan_version: device.plan_version,
+        from: today,
+        entries: build_plan(&actions, today, PLAN_DAYS),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    use crate::db::models::User;
+    use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+    #[actix_web::test]
+    async fn test_sync_devices_reports_every_invalid_field() {
+        let item = |google_device_id: &str, name: &str| SyncDeviceItem {
+            google_device_id: google_device_id.to_string(),
+            name: name.to_string(),
+            device_type: None,
+            room: None,
+            capabilities: vec![],
+        };
+        let request = SyncDevicesRequest {
+            devices: vec![item("google-1", "Termo"), item("", " "), item("google-3", "")],
+        };
+
+        match request.validate() {
+            Err(AppError::Validation(errors)) => {
+                let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+                assert_eq!(fields, vec!["devices[1].google_device_id", "devices[1].name", "devices[2].name"]);
+            }
+            other => panic!("s'esperava un error de validació: {:?}", other),
+        }
+        assert!(SyncDevicesRequest { devices: vec![item("google-1", "Termo")] }.validate().is_ok());
+
+        let repeated = SyncDevicesRequest { devices: vec![item("google-1", "Termo"), item("google-1", "Termo 2")] };
+        match repeated.validate() {
+            Err(AppError::Validation(errors)) => {
+                assert_eq!(errors[0].field, "devices[1].google_device_id");
+                assert_eq!(errors[0].code, "DUPLICATE");
+            }
+            other => panic!("s'esperava un error de validació: {:?}", other),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_sync_devices_cap() {
+        let devices = |n: usize| SyncDevicesRequest {
+            devices: (0..n)
+                .map(|i| SyncDeviceItem {
+                    google_device_id: format!("google-{}", i),
+                    name: format!("Endoll {}", i),
+                    device_type: None,
+                    room: None,
+                    capabilities: vec![],
+                })
+                .collect(),
+        };
+        assert!(devices(MAX_SYNC_DEVICES).validate().is_ok());
+        assert!(matches!(devices(MAX_SYNC_DEVICES + 1).validate(), Err(AppError::BadRequest("TOO_MANY_DEVICES", _))));
+    }
+
+    async fn get_as(pool: &PgPool, user: &User, uri: &str) -> actix_web::dev::ServiceResponse {
+        let config = test_config();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(auth_header(user, &config))
+            .to_request();
+        test::call_service(&app, req).await
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_device_rules_ownership(pool: PgPool) {
+        let owner = create_user(&pool, "owner").await;
+        let other = create_user(&pool, "other").await;
+        let device = create_device(&pool, owner.id, "Rentadora").await;
+        create_rule(&pool, device.id, "Nit", true).await;
+
+        let uri = format!("/api/devices/{}/rules", device.id);
+
+        let resp = get_as(&pool, &owner, &uri).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["items"].as_array().unwrap().len(), 1);
+        assert_eq!(body["items"][0]["schedule_info"]["schedules_created"], 0);
+
+        // Un altre usuari no pot veure les regles del dispositiu
+        let resp = get_as(&pool, &other, &uri).await;
+        assert_eq!(resp.status(), 404);
+
+        let resp = get_as(&pool, &owner, &format!("/api/devices/{}/rules", Uuid::new_v4())).await;
+        assert_eq!(resp.status(), 404);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_device_rules_enabled_filter(pool: PgPool) {
+        let owner = create_user(&pool, "owner").await;
+        let device = create_device(&pool, owner.id, "Termo").await;
+        let enabled = create_rule(&pool, device.id, "Activa", true).await;
+        let disabled = create_rule(&pool, device.id, "Inactiva", false).await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, '2024-06-10', '02:00', '03:00'),
+                   ($1, $2, '2024-06-11', '03:00', '04:00'),
+                   ($1, $2, '2024-06-11', '04:00', '05:00')
+            "#
+        )
+        .bind(enabled.id)
+        .bind(device.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let base = format!("/api/devices/{}/rules", device.id);
+
+        let resp = get_as(&pool, &owner, &base).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["items"].as_array().unwrap().len(), 2);
+        assert_eq!(body["total"], 2);
+
+        // Paginació: el total no depèn de la pàgina
+        let resp = get_as(&pool, &owner, &format!("{}?limit=1&offset=1", base)).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["items"].as_array().unwrap().len(), 1);
+        assert_eq!(body["items"][0]["id"], disabled.id.to_string());
+        assert_eq!(body["total"], 2);
+
+        let resp = get_as(&pool, &owner, &format!("{}?is_enabled=true", base)).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["items"].as_array().unwrap().len(), 1);
+        assert_eq!(body["items"][0]["id"], enabled.id.to_string());
+        // Només compta els schedules de l'última data generada
+        assert_eq!(body["items"][0]["schedule_info"]["schedules_created"], 2);
+
+        let resp = get_as(&pool, &owner, &format!("{}?is_enabled=false", base)).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["items"].as_array().unwrap().len(), 1);
+        assert_eq!(body["items"][0]["id"], disabled.id.to_string());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_cancel_pending_only_future_actions_of_device(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "cancel").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let other_device = create_device(&pool, user.id, "Rentadora").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        let other_rule = create_rule(&pool, other_device.id, "Nit", true).await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, status)
+            VALUES ($1, $2, '2000-01-01', '02:00', '03:00', 'pending'),
+                   ($1, $2, '2100-01-01', '02:00', '03:00', 'pending'),
+                   ($1, $2, '2100-01-02', '03:00', '04:00', 'pending'),
+                   ($1, $2, '2100-01-03', '02:00', '03:00', 'executed'),
+                   ($3, $4, '2100-01-01', '02:00', '03:00', 'pending')
+            "#,
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .bind(other_rule.id)
+        .bind(other_device.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/devices/{}/cancel-pending", device.id))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["cancelled"], 2);
+
+        let statuses: Vec<String> = sqlx::query_scalar(
+            "SELECT status FROM scheduled_actions ORDER BY device_id = $1 DESC, scheduled_date",
+        )
+        .bind(device.id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        // Les passades, les ja executades i les d'altres dispositius no es toquen
+        assert_eq!(statuses, vec!["pending", "cancelled", "cancelled", "executed", "pending"]);
+
+        // Un dispositiu d'un altre usuari no es troba
+        let other = create_user(&pool, "other").await;
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/devices/{}/cancel-pending", device.id))
+            .insert_header(auth_header(&other, &config))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_sync_devices_reports_changes(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "sync").await;
+        let same = create_device(&pool, user.id, "Termo").await;
+        let renamed = create_device(&pool, user.id, "Rentadora").await;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/devices/sync")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({
+                "devices": [
+                    { "google_device_id": same.google_device_id, "name": "Termo" },
+                    { "google_device_id": renamed.google_device_id, "name": "Rentadora", "room": "Safareig" },
+                    { "google_device_id": "google-new", "name": "Assecadora", "device_type": "OUTLET" },
+                ]
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["created"].as_array().unwrap().len(), 1);
+        assert_eq!(body["created"][0]["google_device_id"], "google-new");
+        assert_eq!(body["created"][0]["device_type"], "other:OUTLET");
+        assert_eq!(body["updated"].as_array().unwrap().len(), 1);
+        assert_eq!(body["updated"][0]["id"], renamed.id.to_string());
+        assert_eq!(body["updated"][0]["room"], "Safareig");
+        assert_eq!(body["unchanged"].as_array().unwrap().len(), 1);
+        assert_eq!(body["unchanged"][0]["id"], same.id.to_string());
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM devices WHERE user_id = $1")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_sync_devices_batched_upsert(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "batch-sync").await;
+        let deleted = create_device(&pool, user.id, "Assecadora").await;
+        sqlx::query("UPDATE devices SET deleted_at = NOW() WHERE id = $1")
+            .bind(deleted.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let sync = |devices: serde_json::Value| {
+            test::TestRequest::post()
+                .uri("/api/devices/sync")
+                .insert_header(auth_header(&user, &config))
+                .set_json(serde_json::json!({ "devices": devices }))
+                .to_request()
+        };
+        let devices = serde_json::json!([
+            { "google_device_id": "google-b", "name": "Termo", "capabilities": ["OnOff", "Brightness"] },
+            { "google_device_id": deleted.google_device_id, "name": "Assecadora" },
+            { "google_device_id": "google-a", "name": "Cotxe", "room": "Garatge", "capabilities": ["OnOff"] },
+        ]);
+
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, sync(devices.clone())).await).await;
+        let ids = |list: &serde_json::Value| -> Vec<String> {
+            list.as_array().unwrap().iter().map(|d| d["google_device_id"].as_str().unwrap().to_string()).collect()
+        };
+        // En l'ordre de la petició; l'esborrat es recupera
+        assert_eq!(ids(&body["created"]), vec!["google-b", "google-a"]);
+        assert_eq!(ids(&body["updated"]), vec![deleted.google_device_id.clone()]);
+        assert!(body["updated"][0]["deleted_at"].is_null());
+        assert_eq!(body["created"][0]["capabilities"], serde_json::json!(["OnOff", "Brightness"]));
+        assert_eq!(body["created"][1]["room"], "Garatge");
+
+        // La mateixa sincronització no canvia res
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, sync(devices)).await).await;
+        assert!(body["created"].as_array().unwrap().is_empty());
+        assert!(body["updated"].as_array().unwrap().is_empty());
+        let unchanged = vec!["google-b".to_string(), deleted.google_device_id.clone(), "google-a".to_string()];
+        assert_eq!(ids(&body["unchanged"]), unchanged);
+
+        // Massa dispositius: 400 sense tocar la BD
+        let too_many: Vec<serde_json::Value> = (0..=MAX_SYNC_DEVICES)
+            .map(|i| serde_json::json!({ "google_device_id": format!("google-{}", i), "name": "Endoll" }))
+            .collect();
+        let resp = test::call_service(&app, sync(serde_json::json!(too_many))).await;
+        assert_eq!(resp.status(), 400);
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM devices WHERE user_id = $1")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 3);
+
+        // Un cos més gran que el límit de JSON es rebutja abans de deserialitzar-lo
+        let oversized = "x".repeat(crate::api::MAX_JSON_PAYLOAD_BYTES);
+        let resp = test::call_service(&app, sync(serde_json::json!([{ "google_device_id": "g", "name": oversized }])));
+        let resp = resp.await;
+        assert_eq!(resp.status(), 413);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_resync_single_device(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "resync").await;
+        let other = create_user(&pool, "other").await;
+        let existing = create_device(&pool, user.id, "Termo").await;
+        let foreign = create_device(&pool, other.id, "Termo aliè").await;
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let resync = |google_device_id: &str, body: serde_json::Value| {
+            test::TestRequest::post()
+                .uri(&format!("/api/devices/{}/resync", google_device_id))
+                .insert_header(auth_header(&user, &config))
+                .set_json(body)
+                .to_request()
+        };
+
+        // Actualitza el dispositiu existent
+        let body = serde_json::json!({ "name": "Termo nou", "room": "Bany", "capabilities": ["OnOff"] });
+        let resp = test::call_service(&app, resync(&existing.google_device_id, body.clone())).await;
+        assert_eq!(resp.status(), 200);
+        let device: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(device["id"], existing.id.to_string());
+        assert_eq!(device["name"], "Termo nou");
+        assert_eq!(device["room"], "Bany");
+        assert_eq!(device["capabilities"], serde_json::json!(["OnOff"]));
+
+        let resp = test::call_service(&app, resync(&existing.google_device_id, body)).await;
+        assert_eq!(resp.status(), 200);
+
+        // Un id de Google nou (o d'un altre usuari) crea un dispositiu de l'usuari
+        let resp = test::call_service(&app, resync("google-new", serde_json::json!({ "name": "Assecadora" }))).await;
+        assert_eq!(resp.status(), 201);
+        let device: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(device["capabilities"], serde_json::json!([]));
+
+        let body = serde_json::json!({ "name": "Meu" });
+        let resp = test::call_service(&app, resync(&foreign.google_device_id, body)).await;
+        assert_eq!(resp.status(), 201);
+        let device: serde_json::Value = test::read_body_json(resp).await;
+        assert_ne!(device["id"], foreign.id.to_string());
+
+        let foreign_name: String = sqlx::query_scalar("SELECT name FROM devices WHERE id = $1")
+            .bind(foreign.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(foreign_name, "Termo aliè");
+
+        let resp = test::call_service(&app, resync("google-new", serde_json::json!({ "name": " " }))).await;
+        assert_eq!(resp.status(), 422);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_soft_deleted_device_keeps_history(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "soft").await;
+        let admin = create_user(&pool, "admin").await;
+        sqlx::query("UPDATE users SET is_admin = true WHERE id = $1")
+            .bind(admin.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, status)
+            VALUES ($1, $2, '2024-06-10', '02:00', '03:00', 'executed'),
+                   ($1, $2, '2030-01-01', '02:00', '03:00', 'pending')
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let get = |uri: &str, user: &User| {
+            test::TestRequest::get().uri(uri).insert_header(auth_header(user, &config)).to_request()
+        };
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("/api/devices/{}", device.id))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 204);
+
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, get("/api/devices", &user)).await).await;
+        assert_eq!(body["total"], 0);
+
+        let resp = test::call_service(&app, get("/api/devices?include_deleted=true", &user)).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["total"], 1);
+        assert!(body["items"][0]["deleted_at"].is_string());
+
+        // L'historial es conserva i les accions pendents es cancel·len
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, get("/api/schedule/2024-06-10", &user)).await).await;
+        assert_eq!(body.as_array().unwrap().len(), 1);
+        assert_eq!(body[0]["status"], "executed");
+        let body: serde_json::Value = test::read_body_json(test::call_service(&app, get("/api/schedule/2030-01-01", &user)).await).await;
+        assert_eq!(body[0]["status"], "cancelled");
+
+        // Només un administrador pot esborrar-lo definitivament
+        let purge = |user: &User| {
+            test::TestRequest::delete()
+                .uri(&format!("/api/admin/devices/{}", device.id))
+                .insert_header(auth_header(user, &config))
+                .to_request()
+        };
+        assert_eq!(test::call_service(&app, purge(&user)).await.status(), 403);
+        assert_eq!(test::call_service(&app, purge(&admin)).await.status(), 204);
+
+        let actions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scheduled_actions")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(actions, 0);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_device_plan_etag(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "plan").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+
+        let action_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, $3, '02:00', '03:00')
+            RETURNING id
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .bind(clock::today(config.timezone))
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let get_plan = |etag: Option<&str>| {
+            let mut req = test::TestRequest::get()
+                .uri(&format!("/api/devices/{}/plan", device.id))
+                .insert_header(auth_header(&user, &config));
+            if let Some(etag) = etag {
+                req = req.insert_header((actix_web::http::header::IF_NONE_MATCH, etag.to_string()));
+            }
+            req.to_request()
+        };
+
+        let resp = test::call_service(&app, get_plan(None)).await;
+        assert_eq!(resp.status(), 200);
+        let etag = resp.headers().get(actix_web::http::header::ETAG).unwrap().to_str().unwrap().to_string();
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        let version = body["plan_version"].as_i64().unwrap();
+        assert_eq!(body["entries"].as_array().unwrap().len(), 1);
+        assert_eq!(body["entries"][0]["start_time"], "02:00:00");
+
+        // Sense canvis: 304
+        let resp = test::call_service(&app, get_plan(Some(&etag))).await;
+        assert_eq!(resp.status(), 304);
+
+        // Cancel·lar l'acció canvia la versió i el pla
+        sqlx::query("UPDATE scheduled_actions SET status = 'cancelled' WHERE id = $1")
+            .bind(action_id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let resp = test::call_service(&app, get_plan(Some(&etag))).await;
+        assert_eq!(resp.status(), 200);
+        assert_ne!(resp.headers().get(actix_web::http::header::ETAG).unwrap().to_str().unwrap(), etag);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert!(body["plan_version"].as_i64().unwrap() > version);
+        assert!(body["entries"].as_array().unwrap().is_empty());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_device_schedule_history(pool: PgPool) {
+        let user = create_user(&pool, "history").await;
+        let other = create_user(&pool, "other").await;
+        let termo = create_device(&pool, user.id, "Termo").await;
+        let rentadora = create_device(&pool, user.id, "Rentadora").await;
+        let other_device = create_device(&pool, other.id, "Aliè").await;
+        let nit = create_rule(&pool, termo.id, "Nit", false).await;
+        let tarda = create_rule(&pool, termo.id, "Tarda", false).await;
+        let rentar = create_rule(&pool, rentadora.id, "Rentar", false).await;
+        let alie = create_rule(&pool, other_device.id, "Aliè", false).await;
+
+        let today = clock::today(test_config().timezone);
+        for (rule, days_ago, hour, status) in [
+            (&nit, 1, 2, "executed_on"),
+            (&nit, 2, 3, "missed"),
+            (&tarda, 1, 15, "cancelled"),
+            (&tarda, 40, 16, "executed"),
+            (&rentar, 1, 4, "executed"),
+            (&alie, 1, 5, "executed"),
+        ] {
+            sqlx::query(
+                r#"
+                INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, status,
+                                               price_per_kwh)
+                VALUES ($1, $2, $3, make_time($4, 0, 0), make_time($4 + 1, 0, 0), $5, 0.1)
+                "#
+            )
+            .bind(rule.id)
+            .bind(rule.device_id)
+            .bind(today - chrono::Duration::days(days_ago))
+            .bind(hour)
+            .bind(status)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let uri = |query: &str| format!("/api/devices/{}/schedule-history{}", termo.id, query);
+        let rules_of = |body: &serde_json::Value| -> Vec<(String, String)> {
+            body["actions"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|a| (a["rule_name"].as_str().unwrap().to_string(), a["status"].as_str().unwrap().to_string()))
+                .collect()
+        };
+        let pair = |rule: &str, status: &str| (rule.to_string(), status.to_string());
+
+        // Per defecte, els darrers 30 dies del dispositiu, de la més recent a la més antiga
+        let resp = get_as(&pool, &user, &uri("")).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["device_name"], "Termo");
+        assert_eq!(
+            rules_of(&body),
+            vec![pair("Tarda", "cancelled"), pair("Nit", "executed_on"), pair("Nit", "missed")]
+        );
+        assert_eq!(body["actions"][0]["price_per_kwh"], 0.1);
+
+        let resp = get_as(&pool, &user, &uri("?status=executed")).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(rules_of(&body), vec![pair("Nit", "executed_on")]);
+
+        let query = format!("?from={}&limit=1&offset=3", today - chrono::Duration::days(60));
+        let resp = get_as(&pool, &user, &uri(&query)).await;
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(rules_of(&body), vec![pair("Tarda", "executed")]);
+
+        assert_eq!(get_as(&pool, &user, &uri("?status=pending")).await.status(), 400);
+        assert_eq!(get_as(&pool, &other, &uri("")).await.status(), 404);
+        let unknown = format!("/api/devices/{}/schedule-history", Uuid::new_v4());
+        assert_eq!(get_as(&pool, &user, &unknown).await.status(), 404);
+    }
+}
@@ -0,0 +1,637 @@
+//! Autodiagnòstic del compte: "per què no funciona la meva configuració?"
+//!
+//! Revisa dispositius, regles i accions programades de l'usuari i en retorna els problemes
+//! trobats amb una gravetat. Només llegeix: no regenera ni modifica res.
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::Rule;
+use crate::error::{AppResult, ErrorResponse};
+use crate::services::clock;
+
+use super::auth::extract_user_from_request;
+
+/// Dies anteriors (avui inclòs) en què es busquen regles sense hores i dispositius sense execucions
+const RECENT_DAYS: i64 = 7;
+
+/// Motius d'omissió que indiquen que la regla no troba hores (i no que no li toqui)
+const NO_HOURS_REASONS: [&str; 3] = ["no_eligible_hours", "above_baseline", "device_limit"];
+
+/// Gravetat d'un problema, de menys a més greu
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    /// Pot ser intencionat, però convé saber-ho
+    Info,
+    /// Probablement alguna acció no s'executarà com s'espera
+    Warning,
+    /// Segur que alguna acció no s'executarà
+    Error,
+}
+
+/// Tipus de problema detectat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueCode {
+    /// Dispositiu actiu sense cap regla
+    DeviceWithoutRules,
+    /// Regla habilitada d'un dispositiu desactivat o esborrat
+    RuleOnInactiveDevice,
+    /// Regla habilitada que no ha programat cap hora els darrers dies, o que ja no s'aplicarà
+    RuleNeverSchedules,
+    /// Dues regles del mateix dispositiu amb accions que se solapen
+    OverlappingRules,
+    /// Dispositiu amb accions passades però cap d'executada els darrers dies
+    DeviceNotSeen,
+    /// Un dia en què alguna regla s'aplica no té accions ni motius d'omissió
+    ScheduleNotGenerated,
+    /// Totes les regles del dia s'han omès
+    AllRulesSkipped,
+    /// Encara no hi ha preus per demà: els schedules es generaran quan es publiquin
+    PricesNotPublished,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct DiagnosticIssue {
+    pub code: IssueCode,
+    pub severity: Severity,
+    /// Descripció del problema per mostrar a l'usuari
+    pub message: String,
+    pub device_id: Option<Uuid>,
+    pub rule_id: Option<Uuid>,
+    /// Dia afectat, pels problemes de la programació d'avui o demà
+    pub date: Option<NaiveDate>,
+}
+
+impl DiagnosticIssue {
+    fn new(code: IssueCode, severity: Severity, message: String) -> Self {
+        Self { code, severity, message, device_id: None, rule_id: None, date: None }
+    }
+
+    fn device(mut self, device_id: Uuid) -> Self {
+        self.device_id = Some(device_id);
+        self
+    }
+
+    fn rule(mut self, rule_id: Uuid) -> Self {
+        self.rule_id = Some(rule_id);
+        self
+    }
+
+    fn date(mut self, date: NaiveDate) -> Self {
+        self.date = Some(date);
+        self
+    }
+}
+
+/// Resultat del diagnòstic
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DiagnoseResponse {
+    /// Cap problema de gravetat `warning` o `error`
+    pub healthy: bool,
+    pub checked_at: DateTime<Utc>,
+    /// Problemes trobats, dels més greus als menys
+    pub issues: Vec<DiagnosticIssue>,
+}
+
+impl DiagnoseResponse {
+    fn new(mut issues: Vec<DiagnosticIssue>, checked_at: DateTime<Utc>) -> Self {
+        // Ordenació estable: dins d'una gravetat es manté l'ordre de les comprovacions
+        issues.sort_by_key(|i| std::cmp::Reverse(i.severity));
+        Self {
+            healthy: issues.iter().all(|i| i.severity == Severity::Info),
+            checked_at,
+            issues,
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct DeviceRow {
+    id: Uuid,
+    name: String,
+}
+
+#[derive(Debug, FromRow)]
+struct RuleDeviceRow {
+    rule_id: Uuid,
+    rule_name: String,
+    device_id: Uuid,
+    device_name: String,
+}
+
+#[derive(Debug, FromRow)]
+struct SilentRuleRow {
+    id: Uuid,
+    name: String,
+    active_until: Option<NaiveDate>,
+    /// Motiu de l'omissió més recent dels darrers dies
+    last_reason: Option<String>,
+}
+
+#[derive(Debug, FromRow)]
+struct OverlapRow {
+    rule_id: Uuid,
+    rule_name: String,
+    other_rule_name: String,
+    device_id: Uuid,
+    device_name: String,
+    /// Una regla encén i l'altra apaga
+    opposite: bool,
+}
+
+#[derive(Debug, FromRow)]
+struct UnseenDeviceRow {
+    id: Uuid,
+    name: String,
+    last_executed_at: Option<DateTime<Utc>>,
+}
+
+/// Accions i omissions d'un dia
+#[derive(Debug, FromRow)]
+struct DayCounts {
+    actions: i64,
+    skipped: i64,
+    has_prices: bool,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(diagnose);
+}
+
+/// GET /api/diagnose
+/// Revisa la configuració de l'usuari i retorna els problemes que impedeixen que funcioni
+#[utoipa::path(
+    tag = "diagnose",
+    responses(
+        (status = 200, description = "Problemes trobats", body = DiagnoseResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/diagnose")]
+async fn diagnose(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let pool = pool.get_ref();
+    let today = clock::today(config.timezone);
+    let tomorrow = today + chrono::Duration::days(1);
+
+    let mut issues = Vec::new();
+    issues.extend(devices_without_rules(pool, user.id).await?);
+    issues.extend(rules_on_inactive_devices(pool, user.id).await?);
+    issues.extend(rules_never_scheduling(pool, user.id, today).await?);
+    issues.extend(overlapping_rules(pool, user.id, today, tomorrow).await?);
+    issues.extend(devices_not_seen(pool, user.id, today).await?);
+
+    let rules = enabled_rules(pool, user.id).await?;
+    for date in [today, tomorrow] {
+        issues.extend(day_schedule(pool, user.id, &rules, date, date == tomorrow).await?);
+    }
+
+    Ok(HttpResponse::Ok().json(DiagnoseResponse::new(issues, Utc::now())))
+}
+
+async fn devices_without_rules(pool: &PgPool, user_id: Uuid) -> AppResult<Vec<DiagnosticIssue>> {
+    let devices = sqlx::query_as::<_, DeviceRow>(
+        r#"
+        SELECT d.id, d.name
+        FROM devices d
+        WHERE d.user_id = $1 AND d.is_active AND d.deleted_at IS NULL
+          AND NOT EXISTS (SELECT 1 FROM rule_devices rd WHERE rd.device_id = d.id)
+        ORDER BY d.name
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(devices
+        .into_iter()
+        .map(|d| {
+            let message = format!("El dispositiu '{}' no té cap regla", d.name);
+            DiagnosticIssue::new(IssueCode::DeviceWithoutRules, Severity::Info, message).device(d.id)
+        })
+        .collect())
+}
+
+async fn rules_on_inactive_devices(pool: &PgPool, user_id: Uuid) -> AppResult<Vec<DiagnosticIssue>> {
+    let rows = sqlx::query_as::<_, RuleDeviceRow>(
+        r#"
+        SELECT r.id as rule_id, r.name as rule_name, d.id as device_id, d.name as device_name
+        FROM rules r
+        JOIN rule_devices rd ON rd.rule_id = r.id
+        JOIN devices d ON d.id = rd.device_id
+        WHERE d.user_id = $1 AND r.is_enabled AND (NOT d.is_active OR d.deleted_at IS NOT NULL)
+        ORDER BY r.name, d.name
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let message = format!(
+                "La regla '{}' està habilitada però el dispositiu '{}' està desactivat o esborrat",
+                row.rule_name, row.device_name
+            );
+            DiagnosticIssue::new(IssueCode::RuleOnInactiveDevice, Severity::Error, message)
+                .rule(row.rule_id)
+                .device(row.device_id)
+        })
+        .collect())
+}
+
+/// Regles habilitades que ja no s'aplicaran (temporada acabada) o que els darrers dies
+/// només s'han omès per falta d'hores
+async fn rules_never_scheduling(pool: &PgPool, user_id: Uuid, today: NaiveDate) -> AppResult<Vec<DiagnosticIssue>> {
+    let since = today - chrono::Duration::days(RECENT_DAYS - 1);
+    let rows = sqlx::query_as::<_, SilentRuleRow>(
+        r#"
+        SELECT r.id, r.name, r.active_until,
+               (SELECT s.reason FROM skipped_actions s
+                WHERE s.rule_id = r.id AND s.scheduled_date BETWEEN $3 AND $2
+                ORDER BY s.scheduled_date DESC LIMIT 1) as last_reason
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        WHERE COALESCE(d.user_id, g.user_id) = $1 AND r.is_enabled
+          AND (
+              r.active_until < $2
+              OR (
+                  EXISTS (
+                      SELECT 1 FROM skipped_actions s
+                      WHERE s.rule_id = r.id AND s.scheduled_date BETWEEN $3 AND $2 AND s.reason = ANY($4)
+                  )
+                  AND NOT EXISTS (
+                      SELECT 1 FROM scheduled_actions sa
+                      WHERE sa.rule_id = r.id AND sa.scheduled_date BETWEEN $3 AND $2
+                  )
+              )
+          )
+        ORDER BY r.name
+        "#
+    )
+    .bind(user_id)
+    .bind(today)
+    .bind(since)
+    .bind(&NO_HOURS_REASONS[..])
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let message = match row.active_until.filter(|until| *until < today) {
+                Some(until) => format!("La temporada de la regla '{}' va acabar el {}", row.name, until),
+                None => format!(
+                    "La regla '{}' no ha programat cap hora els darrers {} dies (motiu: {})",
+                    row.name,
+                    RECENT_DAYS,
+                    row.last_reason.as_deref().unwrap_or("desconegut")
+                ),
+            };
+            DiagnosticIssue::new(IssueCode::RuleNeverSchedules, Severity::Warning, message).rule(row.id)
+        })
+        .collect())
+}
+
+/// Regles del mateix dispositiu amb accions solapades entre `from` i `to`. Si una encén i
+/// l'altra apaga, el dispositiu rep ordres contradictòries.
+async fn overlapping_rules(
+    pool: &PgPool,
+    user_id: Uuid,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> AppResult<Vec<DiagnosticIssue>> {
+    let rows = sqlx::query_as::<_, OverlapRow>(
+        r#"
+        SELECT a.rule_id, ra.name as rule_name, rb.name as other_rule_name,
+               d.id as device_id, d.name as device_name, bool_or(a.action <> b.action) as opposite
+        FROM scheduled_actions a
+        JOIN scheduled_actions b
+          ON b.device_id = a.device_id AND b.scheduled_date = a.scheduled_date AND a.rule_id < b.rule_id
+        JOIN devices d ON d.id = a.device_id
+        JOIN rules ra ON ra.id = a.rule_id
+        JOIN rules rb ON rb.id = b.rule_id
+        WHERE d.user_id = $1 AND a.scheduled_date BETWEEN $2 AND $3
+          AND a.status NOT IN ('cancelled', 'superseded') AND b.status NOT IN ('cancelled', 'superseded')
+          AND a.start_time::interval
+              < b.end_time::interval + CASE WHEN b.crosses_midnight THEN INTERVAL '1 day' ELSE INTERVAL '0' END
+          AND b.start_time::interval
+              < a.end_time::interval + CASE WHEN a.crosses_midnight THEN INTERVAL '1 day' ELSE INTERVAL '0' END
+        GROUP BY a.rule_id, ra.name, b.rule_id, rb.name, d.id, d.name
+        ORDER BY d.name, ra.name, rb.name
+        "#
+    )
+    .bind(user_id)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let (severity, detail) = if row.opposite {
+                (Severity::Warning, "amb accions contràries")
+            } else {
+                (Severity::Info, "amb la mateixa acció")
+            };
+            let message = format!(
+                "Les regles '{}' i '{}' programen el dispositiu '{}' a les mateixes hores {}",
+                row.rule_name, row.other_rule_name, row.device_name, detail
+            );
+            DiagnosticIssue::new(IssueCode::OverlappingRules, severity, message)
+                .rule(row.rule_id)
+                .device(row.device_id)
+        })
+        .collect())
+}
+
+/// Dispositius amb accions dels darrers dies (abans d'avui) que no n'han executat cap: l'app
+/// no hi arriba o no està en marxa
+async fn devices_not_seen(pool: &PgPool, user_id: Uuid, today: NaiveDate) -> AppResult<Vec<DiagnosticIssue>> {
+    let since = today - chrono::Duration::days(RECENT_DAYS - 1);
+    let devices = sqlx::query_as::<_, UnseenDeviceRow>(
+        r#"
+        SELECT d.id, d.name,
+               (SELECT MAX(e.executed_at) FROM scheduled_actions e
+                WHERE e.device_id = d.id AND e.status LIKE 'executed%') as last_executed_at
+        FROM devices d
+        JOIN scheduled_actions sa ON sa.device_id = d.id
+        WHERE d.user_id = $1 AND d.is_active AND d.deleted_at IS NULL
+          AND sa.scheduled_date BETWEEN $2 AND $3 - 1
+          AND sa.status NOT IN ('cancelled', 'superseded')
+        GROUP BY d.id, d.name
+        HAVING COUNT(*) FILTER (WHERE sa.status LIKE 'executed%') = 0
+        ORDER BY d.name
+        "#
+    )
+    .bind(user_id)
+    .bind(since)
+    .bind(today)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(devices
+        .into_iter()
+        .map(|d| {
+            let last = match d.last_executed_at {
+                Some(at) => format!("l'última execució va ser el {}", at.format("%Y-%m-%d %H:%M UTC")),
+                None => "no se n'ha executat mai cap acció".to_string(),
+            };
+            let message = format!(
+                "El dispositiu '{}' no ha executat cap acció els darrers {} dies ({})",
+                d.name, RECENT_DAYS, last
+            );
+            DiagnosticIssue::new(IssueCode::DeviceNotSeen, Severity::Warning, message).device(d.id)
+        })
+        .collect())
+}
+
+async fn enabled_rules(pool: &PgPool, user_id: Uuid) -> AppResult<Vec<Rule>> {
+    let rules = sqlx::query_as::<_, Rule>(
+        r#"
+        SELECT r.*
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        WHERE COALESCE(d.user_id, g.user_id) = $1 AND r.is_enabled
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rules)
+}
+
+/// Si un dia en què s'aplica alguna regla no té cap acció programada, el motiu probable
+async fn day_schedule(
+    pool: &PgPool,
+    user_id: Uuid,
+    rules: &[Rule],
+    date: NaiveDate,
+    is_tomorrow: bool,
+) -> AppResult<Option<DiagnosticIssue>> {
+    if !rules.iter().any(|r| r.runs_on(date)) {
+        return Ok(None);
+    }
+
+    let rule_ids: Vec<Uuid> = rules.iter().map(|r| r.id).collect();
+    let counts = sqlx::query_as::<_, DayCounts>(
+        r#"
+        SELECT
+            (SELECT COUNT(*) FROM scheduled_actions sa JOIN devices d ON sa.device_id = d.id
+             WHERE d.user_id = $1 AND sa.scheduled_date = $2 AND sa.status NOT IN ('cancelled', 'superseded')
+            ) as actions,
+            (SELECT COUNT(*) FROM skipped_actions s WHERE s.rule_id = ANY($3) AND s.scheduled_date = $2) as skipped,
+            EXISTS (SELECT 1 FROM cached_prices cp WHERE cp.price_date = $2) as has_prices
+        "#
+    )
+    .bind(user_id)
+    .bind(date)
+    .bind(&rule_ids)
+    .fetch_one(pool)
+    .await?;
+
+    if counts.actions > 0 {
+        return Ok(None);
+    }
+
+    let day = if is_tomorrow { "demà" } else { "avui" };
+    let issue = if counts.skipped > 0 {
+        let message = format!("Totes les regles s'han omès per {} ({})", day, date);
+        DiagnosticIssue::new(IssueCode::AllRulesSkipped, Severity::Info, message)
+    } else if is_tomorrow && !counts.has_prices {
+        let message = format!("Encara no s'han publicat els preus de demà ({})", date);
+        DiagnosticIssue::new(IssueCode::PricesNotPublished, Severity::Info, message)
+    } else {
+        let message = format!("No s'han generat els schedules d'{} ({})", day, date);
+        DiagnosticIssue::new(IssueCode::ScheduleNotGenerated, Severity::Warning, message)
+    };
+    Ok(Some(issue.date(date)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+    use chrono::NaiveTime;
+
+    use crate::services::scheduled_actions::insert_rule_actions;
+    use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+    #[test]
+    fn test_issues_sorted_by_severity() {
+        let issue = |code, severity| DiagnosticIssue::new(code, severity, String::new());
+        let response = DiagnoseResponse::new(
+            vec![
+                issue(IssueCode::DeviceWithoutRules, Severity::Info),
+                issue(IssueCode::DeviceNotSeen, Severity::Warning),
+                issue(IssueCode::RuleOnInactiveDevice, Severity::Error),
+                issue(IssueCode::RuleNeverSchedules, Severity::Warning),
+            ],
+            Utc::now(),
+        );
+
+        let codes: Vec<IssueCode> = response.issues.iter().map(|i| i.code).collect();
+        assert_eq!(codes, vec![
+            IssueCode::RuleOnInactiveDevice,
+            IssueCode::DeviceNotSeen,
+            IssueCode::RuleNeverSchedules,
+            IssueCode::DeviceWithoutRules,
+        ]);
+        assert!(!response.healthy);
+
+        let response = DiagnoseResponse::new(vec![issue(IssueCode::AllRulesSkipped, Severity::Info)], Utc::now());
+        assert!(response.healthy);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_diagnose_reports_problems(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "diagnose").await;
+        let other = create_user(&pool, "other").await;
+        let today = clock::today(config.timezone);
+        let days_ago = |days: i64| today - chrono::Duration::days(days);
+
+        let unused = create_device(&pool, user.id, "Sense regles").await;
+        let inactive = create_device(&pool, user.id, "Desactivat").await;
+        let termo = create_device(&pool, user.id, "Termo").await;
+        sqlx::query("UPDATE devices SET is_active = false WHERE id = $1")
+            .bind(inactive.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        create_device(&pool, other.id, "Aliè").await;
+
+        let orphan = create_rule(&pool, inactive.id, "Orfe", true).await;
+        let silent = create_rule(&pool, termo.id, "Silenciosa", true).await;
+        let on = create_rule(&pool, termo.id, "Encén", true).await;
+        let off = create_rule(&pool, termo.id, "Apaga", true).await;
+
+        // "Silenciosa" no troba hores des de fa dies
+        for days in 1..4 {
+            sqlx::query(
+                "INSERT INTO skipped_actions (rule_id, scheduled_date, reason) VALUES ($1, $2, 'no_eligible_hours')"
+            )
+            .bind(silent.id)
+            .bind(days_ago(days))
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        // Ahir no s'executà res; avui "Encén" i "Apaga" se solapen a les 02:00-03:00
+        for (rule, date, start, end, action, status) in [
+            (&on, days_ago(1), "02:00", "03:00", "on", "missed"),
+            (&on, today, "01:00", "03:00", "on", "pending"),
+            (&off, today, "02:00", "04:00", "off", "pending"),
+        ] {
+            sqlx::query(
+                r#"
+                INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, action, status)
+                VALUES ($1, $2, $3, $4::time, $5::time, $6, $7)
+                "#
+            )
+            .bind(rule.id)
+            .bind(termo.id)
+            .bind(date)
+            .bind(start)
+            .bind(end)
+            .bind(action)
+            .bind(status)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let req = TestRequest::get().uri("/api/diagnose").insert_header(auth_header(&user, &config)).to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["healthy"], false);
+
+        let issues = body["issues"].as_array().unwrap();
+        let find = |code: &str| issues.iter().find(|i| i["code"] == code).unwrap_or_else(|| panic!("falta {}", code));
+        assert_eq!(issues[0]["code"], "rule_on_inactive_device");
+        assert_eq!(find("rule_on_inactive_device")["rule_id"], orphan.id.to_string());
+        assert_eq!(find("device_without_rules")["device_id"], unused.id.to_string());
+        assert_eq!(find("rule_never_schedules")["rule_id"], silent.id.to_string());
+        let overlap = find("overlapping_rules");
+        assert_eq!(overlap["severity"], "warning");
+        assert_eq!(overlap["device_id"], termo.id.to_string());
+        assert_eq!(find("device_not_seen")["device_id"], termo.id.to_string());
+        assert_eq!(find("prices_not_published")["date"], (today + chrono::Duration::days(1)).to_string());
+        assert!(issues.iter().all(|i| i["code"] != "schedule_not_generated"));
+
+        // Un usuari sense res configurat no té problemes
+        let req = TestRequest::get().uri("/api/diagnose").insert_header(auth_header(&other, &config)).to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["healthy"], true);
+        assert_eq!(body["issues"].as_array().unwrap().len(), 1);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_diagnose_ignores_superseded_overlaps(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "priority").await;
+        let termo = create_device(&pool, user.id, "Termo").await;
+        let urgent = create_rule(&pool, termo.id, "Urgent", true).await;
+        let mut normal = create_rule(&pool, termo.id, "Normal", true).await;
+        normal.priority = 2;
+        sqlx::query("UPDATE rules SET priority = 2 WHERE id = $1")
+            .bind(normal.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Les dues regles volen la mateixa franja: la de menys prioritat queda superseded
+        let today = clock::today(config.timezone);
+        let at = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        for rule in [&normal, &urgent] {
+            let created = insert_rule_actions(&mut conn, rule, None, today, at(3), (at(4), false), Some(0.05))
+                .await
+                .unwrap();
+            assert_eq!(created, 1);
+        }
+        drop(conn);
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let req = TestRequest::get().uri("/api/diagnose").insert_header(auth_header(&user, &config)).to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        let issues = body["issues"].as_array().unwrap();
+        assert!(issues.iter().all(|i| i["code"] != "overlapping_rules"), "{:?}", issues);
+    }
+}
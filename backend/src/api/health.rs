@@ -0,0 +1,345 @@
+use std::time::{Duration, Instant};
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+use sqlx::PgPool;
+
+use crate::config::Config;
+use crate::services::clock;
+use crate::services::price_history::{has_prices_for, latest_prices_age, store_prices};
+use crate::services::pvpc::PvpcClient;
+
+/// Temps màxim d'espera de la BD abans de considerar el servei degradat
+const DB_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Temps màxim d'espera de ESIOS quan els preus d'avui no són a la BD
+const ESIOS_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Registra `/health` i `/health/ready` a l'arrel (fora de `/api`, on l'esperen els balancejadors)
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(health_check).service(readiness_check);
+}
+
+/// Estat del servei
+#[derive(Debug, Serialize)]
+pub struct HealthResponse {
+    /// "ok", o "degraded" si la BD no respon
+    pub status: &'static str,
+    pub db_pool_size: u32,
+    pub db_idle_connections: u32,
+    /// Temps de resposta d'un `SELECT 1`
+    pub db_latency_ms: u64,
+    /// Segons des que es van desar els últims preus de ESIOS (None si no n'hi ha)
+    pub pvpc_cache_age_secs: Option<u64>,
+    pub uptime_secs: u64,
+}
+
+/// Resultat d'una comprovació de `/health/ready`
+#[derive(Debug, Serialize)]
+pub struct ReadinessCheck {
+    pub ok: bool,
+    /// D'on surt el resultat o per què ha fallat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl ReadinessCheck {
+    fn ok(detail: Option<&str>) -> Self {
+        Self { ok: true, detail: detail.map(str::to_string) }
+    }
+
+    fn failed(detail: impl Into<String>) -> Self {
+        Self { ok: false, detail: Some(detail.into()) }
+    }
+}
+
+/// Comprovacions de `/health/ready`
+#[derive(Debug, Serialize)]
+pub struct ReadinessChecks {
+    pub database: ReadinessCheck,
+    pub esios_token: ReadinessCheck,
+    pub today_prices: ReadinessCheck,
+}
+
+/// El servei pot atendre peticions
+#[derive(Debug, Serialize)]
+pub struct ReadinessResponse {
+    /// "ready", o "not_ready" si alguna comprovació falla
+    pub status: &'static str,
+    pub checks: ReadinessChecks,
+}
+
+/// GET /health
+/// Estat de la BD, dels preus desats i temps en marxa. Respon 503 si la BD no respon.
+/// És la prova de vida; per saber si el servei està a punt, vegeu `/health/ready`.
+#[get("/health")]
+async fn health_check(pool: web::Data<PgPool>, started_at: web::Data<Instant>) -> HttpResponse {
+    let start = Instant::now();
+    let db_ok = matches!(
+        tokio::time::timeout(DB_TIMEOUT, sqlx::query("SELECT 1").execute(pool.get_ref())).await,
+        Ok(Ok(_))
+    );
+    let db_latency = start.elapsed();
+
+    let pvpc_cache_age_secs = if db_ok {
+        latest_prices_age(&pool).await.unwrap_or_else(|e| {
+            tracing::warn!("No s'ha pogut consultar l'antiguitat dels preus: {}", e);
+            None
+        })
+    } else {
+        tracing::error!("Health check: la BD no respon");
+        None
+    };
+
+    let body = HealthResponse {
+        status: if db_ok { "ok" } else { "degraded" },
+        db_pool_size: pool.size(),
+        db_idle_connections: pool.num_idle() as u32,
+        db_latency_ms: db_latency.as_millis() as u64,
+        pvpc_cache_age_secs,
+        uptime_secs: started_at.elapsed().as_secs(),
+    };
+
+    if db_ok {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+/// GET /health/ready
+/// Respon 200 només si la BD respon, hi ha token de ESIOS i es poden obtenir els preus d'avui
+/// (desats a la BD o, si no, demanant-los a ESIOS); si no, 503 amb el detall de cada comprovació.
+/// Els preus obtinguts de ESIOS es desen, perquè les comprovacions següents no hi tornin a anar.
+#[get("/health/ready")]
+async fn readiness_check(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+) -> HttpResponse {
+    let database = match tokio::time::timeout(DB_TIMEOUT, sqlx::query("SELECT 1").execute(pool.get_ref())).await {
+        Ok(Ok(_)) => ReadinessCheck::ok(None),
+        Ok(Err(e)) => ReadinessCheck::failed(format!("Database error: {}", e)),
+        Err(_) => ReadinessCheck::failed("Database did not respond"),
+    };
+
+    let esios_token = if pvpc.has_token() {
+        ReadinessCheck::ok(None)
+    } else {
+        ReadinessCheck::failed("ESIOS_TOKEN is not configured")
+    };
+
+    let today = clock::today(config.timezone);
+    let cached = database.ok && has_prices_for(&pool, today).await.unwrap_or_else(|e| {
+        tracing::warn!("No s'ha pogut comprovar si hi ha preus d'avui: {}", e);
+        false
+    });
+    let today_prices = if cached {
+        ReadinessCheck::ok(Some("cached"))
+    } else if !esios_token.ok {
+        ReadinessCheck::failed("Today's prices are not cached and there is no ESIOS token to fetch them")
+    } else {
+        // Un sol intent: la prova no ha de quedar-se esperant els reintents
+        let probe = pvpc.get_ref().clone().with_retries(0, Duration::ZERO);
+        match tokio::time::timeout(ESIOS_PROBE_TIMEOUT, probe.get_prices_for_date(today)).await {
+            Ok(Ok(prices)) if !prices.prices.is_empty() => {
+                if database.ok
+                    && let Err(e) = store_prices(&pool, &prices).await
+                {
+                    tracing::warn!("No s'han pogut desar els preus d'avui obtinguts a la readiness: {}", e);
+                }
+                ReadinessCheck::ok(Some("esios"))
+            }
+            Ok(Ok(_)) => ReadinessCheck::failed("ESIOS returned no prices for today"),
+            Ok(Err(e)) => ReadinessCheck::failed(e.to_string()),
+            Err(_) => ReadinessCheck::failed("ESIOS did not respond"),
+        }
+    };
+
+    let ready = database.ok && esios_token.ok && today_prices.ok;
+    if !ready {
+        tracing::warn!(
+            "Readiness: no està a punt (bd: {}, token: {}, preus d'avui: {})",
+            database.ok,
+            esios_token.ok,
+            today_prices.ok
+        );
+    }
+
+    let body = ReadinessResponse {
+        status: if ready { "ready" } else { "not_ready" },
+        checks: ReadinessChecks { database, esios_token, today_prices },
+    };
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{App, HttpServer};
+    use sqlx::postgres::PgPoolOptions;
+
+    #[actix_web::test]
+    async fn test_health_degraded_without_database() {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://nobody@127.0.0.1:1/none")
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(Instant::now()))
+                .configure(configure),
+        )
+        .await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/health").to_request()).await;
+        assert_eq!(resp.status(), 503);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["status"], "degraded");
+        assert_eq!(body["pvpc_cache_age_secs"], serde_json::Value::Null);
+    }
+
+    #[actix_web::test]
+    async fn test_readiness_not_ready_without_database() {
+        let pool = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(200))
+            .connect_lazy("postgres://nobody@127.0.0.1:1/none")
+            .unwrap();
+        // ESIOS tampoc respon: els preus d'avui no es poden obtenir
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url("http://127.0.0.1:1");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(crate::test_utils::test_config()))
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(Instant::now()))
+                .configure(configure),
+        )
+        .await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/health/ready").to_request()).await;
+        assert_eq!(resp.status(), 503);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["status"], "not_ready");
+        assert_eq!(body["checks"]["database"]["ok"], false);
+        assert_eq!(body["checks"]["esios_token"], serde_json::json!({ "ok": true }));
+        assert_eq!(body["checks"]["today_prices"]["ok"], false);
+        assert!(body["checks"]["today_prices"]["detail"].is_string());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_health_over_http(pool: PgPool) {
+        sqlx::query(
+            r#"
+            INSERT INTO cached_prices (price_date, hour, price, fetched_at)
+            VALUES ('2030-01-01', 0, 0.1, NOW() - INTERVAL '90 seconds')
+            "#
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let started_at = std::sync::Arc::new(Instant::now() - Duration::from_secs(60));
+        let server_pool = pool.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(server_pool.clone()))
+                .app_data(web::Data::from(started_at.clone()))
+                .configure(configure)
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let response = reqwest::get(format!("http://{}/health", addr)).await.unwrap();
+        assert_eq!(response.status(), 200);
+        let body: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(body["status"], "ok");
+        assert!(body["db_pool_size"].as_u64().unwrap() >= 1);
+        assert!(body["db_idle_connections"].is_u64());
+        assert!(body["db_latency_ms"].is_u64());
+        assert!((90..120).contains(&body["pvpc_cache_age_secs"].as_u64().unwrap()));
+        assert!(body["uptime_secs"].as_u64().unwrap() >= 60);
+
+        handle.stop(true).await;
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_readiness_ready_with_cached_prices(pool: PgPool) {
+        let config = crate::test_utils::test_config();
+        sqlx::query("INSERT INTO cached_prices (price_date, hour, price) VALUES ($1, 0, 0.1)")
+            .bind(clock::today(config.timezone))
+            .execute(&pool)
+            .await
+            .unwrap();
+        // Amb els preus a la BD no cal preguntar a ESIOS
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url("http://127.0.0.1:1");
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(pvpc))
+                .configure(configure),
+        )
+        .await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/health/ready").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["today_prices"], serde_json::json!({ "ok": true, "detail": "cached" }));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_readiness_stores_prices_from_esios(pool: PgPool) {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let config = crate::test_utils::test_config();
+        let today = clock::today(config.timezone);
+        let values: Vec<_> = (0..24)
+            .map(|hour| {
+                let datetime = format!("{}T{:02}:00:00.000+01:00", today, hour);
+                serde_json::json!({ "value": 100.0, "datetime": datetime, "geo_id": 8741 })
+            })
+            .collect();
+        // Només la primera comprovació pregunta a ESIOS
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": values } })),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool))
+                .app_data(web::Data::new(config))
+                .app_data(web::Data::new(pvpc))
+                .configure(configure),
+        )
+        .await;
+
+        for detail in ["esios", "cached"] {
+            let resp = call_service(&app, TestRequest::get().uri("/health/ready").to_request()).await;
+            assert_eq!(resp.status(), 200);
+            let body: serde_json::Value = read_body_json(resp).await;
+            assert_eq!(body["checks"]["today_prices"], serde_json::json!({ "ok": true, "detail": detail }));
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use actix_web::{get, web, HttpRequest, HttpResponse};
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult};
+use crate::services::metrics;
+
+/// Registra `/metrics` a l'arrel (fora de `/api`, on l'espera Prometheus)
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(prometheus_metrics);
+}
+
+/// GET /metrics
+/// Mètriques en format de text de Prometheus. Si hi ha `METRICS_TOKEN`, cal enviar-lo com a Bearer.
+#[get("/metrics")]
+async fn prometheus_metrics(config: web::Data<Config>, req: HttpRequest) -> AppResult<HttpResponse> {
+    if let Some(expected) = &config.metrics_token {
+        let token = req
+            .headers()
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if token != Some(expected.as_str()) {
+            return Err(AppError::Unauthorized("INVALID_METRICS_TOKEN", "Invalid metrics token".to_string()));
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics::init().render()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::App;
+
+    use crate::test_utils::test_config;
+
+    #[actix_web::test]
+    async fn test_metrics_endpoint() {
+        metrics::init();
+        metrics::record_auth::<()>("google", &Err(AppError::Unauthorized("INVALID_TOKEN", "invalid".to_string())));
+        metrics::record_schedules_generated(3);
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(test_config()))
+                .configure(configure),
+        )
+        .await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        assert!(body.contains(r#"pvpc_auth_attempts_total{method="google",result="failure"}"#), "{}", body);
+        assert!(body.contains("pvpc_schedules_generated_total"), "{}", body);
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_token() {
+        let config = Config {
+            metrics_token: Some("scrape".to_string()),
+            ..test_config()
+        };
+        let app = init_service(App::new().app_data(web::Data::new(config)).configure(configure)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(resp.status(), 401);
+
+        let req = TestRequest::get()
+            .uri("/metrics")
+            .insert_header(("Authorization", "Bearer scrape"))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+    }
+}
@@ -1,18 +1,44 @@
+pub mod admin;
+pub mod api_keys;
 pub mod auth;
+pub mod device_groups;
+pub mod diagnose;
 pub mod devices;
+pub mod health;
+pub mod metrics;
+pub mod named_windows;
+pub mod openapi;
+pub mod pagination;
 pub mod prices;
 pub mod rules;
 pub mod schedule;
+pub mod validation;
+pub mod webhooks;
+pub mod ws;
 
 use actix_web::web;
 
+/// Mida màxima d'un cos JSON (bytes). Els més grans es rebutgen amb 413 sense llegir-los sencers.
+/// Hi caben els 500 dispositius de `/devices/sync` (`devices::MAX_SYNC_DEVICES`).
+pub const MAX_JSON_PAYLOAD_BYTES: usize = 512 * 1024;
+
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(
         web::scope("/api")
+            .app_data(web::JsonConfig::default().limit(MAX_JSON_PAYLOAD_BYTES))
             .configure(auth::configure)
+            .configure(api_keys::configure)
+            .configure(admin::configure)
             .configure(devices::configure)
+            .configure(device_groups::configure)
+            .configure(named_windows::configure)
             .configure(rules::configure)
             .configure(prices::configure)
-            .configure(schedule::configure),
-    );
+            .configure(schedule::configure)
+            .configure(diagnose::configure)
+            .configure(webhooks::configure)
+            .configure(ws::configure),
+    )
+    .configure(health::configure)
+    .configure(metrics::configure);
 }
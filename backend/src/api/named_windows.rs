@@ -0,0 +1,537 @@
+//! Finestres horàries amb nom ("nit" = 22:00-08:00) que l'usuari reutilitza entre regles
+//!
+//! Una regla creada amb `named_window` en copia les hores i en guarda la referència. Quan
+//! es canvien les hores d'una finestra, amb `propagate_to_rules` les regles que la segueixen
+//! s'actualitzen (i es regeneren les seves accions); si no, deixen de seguir-la i es queden
+//! les hores que tenien.
+
+use actix_web::{delete, get, patch, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgConnection, PgPool};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::{NamedWindow, Rule};
+use crate::error::{AppError, AppResult, ErrorResponse, FieldError};
+use crate::services::pvpc::PvpcClient;
+use crate::services::schedule_events::ScheduleEvents;
+use crate::services::scheduler::time_window_hours;
+use crate::services::secrets::pvpc_client_for_user;
+use crate::services::webhooks::WebhookDispatcher;
+
+use super::auth::extract_user_from_request;
+use super::rules::{notify_schedules_generated, regenerate_rules, ScheduleGenerationInfo};
+use super::validation::{FieldErrors, Validate, Validated};
+
+/// Longitud màxima del nom d'una finestra
+const MAX_NAME_LEN: usize = 100;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateNamedWindowRequest {
+    pub name: String,
+    /// Si és posterior a `end_time`, la finestra creua mitjanit
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+}
+
+impl Validate for CreateNamedWindowRequest {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        errors.check(validate_name(&self.name));
+        errors.check(validate_window(self.start_time, self.end_time));
+        errors.into_result()
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct UpdateNamedWindowRequest {
+    pub name: Option<String>,
+    pub start_time: Option<NaiveTime>,
+    pub end_time: Option<NaiveTime>,
+    /// Si és true i canvien les hores, les regles que segueixen la finestra s'actualitzen.
+    /// Per defecte, false: les regles deixen de seguir-la i es queden les hores que tenien.
+    pub propagate_to_rules: Option<bool>,
+}
+
+/// Només valida els camps presents; l'amplada de la finestra amb un sol extrem es
+/// comprova al handler amb l'altre extrem actual
+impl Validate for UpdateNamedWindowRequest {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        if let Some(name) = &self.name {
+            errors.check(validate_name(name));
+        }
+        if let (Some(start), Some(end)) = (self.start_time, self.end_time) {
+            errors.check(validate_window(start, end));
+        }
+        errors.into_result()
+    }
+}
+
+/// Finestra amb els ids de les regles que la segueixen
+#[derive(Debug, FromRow)]
+struct NamedWindowWithRules {
+    #[sqlx(flatten)]
+    window: NamedWindow,
+    rule_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NamedWindowResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    /// Regles que segueixen la finestra
+    pub rule_ids: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+    /// Accions regenerades per les regles actualitzades (només en propagar un canvi d'hores)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub schedule_info: Option<ScheduleGenerationInfo>,
+}
+
+impl From<NamedWindowWithRules> for NamedWindowResponse {
+    fn from(w: NamedWindowWithRules) -> Self {
+        Self {
+            id: w.window.id,
+            name: w.window.name,
+            start_time: w.window.start_time,
+            end_time: w.window.end_time,
+            rule_ids: w.rule_ids,
+            created_at: w.window.created_at,
+            schedule_info: None,
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(list_named_windows)
+        .service(create_named_window)
+        .service(update_named_window)
+        .service(delete_named_window);
+}
+
+/// GET /api/named-windows
+#[utoipa::path(
+    tag = "named-windows",
+    responses(
+        (status = 200, description = "Finestres de l'usuari", body = Vec<NamedWindowResponse>),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/named-windows")]
+async fn list_named_windows(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let windows = sqlx::query_as::<_, NamedWindowWithRules>(
+        r#"
+        SELECT w.*, ARRAY(SELECT r.id FROM rules r WHERE r.named_window_id = w.id ORDER BY r.id) as rule_ids
+        FROM named_windows w
+        WHERE w.user_id = $1
+        ORDER BY w.name, w.id
+        "#
+    )
+    .bind(user.id)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let response: Vec<NamedWindowResponse> = windows.into_iter().map(Into::into).collect();
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// POST /api/named-windows
+#[utoipa::path(
+    tag = "named-windows",
+    request_body = CreateNamedWindowRequest,
+    responses(
+        (status = 201, description = "Finestra creada", body = NamedWindowResponse),
+        (status = 422, description = "Camps invàlids o nom repetit", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/named-windows")]
+async fn create_named_window(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: Validated<CreateNamedWindowRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let window = sqlx::query_as::<_, NamedWindow>(
+        "INSERT INTO named_windows (user_id, name, start_time, end_time) VALUES ($1, $2, $3, $4) RETURNING *"
+    )
+    .bind(user.id)
+    .bind(body.name.trim())
+    .bind(body.start_time)
+    .bind(body.end_time)
+    .fetch_one(pool.get_ref())
+    .await
+    .map_err(duplicate_name)?;
+
+    tracing::info!("Finestra '{}' creada per l'usuari {}", window.name, user.id);
+
+    Ok(HttpResponse::Created().json(NamedWindowResponse::from(NamedWindowWithRules { window, rule_ids: vec![] })))
+}
+
+/// PATCH /api/named-windows/{id}
+/// Canvia el nom o les hores de la finestra
+#[utoipa::path(
+    tag = "named-windows",
+    params(("id" = Uuid, Path, description = "Id de la finestra")),
+    request_body = UpdateNamedWindowRequest,
+    responses(
+        (status = 200, description = "Finestra actualitzada", body = NamedWindowResponse),
+        (status = 422, description = "Camps invàlids o nom repetit", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Finestra no trobada", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[patch("/named-windows/{id}")]
+#[allow(clippy::too_many_arguments)]
+async fn update_named_window(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+    webhooks: web::Data<WebhookDispatcher>,
+    events: web::Data<ScheduleEvents>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    body: Validated<UpdateNamedWindowRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let window_id = path.into_inner();
+
+    let mut tx = pool.begin().await?;
+
+    // Bloquejar la finestra perquè dos canvis simultanis no deixin les regles amb hores diferents
+    let current = sqlx::query_as::<_, NamedWindow>(
+        "SELECT * FROM named_windows WHERE id = $1 AND user_id = $2 FOR UPDATE"
+    )
+    .bind(window_id)
+    .bind(user.id)
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| AppError::NotFound("NAMED_WINDOW_NOT_FOUND", "Named window not found".to_string()))?;
+
+    let start = body.start_time.unwrap_or(current.start_time);
+    let end = body.end_time.unwrap_or(current.end_time);
+    validate_window(start, end)?;
+
+    sqlx::query("UPDATE named_windows SET name = $2, start_time = $3, end_time = $4 WHERE id = $1")
+        .bind(window_id)
+        .bind(body.name.as_deref().map_or(current.name.as_str(), str::trim))
+        .bind(start)
+        .bind(end)
+        .execute(&mut *tx)
+        .await
+        .map_err(duplicate_name)?;
+
+    let mut propagated = vec![];
+    if (start, end) != (current.start_time, current.end_time) {
+        if body.propagate_to_rules.unwrap_or(false) {
+            propagated = propagate_to_rules(&mut tx, window_id, start, end).await?;
+        } else {
+            sqlx::query(
+                r#"
+                UPDATE rules
+                SET named_window_id = NULL, updated_at = NOW(), version = version + 1
+                WHERE named_window_id = $1
+                "#,
+            )
+            .bind(window_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+    }
+
+    let window = fetch_window(&mut tx, window_id).await?;
+    tx.commit().await?;
+
+    let mut response = NamedWindowResponse::from(window);
+    if !propagated.is_empty() {
+        let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+        let enabled: Vec<Rule> = propagated.into_iter().filter(|rule| rule.is_enabled).collect();
+        let info = ScheduleGenerationInfo {
+            schedules_created: regenerate_rules(pool.get_ref(), &pvpc, &enabled, config.timezone).await,
+            message: format!("{} regles actualitzades amb la finestra", response.rule_ids.len()),
+        };
+        notify_schedules_generated(&webhooks, &events, pool.get_ref(), user.id, &info, config.timezone);
+        response.schedule_info = Some(info);
+    }
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// DELETE /api/named-windows/{id}
+/// Les regles que la seguien es queden les hores que tenien
+#[utoipa::path(
+    tag = "named-windows",
+    params(("id" = Uuid, Path, description = "Id de la finestra")),
+    responses(
+        (status = 204, description = "Finestra eliminada"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Finestra no trobada", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[delete("/named-windows/{id}")]
+async fn delete_named_window(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let result = sqlx::query("DELETE FROM named_windows WHERE id = $1 AND user_id = $2")
+        .bind(path.into_inner())
+        .bind(user.id)
+        .execute(pool.get_ref())
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("NAMED_WINDOW_NOT_FOUND", "Named window not found".to_string()));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Finestra de l'usuari amb aquest nom (per crear regles amb `named_window`)
+pub(super) async fn find_named_window(pool: &PgPool, user_id: Uuid, name: &str) -> AppResult<NamedWindow> {
+    sqlx::query_as::<_, NamedWindow>("SELECT * FROM named_windows WHERE user_id = $1 AND name = $2")
+        .bind(user_id)
+        .bind(name.trim())
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| AppError::NotFound("NAMED_WINDOW_NOT_FOUND", format!("Named window not found: {}", name)))
+}
+
+/// Copia les hores noves a les regles que segueixen la finestra. Falla si alguna regla
+/// necessita més hores contínues de les que té la finestra nova.
+async fn propagate_to_rules(
+    conn: &mut PgConnection,
+    window_id: Uuid,
+    start: NaiveTime,
+    end: NaiveTime,
+) -> AppResult<Vec<Rule>> {
+    let hours = time_window_hours(Some(start), Some(end)) as i32;
+    let needed: Option<i32> =
+        sqlx::query_scalar("SELECT MAX(min_continuous_hours) FROM rules WHERE named_window_id = $1")
+            .bind(window_id)
+            .fetch_one(&mut *conn)
+            .await?;
+    if let Some(needed) = needed.filter(|needed| *needed > hours) {
+        return Err(FieldError::new(
+            "end_time",
+            "INVALID_WINDOW",
+            format!("window has {} hours, fewer than the min_continuous_hours of its rules ({})", hours, needed),
+        )
+        .into());
+    }
+
+    let rules = sqlx::query_as::<_, Rule>(
+        r#"
+        WITH updated AS (
+            UPDATE rules
+            SET time_window_start = $2, time_window_end = $3, updated_at = NOW(), version = version + 1
+            WHERE named_window_id = $1
+            RETURNING *
+        )
+        SELECT u.*, lp.weights as load_profile,
+               rule_wattage(u.device_id, u.device_group_id) as wattage_watts
+        FROM updated u
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = u.id
+        "#
+    )
+    .bind(window_id)
+    .bind(start)
+    .bind(end)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    tracing::info!("Hores de la finestra {} propagades a {} regles", window_id, rules.len());
+    Ok(rules)
+}
+
+async fn fetch_window(conn: &mut PgConnection, window_id: Uuid) -> AppResult<NamedWindowWithRules> {
+    let window = sqlx::query_as::<_, NamedWindowWithRules>(
+        r#"
+        SELECT w.*, ARRAY(SELECT r.id FROM rules r WHERE r.named_window_id = w.id ORDER BY r.id) as rule_ids
+        FROM named_windows w
+        WHERE w.id = $1
+        "#
+    )
+    .bind(window_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(window)
+}
+
+/// L'usuari ja té una finestra amb aquest nom
+fn duplicate_name(e: sqlx::Error) -> AppError {
+    match e.as_database_error() {
+        Some(db) if db.is_unique_violation() => {
+            FieldError::new("name", "ALREADY_EXISTS", "a named window with this name already exists").into()
+        }
+        _ => e.into(),
+    }
+}
+
+fn validate_name(name: &str) -> Result<(), FieldError> {
+    if name.trim().is_empty() {
+        return Err(FieldError::new("name", "EMPTY", "name cannot be empty"));
+    }
+    if name.trim().chars().count() > MAX_NAME_LEN {
+        return Err(FieldError::new(
+            "name",
+            "TOO_LONG",
+            format!("name cannot be longer than {} characters", MAX_NAME_LEN),
+        ));
+    }
+    Ok(())
+}
+
+fn validate_window(start: NaiveTime, end: NaiveTime) -> Result<(), FieldError> {
+    if start == end {
+        return Err(FieldError::new("end_time", "INVALID_WINDOW", "start_time and end_time must be different"));
+    }
+    if time_window_hours(Some(start), Some(end)) == 0 {
+        return Err(FieldError::new("end_time", "INVALID_WINDOW", "window must contain at least one whole hour"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+
+    use crate::test_utils::{auth_header, create_device, create_user, test_config};
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_validate_named_window_requests() {
+        let create = |name: &str, start, end| CreateNamedWindowRequest {
+            name: name.to_string(),
+            start_time: start,
+            end_time: end,
+        };
+        assert!(create("Nit", time(22, 0), time(8, 0)).validate().is_ok());
+        assert!(create(" ", time(22, 0), time(8, 0)).validate().is_err());
+        assert!(create(&"n".repeat(101), time(22, 0), time(8, 0)).validate().is_err());
+        assert!(create("Nit", time(22, 0), time(22, 0)).validate().is_err());
+        // Cap hora sencera
+        assert!(create("Curta", time(10, 15), time(10, 45)).validate().is_err());
+
+        let update = |start, end| UpdateNamedWindowRequest {
+            name: None,
+            start_time: start,
+            end_time: end,
+            propagate_to_rules: None,
+        };
+        assert!(update(Some(time(23, 0)), None).validate().is_ok());
+        assert!(update(Some(time(23, 0)), Some(time(23, 0))).validate().is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_create_rule_from_named_window(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "windows").await;
+        let other = create_user(&pool, "other").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(PvpcClient::with_token("test".to_string())))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let send = |request: TestRequest, user: &crate::db::models::User, body: serde_json::Value| {
+            request.insert_header(auth_header(user, &config)).set_json(body).to_request()
+        };
+        let create_rule = |user, name: &str, body: serde_json::Value| {
+            let mut rule =
+                serde_json::json!({ "device_id": device.id, "name": name, "max_hours": 2, "generate_now": false });
+            rule.as_object_mut().unwrap().extend(body.as_object().unwrap().clone());
+            send(TestRequest::post().uri("/api/rules"), user, rule)
+        };
+
+        let night = serde_json::json!({ "name": "Nit", "start_time": "22:00:00", "end_time": "08:00:00" });
+        let resp = call_service(&app, send(TestRequest::post().uri("/api/named-windows"), &user, night.clone())).await;
+        assert_eq!(resp.status(), 201);
+        let window: serde_json::Value = read_body_json(resp).await;
+        let window_id = window["id"].as_str().unwrap().to_string();
+
+        // El nom és únic per usuari
+        let resp = call_service(&app, send(TestRequest::post().uri("/api/named-windows"), &user, night.clone())).await;
+        assert_eq!(resp.status(), 422);
+        let resp = call_service(&app, send(TestRequest::post().uri("/api/named-windows"), &other, night)).await;
+        assert_eq!(resp.status(), 201);
+
+        // La regla copia les hores de la finestra i en guarda la referència
+        let from_window = |name: &str| serde_json::json!({ "named_window": name });
+        let resp = call_service(&app, create_rule(&user, "Termo nit", from_window("Nit"))).await;
+        assert_eq!(resp.status(), 201);
+        let rule: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(rule["time_window_start"], "22:00:00");
+        assert_eq!(rule["time_window_end"], "08:00:00");
+        assert_eq!(rule["named_window_id"], window_id.as_str());
+
+        let resp = call_service(&app, create_rule(&user, "Termo", from_window("Matí"))).await;
+        assert_eq!(resp.status(), 404);
+        let inline = serde_json::json!({ "named_window": "Nit", "time_window_start": "20:00:00" });
+        let resp = call_service(&app, create_rule(&user, "Termo", inline)).await;
+        assert_eq!(resp.status(), 422);
+
+        // Propagar el canvi d'hores a les regles que la segueixen (desactivada: sense ESIOS no es regenera res)
+        sqlx::query("UPDATE rules SET is_enabled = false").execute(&pool).await.unwrap();
+        let uri = format!("/api/named-windows/{}", window_id);
+        let change = serde_json::json!({ "start_time": "23:00:00", "propagate_to_rules": true });
+        let resp = call_service(&app, send(TestRequest::patch().uri(&uri), &user, change)).await;
+        assert_eq!(resp.status(), 200);
+        let window: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(window["rule_ids"], serde_json::json!([rule["id"]]));
+        let (start, version): (Option<NaiveTime>, i64) =
+            sqlx::query_as("SELECT time_window_start, version FROM rules WHERE named_window_id = $1::uuid")
+                .bind(&window_id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((start, version), (Some(time(23, 0)), 2));
+
+        // Sense propagar, la regla deixa de seguir la finestra i es queda les hores
+        let change = serde_json::json!({ "start_time": "21:00:00" });
+        let resp = call_service(&app, send(TestRequest::patch().uri(&uri), &user, change)).await;
+        let window: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(window["start_time"], "21:00:00");
+        assert_eq!(window["rule_ids"], serde_json::json!([]));
+        let (start, named, version): (Option<NaiveTime>, Option<Uuid>, i64) =
+            sqlx::query_as("SELECT time_window_start, named_window_id, version FROM rules WHERE id = $1::uuid")
+                .bind(rule["id"].as_str().unwrap())
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((start, named, version), (Some(time(23, 0)), None, 3));
+    }
+}
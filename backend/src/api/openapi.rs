@@ -0,0 +1,195 @@
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+
+use super::{
+    admin, api_keys, auth, device_groups, devices, diagnose, named_windows, prices, rules, schedule, webhooks, ws,
+};
+
+/// Especificació OpenAPI de l'API (servida a /api-docs/openapi.json)
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "PVPC Cheap API", description = "Programació de dispositius segons el preu PVPC"),
+    servers((url = "/api")),
+    paths(
+        auth::google_login,
+        auth::apple_login,
+        auth::refresh_token,
+        auth::get_me,
+        auth::update_me,
+        auth::get_preferences,
+        auth::update_preferences,
+        auth::export_data,
+        auth::delete_account,
+        api_keys::create_api_key,
+        api_keys::list_api_keys,
+        api_keys::delete_api_key,
+        devices::list_devices,
+        devices::sync_devices,
+        devices::resync_device,
+        devices::update_device,
+        devices::delete_device,
+        devices::cancel_pending_device_actions,
+        devices::list_device_rules,
+        devices::get_device_schedule_history,
+        devices::get_device_plan,
+        device_groups::list_device_groups,
+        device_groups::create_device_group,
+        device_groups::delete_device_group,
+        device_groups::add_device_group_members,
+        named_windows::list_named_windows,
+        named_windows::create_named_window,
+        named_windows::update_named_window,
+        named_windows::delete_named_window,
+        rules::list_rules,
+        rules::create_rule,
+        rules::get_rule,
+        rules::replace_rule,
+        rules::update_rule,
+        rules::batch_update_rules,
+        rules::preview_rule_schedule,
+        rules::list_rule_schedules,
+        rules::get_rule_execution_stats,
+        rules::delete_rule,
+        prices::get_today_prices,
+        prices::get_today_chart_data,
+        prices::get_tomorrow_prices,
+        prices::get_tomorrow_forecast,
+        prices::get_prices_status,
+        prices::compare_prices,
+        prices::get_price_range,
+        prices::fetch_prices,
+        prices::get_price_percentiles,
+        schedule::get_today_schedule,
+        schedule::get_next_actions,
+        schedule::get_missed_actions,
+        schedule::get_schedule_by_date,
+        schedule::get_day_costs,
+        schedule::get_skipped_rules,
+        schedule::get_schedule_ics,
+        schedule::get_schedule_range_ics,
+        schedule::stream_schedule,
+        schedule::generate_schedule_now,
+        schedule::calculate_schedule,
+        schedule::explain_schedule,
+        schedule::plan_week,
+        schedule::update_schedule_status,
+        schedule::retry_scheduled_action,
+        diagnose::diagnose,
+        webhooks::create_webhook,
+        ws::websocket,
+        admin::list_audit_log,
+        admin::list_users,
+        admin::delete_user,
+        admin::purge_device,
+    ),
+    modifiers(&SecurityAddon),
+    tags(
+        (name = "auth", description = "Autenticació amb Google o Apple, tokens JWT i claus d'API"),
+        (name = "devices", description = "Dispositius de Google Home"),
+        (name = "device-groups", description = "Grups de dispositius que comparteixen regles"),
+        (name = "named-windows", description = "Finestres horàries amb nom que es reutilitzen entre regles"),
+        (name = "rules", description = "Regles de programació"),
+        (name = "prices", description = "Preus PVPC"),
+        (name = "schedule", description = "Accions programades"),
+        (name = "diagnose", description = "Autodiagnòstic de la configuració de l'usuari"),
+        (name = "webhooks", description = "Notificacions a sistemes externs"),
+        (name = "admin", description = "Administració (només usuaris administradors)"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Afegeix els esquemes d'autenticació: Bearer (JWT) i clau d'API (`X-API-Key`).
+/// Els endpoints que declaren `bearer_auth` també accepten la clau d'API.
+struct SecurityAddon;
+
+impl Modify for SecurityAddon {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer_auth",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(api_keys::API_KEY_HEADER))),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spec_covers_all_endpoints() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = spec["paths"].as_object().unwrap();
+
+        for path in [
+            "/auth/google",
+            "/auth/apple",
+            "/auth/me",
+            "/auth/preferences",
+            "/auth/export-data",
+            "/auth/account",
+            "/auth/api-keys",
+            "/auth/api-keys/{id}",
+            "/devices",
+            "/devices/{id}",
+            "/devices/{id}/cancel-pending",
+            "/devices/{id}/plan",
+            "/devices/{id}/schedule-history",
+            "/devices/{google_device_id}/resync",
+            "/device-groups",
+            "/device-groups/{id}/members",
+            "/named-windows",
+            "/named-windows/{id}",
+            "/rules",
+            "/rules/{id}",
+            "/rules/batch-update",
+            "/rules/{id}/preview-schedule",
+            "/rules/{id}/schedules",
+            "/rules/{id}/execution-stats",
+            "/prices/today",
+            "/prices/today/chart-data",
+            "/prices/status",
+            "/prices/tomorrow/forecast",
+            "/prices/compare",
+            "/prices/range",
+            "/prices/fetch",
+            "/prices/percentiles",
+            "/schedule/next",
+            "/schedule/{date}",
+            "/schedule/{date}/costs",
+            "/schedule/{date}/skipped",
+            "/schedule/{date}/ics",
+            "/schedule/ics",
+            "/schedule/calculate",
+            "/schedule/explain",
+            "/schedule/plan-week",
+            "/schedule/{id}/status",
+            "/schedule/missed",
+            "/schedule/{id}/retry",
+            "/diagnose",
+            "/webhooks",
+            "/ws",
+            "/admin/audit-log",
+            "/admin/users",
+            "/admin/users/{id}",
+            "/admin/devices/{id}",
+        ] {
+            assert!(paths.contains_key(path), "falta {}", path);
+        }
+        assert!(paths["/rules/{id}"].get("put").is_some() && paths["/rules/{id}"].get("patch").is_some());
+
+        let schemas = spec["components"]["schemas"].as_object().unwrap();
+        for schema in ["RuleResponse", "CreateRuleRequest", "TariffDailyPrices", "ScheduleResponse", "ErrorResponse"] {
+            assert!(schemas.contains_key(schema), "falta l'esquema {}", schema);
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use serde::{Deserialize, Serialize};
+use utoipa::{IntoParams, ToSchema};
+
+use crate::error::{AppError, AppResult};
+
+/// Nombre d'elements per pàgina si no s'indica `limit`
+pub const DEFAULT_LIMIT: i64 = 50;
+
+/// Nombre màxim d'elements per pàgina (els valors superiors es limiten)
+pub const MAX_LIMIT: i64 = 200;
+
+/// Paràmetres de paginació de les llistes (`?limit=&offset=`)
+#[derive(Debug, Default, Deserialize, IntoParams)]
+pub struct PageQuery {
+    /// Elements per pàgina (per defecte 50, màxim 200)
+    pub limit: Option<i64>,
+    /// Elements a saltar (per defecte 0)
+    pub offset: Option<i64>,
+}
+
+/// Paginació validada, a punt per fer servir a `LIMIT`/`OFFSET`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl PageQuery {
+    /// Valida els paràmetres i aplica els valors per defecte i el límit màxim
+    pub fn page(&self) -> AppResult<Page> {
+        let limit = self.limit.unwrap_or(DEFAULT_LIMIT);
+        if limit < 1 {
+            return Err(AppError::BadRequest("INVALID_PAGINATION", "limit must be 1 or greater".to_string()));
+        }
+
+        let offset = self.offset.unwrap_or(0);
+        if offset < 0 {
+            return Err(AppError::BadRequest("INVALID_PAGINATION", "offset cannot be negative".to_string()));
+        }
+
+        Ok(Page {
+            limit: limit.min(MAX_LIMIT),
+            offset,
+        })
+    }
+}
+
+/// Resposta paginada comuna a tots els endpoints de llista
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Paginated<T> {
+    pub items: Vec<T>,
+    /// Total d'elements sense paginar
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+}
+
+impl<T> Paginated<T> {
+    pub fn new(items: Vec<T>, total: i64, page: Page) -> Self {
+        Self {
+            items,
+            total,
+            limit: page.limit,
+            offset: page.offset,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::web;
+
+    fn parse(query: &str) -> Result<AppResult<Page>, actix_web::error::QueryPayloadError> {
+        web::Query::<PageQuery>::from_query(query).map(|q| q.page())
+    }
+
+    #[test]
+    fn test_page_defaults() {
+        let page = parse("").unwrap().unwrap();
+        assert_eq!(page, Page { limit: DEFAULT_LIMIT, offset: 0 });
+
+        let page = parse("limit=10&offset=20").unwrap().unwrap();
+        assert_eq!(page, Page { limit: 10, offset: 20 });
+    }
+
+    #[test]
+    fn test_page_limit_is_capped() {
+        let page = parse("limit=10000").unwrap().unwrap();
+        assert_eq!(page.limit, MAX_LIMIT);
+    }
+
+    #[test]
+    fn test_page_invalid_values() {
+        assert!(parse("limit=0").unwrap().is_err());
+        assert!(parse("limit=-5").unwrap().is_err());
+        assert!(parse("offset=-1").unwrap().is_err());
+        // Valors no numèrics: actix els rebutja en deserialitzar la query
+        assert!(parse("limit=abc").is_err());
+    }
+}
@@ -1,66 +1,1150 @@
-use actix_web::{get, web, HttpResponse};
-use shared::DailyPrices;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
-use crate::error::AppResult;
-use crate::services::pvpc::PvpcClient;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Duration, NaiveDate, Timelike, Utc};
+use serde::{Deserialize, Serialize};
+use shared::{DailyPrices, HourlyPrice, PricePoint};
+use sqlx::PgPool;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::config::Config;
+use crate::error::{AppError, AppResult, ErrorResponse};
+use crate::services::clock;
+use crate::services::energy_tariff::{classify_hour, GeoZone, TariffPeriod};
+use crate::services::forecast::{cached_forecast, estimate_tomorrow_prices, store_forecast, FORECAST_HISTORY_DAYS};
+use crate::services::price_history::{fetch_logs, get_prices_between, record_fetch_failure, store_prices, FetchLog};
+use crate::services::pvpc::{PvpcClient, INDICATOR_PVPC, INDICATOR_SPOT};
+
+use super::auth::{extract_user_from_request, user_geo_zone};
+
+/// Dies per defecte i màxims de les estadístiques de percentils
+const DEFAULT_PERCENTILE_DAYS: i64 = 7;
+const MAX_PERCENTILE_DAYS: i64 = 365;
+
+/// Dies màxims (inclosos) d'una consulta de preus per rang
+const MAX_RANGE_DAYS: i64 = 31;
+
+/// Dies d'avui endavant que es poden demanar a ESIOS a mà (demà i l'endemà com a màxim)
+const MAX_FETCH_DAYS_AHEAD: i64 = 2;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct CompareQuery {
+    /// Data a comparar (per defecte, avui)
+    pub date: Option<NaiveDate>,
+}
+
+/// Preus PVPC i spot alineats per hora. Totes les llistes tenen la mateixa longitud.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PriceComparison {
+    pub date: NaiveDate,
+    /// Hora de cada posició (l'hora repetida del canvi d'horari surt dues vegades)
+    pub hours: Vec<u8>,
+    /// Preu PVPC (€/kWh), null si falta l'hora
+    pub pvpc: Vec<Option<f64>>,
+    /// Preu del mercat diari (€/kWh), null si falta l'hora
+    pub spot: Vec<Option<f64>>,
+    /// Diferència PVPC - spot (€/kWh)
+    pub spread: Vec<Option<f64>>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct RangeQuery {
+    /// Primer dia del rang (inclòs)
+    pub from: NaiveDate,
+    /// Darrer dia del rang (inclòs)
+    pub to: NaiveDate,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct FetchPricesRequest {
+    /// Dia a obtenir de ESIOS
+    pub date: NaiveDate,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct PercentilesQuery {
+    /// Dies anteriors (avui inclòs) a tenir en compte (per defecte, 7)
+    pub days: Option<i64>,
+}
+
+/// Percentils d'un conjunt de preus (€/kWh), pel mètode del rang més proper
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, ToSchema)]
+pub struct Percentiles {
+    pub p10: f64,
+    pub p25: f64,
+    pub p50: f64,
+    pub p75: f64,
+    pub p90: f64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HourPercentiles {
+    pub hour: u8,
+    /// Preus disponibles per aquesta hora (un per dia)
+    pub samples: usize,
+    #[serde(flatten)]
+    pub percentiles: Percentiles,
+}
+
+/// Estadístiques dels preus desats dels darrers dies
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PricePercentilesResponse {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// Dies del període amb preus a l'historial
+    pub days_with_data: usize,
+    /// Percentils per hora del dia (només les hores amb preus)
+    pub hours: Vec<HourPercentiles>,
+    /// Percentils de tots els preus horaris del període
+    pub overall: Percentiles,
+}
+
+/// Preus d'avui preparats per dibuixar-los: un element per hora a cada llista
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ChartData {
+    pub hours: Vec<u8>,
+    /// €/kWh
+    pub prices: Vec<f64>,
+    /// "cheap" (per sota del p25), "expensive" (per sobre del p75) o "normal"
+    pub colors: Vec<String>,
+    /// p25 dels preus del dia
+    pub threshold_cheap: f64,
+    /// p75 dels preus del dia
+    pub threshold_expensive: f64,
+    /// Hora local actual
+    pub current_hour: u8,
+}
+
+/// Preu d'una hora amb el seu període del peatge 2.0TD
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TariffHourlyPrice {
+    #[serde(flatten)]
+    pub price: HourlyPrice,
+    pub tariff_period: TariffPeriod,
+}
+
+/// Preus PVPC d'un dia, amb el període del peatge de cada hora
+#[derive(Debug, Serialize, ToSchema)]
+pub struct TariffDailyPrices {
+    pub date: NaiveDate,
+    /// Preus horaris (els quarts d'hora promitjats per hora)
+    pub prices: Vec<TariffHourlyPrice>,
+    /// Preus quart-horaris, només si ESIOS els publica amb aquesta resolució
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub quarter_hours: Vec<PricePoint>,
+}
+
+impl TariffDailyPrices {
+    fn new(day: DailyPrices, zone: GeoZone) -> Self {
+        let date = day.date;
+        Self {
+            date,
+            prices: day
+                .prices
+                .into_iter()
+                .map(|price| TariffHourlyPrice {
+                    tariff_period: classify_hour(price.hour, date, zone),
+                    price,
+                })
+                .collect(),
+            quarter_hours: day.quarter_hours,
+        }
+    }
+}
+
+/// Preus de demà: els oficials si ja s'han publicat o, si no, una estimació
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PriceForecastResponse {
+    #[serde(flatten)]
+    pub prices: TariffDailyPrices,
+    /// Si els preus són una estimació a partir dels darrers dies (vegeu `services::forecast`)
+    pub is_forecast: bool,
+}
+
+/// Estat dels preus d'un dia al servidor
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PriceDayStatus {
+    pub date: NaiveDate,
+    /// Si s'han obtingut preus del dia
+    pub available: bool,
+    /// Últim cop que es van obtenir els preus
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// Hores rebudes a l'última obtenció
+    pub hours_received: i32,
+    /// Error de l'últim intent, si ha fallat després de l'últim èxit
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+impl PriceDayStatus {
+    fn new(date: NaiveDate, log: Option<FetchLog>) -> Self {
+        let Some(log) = log else {
+            return Self {
+                date,
+                available: false,
+                fetched_at: None,
+                hours_received: 0,
+                last_error: None,
+                last_error_at: None,
+            };
+        };
+        Self {
+            date,
+            available: log.hours_received > 0,
+            fetched_at: log.fetched_at,
+            hours_received: log.hours_received,
+            last_error: log.last_error,
+            last_error_at: log.last_error_at,
+        }
+    }
+}
+
+/// Frescor dels preus amb què es generen els schedules
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PricesStatusResponse {
+    pub today: PriceDayStatus,
+    pub tomorrow: PriceDayStatus,
+}
 
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(get_today_prices)
-        .service(get_tomorrow_prices);
+        .service(get_today_chart_data)
+        .service(get_tomorrow_prices)
+        .service(get_tomorrow_forecast)
+        .service(get_prices_status)
+        .service(compare_prices)
+        .service(get_price_range)
+        .service(fetch_prices)
+        .service(get_price_percentiles);
 }
 
 /// GET /api/prices/today
+#[utoipa::path(
+    tag = "prices",
+    responses(
+        (status = 200, description = "Preus PVPC d'avui", body = TariffDailyPrices),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+)]
 #[get("/prices/today")]
-async fn get_today_prices(pvpc: web::Data<PvpcClient>) -> AppResult<HttpResponse> {
-    let prices = pvpc.get_today_prices().await?;
-    Ok(HttpResponse::Ok().json(prices))
+async fn get_today_prices(pvpc: web::Data<PvpcClient>, config: web::Data<Config>) -> AppResult<HttpResponse> {
+    let prices = pvpc.get_today_prices(config.timezone).await?;
+    Ok(HttpResponse::Ok().json(TariffDailyPrices::new(prices, config.geo_zone)))
+}
+
+/// GET /api/prices/today/chart-data
+/// Preus d'avui amb cada hora classificada com a barata, normal o cara segons els percentils del dia
+#[utoipa::path(
+    tag = "prices",
+    responses(
+        (status = 200, description = "Preus d'avui per a gràfics", body = ChartData),
+        (status = 422, description = "No hi ha preus d'avui", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+)]
+#[get("/prices/today/chart-data")]
+async fn get_today_chart_data(pvpc: web::Data<PvpcClient>, config: web::Data<Config>) -> AppResult<HttpResponse> {
+    let prices = pvpc.get_today_prices(config.timezone).await?;
+    let current_hour = clock::now(config.timezone).hour() as u8;
+
+    let chart = chart_data(&prices, current_hour).ok_or_else(|| {
+        AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!("No prices for {}", prices.date))
+    })?;
+    Ok(HttpResponse::Ok().json(chart))
 }
 
 /// GET /api/prices/tomorrow
+#[utoipa::path(
+    tag = "prices",
+    responses(
+        (status = 200, description = "Preus PVPC de demà", body = TariffDailyPrices),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+)]
 #[get("/prices/tomorrow")]
-async fn get_tomorrow_prices(pvpc: web::Data<PvpcClient>) -> AppResult<HttpResponse> {
-    let prices = pvpc.get_tomorrow_prices().await?;
-    Ok(HttpResponse::Ok().json(prices))
+async fn get_tomorrow_prices(pvpc: web::Data<PvpcClient>, config: web::Data<Config>) -> AppResult<HttpResponse> {
+    let prices = pvpc.get_tomorrow_prices(config.timezone).await?;
+    Ok(HttpResponse::Ok().json(TariffDailyPrices::new(prices, config.geo_zone)))
 }
 
-/// Resposta enriquida amb estadístiques
-#[derive(serde::Serialize)]
-pub struct PricesWithStats {
-    #[serde(flatten)]
-    pub prices: DailyPrices,
-    pub stats: PriceStats,
-}
-
-#[derive(serde::Serialize)]
-pub struct PriceStats {
-    pub min_price: f64,
-    pub max_price: f64,
-    pub avg_price: f64,
-    pub cheapest_hours: Vec<u8>,
-    pub most_expensive_hours: Vec<u8>,
-}
-
-impl From<DailyPrices> for PricesWithStats {
-    fn from(prices: DailyPrices) -> Self {
-        let min_price = prices.prices.iter().map(|p| p.price).fold(f64::MAX, f64::min);
-        let max_price = prices.prices.iter().map(|p| p.price).fold(f64::MIN, f64::max);
-        let avg_price = prices.prices.iter().map(|p| p.price).sum::<f64>() / prices.prices.len() as f64;
-
-        let mut sorted_by_price = prices.prices.clone();
-        sorted_by_price.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
-
-        let cheapest_hours: Vec<u8> = sorted_by_price.iter().take(6).map(|p| p.hour).collect();
-        let most_expensive_hours: Vec<u8> = sorted_by_price.iter().rev().take(6).map(|p| p.hour).collect();
-
-        PricesWithStats {
-            prices,
-            stats: PriceStats {
-                min_price,
-                max_price,
-                avg_price,
-                cheapest_hours,
-                most_expensive_hours,
-            },
+/// GET /api/prices/tomorrow/forecast
+/// Preus oficials de demà si ja s'han publicat; si no, una estimació a partir dels darrers dies.
+/// L'estimació es reutilitza durant `FORECAST_CACHE_MINUTES` minuts sense consultar ESIOS.
+#[utoipa::path(
+    tag = "prices",
+    responses(
+        (status = 200, description = "Preus de demà, oficials o estimats", body = PriceForecastResponse),
+        (status = 422, description = "No hi ha historial per estimar", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+)]
+#[get("/prices/tomorrow/forecast")]
+async fn get_tomorrow_forecast(
+    pool: web::Data<PgPool>,
+    pvpc: web::Data<PvpcClient>,
+    config: web::Data<Config>,
+) -> AppResult<HttpResponse> {
+    let today = clock::today(config.timezone);
+    let tomorrow = today + Duration::days(1);
+    let response = |prices, is_forecast| {
+        HttpResponse::Ok().json(PriceForecastResponse {
+            prices: TariffDailyPrices::new(prices, config.geo_zone),
+            is_forecast,
+        })
+    };
+
+    // Mentre l'estimació és recent no es torna a preguntar a ESIOS (l'endpoint és públic)
+    if let Some(forecast) = cached_forecast(pool.get_ref(), tomorrow).await? {
+        return Ok(response(forecast, true));
+    }
+
+    // Un sol intent: si ESIOS falla, l'estimació és justament el que cal i no cal esperar els reintents
+    match pvpc.as_ref().clone().with_retries(0, std::time::Duration::ZERO).get_prices_for_date(tomorrow).await {
+        Ok(official) if !official.prices.is_empty() => return Ok(response(official, false)),
+        Ok(_) => {}
+        Err(e) => tracing::warn!("No s'han pogut obtenir els preus de demà per l'estimació: {}", e),
+    }
+
+    // Els darrers dies fins avui inclòs (l'estimació és pel dia següent al més recent)
+    let mut history = get_prices_between(pool.get_ref(), today - Duration::days(FORECAST_HISTORY_DAYS - 1), today)
+        .await?;
+    if history.last().is_none_or(|day| day.date != today) {
+        let prices = pvpc.get_prices_for_date(today).await?;
+        if let Err(e) = store_prices(pool.get_ref(), &prices).await {
+            tracing::warn!("No s'han pogut desar els preus d'avui a l'historial: {}", e);
+        }
+        history.push(prices);
+    }
+
+    let forecast = estimate_tomorrow_prices(&history);
+    if forecast.prices.is_empty() {
+        return Err(AppError::PricesUnavailable(
+            "PRICES_UNAVAILABLE",
+            "Not enough price history to estimate tomorrow's prices".to_string(),
+        ));
+    }
+    if let Err(e) = store_forecast(pool.get_ref(), &forecast).await {
+        tracing::warn!("No s'ha pogut desar l'estimació dels preus de {}: {}", tomorrow, e);
+    }
+
+    Ok(response(forecast, true))
+}
+
+/// GET /api/prices/status
+/// Indica si hi ha preus d'avui i de demà, quan es van obtenir i si l'últim intent ha fallat
+#[utoipa::path(
+    tag = "prices",
+    responses(
+        (status = 200, description = "Estat dels preus d'avui i de demà", body = PricesStatusResponse),
+    ),
+)]
+#[get("/prices/status")]
+async fn get_prices_status(pool: web::Data<PgPool>, config: web::Data<Config>) -> AppResult<HttpResponse> {
+    let today = clock::today(config.timezone);
+    let tomorrow = today + Duration::days(1);
+
+    let mut logs: HashMap<NaiveDate, FetchLog> = fetch_logs(pool.get_ref(), &[today, tomorrow])
+        .await?
+        .into_iter()
+        .map(|log| (log.price_date, log))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PricesStatusResponse {
+        today: PriceDayStatus::new(today, logs.remove(&today)),
+        tomorrow: PriceDayStatus::new(tomorrow, logs.remove(&tomorrow)),
+    }))
+}
+
+/// GET /api/prices/compare?date=
+/// Compara hora a hora el preu PVPC amb el del mercat diari (spot)
+#[utoipa::path(
+    tag = "prices",
+    params(CompareQuery),
+    responses(
+        (status = 200, description = "Preus PVPC i spot alineats per hora", body = PriceComparison),
+        (status = 422, description = "Encara no hi ha preus per la data", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+)]
+#[get("/prices/compare")]
+async fn compare_prices(
+    pvpc: web::Data<PvpcClient>,
+    config: web::Data<Config>,
+    query: web::Query<CompareQuery>,
+) -> AppResult<HttpResponse> {
+    let date = query.date.unwrap_or_else(|| clock::today(config.timezone));
+
+    let (pvpc_prices, spot_prices) = futures_util::try_join!(
+        pvpc.get_indicator_prices(INDICATOR_PVPC, date),
+        pvpc.get_indicator_prices(INDICATOR_SPOT, date),
+    )?;
+
+    if pvpc_prices.prices.is_empty() && spot_prices.prices.is_empty() {
+        return Err(AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!("No prices available for {}", date)));
+    }
+
+    Ok(HttpResponse::Ok().json(align_prices(date, &pvpc_prices, &spot_prices)))
+}
+
+/// GET /api/prices/range?from=&to=
+/// Preus PVPC de cada dia del rang, ordenats per data. Els dies passats surten de
+/// l'historial; la resta (i els que hi falten) es demanen a ESIOS en una sola petició.
+#[utoipa::path(
+    tag = "prices",
+    params(RangeQuery),
+    responses(
+        (status = 200, description = "Preus de cada dia del rang", body = Vec<TariffDailyPrices>),
+        (status = 400, description = "Rang invàlid o massa llarg", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+)]
+#[get("/prices/range")]
+async fn get_price_range(
+    pool: web::Data<PgPool>,
+    pvpc: web::Data<PvpcClient>,
+    config: web::Data<Config>,
+    query: web::Query<RangeQuery>,
+) -> AppResult<HttpResponse> {
+    let (from, to) = (query.from, query.to);
+    validate_range(from, to)?;
+
+    let today = clock::today(config.timezone);
+    let cached = get_prices_between(pool.get_ref(), from, to).await?;
+    let missing = dates_to_fetch(from, to, today, &cached);
+
+    let mut days: BTreeMap<NaiveDate, DailyPrices> = cached.into_iter().map(|day| (day.date, day)).collect();
+    let fetched = match (missing.first(), missing.last()) {
+        (Some(&first), Some(&last)) => pvpc.fetch_prices_for_range(first, last).await?,
+        _ => vec![],
+    };
+    // La petició cobreix del primer al darrer dia que falta: es descarten els que ja teníem
+    for prices in fetched.into_iter().filter(|day| missing.contains(&day.date)) {
+        // Els preus d'un dia passat ja no canvien: es desen per la propera consulta
+        if prices.date < today
+            && let Err(e) = store_prices(pool.get_ref(), &prices).await
+        {
+            tracing::warn!("No s'han pogut desar els preus de {}: {}", prices.date, e);
+        }
+        days.insert(prices.date, prices);
+    }
+
+    let days: Vec<_> = days.into_values().map(|day| TariffDailyPrices::new(day, config.geo_zone)).collect();
+    Ok(HttpResponse::Ok().json(days))
+}
+
+/// Comprova que el rang està ordenat i no supera `MAX_RANGE_DAYS` dies
+pub(crate) fn validate_range(from: NaiveDate, to: NaiveDate) -> AppResult<()> {
+    if from > to {
+        return Err(AppError::BadRequest("INVALID_DATE_RANGE", "from must not be after to".to_string()));
+    }
+    if (to - from).num_days() + 1 > MAX_RANGE_DAYS {
+        return Err(AppError::BadRequest("INVALID_DATE_RANGE", format!(
+            "Range must be at most {} days",
+            MAX_RANGE_DAYS
+        )));
+    }
+    Ok(())
+}
+
+/// POST /api/prices/fetch
+/// Obté ara mateix els preus d'un dia de ESIOS i els desa a l'historial, sense esperar la
+/// tasca periòdica (p. ex. per omplir un forat de l'historial)
+#[utoipa::path(
+    tag = "prices",
+    request_body = FetchPricesRequest,
+    responses(
+        (status = 200, description = "Preus obtinguts i desats", body = TariffDailyPrices),
+        (status = 400, description = "Data massa llunyana o massa antiga", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 422, description = "ESIOS encara no té tots els preus del dia", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/prices/fetch")]
+async fn fetch_prices(
+    pool: web::Data<PgPool>,
+    pvpc: web::Data<PvpcClient>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: web::Json<FetchPricesRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let date = body.date;
+    let today = clock::today(config.timezone);
+    validate_fetch_date(date, today, config.price_history_days)?;
+
+    // Client global i sense ajustos de l'usuari: l'historial és compartit per tothom
+    let prices = match pvpc.get_prices_for_date(date).await {
+        Ok(prices) => prices,
+        Err(e) => {
+            if let Err(db_error) = record_fetch_failure(pool.get_ref(), date, &e.to_string()).await {
+                tracing::warn!("No s'ha pogut registrar l'error dels preus de {}: {}", date, db_error);
+            }
+            return Err(e);
+        }
+    };
+    if prices.prices.is_empty() {
+        return Err(AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!(
+            "Prices for {} are not published yet",
+            date
+        )));
+    }
+    // Un dia futur a mitges no es desa: l'historial quedaria amb hores de menys
+    if date > today && !pvpc.is_complete(&prices) {
+        return Err(AppError::PricesUnavailable("PRICES_INCOMPLETE", format!(
+            "Prices for {} are only partially published ({} hours)",
+            date,
+            prices.prices.len()
+        )));
+    }
+
+    store_prices(pool.get_ref(), &prices).await?;
+    tracing::info!("Preus de {} obtinguts a petició de l'usuari {}", date, user.id);
+
+    let zone = user_geo_zone(pool.get_ref(), user.id, config.geo_zone).await?;
+    Ok(HttpResponse::Ok().json(TariffDailyPrices::new(prices, zone)))
+}
+
+/// Comprova que no es demanen preus més enllà de `MAX_FETCH_DAYS_AHEAD` dies d'avui
+/// ni de més enrere que `history_days` dies
+fn validate_fetch_date(date: NaiveDate, today: NaiveDate, history_days: i64) -> AppResult<()> {
+    let max_date = today + Duration::days(MAX_FETCH_DAYS_AHEAD);
+    if date > max_date {
+        return Err(AppError::BadRequest("DATE_TOO_FAR", format!(
+            "Prices can only be fetched up to {} (the day after tomorrow)",
+            max_date
+        )));
+    }
+    let min_date = today - Duration::days(history_days);
+    if date < min_date {
+        return Err(AppError::BadRequest("DATE_TOO_OLD", format!(
+            "Prices can only be fetched back to {}",
+            min_date
+        )));
+    }
+    Ok(())
+}
+
+/// Dies del rang que s'han de demanar a ESIOS: els d'avui en endavant (encara poden
+/// canviar) i els passats que no són a l'historial
+fn dates_to_fetch(from: NaiveDate, to: NaiveDate, today: NaiveDate, cached: &[DailyPrices]) -> Vec<NaiveDate> {
+    let cached: BTreeSet<NaiveDate> = cached
+        .iter()
+        .filter(|day| !day.prices.is_empty())
+        .map(|day| day.date)
+        .collect();
+
+    from.iter_days()
+        .take_while(|date| *date <= to)
+        .filter(|date| *date >= today || !cached.contains(date))
+        .collect()
+}
+
+/// GET /api/prices/percentiles?days=
+/// Percentils dels preus dels darrers dies, per hora i globals, a partir de l'historial.
+/// Serveixen per triar un llindar de preu raonable per una regla.
+#[utoipa::path(
+    tag = "prices",
+    params(PercentilesQuery),
+    responses(
+        (status = 200, description = "Percentils dels preus del període", body = PricePercentilesResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 422, description = "No hi ha preus desats pel període", body = ErrorResponse),
+    ),
+)]
+#[get("/prices/percentiles")]
+async fn get_price_percentiles(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    query: web::Query<PercentilesQuery>,
+) -> AppResult<HttpResponse> {
+    let days = query.days.unwrap_or(DEFAULT_PERCENTILE_DAYS);
+    if !(1..=MAX_PERCENTILE_DAYS).contains(&days) {
+        return Err(AppError::BadRequest("INVALID_DATE_RANGE", format!(
+            "days must be between 1 and {}",
+            MAX_PERCENTILE_DAYS
+        )));
+    }
+
+    let to = clock::today(config.timezone);
+    let from = to - Duration::days(days - 1);
+    let history = get_prices_between(pool.get_ref(), from, to).await?;
+
+    let response = price_percentiles(from, to, &history)
+        .ok_or_else(|| AppError::PricesUnavailable(
+            "PRICES_UNAVAILABLE",
+            format!("No stored prices between {} and {}", from, to),
+        ))?;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Percentils per hora i globals de l'historial. None si no hi ha cap preu.
+fn price_percentiles(from: NaiveDate, to: NaiveDate, history: &[DailyPrices]) -> Option<PricePercentilesResponse> {
+    let mut by_hour: BTreeMap<u8, Vec<f64>> = BTreeMap::new();
+    for price in history.iter().flat_map(|day| &day.prices) {
+        by_hour.entry(price.hour).or_default().push(price.price);
+    }
+
+    let mut all: Vec<f64> = by_hour.values().flatten().copied().collect();
+    let overall = percentiles(&mut all)?;
+
+    let hours = by_hour
+        .into_iter()
+        .filter_map(|(hour, mut prices)| {
+            let samples = prices.len();
+            percentiles(&mut prices).map(|percentiles| HourPercentiles { hour, samples, percentiles })
+        })
+        .collect();
+
+    Some(PricePercentilesResponse {
+        from,
+        to,
+        days_with_data: history.iter().filter(|day| !day.prices.is_empty()).count(),
+        hours,
+        overall,
+    })
+}
+
+/// Percentils pel rang més proper: el p-èsim és l'element `ceil(p/100 · n)` dels preus ordenats.
+/// Ordena `prices` in situ. None si és buit.
+fn percentiles(prices: &mut [f64]) -> Option<Percentiles> {
+    if prices.is_empty() {
+        return None;
+    }
+    prices.sort_by(f64::total_cmp);
+
+    let nth = |p: usize| {
+        let rank = (p * prices.len()).div_ceil(100).max(1);
+        prices[rank - 1]
+    };
+
+    Some(Percentiles {
+        p10: nth(10),
+        p25: nth(25),
+        p50: nth(50),
+        p75: nth(75),
+        p90: nth(90),
+    })
+}
+
+/// Classifica cada hora amb els percentils dels preus del dia. None si no hi ha preus.
+fn chart_data(prices: &DailyPrices, current_hour: u8) -> Option<ChartData> {
+    let mut sorted: Vec<f64> = prices.prices.iter().map(|p| p.price).collect();
+    let Percentiles { p25, p75, .. } = percentiles(&mut sorted)?;
+
+    let color = |price: f64| {
+        if price < p25 {
+            "cheap"
+        } else if price > p75 {
+            "expensive"
+        } else {
+            "normal"
+        }
+    };
+
+    Some(ChartData {
+        hours: prices.prices.iter().map(|p| p.hour).collect(),
+        prices: prices.prices.iter().map(|p| p.price).collect(),
+        colors: prices.prices.iter().map(|p| color(p.price).to_string()).collect(),
+        threshold_cheap: p25,
+        threshold_expensive: p75,
+        current_hour,
+    })
+}
+
+/// Alinea dues sèries horàries. Cada hora s'identifica per (hora, ocurrència)
+/// perquè l'hora repetida del canvi d'horari no es barregi.
+fn align_prices(date: NaiveDate, pvpc: &DailyPrices, spot: &DailyPrices) -> PriceComparison {
+    fn by_hour(prices: &DailyPrices) -> HashMap<(u8, usize), f64> {
+        let mut seen: HashMap<u8, usize> = HashMap::new();
+        prices
+            .prices
+            .iter()
+            .map(|p| {
+                let occurrence = seen.entry(p.hour).or_default();
+                *occurrence += 1;
+                ((p.hour, *occurrence), p.price)
+            })
+            .collect()
+    }
+
+    let pvpc = by_hour(pvpc);
+    let spot = by_hour(spot);
+    let keys: BTreeSet<(u8, usize)> = pvpc.keys().chain(spot.keys()).copied().collect();
+
+    let mut comparison = PriceComparison {
+        date,
+        hours: Vec::with_capacity(keys.len()),
+        pvpc: Vec::with_capacity(keys.len()),
+        spot: Vec::with_capacity(keys.len()),
+        spread: Vec::with_capacity(keys.len()),
+    };
+
+    for key in keys {
+        let pvpc_price = pvpc.get(&key).copied();
+        let spot_price = spot.get(&key).copied();
+        comparison.hours.push(key.0);
+        comparison.pvpc.push(pvpc_price);
+        comparison.spot.push(spot_price);
+        comparison.spread.push(pvpc_price.zip(spot_price).map(|(p, s)| p - s));
+    }
+
+    comparison
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{App, HttpServer};
+    use shared::HourlyPrice;
+    use wiremock::matchers::{method, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::services::forecast::FORECAST_CACHE_MINUTES;
+    use crate::test_utils::{auth_header, create_user, test_config};
+
+    fn day(prices: &[(u8, f64)]) -> DailyPrices {
+        DailyPrices {
+            date: NaiveDate::from_ymd_opt(2024, 10, 27).unwrap(),
+            prices: prices.iter().map(|&(hour, price)| HourlyPrice { hour, price, raw_price: None }).collect(),
+            quarter_hours: vec![],
+        }
+    }
+
+    #[test]
+    fn test_align_prices_with_missing_and_repeated_hours() {
+        let date = NaiveDate::from_ymd_opt(2024, 10, 27).unwrap();
+        // PVPC sense l'hora 3; les dues sèries amb l'hora 2 repetida (canvi d'horari)
+        let pvpc = day(&[(1, 0.12), (2, 0.10), (2, 0.11)]);
+        let spot = day(&[(1, 0.05), (2, 0.04), (2, 0.03), (3, 0.06)]);
+
+        let comparison = align_prices(date, &pvpc, &spot);
+
+        assert_eq!(comparison.hours, vec![1, 2, 2, 3]);
+        assert_eq!(comparison.pvpc, vec![Some(0.12), Some(0.10), Some(0.11), None]);
+        assert_eq!(comparison.spot, vec![Some(0.05), Some(0.04), Some(0.03), Some(0.06)]);
+        assert!((comparison.spread[0].unwrap() - 0.07).abs() < 1e-9);
+        assert!((comparison.spread[2].unwrap() - 0.08).abs() < 1e-9);
+        assert_eq!(comparison.spread[3], None);
+    }
+
+    #[test]
+    fn test_chart_data_colors_by_quartile() {
+        // 0.01, 0.02, ..., 0.24: el p25 és el 6è preu i el p75 el 18è
+        let prices: Vec<(u8, f64)> = (0..24).map(|h| (h, f64::from(h + 1) / 100.0)).collect();
+        let chart = chart_data(&day(&prices), 13).unwrap();
+
+        assert_eq!(chart.hours.len(), 24);
+        assert_eq!(chart.prices.len(), 24);
+        assert_eq!(chart.colors.len(), 24);
+        assert_eq!((chart.threshold_cheap, chart.threshold_expensive), (0.06, 0.18));
+        assert_eq!(chart.current_hour, 13);
+
+        assert!(chart.colors[..5].iter().all(|c| c == "cheap"));
+        assert!(chart.colors[5..18].iter().all(|c| c == "normal"));
+        assert!(chart.colors[18..].iter().all(|c| c == "expensive"));
+
+        assert!(chart_data(&day(&[]), 13).is_none());
+    }
+
+    #[test]
+    fn test_percentiles_nearest_rank() {
+        let mut prices: Vec<f64> = (1..=20).rev().map(|i| i as f64 / 100.0).collect();
+        let p = percentiles(&mut prices).unwrap();
+        assert_eq!((p.p10, p.p25, p.p50, p.p75, p.p90), (0.02, 0.05, 0.10, 0.15, 0.18));
+
+        let p = percentiles(&mut [0.3]).unwrap();
+        assert_eq!((p.p10, p.p90), (0.3, 0.3));
+        assert!(percentiles(&mut []).is_none());
+    }
+
+    #[test]
+    fn test_price_percentiles_by_hour() {
+        let from = NaiveDate::from_ymd_opt(2024, 10, 21).unwrap();
+        let to = NaiveDate::from_ymd_opt(2024, 10, 27).unwrap();
+        let history = vec![day(&[(0, 0.10), (1, 0.30)]), day(&[(0, 0.20)]), day(&[])];
+
+        let stats = price_percentiles(from, to, &history).unwrap();
+
+        assert_eq!(stats.days_with_data, 2);
+        assert_eq!(stats.hours.iter().map(|h| (h.hour, h.samples)).collect::<Vec<_>>(), vec![(0, 2), (1, 1)]);
+        assert_eq!(stats.hours[0].percentiles.p50, 0.10);
+        assert_eq!(stats.hours[1].percentiles.p10, 0.30);
+        assert_eq!((stats.overall.p10, stats.overall.p50, stats.overall.p90), (0.10, 0.20, 0.30));
+        assert!(price_percentiles(from, to, &[day(&[])]).is_none());
+    }
+
+    mod percentile_properties {
+        use super::*;
+        use proptest::prelude::*;
+
+        const LEVELS: [usize; 5] = [10, 25, 50, 75, 90];
+
+        fn values(p: &Percentiles) -> [f64; 5] {
+            [p.p10, p.p25, p.p50, p.p75, p.p90]
         }
+
+        proptest! {
+            #[test]
+            fn percentiles_are_ordered(mut prices in prop::collection::vec(-0.5f64..1.0, 1..200)) {
+                let p = values(&percentiles(&mut prices).unwrap());
+                prop_assert!(p.windows(2).all(|w| w[0] <= w[1]));
+            }
+
+            #[test]
+            fn percentiles_are_input_prices(prices in prop::collection::vec(-0.5f64..1.0, 1..200)) {
+                let p = percentiles(&mut prices.clone()).unwrap();
+                for value in values(&p) {
+                    prop_assert!(prices.contains(&value));
+                }
+            }
+
+            #[test]
+            fn percentiles_cover_their_share(prices in prop::collection::vec(-0.5f64..1.0, 1..200)) {
+                let p = percentiles(&mut prices.clone()).unwrap();
+                for (level, value) in LEVELS.iter().zip(values(&p)) {
+                    let below = prices.iter().filter(|&&x| x <= value).count();
+                    let strictly_below = prices.iter().filter(|&&x| x < value).count();
+                    // Com a mínim el p% dels preus són <= al percentil, i menys del p% en són estrictament inferiors
+                    prop_assert!(below * 100 >= level * prices.len());
+                    prop_assert!(strictly_below * 100 < level * prices.len());
+                }
+            }
+        }
+    }
+
+    /// Resposta d'ESIOS per un indicador: (datetime, valor en €/MWh, geo_id)
+    fn esios_body(values: &[(&str, f64, i32)]) -> serde_json::Value {
+        let values: Vec<_> = values
+            .iter()
+            .map(|(datetime, value, geo_id)| {
+                serde_json::json!({ "value": value, "datetime": datetime, "geo_id": geo_id })
+            })
+            .collect();
+        serde_json::json!({ "indicator": { "values": values } })
+    }
+
+    #[test]
+    fn test_range_is_capped() {
+        let from = NaiveDate::from_ymd_opt(2025, 1, 1).unwrap();
+        assert!(validate_range(from, from).is_ok());
+        assert!(validate_range(from, from + Duration::days(MAX_RANGE_DAYS - 1)).is_ok());
+        assert!(matches!(
+            validate_range(from, from + Duration::days(MAX_RANGE_DAYS)),
+            Err(AppError::BadRequest(..))
+        ));
+        assert!(matches!(
+            validate_range(from, from - Duration::days(1)),
+            Err(AppError::BadRequest(..))
+        ));
+    }
+
+    #[test]
+    fn test_dates_to_fetch_skips_cached_past_days() {
+        let date = |d| NaiveDate::from_ymd_opt(2025, 1, d).unwrap();
+        let cached = |d| DailyPrices {
+            date: date(d),
+            prices: vec![HourlyPrice { hour: 0, price: 0.1, raw_price: None }],
+            quarter_hours: vec![],
+        };
+        let history = [cached(1), cached(3), cached(5)];
+
+        // Dia 2 no és a l'historial; dia 5 és avui i es torna a demanar
+        assert_eq!(dates_to_fetch(date(1), date(6), date(5), &history), vec![date(2), date(4), date(5), date(6)]);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_price_range_merges_history_and_esios(pool: PgPool) {
+        // Només falta el dia 2: una sola petició a ESIOS, només per aquest dia
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(query_param("start_date", "2025-01-02T00:00:00"))
+            .and(query_param("end_date", "2025-01-02T23:59:59"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(esios_body(&[
+                ("2025-01-02T00:00:00.000+01:00", 200.0, 8741),
+                ("2025-01-02T01:00:00.000+01:00", 210.0, 8741),
+            ])))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let date = |d| NaiveDate::from_ymd_opt(2025, 1, d).unwrap();
+        for d in [1, 3] {
+            let mut prices = day(&[(0, 0.1), (1, 0.2)]);
+            prices.date = date(d);
+            store_prices(&pool, &prices).await.unwrap();
+        }
+
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(test_config()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/api/prices/range?from=2025-01-01&to=2025-01-03").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let body: serde_json::Value = read_body_json(resp).await;
+        let dates: Vec<&str> = body.as_array().unwrap().iter().map(|d| d["date"].as_str().unwrap()).collect();
+        assert_eq!(dates, ["2025-01-01", "2025-01-02", "2025-01-03"]);
+        assert_eq!(body[0]["prices"][0]["price"], 0.1);
+        assert_eq!(body[1]["prices"][0]["price"], 0.2);
+        // L'1 de gener és festiu: vall tot el dia
+        assert_eq!(body[0]["prices"][0]["tariff_period"], "P3");
+
+        // El dia demanat a ESIOS queda desat
+        assert_eq!(get_prices_between(&pool, date(2), date(2)).await.unwrap().len(), 1);
+
+        let req = TestRequest::get().uri("/api/prices/range?from=2025-01-01&to=2025-02-01").to_request();
+        assert_eq!(call_service(&app, req).await.status(), 400);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_prices_status_reflects_fetches(pool: PgPool) {
+        let config = test_config();
+        let today = clock::today(config.timezone);
+        let tomorrow = today + Duration::days(1);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let status = || async {
+            let resp = call_service(&app, TestRequest::get().uri("/api/prices/status").to_request()).await;
+            assert_eq!(resp.status(), 200);
+            read_body_json::<serde_json::Value, _>(resp).await
+        };
+
+        let body = status().await;
+        assert_eq!(body["today"]["available"], false);
+        assert!(body["today"]["fetched_at"].is_null());
+
+        // Avui s'obtenen els preus; per demà, ESIOS falla
+        let mut prices = day(&[(0, 0.1), (1, 0.2), (2, 0.3)]);
+        prices.date = today;
+        store_prices(&pool, &prices).await.unwrap();
+        record_fetch_failure(&pool, tomorrow, "External API error: 503").await.unwrap();
+
+        let body = status().await;
+        assert_eq!(body["today"]["date"], today.to_string());
+        assert_eq!(body["today"]["available"], true);
+        assert_eq!(body["today"]["hours_received"], 3);
+        assert!(body["today"]["fetched_at"].is_string());
+        assert!(body["today"]["last_error"].is_null());
+        assert_eq!(body["tomorrow"]["available"], false);
+        assert_eq!(body["tomorrow"]["last_error"], "External API error: 503");
+
+        // Un èxit posterior esborra l'error
+        prices.date = tomorrow;
+        store_prices(&pool, &prices).await.unwrap();
+        let body = status().await;
+        assert_eq!(body["tomorrow"]["available"], true);
+        assert!(body["tomorrow"]["last_error"].is_null());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_tomorrow_forecast_until_prices_are_published(pool: PgPool) {
+        // ESIOS encara no té els preus de demà
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(esios_body(&[])))
+            .mount(&server)
+            .await;
+
+        let config = test_config();
+        let today = clock::today(config.timezone);
+        for days_ago in 0..7 {
+            let mut prices = day(&[(0, 0.1), (1, 0.2)]);
+            prices.date = today - Duration::days(days_ago);
+            store_prices(&pool, &prices).await.unwrap();
+        }
+
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(config))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let forecast = || async {
+            let resp = call_service(&app, TestRequest::get().uri("/api/prices/tomorrow/forecast").to_request()).await;
+            assert_eq!(resp.status(), 200);
+            read_body_json::<serde_json::Value, _>(resp).await
+        };
+
+        let body = forecast().await;
+        assert_eq!(body["is_forecast"], true);
+        assert_eq!(body["date"], (today + Duration::days(1)).to_string());
+        assert_eq!(body["prices"][0]["price"], 0.1);
+        assert_eq!(body["prices"][1]["price"], 0.2);
+        let cached: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM price_forecasts").fetch_one(&pool).await.unwrap();
+        assert_eq!(cached, 1);
+
+        // Mentre l'estimació és recent, no es torna a consultar ESIOS
+        let requests = server.received_requests().await.unwrap().len();
+        let body = forecast().await;
+        assert_eq!(body["is_forecast"], true);
+        assert_eq!(server.received_requests().await.unwrap().len(), requests);
+
+        // Un cop caducada l'estimació i publicats els preus, es retornen els oficials
+        sqlx::query("UPDATE price_forecasts SET created_at = NOW() - make_interval(mins => $1)")
+            .bind(FORECAST_CACHE_MINUTES as i32 + 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+        server.reset().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(esios_body(&[
+                ("2025-01-02T00:00:00.000+01:00", 300.0, 8741),
+            ])))
+            .mount(&server)
+            .await;
+        let body = forecast().await;
+        assert_eq!(body["is_forecast"], false);
+        assert_eq!(body["prices"][0]["price"], 0.3);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_fetch_prices_stores_day(pool: PgPool) {
+        let config = test_config();
+        let today = clock::today(config.timezone);
+        let date = today - Duration::days(30);
+        let tomorrow = today + Duration::days(1);
+        let day_values = |date: NaiveDate, hours: u32| -> Vec<String> {
+            (0..hours).map(|h| format!("{}T{:02}:00:00.000+01:00", date, h)).collect()
+        };
+        let server = MockServer::start().await;
+        for (day, hours) in [(date, 24), (tomorrow, 10)] {
+            let datetimes = day_values(day, hours);
+            let values: Vec<_> = datetimes.iter().map(|dt| (dt.as_str(), 100.0, 8741)).collect();
+            Mock::given(method("GET"))
+                .and(query_param("start_date", format!("{}T00:00:00", day)))
+                .respond_with(ResponseTemplate::new(200).set_body_json(esios_body(&values)))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let user = create_user(&pool, "fetch").await;
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let fetch = |date: NaiveDate| {
+            TestRequest::post()
+                .uri("/api/prices/fetch")
+                .insert_header(auth_header(&user, &config))
+                .set_json(serde_json::json!({ "date": date }))
+                .to_request()
+        };
+
+        let resp = call_service(&app, fetch(date)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["date"], date.to_string());
+        assert_eq!(body["prices"].as_array().unwrap().len(), 24);
+
+        let cached = get_prices_between(&pool, date, date).await.unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].prices.len(), 24);
+
+        // Demà a mitges: no es desa
+        let resp = call_service(&app, fetch(tomorrow)).await;
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "PRICES_INCOMPLETE");
+        assert!(get_prices_between(&pool, tomorrow, tomorrow).await.unwrap().is_empty());
+
+        // Més enllà de l'endemà de demà o de l'historial no es demana res a ESIOS
+        let too_far = today + Duration::days(MAX_FETCH_DAYS_AHEAD + 1);
+        let resp = call_service(&app, fetch(too_far)).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "DATE_TOO_FAR");
+        let too_old = today - Duration::days(config.price_history_days + 1);
+        let resp = call_service(&app, fetch(too_old)).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "DATE_TOO_OLD");
+
+        let req = TestRequest::post()
+            .uri("/api/prices/fetch")
+            .set_json(serde_json::json!({ "date": date }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+    }
+
+    #[actix_web::test]
+    async fn test_compare_prices_with_mocked_esios() {
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/indicators/{id}",
+                web::get().to(|path: web::Path<u32>| async move {
+                    let body = match path.into_inner() {
+                        INDICATOR_PVPC => esios_body(&[
+                            ("2025-11-03T00:00:00.000+01:00", 120.0, 8741),
+                            ("2025-11-03T01:00:00.000+01:00", 110.0, 8741),
+                        ]),
+                        // Spot quart-horari, amb valors de Portugal que s'han d'ignorar
+                        _ => esios_body(&[
+                            ("2025-11-03T00:00:00.000+01:00", 40.0, 3),
+                            ("2025-11-03T00:15:00.000+01:00", 60.0, 3),
+                            ("2025-11-03T00:00:00.000+01:00", 999.0, 2),
+                            ("2025-11-03T01:00:00.000+01:00", 30.0, 3),
+                        ]),
+                    };
+                    HttpResponse::Ok().json(body)
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(format!("http://{}", addr));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(test_config()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/api/prices/compare?date=2025-11-03").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["date"], "2025-11-03");
+        assert_eq!(body["hours"], serde_json::json!([0, 1]));
+        assert_eq!(body["pvpc"], serde_json::json!([0.12, 0.11]));
+        assert_eq!(body["spot"], serde_json::json!([0.05, 0.03]));
+        assert!((body["spread"][0].as_f64().unwrap() - 0.07).abs() < 1e-9);
+
+        handle.stop(false).await;
     }
 }
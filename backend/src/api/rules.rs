@@ -1,71 +1,555 @@
-use actix_web::{delete, get, post, put, web, HttpRequest, HttpResponse};
-use chrono::{Datelike, Local, NaiveTime};
+use std::collections::BTreeMap;
+
+use actix_web::{delete, get, patch, post, put, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, NaiveDate, NaiveTime, Timelike, Utc};
+use chrono_tz::Tz;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use shared::{BlackoutWindow, DailyPrices, HourlyPrice, SubBudget};
+use sqlx::types::Json;
+use sqlx::{FromRow, PgConnection, PgPool};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 use crate::config::Config;
-use crate::db::models::{Device, Rule};
-use crate::error::{AppError, AppResult};
+use crate::db::models::{ActionType, ContinuityPreference, Device, DeviceGroup, Rule, RuleMode, UserPreferences};
+use crate::error::{AppError, AppResult, ErrorResponse, FieldError};
+use crate::services::{clock, metrics};
+use crate::services::price_history::{passes_baseline_gate, store_prices};
 use crate::services::pvpc::PvpcClient;
-use crate::services::scheduler::calculate_optimal_hours;
+use crate::services::secrets::pvpc_client_for_user;
+use crate::services::schedule_events::{ScheduleEvent, ScheduleEvents};
+use crate::services::scheduled_actions::{
+    cancel_pending_actions, insert_rule_actions, load_group_limit, record_skip, PendingScope, SkipReason,
+    SUPERSEDED_STATUS,
+};
+use crate::services::scheduler::{optimal_hours_for_rule, stagger_group_hours, time_window_hours, PriceSlots};
+use crate::services::webhooks::WebhookDispatcher;
 
-use super::auth::extract_user_from_request;
+use super::auth::{deserialize_some, extract_user_from_request, load_preferences};
+use super::named_windows::find_named_window;
+use super::pagination::{PageQuery, Paginated};
+use super::prices::validate_range;
+use super::schedule::{action_kwh, action_power_watts, resolve_calculate_date};
+use super::validation::{FieldErrors, Replacement, Validate, Validated};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CreateRuleRequest {
-    pub device_id: Uuid,
+    /// Dispositiu de la regla. Cal indicar `device_id` o `device_group_id`, no tots dos.
+    pub device_id: Option<Uuid>,
+    /// Grup de dispositius: es genera una acció per cada dispositiu del grup
+    pub device_group_id: Option<Uuid>,
     pub name: String,
-    pub max_hours: i32,
+    /// Per defecte, `DEFAULT_MAX_HOURS` del desplegament (obligatori si no està configurat)
+    pub max_hours: Option<i32>,
+    /// Inici de la finestra horària. Si és posterior al final, la finestra creua mitjanit.
     pub time_window_start: Option<NaiveTime>,
+    /// Final de la finestra horària; la finestra ha de tenir com a mínim `min_continuous_hours` hores senceres
     pub time_window_end: Option<NaiveTime>,
+    /// Nom d'una finestra desada (`/api/named-windows`) en lloc de `time_window_start` i `time_window_end`.
+    /// La regla en guarda la referència: els canvis de la finestra s'hi poden propagar.
+    pub named_window: Option<String>,
+    /// Per defecte, `DEFAULT_MIN_CONTINUOUS` del desplegament (1 si no està configurat)
     pub min_continuous_hours: Option<i32>,
+    /// Hores mínimes sense programar entre dues hores saltejades (0-23; 0 o null, sense separació).
+    /// No es pot combinar amb `min_continuous_hours` més gran que 1. Amb separació, el
+    /// `min_continuous_hours` per defecte és 1.
+    pub min_gap_hours: Option<i32>,
+    /// Els dies amb preus negatius dins la finestra, `max_hours` s'amplia fins a les hores amb
+    /// preu negatiu, com a màxim aquestes (1-24; 0 o null, sense ampliació). Només `turn_on`.
+    pub negative_price_boost: Option<i32>,
+    /// Amb `preferred`, si no hi ha cap bloc continu es programen hores saltejades.
+    /// Per defecte, `required`.
+    pub continuity_preference: Option<ContinuityPreference>,
+    /// Amb `fixed`, es programen sempre `fixed_hours` (o tota la finestra horària) sense mirar
+    /// el preu. Per defecte, `optimize`.
+    pub mode: Option<RuleMode>,
+    /// Hores del dia (0-23) de les regles `fixed`
+    pub fixed_hours: Option<Vec<i32>>,
+    /// Cost màxim (€) de les hores d'un dia. Cal que els dispositius tinguin `wattage_watts`;
+    /// s'ignora a les regles d'apagar.
+    pub max_cost_eur: Option<f64>,
     pub days_of_week: Option<i32>,
+    pub baseline_days: Option<i32>,
+    pub baseline_margin_pct: Option<f64>,
+    pub action_type: Option<ActionType>,
+    pub blackout_windows: Option<Vec<BlackoutWindow>>,
+    /// Si n'hi ha, substitueixen max_hours i la finestra de la regla
+    pub sub_budgets: Option<Vec<SubBudget>>,
+    /// Prioritat en cas de conflicte (1 = màxima)
+    pub priority: Option<i32>,
+    /// Pes relatiu del consum per cada hora del dia (24 valors)
+    pub load_profile: Option<Vec<f64>>,
+    /// Primer dia de la temporada de la regla (per defecte, sense límit)
+    pub active_from: Option<NaiveDate>,
+    /// Últim dia de la temporada de la regla (per defecte, sense límit)
+    pub active_until: Option<NaiveDate>,
+    /// Si és false, no es generen schedules en crear la regla (es faran a la generació diària dels schedules de demà).
+    /// Per defecte, true.
+    pub generate_now: Option<bool>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct UpdateRuleRequest {
+    /// Versió de la regla que s'ha llegit; si ja no és l'actual, es respon 409
+    pub version: i64,
+    #[serde(flatten)]
+    pub changes: RuleChanges,
+}
+
+/// Camps d'una regla que es poden canviar amb PATCH; els absents no canvien
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RuleChanges {
     pub name: Option<String>,
     pub max_hours: Option<i32>,
     pub time_window_start: Option<NaiveTime>,
     pub time_window_end: Option<NaiveTime>,
     pub min_continuous_hours: Option<i32>,
+    /// Hores mínimes entre dues hores saltejades. `0` elimina la separació.
+    pub min_gap_hours: Option<i32>,
+    /// Límit de l'ampliació de `max_hours` els dies amb preus negatius. `0` l'elimina.
+    pub negative_price_boost: Option<i32>,
+    pub continuity_preference: Option<ContinuityPreference>,
+    pub mode: Option<RuleMode>,
+    /// Hores del dia (0-23) de les regles `fixed`. `[]` les elimina (tota la finestra horària).
+    pub fixed_hours: Option<Vec<i32>>,
+    /// Cost màxim (€) de les hores d'un dia. `0` elimina el límit.
+    pub max_cost_eur: Option<f64>,
     pub days_of_week: Option<i32>,
     pub is_enabled: Option<bool>,
+    /// Dies de preus amb què es comparen les hores seleccionades. `0` elimina la condició.
+    pub baseline_days: Option<i32>,
+    pub baseline_margin_pct: Option<f64>,
+    pub action_type: Option<ActionType>,
+    pub blackout_windows: Option<Vec<BlackoutWindow>>,
+    /// Si n'hi ha, substitueixen max_hours i la finestra de la regla
+    pub sub_budgets: Option<Vec<SubBudget>>,
+    /// Prioritat en cas de conflicte (1 = màxima)
+    pub priority: Option<i32>,
+    /// Pes relatiu del consum per cada hora del dia (24 valors). `[]` elimina el perfil.
+    pub load_profile: Option<Vec<f64>>,
+    /// Primer dia de la temporada. `null` l'elimina (sense límit); absent, no el canvia.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>, format = Date)]
+    pub active_from: Option<Option<NaiveDate>>,
+    /// Últim dia de la temporada. `null` l'elimina (sense límit); absent, no el canvia.
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[schema(value_type = Option<String>, format = Date)]
+    pub active_until: Option<Option<NaiveDate>>,
+}
+
+impl RuleChanges {
+    /// Regla resultant d'aplicar els camps presents a `current`, amb les comprovacions
+    /// entre camps que depenen dels valors actuals
+    fn apply_to(&self, current: &Rule) -> AppResult<Rule> {
+        let rule = Rule {
+            name: self.name.clone().unwrap_or_else(|| current.name.clone()),
+            max_hours: self.max_hours.unwrap_or(current.max_hours),
+            time_window_start: self.time_window_start.or(current.time_window_start),
+            time_window_end: self.time_window_end.or(current.time_window_end),
+            min_continuous_hours: self.min_continuous_hours.unwrap_or(current.min_continuous_hours),
+            // Separació: absent = no canvia, 0 = s'elimina
+            min_gap_hours: self.min_gap_hours.map_or(current.min_gap_hours, |gap| Some(gap).filter(|g| *g > 0)),
+            negative_price_boost: self
+                .negative_price_boost
+                .map_or(current.negative_price_boost, |boost| Some(boost).filter(|b| *b > 0)),
+            continuity_preference: self.continuity_preference.unwrap_or(current.continuity_preference),
+            mode: self.mode.unwrap_or(current.mode),
+            // Hores fixes: absent = no canvia, [] = s'eliminen
+            fixed_hours: match &self.fixed_hours {
+                Some(hours) => Some(hours.clone()).filter(|h| !h.is_empty()),
+                None => current.fixed_hours.clone(),
+            },
+            // Cost màxim: absent = no canvia, 0 = s'elimina
+            max_cost_eur: self.max_cost_eur.map_or(current.max_cost_eur, |cost| Some(cost).filter(|c| *c > 0.0)),
+            days_of_week: self.days_of_week.unwrap_or(current.days_of_week),
+            is_enabled: self.is_enabled.unwrap_or(current.is_enabled),
+            // Condició dels darrers dies: absent = no canvia, 0 = s'elimina
+            baseline_days: self.baseline_days.map_or(current.baseline_days, |days| Some(days).filter(|d| *d > 0)),
+            baseline_margin_pct: self.baseline_margin_pct.unwrap_or(current.baseline_margin_pct),
+            action_type: self.action_type.unwrap_or(current.action_type),
+            blackout_windows: self.blackout_windows.clone().map_or_else(|| current.blackout_windows.clone(), Json),
+            sub_budgets: self.sub_budgets.clone().map_or_else(|| current.sub_budgets.clone(), Json),
+            priority: self.priority.unwrap_or(current.priority),
+            active_from: self.active_from.unwrap_or(current.active_from),
+            active_until: self.active_until.unwrap_or(current.active_until),
+            // Perfil de consum: absent = no canvia, [] = s'elimina
+            load_profile: match &self.load_profile {
+                Some(weights) => Some(weights.clone()).filter(|w| !w.is_empty()),
+                None => current.load_profile.clone(),
+            },
+            ..current.clone()
+        };
+        let rule = detach_changed_window(rule, current);
+
+        let mut errors = FieldErrors::default();
+        errors.check(validate_min_continuous_hours(rule.min_continuous_hours, rule.max_hours));
+        errors.check(validate_min_gap_hours(rule.min_gap_hours, rule.min_continuous_hours));
+        errors.check(validate_time_window(rule.time_window_start, rule.time_window_end, rule.min_continuous_hours));
+        errors.check(validate_season(rule.active_from, rule.active_until));
+        errors.check(validate_fixed_mode(
+            rule.mode,
+            rule.fixed_hours.as_deref(),
+            rule.time_window_start.is_some() || rule.time_window_end.is_some(),
+        ));
+        errors.into_result()?;
+
+        Ok(rule)
+    }
+}
+
+/// Regla sencera per substituir-ne una amb PUT. Els camps que poden ser null, si falten,
+/// queden a null; la resta són obligatoris. El dispositiu o grup de la regla no es pot canviar.
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ReplaceRuleRequest {
+    /// Versió de la regla que s'ha llegit; si ja no és l'actual, es respon 409
+    pub version: i64,
+    pub name: String,
+    pub max_hours: i32,
+    pub time_window_start: Option<NaiveTime>,
+    pub time_window_end: Option<NaiveTime>,
+    pub min_continuous_hours: i32,
+    /// Hores mínimes entre dues hores saltejades. Null o 0: sense separació.
+    pub min_gap_hours: Option<i32>,
+    /// Límit de l'ampliació de `max_hours` els dies amb preus negatius. Null o 0: sense ampliació.
+    pub negative_price_boost: Option<i32>,
+    pub continuity_preference: ContinuityPreference,
+    pub mode: RuleMode,
+    /// Hores del dia (0-23) de les regles `fixed`. Null o `[]`: tota la finestra horària.
+    pub fixed_hours: Option<Vec<i32>>,
+    pub max_cost_eur: Option<f64>,
+    pub days_of_week: i32,
+    pub is_enabled: bool,
+    pub baseline_days: Option<i32>,
+    pub baseline_margin_pct: f64,
+    pub action_type: ActionType,
+    pub blackout_windows: Vec<BlackoutWindow>,
+    pub sub_budgets: Vec<SubBudget>,
+    /// Prioritat en cas de conflicte (1 = màxima)
+    pub priority: i32,
+    /// Pes relatiu del consum per cada hora del dia (24 valors). Null o `[]`: sense perfil.
+    pub load_profile: Option<Vec<f64>>,
+    pub active_from: Option<NaiveDate>,
+    pub active_until: Option<NaiveDate>,
+}
+
+impl ReplaceRuleRequest {
+    /// Regla resultant de substituir tots els camps editables de `current`
+    fn apply_to(&self, current: &Rule) -> Rule {
+        let rule = Rule {
+            name: self.name.clone(),
+            max_hours: self.max_hours,
+            time_window_start: self.time_window_start,
+            time_window_end: self.time_window_end,
+            min_continuous_hours: self.min_continuous_hours,
+            min_gap_hours: self.min_gap_hours.filter(|gap| *gap > 0),
+            negative_price_boost: self.negative_price_boost.filter(|boost| *boost > 0),
+            continuity_preference: self.continuity_preference,
+            mode: self.mode,
+            fixed_hours: self.fixed_hours.clone().filter(|h| !h.is_empty()),
+            max_cost_eur: self.max_cost_eur,
+            days_of_week: self.days_of_week,
+            is_enabled: self.is_enabled,
+            baseline_days: self.baseline_days,
+            baseline_margin_pct: self.baseline_margin_pct,
+            action_type: self.action_type,
+            blackout_windows: Json(self.blackout_windows.clone()),
+            sub_budgets: Json(self.sub_budgets.clone()),
+            priority: self.priority,
+            active_from: self.active_from,
+            active_until: self.active_until,
+            load_profile: self.load_profile.clone().filter(|w| !w.is_empty()),
+            ..current.clone()
+        };
+        detach_changed_window(rule, current)
+    }
+}
+
+/// Si s'han canviat les hores de la finestra, la regla deixa de seguir la finestra amb nom
+fn detach_changed_window(mut rule: Rule, current: &Rule) -> Rule {
+    if (rule.time_window_start, rule.time_window_end) != (current.time_window_start, current.time_window_end) {
+        rule.named_window_id = None;
+    }
+    rule
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PreviewScheduleRequest {
+    pub date: NaiveDate,
+    /// Canvis pendents de la regla (els mateixos camps que PATCH, sense `version`); no es desen
+    #[serde(flatten)]
+    pub changes: RuleChanges,
+}
+
+impl Validate for PreviewScheduleRequest {
+    fn validate(&self) -> AppResult<()> {
+        self.changes.validate()
+    }
+}
+
+/// Hores que es programarien per una regla i una data
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SchedulePreviewResponse {
+    pub date: NaiveDate,
+    /// Franges seleccionades: hores o, si `slot_minutes` és 15, quarts d'hora del dia
+    pub hours: Vec<u8>,
+    /// Durada de cada franja en minuts
+    pub slot_minutes: u32,
+    pub total_price: f64,
+    /// Preu de cada franja seleccionada
+    pub price_per_hour: Vec<HourlyPrice>,
+    pub message: String,
+}
+
+/// Nombre màxim de regles en una actualització en bloc
+pub const MAX_BATCH_RULES: usize = 200;
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct BatchUpdateRulesRequest {
+    pub rule_ids: Vec<Uuid>,
+    pub is_enabled: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct BatchUpdateResult {
+    /// Regles actualitzades
+    pub updated: Vec<Uuid>,
+    /// Regles que no existeixen o no són de l'usuari
+    pub not_found: Vec<Uuid>,
+    /// Schedules creats en regenerar les regles activades
+    pub schedules_created: usize,
+}
+
+/// `max_hours` i `min_continuous_hours` poden faltar: es completen i es tornen a validar
+/// amb les preferències de l'usuari (`apply_preferences`) i els valors per defecte del
+/// desplegament (`apply_defaults`)
+impl Validate for CreateRuleRequest {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        if self.device_id.is_some() == self.device_group_id.is_some() {
+            errors.add("device_id", "REQUIRED", "Exactly one of device_id or device_group_id is required");
+        }
+        if let Some(max_hours) = self.max_hours {
+            errors.check(validate_max_hours(max_hours));
+        }
+        if let Some(min_continuous) = self.min_continuous_hours {
+            errors.check(validate_min_continuous_hours(min_continuous, self.max_hours.unwrap_or(24)));
+        }
+        errors.check(validate_min_gap_hours(self.min_gap_hours, self.min_continuous_hours.unwrap_or(1)));
+        errors.check(validate_negative_price_boost(self.negative_price_boost));
+        errors.check(validate_time_window(
+            self.time_window_start,
+            self.time_window_end,
+            self.min_continuous_hours.unwrap_or(1),
+        ));
+        if let Some(name) = &self.named_window {
+            if name.trim().is_empty() {
+                errors.add("named_window", "EMPTY", "named_window cannot be empty");
+            }
+            if self.time_window_start.is_some() || self.time_window_end.is_some() {
+                errors.add(
+                    "named_window",
+                    "CONFLICT",
+                    "named_window cannot be combined with time_window_start or time_window_end",
+                );
+            }
+        }
+        errors.check(validate_baseline(self.baseline_days, self.baseline_margin_pct.unwrap_or(0.0)));
+        errors.check(validate_blackout_windows(self.blackout_windows.as_deref().unwrap_or_default()));
+        errors.check(validate_sub_budgets(self.sub_budgets.as_deref().unwrap_or_default()));
+        errors.check(validate_priority(self.priority.unwrap_or(1)));
+        if let Some(weights) = self.load_profile.as_deref().filter(|w| !w.is_empty()) {
+            errors.check(validate_load_profile(weights));
+        }
+        errors.check(validate_season(self.active_from, self.active_until));
+        errors.check(validate_max_cost(self.max_cost_eur));
+        errors.check(validate_fixed_mode(
+            self.mode.unwrap_or_default(),
+            self.fixed_hours.as_deref(),
+            self.time_window_start.is_some() || self.time_window_end.is_some() || self.named_window.is_some(),
+        ));
+
+        errors.into_result()
+    }
+}
+
+impl CreateRuleRequest {
+    /// Omple `max_hours` i `days_of_week` omesos amb els valors per defecte de l'usuari.
+    /// Va abans d'`apply_defaults`: les preferències tenen prioritat sobre el desplegament.
+    fn apply_preferences(&mut self, prefs: &UserPreferences) {
+        self.max_hours = self.max_hours.or(prefs.default_max_hours);
+        self.days_of_week = self.days_of_week.or(Some(prefs.default_days_of_week));
+    }
+
+    /// Omple `max_hours` i `min_continuous_hours` omesos amb els valors per defecte del desplegament
+    ///
+    /// El `min_continuous_hours` per defecte es limita a `max_hours` perquè una regla curta
+    /// no falli per un valor que no ha indicat, i és 1 si la regla té `min_gap_hours`.
+    fn apply_defaults(&mut self, config: &Config) -> AppResult<()> {
+        let max_hours = self
+            .max_hours
+            .or(config.default_max_hours)
+            .ok_or_else(|| FieldError::new("max_hours", "REQUIRED", "max_hours is required"))?;
+        let min_continuous = self.min_continuous_hours.unwrap_or_else(|| {
+            if self.min_gap_hours.is_some_and(|gap| gap > 0) {
+                1
+            } else {
+                config.default_min_continuous.min(max_hours)
+            }
+        });
+        validate_min_continuous_hours(min_continuous, max_hours)?;
+        validate_time_window(self.time_window_start, self.time_window_end, min_continuous)?;
+
+        self.max_hours = Some(max_hours);
+        self.min_continuous_hours = Some(min_continuous);
+        Ok(())
+    }
+}
+
+/// Només valida els camps presents. Les comprovacions que depenen dels valors
+/// actuals de la regla (p. ex. `min_continuous_hours <= max_hours`) es fan al handler.
+impl Validate for RuleChanges {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        if let Some(max_hours) = self.max_hours {
+            errors.check(validate_max_hours(max_hours));
+        }
+        if let Some(min_continuous) = self.min_continuous_hours {
+            errors.check(validate_min_continuous_hours(min_continuous, self.max_hours.unwrap_or(24)));
+        }
+        errors.check(validate_min_gap_hours(self.min_gap_hours, self.min_continuous_hours.unwrap_or(1)));
+        errors.check(validate_negative_price_boost(self.negative_price_boost));
+        // Amb només un extrem, l'amplada depèn de l'altre extrem actual de la regla
+        if self.time_window_start.is_some() && self.time_window_end.is_some() {
+            errors.check(validate_time_window(
+                self.time_window_start,
+                self.time_window_end,
+                self.min_continuous_hours.unwrap_or(1),
+            ));
+        }
+        errors.check(validate_baseline(
+            self.baseline_days.filter(|days| *days != 0),
+            self.baseline_margin_pct.unwrap_or(0.0),
+        ));
+        errors.check(validate_blackout_windows(self.blackout_windows.as_deref().unwrap_or_default()));
+        errors.check(validate_sub_budgets(self.sub_budgets.as_deref().unwrap_or_default()));
+        if let Some(priority) = self.priority {
+            errors.check(validate_priority(priority));
+        }
+        if let Some(weights) = self.load_profile.as_deref().filter(|w| !w.is_empty()) {
+            errors.check(validate_load_profile(weights));
+        }
+        errors.check(validate_season(self.active_from.flatten(), self.active_until.flatten()));
+        errors.check(validate_max_cost(self.max_cost_eur.filter(|cost| *cost != 0.0)));
+        if let Some(hours) = &self.fixed_hours {
+            errors.check(validate_fixed_hours(hours));
+        }
+
+        errors.into_result()
+    }
+}
+
+impl Validate for UpdateRuleRequest {
+    fn validate(&self) -> AppResult<()> {
+        self.changes.validate()
+    }
+}
+
+/// Tots els camps hi són: es validen també les comprovacions entre camps
+impl Validate for ReplaceRuleRequest {
+    fn validate(&self) -> AppResult<()> {
+        let mut errors = FieldErrors::default();
+        errors.check(validate_max_hours(self.max_hours));
+        errors.check(validate_min_continuous_hours(self.min_continuous_hours, self.max_hours));
+        errors.check(validate_min_gap_hours(self.min_gap_hours, self.min_continuous_hours));
+        errors.check(validate_negative_price_boost(self.negative_price_boost));
+        errors.check(validate_time_window(self.time_window_start, self.time_window_end, self.min_continuous_hours));
+        errors.check(validate_baseline(self.baseline_days, self.baseline_margin_pct));
+        errors.check(validate_blackout_windows(&self.blackout_windows));
+        errors.check(validate_sub_budgets(&self.sub_budgets));
+        errors.check(validate_priority(self.priority));
+        if let Some(weights) = self.load_profile.as_deref().filter(|w| !w.is_empty()) {
+            errors.check(validate_load_profile(weights));
+        }
+        errors.check(validate_season(self.active_from, self.active_until));
+        errors.check(validate_max_cost(self.max_cost_eur));
+        errors.check(validate_fixed_mode(
+            self.mode,
+            self.fixed_hours.as_deref(),
+            self.time_window_start.is_some() || self.time_window_end.is_some(),
+        ));
+
+        errors.into_result()
+    }
+}
+
+impl Validate for BatchUpdateRulesRequest {
+    fn validate(&self) -> AppResult<()> {
+        if self.rule_ids.is_empty() {
+            return Err(FieldError::new("rule_ids", "EMPTY", "rule_ids cannot be empty").into());
+        }
+
+        if self.rule_ids.len() > MAX_BATCH_RULES {
+            return Err(FieldError::new(
+                "rule_ids",
+                "OUT_OF_RANGE",
+                format!("rule_ids cannot have more than {} values", MAX_BATCH_RULES),
+            )
+            .into());
+        }
+
+        Ok(())
+    }
 }
 
 /// Struct per queries amb JOIN
 #[derive(Debug, FromRow)]
-struct RuleWithDevice {
-    id: Uuid,
-    device_id: Uuid,
-    name: String,
-    max_hours: i32,
-    time_window_start: Option<NaiveTime>,
-    time_window_end: Option<NaiveTime>,
-    min_continuous_hours: i32,
-    days_of_week: i32,
-    is_enabled: bool,
-    device_name: String,
+pub(crate) struct RuleWithDevice {
+    #[sqlx(flatten)]
+    pub(crate) rule: Rule,
+    pub(crate) device_name: Option<String>,
+    pub(crate) device_group_name: Option<String>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct RuleResponse {
     pub id: Uuid,
-    pub device_id: Uuid,
-    pub device_name: String,
+    /// Null si la regla és d'un grup
+    pub device_id: Option<Uuid>,
+    pub device_name: Option<String>,
+    /// Null si la regla és d'un sol dispositiu
+    pub device_group_id: Option<Uuid>,
+    pub device_group_name: Option<String>,
     pub name: String,
     pub max_hours: i32,
     pub time_window_start: Option<NaiveTime>,
     pub time_window_end: Option<NaiveTime>,
+    /// Finestra amb nom que segueix la regla (null si les hores són pròpies)
+    pub named_window_id: Option<Uuid>,
     pub min_continuous_hours: i32,
+    pub min_gap_hours: Option<i32>,
+    pub negative_price_boost: Option<i32>,
+    pub continuity_preference: ContinuityPreference,
+    pub mode: RuleMode,
+    pub fixed_hours: Option<Vec<i32>>,
+    pub max_cost_eur: Option<f64>,
     pub days_of_week: i32,
     pub is_enabled: bool,
+    pub baseline_days: Option<i32>,
+    pub baseline_margin_pct: f64,
+    pub action_type: ActionType,
+    pub blackout_windows: Vec<BlackoutWindow>,
+    pub sub_budgets: Vec<SubBudget>,
+    pub priority: i32,
+    pub load_profile: Option<Vec<f64>>,
+    pub active_from: Option<NaiveDate>,
+    pub active_until: Option<NaiveDate>,
+    /// Versió actual; s'ha d'enviar en actualitzar la regla
+    pub version: i64,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub schedule_info: Option<ScheduleGenerationInfo>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, ToSchema)]
 pub struct ScheduleGenerationInfo {
     pub schedules_created: usize,
     pub message: String,
@@ -73,140 +557,233 @@ pub struct ScheduleGenerationInfo {
 
 impl From<RuleWithDevice> for RuleResponse {
     fn from(r: RuleWithDevice) -> Self {
+        let RuleWithDevice { rule, device_name, device_group_name } = r;
         Self {
-            id: r.id,
-            device_id: r.device_id,
-            device_name: r.device_name,
-            name: r.name,
-            max_hours: r.max_hours,
-            time_window_start: r.time_window_start,
-            time_window_end: r.time_window_end,
-            min_continuous_hours: r.min_continuous_hours,
-            days_of_week: r.days_of_week,
-            is_enabled: r.is_enabled,
+            id: rule.id,
+            device_id: rule.device_id,
+            device_name,
+            device_group_id: rule.device_group_id,
+            device_group_name,
+            name: rule.name,
+            max_hours: rule.max_hours,
+            time_window_start: rule.time_window_start,
+            time_window_end: rule.time_window_end,
+            named_window_id: rule.named_window_id,
+            min_continuous_hours: rule.min_continuous_hours,
+            min_gap_hours: rule.min_gap_hours,
+            negative_price_boost: rule.negative_price_boost,
+            continuity_preference: rule.continuity_preference,
+            mode: rule.mode,
+            fixed_hours: rule.fixed_hours,
+            max_cost_eur: rule.max_cost_eur,
+            days_of_week: rule.days_of_week,
+            is_enabled: rule.is_enabled,
+            baseline_days: rule.baseline_days,
+            baseline_margin_pct: rule.baseline_margin_pct,
+            action_type: rule.action_type,
+            blackout_windows: rule.blackout_windows.0,
+            sub_budgets: rule.sub_budgets.0,
+            priority: rule.priority,
+            load_profile: rule.load_profile,
+            active_from: rule.active_from,
+            active_until: rule.active_until,
+            version: rule.version,
             schedule_info: None,
         }
     }
 }
 
+/// Dies que es retornen per defecte a partir de `from` (avui, demà i demà passat)
+const DEFAULT_RULE_SCHEDULE_DAYS: i64 = 2;
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct RuleSchedulesQuery {
+    /// Primer dia (per defecte, avui)
+    pub from: Option<NaiveDate>,
+    /// Darrer dia (per defecte, dos dies després de `from`)
+    pub to: Option<NaiveDate>,
+}
+
+/// Acció programada per una regla, en qualsevol estat
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct RuleScheduleResponse {
+    pub id: Uuid,
+    pub device_id: Uuid,
+    pub device_name: String,
+    pub scheduled_date: NaiveDate,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    /// L'acció acaba l'endemà (`end_time` és de l'endemà)
+    pub crosses_midnight: bool,
+    /// Acció a executar: "on" o "off"
+    pub action: String,
+    pub status: String,
+    /// Preu de la franja programada (€/kWh)
+    pub price_per_kwh: Option<f64>,
+    pub executed_at: Option<DateTime<Utc>>,
+    pub error_message: Option<String>,
+}
+
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(list_rules)
         .service(create_rule)
         .service(get_rule)
+        .service(replace_rule)
         .service(update_rule)
+        .service(batch_update_rules)
+        .service(preview_rule_schedule)
+        .service(list_rule_schedules)
+        .service(get_rule_execution_stats)
         .service(delete_rule);
 }
 
 /// GET /api/rules
+#[utoipa::path(
+    tag = "rules",
+    params(PageQuery),
+    responses(
+        (status = 200, description = "Regles de l'usuari", body = Paginated<RuleResponse>),
+        (status = 400, description = "Paràmetres de paginació invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[get("/rules")]
 async fn list_rules(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
+    page: web::Query<PageQuery>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let page = page.page()?;
+
+    let total: i64 = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) as "count!"
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        WHERE COALESCE(d.user_id, g.user_id) = $1
+        "#,
+        user.id
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
 
     let rules = sqlx::query_as::<_, RuleWithDevice>(
         r#"
-        SELECT r.id, r.device_id, r.name, r.max_hours, r.time_window_start,
-               r.time_window_end, r.min_continuous_hours, r.days_of_week, r.is_enabled,
-               d.name as device_name
+        SELECT r.*, d.name as device_name, g.name as device_group_name, lp.weights as load_profile
         FROM rules r
-        JOIN devices d ON r.device_id = d.id
-        WHERE d.user_id = $1
-        ORDER BY r.name
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE COALESCE(d.user_id, g.user_id) = $1
+        ORDER BY r.name, r.id
+        LIMIT $2 OFFSET $3
         "#
     )
     .bind(user.id)
+    .bind(page.limit)
+    .bind(page.offset)
     .fetch_all(pool.get_ref())
     .await?;
 
-    let response: Vec<RuleResponse> = rules.into_iter().map(Into::into).collect();
-    Ok(HttpResponse::Ok().json(response))
+    let items: Vec<RuleResponse> = rules.into_iter().map(Into::into).collect();
+    Ok(HttpResponse::Ok().json(Paginated::new(items, total, page)))
 }
 
 /// POST /api/rules
+#[utoipa::path(
+    tag = "rules",
+    request_body = CreateRuleRequest,
+    responses(
+        (status = 201, description = "Regla creada", body = RuleResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Dispositiu no trobat", body = ErrorResponse),
+        (status = 422, description = "Camps invàlids (un error per camp)", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[post("/rules")]
 async fn create_rule(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     pvpc: web::Data<PvpcClient>,
+    webhooks: web::Data<WebhookDispatcher>,
+    events: web::Data<ScheduleEvents>,
     req: HttpRequest,
-    body: web::Json<CreateRuleRequest>,
+    body: Validated<CreateRuleRequest>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
 
-    // Verificar que el dispositiu pertany a l'usuari
-    let device = sqlx::query_as::<_, Device>(
-        "SELECT * FROM devices WHERE id = $1 AND user_id = $2"
-    )
-    .bind(body.device_id)
-    .bind(user.id)
-    .fetch_optional(pool.get_ref())
-    .await?
-    .ok_or_else(|| AppError::NotFound("Device not found".to_string()))?;
+    let Validated(mut body) = body;
+    body.apply_preferences(&load_preferences(pool.get_ref(), user.id).await?);
+    // Les hores de la finestra amb nom es copien a la regla abans de validar-la amb els valors per defecte
+    let named_window_id = match &body.named_window {
+        Some(name) => {
+            let window = find_named_window(pool.get_ref(), user.id, name).await?;
+            body.time_window_start = Some(window.start_time);
+            body.time_window_end = Some(window.end_time);
+            Some(window.id)
+        }
+        None => None,
+    };
+    body.apply_defaults(&config)?;
 
-    // Validacions
-    if body.max_hours < 1 || body.max_hours > 24 {
-        return Err(AppError::BadRequest("max_hours must be between 1 and 24".to_string()));
-    }
+    // Verificar que el dispositiu o el grup pertany a l'usuari
+    let target = match (body.device_id, body.device_group_id) {
+        (Some(device_id), _) => sqlx::query_as!(
+            Device,
+            "SELECT * FROM devices WHERE id = $1 AND user_id = $2 AND deleted_at IS NULL",
+            device_id,
+            user.id
+        )
+        .fetch_optional(pool.get_ref())
+        .await?
+        .map(RuleTarget::Device)
+        .ok_or_else(|| AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()))?,
+        (None, group_id) => sqlx::query_as!(
+            DeviceGroup,
+            "SELECT * FROM device_groups WHERE id = $1 AND user_id = $2",
+            group_id,
+            user.id
+        )
+        .fetch_optional(pool.get_ref())
+        .await?
+        .map(RuleTarget::Group)
+        .ok_or_else(|| AppError::NotFound("DEVICE_GROUP_NOT_FOUND", "Device group not found".to_string()))?,
+    };
 
-    let min_continuous = body.min_continuous_hours.unwrap_or(1);
-    if min_continuous < 1 || min_continuous > body.max_hours {
-        return Err(AppError::BadRequest(
-            "min_continuous_hours must be between 1 and max_hours".to_string()
-        ));
-    }
+    let mut tx = pool.begin().await?;
+    let rule = insert_rule(&mut tx, &body, &target, named_window_id).await?;
+    tx.commit().await?;
 
-    let rule = sqlx::query_as::<_, RuleWithDevice>(
-        r#"
-        WITH inserted AS (
-            INSERT INTO rules (device_id, name, max_hours, time_window_start, time_window_end, min_continuous_hours, days_of_week)
-            VALUES ($1, $2, $3, $4, $5, $6, $7)
-            RETURNING *
-        )
-        SELECT i.id, i.device_id, i.name, i.max_hours, i.time_window_start,
-               i.time_window_end, i.min_continuous_hours, i.days_of_week, i.is_enabled,
-               $8::text as device_name
-        FROM inserted i
-        "#
-    )
-    .bind(body.device_id)
-    .bind(&body.name)
-    .bind(body.max_hours)
-    .bind(body.time_window_start)
-    .bind(body.time_window_end)
-    .bind(min_continuous)
-    .bind(body.days_of_week.unwrap_or(127))
-    .bind(&device.name)
-    .fetch_one(pool.get_ref())
-    .await?;
+    if !body.generate_now.unwrap_or(true) {
+        let mut response = RuleResponse::from(rule);
+        response.schedule_info = Some(ScheduleGenerationInfo {
+            schedules_created: 0,
+            message: "Els schedules es generaran a la propera generació diària.".to_string(),
+        });
+        return Ok(HttpResponse::Created().json(response));
+    }
 
     // Generar schedules per la nova regla
-    tracing::info!("Generant schedules per la nova regla '{}'...", rule.name);
-    let db_rule = Rule {
-        id: rule.id,
-        device_id: rule.device_id,
-        name: rule.name.clone(),
-        max_hours: rule.max_hours,
-        time_window_start: rule.time_window_start,
-        time_window_end: rule.time_window_end,
-        min_continuous_hours: rule.min_continuous_hours,
-        days_of_week: rule.days_of_week,
-        is_enabled: rule.is_enabled,
-        created_at: chrono::Utc::now(),
-        updated_at: chrono::Utc::now(),
-    };
+    tracing::info!("Generant schedules per la nova regla '{}'...", rule.rule.name);
 
     // include_past_hours = true: quan es crea una regla, generar schedules per totes les hores
     // del dia (incloses les passades) per tenir l'historial complet
-    let schedule_info = match regenerate_schedules_for_rule(pool.get_ref(), &pvpc, &db_rule, true).await {
+    let regenerated = regenerate_schedules_for_rule(pool.get_ref(), &pvpc, &rule.rule, true, config.timezone).await;
+    let schedule_info = match regenerated {
         Ok(info) => {
-            tracing::info!("Creats {} schedules per la nova regla '{}': {}", info.schedules_created, rule.name, info.message);
+            tracing::info!("Creats {} schedules per la nova regla '{}': {}", info.schedules_created, rule.rule.name, info.message);
+            notify_schedules_generated(&webhooks, &events, pool.get_ref(), user.id, &info, config.timezone);
             Some(info)
         }
         Err(e) => {
-            tracing::error!("Error generant schedules per la nova regla '{}': {}", rule.name, e);
+            tracing::error!("Error generant schedules per la nova regla '{}': {}", rule.rule.name, e);
             None
         }
     };
@@ -218,6 +795,16 @@ async fn create_rule(
 }
 
 /// GET /api/rules/{id}
+#[utoipa::path(
+    tag = "rules",
+    params(("id" = Uuid, Path, description = "Id de la regla")),
+    responses(
+        (status = 200, description = "Regla", body = RuleResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[get("/rules/{id}")]
 async fn get_rule(
     pool: web::Data<PgPool>,
@@ -230,122 +817,235 @@ async fn get_rule(
 
     let rule = sqlx::query_as::<_, RuleWithDevice>(
         r#"
-        SELECT r.id, r.device_id, r.name, r.max_hours, r.time_window_start,
-               r.time_window_end, r.min_continuous_hours, r.days_of_week, r.is_enabled,
-               d.name as device_name
+        SELECT r.*, d.name as device_name, g.name as device_group_name, lp.weights as load_profile
         FROM rules r
-        JOIN devices d ON r.device_id = d.id
-        WHERE r.id = $1 AND d.user_id = $2
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE r.id = $1 AND COALESCE(d.user_id, g.user_id) = $2
         "#
     )
     .bind(rule_id)
     .bind(user.id)
     .fetch_optional(pool.get_ref())
     .await?
-    .ok_or_else(|| AppError::NotFound("Rule not found".to_string()))?;
+    .ok_or_else(|| AppError::NotFound("RULE_NOT_FOUND", "Rule not found".to_string()))?;
 
     Ok(HttpResponse::Ok().json(RuleResponse::from(rule)))
 }
 
 /// PUT /api/rules/{id}
+/// Substitueix la regla sencera: els camps que poden ser null i falten queden a null
+#[utoipa::path(
+    tag = "rules",
+    params(("id" = Uuid, Path, description = "Id de la regla")),
+    request_body = ReplaceRuleRequest,
+    responses(
+        (status = 200, description = "Regla substituïda", body = RuleResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+        (status = 409, description = "La regla ha canviat des que es va llegir", body = ErrorResponse),
+        (status = 422, description = "Falta algun camp obligatori o hi ha camps invàlids", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[put("/rules/{id}")]
+#[allow(clippy::too_many_arguments)]
+async fn replace_rule(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+    webhooks: web::Data<WebhookDispatcher>,
+    events: web::Data<ScheduleEvents>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    body: Replacement<ReplaceRuleRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+    let existing = find_user_rule(pool.get_ref(), path.into_inner(), user.id).await?;
+
+    let new = body.apply_to(&existing.rule);
+    let load_profile = Some(body.load_profile.as_deref().filter(|w| !w.is_empty()));
+    let response = save_rule(
+        pool.get_ref(), &pvpc, &webhooks, &events, user.id, existing, new, body.version, load_profile, config.timezone,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// PATCH /api/rules/{id}
+/// Actualitza només els camps presents al cos
+#[utoipa::path(
+    tag = "rules",
+    params(("id" = Uuid, Path, description = "Id de la regla")),
+    request_body = UpdateRuleRequest,
+    responses(
+        (status = 200, description = "Regla actualitzada", body = RuleResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+        (status = 409, description = "La regla ha canviat des que es va llegir", body = ErrorResponse),
+        (status = 422, description = "Camps invàlids (un error per camp)", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[patch("/rules/{id}")]
+#[allow(clippy::too_many_arguments)]
 async fn update_rule(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     pvpc: web::Data<PvpcClient>,
+    webhooks: web::Data<WebhookDispatcher>,
+    events: web::Data<ScheduleEvents>,
     req: HttpRequest,
     path: web::Path<Uuid>,
-    body: web::Json<UpdateRuleRequest>,
+    body: Validated<UpdateRuleRequest>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
-    let rule_id = path.into_inner();
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+    let existing = find_user_rule(pool.get_ref(), path.into_inner(), user.id).await?;
+
+    // Aplicar actualitzacions
+    let new = body.changes.apply_to(&existing.rule)?;
 
-    // Verificar que la regla pertany a un dispositiu de l'usuari
-    let existing = sqlx::query_as::<_, RuleWithDevice>(
+    // Perfil de consum: absent = no canvia, [] = s'elimina
+    let load_profile = body.changes.load_profile.as_deref().map(|w| Some(w).filter(|w| !w.is_empty()));
+    let response = save_rule(
+        pool.get_ref(), &pvpc, &webhooks, &events, user.id, existing, new, body.version, load_profile, config.timezone,
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(response))
+}
+
+/// Regla de l'usuari (d'un dispositiu o grup seu) amb el nom del dispositiu o grup
+async fn find_user_rule(pool: &PgPool, rule_id: Uuid, user_id: Uuid) -> AppResult<RuleWithDevice> {
+    sqlx::query_as::<_, RuleWithDevice>(
         r#"
-        SELECT r.id, r.device_id, r.name, r.max_hours, r.time_window_start,
-               r.time_window_end, r.min_continuous_hours, r.days_of_week, r.is_enabled,
-               d.name as device_name
+        SELECT r.*, d.name as device_name, g.name as device_group_name, lp.weights as load_profile
         FROM rules r
-        JOIN devices d ON r.device_id = d.id
-        WHERE r.id = $1 AND d.user_id = $2
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE r.id = $1 AND COALESCE(d.user_id, g.user_id) = $2
         "#
     )
     .bind(rule_id)
-    .bind(user.id)
-    .fetch_optional(pool.get_ref())
+    .bind(user_id)
+    .fetch_optional(pool)
     .await?
-    .ok_or_else(|| AppError::NotFound("Rule not found".to_string()))?;
+    .ok_or_else(|| AppError::NotFound("RULE_NOT_FOUND", "Rule not found".to_string()))
+}
 
-    // Aplicar actualitzacions
-    let new_name = body.name.as_ref().unwrap_or(&existing.name);
-    let new_max_hours = body.max_hours.unwrap_or(existing.max_hours);
-    let new_time_window_start = body.time_window_start.or(existing.time_window_start);
-    let new_time_window_end = body.time_window_end.or(existing.time_window_end);
-    let new_min_continuous = body.min_continuous_hours.unwrap_or(existing.min_continuous_hours);
-    let new_days_of_week = body.days_of_week.unwrap_or(existing.days_of_week);
-    let new_is_enabled = body.is_enabled.unwrap_or(existing.is_enabled);
+/// Desa la regla modificada i en regenera els schedules (o cancel·la els pendents si
+/// s'ha desactivat). `load_profile`: None = no canvia, Some(None) = s'elimina.
+/// Només es desa si la regla encara és a la `version` que ha llegit el client; si no, 409.
+#[allow(clippy::too_many_arguments)]
+async fn save_rule(
+    pool: &PgPool,
+    pvpc: &PvpcClient,
+    webhooks: &WebhookDispatcher,
+    events: &ScheduleEvents,
+    user_id: Uuid,
+    existing: RuleWithDevice,
+    new: Rule,
+    version: i64,
+    load_profile: Option<Option<&[f64]>>,
+    tz: Tz,
+) -> AppResult<RuleResponse> {
+    let rule_id = existing.rule.id;
+    let mut tx = pool.begin().await?;
+
+    if let Some(weights) = load_profile {
+        save_load_profile(&mut tx, rule_id, weights).await?;
+    }
 
     let updated = sqlx::query_as::<_, RuleWithDevice>(
         r#"
         WITH updated AS (
             UPDATE rules
             SET name = $1, max_hours = $2, time_window_start = $3, time_window_end = $4,
-                min_continuous_hours = $5, days_of_week = $6, is_enabled = $7, updated_at = NOW()
-            WHERE id = $8
+                min_continuous_hours = $5, days_of_week = $6, is_enabled = $7,
+                baseline_days = $8, baseline_margin_pct = $9, action_type = $10,
+                blackout_windows = $11, sub_budgets = $12, priority = $13, active_from = $14,
+                active_until = $15, continuity_preference = $16, max_cost_eur = $17, mode = $22,
+                fixed_hours = $23, named_window_id = $24, min_gap_hours = $25, negative_price_boost = $26,
+                updated_at = NOW(), version = version + 1
+            WHERE id = $18 AND version = $21
             RETURNING *
         )
-        SELECT u.id, u.device_id, u.name, u.max_hours, u.time_window_start,
-               u.time_window_end, u.min_continuous_hours, u.days_of_week, u.is_enabled,
-               $9::text as device_name
+        SELECT u.*, $19::text as device_name, $20::text as device_group_name, lp.weights as load_profile,
+               rule_wattage(u.device_id, u.device_group_id) as wattage_watts
         FROM updated u
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = u.id
         "#
     )
-    .bind(new_name)
-    .bind(new_max_hours)
-    .bind(new_time_window_start)
-    .bind(new_time_window_end)
-    .bind(new_min_continuous)
-    .bind(new_days_of_week)
-    .bind(new_is_enabled)
+    .bind(&new.name)
+    .bind(new.max_hours)
+    .bind(new.time_window_start)
+    .bind(new.time_window_end)
+    .bind(new.min_continuous_hours)
+    .bind(new.days_of_week)
+    .bind(new.is_enabled)
+    .bind(new.baseline_days)
+    .bind(new.baseline_margin_pct)
+    .bind(new.action_type)
+    .bind(&new.blackout_windows)
+    .bind(&new.sub_budgets)
+    .bind(new.priority)
+    .bind(new.active_from)
+    .bind(new.active_until)
+    .bind(new.continuity_preference)
+    .bind(new.max_cost_eur)
     .bind(rule_id)
     .bind(&existing.device_name)
-    .fetch_one(pool.get_ref())
+    .bind(&existing.device_group_name)
+    .bind(version)
+    .bind(new.mode)
+    .bind(&new.fixed_hours)
+    .bind(new.named_window_id)
+    .bind(new.min_gap_hours)
+    .bind(new.negative_price_boost)
+    .fetch_optional(&mut *tx)
     .await?;
 
-    // Regenerar schedules si la regla ha canviat
-    let db_rule = Rule {
-        id: updated.id,
-        device_id: updated.device_id,
-        name: updated.name.clone(),
-        max_hours: updated.max_hours,
-        time_window_start: updated.time_window_start,
-        time_window_end: updated.time_window_end,
-        min_continuous_hours: updated.min_continuous_hours,
-        days_of_week: updated.days_of_week,
-        is_enabled: updated.is_enabled,
-        created_at: chrono::Utc::now(),
-        updated_at: chrono::Utc::now(),
+    // Cap fila: algú altre l'ha actualitzat (o esborrat) des que el client la va llegir
+    let Some(updated) = updated else {
+        tx.rollback().await?;
+        let current_version = sqlx::query_scalar!("SELECT version FROM rules WHERE id = $1", rule_id)
+            .fetch_optional(pool)
+            .await?
+            .ok_or_else(|| AppError::NotFound("RULE_NOT_FOUND", "Rule not found".to_string()))?;
+        return Err(AppError::Conflict { current_version });
     };
 
-    let schedule_info = if updated.is_enabled {
+    tx.commit().await?;
+
+    // Regenerar schedules si la regla ha canviat
+    let db_rule = &updated.rule;
+
+    let schedule_info = if db_rule.is_enabled {
         // Si està habilitada, regenerar schedules
         // include_past_hours = false: en actualitzar, només generem hores futures
-        tracing::info!("Regenerant schedules per la regla '{}'...", updated.name);
-        match regenerate_schedules_for_rule(pool.get_ref(), &pvpc, &db_rule, false).await {
+        tracing::info!("Regenerant schedules per la regla '{}'...", db_rule.name);
+        match regenerate_schedules_for_rule(pool, pvpc, db_rule, false, tz).await {
             Ok(info) => {
-                tracing::info!("Regenerats {} schedules per la regla '{}': {}", info.schedules_created, updated.name, info.message);
+                tracing::info!("Regenerats {} schedules per la regla '{}': {}", info.schedules_created, db_rule.name, info.message);
+                notify_schedules_generated(webhooks, events, pool, user_id, &info, tz);
                 Some(info)
             }
             Err(e) => {
-                tracing::error!("Error regenerant schedules per la regla '{}': {}", updated.name, e);
+                tracing::error!("Error regenerant schedules per la regla '{}': {}", db_rule.name, e);
                 None
             }
         }
     } else {
         // Si s'ha desactivat, cancel·lar schedules pendents
-        tracing::info!("Cancel·lant schedules per la regla desactivada '{}'...", updated.name);
-        let cancelled = cancel_pending_schedules_for_rule(pool.get_ref(), rule_id).await.unwrap_or(0);
+        tracing::info!("Cancel·lant schedules per la regla desactivada '{}'...", db_rule.name);
+        let cancelled = cancel_pending_actions(pool, PendingScope::Rule(rule_id), tz).await.unwrap_or(0);
         Some(ScheduleGenerationInfo {
             schedules_created: 0,
             message: format!("Regla desactivada. {} schedules pendents cancel·lats.", cancelled),
@@ -355,101 +1055,834 @@ async fn update_rule(
     let mut response = RuleResponse::from(updated);
     response.schedule_info = schedule_info;
 
-    Ok(HttpResponse::Ok().json(response))
+    Ok(response)
 }
 
-/// DELETE /api/rules/{id}
-#[delete("/rules/{id}")]
-async fn delete_rule(
+/// POST /api/rules/{id}/preview-schedule
+/// Calcula les hores que es programarien per una data, amb els canvis indicats aplicats a la regla,
+/// sense desar res. Inclou totes les hores del dia, també les que ja han passat.
+#[utoipa::path(
+    tag = "rules",
+    params(("id" = Uuid, Path, description = "Id de la regla")),
+    request_body = PreviewScheduleRequest,
+    responses(
+        (status = 200, description = "Hores que es programarien", body = SchedulePreviewResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+        (status = 422, description = "Camps invàlids o preus no disponibles per la data", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/rules/{id}/preview-schedule")]
+async fn preview_rule_schedule(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
     req: HttpRequest,
     path: web::Path<Uuid>,
+    body: Validated<PreviewScheduleRequest>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
-    let rule_id = path.into_inner();
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
 
-    // Verificar que la regla pertany a un dispositiu de l'usuari i eliminar
-    let result = sqlx::query(
+    let current = sqlx::query_as::<_, Rule>(
         r#"
-        DELETE FROM rules
-        WHERE id = $1 AND device_id IN (
-            SELECT id FROM devices WHERE user_id = $2
-        )
+        SELECT r.*, lp.weights as load_profile,
+               rule_wattage(r.device_id, r.device_group_id) as wattage_watts
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE r.id = $1 AND COALESCE(d.user_id, g.user_id) = $2
         "#
     )
-    .bind(rule_id)
+    .bind(path.into_inner())
     .bind(user.id)
-    .execute(pool.get_ref())
-    .await?;
+    .fetch_optional(pool.get_ref())
+    .await?
+    .ok_or_else(|| AppError::NotFound("RULE_NOT_FOUND", "Rule not found".to_string()))?;
 
-    if result.rows_affected() == 0 {
-        return Err(AppError::NotFound("Rule not found".to_string()));
+    let rule = body.changes.apply_to(&current)?;
+
+    let today = clock::today(config.timezone);
+    let date = resolve_calculate_date(body.date, today, config.price_horizon_days, config.price_history_days)?;
+
+    let prices = pvpc.get_prices_for_date(date).await?;
+    if prices.prices.is_empty() {
+        return Err(AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!(
+            "No hi ha preus disponibles per {}",
+            date
+        )));
     }
 
-    Ok(HttpResponse::NoContent().finish())
+    let preview = preview_schedules_for_rule_and_date(pool.get_ref(), &rule, &prices, date).await?;
+    Ok(HttpResponse::Ok().json(preview))
 }
 
-/// Regenera els schedules per una regla (avui i demà si els preus estan disponibles)
-/// Retorna informació sobre els schedules generats
-///
-/// - `include_past_hours`: si és true, genera schedules per totes les hores del dia (incloses les passades).
-///   Útil quan es crea una nova regla per tenir l'historial complet del dia.
-async fn regenerate_schedules_for_rule(
-    pool: &PgPool,
-    pvpc: &PvpcClient,
-    rule: &Rule,
-    include_past_hours: bool,
-) -> Result<ScheduleGenerationInfo, Box<dyn std::error::Error + Send + Sync>> {
-    let now = Local::now();
-    let today = now.date_naive();
-    let tomorrow = today + chrono::Duration::days(1);
-    let current_time = now.time();
+/// GET /api/rules/{id}/schedules?from=&to=
+/// Accions que ha generat la regla (totes, incloses les cancel·lades), per diagnosticar-la
+#[utoipa::path(
+    tag = "rules",
+    params(("id" = Uuid, Path, description = "Id de la regla"), RuleSchedulesQuery),
+    responses(
+        (status = 200, description = "Accions de la regla del rang", body = Vec<RuleScheduleResponse>),
+        (status = 400, description = "Rang invàlid o massa llarg", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/rules/{id}/schedules")]
+async fn list_rule_schedules(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    query: web::Query<RuleSchedulesQuery>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let rule = find_user_rule(pool.get_ref(), path.into_inner(), user.id).await?.rule;
 
-    // Primer, eliminar schedules pendents d'aquesta regla (que encara no han passat)
-    // Si include_past_hours, eliminem tots els schedules pendents d'avui i demà
-    if include_past_hours {
-        sqlx::query(
-            r#"
-            DELETE FROM scheduled_actions
-            WHERE rule_id = $1
-              AND status = 'pending'
-              AND scheduled_date >= $2
-            "#
-        )
-        .bind(rule.id)
-        .bind(today)
-        .execute(pool)
-        .await?;
-    } else {
-        sqlx::query(
-            r#"
-            DELETE FROM scheduled_actions
-            WHERE rule_id = $1
-              AND status = 'pending'
-              AND (scheduled_date > $2 OR (scheduled_date = $2 AND start_time > $3))
-            "#
-        )
-        .bind(rule.id)
-        .bind(today)
-        .bind(current_time)
-        .execute(pool)
-        .await?;
-    }
+    let from = query.from.unwrap_or_else(|| clock::today(config.timezone));
+    let to = query.to.unwrap_or(from + chrono::Duration::days(DEFAULT_RULE_SCHEDULE_DAYS));
+    validate_range(from, to)?;
 
-    let mut created_count = 0;
-    let mut today_count = 0;
-    let mut tomorrow_count = 0;
-    let mut today_available = false;
-    let mut tomorrow_available = false;
+    let actions = sqlx::query_as!(
+        RuleScheduleResponse,
+        r#"
+        SELECT sa.id, d.id as device_id, d.name as device_name, sa.scheduled_date, sa.start_time, sa.end_time,
+               sa.crosses_midnight, sa.action, sa.status, sa.price_per_kwh::float8 as price_per_kwh,
+               sa.executed_at, sa.error_message
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        WHERE sa.rule_id = $1 AND d.user_id = $2 AND sa.scheduled_date BETWEEN $3 AND $4
+        ORDER BY sa.scheduled_date, sa.start_time, d.name
+        "#,
+        rule.id,
+        user.id,
+        from,
+        to
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
 
-    // El filtre de temps: None = totes les hores, Some(time) = només hores futures
-    let time_filter = if include_past_hours { None } else { Some(current_time) };
+    Ok(HttpResponse::Ok().json(actions))
+}
+
+/// Dies (fins avui) que cobreixen les estadístiques d'execució d'una regla
+const EXECUTION_STATS_DAYS: i64 = 30;
+
+/// Execucions correctes de les accions d'una regla que començaven a una hora del dia
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HourlyExecutions {
+    /// Hora d'inici (0-23)
+    pub hour: u8,
+    pub executions: i64,
+    /// Preu mitjà de les execucions amb preu (€/kWh)
+    pub avg_price: f64,
+}
+
+/// Com s'han executat les accions d'una regla els darrers dies
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ExecutionStatsResponse {
+    pub rule_id: Uuid,
+    pub period_days: i64,
+    /// Accions programades, en qualsevol estat excepte `superseded`
+    pub total_scheduled: i64,
+    /// Execucions correctes (executed, executed_on, executed_off)
+    pub total_executed: i64,
+    /// Accions que no s'han fet: `missed` i `failed`
+    pub total_missed: i64,
+    pub total_cancelled: i64,
+    /// Executades respecte les que ja s'havien d'haver fet (executades i no fetes). Les
+    /// pendents i les cancel·lades no hi compten.
+    pub execution_rate_pct: f64,
+    /// Preu mitjà de les execucions amb preu (0 si no n'hi ha cap)
+    pub avg_price_paid_eur_kwh: f64,
+    /// Cost estimat de les accions d'encesa executades, amb la potència de `/schedule/{date}/costs`
+    pub total_cost_eur: f64,
+    /// Només les hores amb alguna execució, per ordre
+    pub hourly_breakdown: Vec<HourlyExecutions>,
+}
+
+/// Acció d'una regla per a les estadístiques d'execució
+struct ExecutionStatsRow {
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+    action: String,
+    status: String,
+    price_per_kwh: Option<f64>,
+    /// Potència reportada per l'execució de l'acció
+    actual_power_watts: Option<f64>,
+    /// Potència configurada del dispositiu (`devices.wattage_watts`)
+    wattage_watts: Option<f64>,
+    /// Potència mitjana mesurada en les execucions correctes del dispositiu
+    device_power_watts: Option<f64>,
+}
+
+/// GET /api/rules/{id}/execution-stats
+/// Estadístiques d'execució de les accions de la regla dels darrers 30 dies (avui inclòs)
+#[utoipa::path(
+    tag = "rules",
+    params(("id" = Uuid, Path, description = "Id de la regla")),
+    responses(
+        (status = 200, description = "Estadístiques d'execució de la regla", body = ExecutionStatsResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobada", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/rules/{id}/execution-stats")]
+async fn get_rule_execution_stats(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let rule = find_user_rule(pool.get_ref(), path.into_inner(), user.id).await?.rule;
+
+    let to = clock::today(config.timezone);
+    let from = to - chrono::Duration::days(EXECUTION_STATS_DAYS - 1);
+
+    let rows = sqlx::query_as!(
+        ExecutionStatsRow,
+        r#"
+        SELECT sa.start_time, sa.end_time, sa.action, sa.status,
+               sa.price_per_kwh::float8 as price_per_kwh, sa.actual_power_watts,
+               d.wattage_watts, power.watts as device_power_watts
+        FROM scheduled_actions sa
+        JOIN devices d ON d.id = sa.device_id
+        LEFT JOIN device_measured_power power ON power.device_id = sa.device_id
+        WHERE sa.rule_id = $1 AND sa.scheduled_date BETWEEN $2 AND $3 AND sa.status <> $4
+        "#,
+        rule.id,
+        from,
+        to,
+        SUPERSEDED_STATUS
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    Ok(HttpResponse::Ok().json(execution_stats(rule.id, &rows)))
+}
+
+/// Agrega les accions d'una regla en estadístiques d'execució
+fn execution_stats(rule_id: Uuid, rows: &[ExecutionStatsRow]) -> ExecutionStatsResponse {
+    let count = |matches: fn(&str) -> bool| rows.iter().filter(|row| matches(&row.status)).count() as i64;
+    let total_executed = count(|status| status.starts_with("executed"));
+    let total_missed = count(|status| matches!(status, "missed" | "failed"));
+    let total_cancelled = count(|status| status == "cancelled");
+
+    let executed: Vec<&ExecutionStatsRow> = rows.iter().filter(|row| row.status.starts_with("executed")).collect();
+    let average = |prices: &[f64]| {
+        if prices.is_empty() { 0.0 } else { prices.iter().sum::<f64>() / prices.len() as f64 }
+    };
+    let prices: Vec<f64> = executed.iter().filter_map(|row| row.price_per_kwh).collect();
+
+    let total_cost_eur = executed
+        .iter()
+        .filter(|row| row.action == "on")
+        .filter_map(|row| {
+            let watts = action_power_watts(row.actual_power_watts, row.wattage_watts, row.device_power_watts);
+            let kwh = action_kwh(watts, row.start_time, row.end_time);
+            row.price_per_kwh.map(|price| price * kwh)
+        })
+        .sum();
+
+    let mut by_hour: BTreeMap<u8, (i64, Vec<f64>)> = BTreeMap::new();
+    for row in &executed {
+        let (executions, prices) = by_hour.entry(row.start_time.hour() as u8).or_default();
+        *executions += 1;
+        prices.extend(row.price_per_kwh);
+    }
+
+    let resolved = total_executed + total_missed;
+    ExecutionStatsResponse {
+        rule_id,
+        period_days: EXECUTION_STATS_DAYS,
+        total_scheduled: rows.len() as i64,
+        total_executed,
+        total_missed,
+        total_cancelled,
+        execution_rate_pct: if resolved > 0 { total_executed as f64 * 100.0 / resolved as f64 } else { 0.0 },
+        avg_price_paid_eur_kwh: average(&prices),
+        total_cost_eur,
+        hourly_breakdown: by_hour
+            .into_iter()
+            .map(|(hour, (executions, prices))| HourlyExecutions { hour, executions, avg_price: average(&prices) })
+            .collect(),
+    }
+}
+
+/// POST /api/rules/batch-update
+/// Activa o desactiva diverses regles alhora (p. ex. "pausar-ho tot")
+#[utoipa::path(
+    tag = "rules",
+    request_body = BatchUpdateRulesRequest,
+    responses(
+        (status = 200, description = "Regles actualitzades", body = BatchUpdateResult),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/rules/batch-update")]
+async fn batch_update_rules(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+    webhooks: web::Data<WebhookDispatcher>,
+    events: web::Data<ScheduleEvents>,
+    req: HttpRequest,
+    body: Validated<BatchUpdateRulesRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+
+    // Només s'actualitzen les regles dels dispositius i grups de l'usuari, en una sola sentència
+    let rules = sqlx::query_as::<_, RuleWithDevice>(
+        r#"
+        WITH updated AS (
+            UPDATE rules r
+            SET is_enabled = $3, updated_at = NOW(), version = version + 1
+            WHERE r.id = ANY($1) AND (
+                r.device_id IN (SELECT id FROM devices WHERE user_id = $2)
+                OR r.device_group_id IN (SELECT id FROM device_groups WHERE user_id = $2)
+            )
+            RETURNING r.*
+        )
+        SELECT u.*, d.name as device_name, g.name as device_group_name, lp.weights as load_profile,
+               rule_wattage(u.device_id, u.device_group_id) as wattage_watts
+        FROM updated u
+        LEFT JOIN devices d ON u.device_id = d.id
+        LEFT JOIN device_groups g ON u.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = u.id
+        "#
+    )
+    .bind(&body.rule_ids)
+    .bind(user.id)
+    .bind(body.is_enabled)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let mut updated: Vec<Uuid> = rules.iter().map(|r| r.rule.id).collect();
+    updated.sort_by_key(|id| body.rule_ids.iter().position(|r| r == id));
+    let not_found = missing_ids(&body.rule_ids, &updated);
+
+    let mut schedules_created = 0;
+    if body.is_enabled {
+        let rules: Vec<Rule> = rules.into_iter().map(|r| r.rule).collect();
+        schedules_created = regenerate_rules(pool.get_ref(), &pvpc, &rules, config.timezone).await;
+
+        let info = ScheduleGenerationInfo {
+            schedules_created,
+            message: format!("{} regles activades", updated.len()),
+        };
+        notify_schedules_generated(&webhooks, &events, pool.get_ref(), user.id, &info, config.timezone);
+    } else {
+        let mut cancelled = 0;
+        for rule_id in &updated {
+            cancelled += cancel_pending_actions(pool.get_ref(), PendingScope::Rule(*rule_id), config.timezone)
+                .await
+                .unwrap_or(0);
+        }
+        tracing::info!("Desactivades {} regles, {} schedules pendents cancel·lats", updated.len(), cancelled);
+    }
+
+    Ok(HttpResponse::Ok().json(BatchUpdateResult {
+        updated,
+        not_found,
+        schedules_created,
+    }))
+}
+
+/// Regenera les accions futures de diverses regles ja actualitzades. Les que fallen només
+/// es registren. Retorna el total d'accions creades.
+pub(super) async fn regenerate_rules(pool: &PgPool, pvpc: &PvpcClient, rules: &[Rule], tz: Tz) -> usize {
+    let mut schedules_created = 0;
+    // include_past_hours = false: igual que en actualitzar una regla
+    for rule in rules {
+        match regenerate_schedules_for_rule(pool, pvpc, rule, false, tz).await {
+            Ok(info) => schedules_created += info.schedules_created,
+            Err(e) => tracing::error!("Error regenerant schedules per la regla '{}': {}", rule.name, e),
+        }
+    }
+    schedules_created
+}
+
+/// Ids demanats que no s'han trobat, sense repetits i en l'ordre de la petició
+fn missing_ids(requested: &[Uuid], found: &[Uuid]) -> Vec<Uuid> {
+    let mut missing: Vec<Uuid> = Vec::new();
+    for id in requested {
+        if !found.contains(id) && !missing.contains(id) {
+            missing.push(*id);
+        }
+    }
+    missing
+}
+
+/// DELETE /api/rules/{id}
+#[utoipa::path(
+    tag = "rules",
+    params(("id" = Uuid, Path, description = "Id de la regla")),
+    responses(
+        (status = 204, description = "Regla eliminada"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[delete("/rules/{id}")]
+async fn delete_rule(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let rule_id = path.into_inner();
+
+    // Verificar que la regla pertany a un dispositiu o grup de l'usuari i eliminar
+    let result = sqlx::query!(
+        r#"
+        DELETE FROM rules
+        WHERE id = $1 AND (
+            device_id IN (SELECT id FROM devices WHERE user_id = $2)
+            OR device_group_id IN (SELECT id FROM device_groups WHERE user_id = $2)
+        )
+        "#,
+        rule_id,
+        user.id
+    )
+    .execute(pool.get_ref())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::NotFound("RULE_NOT_FOUND", "Rule not found".to_string()));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub(super) fn validate_max_hours(max_hours: i32) -> Result<(), FieldError> {
+    if !(1..=24).contains(&max_hours) {
+        return Err(FieldError::new("max_hours", "OUT_OF_RANGE", "max_hours must be between 1 and 24"));
+    }
+
+    Ok(())
+}
+
+pub(super) fn validate_min_continuous_hours(min_continuous_hours: i32, max_hours: i32) -> Result<(), FieldError> {
+    if min_continuous_hours < 1 || min_continuous_hours > max_hours {
+        return Err(FieldError::new(
+            "min_continuous_hours",
+            "OUT_OF_RANGE",
+            "min_continuous_hours must be between 1 and max_hours",
+        ));
+    }
+
+    Ok(())
+}
+
+/// La finestra ha de contenir com a mínim un bloc de `min_continuous_hours` hores senceres.
+/// Un inici posterior al final és una finestra que creua mitjanit (p. ex. 22:00-06:00), no un error.
+pub(super) fn validate_time_window(
+    start: Option<NaiveTime>,
+    end: Option<NaiveTime>,
+    min_continuous_hours: i32,
+) -> Result<(), FieldError> {
+    if let (Some(start), Some(end)) = (start, end)
+        && start == end
+    {
+        return Err(FieldError::new(
+            "time_window_end",
+            "INVALID_WINDOW",
+            "time_window_start and time_window_end must be different",
+        ));
+    }
+
+    let hours = time_window_hours(start, end) as i32;
+    if hours == 0 {
+        return Err(FieldError::new(
+            "time_window_end",
+            "INVALID_WINDOW",
+            "time window must contain at least one whole hour",
+        ));
+    }
+    if hours < min_continuous_hours {
+        return Err(FieldError::new(
+            "time_window_end",
+            "INVALID_WINDOW",
+            format!("time window has {} hours, fewer than min_continuous_hours ({})", hours, min_continuous_hours),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Valida els paràmetres de la porta "només si és més barat que els darrers dies"
+fn validate_baseline(baseline_days: Option<i32>, baseline_margin_pct: f64) -> Result<(), FieldError> {
+    if let Some(days) = baseline_days
+        && !(1..=30).contains(&days)
+    {
+        return Err(FieldError::new("baseline_days", "OUT_OF_RANGE", "baseline_days must be between 1 and 30"));
+    }
+
+    if !(0.0..100.0).contains(&baseline_margin_pct) {
+        return Err(FieldError::new(
+            "baseline_margin_pct",
+            "OUT_OF_RANGE",
+            "baseline_margin_pct must be between 0 and 100",
+        ));
+    }
+
+    Ok(())
+}
+
+pub(super) fn validate_blackout_windows(windows: &[BlackoutWindow]) -> Result<(), FieldError> {
+    if windows.iter().any(|w| w.start == w.end) {
+        return Err(FieldError::new(
+            "blackout_windows",
+            "INVALID_WINDOW",
+            "blackout window start and end must be different",
+        ));
+    }
+
+    Ok(())
+}
+
+fn validate_sub_budgets(sub_budgets: &[SubBudget]) -> Result<(), FieldError> {
+    if sub_budgets.iter().any(|b| !(1..=24).contains(&b.hours)) {
+        return Err(FieldError::new("sub_budgets", "OUT_OF_RANGE", "sub_budget hours must be between 1 and 24"));
+    }
+
+    if sub_budgets.iter().any(|b| b.start == b.end) {
+        return Err(FieldError::new(
+            "sub_budgets",
+            "INVALID_WINDOW",
+            "sub_budget window start and end must be different",
+        ));
+    }
+
+    if sub_budgets.iter().map(|b| b.hours).sum::<i32>() > 24 {
+        return Err(FieldError::new("sub_budgets", "OUT_OF_RANGE", "sub_budgets cannot add up to more than 24 hours"));
+    }
+
+    Ok(())
+}
+
+fn validate_season(active_from: Option<NaiveDate>, active_until: Option<NaiveDate>) -> Result<(), FieldError> {
+    if let (Some(from), Some(until)) = (active_from, active_until)
+        && from > until
+    {
+        return Err(FieldError::new("active_until", "INVALID_RANGE", "active_from must not be after active_until"));
+    }
+    Ok(())
+}
+
+/// La separació entre hores només té sentit amb hores saltejades (`min_continuous_hours` 1)
+pub(super) fn validate_min_gap_hours(min_gap_hours: Option<i32>, min_continuous_hours: i32) -> Result<(), FieldError> {
+    let Some(gap) = min_gap_hours else {
+        return Ok(());
+    };
+    if !(0..=23).contains(&gap) {
+        return Err(FieldError::new("min_gap_hours", "OUT_OF_RANGE", "min_gap_hours must be between 0 and 23"));
+    }
+    if gap > 0 && min_continuous_hours > 1 {
+        return Err(FieldError::new(
+            "min_gap_hours",
+            "CONFLICT",
+            "min_gap_hours cannot be combined with min_continuous_hours greater than 1",
+        ));
+    }
+
+    Ok(())
+}
+
+/// Límit de l'ampliació amb preus negatius: 0 (sense ampliació) a 24 hores
+pub(super) fn validate_negative_price_boost(boost: Option<i32>) -> Result<(), FieldError> {
+    if boost.is_some_and(|boost| !(0..=24).contains(&boost)) {
+        return Err(FieldError::new(
+            "negative_price_boost",
+            "OUT_OF_RANGE",
+            "negative_price_boost must be between 0 and 24",
+        ));
+    }
+    Ok(())
+}
+
+pub(super) fn validate_max_cost(max_cost_eur: Option<f64>) -> Result<(), FieldError> {
+    if max_cost_eur.is_some_and(|cost| !cost.is_finite() || cost <= 0.0) {
+        return Err(FieldError::new("max_cost_eur", "OUT_OF_RANGE", "max_cost_eur must be greater than 0"));
+    }
+
+    Ok(())
+}
+
+fn validate_priority(priority: i32) -> Result<(), FieldError> {
+    if priority < 1 {
+        return Err(FieldError::new("priority", "OUT_OF_RANGE", "priority must be 1 or greater"));
+    }
+
+    Ok(())
+}
+
+pub(super) fn validate_load_profile(weights: &[f64]) -> Result<(), FieldError> {
+    if weights.len() != 24 {
+        return Err(FieldError::new("load_profile", "INVALID_LENGTH", "load_profile must have 24 values"));
+    }
+
+    if weights.iter().any(|w| !w.is_finite() || *w < 0.0) {
+        return Err(FieldError::new("load_profile", "OUT_OF_RANGE", "load_profile values must be non-negative numbers"));
+    }
+
+    if weights.iter().all(|w| *w == 0.0) {
+        return Err(FieldError::new("load_profile", "INVALID_VALUE", "load_profile cannot be all zeros"));
+    }
+
+    Ok(())
+}
+
+fn validate_fixed_hours(hours: &[i32]) -> Result<(), FieldError> {
+    if hours.iter().any(|h| !(0..=23).contains(h)) {
+        return Err(FieldError::new("fixed_hours", "OUT_OF_RANGE", "fixed_hours values must be between 0 and 23"));
+    }
+
+    let mut unique = hours.to_vec();
+    unique.sort_unstable();
+    unique.dedup();
+    if unique.len() != hours.len() {
+        return Err(FieldError::new("fixed_hours", "DUPLICATE", "fixed_hours cannot contain duplicate hours"));
+    }
+
+    Ok(())
+}
+
+/// Valida les hores fixes i que una regla `fixed` tingui hores o una finestra horària
+/// (sense cap de les dues, s'encendria tot el dia)
+fn validate_fixed_mode(mode: RuleMode, fixed_hours: Option<&[i32]>, has_window: bool) -> Result<(), FieldError> {
+    if let Some(hours) = fixed_hours {
+        validate_fixed_hours(hours)?;
+    }
+
+    let has_hours = fixed_hours.is_some_and(|hours| !hours.is_empty());
+    if mode == RuleMode::Fixed && !has_hours && !has_window {
+        return Err(FieldError::new("fixed_hours", "REQUIRED", "fixed mode requires fixed_hours or a time window"));
+    }
+
+    Ok(())
+}
+
+/// Dispositiu o grup d'una regla nova, ja verificat que és de l'usuari
+enum RuleTarget {
+    Device(Device),
+    Group(DeviceGroup),
+}
+
+/// Insereix la regla (i el seu perfil de consum) per un dispositiu o grup ja verificat.
+/// `named_window_id` és la finestra amb nom de la qual ja s'han copiat les hores al cos.
+async fn insert_rule(
+    conn: &mut PgConnection,
+    body: &CreateRuleRequest,
+    target: &RuleTarget,
+    named_window_id: Option<Uuid>,
+) -> AppResult<RuleWithDevice> {
+    // El cos ja s'ha validat (`Validate`) i té els valors per defecte aplicats (`apply_defaults`)
+    let min_continuous = body.min_continuous_hours.unwrap_or(1);
+    let baseline_margin_pct = body.baseline_margin_pct.unwrap_or(0.0);
+    let blackout_windows = body.blackout_windows.clone().unwrap_or_default();
+    let sub_budgets = body.sub_budgets.clone().unwrap_or_default();
+    let priority = body.priority.unwrap_or(1);
+    let load_profile = body.load_profile.clone().filter(|weights| !weights.is_empty());
+    let (device_id, device_name, group_id, group_name) = match target {
+        RuleTarget::Device(device) => (Some(device.id), Some(&device.name), None, None),
+        RuleTarget::Group(group) => (None, None, Some(group.id), Some(&group.name)),
+    };
+
+    let mut rule = sqlx::query_as::<_, RuleWithDevice>(
+        r#"
+        WITH inserted AS (
+            INSERT INTO rules (device_id, device_group_id, name, max_hours, time_window_start, time_window_end,
+                               min_continuous_hours, days_of_week, baseline_days, baseline_margin_pct, action_type,
+                               blackout_windows, sub_budgets, priority, active_from, active_until,
+                               continuity_preference, max_cost_eur, mode, fixed_hours, named_window_id,
+                               min_gap_hours, negative_price_boost)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17, $18, $21, $22, $23,
+                    $24, $25)
+            RETURNING *
+        )
+        SELECT i.*, $19::text as device_name, $20::text as device_group_name,
+               rule_wattage(i.device_id, i.device_group_id) as wattage_watts
+        FROM inserted i
+        "#
+    )
+    .bind(device_id)
+    .bind(group_id)
+    .bind(&body.name)
+    .bind(body.max_hours)
+    .bind(body.time_window_start)
+    .bind(body.time_window_end)
+    .bind(min_continuous)
+    .bind(body.days_of_week.unwrap_or(127))
+    .bind(body.baseline_days)
+    .bind(baseline_margin_pct)
+    .bind(body.action_type.unwrap_or_default())
+    .bind(Json(&blackout_windows))
+    .bind(Json(&sub_budgets))
+    .bind(priority)
+    .bind(body.active_from)
+    .bind(body.active_until)
+    .bind(body.continuity_preference.unwrap_or_default())
+    .bind(body.max_cost_eur)
+    .bind(device_name)
+    .bind(group_name)
+    .bind(body.mode.unwrap_or_default())
+    .bind(body.fixed_hours.clone().filter(|hours| !hours.is_empty()))
+    .bind(named_window_id)
+    .bind(body.min_gap_hours.filter(|gap| *gap > 0))
+    .bind(body.negative_price_boost.filter(|boost| *boost > 0))
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|e| target_fk_violation(e, target))?;
+
+    if let Some(weights) = &load_profile {
+        save_load_profile(conn, rule.rule.id, Some(weights)).await?;
+    }
+    rule.rule.load_profile = load_profile;
+
+    Ok(rule)
+}
+
+/// El dispositiu o el grup es pot esborrar entre la comprovació de propietat i l'INSERT:
+/// la clau forana falla i es respon com si no existís
+fn target_fk_violation(e: sqlx::Error, target: &RuleTarget) -> AppError {
+    match e.as_database_error() {
+        Some(db) if db.is_foreign_key_violation() => match target {
+            RuleTarget::Device(_) => AppError::NotFound("DEVICE_NOT_FOUND", "Device not found".to_string()),
+            RuleTarget::Group(_) => AppError::NotFound("DEVICE_GROUP_NOT_FOUND", "Device group not found".to_string()),
+        },
+        _ => e.into(),
+    }
+}
+
+/// Desa el perfil de consum d'una regla, o l'elimina si és None
+async fn save_load_profile(
+    conn: &mut PgConnection,
+    rule_id: Uuid,
+    weights: Option<&[f64]>,
+) -> Result<(), sqlx::Error> {
+    match weights {
+        Some(weights) => {
+            sqlx::query!(
+                r#"
+                INSERT INTO rule_load_profiles (rule_id, weights)
+                VALUES ($1, $2)
+                ON CONFLICT (rule_id)
+                DO UPDATE SET weights = EXCLUDED.weights, updated_at = NOW()
+                "#,
+                rule_id,
+                weights
+            )
+            .execute(conn)
+            .await?;
+        }
+        None => {
+            sqlx::query!("DELETE FROM rule_load_profiles WHERE rule_id = $1", rule_id)
+                .execute(conn)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Notifica els webhooks i clients connectats de l'usuari si s'han generat schedules (avui i demà)
+pub(super) fn notify_schedules_generated(
+    webhooks: &WebhookDispatcher,
+    events: &ScheduleEvents,
+    pool: &PgPool,
+    user_id: Uuid,
+    info: &ScheduleGenerationInfo,
+    tz: Tz,
+) {
+    if info.schedules_created == 0 {
+        return;
+    }
+
+    let today = clock::today(tz);
+    for date in [today, today + chrono::Duration::days(1)] {
+        webhooks.schedule_generated(pool, Some(user_id), date);
+        events.send(ScheduleEvent::ScheduleGenerated { user_id: Some(user_id), date });
+    }
+}
+
+/// Regenera els schedules per una regla (avui i demà si els preus estan disponibles)
+/// Retorna informació sobre els schedules generats
+///
+/// - `include_past_hours`: si és true, genera schedules per totes les hores del dia (incloses les passades).
+///   Útil quan es crea una nova regla per tenir l'historial complet del dia.
+async fn regenerate_schedules_for_rule(
+    pool: &PgPool,
+    pvpc: &PvpcClient,
+    rule: &Rule,
+    include_past_hours: bool,
+    tz: Tz,
+) -> Result<ScheduleGenerationInfo, Box<dyn std::error::Error + Send + Sync>> {
+    let now = clock::now(tz);
+    let today = now.date_naive();
+    let tomorrow = today + chrono::Duration::days(1);
+    let current_time = now.time();
+
+    // Primer, eliminar schedules pendents d'aquesta regla (que encara no han passat)
+    // Si include_past_hours, eliminem tots els schedules pendents d'avui i demà
+    if include_past_hours {
+        sqlx::query!(
+            r#"
+            DELETE FROM scheduled_actions
+            WHERE rule_id = $1
+              AND status = 'pending'
+              AND scheduled_date >= $2
+            "#,
+            rule.id,
+            today
+        )
+        .execute(pool)
+        .await?;
+    } else {
+        sqlx::query!(
+            r#"
+            DELETE FROM scheduled_actions
+            WHERE rule_id = $1
+              AND status = 'pending'
+              AND (scheduled_date > $2 OR (scheduled_date = $2 AND start_time > $3))
+            "#,
+            rule.id,
+            today,
+            current_time
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    let mut created_count = 0;
+    let mut today_count = 0;
+    let mut tomorrow_count = 0;
+    let mut today_available = false;
+    let mut tomorrow_available = false;
+
+    // El filtre de temps: None = totes les hores, Some(time) = només hores futures
+    let time_filter = if include_past_hours { None } else { Some(current_time) };
 
     // Generar per avui
-    match pvpc.get_today_prices().await {
+    match pvpc.get_prices_for_date(today).await {
         Ok(prices) => {
             today_available = !prices.prices.is_empty();
+            if let Err(e) = store_prices(pool, &prices).await {
+                tracing::warn!("No s'han pogut desar els preus d'avui a l'historial: {}", e);
+            }
             tracing::info!(
                 "Preus d'avui ({}) obtinguts: {} hores",
                 today,
@@ -477,16 +1910,25 @@ async fn regenerate_schedules_for_rule(
     }
 
     // Generar per demà (si els preus estan disponibles)
-    match pvpc.get_tomorrow_prices().await {
+    match pvpc.get_prices_for_date(tomorrow).await {
         Ok(prices) => {
-            tomorrow_available = !prices.prices.is_empty();
+            tomorrow_available = pvpc.is_complete(&prices);
             if tomorrow_available {
+                if let Err(e) = store_prices(pool, &prices).await {
+                    tracing::warn!("No s'han pogut desar els preus de demà a l'historial: {}", e);
+                }
                 let count = generate_schedules_for_rule_and_date(pool, rule, &prices, tomorrow, None).await?;
                 tracing::info!("Generats {} schedules per demà ({})", count, tomorrow);
                 tomorrow_count = count;
                 created_count += count;
-            } else {
+            } else if prices.prices.is_empty() {
                 tracing::info!("Preus de demà ({}) encara no disponibles", tomorrow);
+            } else {
+                tracing::info!(
+                    "Preus de demà ({}) incomplets ({} hores): es generaran quan estiguin tots",
+                    tomorrow,
+                    prices.prices.len()
+                );
             }
         }
         Err(e) => {
@@ -508,7 +1950,7 @@ async fn regenerate_schedules_for_rule(
             created_count, today_count, tomorrow_count
         )
     } else if today_available && !tomorrow_available {
-        "Les hores òptimes d'avui ja han passat. Els schedules de demà es generaran a les 20:30 quan els preus estiguin disponibles.".to_string()
+        "Les hores òptimes d'avui ja han passat. Els schedules de demà es generaran a la generació diària, quan els preus estiguin disponibles.".to_string()
     } else if !today_available && !tomorrow_available {
         "Els preus encara no estan disponibles. Els schedules es generaran automàticament quan els preus estiguin disponibles.".to_string()
     } else {
@@ -529,108 +1971,1247 @@ async fn generate_schedules_for_rule_and_date(
     date: chrono::NaiveDate,
     min_time: Option<NaiveTime>,
 ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
-    // Comprovar si el dia de la setmana està inclòs
-    let weekday = date.weekday();
-    let day_bit = match weekday {
-        chrono::Weekday::Mon => 1,
-        chrono::Weekday::Tue => 2,
-        chrono::Weekday::Wed => 4,
-        chrono::Weekday::Thu => 8,
-        chrono::Weekday::Fri => 16,
-        chrono::Weekday::Sat => 32,
-        chrono::Weekday::Sun => 64,
-    };
+    let mut conn = pool.acquire().await?;
 
-    if (rule.days_of_week & day_bit) == 0 {
+    // Comprovar si el dia de la setmana està inclòs i si és dins la temporada
+    if let Some(reason) = SkipReason::for_day(rule, date) {
+        record_skip(&mut conn, rule.id, date, Some(reason)).await?;
         return Ok(0);
     }
 
-    // Calcular les hores òptimes
-    let optimal = calculate_optimal_hours(
-        &prices.prices,
-        rule.max_hours,
-        rule.min_continuous_hours,
-        rule.time_window_start,
-        rule.time_window_end,
-    );
+    // Calcular les hores òptimes (quarts d'hora si els preus són quart-horaris)
+    let slots = PriceSlots::from_day(prices);
+    let optimal = optimal_hours_for_rule(&slots, rule);
+    if optimal.hours.is_empty() {
+        record_skip(&mut conn, rule.id, date, Some(SkipReason::NoEligibleHours)).await?;
+        return Ok(0);
+    }
+
+    // Porta "només si és més barat que els darrers dies"
+    if !passes_baseline_gate(&mut conn, rule, date, &optimal).await? {
+        record_skip(&mut conn, rule.id, date, Some(SkipReason::AboveBaseline)).await?;
+        return Ok(0);
+    }
+
+    // Si el grup té un màxim de dispositius alhora, cada tanda de dispositius té les seves hores
+    let mut rule = rule.clone();
+    load_group_limit(pool, &mut rule).await?;
 
     let mut created_count = 0;
+    let mut upcoming = false;
 
-    for hour in &optimal.hours {
-        let start_time = NaiveTime::from_hms_opt(*hour as u32, 0, 0).unwrap();
+    for plan in stagger_group_hours(&slots, &rule, optimal.hours) {
+        for hour in &plan.hours {
+            let start_time = slots.start_time(*hour);
 
-        // Si hi ha min_time, saltar hores que ja han passat
-        if let Some(min) = min_time {
-            if start_time <= min {
+            // Si hi ha min_time, saltar hores que ja han passat
+            if let Some(min) = min_time
+                && start_time <= min
+            {
                 continue;
             }
+            upcoming = true;
+
+            // L'última franja acaba a les 00:00 de l'endemà
+            let end = slots.end_time(*hour);
+            let price = slots.price(*hour);
+
+            // Una acció per cada dispositiu del pla (més d'una si és d'un grup)
+            let devices = plan.device_ids.as_deref();
+            created_count += insert_rule_actions(&mut conn, &rule, devices, date, start_time, end, price).await?;
         }
+    }
+
+    let reason = (!upcoming).then_some(SkipReason::HoursPassed);
+    record_skip(&mut conn, rule.id, date, reason).await?;
+
+    metrics::record_schedules_generated(created_count);
+
+    Ok(created_count)
+}
 
-        // Per l'hora 23, end_time seria 00:00 que causa problemes de comparació
-        // Usem 23:59:59 per evitar que end_time < start_time
-        let end_time = if *hour == 23 {
-            NaiveTime::from_hms_opt(23, 59, 59).unwrap()
+/// El que faria `generate_schedules_for_rule_and_date` per totes les hores del dia, sense escriure res
+async fn preview_schedules_for_rule_and_date(
+    pool: &PgPool,
+    rule: &Rule,
+    prices: &DailyPrices,
+    date: NaiveDate,
+) -> AppResult<SchedulePreviewResponse> {
+    let slots = PriceSlots::from_day(prices);
+    let (optimal, message) = if !rule.is_enabled {
+        (None, "La regla està desactivada: no es programaria cap hora".to_string())
+    } else if !rule.runs_on(date) {
+        (None, format!("La regla no s'aplica el {} (dia de la setmana o temporada)", date))
+    } else {
+        let optimal = optimal_hours_for_rule(&slots, rule);
+        if !passes_baseline_gate(&mut *pool.acquire().await?, rule, date, &optimal).await? {
+            (None, "Les hores òptimes no són prou més barates que els darrers dies".to_string())
+        } else if optimal.hours.is_empty() {
+            (None, "Cap hora compleix les condicions de la regla".to_string())
         } else {
-            NaiveTime::from_hms_opt(*hour as u32 + 1, 0, 0).unwrap()
+            let message = format!("Es programarien {} hores", optimal.hours.len());
+            (Some(optimal), message)
+        }
+    };
+
+    let (hours, total_price) = optimal.map_or((Vec::new(), 0.0), |o| (o.hours, o.total_price));
+    let price_per_hour = slots.prices.iter().filter(|p| hours.contains(&p.hour)).cloned().collect();
+
+    Ok(SchedulePreviewResponse {
+        date,
+        hours,
+        slot_minutes: slots.slot_minutes,
+        total_price,
+        price_per_hour,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::App;
+
+    use crate::db::models::User;
+    use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+    fn time(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    fn create_request() -> CreateRuleRequest {
+        CreateRuleRequest {
+            device_id: Some(Uuid::new_v4()),
+            device_group_id: None,
+            name: "Termo".to_string(),
+            max_hours: Some(4),
+            time_window_start: None,
+            time_window_end: None,
+            named_window: None,
+            min_continuous_hours: None,
+            min_gap_hours: None,
+            negative_price_boost: None,
+            continuity_preference: None,
+            mode: None,
+            fixed_hours: None,
+            max_cost_eur: None,
+            days_of_week: None,
+            baseline_days: None,
+            baseline_margin_pct: None,
+            action_type: None,
+            blackout_windows: None,
+            sub_budgets: None,
+            priority: None,
+            load_profile: None,
+            active_from: None,
+            active_until: None,
+            generate_now: None,
+        }
+    }
+
+    fn rule_changes() -> RuleChanges {
+        RuleChanges {
+            name: None,
+            max_hours: None,
+            time_window_start: None,
+            time_window_end: None,
+            min_continuous_hours: None,
+            min_gap_hours: None,
+            negative_price_boost: None,
+            continuity_preference: None,
+            mode: None,
+            fixed_hours: None,
+            max_cost_eur: None,
+            days_of_week: None,
+            is_enabled: None,
+            baseline_days: None,
+            baseline_margin_pct: None,
+            action_type: None,
+            blackout_windows: None,
+            sub_budgets: None,
+            priority: None,
+            load_profile: None,
+            active_from: None,
+            active_until: None,
+        }
+    }
+
+    /// Comprova que la validació falla amb un error per al camp
+    fn assert_rejected(result: AppResult<()>, field: &str) {
+        match result {
+            Err(AppError::Validation(errors)) => assert!(errors.iter().any(|e| e.field == field), "{:?}", errors),
+            other => panic!("s'esperava un error de validació per {}: {:?}", field, other),
+        }
+    }
+
+    #[test]
+    fn test_create_rule_valid() {
+        assert!(create_request().validate().is_ok());
+
+        let rule = CreateRuleRequest {
+            time_window_start: Some(time(22)),
+            time_window_end: Some(time(6)),
+            min_continuous_hours: Some(4),
+            load_profile: Some(vec![1.0; 24]),
+            ..create_request()
         };
-        let price = prices.prices.iter()
-            .find(|p| p.hour == *hour)
-            .map(|p| p.price);
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_create_rule_device_or_group() {
+        let group = CreateRuleRequest { device_id: None, device_group_id: Some(Uuid::new_v4()), ..create_request() };
+        assert!(group.validate().is_ok());
+
+        let both = CreateRuleRequest { device_group_id: Some(Uuid::new_v4()), ..create_request() };
+        assert_rejected(both.validate(), "device_id");
+        let neither = CreateRuleRequest { device_id: None, ..create_request() };
+        assert_rejected(neither.validate(), "device_id");
+    }
+
+    #[test]
+    fn test_create_rule_reports_every_invalid_field() {
+        let rule = CreateRuleRequest {
+            max_hours: Some(0),
+            priority: Some(0),
+            max_cost_eur: Some(-1.0),
+            ..create_request()
+        };
+
+        match rule.validate() {
+            Err(AppError::Validation(errors)) => {
+                let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+                assert_eq!(fields, vec!["max_hours", "priority", "max_cost_eur"]);
+                assert_eq!(errors[0].message, "max_hours must be between 1 and 24");
+            }
+            other => panic!("s'esperava un error de validació: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_create_rule_max_hours() {
+        assert_rejected(CreateRuleRequest { max_hours: Some(0), ..create_request() }.validate(), "max_hours");
+        assert_rejected(CreateRuleRequest { max_hours: Some(25), ..create_request() }.validate(), "max_hours");
+    }
+
+    #[test]
+    fn test_create_rule_inherits_deployment_defaults() {
+        let config = Config {
+            default_max_hours: Some(6),
+            default_min_continuous: 3,
+            ..test_config()
+        };
+
+        // Sense max_hours ni min_continuous_hours: valors del desplegament
+        let mut rule = CreateRuleRequest { max_hours: None, ..create_request() };
+        rule.apply_defaults(&config).unwrap();
+        assert_eq!(rule.max_hours, Some(6));
+        assert_eq!(rule.min_continuous_hours, Some(3));
+
+        // Els valors de la regla tenen prioritat; el mínim per defecte no supera max_hours
+        let mut rule = CreateRuleRequest { max_hours: Some(2), ..create_request() };
+        rule.apply_defaults(&config).unwrap();
+        assert_eq!(rule.max_hours, Some(2));
+        assert_eq!(rule.min_continuous_hours, Some(2));
+
+        // Un min_continuous_hours explícit per sobre del max_hours per defecte es rebutja
+        let mut rule = CreateRuleRequest { max_hours: None, min_continuous_hours: Some(8), ..create_request() };
+        assert!(rule.validate().is_ok());
+        assert_rejected(rule.apply_defaults(&config), "min_continuous_hours");
+
+        // Sense valor per defecte, max_hours és obligatori
+        let mut rule = CreateRuleRequest { max_hours: None, ..create_request() };
+        assert_rejected(rule.apply_defaults(&test_config()), "max_hours");
+        let mut rule = create_request();
+        rule.apply_defaults(&test_config()).unwrap();
+        assert_eq!(rule.min_continuous_hours, Some(1));
+
+        // Les preferències de l'usuari van per davant del desplegament
+        let prefs = UserPreferences {
+            default_max_hours: Some(4),
+            default_days_of_week: 31,
+            ..UserPreferences::defaults(Uuid::nil())
+        };
+        let mut rule = CreateRuleRequest { max_hours: None, ..create_request() };
+        rule.apply_preferences(&prefs);
+        rule.apply_defaults(&config).unwrap();
+        assert_eq!((rule.max_hours, rule.days_of_week), (Some(4), Some(31)));
+        let mut rule = CreateRuleRequest { max_hours: Some(2), days_of_week: Some(96), ..create_request() };
+        rule.apply_preferences(&prefs);
+        assert_eq!((rule.max_hours, rule.days_of_week), (Some(2), Some(96)));
+    }
+
+    #[test]
+    fn test_create_rule_min_continuous_hours() {
+        let rule = CreateRuleRequest { min_continuous_hours: Some(5), ..create_request() };
+        assert_rejected(rule.validate(), "min_continuous_hours");
+
+        let rule = CreateRuleRequest { min_continuous_hours: Some(0), ..create_request() };
+        assert_rejected(rule.validate(), "min_continuous_hours");
+    }
+
+    #[test]
+    fn test_create_rule_time_window() {
+        let rule = CreateRuleRequest {
+            time_window_start: Some(time(8)),
+            time_window_end: Some(time(8)),
+            ..create_request()
+        };
+        assert_rejected(rule.validate(), "time_window_end");
+
+        // Sense cap hora sencera: 10:00-10:30
+        let rule = CreateRuleRequest {
+            time_window_start: Some(time(10)),
+            time_window_end: NaiveTime::from_hms_opt(10, 30, 0),
+            ..create_request()
+        };
+        assert_rejected(rule.validate(), "time_window_end");
+
+        // Més estreta que el bloc mínim, també creuant mitjanit: 23:00-01:00 són 2 hores
+        let rule = CreateRuleRequest {
+            time_window_start: Some(time(23)),
+            time_window_end: Some(time(1)),
+            min_continuous_hours: Some(3),
+            ..create_request()
+        };
+        assert_rejected(rule.validate(), "time_window_end");
+        let rule = CreateRuleRequest { min_continuous_hours: Some(2), ..rule };
+        assert!(rule.validate().is_ok());
+
+        // El mínim per defecte del desplegament també ha de cabre a la finestra
+        let config = Config { default_min_continuous: 3, ..test_config() };
+        let mut rule = CreateRuleRequest {
+            time_window_start: Some(time(23)),
+            time_window_end: Some(time(1)),
+            ..create_request()
+        };
+        assert_rejected(rule.apply_defaults(&config), "time_window_end");
+
+        // Una finestra oberta (només inici) és vàlida
+        let rule = CreateRuleRequest { time_window_start: Some(time(8)), ..create_request() };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_create_rule_baseline() {
+        let rule = CreateRuleRequest { baseline_days: Some(31), ..create_request() };
+        assert_rejected(rule.validate(), "baseline_days");
+
+        let rule = CreateRuleRequest { baseline_margin_pct: Some(100.0), ..create_request() };
+        assert_rejected(rule.validate(), "baseline_margin_pct");
+    }
+
+    #[test]
+    fn test_create_rule_windows_and_budgets() {
+        let rule = CreateRuleRequest {
+            blackout_windows: Some(vec![BlackoutWindow { start: time(3), end: time(3) }]),
+            ..create_request()
+        };
+        assert_rejected(rule.validate(), "blackout_windows");
+
+        let rule = CreateRuleRequest {
+            sub_budgets: Some(vec![SubBudget { start: time(0), end: time(6), hours: 0 }]),
+            ..create_request()
+        };
+        assert_rejected(rule.validate(), "sub_budgets");
+    }
+
+    #[test]
+    fn test_create_rule_priority_and_load_profile() {
+        assert_rejected(CreateRuleRequest { priority: Some(0), ..create_request() }.validate(), "priority");
+
+        let rule = CreateRuleRequest { load_profile: Some(vec![1.0; 12]), ..create_request() };
+        assert_rejected(rule.validate(), "load_profile");
+
+        let rule = CreateRuleRequest { load_profile: Some(vec![0.0; 24]), ..create_request() };
+        assert_rejected(rule.validate(), "load_profile");
+
+        // [] equival a no tenir perfil
+        let rule = CreateRuleRequest { load_profile: Some(vec![]), ..create_request() };
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn test_create_rule_season() {
+        let june = NaiveDate::from_ymd_opt(2030, 6, 1).unwrap();
+        let september = NaiveDate::from_ymd_opt(2030, 9, 30).unwrap();
+
+        let rule = CreateRuleRequest { active_from: Some(june), active_until: Some(september), ..create_request() };
+        assert!(rule.validate().is_ok());
+        // Només un dels dos extrems
+        assert!(CreateRuleRequest { active_until: Some(june), ..create_request() }.validate().is_ok());
+
+        let rule = CreateRuleRequest { active_from: Some(september), active_until: Some(june), ..create_request() };
+        assert_rejected(rule.validate(), "active_until");
+    }
+
+    #[test]
+    fn test_create_rule_fixed_mode() {
+        let fixed = |fixed_hours: Option<Vec<i32>>| CreateRuleRequest {
+            mode: Some(RuleMode::Fixed),
+            fixed_hours,
+            ..create_request()
+        };
+        assert!(fixed(Some(vec![14, 15])).validate().is_ok());
+        assert_rejected(fixed(Some(vec![14, 24])).validate(), "fixed_hours");
+        assert_rejected(fixed(Some(vec![14, 14])).validate(), "fixed_hours");
+
+        // Sense hores, cal una finestra horària
+        assert_rejected(fixed(None).validate(), "fixed_hours");
+        assert_rejected(fixed(Some(vec![])).validate(), "fixed_hours");
+        let window = CreateRuleRequest { time_window_start: NaiveTime::from_hms_opt(14, 0, 0), ..fixed(None) };
+        assert!(window.validate().is_ok());
+    }
+
+    #[test]
+    fn test_min_gap_excludes_continuous_blocks() {
+        let gapped = |min_gap_hours, min_continuous_hours| CreateRuleRequest {
+            min_gap_hours: Some(min_gap_hours),
+            min_continuous_hours,
+            ..create_request()
+        };
+        assert!(gapped(2, None).validate().is_ok());
+        assert!(gapped(2, Some(1)).validate().is_ok());
+        assert_rejected(gapped(2, Some(2)).validate(), "min_gap_hours");
+        assert_rejected(gapped(24, None).validate(), "min_gap_hours");
+        // 0 equival a no tenir separació
+        assert!(gapped(0, Some(2)).validate().is_ok());
+
+        // Amb separació, el min_continuous_hours per defecte del desplegament no s'aplica
+        let config = Config { default_min_continuous: 3, ..test_config() };
+        let mut rule = gapped(2, None);
+        rule.apply_defaults(&config).unwrap();
+        assert_eq!(rule.min_continuous_hours, Some(1));
+    }
+
+    #[test]
+    fn test_negative_price_boost_range() {
+        let boosted = |boost| CreateRuleRequest { negative_price_boost: Some(boost), ..create_request() };
+        assert!(boosted(0).validate().is_ok());
+        assert!(boosted(24).validate().is_ok());
+        assert_rejected(boosted(-1).validate(), "negative_price_boost");
+        assert_rejected(boosted(25).validate(), "negative_price_boost");
+    }
+
+    #[test]
+    fn test_update_rule_only_checks_present_fields() {
+        assert!(rule_changes().validate().is_ok());
+
+        assert_rejected(RuleChanges { max_hours: Some(30), ..rule_changes() }.validate(), "max_hours");
+
+        let rule = RuleChanges {
+            max_hours: Some(2),
+            min_continuous_hours: Some(3),
+            ..rule_changes()
+        };
+        assert_rejected(rule.validate(), "min_continuous_hours");
+
+        // Sense max_hours, min_continuous_hours es comprova al handler amb el valor actual
+        let rule = RuleChanges { min_continuous_hours: Some(3), ..rule_changes() };
+        assert!(rule.validate().is_ok());
+
+        assert_rejected(RuleChanges { priority: Some(-1), ..rule_changes() }.validate(), "priority");
+
+        // 0 elimina la condició dels darrers dies
+        assert!(RuleChanges { baseline_days: Some(0), ..rule_changes() }.validate().is_ok());
+        assert_rejected(RuleChanges { baseline_days: Some(31), ..rule_changes() }.validate(), "baseline_days");
+        // 0 elimina el cost màxim
+        assert!(RuleChanges { max_cost_eur: Some(0.0), ..rule_changes() }.validate().is_ok());
+        assert_rejected(RuleChanges { max_cost_eur: Some(-1.0), ..rule_changes() }.validate(), "max_cost_eur");
+        let (june, september) = (NaiveDate::from_ymd_opt(2030, 6, 1), NaiveDate::from_ymd_opt(2030, 9, 30));
+        let rule = RuleChanges { active_from: Some(september), active_until: Some(june), ..rule_changes() };
+        assert_rejected(rule.validate(), "active_until");
+        let rule = RuleChanges { active_from: Some(None), active_until: Some(june), ..rule_changes() };
+        assert!(rule.validate().is_ok());
+
+        // La finestra només es comprova aquí si arriben els dos extrems
+        let rule = RuleChanges { time_window_start: Some(time(23)), ..rule_changes() };
+        assert!(rule.validate().is_ok());
+        let rule = RuleChanges { time_window_end: Some(time(0)), min_continuous_hours: Some(2), ..rule };
+        assert_rejected(rule.validate(), "time_window_end");
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_insert_rule_device_deleted_mid_request(pool: PgPool) {
+        let user = create_user(&pool, "race").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+
+        // Simula que el dispositiu s'esborra després de comprovar-ne la propietat
+        sqlx::query("DELETE FROM devices WHERE id = $1")
+            .bind(device.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let body = CreateRuleRequest { device_id: Some(device.id), ..create_request() };
+        let mut conn = pool.acquire().await.unwrap();
+        match insert_rule(&mut conn, &body, &RuleTarget::Device(device), None).await {
+            Err(AppError::NotFound(_, message)) => assert_eq!(message, "Device not found"),
+            other => panic!("s'esperava NotFound: {:?}", other.map(|r| r.rule.id)),
+        }
+    }
 
-        let result = sqlx::query(
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_batch_update_mixed_ownership(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "batch").await;
+        let other = create_user(&pool, "other").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let other_device = create_device(&pool, other.id, "Rentadora").await;
+        let first = create_rule(&pool, device.id, "Nit", true).await;
+        let second = create_rule(&pool, device.id, "Matí", true).await;
+        let foreign = create_rule(&pool, other_device.id, "Aliena", true).await;
+        let unknown = Uuid::new_v4();
+
+        sqlx::query(
             r#"
-            INSERT INTO scheduled_actions (rule_id, scheduled_date, start_time, end_time, price_per_kwh, status)
-            VALUES ($1, $2, $3, $4, $5, 'pending')
-            ON CONFLICT (rule_id, scheduled_date, start_time) DO NOTHING
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, $3, '02:00', '03:00')
             "#
         )
+        .bind(first.id)
+        .bind(device.id)
+        .bind(clock::today(config.timezone) + chrono::Duration::days(1))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        // Sense ESIOS accessible: la regeneració falla però la resposta no
+        let pvpc = PvpcClient::with_token("test".to_string())
+            .with_base_url("http://127.0.0.1:9")
+            .with_retries(0, std::time::Duration::ZERO);
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/api/rules/batch-update")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({
+                "rule_ids": [foreign.id, first.id, unknown, second.id],
+                "is_enabled": false,
+            }))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["updated"], serde_json::json!([first.id, second.id]));
+        assert_eq!(body["not_found"], serde_json::json!([foreign.id, unknown]));
+        assert_eq!(body["schedules_created"], 0);
+
+        // Les regles canviades passen de versió: un PATCH amb la versió llegida abans respon 409
+        let enabled: Vec<(Uuid, bool, i64)> = sqlx::query_as("SELECT id, is_enabled, version FROM rules ORDER BY name")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert!(enabled.contains(&(first.id, false, 2)));
+        assert!(enabled.contains(&(second.id, false, 2)));
+        assert!(enabled.contains(&(foreign.id, true, 1)));
+
+        let status: String = sqlx::query_scalar("SELECT status FROM scheduled_actions WHERE rule_id = $1")
+            .bind(first.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(status, "cancelled");
+
+        // Tornar-les a activar: la regla aliena continua sense trobar-se
+        let req = TestRequest::post()
+            .uri("/api/rules/batch-update")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "rule_ids": [first.id, foreign.id], "is_enabled": true }))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["updated"], serde_json::json!([first.id]));
+        assert_eq!(body["not_found"], serde_json::json!([foreign.id]));
+    }
+
+    #[test]
+    fn test_batch_update_validation() {
+        let batch = |rule_ids: Vec<Uuid>| BatchUpdateRulesRequest { rule_ids, is_enabled: false };
+
+        assert!(batch(vec![Uuid::new_v4()]).validate().is_ok());
+        assert_rejected(batch(vec![]).validate(), "rule_ids");
+        assert_rejected(batch(vec![Uuid::new_v4(); MAX_BATCH_RULES + 1]).validate(), "rule_ids");
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_create_rule_generate_now(pool: PgPool) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        use actix_web::HttpServer;
+
+        // ESIOS simulat sense preus que compta les peticions rebudes
+        let esios_calls = Arc::new(AtomicUsize::new(0));
+        let calls = esios_calls.clone();
+        let server = HttpServer::new(move || {
+            let calls = calls.clone();
+            App::new().default_service(web::to(move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                async { HttpResponse::Ok().json(serde_json::json!({ "indicator": { "values": [] } })) }
+            }))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        let config = test_config();
+        let user = create_user(&pool, "generate").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(format!("http://{}", addr));
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let create = |generate_now: bool| {
+            TestRequest::post()
+                .uri("/api/rules")
+                .insert_header(auth_header(&user, &config))
+                .set_json(serde_json::json!({
+                    "device_id": device.id,
+                    "name": "Nit",
+                    "max_hours": 3,
+                    "generate_now": generate_now,
+                }))
+                .to_request()
+        };
+
+        let resp = call_service(&app, create(false)).await;
+        assert_eq!(resp.status(), 201);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["schedule_info"]["schedules_created"], 0);
+        assert_eq!(esios_calls.load(Ordering::SeqCst), 0);
+
+        let resp = call_service(&app, create(true)).await;
+        assert_eq!(resp.status(), 201);
+        assert!(esios_calls.load(Ordering::SeqCst) > 0);
+
+        handle.stop(false).await;
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_group_rule_generates_action_per_member(pool: PgPool) {
+        let user = create_user(&pool, "group").await;
+        let first = create_device(&pool, user.id, "Termo 1").await;
+        let second = create_device(&pool, user.id, "Termo 2").await;
+
+        let group_id: Uuid = sqlx::query_scalar(
+            "INSERT INTO device_groups (user_id, name) VALUES ($1, 'Termos') RETURNING id"
+        )
+        .bind(user.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO device_group_members (group_id, device_id) VALUES ($1, $2), ($1, $3)")
+            .bind(group_id)
+            .bind(first.id)
+            .bind(second.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let rule = sqlx::query_as::<_, Rule>(
+            "INSERT INTO rules (device_group_id, name, max_hours) VALUES ($1, 'Termos nit', 2) RETURNING *"
+        )
+        .bind(group_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let prices = shared::DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| shared::HourlyPrice { hour, price: 0.10 + hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+
+        let created = generate_schedules_for_rule_and_date(&pool, &rule, &prices, date, None).await.unwrap();
+        assert_eq!(created, 4);
+
+        let mut rows: Vec<(Uuid, NaiveTime)> = sqlx::query_as(
+            "SELECT device_id, start_time FROM scheduled_actions WHERE rule_id = $1"
+        )
         .bind(rule.id)
-        .bind(date)
-        .bind(start_time)
-        .bind(end_time)
-        .bind(price)
-        .execute(pool)
-        .await?;
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        rows.sort();
+        let mut expected = vec![(first.id, time(0)), (first.id, time(1)), (second.id, time(0)), (second.id, time(1))];
+        expected.sort();
+        assert_eq!(rows, expected);
+    }
 
-        if result.rows_affected() > 0 {
-            created_count += 1;
-        }
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_fixed_rule_schedules_configured_hours(pool: PgPool) {
+        let user = create_user(&pool, "fixed").await;
+        let device = create_device(&pool, user.id, "Depuradora").await;
+        // La porta del preu de referència no s'aplica a les regles d'hores fixes
+        let rule = sqlx::query_as::<_, Rule>(
+            r#"
+            INSERT INTO rules (device_id, name, max_hours, mode, fixed_hours, baseline_days)
+            VALUES ($1, 'Depuradora', 1, 'fixed', '{14, 15}', 7)
+            RETURNING *
+            "#
+        )
+        .bind(device.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        assert_eq!(rule.mode, RuleMode::Fixed);
+
+        // Les hores configurades són les més cares del dia
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let prices = shared::DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| shared::HourlyPrice { hour, price: 0.10 + hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+
+        let created = generate_schedules_for_rule_and_date(&pool, &rule, &prices, date, None).await.unwrap();
+        assert_eq!(created, 2);
+
+        let mut starts: Vec<NaiveTime> =
+            sqlx::query_scalar("SELECT start_time FROM scheduled_actions WHERE rule_id = $1")
+                .bind(rule.id)
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        starts.sort();
+        assert_eq!(starts, vec![time(14), time(15)]);
     }
 
-    Ok(created_count)
-}
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_put_replaces_and_patch_updates_rule(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "put").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        // Desactivada: en desar-la no es regeneren schedules
+        let rule = create_rule(&pool, device.id, "Nit", false).await;
 
-/// Cancel·la els schedules pendents d'una regla (quan es desactiva)
-async fn cancel_pending_schedules_for_rule(
-    pool: &PgPool,
-    rule_id: Uuid,
-) -> Result<u64, sqlx::Error> {
-    let now = Local::now();
-    let today = now.date_naive();
-    let current_time = now.time();
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(PvpcClient::with_token("test".to_string())))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let send = |request: TestRequest, body: serde_json::Value| {
+            request
+                .uri(&format!("/api/rules/{}", rule.id))
+                .insert_header(auth_header(&user, &config))
+                .set_json(body)
+                .to_request()
+        };
 
-    let result = sqlx::query(
-        r#"
-        UPDATE scheduled_actions
-        SET status = 'cancelled'
-        WHERE rule_id = $1
-          AND status = 'pending'
-          AND (scheduled_date > $2 OR (scheduled_date = $2 AND start_time > $3))
-        "#
-    )
-    .bind(rule_id)
-    .bind(today)
-    .bind(current_time)
-    .execute(pool)
-    .await?;
+        let mut full = serde_json::json!({
+            "version": 1,
+            "name": "Tarda",
+            "max_hours": 3,
+            "min_continuous_hours": 1,
+            "continuity_preference": "required",
+            "mode": "optimize",
+            "days_of_week": 127,
+            "is_enabled": false,
+            "baseline_margin_pct": 0.0,
+            "action_type": "turn_on",
+            "blackout_windows": [],
+            "sub_budgets": [],
+            "priority": 2,
+            "time_window_start": "14:00:00",
+            "time_window_end": "20:00:00",
+        });
+
+        // A PUT li cal la regla sencera
+        let mut partial = full.clone();
+        partial.as_object_mut().unwrap().remove("name");
+        let resp = call_service(&app, send(TestRequest::put(), partial)).await;
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "INCOMPLETE_BODY");
+
+        let resp = call_service(&app, send(TestRequest::put(), full.clone())).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!((body["name"].as_str(), body["max_hours"].as_i64()), (Some("Tarda"), Some(3)));
+        assert_eq!(body["time_window_start"], "14:00:00");
+        assert_eq!(body["version"], 2);
+
+        // Els camps opcionals que falten a un PUT queden a null
+        full["version"] = body["version"].clone();
+        full.as_object_mut().unwrap().remove("time_window_start");
+        full.as_object_mut().unwrap().remove("time_window_end");
+        let body: serde_json::Value = read_body_json(call_service(&app, send(TestRequest::put(), full)).await).await;
+        assert!(body["time_window_start"].is_null());
+
+        // PATCH només canvia els camps presents
+        let patch = serde_json::json!({ "version": 3, "priority": 5 });
+        let resp = call_service(&app, send(TestRequest::patch(), patch)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!((body["name"].as_str(), body["priority"].as_i64()), (Some("Tarda"), Some(5)));
+        assert_eq!(body["version"], 4);
+    }
 
-    if result.rows_affected() > 0 {
-        tracing::info!(
-            "Cancel·lats {} schedules pendents per la regla {}",
-            result.rows_affected(),
-            rule_id
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_invalid_rule_reports_every_field_error(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "invalid").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", false).await;
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(PvpcClient::with_token("test".to_string())))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let errors = |resp: actix_web::dev::ServiceResponse| async move {
+            assert_eq!(resp.status(), 422);
+            let body: serde_json::Value = read_body_json(resp).await;
+            body["errors"].as_array().unwrap().clone()
+        };
+
+        // max_hours = 0 i min_continuous_hours > max_hours: els dos errors alhora
+        let req = TestRequest::post()
+            .uri("/api/rules")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({
+                "device_id": device.id,
+                "name": "Invàlida",
+                "max_hours": 0,
+                "min_continuous_hours": 3,
+            }))
+            .to_request();
+        let created = errors(call_service(&app, req).await).await;
+        assert_eq!(
+            created,
+            vec![
+                serde_json::json!({
+                    "field": "max_hours",
+                    "message": "max_hours must be between 1 and 24",
+                    "code": "OUT_OF_RANGE",
+                }),
+                serde_json::json!({
+                    "field": "min_continuous_hours",
+                    "message": "min_continuous_hours must be between 1 and max_hours",
+                    "code": "OUT_OF_RANGE",
+                }),
+            ]
         );
+
+        let req = TestRequest::patch()
+            .uri(&format!("/api/rules/{}", rule.id))
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "version": 1, "max_hours": 0, "min_continuous_hours": 3 }))
+            .to_request();
+        assert_eq!(errors(call_service(&app, req).await).await, created);
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM rules").fetch_one(&pool).await.unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_concurrent_updates_with_same_version_conflict(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "concurrent").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", false).await;
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(PvpcClient::with_token("test".to_string())))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let patch = |priority: i32| {
+            TestRequest::patch()
+                .uri(&format!("/api/rules/{}", rule.id))
+                .insert_header(auth_header(&user, &config))
+                .set_json(serde_json::json!({ "version": 1, "priority": priority }))
+                .to_request()
+        };
+
+        // Dos clients que han llegit la mateixa versió
+        let (first, second) = futures_util::join!(call_service(&app, patch(3)), call_service(&app, patch(4)));
+        let mut statuses = [first.status().as_u16(), second.status().as_u16()];
+        statuses.sort();
+        assert_eq!(statuses, [200, 409]);
+
+        let conflict = if first.status() == 409 { first } else { second };
+        let body: serde_json::Value = read_body_json(conflict).await;
+        assert_eq!((body["error"].as_str(), body["current_version"].as_i64()), (Some("CONFLICT"), Some(2)));
+
+        let version: i64 = sqlx::query_scalar("SELECT version FROM rules WHERE id = $1")
+            .bind(rule.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(version, 2);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_patch_removes_optional_limits(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "limits").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", false).await;
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(PvpcClient::with_token("test".to_string())))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let patch = |body: serde_json::Value| {
+            TestRequest::patch()
+                .uri(&format!("/api/rules/{}", rule.id))
+                .insert_header(auth_header(&user, &config))
+                .set_json(body)
+                .to_request()
+        };
+
+        let set = serde_json::json!({
+            "version": 1,
+            "baseline_days": 7,
+            "max_cost_eur": 0.5,
+            "active_from": "2030-06-01",
+            "active_until": "2030-09-30",
+        });
+        let body: serde_json::Value = read_body_json(call_service(&app, patch(set)).await).await;
+        assert_eq!((body["baseline_days"].as_i64(), body["max_cost_eur"].as_f64()), (Some(7), Some(0.5)));
+        assert_eq!(body["active_from"], "2030-06-01");
+        assert_eq!(body["active_until"], "2030-09-30");
+
+        // Absent no canvia res; null tampoc als camps que s'eliminen amb 0
+        let unchanged = serde_json::json!({ "version": 2, "priority": 2, "baseline_days": null });
+        let body: serde_json::Value = read_body_json(call_service(&app, patch(unchanged)).await).await;
+        assert_eq!((body["baseline_days"].as_i64(), body["max_cost_eur"].as_f64()), (Some(7), Some(0.5)));
+        assert_eq!(body["active_from"], "2030-06-01");
+        assert_eq!(body["active_until"], "2030-09-30");
+
+        // 0 elimina la condició i el cost màxim; null, els límits de la temporada
+        let cleared = serde_json::json!({
+            "version": 3,
+            "baseline_days": 0,
+            "max_cost_eur": 0,
+            "active_from": null,
+            "active_until": null,
+        });
+        let resp = call_service(&app, patch(cleared)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert!(body["baseline_days"].is_null());
+        assert!(body["max_cost_eur"].is_null());
+        assert!(body["active_from"].is_null() && body["active_until"].is_null());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_preview_schedule_applies_changes_without_saving(pool: PgPool) {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // ESIOS: l'hora 2 és la més barata i l'hora 1 la segona
+        let today = clock::today(clock::DEFAULT_TIMEZONE);
+        let values: Vec<_> = [(0, 200.0), (1, 100.0), (2, 50.0), (3, 300.0)]
+            .iter()
+            .map(|(hour, value)| {
+                let datetime = format!("{}T{:02}:00:00.000+01:00", today, hour);
+                serde_json::json!({ "value": value, "datetime": datetime, "geo_id": 8741 })
+            })
+            .collect();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": values } })),
+            )
+            .mount(&server)
+            .await;
+
+        let config = test_config();
+        let user = create_user(&pool, "preview").await;
+        let other = create_user(&pool, "other").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(pvpc))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let preview = |user: &User, body: serde_json::Value| {
+            TestRequest::post()
+                .uri(&format!("/api/rules/{}/preview-schedule", rule.id))
+                .insert_header(auth_header(user, &config))
+                .set_json(body)
+                .to_request()
+        };
+
+        // La regla tal com està: les 2 hores més barates
+        let resp = call_service(&app, preview(&user, serde_json::json!({ "date": today }))).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["hours"], serde_json::json!([1, 2]));
+        assert!((body["total_price"].as_f64().unwrap() - 0.15).abs() < 1e-9);
+        assert_eq!(body["price_per_hour"].as_array().unwrap().len(), 2);
+
+        // Amb canvis pendents
+        let body = serde_json::json!({ "date": today, "max_hours": 1 });
+        let resp = call_service(&app, preview(&user, body)).await;
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["hours"], serde_json::json!([2]));
+
+        let body = serde_json::json!({ "date": today, "is_enabled": false });
+        let resp = call_service(&app, preview(&user, body)).await;
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["hours"], serde_json::json!([]));
+
+        let body = serde_json::json!({ "date": today, "min_continuous_hours": 3 });
+        let resp = call_service(&app, preview(&user, body)).await;
+        assert_eq!(resp.status(), 422);
+        let resp = call_service(&app, preview(&other, serde_json::json!({ "date": today }))).await;
+        assert_eq!(resp.status(), 404);
+        // La versió no cal: la previsualització no desa res
+        let resp = call_service(&app, preview(&user, serde_json::json!({ "date": today, "version": 7 }))).await;
+        assert_eq!(resp.status(), 200);
+
+        // No s'ha desat res
+        let saved: Rule = sqlx::query_as("SELECT * FROM rules WHERE id = $1")
+            .bind(rule.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!((saved.max_hours, saved.is_enabled), (2, true));
+        let actions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scheduled_actions").fetch_one(&pool).await.unwrap();
+        assert_eq!(actions, 0);
     }
 
-    Ok(result.rows_affected())
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_rule_schedules_only_returns_own_rule(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "schedules").await;
+        let other = create_user(&pool, "other").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let other_device = create_device(&pool, other.id, "Rentadora").await;
+        let rule = create_rule(&pool, device.id, "Nit", false).await;
+        let sibling = create_rule(&pool, device.id, "Tarda", false).await;
+        let other_rule = create_rule(&pool, other_device.id, "Seva", false).await;
+
+        let today = clock::today(config.timezone);
+        for (rule_id, device_id, days, hour) in [
+            (rule.id, device.id, 0, 2),
+            (rule.id, device.id, 1, 3),
+            (rule.id, device.id, 5, 4),
+            (sibling.id, device.id, 0, 5),
+            (other_rule.id, other_device.id, 0, 2),
+        ] {
+            sqlx::query(
+                r#"
+                INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, price_per_kwh)
+                VALUES ($1, $2, $3, $4, $5, 0.1)
+                "#
+            )
+            .bind(rule_id)
+            .bind(device_id)
+            .bind(today + chrono::Duration::days(days))
+            .bind(time(hour))
+            .bind(time(hour + 1))
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let get = |user: &User, uri: String| {
+            TestRequest::get().uri(&uri).insert_header(auth_header(user, &config)).to_request()
+        };
+
+        // Per defecte, d'avui a demà passat: la de d'aquí a 5 dies queda fora
+        let resp = call_service(&app, get(&user, format!("/api/rules/{}/schedules", rule.id))).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        let starts: Vec<&str> = body.as_array().unwrap().iter().map(|a| a["start_time"].as_str().unwrap()).collect();
+        assert_eq!(starts, vec!["02:00:00", "03:00:00"]);
+        assert_eq!(body[0]["status"], "pending");
+        assert_eq!(body[0]["price_per_kwh"], 0.1);
+
+        let uri = format!("/api/rules/{}/schedules?from={}&to={}", rule.id, today, today + chrono::Duration::days(7));
+        let body: serde_json::Value = read_body_json(call_service(&app, get(&user, uri)).await).await;
+        assert_eq!(body.as_array().unwrap().len(), 3);
+
+        // La regla d'un altre usuari no es troba
+        let resp = call_service(&app, get(&user, format!("/api/rules/{}/schedules", other_rule.id))).await;
+        assert_eq!(resp.status(), 404);
+
+        let uri = format!("/api/rules/{}/schedules?from={}&to={}", rule.id, today, today - chrono::Duration::days(1));
+        assert_eq!(call_service(&app, get(&user, uri)).await.status(), 400);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_rule_execution_stats(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "stats").await;
+        let termo = create_device(&pool, user.id, "Termo").await;
+        let nit = create_rule(&pool, termo.id, "Nit", true).await;
+        let altra = create_rule(&pool, termo.id, "Altra", true).await;
+        let today = clock::today(config.timezone);
+        let yesterday = today - chrono::Duration::days(1);
+        let too_old = today - chrono::Duration::days(EXECUTION_STATS_DAYS);
+
+        // (regla, dia, inici, acció, estat, preu, potència)
+        let actions = [
+            (nit.id, yesterday, "02:00", "on", "executed_on", Some(0.10), Some(2000.0)),
+            (nit.id, yesterday, "03:00", "on", "executed", Some(0.20), None),
+            (nit.id, yesterday, "06:00", "off", "executed_off", Some(0.30), None),
+            (nit.id, yesterday, "07:00", "on", "executed_on", None, Some(2000.0)),
+            (nit.id, yesterday, "04:00", "on", "missed", Some(0.05), None),
+            (nit.id, yesterday, "05:00", "on", "failed", Some(0.05), None),
+            (nit.id, yesterday, "08:00", "on", "cancelled", Some(0.05), None),
+            (nit.id, yesterday, "01:00", "on", SUPERSEDED_STATUS, Some(0.05), None),
+            (nit.id, today, "23:00", "on", "pending", Some(0.05), None),
+            // Fora del període i d'una altra regla
+            (nit.id, too_old, "02:00", "on", "executed_on", Some(0.9), None),
+            (altra.id, yesterday, "02:00", "on", "executed_on", Some(0.9), None),
+        ];
+        for (rule_id, date, start, action, status, price, watts) in actions {
+            sqlx::query(
+                r#"
+                INSERT INTO scheduled_actions
+                    (rule_id, device_id, scheduled_date, start_time, end_time, price_per_kwh,
+                     action, status, actual_power_watts)
+                VALUES ($1, $2, $3, $4::time, $4::time + interval '1 hour', $5, $6, $7, $8)
+                "#
+            )
+            .bind(rule_id)
+            .bind(termo.id)
+            .bind(date)
+            .bind(start)
+            .bind(price)
+            .bind(action)
+            .bind(status)
+            .bind(watts)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .service(web::scope("/api").configure(configure)),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri(&format!("/api/rules/{}/execution-stats", nit.id))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let stats: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+
+        assert_eq!(stats["rule_id"], nit.id.to_string());
+        assert_eq!(stats["period_days"], 30);
+        // Tot menys la superseded
+        assert_eq!(stats["total_scheduled"], 8);
+        assert_eq!(stats["total_executed"], 4);
+        assert_eq!(stats["total_missed"], 2);
+        assert_eq!(stats["total_cancelled"], 1);
+        // 4 executades de 6 que s'havien de fer
+        assert!((stats["execution_rate_pct"].as_f64().unwrap() - 400.0 / 6.0).abs() < 1e-9);
+        // Només les executades amb preu
+        assert!((stats["avg_price_paid_eur_kwh"].as_f64().unwrap() - 0.20).abs() < 1e-9);
+        // Encesos amb preu: 2 kWh a 0.10 (mesurat) + 2 kWh a 0.20 (mitjana del dispositiu)
+        assert!((stats["total_cost_eur"].as_f64().unwrap() - 0.60).abs() < 1e-9);
+
+        let hours: Vec<(u64, i64)> = stats["hourly_breakdown"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|h| (h["hour"].as_u64().unwrap(), h["executions"].as_i64().unwrap()))
+            .collect();
+        assert_eq!(hours, vec![(2, 1), (3, 1), (6, 1), (7, 1)]);
+        assert_eq!(stats["hourly_breakdown"][3]["avg_price"], 0.0);
+
+        // La potència configurada del dispositiu passa davant de la mitjana: 2 kWh a 0.10 + 1 kWh a 0.20
+        sqlx::query("UPDATE devices SET wattage_watts = 1000 WHERE id = $1")
+            .bind(termo.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let req = TestRequest::get()
+            .uri(&format!("/api/rules/{}/execution-stats", nit.id))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let stats: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert!((stats["total_cost_eur"].as_f64().unwrap() - 0.40).abs() < 1e-9);
+
+        // D'un altre usuari, 404
+        let other = create_user(&pool, "intrus").await;
+        let req = TestRequest::get()
+            .uri(&format!("/api/rules/{}/execution-stats", nit.id))
+            .insert_header(auth_header(&other, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 404);
+    }
 }
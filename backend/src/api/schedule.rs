@@ -1,57 +1,290 @@
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use actix_web::http::header;
+use actix_web::web::Bytes;
 use actix_web::{get, patch, post, web, HttpRequest, HttpResponse};
-use chrono::{Datelike, NaiveDate, NaiveTime};
+use chrono::{DateTime, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use futures_util::Stream;
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
+use shared::{BlackoutWindow, DailyPrices, HourlyPrice};
+use sqlx::{FromRow, PgConnection, PgPool};
+use tokio::sync::broadcast;
+use tokio::time::{interval, interval_at, Instant, Interval};
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 use crate::config::Config;
-use crate::db::models::Rule;
-use crate::error::{AppError, AppResult};
+use crate::db::lock_schedule_generation;
+use crate::db::models::{ActionType, ContinuityPreference, Rule};
+use crate::error::{AppError, AppResult, ErrorResponse};
+use crate::services::energy_tariff::{cost_by_period, PriceAdjustment};
+use crate::services::forecast::{estimate_tomorrow_prices, FORECAST_HISTORY_DAYS};
+use crate::services::ical::{schedule_calendar, CalendarAction};
+use crate::services::price_history::{get_prices_between, passes_baseline_gate, store_prices};
 use crate::services::pvpc::PvpcClient;
-use crate::services::scheduler::calculate_optimal_hours;
+use crate::services::{clock, metrics};
+use crate::services::schedule_events::{ScheduleEvent, ScheduleEvents};
+use crate::services::scheduled_actions::{
+    find_conflicts, insert_rule_actions, proposed_rule_action_devices, record_skip, SkipReason, SUPERSEDED_STATUS,
+};
+use crate::services::scheduler::{
+    calculate_weekly_optimal, detect_action_conflicts, explain_optimal_hours, optimal_hours_for_rule,
+    stagger_group_hours, weekly_hours, HourDecision, PriceSlots, SchedulerParams,
+};
+use crate::services::secrets::pvpc_client_for_user;
+use crate::services::webhooks::WebhookDispatcher;
+
+use super::auth::{extract_user_from_request, user_geo_zone};
+use super::pagination::{PageQuery, Paginated};
+use super::prices::{validate_range, RangeQuery};
+use super::rules::{
+    validate_blackout_windows, validate_load_profile, validate_max_cost, validate_max_hours,
+    validate_min_continuous_hours, validate_min_gap_hours, validate_negative_price_boost, validate_time_window,
+};
+use super::validation::{Validate, Validated};
+
+/// Interval de consulta a la BD per detectar canvis d'estat (SSE)
+const STREAM_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Interval entre comentaris keep-alive (SSE)
+const STREAM_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
 
-use super::auth::extract_user_from_request;
+/// Dies enrere que es consulten per defecte a `/schedule/missed`
+const DEFAULT_MISSED_DAYS: i64 = 30;
 
-#[derive(Debug, Deserialize)]
+/// Estats d'una acció que es poden tornar a posar a pending
+const RETRYABLE_STATUSES: [&str; 2] = ["missed", "failed"];
+
+/// Dies que planifica `/schedule/plan-week`
+const PLAN_WEEK_DAYS: usize = 7;
+
+/// Potència que es suposa per als dispositius sense cap mesura (1 kWh per hora)
+pub(super) const ASSUMED_POWER_WATTS: f64 = 1000.0;
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct CalculateRequest {
     pub rule_id: Uuid,
     pub date: Option<NaiveDate>,
 }
 
-#[derive(Debug, Deserialize)]
+/// Mida màxima del missatge d'error reportat pel client
+const MAX_ERROR_MESSAGE_LEN: usize = 1000;
+
+/// Estats vàlids d'una acció programada
+/// - pending: acció programada pendent d'executar
+/// - executed: executat genèric (legacy)
+/// - executed_on: dispositiu encès correctament
+/// - executed_off: dispositiu apagat correctament
+/// - failed: error en l'execució
+/// - cancelled: cancel·lat manualment
+/// - missed: l'hora va passar sense executar-se
+/// - superseded: una regla del mateix dispositiu amb més prioritat té la mateixa franja
+const VALID_STATUSES: [&str; 8] = [
+    "pending",
+    "executed",
+    "executed_on",
+    "executed_off",
+    "failed",
+    "cancelled",
+    "missed",
+    SUPERSEDED_STATUS,
+];
+
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct UpdateStatusRequest {
-    /// Status de l'acció: pending, executed, executed_on, executed_off, failed, cancelled, missed
+    /// Status de l'acció: pending, executed, executed_on, executed_off, failed, cancelled, missed, superseded
     pub status: String,
+    /// Potència mesurada per l'endoll (W)
+    pub actual_power_watts: Option<f64>,
+    /// Motiu de l'error (p. ex. amb status failed)
+    pub error_message: Option<String>,
+    /// Hora real d'execució segons el dispositiu
+    pub executed_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize)]
+impl Validate for UpdateStatusRequest {
+    fn validate(&self) -> AppResult<()> {
+        if !VALID_STATUSES.contains(&self.status.as_str()) {
+            return Err(AppError::BadRequest("ACTION_VALIDATION_FAILED", format!(
+                "Invalid status '{}'. Valid values: {:?}",
+                self.status, VALID_STATUSES
+            )));
+        }
+
+        if self.actual_power_watts.is_some_and(|w| !w.is_finite() || w < 0.0) {
+            return Err(AppError::BadRequest(
+                "ACTION_VALIDATION_FAILED",
+                "actual_power_watts must be a non-negative number".to_string()
+            ));
+        }
+
+        if self.error_message.as_ref().is_some_and(|m| m.chars().count() > MAX_ERROR_MESSAGE_LEN) {
+            return Err(AppError::BadRequest("ACTION_VALIDATION_FAILED", format!(
+                "error_message cannot be longer than {} characters",
+                MAX_ERROR_MESSAGE_LEN
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ScheduleQuery {
+    /// Si és true, les accions que creuen mitjanit es retornen en dos segments
+    #[serde(default)]
+    pub split_midnight: bool,
+}
+
+/// Regla que no ha programat cap hora per un dia
+#[derive(Debug, Serialize, FromRow, ToSchema)]
+pub struct SkippedRuleResponse {
+    pub rule_id: Uuid,
+    pub rule_name: String,
+    pub reason: SkipReason,
+    /// Quan es va generar (i ometre) la regla per última vegada
+    pub recorded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
 pub struct CalculateResponse {
     pub rule_id: Uuid,
     pub date: NaiveDate,
+    /// Franges seleccionades: hores o, si `slot_minutes` és 15, quarts d'hora del dia
     pub optimal_hours: Vec<u8>,
+    /// Durada de cada franja en minuts
+    pub slot_minutes: u32,
     pub total_price: f64,
+    /// Part de `total_price` de cada període del peatge 2.0TD ("P1", "P2", "P3")
+    pub tariff_breakdown: HashMap<String, f64>,
 }
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct PlanWeekRequest {
+    pub rule_id: Uuid,
+    /// Primer dia de la setmana (per defecte, avui)
+    pub start_date: Option<NaiveDate>,
+}
+
+/// Hores planificades d'un dia de la setmana
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PlanWeekDay {
+    pub date: NaiveDate,
+    pub optimal_hours: Vec<u8>,
+    pub total_price: f64,
+    /// Si els preus del dia encara no s'han publicat i són una estimació
+    pub is_forecast: bool,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct PlanWeekResponse {
+    pub rule_id: Uuid,
+    /// Hores de tota la setmana: `max_hours * 7 / dies actius de days_of_week`
+    pub weekly_hours: usize,
+    pub total_price: f64,
+    pub days: Vec<PlanWeekDay>,
+}
+
+/// Paràmetres d'una regla (sense desar) per explicar la selecció d'hores
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct ExplainRequest {
+    /// Data dels preus (per defecte, avui)
+    pub date: Option<NaiveDate>,
+    pub max_hours: i32,
+    pub time_window_start: Option<NaiveTime>,
+    pub time_window_end: Option<NaiveTime>,
+    pub min_continuous_hours: Option<i32>,
+    /// Hores mínimes sense programar entre dues hores saltejades
+    pub min_gap_hours: Option<i32>,
+    /// Límit de l'ampliació de `max_hours` els dies amb preus negatius
+    pub negative_price_boost: Option<i32>,
+    /// Per defecte, `required`
+    pub continuity_preference: Option<ContinuityPreference>,
+    pub action_type: Option<ActionType>,
+    pub blackout_windows: Option<Vec<BlackoutWindow>>,
+    /// Pes relatiu del consum per cada hora del dia (24 valors)
+    pub load_profile: Option<Vec<f64>>,
+    /// Cost màxim (€) de les hores seleccionades; només s'aplica amb `wattage_watts`
+    pub max_cost_eur: Option<f64>,
+    /// Potència del dispositiu (W)
+    pub wattage_watts: Option<f64>,
+}
+
+impl Validate for ExplainRequest {
+    fn validate(&self) -> AppResult<()> {
+        validate_max_hours(self.max_hours)?;
+        validate_min_continuous_hours(self.min_continuous_hours.unwrap_or(1), self.max_hours)?;
+        validate_min_gap_hours(self.min_gap_hours, self.min_continuous_hours.unwrap_or(1))?;
+        validate_negative_price_boost(self.negative_price_boost)?;
+        validate_time_window(self.time_window_start, self.time_window_end, self.min_continuous_hours.unwrap_or(1))?;
+        validate_blackout_windows(self.blackout_windows.as_deref().unwrap_or_default())?;
+        if let Some(weights) = self.load_profile.as_deref() {
+            validate_load_profile(weights)?;
+        }
+        validate_max_cost(self.max_cost_eur)?;
+        if self.wattage_watts.is_some_and(|w| !w.is_finite() || w <= 0.0) {
+            return Err(AppError::BadRequest(
+                "RULE_VALIDATION_FAILED",
+                "wattage_watts must be greater than 0".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ExplainResponse {
+    pub date: NaiveDate,
+    /// Franges seleccionades: hores o, si `slot_minutes` és 15, quarts d'hora del dia
+    pub optimal_hours: Vec<u8>,
+    /// Durada de cada franja en minuts
+    pub slot_minutes: u32,
+    pub total_price: f64,
+    /// Decisió i motiu per cada franja del dia, en ordre cronològic
+    pub hours: Vec<HourDecision>,
+}
+
+#[derive(Debug, Clone, FromRow)]
 struct ScheduledActionRow {
     id: Uuid,
     device_id: Uuid,
     device_name: String,
     google_device_id: String,
+    room: Option<String>,
     start_time: NaiveTime,
     end_time: NaiveTime,
     status: String,
+    action: String,
+    actual_power_watts: Option<f64>,
+    error_message: Option<String>,
+    executed_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, ToSchema)]
 pub struct ScheduleResponse {
     pub id: Uuid,
     pub device_id: Uuid,
     pub device_name: String,
     pub google_device_id: String,
+    /// Habitació del dispositiu, per agrupar les accions a l'app
+    pub room: Option<String>,
     pub start_time: String,
     pub end_time: String,
     pub status: String,
+    /// Acció a executar: "on" o "off"
+    pub action: String,
+    /// Potència mesurada en executar l'acció (W), segons l'app
+    pub actual_power_watts: Option<f64>,
+    /// Error reportat per l'app si l'execució ha fallat
+    pub error_message: Option<String>,
+    /// Hora d'execució (la del client si l'ha enviat, si no la de recepció)
+    pub executed_at: Option<DateTime<Utc>>,
+    /// Número de segment (1 o 2) quan una acció que creua mitjanit s'ha dividit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub segment: Option<u8>,
 }
 
 impl From<ScheduledActionRow> for ScheduleResponse {
@@ -61,316 +294,1521 @@ impl From<ScheduledActionRow> for ScheduleResponse {
             device_id: a.device_id,
             device_name: a.device_name,
             google_device_id: a.google_device_id,
+            room: a.room,
             start_time: a.start_time.to_string(),
             end_time: a.end_time.to_string(),
             status: a.status,
+            action: a.action,
+            actual_power_watts: a.actual_power_watts,
+            error_message: a.error_message,
+            executed_at: a.executed_at,
+            segment: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct MissedQuery {
+    /// Primera data (per defecte, fa 30 dies)
+    pub from: Option<NaiveDate>,
+    /// Última data (per defecte, avui)
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Debug, FromRow)]
+struct MissedActionRow {
+    id: Uuid,
+    rule_id: Uuid,
+    rule_name: String,
+    device_id: Uuid,
+    device_name: String,
+    google_device_id: String,
+    scheduled_date: NaiveDate,
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+    action: String,
+    price_per_kwh: Option<f64>,
+    /// Preu mitjà del dia segons l'historial de preus
+    day_average_price: Option<f64>,
+    /// Potència configurada del dispositiu (`devices.wattage_watts`)
+    wattage_watts: Option<f64>,
+    /// Potència mitjana mesurada en les execucions correctes del dispositiu
+    device_power_watts: Option<f64>,
+}
+
+/// Acció que no s'ha executat a temps, amb el context per diagnosticar-la
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MissedActionResponse {
+    pub id: Uuid,
+    pub rule_id: Uuid,
+    pub rule_name: String,
+    pub device_id: Uuid,
+    pub device_name: String,
+    pub google_device_id: String,
+    pub scheduled_date: NaiveDate,
+    pub start_time: String,
+    pub end_time: String,
+    /// Acció a executar: "on" o "off"
+    pub action: String,
+    /// Preu de l'hora programada (€/kWh)
+    pub price_per_kwh: Option<f64>,
+    /// Estalvi perdut respecte al preu mitjà del dia (€). Absent si no es coneix el
+    /// preu mitjà o la potència del dispositiu.
+    pub estimated_cost_missed_eur: Option<f64>,
+}
+
+impl From<MissedActionRow> for MissedActionResponse {
+    fn from(row: MissedActionRow) -> Self {
+        let estimated_cost_missed_eur = estimate_missed_cost(&row);
+        Self {
+            id: row.id,
+            rule_id: row.rule_id,
+            rule_name: row.rule_name,
+            device_id: row.device_id,
+            device_name: row.device_name,
+            google_device_id: row.google_device_id,
+            scheduled_date: row.scheduled_date,
+            start_time: row.start_time.to_string(),
+            end_time: row.end_time.to_string(),
+            action: row.action,
+            price_per_kwh: row.price_per_kwh,
+            estimated_cost_missed_eur,
+        }
+    }
+}
+
+/// Acció d'encesa d'un dia amb el preu i la potència per calcular-ne el cost
+#[derive(Debug, FromRow)]
+struct ActionCostRow {
+    rule_id: Uuid,
+    rule_name: String,
+    device_id: Uuid,
+    device_name: String,
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+    price_per_kwh: Option<f64>,
+    /// Potència reportada per l'execució de l'acció
+    actual_power_watts: Option<f64>,
+    /// Potència configurada del dispositiu (`devices.wattage_watts`)
+    wattage_watts: Option<f64>,
+    /// Potència mitjana mesurada en les execucions correctes del dispositiu
+    device_power_watts: Option<f64>,
+}
+
+/// Cost estimat d'una regla o d'un dispositiu en un dia
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CostEntry {
+    /// Id de la regla o del dispositiu
+    pub id: Uuid,
+    pub name: String,
+    /// Accions d'encesa comptades
+    pub actions: u32,
+    pub kwh: f64,
+    pub cost_eur: f64,
+    /// Accions sense potència mesurada ni configurada, calculades amb 1 kW
+    pub assumed_power_actions: u32,
+}
+
+/// Cost estimat del pla d'un dia
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DayCostsResponse {
+    pub date: NaiveDate,
+    pub by_rule: Vec<CostEntry>,
+    pub by_device: Vec<CostEntry>,
+    pub total_kwh: f64,
+    pub total_eur: f64,
+}
+
+impl CostEntry {
+    fn new(id: Uuid, name: &str) -> Self {
+        Self { id, name: name.to_string(), actions: 0, kwh: 0.0, cost_eur: 0.0, assumed_power_actions: 0 }
+    }
+
+    fn add(&mut self, kwh: f64, cost_eur: f64, assumed_power: bool) {
+        self.actions += 1;
+        self.kwh += kwh;
+        self.cost_eur += cost_eur;
+        self.assumed_power_actions += u32::from(assumed_power);
+    }
+}
+
+/// Agrega el cost de les accions per regla i per dispositiu
+///
+/// La potència d'una acció és la reportada en executar-la, si no la configurada del dispositiu,
+/// si no la mitjana mesurada del dispositiu i, si no n'hi ha cap, `ASSUMED_POWER_WATTS`. Les accions sense preu
+/// sumen energia però no cost.
+fn day_costs(date: NaiveDate, rows: &[ActionCostRow]) -> DayCostsResponse {
+    let mut by_rule: HashMap<Uuid, CostEntry> = HashMap::new();
+    let mut by_device: HashMap<Uuid, CostEntry> = HashMap::new();
+
+    for row in rows {
+        let measured = action_power_watts(row.actual_power_watts, row.wattage_watts, row.device_power_watts);
+        let kwh = action_kwh(measured, row.start_time, row.end_time);
+        let cost_eur = row.price_per_kwh.map_or(0.0, |price| price * kwh);
+
+        by_rule
+            .entry(row.rule_id)
+            .or_insert_with(|| CostEntry::new(row.rule_id, &row.rule_name))
+            .add(kwh, cost_eur, measured.is_none());
+        by_device
+            .entry(row.device_id)
+            .or_insert_with(|| CostEntry::new(row.device_id, &row.device_name))
+            .add(kwh, cost_eur, measured.is_none());
+    }
+
+    let sorted = |entries: HashMap<Uuid, CostEntry>| {
+        let mut entries: Vec<CostEntry> = entries.into_values().collect();
+        entries.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id)));
+        entries
+    };
+    let by_rule = sorted(by_rule);
+
+    DayCostsResponse {
+        date,
+        total_kwh: by_rule.iter().map(|e| e.kwh).sum(),
+        total_eur: by_rule.iter().map(|e| e.cost_eur).sum(),
+        by_rule,
+        by_device: sorted(by_device),
+    }
+}
+
+/// Potència amb què s'estima el consum d'una acció: la reportada en executar-la, si no la
+/// configurada del dispositiu i, si no, la mitjana mesurada (`device_measured_power`).
+/// None si no se'n coneix cap.
+pub(super) fn action_power_watts(actual: Option<f64>, wattage: Option<f64>, measured: Option<f64>) -> Option<f64> {
+    actual.filter(|w| *w > 0.0).or(wattage).or(measured)
+}
+
+/// Energia d'una acció en kWh; sense cap potència coneguda, amb `ASSUMED_POWER_WATTS`
+pub(super) fn action_kwh(power_watts: Option<f64>, start_time: NaiveTime, end_time: NaiveTime) -> f64 {
+    power_watts.unwrap_or(ASSUMED_POWER_WATTS) / 1000.0 * action_hours(start_time, end_time)
+}
+
+/// Durada d'una acció en hores. Les que creuen mitjanit acaben l'endemà.
+pub(super) fn action_hours(start_time: NaiveTime, end_time: NaiveTime) -> f64 {
+    let mut duration = end_time - start_time;
+    if duration <= chrono::Duration::zero() {
+        duration += chrono::Duration::days(1);
+    }
+    duration.num_seconds() as f64 / 3600.0
+}
+
+/// Cost d'oportunitat d'una acció perduda: l'energia del tram (potència configurada o mesurada
+/// del dispositiu × durada) per la diferència amb el preu mitjà del dia.
+///
+/// Un "on" perdut obliga a consumir a una hora més cara que la triada; un "off" perdut
+/// deixa el dispositiu consumint a una hora que s'havia d'evitar. Mai és negatiu.
+fn estimate_missed_cost(row: &MissedActionRow) -> Option<f64> {
+    let price = row.price_per_kwh?;
+    let average = row.day_average_price?;
+    let power = action_power_watts(None, row.wattage_watts, row.device_power_watts)?;
+    let kwh = action_kwh(Some(power), row.start_time, row.end_time);
+
+    let difference = if row.action == "off" { price - average } else { average - price };
+    Some((difference * kwh).max(0.0))
+}
+
+/// Comprova que una acció es pot tornar a programar: ha de ser missed o failed i
+/// no pot ser d'un dia passat
+fn check_retryable(status: &str, scheduled_date: NaiveDate, today: NaiveDate) -> AppResult<()> {
+    if !RETRYABLE_STATUSES.contains(&status) {
+        return Err(AppError::BadRequest("ACTION_NOT_RETRYABLE", format!(
+            "Only actions with status {:?} can be retried (current: '{}')",
+            RETRYABLE_STATUSES, status
+        )));
+    }
+
+    if scheduled_date < today {
+        return Err(AppError::BadRequest("ACTION_NOT_RETRYABLE", format!(
+            "Actions from past days cannot be retried ({})",
+            scheduled_date
+        )));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, FromRow)]
+struct NextActionRow {
+    #[sqlx(flatten)]
+    action: ScheduledActionRow,
+    starts_at: NaiveDateTime,
+    ends_at: NaiveDateTime,
+}
+
+/// Pròxima acció pendent d'un dispositiu, amb la data i hora absolutes
+#[derive(Debug, Serialize, ToSchema)]
+pub struct NextActionResponse {
+    #[serde(flatten)]
+    pub action: ScheduleResponse,
+    /// `scheduled_date` + `start_time`
+    pub starts_at: NaiveDateTime,
+    /// Final de l'acció (l'endemà si creua mitjanit)
+    pub ends_at: NaiveDateTime,
+}
+
+impl From<NextActionRow> for NextActionResponse {
+    fn from(row: NextActionRow) -> Self {
+        Self {
+            action: row.action.into(),
+            starts_at: row.starts_at,
+            ends_at: row.ends_at,
         }
     }
 }
 
+/// Divideix una acció que creua mitjanit (end_time < start_time) en dos segments:
+/// start_time → 23:59:59 i 00:00 → end_time. La resta d'accions es retornen tal qual.
+///
+/// Si l'acció acaba just a mitjanit (00:00), el segon segment seria buit i s'omet.
+fn split_midnight_crossing(row: ScheduledActionRow) -> Vec<ScheduleResponse> {
+    if row.end_time >= row.start_time {
+        return vec![row.into()];
+    }
+
+    let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+    let end_of_day = NaiveTime::from_hms_opt(23, 59, 59).unwrap();
+    let end_time = row.end_time;
+
+    let mut first = ScheduleResponse::from(ScheduledActionRow {
+        end_time: end_of_day,
+        ..row.clone()
+    });
+
+    if end_time == midnight {
+        return vec![first];
+    }
+
+    first.segment = Some(1);
+    let mut second = ScheduleResponse::from(ScheduledActionRow {
+        start_time: midnight,
+        ..row
+    });
+    second.segment = Some(2);
+
+    vec![first, second]
+}
+
 pub fn configure(cfg: &mut web::ServiceConfig) {
     cfg.service(get_today_schedule)
+        .service(get_next_actions)
+        .service(get_missed_actions)
+        .service(get_schedule_range_ics)
+        .service(stream_schedule)
         .service(get_schedule_by_date)
+        .service(get_day_costs)
+        .service(get_schedule_ics)
+        .service(get_skipped_rules)
         .service(calculate_schedule)
+        .service(explain_schedule)
+        .service(plan_week)
         .service(generate_schedule_now)
-        .service(update_schedule_status);
+        .service(update_schedule_status)
+        .service(retry_scheduled_action);
 }
 
 /// GET /api/schedule/today
+#[utoipa::path(
+    tag = "schedule",
+    params(ScheduleQuery),
+    responses(
+        (status = 200, description = "Accions programades d'avui", body = Vec<ScheduleResponse>),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[get("/schedule/today")]
 async fn get_today_schedule(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
+    query: web::Query<ScheduleQuery>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let today = clock::today(config.timezone);
+
+    let actions =
+        get_schedule_for_user_and_date(pool.get_ref(), user.id, today, query.split_midnight).await?;
+    Ok(HttpResponse::Ok().json(actions))
+}
+
+/// GET /api/schedule/next
+/// Pròxima acció pendent (no acabada) de cada dispositiu de l'usuari
+#[utoipa::path(
+    tag = "schedule",
+    responses(
+        (status = 200, description = "Una acció per dispositiu, ordenades per inici", body = Vec<NextActionResponse>),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/schedule/next")]
+async fn get_next_actions(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
-    let today = chrono::Local::now().date_naive();
+    let now = clock::now(config.timezone).naive_local();
 
-    let actions = get_schedule_for_user_and_date(pool.get_ref(), user.id, today).await?;
+    let actions = get_next_actions_for_user(pool.get_ref(), user.id, now).await?;
     Ok(HttpResponse::Ok().json(actions))
 }
 
+/// GET /api/schedule/missed?from=&to=
+/// Accions que no s'han executat a temps (status missed), de la més recent a la més antiga
+#[utoipa::path(
+    tag = "schedule",
+    params(MissedQuery, PageQuery),
+    responses(
+        (status = 200, description = "Accions perdudes del període", body = Paginated<MissedActionResponse>),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/schedule/missed")]
+async fn get_missed_actions(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    query: web::Query<MissedQuery>,
+    page: web::Query<PageQuery>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let page = page.page()?;
+
+    let to = query.to.unwrap_or_else(|| clock::today(config.timezone));
+    let from = query.from.unwrap_or(to - chrono::Duration::days(DEFAULT_MISSED_DAYS));
+    if from > to {
+        return Err(AppError::BadRequest("INVALID_DATE_RANGE", "from must be before or equal to to".to_string()));
+    }
+
+    let total: i64 = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*) as "count!"
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        WHERE d.user_id = $1 AND sa.status = 'missed' AND sa.scheduled_date BETWEEN $2 AND $3
+        "#,
+        user.id,
+        from,
+        to
+    )
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    let actions = sqlx::query_as!(
+        MissedActionRow,
+        r#"
+        SELECT
+            sa.id, sa.rule_id, r.name as rule_name,
+            d.id as device_id, d.name as device_name, d.google_device_id,
+            sa.scheduled_date, sa.start_time, sa.end_time, sa.action,
+            sa.price_per_kwh::float8 as price_per_kwh,
+            day.average as day_average_price,
+            d.wattage_watts, power.watts as device_power_watts
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        JOIN rules r ON sa.rule_id = r.id
+        LEFT JOIN LATERAL (
+            SELECT AVG(cp.price) as average FROM cached_prices cp WHERE cp.price_date = sa.scheduled_date
+        ) day ON true
+        LEFT JOIN device_measured_power power ON power.device_id = d.id
+        WHERE d.user_id = $1 AND sa.status = 'missed' AND sa.scheduled_date BETWEEN $2 AND $3
+        ORDER BY sa.scheduled_date DESC, sa.start_time DESC, sa.id
+        LIMIT $4 OFFSET $5
+        "#,
+        user.id,
+        from,
+        to,
+        page.limit,
+        page.offset
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let items: Vec<MissedActionResponse> = actions.into_iter().map(Into::into).collect();
+    Ok(HttpResponse::Ok().json(Paginated::new(items, total, page)))
+}
+
 /// GET /api/schedule/{date}
+#[utoipa::path(
+    tag = "schedule",
+    params(("date" = NaiveDate, Path, description = "Data (YYYY-MM-DD)"), ScheduleQuery),
+    responses(
+        (status = 200, description = "Accions programades del dia", body = Vec<ScheduleResponse>),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[get("/schedule/{date}")]
 async fn get_schedule_by_date(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
     path: web::Path<NaiveDate>,
+    query: web::Query<ScheduleQuery>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
     let date = path.into_inner();
 
-    let actions = get_schedule_for_user_and_date(pool.get_ref(), user.id, date).await?;
+    let actions =
+        get_schedule_for_user_and_date(pool.get_ref(), user.id, date, query.split_midnight).await?;
     Ok(HttpResponse::Ok().json(actions))
 }
 
-/// POST /api/schedule/generate
-/// Força la generació de schedules per avui i demà (si els preus estan disponibles)
-#[post("/schedule/generate")]
-async fn generate_schedule_now(
+/// GET /api/schedule/{date}/costs
+/// Cost estimat de les encesses del dia, per regla i per dispositiu
+#[utoipa::path(
+    tag = "schedule",
+    params(("date" = NaiveDate, Path, description = "Data (YYYY-MM-DD)")),
+    responses(
+        (status = 200, description = "Cost estimat del dia", body = DayCostsResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/schedule/{date}/costs")]
+async fn get_day_costs(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
-    pvpc: web::Data<PvpcClient>,
     req: HttpRequest,
+    path: web::Path<NaiveDate>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
-    let today = chrono::Local::now().date_naive();
-    let tomorrow = today + chrono::Duration::days(1);
+    let date = path.into_inner();
 
-    // Obtenir totes les regles actives de l'usuari
-    let rules = sqlx::query_as::<_, Rule>(
+    let rows = sqlx::query_as!(
+        ActionCostRow,
         r#"
-        SELECT r.*
-        FROM rules r
-        JOIN devices d ON r.device_id = d.id
-        WHERE r.is_enabled = true AND d.user_id = $1
-        "#
+        SELECT
+            r.id as rule_id, r.name as rule_name,
+            d.id as device_id, d.name as device_name,
+            sa.start_time, sa.end_time,
+            sa.price_per_kwh::float8 as price_per_kwh,
+            sa.actual_power_watts, d.wattage_watts,
+            power.watts as device_power_watts
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        JOIN rules r ON sa.rule_id = r.id
+        LEFT JOIN device_measured_power power ON power.device_id = d.id
+        WHERE d.user_id = $1 AND sa.scheduled_date = $2 AND sa.action = 'on'
+          AND sa.status NOT IN ('cancelled', $3)
+        "#,
+        user.id,
+        date,
+        SUPERSEDED_STATUS
     )
-    .bind(user.id)
     .fetch_all(pool.get_ref())
     .await?;
 
-    let mut total_created = 0;
-    let mut results = Vec::new();
-
-    // Generar per avui
-    if let Ok(prices_today) = pvpc.get_today_prices().await {
-        let count = generate_schedules_for_rules(&pool, &rules, &prices_today, today).await?;
-        total_created += count;
-        results.push(serde_json::json!({
-            "date": today.to_string(),
-            "count": count
-        }));
-    }
-
-    // Generar per demà (si els preus estan disponibles)
-    if let Ok(prices_tomorrow) = pvpc.get_tomorrow_prices().await {
-        if !prices_tomorrow.prices.is_empty() {
-            let count = generate_schedules_for_rules(&pool, &rules, &prices_tomorrow, tomorrow).await?;
-            total_created += count;
-            results.push(serde_json::json!({
-                "date": tomorrow.to_string(),
-                "count": count
-            }));
-        }
-    }
-
-    Ok(HttpResponse::Ok().json(serde_json::json!({
-        "message": format!("Generats {} schedules en total", total_created),
-        "total_count": total_created,
-        "details": results
-    })))
+    Ok(HttpResponse::Ok().json(day_costs(date, &rows)))
 }
 
-/// Funció auxiliar per generar schedules per una llista de regles i una data
-async fn generate_schedules_for_rules(
-    pool: &PgPool,
-    rules: &[Rule],
-    prices: &shared::DailyPrices,
-    date: NaiveDate,
-) -> AppResult<usize> {
-    let mut created_count = 0;
-
-    for rule in rules {
-        // Comprovar si el dia de la setmana està inclòs
-        let weekday = date.weekday();
-        let day_bit = match weekday {
-            chrono::Weekday::Mon => 1,
-            chrono::Weekday::Tue => 2,
-            chrono::Weekday::Wed => 4,
-            chrono::Weekday::Thu => 8,
-            chrono::Weekday::Fri => 16,
-            chrono::Weekday::Sat => 32,
-            chrono::Weekday::Sun => 64,
-        };
-
-        if (rule.days_of_week & day_bit) == 0 {
-            continue;
-        }
+/// GET /api/schedule/{date}/skipped
+/// Regles de l'usuari que no han programat cap hora pel dia, i per què
+#[utoipa::path(
+    tag = "schedule",
+    params(("date" = NaiveDate, Path, description = "Data (YYYY-MM-DD)")),
+    responses(
+        (status = 200, description = "Regles omeses amb el motiu", body = Vec<SkippedRuleResponse>),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/schedule/{date}/skipped")]
+async fn get_skipped_rules(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<NaiveDate>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
 
-        // Calcular les hores òptimes
-        let optimal = calculate_optimal_hours(
-            &prices.prices,
-            rule.max_hours,
-            rule.min_continuous_hours,
-            rule.time_window_start,
-            rule.time_window_end,
-        );
+    let skipped = sqlx::query_as!(
+        SkippedRuleResponse,
+        r#"
+        SELECT r.id as rule_id, r.name as rule_name, s.reason as "reason: SkipReason", s.created_at as recorded_at
+        FROM skipped_actions s
+        JOIN rules r ON s.rule_id = r.id
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        WHERE COALESCE(d.user_id, g.user_id) = $1 AND s.scheduled_date = $2
+        ORDER BY r.priority, r.name
+        "#,
+        user.id,
+        path.into_inner()
+    )
+    .fetch_all(pool.get_ref())
+    .await?;
 
-        // Crear scheduled_actions per cada hora
-        for hour in &optimal.hours {
-            let start_time = NaiveTime::from_hms_opt(*hour as u32, 0, 0).unwrap();
-            // Per l'hora 23, end_time seria 00:00 que causa problemes de comparació
-            // Usem 23:59:59 per evitar que end_time < start_time
-            let end_time = if *hour == 23 {
-                NaiveTime::from_hms_opt(23, 59, 59).unwrap()
-            } else {
-                NaiveTime::from_hms_opt(*hour as u32 + 1, 0, 0).unwrap()
-            };
-
-            let price = prices.prices.iter()
-                .find(|p| p.hour == *hour)
-                .map(|p| p.price);
-
-            let result = sqlx::query(
-                r#"
-                INSERT INTO scheduled_actions (rule_id, scheduled_date, start_time, end_time, price_per_kwh, status)
-                VALUES ($1, $2, $3, $4, $5, 'pending')
-                ON CONFLICT (rule_id, scheduled_date, start_time) DO NOTHING
-                "#
-            )
-            .bind(rule.id)
-            .bind(date)
-            .bind(start_time)
-            .bind(end_time)
-            .bind(price)
-            .execute(pool)
-            .await?;
+    Ok(HttpResponse::Ok().json(skipped))
+}
 
-            if result.rows_affected() > 0 {
-                created_count += 1;
-            }
-        }
-    }
+/// GET /api/schedule/{date}/ics
+/// Accions programades del dia en format iCalendar, per afegir-les a una aplicació de calendari
+#[utoipa::path(
+    tag = "schedule",
+    params(("date" = NaiveDate, Path, description = "Data (YYYY-MM-DD)")),
+    responses(
+        (status = 200, description = "Calendari amb un esdeveniment per acció", content_type = "text/calendar",
+            body = String),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/schedule/{date}/ics")]
+async fn get_schedule_ics(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    path: web::Path<NaiveDate>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let date = path.into_inner();
 
-    Ok(created_count)
+    let actions = calendar_actions(pool.get_ref(), user.id, date, date).await?;
+    Ok(calendar_response(&actions, &config, &format!("schedule-{}.ics", date)))
 }
 
-/// POST /api/schedule/calculate
-/// Calcula les hores òptimes per una regla sense guardar-les
-#[post("/schedule/calculate")]
-async fn calculate_schedule(
+/// GET /api/schedule/ics?from=&to=
+/// Accions programades d'un rang de dies (com a màxim 31) en format iCalendar
+#[utoipa::path(
+    tag = "schedule",
+    params(RangeQuery),
+    responses(
+        (status = 200, description = "Calendari amb un esdeveniment per acció", content_type = "text/calendar",
+            body = String),
+        (status = 400, description = "Rang invàlid o massa llarg", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/schedule/ics")]
+async fn get_schedule_range_ics(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
-    pvpc: web::Data<PvpcClient>,
     req: HttpRequest,
-    body: web::Json<CalculateRequest>,
+    query: web::Query<RangeQuery>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    validate_range(query.from, query.to)?;
 
-    // Verificar que la regla pertany a l'usuari
-    let rule = sqlx::query_as::<_, Rule>(
+    let actions = calendar_actions(pool.get_ref(), user.id, query.from, query.to).await?;
+    Ok(calendar_response(&actions, &config, &format!("schedule-{}-{}.ics", query.from, query.to)))
+}
+
+/// Accions de l'usuari entre dues dates (incloses) que s'han executat o s'executaran
+async fn calendar_actions(
+    pool: &PgPool,
+    user_id: Uuid,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> AppResult<Vec<CalendarAction>> {
+    let actions = sqlx::query_as!(
+        CalendarAction,
         r#"
-        SELECT r.*
-        FROM rules r
-        JOIN devices d ON r.device_id = d.id
-        WHERE r.id = $1 AND d.user_id = $2
-        "#
+        SELECT sa.id, d.name as device_name, sa.action, sa.scheduled_date, sa.start_time, sa.end_time,
+               sa.price_per_kwh::float8 as price_per_kwh
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        WHERE d.user_id = $1 AND sa.scheduled_date BETWEEN $2 AND $3
+          AND sa.status NOT IN ('cancelled', $4)
+        ORDER BY sa.scheduled_date, sa.start_time, d.name
+        "#,
+        user_id,
+        from,
+        to,
+        SUPERSEDED_STATUS
     )
-    .bind(body.rule_id)
-    .bind(user.id)
-    .fetch_optional(pool.get_ref())
-    .await?
-    .ok_or_else(|| AppError::NotFound("Rule not found".to_string()))?;
+    .fetch_all(pool)
+    .await?;
+
+    Ok(actions)
+}
+
+fn calendar_response(actions: &[CalendarAction], config: &Config, filename: &str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/calendar; charset=utf-8")
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)))
+        .body(schedule_calendar(actions, config.timezone, Utc::now()))
+}
+
+/// GET /api/schedule/stream
+/// Server-sent events amb els canvis d'estat de les accions de l'usuari (avui i demà)
+#[utoipa::path(
+    tag = "schedule",
+    responses(
+        (status = 200, description = "Stream SSE amb les accions que canvien d'estat", content_type = "text/event-stream", body = String),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[get("/schedule/stream")]
+async fn stream_schedule(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    events: web::Data<ScheduleEvents>,
+    req: HttpRequest,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    // Subscriure's abans de la primera consulta per no perdre cap canvi
+    let rx = events.subscribe();
+    let poller = StatusPoller::new(pool.get_ref().clone(), user.id, config.timezone).await?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header((header::CACHE_CONTROL, "no-cache"))
+        .streaming(schedule_event_stream(
+            user.id,
+            rx,
+            Some(poller),
+            STREAM_KEEP_ALIVE_INTERVAL,
+        )))
+}
+
+/// Detecta canvis d'estat consultant la BD periòdicament
+struct StatusPoller {
+    pool: PgPool,
+    user_id: Uuid,
+    /// Zona horària per saber quin dia és avui
+    tz: Tz,
+    /// Últim estat conegut de cada acció
+    known: HashMap<Uuid, String>,
+    interval: Interval,
+}
+
+impl StatusPoller {
+    /// Crea el poller amb l'estat actual com a punt de partida (no s'emet)
+    async fn new(pool: PgPool, user_id: Uuid, tz: Tz) -> AppResult<Self> {
+        let mut poller = Self {
+            pool,
+            user_id,
+            tz,
+            known: HashMap::new(),
+            interval: interval_at(Instant::now() + STREAM_POLL_INTERVAL, STREAM_POLL_INTERVAL),
+        };
+        poller.poll().await?;
+        Ok(poller)
+    }
+
+    /// Retorna les accions d'avui i demà que han canviat des de l'última consulta
+    async fn poll(&mut self) -> AppResult<Vec<ScheduleResponse>> {
+        let today = clock::today(self.tz);
+        let mut changed = Vec::new();
+
+        for date in [today, today + chrono::Duration::days(1)] {
+            let actions = get_schedule_for_user_and_date(&self.pool, self.user_id, date, false).await?;
+            for action in actions {
+                if self.known.get(&action.id) != Some(&action.status) {
+                    self.known.insert(action.id, action.status.clone());
+                    changed.push(action);
+                }
+            }
+        }
+
+        Ok(changed)
+    }
+}
+
+/// Espera el següent interval del poller i retorna els canvis.
+/// Sense poller, no acaba mai (només s'emeten esdeveniments del canal).
+async fn next_poll(poller: &mut Option<StatusPoller>) -> AppResult<Vec<ScheduleResponse>> {
+    match poller {
+        Some(poller) => {
+            poller.interval.tick().await;
+            poller.poll().await
+        }
+        None => std::future::pending().await,
+    }
+}
+
+struct EventStreamState {
+    user_id: Uuid,
+    rx: broadcast::Receiver<ScheduleEvent>,
+    poller: Option<StatusPoller>,
+    keep_alive: Interval,
+    pending: VecDeque<Bytes>,
+}
+
+/// Format SSE d'un esdeveniment amb l'acció completa
+fn sse_data(action: &ScheduleResponse) -> Bytes {
+    let json = serde_json::to_string(action).unwrap_or_default();
+    Bytes::from(format!("data: {}\n\n", json))
+}
+
+/// Stream SSE que combina els canvis publicats al canal (només els de l'usuari),
+/// els detectats pel poller i comentaris keep-alive periòdics
+fn schedule_event_stream(
+    user_id: Uuid,
+    rx: broadcast::Receiver<ScheduleEvent>,
+    poller: Option<StatusPoller>,
+    keep_alive_period: Duration,
+) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let state = EventStreamState {
+        user_id,
+        rx,
+        poller,
+        keep_alive: interval(keep_alive_period),
+        pending: VecDeque::new(),
+    };
+
+    futures_util::stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(chunk) = state.pending.pop_front() {
+                return Some((Ok(chunk), state));
+            }
+
+            tokio::select! {
+                event = state.rx.recv() => match event {
+                    Ok(ScheduleEvent::StatusChanged { user_id, action }) if user_id == state.user_id => {
+                        // Evitar que el poller torni a emetre el mateix canvi
+                        if let Some(poller) = state.poller.as_mut() {
+                            poller.known.insert(action.id, action.status.clone());
+                        }
+                        state.pending.push_back(sse_data(&action));
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Stream SSE endarrerit, {} esdeveniments descartats", skipped);
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+                changed = next_poll(&mut state.poller) => match changed {
+                    Ok(actions) => state.pending.extend(actions.iter().map(sse_data)),
+                    Err(e) => tracing::warn!("Error consultant canvis per l'stream SSE: {}", e),
+                },
+                _ = state.keep_alive.tick() => {
+                    state.pending.push_back(Bytes::from_static(b": keep-alive\n\n"));
+                }
+            }
+        }
+    })
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct GenerateQuery {
+    /// Si és true, només es calcula què es crearia: no es desa res ni es notifica ningú
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// POST /api/schedule/generate
+/// Força la generació de schedules per avui i demà (si els preus estan disponibles)
+///
+/// Cada data indica si s'han creat accions (`generated`), si ja existien (`existing`) o si
+/// cap regla en programa (`empty`). Amb `dry_run`, els comptadors són els que sortirien i
+/// cada data inclou les accions proposades de cada regla (`proposed_actions`).
+///
+/// `conflicts` llista els dispositius amb accions pendents de més d'una regla a la mateixa
+/// hora (regles d'igual prioritat). Amb `dry_run`, els de les accions proposades.
+#[utoipa::path(
+    tag = "schedule",
+    params(GenerateQuery),
+    responses(
+        (status = 200, description = "Resum dels schedules generats", body = serde_json::Value),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/schedule/generate")]
+async fn generate_schedule_now(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+    webhooks: web::Data<WebhookDispatcher>,
+    events: web::Data<ScheduleEvents>,
+    req: HttpRequest,
+    query: web::Query<GenerateQuery>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+    let today = clock::today(config.timezone);
+    let tomorrow = today + chrono::Duration::days(1);
+    let dry_run = query.dry_run;
+
+    // Obtenir totes les regles actives de l'usuari
+    let rules = sqlx::query_as::<_, Rule>(
+        r#"
+        SELECT r.*, lp.weights as load_profile, g.max_simultaneous,
+               ARRAY(SELECT rd.device_id FROM rule_devices rd WHERE rd.rule_id = r.id ORDER BY rd.device_id) as device_ids,
+               rule_wattage(r.device_id, r.device_group_id) as wattage_watts
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE r.is_enabled = true AND COALESCE(d.user_id, g.user_id) = $1
+        "#
+    )
+    .bind(user.id)
+    .fetch_all(pool.get_ref())
+    .await?;
+
+    let mut total_created = 0;
+    let mut results = Vec::new();
+    let mut conflicts = Vec::new();
+    let rule_ids: Vec<Uuid> = rules.iter().map(|r| r.id).collect();
+
+    // Preus d'avui i, si ja són complets, de demà
+    let mut days = Vec::new();
+    if let Ok(prices_today) = pvpc.get_prices_for_date(today).await {
+        days.push((today, prices_today));
+    }
+    if let Ok(prices_tomorrow) = pvpc.get_prices_for_date(tomorrow).await
+        && pvpc.is_complete(&prices_tomorrow)
+    {
+        days.push((tomorrow, prices_tomorrow));
+    }
+
+    for (date, prices) in days {
+        if !dry_run && let Err(e) = store_prices(&pool, &prices).await {
+            tracing::warn!("No s'han pogut desar els preus de {} a l'historial: {}", date, e);
+        }
+        let (count, status, proposals) =
+            generate_schedules_for_user(&pool, user.id, &rules, &prices, date, dry_run).await?;
+        if count > 0 && !dry_run {
+            webhooks.schedule_generated(&pool, Some(user.id), date);
+            events.send(ScheduleEvent::ScheduleGenerated { user_id: Some(user.id), date });
+        }
+        total_created += count;
+
+        let mut result = serde_json::json!({
+            "date": date.to_string(),
+            "count": count,
+            "status": status
+        });
+        if dry_run {
+            conflicts.extend(detect_action_conflicts(proposals.iter().flat_map(|proposal| {
+                proposal.actions.iter().map(move |a| (proposal.rule_id, a.device_id, date, a.start_time))
+            })));
+            result["proposed_actions"] = serde_json::json!(proposals);
+        } else {
+            conflicts.extend(find_conflicts(&mut *pool.acquire().await?, &rule_ids, date).await?);
+        }
+        results.push(result);
+    }
+
+    for conflict in &conflicts {
+        tracing::warn!(
+            "Conflicte de schedules el {} pel dispositiu {}: hores {:?} programades per les regles {:?}",
+            conflict.date,
+            conflict.device_id,
+            conflict.conflicting_hours,
+            conflict.rule_ids
+        );
+    }
+
+    let message = if dry_run {
+        format!("Es generarien {} schedules en total", total_created)
+    } else {
+        format!("Generats {} schedules en total", total_created)
+    };
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "message": message,
+        "total_count": total_created,
+        "dry_run": dry_run,
+        "details": results,
+        "conflicts": conflicts
+    })))
+}
 
-    // Obtenir la data (avui per defecte)
-    let date = body.date.unwrap_or_else(|| chrono::Local::now().date_naive());
+/// Resultat de generar els schedules d'un usuari per una data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum GenerationStatus {
+    /// S'han creat accions noves
+    Generated,
+    /// No calia crear res: les accions ja existien (d'una generació anterior o simultània)
+    Existing,
+    /// Cap regla programa res per aquesta data
+    Empty,
+}
+
+/// Acció que crearia la generació (només en simulació)
+#[derive(Debug, Serialize)]
+struct ProposedAction {
+    device_id: Uuid,
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+    price_per_kwh: Option<f64>,
+}
+
+/// Resultat simulat de la generació d'una regla per una data
+#[derive(Debug, Serialize)]
+struct RuleProposal {
+    rule_id: Uuid,
+    rule_name: String,
+    /// Per què la regla no programaria res, si és el cas
+    skip_reason: Option<SkipReason>,
+    actions: Vec<ProposedAction>,
+}
+
+/// Genera els schedules de les regles d'un usuari per una data, amb el bloqueig de generació
+///
+/// Si hi ha una altra generació en curs pel mateix usuari i data, s'espera que acabi i
+/// no es duplica res: les accions que ha creat fan que el resultat sigui `Existing`.
+/// Amb `dry_run` no es desa res (ni cal el bloqueig) i es retornen les accions que es crearien.
+async fn generate_schedules_for_user(
+    pool: &PgPool,
+    user_id: Uuid,
+    rules: &[Rule],
+    prices: &shared::DailyPrices,
+    date: NaiveDate,
+    dry_run: bool,
+) -> AppResult<(usize, GenerationStatus, Vec<RuleProposal>)> {
+    let mut lock = pool.begin().await?;
+    if !dry_run {
+        lock_schedule_generation(&mut lock, user_id, date).await?;
+    }
+
+    let (count, proposals) = generate_schedules_for_rules(&mut lock, rules, prices, date, dry_run).await?;
+    let status = if count > 0 {
+        GenerationStatus::Generated
+    } else {
+        let rule_ids: Vec<Uuid> = rules.iter().map(|r| r.id).collect();
+        let existing: bool = sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS(SELECT 1 FROM scheduled_actions WHERE rule_id = ANY($1) AND scheduled_date = $2) as "exists!"
+            "#,
+            &rule_ids,
+            date
+        )
+        .fetch_one(&mut *lock)
+        .await?;
+
+        if existing { GenerationStatus::Existing } else { GenerationStatus::Empty }
+    };
+
+    lock.commit().await?;
+    Ok((count, status, proposals))
+}
+
+/// Funció auxiliar per generar schedules per una llista de regles i una data
+///
+/// Amb `dry_run` no es desa res: retorna quantes accions es crearien i quines, per regla.
+async fn generate_schedules_for_rules(
+    conn: &mut PgConnection,
+    rules: &[Rule],
+    prices: &shared::DailyPrices,
+    date: NaiveDate,
+    dry_run: bool,
+) -> AppResult<(usize, Vec<RuleProposal>)> {
+    let mut created_count = 0;
+    let mut proposals = Vec::new();
+    // En simulació, les franges que ja s'han proposat: (dispositiu, inici, prioritat de la regla)
+    let mut claimed: Vec<(Uuid, NaiveTime, i32)> = Vec::new();
+
+    // Primer les de més prioritat: les altres no arriben a crear accions en franges ja ocupades
+    let mut rules: Vec<&Rule> = rules.iter().collect();
+    rules.sort_by_key(|r| r.priority);
+
+    for rule in rules {
+        // Calcular les hores òptimes (quarts d'hora si els preus són quart-horaris) si el dia
+        // de la setmana està inclòs i és dins la temporada
+        let slots = PriceSlots::from_day(prices);
+        let (skip_reason, optimal) = match SkipReason::for_day(rule, date) {
+            Some(reason) => (Some(reason), None),
+            None => {
+                let optimal = optimal_hours_for_rule(&slots, rule);
+                if optimal.hours.is_empty() {
+                    (Some(SkipReason::NoEligibleHours), None)
+                } else if !passes_baseline_gate(&mut *conn, rule, date, &optimal).await? {
+                    // Porta "només si és més barat que els darrers dies"
+                    (Some(SkipReason::AboveBaseline), None)
+                } else {
+                    (None, Some(optimal))
+                }
+            }
+        };
+        if !dry_run {
+            record_skip(&mut *conn, rule.id, date, skip_reason).await?;
+        }
+        let plans = optimal.map_or_else(Vec::new, |optimal| stagger_group_hours(&slots, rule, optimal.hours));
+        let mut actions = Vec::new();
+
+        // Crear scheduled_actions per cada hora (per tandes si el grup té un màxim de dispositius alhora)
+        for plan in plans {
+            for hour in &plan.hours {
+                let start_time = slots.start_time(*hour);
+                // L'última franja acaba a les 00:00 de l'endemà
+                let (end_time, crosses_midnight) = slots.end_time(*hour);
+
+                let price = slots.price(*hour);
+
+                // Una acció per cada dispositiu del pla (més d'una si és d'un grup)
+                let devices = plan.device_ids.as_deref();
+                if !dry_run {
+                    let end = (end_time, crosses_midnight);
+                    created_count +=
+                        insert_rule_actions(&mut *conn, rule, devices, date, start_time, end, price).await?;
+                    continue;
+                }
+
+                let mut device_ids = proposed_rule_action_devices(&mut *conn, rule, devices, date, start_time).await?;
+                // Les regles de més prioritat d'aquesta mateixa simulació no han desat res
+                device_ids.retain(|device_id| {
+                    !claimed.iter().any(|(d, s, p)| d == device_id && *s == start_time && *p < rule.priority)
+                });
+                created_count += device_ids.len();
+                for device_id in device_ids {
+                    claimed.push((device_id, start_time, rule.priority));
+                    actions.push(ProposedAction { device_id, start_time, end_time, price_per_kwh: price });
+                }
+            }
+        }
+
+        if dry_run {
+            proposals.push(RuleProposal { rule_id: rule.id, rule_name: rule.name.clone(), skip_reason, actions });
+        }
+    }
+
+    if !dry_run {
+        metrics::record_schedules_generated(created_count);
+    }
+
+    Ok((created_count, proposals))
+}
+
+/// POST /api/schedule/calculate
+/// Calcula les hores òptimes per una regla sense guardar-les
+#[utoipa::path(
+    tag = "schedule",
+    request_body = CalculateRequest,
+    responses(
+        (status = 200, description = "Hores òptimes calculades", body = CalculateResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+        (status = 422, description = "Preus no disponibles per la data", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/schedule/calculate")]
+async fn calculate_schedule(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+    req: HttpRequest,
+    body: web::Json<CalculateRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+
+    // Verificar que la regla pertany a l'usuari
+    let rule = get_rule_for_calculation(pool.get_ref(), body.rule_id, user.id).await?;
+
+    // Obtenir la data (avui per defecte) dins l'horitzó de preus disponibles
+    let today = clock::today(config.timezone);
+    let date = resolve_calculate_date(
+        body.date.unwrap_or(today),
+        today,
+        config.price_horizon_days,
+        config.price_history_days,
+    )?;
 
     // Obtenir els preus
     let prices = pvpc.get_prices_for_date(date).await?;
+    if prices.prices.is_empty() {
+        return Err(AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!(
+            "No hi ha preus disponibles per {}",
+            date
+        )));
+    }
 
     // Calcular les hores òptimes
-    let optimal = calculate_optimal_hours(
-        &prices.prices,
-        rule.max_hours,
-        rule.min_continuous_hours,
-        rule.time_window_start,
-        rule.time_window_end,
-    );
+    let slots = PriceSlots::from_day(&prices);
+    let optimal = optimal_hours_for_rule(&slots, &rule);
+    let zone = user_geo_zone(pool.get_ref(), user.id, config.geo_zone).await?;
+    let tariff_breakdown = cost_by_period(&slots, &optimal.hours, date, zone);
 
     Ok(HttpResponse::Ok().json(CalculateResponse {
         rule_id: rule.id,
         date,
         optimal_hours: optimal.hours,
+        slot_minutes: slots.slot_minutes,
         total_price: optimal.total_price,
+        tariff_breakdown,
     }))
 }
 
+/// POST /api/schedule/plan-week
+/// Planifica una setmana sencera d'una regla sense guardar-la: les hores de tota la setmana
+/// es reparteixen entre els dies més barats (vegeu `calculate_weekly_optimal`)
+#[utoipa::path(
+    tag = "schedule",
+    request_body = PlanWeekRequest,
+    responses(
+        (status = 200, description = "Hores de cada dia de la setmana", body = PlanWeekResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Regla no trobat", body = ErrorResponse),
+        (status = 422, description = "Preus no disponibles ni estimables", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/schedule/plan-week")]
+async fn plan_week(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+    req: HttpRequest,
+    body: web::Json<PlanWeekRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let adjustment = PriceAdjustment::for_user(&user);
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+    let rule = get_rule_for_calculation(pool.get_ref(), body.rule_id, user.id).await?;
+
+    let today = clock::today(config.timezone);
+    let start = resolve_calculate_date(
+        body.start_date.unwrap_or(today),
+        today,
+        config.price_horizon_days,
+        config.price_history_days,
+    )?;
+    let horizon = today + chrono::Duration::days(config.price_horizon_days);
+    let week = week_prices(pool.get_ref(), &pvpc, adjustment, start, horizon).await?;
+
+    let prices: Vec<(NaiveDate, &[HourlyPrice])> =
+        week.iter().map(|(day, _)| (day.date, day.prices.as_slice())).collect();
+    let mut plan = calculate_weekly_optimal(&prices, &rule);
+    let days: Vec<PlanWeekDay> = week
+        .iter()
+        .map(|(day, is_forecast)| {
+            let optimal = plan.remove(&day.date);
+            PlanWeekDay {
+                date: day.date,
+                optimal_hours: optimal.as_ref().map(|o| o.hours.clone()).unwrap_or_default(),
+                total_price: optimal.map_or(0.0, |o| o.total_price),
+                is_forecast: *is_forecast,
+            }
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok().json(PlanWeekResponse {
+        rule_id: rule.id,
+        weekly_hours: weekly_hours(&rule),
+        total_price: days.iter().map(|d| d.total_price).sum(),
+        days,
+    }))
+}
+
+/// Preus dels `PLAN_WEEK_DAYS` dies des de `start` i si són estimats
+///
+/// Fins a `horizon` es fan servir els preus publicats; els dies sense preus s'estimen amb
+/// els `FORECAST_HISTORY_DAYS` dies anteriors (inclosos els ja estimats). Les estimacions
+/// es fan amb preus sense impostos i després s'hi aplica `adjustment`, com als publicats.
+async fn week_prices(
+    pool: &PgPool,
+    pvpc: &PvpcClient,
+    adjustment: PriceAdjustment,
+    start: NaiveDate,
+    horizon: NaiveDate,
+) -> AppResult<Vec<(DailyPrices, bool)>> {
+    let history_start = start - chrono::Duration::days(FORECAST_HISTORY_DAYS);
+    let mut history = get_prices_between(pool, history_start, start - chrono::Duration::days(1)).await?;
+
+    let mut week = Vec::new();
+    for date in start.iter_days().take(PLAN_WEEK_DAYS) {
+        if date <= horizon {
+            let published = pvpc.get_prices_for_date(date).await?;
+            if !published.prices.is_empty() {
+                history.push(DailyPrices {
+                    prices: published
+                        .prices
+                        .iter()
+                        .map(|p| HourlyPrice { price: p.raw_price.unwrap_or(p.price), raw_price: None, ..*p })
+                        .collect(),
+                    quarter_hours: vec![],
+                    date,
+                });
+                week.push((published, false));
+                continue;
+            }
+        }
+
+        let recent = &history[history.len().saturating_sub(FORECAST_HISTORY_DAYS as usize)..];
+        let estimate = DailyPrices { date, ..estimate_tomorrow_prices(recent) };
+        if estimate.prices.is_empty() {
+            return Err(AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!(
+                "No hi ha preus ni historial per estimar els preus de {}",
+                date
+            )));
+        }
+        week.push((adjustment.apply(estimate.clone()), true));
+        history.push(estimate);
+    }
+
+    Ok(week)
+}
+
+/// Regla de l'usuari amb el perfil de consum i la potència dels seus dispositius, per calcular-ne les hores
+async fn get_rule_for_calculation(pool: &PgPool, rule_id: Uuid, user_id: Uuid) -> AppResult<Rule> {
+    sqlx::query_as::<_, Rule>(
+        r#"
+        SELECT r.*, lp.weights as load_profile,
+               rule_wattage(r.device_id, r.device_group_id) as wattage_watts
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE r.id = $1 AND COALESCE(d.user_id, g.user_id) = $2
+        "#
+    )
+    .bind(rule_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?
+    .ok_or_else(|| AppError::NotFound("RULE_NOT_FOUND", "Rule not found".to_string()))
+}
+
+/// POST /api/schedule/explain
+/// Explica, hora a hora, per què unes hores se seleccionarien i d'altres no
+#[utoipa::path(
+    tag = "schedule",
+    request_body = ExplainRequest,
+    responses(
+        (status = 200, description = "Motiu de selecció de cada hora", body = ExplainResponse),
+        (status = 400, description = "Paràmetres de la regla invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 422, description = "Preus no disponibles per la data", body = ErrorResponse),
+        (status = 502, description = "Error de l'API de ESIOS", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/schedule/explain")]
+async fn explain_schedule(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    pvpc: web::Data<PvpcClient>,
+    req: HttpRequest,
+    body: Validated<ExplainRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let pvpc = pvpc_client_for_user(&pvpc, &config, &user);
+
+    let today = clock::today(config.timezone);
+    let date = resolve_calculate_date(
+        body.date.unwrap_or(today),
+        today,
+        config.price_horizon_days,
+        config.price_history_days,
+    )?;
+
+    let prices = pvpc.get_prices_for_date(date).await?;
+    if prices.prices.is_empty() {
+        return Err(AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!(
+            "No hi ha preus disponibles per {}",
+            date
+        )));
+    }
+
+    let slots = PriceSlots::from_day(&prices);
+    let params = SchedulerParams {
+        action_type: body.action_type.unwrap_or_default(),
+        max_hours: body.max_hours,
+        min_continuous_hours: body.min_continuous_hours.unwrap_or(1),
+        min_gap_hours: body.min_gap_hours,
+        negative_price_boost: body.negative_price_boost.filter(|boost| *boost > 0),
+        continuity: body.continuity_preference.unwrap_or_default(),
+        time_window_start: body.time_window_start,
+        time_window_end: body.time_window_end,
+        load_profile: body.load_profile.as_deref(),
+        max_cost_eur: body.max_cost_eur,
+        wattage_watts: body.wattage_watts,
+        slot_minutes: slots.slot_minutes,
+    };
+    let (optimal, hours) =
+        explain_optimal_hours(&slots.prices, &params, body.blackout_windows.as_deref().unwrap_or_default());
+
+    Ok(HttpResponse::Ok().json(ExplainResponse {
+        date,
+        optimal_hours: optimal.hours,
+        slot_minutes: slots.slot_minutes,
+        total_price: optimal.total_price,
+        hours,
+    }))
+}
+
+/// Valida la data d'un càlcul: rebutja dates posteriors a l'horitzó de preus
+/// i limita les massa antigues al límit de l'historial
+pub(super) fn resolve_calculate_date(
+    requested: NaiveDate,
+    today: NaiveDate,
+    horizon_days: i64,
+    history_days: i64,
+) -> AppResult<NaiveDate> {
+    let horizon = today + chrono::Duration::days(horizon_days);
+    if requested > horizon {
+        return Err(AppError::PricesUnavailable("PRICES_UNAVAILABLE", format!(
+            "Els preus per {} encara no estan disponibles (màxim {})",
+            requested, horizon
+        )));
+    }
+
+    let oldest = today - chrono::Duration::days(history_days);
+    Ok(requested.max(oldest))
+}
+
 async fn get_schedule_for_user_and_date(
     pool: &PgPool,
     user_id: Uuid,
     date: NaiveDate,
+    split_midnight: bool,
 ) -> AppResult<Vec<ScheduleResponse>> {
-    let actions = sqlx::query_as::<_, ScheduledActionRow>(
+    let actions = sqlx::query_as!(
+        ScheduledActionRow,
         r#"
         SELECT
-            sa.id, sa.start_time, sa.end_time, sa.status,
-            d.id as device_id, d.name as device_name, d.google_device_id
+            sa.id, sa.start_time, sa.end_time, sa.status, sa.action,
+            sa.actual_power_watts, sa.error_message, COALESCE(sa.client_executed_at, sa.executed_at) as executed_at,
+            d.id as device_id, d.name as device_name, d.google_device_id, d.room
         FROM scheduled_actions sa
-        JOIN rules r ON sa.rule_id = r.id
-        JOIN devices d ON r.device_id = d.id
+        JOIN devices d ON sa.device_id = d.id
         WHERE d.user_id = $1 AND sa.scheduled_date = $2
         ORDER BY sa.start_time
+        "#,
+        user_id,
+        date
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let response: Vec<ScheduleResponse> = if split_midnight {
+        actions.into_iter().flat_map(split_midnight_crossing).collect()
+    } else {
+        actions.into_iter().map(Into::into).collect()
+    };
+    Ok(response)
+}
+
+/// Primera acció pendent de cada dispositiu que encara no ha acabat a `now`
+///
+/// Les accions que creuen mitjanit (end_time <= start_time) acaben l'endemà.
+async fn get_next_actions_for_user(
+    pool: &PgPool,
+    user_id: Uuid,
+    now: NaiveDateTime,
+) -> AppResult<Vec<NextActionResponse>> {
+    let actions = sqlx::query_as::<_, NextActionRow>(
+        r#"
+        SELECT * FROM (
+            SELECT DISTINCT ON (d.id)
+                sa.id, sa.start_time, sa.end_time, sa.status, sa.action,
+                sa.actual_power_watts, sa.error_message, COALESCE(sa.client_executed_at, sa.executed_at) as executed_at,
+                d.id as device_id, d.name as device_name, d.google_device_id, d.room,
+                sa.scheduled_date + sa.start_time as starts_at,
+                sa.scheduled_date + sa.end_time
+                    + CASE WHEN sa.end_time <= sa.start_time THEN INTERVAL '1 day' ELSE INTERVAL '0' END as ends_at
+            FROM scheduled_actions sa
+            JOIN devices d ON sa.device_id = d.id
+            WHERE d.user_id = $1
+              AND d.deleted_at IS NULL
+              AND sa.status = 'pending'
+              AND sa.scheduled_date + sa.end_time
+                  + CASE WHEN sa.end_time <= sa.start_time THEN INTERVAL '1 day' ELSE INTERVAL '0' END > $2
+            ORDER BY d.id, starts_at
+        ) next
+        ORDER BY starts_at
         "#
     )
     .bind(user_id)
-    .bind(date)
+    .bind(now)
     .fetch_all(pool)
     .await?;
 
-    let response: Vec<ScheduleResponse> = actions.into_iter().map(Into::into).collect();
-    Ok(response)
+    Ok(actions.into_iter().map(Into::into).collect())
+}
+
+async fn get_scheduled_action_for_user(
+    pool: &PgPool,
+    user_id: Uuid,
+    action_id: Uuid,
+) -> AppResult<Option<ScheduleResponse>> {
+    let action = sqlx::query_as!(
+        ScheduledActionRow,
+        r#"
+        SELECT
+            sa.id, sa.start_time, sa.end_time, sa.status, sa.action,
+            sa.actual_power_watts, sa.error_message, COALESCE(sa.client_executed_at, sa.executed_at) as executed_at,
+            d.id as device_id, d.name as device_name, d.google_device_id, d.room
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        WHERE d.user_id = $1 AND sa.id = $2
+        "#,
+        user_id,
+        action_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(action.map(Into::into))
 }
 
 /// PATCH /api/schedule/{id}/status
 /// Actualitza l'estat d'una acció programada (executed, failed, cancelled)
+#[utoipa::path(
+    tag = "schedule",
+    params(("id" = Uuid, Path, description = "Id de l'acció programada")),
+    request_body = UpdateStatusRequest,
+    responses(
+        (status = 200, description = "Estat actualitzat", body = serde_json::Value),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Acció no trobat", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
 #[patch("/schedule/{id}/status")]
 async fn update_schedule_status(
     pool: web::Data<PgPool>,
     config: web::Data<Config>,
     req: HttpRequest,
+    events: web::Data<ScheduleEvents>,
+    webhooks: web::Data<WebhookDispatcher>,
     path: web::Path<Uuid>,
-    body: web::Json<UpdateStatusRequest>,
+    body: Validated<UpdateStatusRequest>,
 ) -> AppResult<HttpResponse> {
     let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
     let schedule_id = path.into_inner();
 
-    // Validar que l'status és vàlid
-    // - pending: acció programada pendent d'executar
-    // - executed: executat genèric (legacy)
-    // - executed_on: dispositiu encès correctament
-    // - executed_off: dispositiu apagat correctament
-    // - failed: error en l'execució
-    // - cancelled: cancel·lat manualment
-    // - missed: l'hora va passar sense executar-se
-    let valid_statuses = [
-        "pending",
-        "executed",
-        "executed_on",
-        "executed_off",
-        "failed",
-        "cancelled",
-        "missed",
-    ];
-    if !valid_statuses.contains(&body.status.as_str()) {
-        return Err(AppError::BadRequest(format!(
-            "Invalid status '{}'. Valid values: {:?}",
-            body.status, valid_statuses
-        )));
-    }
-
     // Verificar que l'acció pertany a l'usuari
     // Actualitzar executed_at per qualsevol estat d'execució (executed, executed_on, executed_off)
+    // Els detalls de l'execució es reemplacen a cada informe perquè no quedin errors d'un intent anterior
     let is_executed = body.status.starts_with("executed");
-    let result = sqlx::query(
+    let result = sqlx::query!(
         r#"
         UPDATE scheduled_actions sa
-        SET status = $1, executed_at = CASE WHEN $4 THEN NOW() ELSE executed_at END
-        FROM rules r
-        JOIN devices d ON r.device_id = d.id
-        WHERE sa.id = $2 AND sa.rule_id = r.id AND d.user_id = $3
-        "#
+        SET status = $1, executed_at = CASE WHEN $4 THEN NOW() ELSE executed_at END,
+            actual_power_watts = $5, error_message = $6, client_executed_at = $7
+        FROM devices d
+        WHERE sa.id = $2 AND sa.device_id = d.id AND d.user_id = $3
+        "#,
+        body.status,
+        schedule_id,
+        user.id,
+        is_executed,
+        body.actual_power_watts,
+        body.error_message,
+        body.executed_at
     )
-    .bind(&body.status)
-    .bind(schedule_id)
-    .bind(user.id)
-    .bind(is_executed)
     .execute(pool.get_ref())
     .await?;
 
     if result.rows_affected() == 0 {
-        return Err(AppError::NotFound("Scheduled action not found".to_string()));
+        return Err(AppError::NotFound("ACTION_NOT_FOUND", "Scheduled action not found".to_string()));
+    }
+
+    // Notificar els clients connectats a l'stream i els webhooks
+    if let Some(action) = get_scheduled_action_for_user(pool.get_ref(), user.id, schedule_id).await? {
+        webhooks.status_changed(pool.get_ref(), user.id, &action);
+        events.publish(user.id, action);
     }
 
     Ok(HttpResponse::Ok().json(serde_json::json!({
@@ -380,3 +1818,895 @@ async fn update_schedule_status(
     })))
 }
 
+/// POST /api/schedule/{id}/retry
+/// Torna a posar a pending una acció missed o failed d'avui o d'un dia futur
+#[utoipa::path(
+    tag = "schedule",
+    params(("id" = Uuid, Path, description = "Id de l'acció programada")),
+    responses(
+        (status = 200, description = "Acció tornada a pending", body = ScheduleResponse),
+        (status = 400, description = "L'acció no es pot tornar a intentar", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+        (status = 404, description = "Acció no trobada", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/schedule/{id}/retry")]
+async fn retry_scheduled_action(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    events: web::Data<ScheduleEvents>,
+    webhooks: web::Data<WebhookDispatcher>,
+    path: web::Path<Uuid>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+    let schedule_id = path.into_inner();
+
+    let (status, scheduled_date) = sqlx::query!(
+        r#"
+        SELECT sa.status, sa.scheduled_date
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        WHERE sa.id = $1 AND d.user_id = $2
+        "#,
+        schedule_id,
+        user.id
+    )
+    .fetch_optional(pool.get_ref())
+    .await?
+    .map(|row| (row.status, row.scheduled_date))
+    .ok_or_else(|| AppError::NotFound("ACTION_NOT_FOUND", "Scheduled action not found".to_string()))?;
+
+    let today = clock::today(config.timezone);
+    check_retryable(&status, scheduled_date, today)?;
+
+    // L'estat es torna a comprovar a l'UPDATE per si l'app l'ha canviat mentrestant.
+    // Els detalls de l'intent anterior s'esborren.
+    let result = sqlx::query!(
+        r#"
+        UPDATE scheduled_actions
+        SET status = 'pending', executed_at = NULL, actual_power_watts = NULL,
+            error_message = NULL, client_executed_at = NULL
+        WHERE id = $1 AND status = ANY($2)
+        "#,
+        schedule_id,
+        &RETRYABLE_STATUSES.map(String::from)[..]
+    )
+    .execute(pool.get_ref())
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(AppError::BadRequest(
+            "ACTION_STATUS_CHANGED",
+            "Scheduled action status changed, try again".to_string(),
+        ));
+    }
+
+    tracing::info!("Acció {} ({}) tornada a pending per l'usuari {}", schedule_id, status, user.id);
+
+    let action = get_scheduled_action_for_user(pool.get_ref(), user.id, schedule_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("ACTION_NOT_FOUND", "Scheduled action not found".to_string()))?;
+    webhooks.status_changed(pool.get_ref(), user.id, &action);
+    events.publish(user.id, action.clone());
+
+    Ok(HttpResponse::Ok().json(action))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(start: (u32, u32), end: (u32, u32)) -> ScheduledActionRow {
+        ScheduledActionRow {
+            id: Uuid::new_v4(),
+            device_id: Uuid::new_v4(),
+            device_name: "Termo".to_string(),
+            google_device_id: "google-1".to_string(),
+            room: Some("Bany".to_string()),
+            start_time: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+            status: "pending".to_string(),
+            action: "on".to_string(),
+            actual_power_watts: None,
+            error_message: None,
+            executed_at: None,
+        }
+    }
+
+    /// Durada d'un segment, comptant 23:59:59 com a final del dia (mitjanit)
+    fn segment_duration(segment: &ScheduleResponse) -> chrono::Duration {
+        let start: NaiveTime = segment.start_time.parse().unwrap();
+        let end: NaiveTime = segment.end_time.parse().unwrap();
+        let duration = end - start;
+        if segment.end_time == "23:59:59" {
+            duration + chrono::Duration::seconds(1)
+        } else {
+            duration
+        }
+    }
+
+    #[test]
+    fn test_split_midnight_crossing_action() {
+        let segments = split_midnight_crossing(action((23, 0), (1, 0)));
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].start_time, "23:00:00");
+        assert_eq!(segments[0].end_time, "23:59:59");
+        assert_eq!(segments[0].segment, Some(1));
+        assert_eq!(segments[1].start_time, "00:00:00");
+        assert_eq!(segments[1].end_time, "01:00:00");
+        assert_eq!(segments[1].segment, Some(2));
+        assert_eq!(segments[0].id, segments[1].id);
+
+        let total: chrono::Duration = segments.iter().map(segment_duration).sum();
+        assert_eq!(total, chrono::Duration::hours(2));
+    }
+
+    #[test]
+    fn test_split_action_ending_at_midnight() {
+        let segments = split_midnight_crossing(action((23, 0), (0, 0)));
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].end_time, "23:59:59");
+        assert_eq!(segments[0].segment, None);
+        assert_eq!(segment_duration(&segments[0]), chrono::Duration::hours(1));
+    }
+
+    #[actix_web::test]
+    async fn test_event_stream_over_http() {
+        use actix_web::{App, HttpServer};
+
+        let events = ScheduleEvents::new();
+        let user_id = Uuid::new_v4();
+
+        let server_events = events.clone();
+        let server = HttpServer::new(move || {
+            let events = server_events.clone();
+            App::new().route(
+                "/stream",
+                web::get().to(move || {
+                    let rx = events.subscribe();
+                    async move {
+                        HttpResponse::Ok()
+                            .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+                            .streaming(schedule_event_stream(user_id, rx, None, Duration::from_secs(3600)))
+                    }
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let mut response = reqwest::Client::new()
+            .get(format!("http://{}/stream", addr))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+        let timeout = Duration::from_secs(5);
+        let keep_alive = tokio::time::timeout(timeout, response.chunk()).await.unwrap().unwrap().unwrap();
+        assert_eq!(&keep_alive[..], b": keep-alive\n\n");
+
+        // Els canvis d'altres usuaris no s'han de rebre
+        events.publish(Uuid::new_v4(), action((8, 0), (9, 0)).into());
+        let expected: ScheduleResponse = action((10, 0), (11, 0)).into();
+        events.publish(user_id, expected.clone());
+
+        let chunk = tokio::time::timeout(timeout, response.chunk()).await.unwrap().unwrap().unwrap();
+        let text = std::str::from_utf8(&chunk).unwrap();
+        let json = text.strip_prefix("data: ").unwrap().trim_end();
+        let received: serde_json::Value = serde_json::from_str(json).unwrap();
+        assert_eq!(received["id"], expected.id.to_string());
+        assert_eq!(received["start_time"], "10:00:00");
+        assert_eq!(received["status"], "pending");
+
+        handle.stop(false).await;
+    }
+
+    #[test]
+    fn test_resolve_calculate_date_in_range() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        assert_eq!(resolve_calculate_date(today, today, 1, 30).unwrap(), today);
+        let tomorrow = today + chrono::Duration::days(1);
+        assert_eq!(resolve_calculate_date(tomorrow, today, 1, 30).unwrap(), tomorrow);
+    }
+
+    #[test]
+    fn test_resolve_calculate_date_beyond_horizon() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        let future = today + chrono::Duration::days(2);
+        assert!(matches!(
+            resolve_calculate_date(future, today, 1, 30),
+            Err(AppError::PricesUnavailable(..))
+        ));
+        let far_future = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        assert!(matches!(
+            resolve_calculate_date(far_future, today, 1, 30),
+            Err(AppError::PricesUnavailable(..))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_calculate_date_clamps_past() {
+        let today = NaiveDate::from_ymd_opt(2024, 6, 10).unwrap();
+        let old = NaiveDate::from_ymd_opt(2020, 1, 1).unwrap();
+        assert_eq!(
+            resolve_calculate_date(old, today, 1, 30).unwrap(),
+            today - chrono::Duration::days(30)
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_schedule_includes_device_room(pool: PgPool) {
+        use crate::test_utils::{create_device, create_rule, create_user};
+
+        let user = create_user(&pool, "rooms").await;
+        let rentaplats = create_device(&pool, user.id, "Rentaplats").await;
+        let termo = create_device(&pool, user.id, "Termo").await;
+        sqlx::query("UPDATE devices SET room = 'Cuina' WHERE id = $1")
+            .bind(rentaplats.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let rentaplats_rule = create_rule(&pool, rentaplats.id, "Rentaplats", true).await;
+        let termo_rule = create_rule(&pool, termo.id, "Termo", true).await;
+
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, status)
+            VALUES ($1, $3, '2030-01-01', '03:00', '04:00', 'pending'),
+                   ($2, $4, '2030-01-01', '05:00', '06:00', 'pending')
+            "#
+        )
+        .bind(rentaplats_rule.id)
+        .bind(termo_rule.id)
+        .bind(rentaplats.id)
+        .bind(termo.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let schedule = get_schedule_for_user_and_date(&pool, user.id, date, false).await.unwrap();
+        let rooms: Vec<(&str, Option<&str>)> =
+            schedule.iter().map(|s| (s.device_name.as_str(), s.room.as_deref())).collect();
+        assert_eq!(rooms, vec![("Rentaplats", Some("Cuina")), ("Termo", None)]);
+
+        let action = get_scheduled_action_for_user(&pool, user.id, schedule[0].id).await.unwrap().unwrap();
+        assert_eq!(action.room.as_deref(), Some("Cuina"));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_next_actions_one_per_device(pool: PgPool) {
+        use crate::test_utils::{create_device, create_rule, create_user};
+
+        let user = create_user(&pool, "next").await;
+        let other = create_user(&pool, "other").await;
+        let termo = create_device(&pool, user.id, "Termo").await;
+        let bomba = create_device(&pool, user.id, "Bomba").await;
+        let aliena = create_device(&pool, other.id, "Aliena").await;
+        let termo_rule = create_rule(&pool, termo.id, "Termo nit", true).await;
+        let bomba_rule = create_rule(&pool, bomba.id, "Bomba", true).await;
+        let aliena_rule = create_rule(&pool, aliena.id, "Aliena", true).await;
+
+        let ids: Vec<Uuid> = sqlx::query_scalar(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, status)
+            VALUES ($1, $4, '2030-01-01', '20:00', '21:00', 'pending'),   -- ja acabada
+                   ($1, $4, '2030-01-01', '22:45', '23:00', 'executed'),  -- no pendent
+                   ($1, $4, '2030-01-02', '00:00', '01:00', 'pending'),
+                   ($1, $4, '2030-01-01', '23:00', '01:00', 'pending'),   -- creua mitjanit
+                   ($2, $5, '2030-01-02', '03:00', '04:00', 'pending'),
+                   ($2, $5, '2030-01-01', '22:00', '23:00', 'pending'),   -- en curs
+                   ($3, $6, '2030-01-01', '22:30', '23:00', 'pending')
+            RETURNING id
+            "#
+        )
+        .bind(termo_rule.id)
+        .bind(bomba_rule.id)
+        .bind(aliena_rule.id)
+        .bind(termo.id)
+        .bind(bomba.id)
+        .bind(aliena.id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+
+        let now = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap().and_hms_opt(22, 30, 0).unwrap();
+        let next = get_next_actions_for_user(&pool, user.id, now).await.unwrap();
+
+        assert_eq!(next.len(), 2);
+        assert_eq!(next[0].action.id, ids[5]);
+        assert_eq!(next[0].action.device_id, bomba.id);
+        assert_eq!(next[1].action.id, ids[3]);
+        assert_eq!(next[1].action.device_id, termo.id);
+        assert_eq!(next[1].starts_at, now.date().and_hms_opt(23, 0, 0).unwrap());
+        assert_eq!(
+            next[1].ends_at,
+            NaiveDate::from_ymd_opt(2030, 1, 2).unwrap().and_hms_opt(1, 0, 0).unwrap()
+        );
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_concurrent_generations_do_not_duplicate(pool: PgPool) {
+        use crate::test_utils::{create_device, create_rule, create_user};
+        use shared::{DailyPrices, HourlyPrice};
+
+        let user = create_user(&pool, "concurrent").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rules = [create_rule(&pool, device.id, "Barates", true).await];
+        let date = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let prices = DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| HourlyPrice { hour, price: 0.10 + hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+
+        // La segona generació espera la primera i troba les accions ja creades
+        let (first, second) = tokio::join!(
+            generate_schedules_for_user(&pool, user.id, &rules, &prices, date, false),
+            generate_schedules_for_user(&pool, user.id, &rules, &prices, date, false),
+        );
+        let mut outcomes: Vec<_> = [first, second].map(|r| r.map(|(count, status, _)| (count, status)).unwrap()).into();
+        outcomes.sort_by_key(|(count, _)| std::cmp::Reverse(*count));
+        assert_eq!(outcomes, vec![(2, GenerationStatus::Generated), (0, GenerationStatus::Existing)]);
+
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM scheduled_actions WHERE rule_id = $1")
+            .bind(rules[0].id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+
+        // Sense regles que programin res, no hi ha accions
+        let (count, status, _) = generate_schedules_for_user(&pool, user.id, &[], &prices, date, false).await.unwrap();
+        assert_eq!((count, status), (0, GenerationStatus::Empty));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_generate_dry_run_writes_nothing(pool: PgPool) {
+        use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+        use actix_web::App;
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+        // ESIOS: les mateixes 24 hores per qualsevol dia, les de matinada més barates
+        let config = test_config();
+        let today = clock::today(config.timezone);
+        let values: Vec<_> = (0..24)
+            .map(|hour| {
+                let datetime = format!("{}T{:02}:00:00.000+01:00", today, hour);
+                serde_json::json!({ "value": 50.0 + f64::from(hour) * 10.0, "datetime": datetime, "geo_id": 8741 })
+            })
+            .collect();
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": values } })),
+            )
+            .mount(&server)
+            .await;
+
+        // Dues regles del mateix dispositiu volen les mateixes hores: només guanya la prioritària
+        let user = create_user(&pool, "dry-run").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let first = create_rule(&pool, device.id, "Prioritària", true).await;
+        let second = create_rule(&pool, device.id, "Secundària", true).await;
+        sqlx::query("UPDATE rules SET priority = 2 WHERE id = $1")
+            .bind(second.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(pvpc))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let generate = |uri: &str| TestRequest::post().uri(uri).insert_header(auth_header(&user, &config)).to_request();
+        let rows = |table: &str| {
+            let sql = format!("SELECT COUNT(*) FROM {}", table);
+            let pool = pool.clone();
+            async move { sqlx::query_scalar::<_, i64>(&sql).fetch_one(&pool).await }
+        };
+
+        let resp = call_service(&app, generate("/api/schedule/generate?dry_run=true")).await;
+        assert_eq!(resp.status(), 200);
+        let preview: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(preview["dry_run"], true);
+        assert_eq!(preview["total_count"], 4);
+        let proposed = &preview["details"][0]["proposed_actions"];
+        assert_eq!(proposed[0]["rule_id"], first.id.to_string());
+        assert_eq!(proposed[0]["actions"].as_array().unwrap().len(), 2);
+        assert_eq!(proposed[0]["actions"][0]["start_time"], "00:00:00");
+        assert_eq!(proposed[1]["rule_id"], second.id.to_string());
+        assert!(proposed[1]["actions"].as_array().unwrap().is_empty());
+        // Prioritats diferents: la secundària no arriba a programar res, no hi ha conflicte
+        assert_eq!(preview["conflicts"], serde_json::json!([]));
+
+        // Res desat
+        assert_eq!(rows("scheduled_actions").await.unwrap(), 0);
+        assert_eq!(rows("skipped_actions").await.unwrap(), 0);
+        assert_eq!(rows("cached_prices").await.unwrap(), 0);
+
+        // La generació real crea exactament el que s'havia previst
+        let resp = call_service(&app, generate("/api/schedule/generate")).await;
+        let generated: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(generated["dry_run"], false);
+        assert_eq!(generated["total_count"], preview["total_count"]);
+        assert_eq!(generated["conflicts"], serde_json::json!([]));
+        for (real, dry) in generated["details"].as_array().unwrap().iter().zip(preview["details"].as_array().unwrap()) {
+            assert_eq!((&real["date"], &real["count"], &real["status"]), (&dry["date"], &dry["count"], &dry["status"]));
+            assert!(real.get("proposed_actions").is_none());
+        }
+        assert_eq!(rows("scheduled_actions").await.unwrap(), 4);
+    }
+
+    #[test]
+    fn test_day_costs_power_fallbacks() {
+        let rule_id = Uuid::new_v4();
+        let row = |device_id, start: u32, end: u32, actual: Option<f64>, device: Option<f64>| ActionCostRow {
+            wattage_watts: None,
+            rule_id,
+            rule_name: "Nit".to_string(),
+            device_id,
+            device_name: format!("Dispositiu {}", start),
+            start_time: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+            price_per_kwh: Some(0.10),
+            actual_power_watts: actual,
+            device_power_watts: device,
+        };
+        let (termo, cotxe) = (Uuid::new_v4(), Uuid::new_v4());
+        let rows = [
+            // Potència reportada: 2 kW × 1 h
+            row(termo, 2, 3, Some(2000.0), Some(500.0)),
+            // Mitjana del dispositiu: 0.5 kW × 2 h (creua mitjanit)
+            row(termo, 23, 1, None, Some(500.0)),
+            // Sense cap mesura: 1 kW × 1 h
+            row(cotxe, 4, 5, Some(0.0), None),
+        ];
+
+        let costs = day_costs(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(), &rows);
+        assert_eq!(costs.by_rule.len(), 1);
+        assert_eq!((costs.by_rule[0].actions, costs.by_rule[0].assumed_power_actions), (3, 1));
+        assert!((costs.total_kwh - 4.0).abs() < 1e-9);
+        assert!((costs.total_eur - 0.4).abs() < 1e-9);
+
+        let termo_cost = costs.by_device.iter().find(|e| e.id == termo).unwrap();
+        assert!((termo_cost.kwh - 3.0).abs() < 1e-9);
+        assert_eq!(termo_cost.assumed_power_actions, 0);
+
+        // La potència configurada del dispositiu passa davant de la mitjana mesurada i de la suposada
+        let configured = [
+            ActionCostRow { wattage_watts: Some(3000.0), ..row(termo, 23, 1, None, Some(500.0)) },
+            ActionCostRow { wattage_watts: Some(1500.0), ..row(cotxe, 4, 5, None, None) },
+        ];
+        let costs = day_costs(NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(), &configured);
+        assert!((costs.total_kwh - 7.5).abs() < 1e-9);
+        assert_eq!(costs.by_rule[0].assumed_power_actions, 0);
+    }
+
+    #[test]
+    fn test_split_keeps_normal_action() {
+        let segments = split_midnight_crossing(action((10, 0), (11, 0)));
+
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].start_time, "10:00:00");
+        assert_eq!(segments[0].end_time, "11:00:00");
+        assert_eq!(segments[0].segment, None);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_day_costs_per_rule_and_device(pool: PgPool) {
+        use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+        use actix_web::App;
+
+        use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+        let config = test_config();
+        let user = create_user(&pool, "costs").await;
+        let termo = create_device(&pool, user.id, "Termo").await;
+        let cotxe = create_device(&pool, user.id, "Cotxe").await;
+        let nit = create_rule(&pool, termo.id, "Nit", true).await;
+        let carrega = create_rule(&pool, cotxe.id, "Càrrega", true).await;
+
+        // (regla, dispositiu, inici, final, preu, acció, estat, potència)
+        let actions = [
+            (nit.id, termo.id, "02:00", "03:00", 0.10, "on", "executed_on", Some(2000.0)),
+            (nit.id, termo.id, "03:00", "04:00", 0.12, "on", "pending", None),
+            (nit.id, termo.id, "05:00", "06:00", 0.50, "on", "cancelled", None),
+            (nit.id, termo.id, "06:00", "07:00", 0.50, "off", "pending", None),
+            (carrega.id, cotxe.id, "01:00", "03:00", 0.08, "on", "pending", None),
+        ];
+        for (rule_id, device_id, start, end, price, action, status, watts) in actions {
+            sqlx::query(
+                r#"
+                INSERT INTO scheduled_actions
+                    (rule_id, device_id, scheduled_date, start_time, end_time, price_per_kwh,
+                     action, status, actual_power_watts)
+                VALUES ($1, $2, '2030-01-01', $3::time, $4::time, $5, $6, $7, $8)
+                "#
+            )
+            .bind(rule_id)
+            .bind(device_id)
+            .bind(start)
+            .bind(end)
+            .bind(price)
+            .bind(action)
+            .bind(status)
+            .bind(watts)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let req = TestRequest::get()
+            .uri("/api/schedule/2030-01-01/costs")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+
+        // Termo: 2 kWh a 0.10 (mesurat) + 2 kWh a 0.12 (mitjana del dispositiu) = 0.44 €
+        // Cotxe: 2 h a 1 kW suposat a 0.08 = 0.16 €
+        let by_device = body["by_device"].as_array().unwrap();
+        assert_eq!(by_device[0]["name"], "Cotxe");
+        assert!((by_device[0]["cost_eur"].as_f64().unwrap() - 0.16).abs() < 1e-9);
+        assert_eq!(by_device[0]["assumed_power_actions"], 1);
+        assert_eq!(by_device[1]["actions"], 2);
+        assert!((by_device[1]["cost_eur"].as_f64().unwrap() - 0.44).abs() < 1e-9);
+
+        assert_eq!(body["by_rule"].as_array().unwrap().len(), 2);
+        assert!((body["total_kwh"].as_f64().unwrap() - 6.0).abs() < 1e-9);
+        assert!((body["total_eur"].as_f64().unwrap() - 0.60).abs() < 1e-9);
+
+        // Amb la potència configurada el cotxe ja no es calcula amb 1 kW: 2 h a 3 kW a 0.08 = 0.48 €
+        sqlx::query("UPDATE devices SET wattage_watts = 3000 WHERE name = 'Cotxe'").execute(&pool).await.unwrap();
+        let req = TestRequest::get()
+            .uri("/api/schedule/2030-01-01/costs")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        let by_device = body["by_device"].as_array().unwrap();
+        assert!((by_device[0]["cost_eur"].as_f64().unwrap() - 0.48).abs() < 1e-9);
+        assert_eq!(by_device[0]["assumed_power_actions"], 0);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_failed_execution_round_trip(pool: PgPool) {
+        use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+        use actix_web::App;
+
+        use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+        let config = test_config();
+        let user = create_user(&pool, "exec").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        let action_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, '2030-01-01', '02:00', '03:00')
+            RETURNING id
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let req = TestRequest::patch()
+            .uri(&format!("/api/schedule/{}/status", action_id))
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({
+                "status": "failed",
+                "actual_power_watts": 0.0,
+                "error_message": "Endoll no accessible",
+                "executed_at": "2030-01-01T01:00:05Z",
+            }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        let req = TestRequest::get()
+            .uri("/api/schedule/2030-01-01")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        let action = &body[0];
+        assert_eq!(action["status"], "failed");
+        assert_eq!(action["actual_power_watts"], 0.0);
+        assert_eq!(action["error_message"], "Endoll no accessible");
+        assert_eq!(action["executed_at"], "2030-01-01T01:00:05Z");
+
+        // Un nou informe sense detalls esborra l'error anterior
+        let req = TestRequest::patch()
+            .uri(&format!("/api/schedule/{}/status", action_id))
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "status": "executed_on", "actual_power_watts": 1850.5 }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        let action = get_scheduled_action_for_user(&pool, user.id, action_id).await.unwrap().unwrap();
+        assert_eq!(action.status, "executed_on");
+        assert_eq!(action.actual_power_watts, Some(1850.5));
+        assert_eq!(action.error_message, None);
+        assert!(action.executed_at.is_some());
+    }
+
+    #[test]
+    fn test_retry_only_from_today() {
+        let today = NaiveDate::from_ymd_opt(2030, 1, 2).unwrap();
+        let yesterday = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let tomorrow = NaiveDate::from_ymd_opt(2030, 1, 3).unwrap();
+
+        assert!(check_retryable("missed", today, today).is_ok());
+        assert!(check_retryable("failed", tomorrow, today).is_ok());
+        assert!(check_retryable("missed", yesterday, today).is_err());
+        assert!(check_retryable("pending", today, today).is_err());
+        assert!(check_retryable("executed_on", today, today).is_err());
+    }
+
+    #[test]
+    fn test_estimate_missed_cost() {
+        let row = |action: &str, start: u32, end: u32| MissedActionRow {
+            id: Uuid::new_v4(),
+            rule_id: Uuid::new_v4(),
+            rule_name: "Nit".to_string(),
+            device_id: Uuid::new_v4(),
+            device_name: "Termo".to_string(),
+            google_device_id: "google-1".to_string(),
+            scheduled_date: NaiveDate::from_ymd_opt(2030, 1, 1).unwrap(),
+            start_time: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+            action: action.to_string(),
+            price_per_kwh: Some(0.05),
+            day_average_price: Some(0.15),
+            wattage_watts: None,
+            device_power_watts: Some(2000.0),
+        };
+
+        // 2 kW × 2 h × (0.15 - 0.05) €/kWh
+        let cost = estimate_missed_cost(&row("on", 2, 4)).unwrap();
+        assert!((cost - 0.4).abs() < 1e-9);
+
+        // Creua mitjanit: 23:00-01:00 són 2 hores
+        let cost = estimate_missed_cost(&row("on", 23, 1)).unwrap();
+        assert!((cost - 0.4).abs() < 1e-9);
+
+        // Un "off" perdut a una hora barata no costa res
+        assert_eq!(estimate_missed_cost(&row("off", 2, 4)), Some(0.0));
+
+        let unknown_power = MissedActionRow { device_power_watts: None, ..row("on", 2, 4) };
+        assert_eq!(estimate_missed_cost(&unknown_power), None);
+
+        // La potència configurada passa davant de la mesurada: 1 kW × 2 h × 0.10 €/kWh
+        let configured = MissedActionRow { wattage_watts: Some(1000.0), ..row("on", 2, 4) };
+        assert!((estimate_missed_cost(&configured).unwrap() - 0.2).abs() < 1e-9);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_missed_actions_and_retry(pool: PgPool) {
+        use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+        use actix_web::App;
+
+        use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+        let config = test_config();
+        let user = create_user(&pool, "missed").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        let today = clock::today(config.timezone);
+        let yesterday = today - chrono::Duration::days(1);
+
+        let insert_missed = |date: NaiveDate| {
+            sqlx::query_scalar::<_, Uuid>(
+                r#"
+                INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, price_per_kwh, status)
+                VALUES ($1, $2, $3, '02:00', '03:00', 0.05, 'missed')
+                RETURNING id
+                "#
+            )
+            .bind(rule.id)
+            .bind(device.id)
+            .bind(date)
+            .fetch_one(&pool)
+        };
+        let today_id = insert_missed(today).await.unwrap();
+        let yesterday_id = insert_missed(yesterday).await.unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri(&format!("/api/schedule/missed?from={}&to={}&limit=1", yesterday, today))
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        let body: serde_json::Value = read_body_json(call_service(&app, req).await).await;
+        assert_eq!(body["total"], 2);
+        assert_eq!(body["items"].as_array().unwrap().len(), 1);
+        assert_eq!(body["items"][0]["id"], today_id.to_string());
+        assert_eq!(body["items"][0]["rule_name"], "Nit");
+        assert_eq!(body["items"][0]["price_per_kwh"], 0.05);
+        // Sense historial de preus ni potència mesurada no es pot estimar el cost
+        assert_eq!(body["items"][0]["estimated_cost_missed_eur"], serde_json::Value::Null);
+
+        let retry = |id: Uuid| {
+            TestRequest::post()
+                .uri(&format!("/api/schedule/{}/retry", id))
+                .insert_header(auth_header(&user, &config))
+                .to_request()
+        };
+
+        let resp = call_service(&app, retry(today_id)).await;
+        assert_eq!(resp.status(), 200);
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["status"], "pending");
+
+        // Ja és pending: no es pot tornar a intentar
+        assert_eq!(call_service(&app, retry(today_id)).await.status(), 400);
+        // D'ahir: massa tard
+        assert_eq!(call_service(&app, retry(yesterday_id)).await.status(), 400);
+        // D'un altre usuari
+        let other = create_user(&pool, "other").await;
+        let req = TestRequest::post()
+            .uri(&format!("/api/schedule/{}/retry", yesterday_id))
+            .insert_header(auth_header(&other, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 404);
+    }
+
+    #[test]
+    fn test_update_status_validation() {
+        let request = |status: &str, actual_power_watts: Option<f64>| UpdateStatusRequest {
+            status: status.to_string(),
+            actual_power_watts,
+            error_message: None,
+            executed_at: None,
+        };
+
+        assert!(request("executed_on", Some(120.0)).validate().is_ok());
+        assert!(request("done", None).validate().is_err());
+        assert!(request("failed", Some(-1.0)).validate().is_err());
+        assert!(request("failed", Some(f64::NAN)).validate().is_err());
+
+        let long = UpdateStatusRequest {
+            error_message: Some("x".repeat(MAX_ERROR_MESSAGE_LEN + 1)),
+            ..request("failed", None)
+        };
+        assert!(long.validate().is_err());
+    }
+
+    #[test]
+    fn test_explain_request_validation() {
+        let request = |max_hours: i32, min_continuous_hours: Option<i32>| ExplainRequest {
+            date: None,
+            max_hours,
+            time_window_start: None,
+            time_window_end: None,
+            min_continuous_hours,
+            min_gap_hours: None,
+            negative_price_boost: None,
+            continuity_preference: None,
+            action_type: None,
+            blackout_windows: None,
+            load_profile: None,
+            max_cost_eur: None,
+            wattage_watts: None,
+        };
+
+        assert!(request(4, Some(2)).validate().is_ok());
+        assert!(request(0, None).validate().is_err());
+        assert!(request(2, Some(3)).validate().is_err());
+
+        let bad_profile = ExplainRequest {
+            load_profile: Some(vec![1.0; 12]),
+            ..request(4, None)
+        };
+        assert!(bad_profile.validate().is_err());
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_schedule_ics_export(pool: PgPool) {
+        use actix_web::test::{call_service, init_service, read_body, TestRequest};
+        use actix_web::App;
+
+        use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+        let config = test_config();
+        let user = create_user(&pool, "ics").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, status)
+            VALUES ($1, $2, '2030-01-01', '02:00', '03:00', 'pending'),
+                   ($1, $2, '2030-01-01', '23:00', '00:00', 'pending'),
+                   ($1, $2, '2030-01-01', '05:00', '06:00', 'cancelled'),
+                   ($1, $2, '2030-01-02', '04:00', '05:00', 'pending')
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let ics = |uri: &'static str| {
+            let req = TestRequest::get().uri(uri).insert_header(auth_header(&user, &config)).to_request();
+            async { call_service(&app, req).await }
+        };
+
+        let resp = ics("/api/schedule/2030-01-01/ics").await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers().get(header::CONTENT_TYPE).unwrap(), "text/calendar; charset=utf-8");
+        let body = String::from_utf8(read_body(resp).await.to_vec()).unwrap();
+        assert!(body.starts_with("BEGIN:VCALENDAR\r\n") && body.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(body.matches("BEGIN:VEVENT").count(), 2);
+        // La de les 23:00 acaba l'endemà
+        assert!(body.contains("DTSTART:20300101T220000Z\r\nDTEND:20300101T230000Z"));
+
+        let body = read_body(ics("/api/schedule/ics?from=2030-01-01&to=2030-01-02").await).await;
+        assert_eq!(String::from_utf8(body.to_vec()).unwrap().matches("BEGIN:VEVENT").count(), 3);
+
+        assert_eq!(ics("/api/schedule/ics?from=2030-01-02&to=2030-01-01").await.status(), 400);
+    }
+}
@@ -0,0 +1,231 @@
+use std::ops::Deref;
+
+use actix_web::error::JsonPayloadError;
+use actix_web::{dev::Payload, web, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use serde::de::DeserializeOwned;
+
+use crate::error::{AppError, AppResult, FieldError};
+
+/// Validació d'un cos de petició. Els errors han de ser `AppError::BadRequest` o,
+/// si se'n poden indicar els camps, `AppError::Validation`.
+pub trait Validate {
+    fn validate(&self) -> AppResult<()>;
+}
+
+/// Acumula els errors de validació per retornar-los tots alhora
+#[derive(Debug, Default)]
+pub struct FieldErrors(Vec<FieldError>);
+
+impl FieldErrors {
+    pub fn check(&mut self, result: Result<(), FieldError>) {
+        if let Err(e) = result {
+            self.0.push(e);
+        }
+    }
+
+    pub fn add(&mut self, field: impl Into<String>, code: impl Into<String>, message: impl Into<String>) {
+        self.0.push(FieldError::new(field, code, message));
+    }
+
+    /// `AppError::Validation` amb tots els errors, o `Ok` si no n'hi ha cap
+    pub fn into_result(self) -> AppResult<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(AppError::Validation(self.0))
+        }
+    }
+}
+
+/// Extractor JSON que valida el cos abans d'arribar al handler
+///
+/// Equivalent a `web::Json<T>`, però retorna l'error de `Validate` si el cos no és vàlid
+/// (400 amb el missatge, o 422 amb la llista de camps).
+#[derive(Debug)]
+pub struct Validated<T>(pub T);
+
+impl<T> Deref for Validated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for Validated<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let body = json.await?.into_inner();
+            body.validate()?;
+            Ok(Validated(body))
+        })
+    }
+}
+
+/// Extractor JSON per als cossos que substitueixen un recurs sencer (PUT)
+///
+/// Com `Validated`, però un cos que no es pot deserialitzar (p. ex. hi falta un camp
+/// obligatori) retorna 422 en lloc de 400.
+#[derive(Debug)]
+pub struct Replacement<T>(pub T);
+
+impl<T> Deref for Replacement<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for Replacement<T>
+where
+    T: DeserializeOwned + Validate + 'static,
+{
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let json = web::Json::<T>::from_request(req, payload);
+        Box::pin(async move {
+            let body = json.await.map_err(|e| match e.as_error::<JsonPayloadError>() {
+                Some(JsonPayloadError::Deserialize(de)) if de.is_data() => {
+                    AppError::Unprocessable("INCOMPLETE_BODY", de.to_string()).into()
+                }
+                _ => e,
+            })?;
+            let body = body.into_inner();
+            body.validate()?;
+            Ok(Replacement(body))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{post, test, App, HttpResponse};
+    use serde::Deserialize;
+
+    use crate::error::AppError;
+
+    #[derive(Deserialize)]
+    struct Hours {
+        hours: i32,
+    }
+
+    impl Validate for Hours {
+        fn validate(&self) -> AppResult<()> {
+            if self.hours < 1 {
+                return Err(AppError::BadRequest("VALIDATION_FAILED", "hours must be positive".to_string()));
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct Window {
+        start: i32,
+        end: i32,
+    }
+
+    impl Validate for Window {
+        fn validate(&self) -> AppResult<()> {
+            let mut errors = FieldErrors::default();
+            if !(0..24).contains(&self.start) {
+                errors.add("start", "OUT_OF_RANGE", "start must be between 0 and 23");
+            }
+            if !(0..24).contains(&self.end) {
+                errors.add("end", "OUT_OF_RANGE", "end must be between 0 and 23");
+            }
+            errors.into_result()
+        }
+    }
+
+    #[post("/window")]
+    async fn window(body: Validated<Window>) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{}-{}", body.start, body.end))
+    }
+
+    #[post("/hours")]
+    async fn hours(body: Validated<Hours>) -> HttpResponse {
+        HttpResponse::Ok().body(body.hours.to_string())
+    }
+
+    #[actix_web::test]
+    async fn test_validated_extractor() {
+        let app = test::init_service(App::new().service(hours)).await;
+
+        let req = test::TestRequest::post().uri("/hours").set_json(serde_json::json!({ "hours": 3 })).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(test::read_body(resp).await, "3");
+
+        let req = test::TestRequest::post().uri("/hours").set_json(serde_json::json!({ "hours": 0 })).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["error"], "hours must be positive");
+        assert!(body.get("errors").is_none());
+    }
+
+    #[post("/replace")]
+    async fn replace(body: Replacement<Window>) -> HttpResponse {
+        HttpResponse::Ok().body(format!("{}-{}", body.start, body.end))
+    }
+
+    #[actix_web::test]
+    async fn test_replacement_requires_every_field() {
+        let app = test::init_service(App::new().service(replace)).await;
+        let send = |body: serde_json::Value| test::TestRequest::post().uri("/replace").set_json(body).to_request();
+
+        assert_eq!(test::call_service(&app, send(serde_json::json!({ "start": 2, "end": 6 }))).await.status(), 200);
+
+        let resp = test::call_service(&app, send(serde_json::json!({ "start": 2 }))).await;
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["code"], "INCOMPLETE_BODY");
+
+        // Un JSON mal format continua sent 400
+        let req = test::TestRequest::post()
+            .uri("/replace")
+            .insert_header(("content-type", "application/json"))
+            .set_payload("{")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+        assert_eq!(test::call_service(&app, send(serde_json::json!({ "start": 2, "end": 30 }))).await.status(), 422);
+    }
+
+    #[actix_web::test]
+    async fn test_validation_errors_list_every_field() {
+        let app = test::init_service(App::new().service(window)).await;
+
+        let req = test::TestRequest::post().uri("/window").set_json(serde_json::json!({ "start": 2, "end": 6 }));
+        let req = req.to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 200);
+
+        let req = test::TestRequest::post().uri("/window").set_json(serde_json::json!({ "start": -1, "end": 24 }));
+        let req = req.to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 422);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "error": "validation",
+                "code": "validation",
+                "errors": [
+                    { "field": "start", "message": "start must be between 0 and 23", "code": "OUT_OF_RANGE" },
+                    { "field": "end", "message": "end must be between 0 and 23", "code": "OUT_OF_RANGE" },
+                ],
+            })
+        );
+    }
+}
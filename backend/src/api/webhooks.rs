@@ -0,0 +1,154 @@
+use actix_web::{post, web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::Webhook;
+use crate::error::{AppError, AppResult, ErrorResponse};
+use crate::services::webhooks::WebhookEvent;
+
+use super::auth::extract_user_from_request;
+use super::validation::{Validate, Validated};
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    /// Clau per signar els payloads. Si no s'indica, se'n genera una.
+    pub secret: Option<String>,
+    /// Esdeveniments subscrits (per defecte, tots)
+    pub events: Option<Vec<String>>,
+}
+
+impl Validate for CreateWebhookRequest {
+    fn validate(&self) -> AppResult<()> {
+        if !(self.url.starts_with("http://") || self.url.starts_with("https://")) {
+            return Err(AppError::BadRequest(
+                "WEBHOOK_VALIDATION_FAILED",
+                "url must start with http:// or https://".to_string(),
+            ));
+        }
+
+        if self.secret.as_deref().is_some_and(str::is_empty) {
+            return Err(AppError::BadRequest("WEBHOOK_VALIDATION_FAILED", "secret cannot be empty".to_string()));
+        }
+
+        if let Some(events) = &self.events {
+            if events.is_empty() {
+                return Err(AppError::BadRequest("WEBHOOK_VALIDATION_FAILED", "events cannot be empty".to_string()));
+            }
+
+            if let Some(unknown) = events.iter().find(|e| WebhookEvent::parse(e).is_none()) {
+                return Err(AppError::BadRequest(
+                    "WEBHOOK_VALIDATION_FAILED",
+                    format!("Unknown webhook event: {}", unknown),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct WebhookResponse {
+    pub id: Uuid,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Webhook> for WebhookResponse {
+    fn from(w: Webhook) -> Self {
+        Self {
+            id: w.id,
+            url: w.url,
+            secret: w.secret,
+            events: w.events,
+            created_at: w.created_at,
+        }
+    }
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(create_webhook);
+}
+
+/// POST /api/webhooks
+/// Registra una URL que rebrà els esdeveniments signats (HMAC-SHA256 a `X-Signature`)
+#[utoipa::path(
+    tag = "webhooks",
+    request_body = CreateWebhookRequest,
+    responses(
+        (status = 201, description = "Webhook registrat", body = WebhookResponse),
+        (status = 400, description = "Paràmetres invàlids", body = ErrorResponse),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+    security(("bearer_auth" = [])),
+)]
+#[post("/webhooks")]
+async fn create_webhook(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    req: HttpRequest,
+    body: Validated<CreateWebhookRequest>,
+) -> AppResult<HttpResponse> {
+    let user = extract_user_from_request(&req, &pool, &config.jwt_secret).await?;
+
+    let events = match &body.events {
+        Some(events) => events.clone(),
+        None => WebhookEvent::ALL.iter().map(|e| e.as_str().to_string()).collect(),
+    };
+
+    let secret = match &body.secret {
+        Some(secret) => secret.clone(),
+        None => format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple()),
+    };
+
+    let webhook = sqlx::query_as::<_, Webhook>(
+        r#"
+        INSERT INTO webhooks (user_id, url, secret, events)
+        VALUES ($1, $2, $3, $4)
+        RETURNING *
+        "#
+    )
+    .bind(user.id)
+    .bind(&body.url)
+    .bind(&secret)
+    .bind(&events)
+    .fetch_one(pool.get_ref())
+    .await?;
+
+    tracing::info!("Webhook {} registrat per l'usuari {}", webhook.id, user.id);
+
+    Ok(HttpResponse::Created().json(WebhookResponse::from(webhook)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn webhook(url: &str, secret: Option<&str>, events: Option<&[&str]>) -> CreateWebhookRequest {
+        CreateWebhookRequest {
+            url: url.to_string(),
+            secret: secret.map(str::to_string),
+            events: events.map(|e| e.iter().map(|s| s.to_string()).collect()),
+        }
+    }
+
+    #[test]
+    fn test_validate_webhook() {
+        let events: &[&str] = &["schedule.status_changed"];
+        assert!(webhook("https://ha.local/api/webhook/pvpc", None, Some(events)).validate().is_ok());
+        // Sense events se subscriu a tots
+        assert!(webhook("http://ha.local", Some("s3cret"), None).validate().is_ok());
+
+        assert!(webhook("ftp://ha.local", None, Some(events)).validate().is_err());
+        assert!(webhook("https://ha.local", Some(""), None).validate().is_err());
+        assert!(webhook("https://ha.local", None, Some(&[])).validate().is_err());
+        assert!(webhook("https://ha.local", None, Some(&["other"])).validate().is_err());
+    }
+}
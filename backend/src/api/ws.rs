@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use actix_web::{get, web, HttpRequest, HttpResponse};
+use actix_ws::{Message, MessageStream, Session};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use sqlx::PgPool;
+use tokio::sync::broadcast;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::error::ErrorResponse;
+use crate::services::schedule_events::{ScheduleEvent, ScheduleEvents};
+
+use super::auth::extract_user_from_token;
+
+/// Interval entre pings al client per detectar connexions mortes
+const WS_PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct WsQuery {
+    /// JWT de l'usuari (els navegadors no permeten capçaleres al WebSocket)
+    pub token: String,
+}
+
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(websocket);
+}
+
+/// GET /api/ws?token=...
+/// WebSocket amb els canvis de schedules i preus de l'usuari en temps real
+#[utoipa::path(
+    tag = "schedule",
+    params(WsQuery),
+    responses(
+        (status = 101, description = "Connexió WebSocket establerta. Missatges JSON amb camp `type`: \
+            status_changed, schedule_generated o prices_updated"),
+        (status = 401, description = "Token invàlid o absent", body = ErrorResponse),
+    ),
+)]
+#[get("/ws")]
+async fn websocket(
+    pool: web::Data<PgPool>,
+    config: web::Data<Config>,
+    events: web::Data<ScheduleEvents>,
+    req: HttpRequest,
+    body: web::Payload,
+    query: web::Query<WsQuery>,
+) -> actix_web::Result<HttpResponse> {
+    let user = extract_user_from_token(&query.token, &pool, &config.jwt_secret).await?;
+
+    let (response, session, messages) = actix_ws::handle(&req, body)?;
+
+    // Subscriure's abans de retornar la resposta per no perdre esdeveniments
+    let rx = events.subscribe();
+    actix_web::rt::spawn(run_session(user.id, session, messages, rx));
+
+    tracing::debug!("WebSocket obert per l'usuari {}", user.id);
+
+    Ok(response)
+}
+
+/// Reenvia els esdeveniments de l'usuari fins que el client tanca la connexió
+async fn run_session(
+    user_id: Uuid,
+    mut session: Session,
+    mut messages: MessageStream,
+    mut rx: broadcast::Receiver<ScheduleEvent>,
+) {
+    let mut ping = tokio::time::interval(WS_PING_INTERVAL);
+
+    loop {
+        tokio::select! {
+            message = messages.next() => match message {
+                Some(Ok(Message::Ping(bytes))) => {
+                    if session.pong(&bytes).await.is_err() {
+                        break;
+                    }
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    tracing::warn!("Error al WebSocket de l'usuari {}: {}", user_id, e);
+                    break;
+                }
+            },
+            event = rx.recv() => match event {
+                Ok(event) if event.is_for(user_id) => {
+                    let Ok(json) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if session.text(json).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!("WebSocket endarrerit, {} esdeveniments descartats", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = ping.tick() => {
+                if session.ping(b"").await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    let _ = session.close(None).await;
+    tracing::debug!("WebSocket tancat per l'usuari {}", user_id);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{App, HttpServer};
+    use futures_util::StreamExt;
+    use tokio_tungstenite::tungstenite;
+
+    use crate::api::auth::generate_jwt;
+    use crate::services::webhooks::WebhookDispatcher;
+    use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_ws_receives_status_change(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "ws").await;
+        let device = create_device(&pool, user.id, "Rentadora").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        let action_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, CURRENT_DATE, '02:00', '03:00')
+            RETURNING id
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        let events = ScheduleEvents::new();
+        let server_pool = pool.clone();
+        let server_config = config.clone();
+        let server_events = events.clone();
+        let server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(server_pool.clone()))
+                .app_data(web::Data::new(server_config.clone()))
+                .app_data(web::Data::new(server_events.clone()))
+                .app_data(web::Data::new(WebhookDispatcher::new(reqwest::Client::new())))
+                .configure(crate::api::configure)
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        tokio::spawn(server);
+
+        // Sense token vàlid no es pot connectar
+        assert!(tokio_tungstenite::connect_async(format!("ws://{}/api/ws?token=invalid", addr))
+            .await
+            .is_err());
+
+        let (token, _) = generate_jwt(&user, &config).unwrap();
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/api/ws?token={}", addr, token))
+            .await
+            .unwrap();
+
+        let (name, value) = auth_header(&user, &config);
+        let response = reqwest::Client::new()
+            .patch(format!("http://{}/api/schedule/{}/status", addr, action_id))
+            .header(name.as_str(), value)
+            .json(&serde_json::json!({ "status": "executed" }))
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+
+        let message = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                match socket.next().await.unwrap().unwrap() {
+                    tungstenite::Message::Text(text) => break text,
+                    _ => continue,
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        let event: serde_json::Value = serde_json::from_str(&message).unwrap();
+        assert_eq!(event["type"], "status_changed");
+        assert_eq!(event["action"]["id"], action_id.to_string());
+        assert_eq!(event["action"]["status"], "executed");
+
+        handle.stop(false).await;
+    }
+}
@@ -1,16 +1,25 @@
-use chrono::{Datelike, Local, NaiveTime, Timelike};
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use chrono_tz::Tz;
 use shared::DailyPrices;
-use sqlx::PgPool;
+use sqlx::{FromRow, PgPool};
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use tokio::time::{interval, Duration};
+use uuid::Uuid;
 
+use crate::config::Config;
+use crate::db::lock_schedule_generation;
 use crate::db::models::Rule;
+use crate::services::{clock, metrics};
+use crate::services::energy_tariff::PriceAdjustment;
+use crate::services::notifications::{send_weekly_summaries, Notifier};
+use crate::services::price_history::{passes_baseline_gate, record_fetch_failure, store_prices};
 use crate::services::pvpc::PvpcClient;
-use crate::services::scheduler::calculate_optimal_hours;
-
-/// Hora a la qual es generen els schedules de demà (20:30)
-const SCHEDULE_GENERATION_HOUR: u32 = 20;
-const SCHEDULE_GENERATION_MINUTE: u32 = 30;
+use crate::services::secrets::open_esios_token;
+use crate::services::schedule_events::{ScheduleEvent, ScheduleEvents};
+use crate::services::scheduled_actions::{find_conflicts, insert_rule_actions, record_skip, SkipReason};
+use crate::services::scheduler::{optimal_hours_for_rule, resolve_conflicts, stagger_group_hours, PriceSlots};
+use crate::services::webhooks::WebhookDispatcher;
 
 /// Interval de reintent si falla (30 minuts)
 const RETRY_INTERVAL_MINUTES: u64 = 30;
@@ -18,38 +27,128 @@ const RETRY_INTERVAL_MINUTES: u64 = 30;
 /// Interval de comprovació (cada minut)
 const CHECK_INTERVAL_SECONDS: u64 = 60;
 
+/// Dia i hora local d'enviament del resum setmanal
+const WEEKLY_SUMMARY_DAY: Weekday = Weekday::Sun;
+const WEEKLY_SUMMARY_HOUR: u32 = 9;
+
 /// Inicia les tasques en background
-pub fn start_background_tasks(pool: Arc<PgPool>, pvpc_client: Arc<PvpcClient>) {
+///
+/// L'hora de generació de `config` és la dels schedules de demà dels usuaris que no n'han
+/// triat una altra (`users.schedule_gen_time`). Totes les dates i hores són locals de la
+/// zona horària de `config`.
+pub fn start_background_tasks(
+    pool: Arc<PgPool>,
+    pvpc_client: Arc<PvpcClient>,
+    webhooks: Arc<WebhookDispatcher>,
+    events: ScheduleEvents,
+    notifier: Arc<dyn Notifier>,
+    config: Arc<Config>,
+) {
+    let tz = config.timezone;
     let pool_clone = pool.clone();
     let pvpc_clone = pvpc_client.clone();
     let pool_for_cleanup = pool.clone();
+    let pool_for_summaries = pool.clone();
 
     // Tasca 1: Generació de schedules
     tokio::spawn(async move {
         // Primer, comprovar si falten schedules d'avui
-        check_and_generate_today_schedules(&pool_clone, &pvpc_clone).await;
+        let generated = check_and_generate_today_schedules(
+            &pool_clone,
+            &pvpc_clone,
+            &webhooks,
+            &events,
+            &config,
+        )
+        .await;
 
         // Després, iniciar el scheduler diari
-        run_daily_scheduler(pool_clone, pvpc_clone).await;
+        run_daily_scheduler(pool_clone, pvpc_clone, webhooks, events, config, generated).await;
     });
 
     // Tasca 2: Marcar accions pendents expirades com a 'missed'
     tokio::spawn(async move {
-        run_expired_actions_checker(pool_for_cleanup).await;
+        run_expired_actions_checker(pool_for_cleanup, tz).await;
     });
+
+    // Tasca 3: Resum setmanal per correu
+    tokio::spawn(async move {
+        run_weekly_summaries(pool_for_summaries, notifier, tz).await;
+    });
+}
+
+/// Usuaris amb regles actives agrupats per la seva hora de generació
+async fn generation_buckets(
+    pool: &PgPool,
+    default_time: NaiveTime,
+) -> Result<BTreeMap<NaiveTime, Vec<Uuid>>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT COALESCE(u.schedule_gen_time, $1) as "gen_time!", array_agg(u.id) as "user_ids!"
+        FROM users u
+        WHERE EXISTS (
+            SELECT 1
+            FROM rules r
+            LEFT JOIN devices d ON r.device_id = d.id
+            LEFT JOIN device_groups g ON r.device_group_id = g.id
+            WHERE COALESCE(d.user_id, g.user_id) = u.id AND r.is_enabled
+        )
+        GROUP BY 1
+        "#,
+        default_time
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| (row.gen_time, row.user_ids)).collect())
+}
+
+/// Si toca generar els schedules de demà d'un grup amb hora de generació `time`
+///
+/// Toca des que `now` (local) passa de `time` fins que es generen, sigui quan sigui la
+/// comprovació: no cal que una comprovació caigui just al minut de `time`. `last_generated`
+/// és l'última data generada pel grup. Quan es retarda el rellotge (hora d'hivern) i una
+/// hora es repeteix, la segona vegada ja està generat; les hores que no existeixen per
+/// l'avançament (hora d'estiu) arriben en passar el salt.
+fn generation_due(time: NaiveTime, now: NaiveDateTime, last_generated: Option<NaiveDate>) -> bool {
+    let tomorrow = now.date() + chrono::Duration::days(1);
+    now.time() >= time && last_generated.is_none_or(|date| date < tomorrow)
+}
+
+/// Si `time` ha arribat entre dues comprovacions (`previous` exclosa, `now` inclosa)
+///
+/// Es comparen dates i hores locals i no només hores: el pas de mitjanit és un canvi de
+/// dia, i quan es retarda el rellotge (hora d'hivern) `now` és anterior a `previous` i no
+/// arriba cap hora. Les hores que no existeixen per l'avançament (hora d'estiu) arriben
+/// en passar el salt.
+fn time_reached(time: NaiveTime, previous: NaiveDateTime, now: NaiveDateTime) -> bool {
+    let mut next = previous.date().and_time(time);
+    if next <= previous {
+        next += chrono::Duration::days(1);
+    }
+    next <= now
 }
 
 /// Comprova si hi ha schedules per avui i demà, si no, els genera
-async fn check_and_generate_today_schedules(pool: &PgPool, pvpc: &PvpcClient) {
-    let now = Local::now();
+///
+/// Retorna els grups (per hora de generació) que ja tenen els schedules de demà, perquè
+/// el scheduler diari no els torni a generar; els que han fallat els reintenta ell.
+async fn check_and_generate_today_schedules(
+    pool: &PgPool,
+    pvpc: &PvpcClient,
+    webhooks: &WebhookDispatcher,
+    events: &ScheduleEvents,
+    config: &Config,
+) -> HashMap<NaiveTime, NaiveDate> {
+    let now = clock::now(config.timezone);
     let today = now.date_naive();
     let tomorrow = today + chrono::Duration::days(1);
 
     // === Generar schedules per AVUI ===
-    let existing_today: i64 = sqlx::query_scalar(
-        "SELECT COUNT(*) FROM scheduled_actions WHERE scheduled_date = $1"
+    let existing_today: i64 = sqlx::query_scalar!(
+        r#"SELECT COUNT(*) as "count!" FROM scheduled_actions WHERE scheduled_date = $1"#,
+        today
     )
-    .bind(today)
     .fetch_one(pool)
     .await
     .unwrap_or(0);
@@ -62,7 +161,7 @@ async fn check_and_generate_today_schedules(pool: &PgPool, pvpc: &PvpcClient) {
         );
     } else {
         tracing::info!("No hi ha schedules per avui ({}), intentant generar-los...", today);
-        match generate_schedules_for_date(pool, pvpc, today).await {
+        match generate_schedules_for_date(pool, pvpc, webhooks, events, today, None, config).await {
             Ok(count) => {
                 tracing::info!("Generats {} schedules per avui ({})", count, today);
             }
@@ -75,207 +174,457 @@ async fn check_and_generate_today_schedules(pool: &PgPool, pvpc: &PvpcClient) {
         }
     }
 
-    // === Generar schedules per DEMÀ (si ja són passades les 20:30) ===
-    let is_after_schedule_time = now.hour() > SCHEDULE_GENERATION_HOUR
-        || (now.hour() == SCHEDULE_GENERATION_HOUR && now.minute() >= SCHEDULE_GENERATION_MINUTE);
+    // === Generar schedules per DEMÀ dels usuaris amb l'hora de generació ja passada ===
+    let mut generated = HashMap::new();
+    let buckets = match generation_buckets(pool, config.schedule_generation_time()).await {
+        Ok(buckets) => buckets,
+        Err(e) => {
+            tracing::warn!("No s'han pogut obtenir les hores de generació: {}", e);
+            return generated;
+        }
+    };
 
-    if is_after_schedule_time {
-        let existing_tomorrow: i64 = sqlx::query_scalar(
-            "SELECT COUNT(*) FROM scheduled_actions WHERE scheduled_date = $1"
+    for (generation_time, user_ids) in buckets.range(..=now.time()) {
+        let existing_tomorrow: i64 = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) as "count!"
+            FROM scheduled_actions sa
+            JOIN devices d ON sa.device_id = d.id
+            WHERE sa.scheduled_date = $1 AND d.user_id = ANY($2)
+            "#,
+            tomorrow,
+            user_ids
         )
-        .bind(tomorrow)
         .fetch_one(pool)
         .await
         .unwrap_or(0);
 
         if existing_tomorrow > 0 {
             tracing::info!(
-                "Ja existeixen {} schedules per demà ({}), no cal generar-ne",
+                "Ja existeixen {} schedules per demà ({}) dels usuaris de les {}, no cal generar-ne",
                 existing_tomorrow,
-                tomorrow
-            );
-        } else {
-            tracing::info!(
-                "Són passades les {}:{:02} i no hi ha schedules per demà ({}), intentant generar-los...",
-                SCHEDULE_GENERATION_HOUR,
-                SCHEDULE_GENERATION_MINUTE,
-                tomorrow
+                tomorrow,
+                generation_time.format("%H:%M")
             );
-            match generate_schedules_for_date(pool, pvpc, tomorrow).await {
-                Ok(count) => {
-                    tracing::info!("Generats {} schedules per demà ({})", count, tomorrow);
-                }
-                Err(e) => {
-                    tracing::warn!(
-                        "No s'han pogut generar schedules per demà: {}. Es reintentarà més tard.",
-                        e
-                    );
-                }
+            generated.insert(*generation_time, tomorrow);
+            continue;
+        }
+
+        tracing::info!(
+            "Són passades les {} i no hi ha schedules per demà ({}) de {} usuaris, intentant generar-los...",
+            generation_time.format("%H:%M"),
+            tomorrow,
+            user_ids.len()
+        );
+        match generate_schedules_for_date(pool, pvpc, webhooks, events, tomorrow, Some(user_ids), config).await {
+            Ok(count) => {
+                tracing::info!("Generats {} schedules per demà ({})", count, tomorrow);
+                generated.insert(*generation_time, tomorrow);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "No s'han pogut generar schedules per demà: {}. Es reintentarà més tard.",
+                    e
+                );
             }
         }
     }
+
+    generated
 }
 
-/// Scheduler que s'executa cada dia a les 20:30
-async fn run_daily_scheduler(pool: Arc<PgPool>, pvpc: Arc<PvpcClient>) {
+/// Scheduler que genera cada dia els schedules de demà de cada grup d'usuaris a la seva hora
+///
+/// Els usuaris s'agrupen per hora de generació (la seva o la del servidor); quan arriba
+/// l'hora d'un grup es generen els schedules de demà només dels seus usuaris. Si falla,
+/// el grup es reintenta cada `RETRY_INTERVAL_MINUTES` per la mateixa data.
+///
+/// `generated` és l'última data generada de cada grup (la de demà dels que ja s'han
+/// generat en arrencar).
+async fn run_daily_scheduler(
+    pool: Arc<PgPool>,
+    pvpc: Arc<PvpcClient>,
+    webhooks: Arc<WebhookDispatcher>,
+    events: ScheduleEvents,
+    config: Arc<Config>,
+    mut generated: HashMap<NaiveTime, NaiveDate>,
+) {
+    let tz = config.timezone;
     let mut check_interval = interval(Duration::from_secs(CHECK_INTERVAL_SECONDS));
-    let mut last_generation_date: Option<chrono::NaiveDate> = None;
-    let mut retry_pending = false;
-    let mut last_retry: Option<chrono::DateTime<Local>> = None;
+    // Grups pendents de reintent: data a generar i últim intent
+    let mut retries: HashMap<NaiveTime, (NaiveDate, chrono::DateTime<Tz>)> = HashMap::new();
 
     loop {
         check_interval.tick().await;
 
-        let now = Local::now();
-        let today = now.date_naive();
-        let tomorrow = today + chrono::Duration::days(1);
+        let now = clock::now(tz);
+        let tomorrow = now.date_naive() + chrono::Duration::days(1);
 
-        // Comprovar si és hora de generar schedules (20:30)
-        let is_schedule_time = now.hour() == SCHEDULE_GENERATION_HOUR
-            && now.minute() >= SCHEDULE_GENERATION_MINUTE
-            && now.minute() < SCHEDULE_GENERATION_MINUTE + 1;
+        let buckets = match generation_buckets(&pool, config.schedule_generation_time()).await {
+            Ok(buckets) => buckets,
+            Err(e) => {
+                tracing::error!("Error obtenint les hores de generació: {}", e);
+                continue;
+            }
+        };
 
-        // Comprovar si ja hem generat per demà avui
-        let already_generated_today = last_generation_date == Some(tomorrow);
+        for (generation_time, user_ids) in &buckets {
+            // Si demà ja ha fallat, es deixa per al reintent
+            let due = generation_due(*generation_time, now.naive_local(), generated.get(generation_time).copied())
+                && retries.get(generation_time).is_none_or(|(date, _)| *date != tomorrow);
 
-        // Comprovar si cal reintentar
-        let should_retry = retry_pending && last_retry.map_or(true, |last| {
-            now.signed_duration_since(last).num_minutes() >= RETRY_INTERVAL_MINUTES as i64
-        });
+            let date = if due {
+                tomorrow
+            } else {
+                match retries.get(generation_time) {
+                    Some((date, last))
+                        if now.signed_duration_since(*last).num_minutes() >= RETRY_INTERVAL_MINUTES as i64 =>
+                    {
+                        *date
+                    }
+                    _ => continue,
+                }
+            };
 
-        if (is_schedule_time && !already_generated_today) || should_retry {
             tracing::info!(
-                "Generant schedules per demà ({})...",
-                tomorrow
+                "Generant schedules per {} dels usuaris de les {} ({} usuaris)...",
+                date,
+                generation_time.format("%H:%M"),
+                user_ids.len()
             );
 
-            match generate_schedules_for_date(&pool, &pvpc, tomorrow).await {
+            match generate_schedules_for_date(&pool, &pvpc, &webhooks, &events, date, Some(user_ids), &config).await {
                 Ok(count) => {
-                    tracing::info!(
-                        "Generats {} schedules per demà ({})",
-                        count,
-                        tomorrow
-                    );
-                    last_generation_date = Some(tomorrow);
-                    retry_pending = false;
-                    last_retry = None;
+                    tracing::info!("Generats {} schedules per {}", count, date);
+                    retries.remove(generation_time);
+                    if generated.get(generation_time).is_none_or(|last| *last < date) {
+                        generated.insert(*generation_time, date);
+                    }
                 }
                 Err(e) => {
                     tracing::error!(
-                        "Error generant schedules per demà: {}. Es reintentarà en {} minuts.",
+                        "Error generant schedules per {}: {}. Es reintentarà en {} minuts.",
+                        date,
                         e,
                         RETRY_INTERVAL_MINUTES
                     );
-                    retry_pending = true;
-                    last_retry = Some(now);
+                    retries.insert(*generation_time, (date, now));
                 }
             }
         }
+
+        // Els grups que ja no existeixen (canvi d'hora) es descarten
+        retries.retain(|time, _| buckets.contains_key(time));
+        generated.retain(|time, _| buckets.contains_key(time));
     }
 }
 
 /// Genera schedules per una data específica
+///
+/// Amb `user_ids`, només per les regles d'aquests usuaris. Els preus dels usuaris amb
+/// token de ESIOS propi s'obtenen amb el seu token; els de la resta, amb el global. Si
+/// falla algun usuari es retorna error perquè es reintenti (els ja generats no es dupliquen).
 async fn generate_schedules_for_date(
     pool: &PgPool,
     pvpc: &PvpcClient,
+    webhooks: &WebhookDispatcher,
+    events: &ScheduleEvents,
     date: chrono::NaiveDate,
+    user_ids: Option<&[Uuid]>,
+    config: &Config,
 ) -> Result<usize, String> {
-    let today = Local::now().date_naive();
+    // Totes les peticions a ESIOS de la generació duen el mateix identificador
+    let run_id = Uuid::new_v4();
+    let pvpc = pvpc.clone().with_request_id(Some(run_id));
+    tracing::debug!("Generant schedules per {} (request_id {})", date, run_id);
+
+    let groups = esios_client_groups(pool, &pvpc, config, user_ids)
+        .await
+        .map_err(|e| format!("Error obtenint els tokens de ESIOS: {}", e))?;
 
-    // Obtenir els preus per la data
-    let prices = if date == today {
-        pvpc.get_today_prices().await
-    } else if date == today + chrono::Duration::days(1) {
-        pvpc.get_tomorrow_prices().await
+    let mut count = 0;
+    let mut errors = Vec::new();
+    for (client, group_user_ids) in &groups {
+        match generate_schedules_with_client(pool, client, webhooks, events, date, group_user_ids.as_deref(), config.timezone)
+            .await
+        {
+            Ok(created) => count += created,
+            Err(e) => errors.push(e),
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(count)
     } else {
-        pvpc.get_prices_for_date(date).await
+        Err(errors.join("; "))
+    }
+}
+
+/// Usuaris a generar agrupats pel client de ESIOS amb què s'obtenen els seus preus
+///
+/// Cada usuari (de `user_ids`, o tots) amb regles actives i token propi té el seu grup; la
+/// resta comparteixen el client global. Sense cap token propi, un sol grup amb `user_ids`.
+async fn esios_client_groups(
+    pool: &PgPool,
+    pvpc: &PvpcClient,
+    config: &Config,
+    user_ids: Option<&[Uuid]>,
+) -> Result<Vec<(PvpcClient, Option<Vec<Uuid>>)>, sqlx::Error> {
+    let users = sqlx::query!(
+        r#"
+        SELECT u.id, u.esios_token
+        FROM users u
+        WHERE ($1::uuid[] IS NULL OR u.id = ANY($1))
+          AND EXISTS (
+              SELECT 1
+              FROM rules r
+              LEFT JOIN devices d ON r.device_id = d.id
+              LEFT JOIN device_groups g ON r.device_group_id = g.id
+              WHERE COALESCE(d.user_id, g.user_id) = u.id AND r.is_enabled
+          )
+        ORDER BY u.id
+        "#,
+        user_ids as Option<&[Uuid]>
+    )
+    .fetch_all(pool)
+    .await?;
+
+    let mut groups = Vec::new();
+    let mut shared = Vec::new();
+    for user in users {
+        let token = user.esios_token.and_then(|sealed| open_esios_token(config, user.id, &sealed));
+        match token {
+            Some(token) => groups.push((pvpc.with_user_token(Some(token)), Some(vec![user.id]))),
+            None => shared.push(user.id),
+        }
+    }
+
+    if groups.is_empty() {
+        groups.push((pvpc.clone(), user_ids.map(<[Uuid]>::to_vec)));
+    } else if !shared.is_empty() {
+        groups.push((pvpc.clone(), Some(shared)));
+    }
+
+    Ok(groups)
+}
+
+/// Genera schedules per una data amb els preus obtinguts amb `pvpc`
+///
+/// Amb `user_ids`, només per les regles d'aquests usuaris.
+async fn generate_schedules_with_client(
+    pool: &PgPool,
+    pvpc: &PvpcClient,
+    webhooks: &WebhookDispatcher,
+    events: &ScheduleEvents,
+    date: chrono::NaiveDate,
+    user_ids: Option<&[Uuid]>,
+    tz: Tz,
+) -> Result<usize, String> {
+    let now = clock::now(tz);
+    let today = now.date_naive();
+
+    // Obtenir els preus per la data (els errors queden registrats per l'estat dels preus)
+    let prices = match pvpc.get_prices_for_date(date).await {
+        Ok(prices) => prices,
+        Err(e) => {
+            if let Err(db_error) = record_fetch_failure(pool, date, &e.to_string()).await {
+                tracing::warn!("No s'ha pogut registrar l'error dels preus de {}: {}", date, db_error);
+            }
+            return Err(format!("Error obtenint preus: {:?}", e));
+        }
     };
 
-    let prices = prices.map_err(|e| format!("Error obtenint preus: {:?}", e))?;
+    // Un dia futur amb els preus a mitges es deixa per més tard: l'horari sortiria incomplet.
+    // L'error fa que es torni a provar al següent reintent.
+    if date > today && !pvpc.is_complete(&prices) {
+        return Err(format!("Preus incomplets per {}: {} hores", date, prices.prices.len()));
+    }
+
+    // Per avui, no crear accions per hores que ja han començat (es marcarien 'missed' de seguida)
+    let min_time = (date == today).then(|| now.time());
 
-    // Utilitzar la funció existent per generar schedules
-    // Però primer hem de modificar-la per acceptar una data i preus
-    let count = generate_schedule_with_prices(pool, &prices, date)
+    // Desar els preus a l'historial (no és crític si falla)
+    if let Err(e) = store_prices(pool, &prices).await {
+        tracing::warn!("No s'han pogut desar els preus de {} a l'historial: {}", date, e);
+    }
+    events.send(ScheduleEvent::PricesUpdated { date });
+
+    let count = generate_schedule_with_prices(pool, &prices, date, min_time, user_ids)
         .await
         .map_err(|e| format!("Error generant schedules: {:?}", e))?;
 
+    // Notificar els webhooks i clients connectats dels usuaris afectats
+    if count > 0 {
+        match user_ids {
+            None => {
+                webhooks.schedule_generated(pool, None, date);
+                events.send(ScheduleEvent::ScheduleGenerated { user_id: None, date });
+            }
+            Some(user_ids) => {
+                for user_id in user_ids {
+                    webhooks.schedule_generated(pool, Some(*user_id), date);
+                    events.send(ScheduleEvent::ScheduleGenerated { user_id: Some(*user_id), date });
+                }
+            }
+        }
+    }
+
     Ok(count)
 }
 
+/// Regla amb l'usuari propietari i el seu límit de dispositius simultanis
+#[derive(Debug, FromRow)]
+struct RuleWithOwner {
+    #[sqlx(flatten)]
+    rule: Rule,
+    user_id: Uuid,
+    max_concurrent_devices: Option<i32>,
+    energy_surcharge_eur_kwh: f64,
+    tax_multiplier: f64,
+}
+
 /// Genera schedules per una data amb preus ja obtinguts
+///
+/// Si hi ha `min_time`, se salten les hores que comencen abans o en aquell moment.
+/// Amb `user_ids`, només es generen les regles d'aquests usuaris.
 async fn generate_schedule_with_prices(
     pool: &PgPool,
     prices: &DailyPrices,
     date: chrono::NaiveDate,
+    min_time: Option<NaiveTime>,
+    user_ids: Option<&[Uuid]>,
 ) -> Result<usize, sqlx::Error> {
 
-    // Obtenir totes les regles actives
-    let rules = sqlx::query_as::<_, Rule>(
-        "SELECT * FROM rules WHERE is_enabled = true"
+    // Obtenir totes les regles actives amb el límit de dispositius del seu usuari
+    let rules = sqlx::query_as::<_, RuleWithOwner>(
+        r#"
+        SELECT r.*, lp.weights as load_profile, u.id as user_id, u.max_concurrent_devices, g.max_simultaneous,
+               u.energy_surcharge_eur_kwh, u.tax_multiplier,
+               ARRAY(SELECT rd.device_id FROM rule_devices rd WHERE rd.rule_id = r.id ORDER BY rd.device_id) as device_ids,
+               rule_wattage(r.device_id, r.device_group_id) as wattage_watts
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        JOIN users u ON COALESCE(d.user_id, g.user_id) = u.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE r.is_enabled = true AND ($1::uuid[] IS NULL OR u.id = ANY($1))
+        ORDER BY r.priority, r.created_at
+        "#
     )
+    .bind(user_ids)
     .fetch_all(pool)
     .await?;
 
     let mut created_count = 0;
     let rules_count = rules.len();
 
-    for rule in rules {
-        // Comprovar si el dia de la setmana està inclòs
-        let weekday = date.weekday();
-        let day_bit = match weekday {
-            chrono::Weekday::Mon => 1,
-            chrono::Weekday::Tue => 2,
-            chrono::Weekday::Wed => 4,
-            chrono::Weekday::Thu => 8,
-            chrono::Weekday::Fri => 16,
-            chrono::Weekday::Sat => 32,
-            chrono::Weekday::Sun => 64,
+    // Agrupar per usuari (les regles queden per prioritat: les de menys prioritat no
+    // arriben a crear accions en franges ja ocupades): els conflictes només es resolen entre regles del mateix usuari
+    let mut rules_by_user: HashMap<Uuid, (Option<i32>, PriceAdjustment, Vec<Rule>)> = HashMap::new();
+    for owned in rules {
+        let adjustment = PriceAdjustment {
+            surcharge_eur_kwh: owned.energy_surcharge_eur_kwh,
+            tax_multiplier: owned.tax_multiplier,
         };
+        rules_by_user
+            .entry(owned.user_id)
+            .or_insert_with(|| (owned.max_concurrent_devices, adjustment, Vec::new()))
+            .2
+            .push(owned.rule);
+    }
+
+    for (user_id, (max_concurrent_devices, adjustment, user_rules)) in rules_by_user {
+        // Quarts d'hora si els preus són quart-horaris, amb els impostos i càrrecs de l'usuari
+        let slots = PriceSlots::from_day(&adjustment.apply(prices.clone()));
+
+        // Una generació simultània del mateix usuari (p. ex. manual) acaba abans de començar aquesta.
+        // Tot es fa dins la transacció del bloqueig: les accions i els motius es desen de cop.
+        let mut lock = pool.begin().await?;
+        lock_schedule_generation(&mut lock, user_id, date).await?;
+
+        let mut plans = Vec::new();
+        // Regles que no programen cap hora, amb el motiu
+        let mut skipped: HashMap<Uuid, SkipReason> = HashMap::new();
+
+        for rule in &user_rules {
+            // Comprovar si el dia de la setmana està inclòs i si és dins la temporada
+            if let Some(reason) = SkipReason::for_day(rule, date) {
+                skipped.insert(rule.id, reason);
+                continue; // Aquesta regla no s'aplica aquest dia
+            }
+
+            // Calcular les hores òptimes
+            let optimal = optimal_hours_for_rule(&slots, rule);
+            if optimal.hours.is_empty() {
+                skipped.insert(rule.id, SkipReason::NoEligibleHours);
+                continue;
+            }
 
-        if (rule.days_of_week & day_bit) == 0 {
-            continue; // Aquesta regla no s'aplica aquest dia
+            // Porta "només si és més barat que els darrers dies"
+            if !passes_baseline_gate(&mut lock, rule, date, &optimal).await? {
+                skipped.insert(rule.id, SkipReason::AboveBaseline);
+                continue;
+            }
+
+            // Si el grup té un màxim de dispositius alhora, cada tanda de dispositius té les seves hores
+            let mut upcoming = false;
+            for mut plan in stagger_group_hours(&slots, rule, optimal.hours) {
+                plan.hours = upcoming_hours(plan.hours, &slots, min_time);
+                upcoming |= !plan.hours.is_empty();
+                plans.push(plan);
+            }
+            if !upcoming {
+                skipped.insert(rule.id, SkipReason::HoursPassed);
+            }
         }
 
-        // Calcular les hores òptimes
-        let optimal = calculate_optimal_hours(
-            &prices.prices,
-            rule.max_hours,
-            rule.min_continuous_hours,
-            rule.time_window_start,
-            rule.time_window_end,
-        );
+        // Respectar el màxim de dispositius simultanis segons la prioritat
+        resolve_conflicts(&user_rules, &mut plans, max_concurrent_devices);
 
-        // Crear scheduled_actions per cada hora
-        for hour in &optimal.hours {
-            let start_time = NaiveTime::from_hms_opt(*hour as u32, 0, 0).unwrap();
-            // end_time és sempre l'hora següent (00:00 per l'hora 23)
-            // Quan start_time > end_time, significa que l'acció creua mitjanit
-            // L'Android i el backend han de tractar aquest cas especialment
-            let end_time = NaiveTime::from_hms_opt(((*hour + 1) % 24) as u32, 0, 0).unwrap();
+        // Les regles que tenien hores i les han perdut totes
+        for rule in &user_rules {
+            if !plans.iter().any(|p| p.rule_id == rule.id && !p.hours.is_empty()) {
+                skipped.entry(rule.id).or_insert(SkipReason::DeviceLimit);
+            }
+        }
 
-            let price = prices.prices.iter()
-                .find(|p| p.hour == *hour)
-                .map(|p| p.price);
+        for plan in plans {
+            let Some(rule) = user_rules.iter().find(|r| r.id == plan.rule_id) else {
+                continue;
+            };
 
-            let result = sqlx::query(
-                r#"
-                INSERT INTO scheduled_actions (rule_id, scheduled_date, start_time, end_time, price_per_kwh, status)
-                VALUES ($1, $2, $3, $4, $5, 'pending')
-                ON CONFLICT (rule_id, scheduled_date, start_time) DO NOTHING
-                "#
-            )
-            .bind(rule.id)
-            .bind(date)
-            .bind(start_time)
-            .bind(end_time)
-            .bind(price)
-            .execute(pool)
-            .await?;
-
-            if result.rows_affected() > 0 {
-                created_count += 1;
+            // Crear scheduled_actions per cada hora
+            for hour in &plan.hours {
+                let start_time = slots.start_time(*hour);
+                // end_time és sempre l'inici de la franja següent; l'última del dia acaba a les
+                // 00:00 de l'endemà i es desa amb crosses_midnight
+                let end = slots.end_time(*hour);
+
+                let price = slots.price(*hour);
+
+                // Una acció per cada dispositiu de la regla (més d'una si és d'un grup)
+                let devices = plan.device_ids.as_deref();
+                created_count += insert_rule_actions(&mut lock, rule, devices, date, start_time, end, price).await?;
             }
         }
+
+        for rule in &user_rules {
+            record_skip(&mut lock, rule.id, date, skipped.get(&rule.id).copied()).await?;
+        }
+
+        // Regles d'igual prioritat que han programat el mateix dispositiu a la mateixa hora
+        let rule_ids: Vec<Uuid> = user_rules.iter().map(|r| r.id).collect();
+        for conflict in find_conflicts(&mut lock, &rule_ids, date).await? {
+            tracing::warn!(
+                "Conflicte de schedules el {} pel dispositiu {}: hores {:?} programades per les regles {:?}",
+                conflict.date,
+                conflict.device_id,
+                conflict.conflicting_hours,
+                conflict.rule_ids
+            );
+        }
+
+        lock.commit().await?;
     }
 
     tracing::info!(
@@ -285,52 +634,112 @@ async fn generate_schedule_with_prices(
         date
     );
 
+    metrics::record_schedules_generated(created_count);
+
     Ok(created_count)
 }
 
-/// Comprova cada minut si hi ha accions pendents que ja han expirat i les marca com 'missed'
-async fn run_expired_actions_checker(pool: Arc<PgPool>) {
+/// Descarta les hores que ja han començat a `min_time`
+fn upcoming_hours(hours: Vec<u8>, slots: &PriceSlots, min_time: Option<NaiveTime>) -> Vec<u8> {
+    let Some(min) = min_time else {
+        return hours;
+    };
+
+    hours
+        .into_iter()
+        .filter(|hour| slots.start_time(*hour) > min)
+        .collect()
+}
+
+/// Envia el resum dels darrers 7 dies cada `WEEKLY_SUMMARY_DAY` a les `WEEKLY_SUMMARY_HOUR`
+async fn run_weekly_summaries(pool: Arc<PgPool>, notifier: Arc<dyn Notifier>, tz: Tz) {
+    let summary_time = NaiveTime::from_hms_opt(WEEKLY_SUMMARY_HOUR, 0, 0).unwrap();
     let mut check_interval = interval(Duration::from_secs(CHECK_INTERVAL_SECONDS));
+    let mut previous_check = clock::now(tz).naive_local();
 
     loop {
         check_interval.tick().await;
 
-        if let Err(e) = mark_expired_actions_as_missed(&pool).await {
+        let now = clock::now(tz);
+        let due = now.weekday() == WEEKLY_SUMMARY_DAY
+            && time_reached(summary_time, previous_check, now.naive_local());
+        previous_check = now.naive_local();
+        if !due {
+            continue;
+        }
+
+        // La setmana acaba ahir: avui encara hi ha accions pendents
+        let yesterday = now.date_naive() - chrono::Duration::days(1);
+        match send_weekly_summaries(&pool, notifier.as_ref(), yesterday).await {
+            Ok(sent) => tracing::info!("Enviats {} resums setmanals (fins a {})", sent, yesterday),
+            Err(e) => tracing::error!("Error enviant els resums setmanals: {}", e),
+        }
+    }
+}
+
+/// Comprova cada minut si hi ha accions pendents que ja han expirat i les marca com 'missed',
+/// i esborra els refresh tokens caducats
+async fn run_expired_actions_checker(pool: Arc<PgPool>, tz: Tz) {
+    let mut check_interval = interval(Duration::from_secs(CHECK_INTERVAL_SECONDS));
+
+    loop {
+        check_interval.tick().await;
+
+        if let Err(e) = mark_expired_actions_as_missed(&pool, clock::now(tz).naive_local()).await {
             tracing::error!("Error marcant accions expirades: {}", e);
         }
+
+        if let Err(e) = delete_expired_refresh_tokens(&pool).await {
+            tracing::error!("Error esborrant refresh tokens caducats: {}", e);
+        }
     }
 }
 
+/// Esborra els refresh tokens caducats, revocats o no. Cada renovació en revoca un i en crea
+/// un altre: sense això la taula creixeria per sempre. Els revocats es guarden fins que caduquen
+/// perquè tornar-los a presentar es detecti com un reús (i es tanquin les sessions).
+async fn delete_expired_refresh_tokens(pool: &PgPool) -> Result<(), sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM refresh_tokens WHERE expires_at <= NOW()")
+        .execute(pool)
+        .await?;
+
+    if result.rows_affected() > 0 {
+        tracing::info!("Esborrats {} refresh tokens caducats", result.rows_affected());
+    }
+
+    Ok(())
+}
+
 /// Marca les accions pendents que ja han passat la seva hora end_time com a 'missed'
 ///
 /// Lògica:
-/// - Accions normals (ex: 10:00-14:00, start < end): es marquen com missed quan current_time >= end_time
-/// - Accions que creuen mitjanit (ex: 23:00-00:00, start > end): NO es marquen com missed el mateix dia,
-///   sinó quan el dia següent arriba (scheduled_date < today)
+/// - Accions normals (ex: 10:00-14:00): es marquen com missed quan current_time >= end_time
+/// - Accions que creuen mitjanit (`crosses_midnight`, ex: 23:00-00:00): NO es marquen com missed el mateix
+///   dia, sinó quan el dia següent arriba (scheduled_date < today)
 ///
 /// Això és consistent amb la lògica de l'app Android (ScheduleExecutionWorker.markMissedActionsAsFailed)
-async fn mark_expired_actions_as_missed(pool: &PgPool) -> Result<(), sqlx::Error> {
-    let now = Local::now();
-    let today = now.date_naive();
+async fn mark_expired_actions_as_missed(pool: &PgPool, now: NaiveDateTime) -> Result<(), sqlx::Error> {
+    let today = now.date();
     let current_time = now.time();
 
-    // Cas 1: Accions normals d'avui (end_time > start_time) que ja han acabat
+    // Cas 1: Accions normals d'avui (que no creuen mitjanit) que ja han acabat
     // Ex: 10:00-14:00 i ara són les 15:00 → missed
-    let result = sqlx::query(
+    let result = sqlx::query!(
         r#"
         UPDATE scheduled_actions
         SET status = 'missed'
         WHERE status = 'pending'
           AND scheduled_date = $1
-          AND end_time > start_time
+          AND NOT crosses_midnight
           AND end_time <= $2
-        "#
+        "#,
+        today,
+        current_time
     )
-    .bind(today)
-    .bind(current_time)
     .execute(pool)
     .await?;
 
+    metrics::record_actions_missed(result.rows_affected());
     if result.rows_affected() > 0 {
         tracing::info!(
             "Marcades {} accions normals com a 'missed' (data: {}, hora actual: {})",
@@ -343,18 +752,19 @@ async fn mark_expired_actions_as_missed(pool: &PgPool) -> Result<(), sqlx::Error
     // Cas 2: Accions de dies anteriors que encara estiguin pendents
     // Inclou tant accions normals com les que creuaven mitjanit
     // Ex: Acció de ahir 23:00-00:00 que no es va executar → missed
-    let result_old = sqlx::query(
+    let result_old = sqlx::query!(
         r#"
         UPDATE scheduled_actions
         SET status = 'missed'
         WHERE status = 'pending'
           AND scheduled_date < $1
-        "#
+        "#,
+        today
     )
-    .bind(today)
     .execute(pool)
     .await?;
 
+    metrics::record_actions_missed(result_old.rows_affected());
     if result_old.rows_affected() > 0 {
         tracing::info!(
             "Marcades {} accions de dies anteriors com a 'missed'",
@@ -364,3 +774,569 @@ async fn mark_expired_actions_as_missed(pool: &PgPool) -> Result<(), sqlx::Error
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use shared::HourlyPrice;
+
+    use crate::test_utils::{create_device, create_rule, create_user, test_config};
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn test_upcoming_hours() {
+        let slots = PriceSlots::hourly(&[]);
+        let hours = vec![3, 4, 15, 16, 17];
+        assert_eq!(upcoming_hours(hours.clone(), &slots, None), hours);
+        // L'hora en curs ja no es programa
+        assert_eq!(upcoming_hours(hours.clone(), &slots, Some(time(15, 30))), vec![16, 17]);
+        assert_eq!(upcoming_hours(hours, &slots, Some(time(16, 0))), vec![17]);
+
+        // Amb quarts d'hora, el de 15:30 (62) ja ha començat i només queda el de 15:45
+        let slots = PriceSlots { prices: vec![], slot_minutes: 15 };
+        assert_eq!(upcoming_hours(vec![61, 62, 63], &slots, Some(time(15, 30))), vec![63]);
+    }
+
+    #[test]
+    fn test_time_reached() {
+        let day = chrono::NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        let at = |hour, minute| day.and_time(time(hour, minute));
+        let next_day = |hour, minute| day.succ_opt().unwrap().and_time(time(hour, minute));
+
+        assert!(time_reached(time(20, 30), at(20, 29), at(20, 30)));
+        assert!(time_reached(time(20, 30), at(20, 29), at(20, 31)));
+        assert!(!time_reached(time(20, 30), at(20, 30), at(20, 31)));
+        assert!(!time_reached(time(19, 30), at(20, 29), at(20, 30)));
+        // Comprovacions a banda i banda de mitjanit
+        assert!(time_reached(time(0, 0), at(23, 59), next_day(0, 0)));
+        assert!(time_reached(time(23, 59), at(23, 58), next_day(0, 1)));
+        assert!(!time_reached(time(12, 0), at(23, 59), next_day(0, 1)));
+    }
+
+    #[test]
+    fn test_time_reached_across_dst_changes() {
+        let local = |utc: &str| {
+            utc.parse::<chrono::DateTime<chrono::Utc>>().unwrap().with_timezone(&clock::DEFAULT_TIMEZONE)
+        };
+
+        // Hora d'estiu: de les 01:59 a les 03:00 locals. Les 02:30 no existeixen però arriben.
+        let (previous, now) = (local("2024-03-31T00:59:00Z"), local("2024-03-31T01:00:00Z"));
+        assert_eq!((previous.time(), now.time()), (time(1, 59), time(3, 0)));
+        assert!(time_reached(time(2, 30), previous.naive_local(), now.naive_local()));
+        assert!(!time_reached(time(3, 1), previous.naive_local(), now.naive_local()));
+
+        // Hora d'hivern: de les 02:59 a les 02:00 locals. No és un pas de mitjanit: no arriba
+        // cap hora (ni les de la tarda, ni les 02:30 per segona vegada).
+        let (previous, now) = (local("2024-10-27T00:59:00Z"), local("2024-10-27T01:00:00Z"));
+        assert_eq!((previous.time(), now.time()), (time(2, 59), time(2, 0)));
+        assert!(!time_reached(time(20, 30), previous.naive_local(), now.naive_local()));
+        assert!(!time_reached(time(9, 0), previous.naive_local(), now.naive_local()));
+        assert!(!time_reached(time(2, 30), previous.naive_local(), now.naive_local()));
+    }
+
+    #[test]
+    fn test_generation_due() {
+        let day = chrono::NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        let tomorrow = day.succ_opt().unwrap();
+        let at = |hour, minute| day.and_time(time(hour, minute));
+
+        assert!(!generation_due(time(20, 30), at(20, 29), None));
+        assert!(generation_due(time(20, 30), at(20, 30), None));
+        // Qualsevol comprovació posterior, fins que es genera
+        assert!(generation_due(time(20, 30), at(23, 59), Some(day)));
+        assert!(!generation_due(time(20, 30), at(20, 31), Some(tomorrow)));
+        // Passada mitjanit, demà és un altre dia i encara no toca
+        let next_day = tomorrow.and_time(time(0, 1));
+        assert!(!generation_due(time(20, 30), next_day, Some(tomorrow)));
+        assert!(generation_due(time(0, 0), next_day, Some(tomorrow)));
+    }
+
+    #[test]
+    fn test_generation_fires_once_when_ticks_straddle_the_time() {
+        let day = chrono::NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        let ticks = [
+            day.and_hms_opt(20, 29, 59).unwrap(),
+            day.and_hms_opt(20, 31, 0).unwrap(),
+            day.and_hms_opt(20, 32, 0).unwrap(),
+            day.and_hms_opt(23, 59, 30).unwrap(),
+            day.succ_opt().unwrap().and_hms_opt(0, 0, 30).unwrap(),
+        ];
+
+        let mut last_generated = None;
+        let mut fired = vec![];
+        for now in ticks {
+            if generation_due(time(20, 30), now, last_generated) {
+                fired.push(now);
+                last_generated = Some(now.date() + chrono::Duration::days(1));
+            }
+        }
+        assert_eq!(fired, vec![ticks[1]]);
+    }
+
+    #[test]
+    fn test_generation_due_across_dst_changes() {
+        let local = |utc: &str| {
+            utc.parse::<chrono::DateTime<chrono::Utc>>().unwrap().with_timezone(&clock::DEFAULT_TIMEZONE)
+        };
+
+        // Hora d'estiu: de les 01:59 a les 03:00 locals. Les 02:30 no existeixen però arriben.
+        let (previous, now) = (local("2024-03-31T00:59:00Z"), local("2024-03-31T01:00:00Z"));
+        assert_eq!((previous.time(), now.time()), (time(1, 59), time(3, 0)));
+        assert!(!generation_due(time(2, 30), previous.naive_local(), None));
+        assert!(generation_due(time(2, 30), now.naive_local(), None));
+        assert!(!generation_due(time(3, 1), now.naive_local(), None));
+
+        // Hora d'hivern: de les 02:59 a les 02:00 locals. Les 02:30 es repeteixen però ja s'han generat.
+        let (first, second) = (local("2024-10-27T00:30:00Z"), local("2024-10-27T01:30:00Z"));
+        assert_eq!((first.time(), second.time()), (time(2, 30), time(2, 30)));
+        let tomorrow = first.date_naive().succ_opt().unwrap();
+        assert!(generation_due(time(2, 30), first.naive_local(), None));
+        assert!(!generation_due(time(2, 30), second.naive_local(), Some(tomorrow)));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_generation_buckets_by_user_time(pool: PgPool) {
+        let peninsula = create_user(&pool, "peninsula").await;
+        let canaries = create_user(&pool, "canaries").await;
+        let without_rules = create_user(&pool, "buit").await;
+        sqlx::query("UPDATE users SET schedule_gen_time = '19:30' WHERE id = ANY($1)")
+            .bind(vec![canaries.id, without_rules.id])
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let mut rules = Vec::new();
+        for user in [&peninsula, &canaries] {
+            let device = create_device(&pool, user.id, "Termo").await;
+            rules.push(create_rule(&pool, device.id, "Nit", true).await);
+        }
+
+        let buckets = generation_buckets(&pool, time(20, 30)).await.unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[&time(20, 30)], vec![peninsula.id]);
+        assert_eq!(buckets[&time(19, 30)], vec![canaries.id]);
+
+        // Només es generen les regles dels usuaris del grup
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let prices = DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| HourlyPrice { hour, price: 0.10 + hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+        let created = generate_schedule_with_prices(&pool, &prices, date, None, Some(&buckets[&time(19, 30)]))
+            .await
+            .unwrap();
+        assert_eq!(created, 2);
+
+        let generated: Vec<Uuid> = sqlx::query_scalar("SELECT DISTINCT rule_id FROM scheduled_actions")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(generated, vec![rules[1].id]);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_partial_tomorrow_prices_defer_generation(pool: PgPool) {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        use crate::services::price_history::get_prices_between;
+
+        let config = test_config();
+        let tomorrow = clock::today(config.timezone) + chrono::Duration::days(1);
+        let esios_hours = |hours: u32| {
+            let values: Vec<_> = (0..hours)
+                .map(|hour| {
+                    let datetime = format!("{}T{:02}:00:00.000+01:00", tomorrow, hour);
+                    serde_json::json!({ "value": 100.0 + f64::from(hour), "datetime": datetime, "geo_id": 8741 })
+                })
+                .collect();
+            ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": values } }))
+        };
+
+        let user = create_user(&pool, "partial").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        create_rule(&pool, device.id, "Nit", true).await;
+
+        let server = MockServer::start().await;
+        let pvpc = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let webhooks = WebhookDispatcher::new(reqwest::Client::new());
+        let events = ScheduleEvents::new();
+        let scheduled = || async {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM scheduled_actions WHERE scheduled_date = $1")
+                .bind(tomorrow)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+        };
+
+        // ESIOS només ha publicat 12 hores: no es genera res ni es desen els preus a l'historial
+        Mock::given(method("GET")).respond_with(esios_hours(12)).mount(&server).await;
+        let result = generate_schedules_for_date(&pool, &pvpc, &webhooks, &events, tomorrow, None, &config).await;
+        assert!(result.unwrap_err().contains("incomplets"));
+        assert_eq!(scheduled().await, 0);
+        assert!(get_prices_between(&pool, tomorrow, tomorrow).await.unwrap().is_empty());
+
+        // Amb un llindar més baix, 12 hores ja n'hi ha prou
+        let lenient = pvpc.clone().with_min_tomorrow_hours(12);
+        assert!(generate_schedules_for_date(&pool, &lenient, &webhooks, &events, tomorrow, None, &config).await.is_ok());
+        sqlx::query("DELETE FROM scheduled_actions").execute(&pool).await.unwrap();
+
+        // Quan hi són totes, el reintent genera l'horari
+        server.reset().await;
+        Mock::given(method("GET")).respond_with(esios_hours(24)).mount(&server).await;
+        let created = generate_schedules_for_date(&pool, &pvpc, &webhooks, &events, tomorrow, None, &config).await.unwrap();
+        assert!(created > 0);
+        assert_eq!(scheduled().await as usize, created);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_generation_uses_owner_esios_token(pool: PgPool) {
+        use wiremock::matchers::header;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        use crate::services::secrets::SecretBox;
+
+        let config = test_config();
+        let tomorrow = clock::today(config.timezone) + chrono::Duration::days(1);
+        let values: Vec<_> = (0..24)
+            .map(|hour| {
+                let datetime = format!("{}T{:02}:00:00.000+01:00", tomorrow, hour);
+                serde_json::json!({ "value": 100.0 + f64::from(hour), "datetime": datetime, "geo_id": 8741 })
+            })
+            .collect();
+
+        // Només el token de l'usuari és vàlid: el global el rebutja ESIOS
+        let server = MockServer::start().await;
+        Mock::given(header("x-api-key", "user-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": values } })))
+            .mount(&server)
+            .await;
+        Mock::given(header("x-api-key", "global-token"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+
+        let owner = create_user(&pool, "owner").await;
+        let sealed = SecretBox::from_config(&config).unwrap().encrypt("user-token");
+        sqlx::query("UPDATE users SET esios_token = $1 WHERE id = $2")
+            .bind(sealed)
+            .bind(owner.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let device = create_device(&pool, owner.id, "Termo").await;
+        create_rule(&pool, device.id, "Nit", true).await;
+
+        let other = create_user(&pool, "other").await;
+        let other_device = create_device(&pool, other.id, "Rentadora").await;
+        create_rule(&pool, other_device.id, "Nit", true).await;
+
+        let pvpc = PvpcClient::with_token("global-token".to_string())
+            .with_base_url(server.uri())
+            .with_retries(0, Duration::ZERO);
+        let webhooks = WebhookDispatcher::new(reqwest::Client::new());
+        let events = ScheduleEvents::new();
+        let scheduled = |user_id: Uuid| {
+            let pool = &pool;
+            async move {
+                sqlx::query_scalar::<_, i64>(
+                    "SELECT COUNT(*) FROM scheduled_actions sa JOIN devices d ON sa.device_id = d.id WHERE d.user_id = $1",
+                )
+                .bind(user_id)
+                .fetch_one(pool)
+                .await
+                .unwrap()
+            }
+        };
+
+        // L'usuari amb token propi genera l'horari; el que depèn del global falla i es reintentarà
+        let result = generate_schedules_for_date(&pool, &pvpc, &webhooks, &events, tomorrow, None, &config).await;
+        assert!(result.is_err());
+        assert!(scheduled(owner.id).await > 0);
+        assert_eq!(scheduled(other.id).await, 0);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_generate_today_mid_afternoon_skips_past_hours(pool: PgPool) {
+        let user = create_user(&pool, "today").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Barates", true).await;
+        sqlx::query("UPDATE rules SET max_hours = 4 WHERE id = $1")
+            .bind(rule.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Les hores més barates són dues de matinada i dues de tarda
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let prices = DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| {
+                    let price = if [3, 4, 16, 17].contains(&hour) { 0.05 } else { 0.20 };
+                    HourlyPrice { hour, price, raw_price: None }
+                })
+                .collect(),
+            quarter_hours: vec![],
+        };
+
+        let created = generate_schedule_with_prices(&pool, &prices, date, Some(time(15, 30)), None).await.unwrap();
+        assert_eq!(created, 2);
+
+        let starts: Vec<NaiveTime> = sqlx::query_scalar(
+            "SELECT start_time FROM scheduled_actions WHERE rule_id = $1 ORDER BY start_time"
+        )
+        .bind(rule.id)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(starts, vec![time(16, 0), time(17, 0)]);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_higher_priority_rule_keeps_shared_cheap_hours(pool: PgPool) {
+        let user = create_user(&pool, "priority").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let urgent = create_rule(&pool, device.id, "Urgent", true).await;
+        let normal = create_rule(&pool, device.id, "Normal", true).await;
+        sqlx::query("UPDATE rules SET priority = 2 WHERE id = $1")
+            .bind(normal.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // Totes dues regles volen les dues hores més barates (0 i 1)
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let prices = DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| HourlyPrice { hour, price: 0.10 + hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+
+        let created = generate_schedule_with_prices(&pool, &prices, date, None, None).await.unwrap();
+        assert_eq!(created, 2);
+
+        let rules: Vec<Uuid> = sqlx::query_scalar("SELECT DISTINCT rule_id FROM scheduled_actions")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(rules, vec![urgent.id]);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_rules_outside_season_generate_nothing(pool: PgPool) {
+        let user = create_user(&pool, "season").await;
+        let device = create_device(&pool, user.id, "Depuradora").await;
+        let summer = create_rule(&pool, device.id, "Estiu", true).await;
+        let winter = create_rule(&pool, device.id, "Hivern", true).await;
+        sqlx::query(
+            r#"
+            UPDATE rules SET
+                active_from = CASE WHEN id = $1 THEN DATE '2030-06-01' ELSE DATE '2030-11-01' END,
+                active_until = CASE WHEN id = $1 THEN DATE '2030-09-30' ELSE NULL END
+            "#
+        )
+        .bind(summer.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 7, 15).unwrap();
+        let prices = DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| HourlyPrice { hour, price: 0.10 + hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+
+        let created = generate_schedule_with_prices(&pool, &prices, date, None, None).await.unwrap();
+        assert_eq!(created, 2);
+
+        let count = |rule_id: Uuid| {
+            sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM scheduled_actions WHERE rule_id = $1")
+                .bind(rule_id)
+                .fetch_one(&pool)
+        };
+        assert_eq!(count(summer.id).await.unwrap(), 2);
+        assert_eq!(count(winter.id).await.unwrap(), 0);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_each_skip_reason_is_recorded(pool: PgPool) {
+        let user = create_user(&pool, "skips").await;
+        sqlx::query("UPDATE users SET max_concurrent_devices = 1 WHERE id = $1")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let mut rules = Vec::new();
+        for name in ["Dilluns", "Estiu", "Car", "Referencia", "Primera", "Segona"] {
+            let device = create_device(&pool, user.id, name).await;
+            rules.push(create_rule(&pool, device.id, name, true).await);
+        }
+        let set = |sql: &'static str, rule: &Rule| sqlx::query(sql).bind(rule.id).execute(&pool);
+        set("UPDATE rules SET days_of_week = 1 WHERE id = $1", &rules[0]).await.unwrap();
+        set("UPDATE rules SET active_from = DATE '2030-06-01' WHERE id = $1", &rules[1]).await.unwrap();
+        set("UPDATE rules SET max_cost_eur = 0.01 WHERE id = $1", &rules[2]).await.unwrap();
+        set("UPDATE devices SET wattage_watts = 2000 WHERE id = (SELECT device_id FROM rules WHERE id = $1)", &rules[2])
+            .await
+            .unwrap();
+        set("UPDATE rules SET baseline_days = 1 WHERE id = $1", &rules[3]).await.unwrap();
+
+        // Dimarts; el dia abans va ser molt més barat
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let day = |date, base: f64| DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| HourlyPrice { hour, price: base + hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+        store_prices(&pool, &day(date.pred_opt().unwrap(), 0.01)).await.unwrap();
+        generate_schedule_with_prices(&pool, &day(date, 0.10), date, None, Some(&[user.id])).await.unwrap();
+
+        // Una generació a última hora del dia, d'un altre usuari
+        let late = create_user(&pool, "tard").await;
+        let device = create_device(&pool, late.id, "Termo").await;
+        let late_rule = create_rule(&pool, device.id, "Tard", true).await;
+        generate_schedule_with_prices(&pool, &day(date, 0.10), date, Some(time(23, 30)), Some(&[late.id]))
+            .await
+            .unwrap();
+
+        let reason = |rule_id: Uuid| {
+            sqlx::query_scalar::<_, SkipReason>(
+                "SELECT reason FROM skipped_actions WHERE rule_id = $1 AND scheduled_date = $2"
+            )
+            .bind(rule_id)
+            .bind(date)
+            .fetch_optional(&pool)
+        };
+        assert_eq!(reason(rules[0].id).await.unwrap(), Some(SkipReason::NotScheduledDay));
+        assert_eq!(reason(rules[1].id).await.unwrap(), Some(SkipReason::OutOfSeason));
+        assert_eq!(reason(rules[2].id).await.unwrap(), Some(SkipReason::NoEligibleHours));
+        assert_eq!(reason(rules[3].id).await.unwrap(), Some(SkipReason::AboveBaseline));
+        assert_eq!(reason(rules[4].id).await.unwrap(), None);
+        assert_eq!(reason(rules[5].id).await.unwrap(), Some(SkipReason::DeviceLimit));
+        assert_eq!(reason(late_rule.id).await.unwrap(), Some(SkipReason::HoursPassed));
+
+        // Si una generació posterior programa hores, el motiu s'esborra
+        set("UPDATE rules SET days_of_week = 127 WHERE id = $1", &rules[0]).await.unwrap();
+        sqlx::query("UPDATE users SET max_concurrent_devices = NULL WHERE id = $1")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        generate_schedule_with_prices(&pool, &day(date, 0.10), date, None, Some(&[user.id])).await.unwrap();
+        assert_eq!(reason(rules[0].id).await.unwrap(), None);
+        assert_eq!(reason(rules[5].id).await.unwrap(), None);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_hour_23_action_ends_at_midnight(pool: PgPool) {
+        let user = create_user(&pool, "mitjanit").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        sqlx::query("UPDATE rules SET max_hours = 1 WHERE id = $1")
+            .bind(rule.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // L'hora més barata és la darrera del dia
+        let date = chrono::NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let prices = DailyPrices {
+            date,
+            prices: (0..24)
+                .map(|hour| HourlyPrice { hour, price: 0.40 - hour as f64 / 100.0, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        };
+        generate_schedule_with_prices(&pool, &prices, date, None, None).await.unwrap();
+
+        let action = |rule_id: Uuid| {
+            sqlx::query_as::<_, (NaiveTime, NaiveTime, bool, String)>(
+                "SELECT start_time, end_time, crosses_midnight, status FROM scheduled_actions WHERE rule_id = $1"
+            )
+            .bind(rule_id)
+            .fetch_one(&pool)
+        };
+        assert_eq!(action(rule.id).await.unwrap(), (time(23, 0), NaiveTime::MIN, true, "pending".to_string()));
+
+        // Una acció normal del mateix dia sí que expira a les 23:30
+        let other = create_rule(&pool, device.id, "Tarda", false).await;
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, $3, '22:00', '23:00')
+            "#
+        )
+        .bind(other.id)
+        .bind(device.id)
+        .bind(date)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        mark_expired_actions_as_missed(&pool, date.and_time(time(23, 30))).await.unwrap();
+        assert_eq!(action(rule.id).await.unwrap().3, "pending");
+        assert_eq!(action(other.id).await.unwrap().3, "missed");
+
+        let next_day = date.succ_opt().unwrap().and_time(time(0, 5));
+        mark_expired_actions_as_missed(&pool, next_day).await.unwrap();
+        assert_eq!(action(rule.id).await.unwrap().3, "missed");
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_expired_refresh_tokens_are_deleted(pool: PgPool) {
+        let user = create_user(&pool, "sessions").await;
+        for (hash, expires_in, revoked) in [
+            ("expired", "-1 hour", false),
+            ("rotated-expired", "-1 hour", true),
+            ("rotated", "1 day", true),
+            ("active", "1 day", false),
+        ] {
+            sqlx::query(
+                r#"
+                INSERT INTO refresh_tokens (user_id, token_hash, expires_at, revoked_at)
+                VALUES ($1, $2, NOW() + $3::interval, CASE WHEN $4 THEN NOW() END)
+                "#
+            )
+            .bind(user.id)
+            .bind(hash)
+            .bind(expires_in)
+            .bind(revoked)
+            .execute(&pool)
+            .await
+            .unwrap();
+        }
+
+        delete_expired_refresh_tokens(&pool).await.unwrap();
+
+        // Els revocats que no han caducat es queden per detectar-ne el reús
+        let remaining: Vec<String> =
+            sqlx::query_scalar("SELECT token_hash FROM refresh_tokens ORDER BY token_hash")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        assert_eq!(remaining, vec!["active", "rotated"]);
+    }
+}
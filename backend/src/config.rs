@@ -1,13 +1,55 @@
 use std::env;
 
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+
+use crate::services::clock::DEFAULT_TIMEZONE;
+use crate::services::energy_tariff::GeoZone;
+use crate::services::secrets::MIN_KEY_LEN;
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub database_url: String,
     pub jwt_secret: String,
+    /// Durada de l'access token (segons)
+    pub jwt_access_ttl: i64,
+    /// Durada del refresh token (segons)
+    pub jwt_refresh_ttl: i64,
     pub google_client_id: String,
+    /// Identificadors (bundle ID de l'app iOS i Services ID del web) acceptats com a `aud` dels
+    /// tokens de "Sign in with Apple". Si no n'hi ha, el login amb Apple està desactivat.
+    pub apple_client_ids: Vec<String>,
     pub server_host: String,
     pub server_port: u16,
     pub allowed_origins: Vec<String>,
+    /// Dies endavant per als quals es poden calcular horaris (preus publicats)
+    pub price_horizon_days: i64,
+    /// Dies enrere màxims per als càlculs sobre preus històrics
+    pub price_history_days: i64,
+    /// Token per llegir `/metrics`. Si no n'hi ha, l'endpoint és públic.
+    pub metrics_token: Option<String>,
+    /// `max_hours` de les regles que no l'indiquen. Si no n'hi ha, és obligatori.
+    pub default_max_hours: Option<i32>,
+    /// `min_continuous_hours` de les regles que no l'indiquen
+    pub default_min_continuous: i32,
+    /// Emails (en minúscules) que es promouen a administrador en iniciar sessió
+    pub admin_emails: Vec<String>,
+    /// Clau per xifrar els secrets dels usuaris. Sense clau, no poden desar el seu token de ESIOS.
+    pub encryption_key: Option<String>,
+    /// Zona horària de les dates i hores programades i dels preus (vegeu `services::clock`)
+    pub timezone: Tz,
+    /// Zona del subministrament, per als períodes del peatge 2.0TD (vegeu `services::energy_tariff`)
+    pub geo_zone: GeoZone,
+    /// Hora local a la qual es generen els schedules de demà (si l'usuari no n'ha triat una altra)
+    pub schedule_generation_hour: u8,
+    pub schedule_generation_minute: u8,
+    /// Servidor SMTP per enviar el resum setmanal. Sense servidor, no s'envia cap correu.
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_username: Option<String>,
+    pub smtp_password: Option<String>,
+    /// Remitent dels correus (ex: "PVPC Cheap <noreply@example.com>")
+    pub smtp_from: Option<String>,
 }
 
 impl Config {
@@ -20,20 +62,200 @@ impl Config {
             .filter(|s| !s.is_empty())
             .collect();
 
+        let apple_client_ids = env::var("APPLE_CLIENT_IDS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect();
+
+        let admin_emails = parse_emails(&env::var("ADMIN_EMAILS").unwrap_or_default());
+
         Ok(Self {
             database_url: env::var("DATABASE_URL")?,
             jwt_secret: env::var("JWT_SECRET")?,
+            jwt_access_ttl: env::var("JWT_ACCESS_TTL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(24 * 3600),
+            jwt_refresh_ttl: env::var("JWT_REFRESH_TTL")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30 * 24 * 3600),
             google_client_id: env::var("GOOGLE_CLIENT_ID")?,
+            apple_client_ids,
             server_host: env::var("SERVER_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
             server_port: env::var("SERVER_PORT")
                 .unwrap_or_else(|_| "8080".to_string())
                 .parse()
                 .unwrap_or(8080),
             allowed_origins,
+            price_horizon_days: env::var("PRICE_HORIZON_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1),
+            price_history_days: env::var("PRICE_HISTORY_DAYS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(365),
+            metrics_token: env::var("METRICS_TOKEN").ok().filter(|t| !t.is_empty()),
+            default_max_hours: env::var("DEFAULT_MAX_HOURS").ok().and_then(|v| v.parse().ok()),
+            default_min_continuous: env::var("DEFAULT_MIN_CONTINUOUS")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(1),
+            admin_emails,
+            encryption_key: env::var("ENCRYPTION_KEY").ok().filter(|k| !k.is_empty()),
+            timezone: env::var("TIMEZONE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(DEFAULT_TIMEZONE),
+            geo_zone: env::var("GEO_ZONE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(GeoZone::Peninsula),
+            schedule_generation_hour: env::var("SCHEDULE_GEN_HOUR")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(20),
+            schedule_generation_minute: env::var("SCHEDULE_GEN_MINUTE")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(30),
+            smtp_host: env::var("SMTP_HOST").ok().filter(|h| !h.is_empty()),
+            smtp_port: env::var("SMTP_PORT")
+                .ok()
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(587),
+            smtp_username: env::var("SMTP_USERNAME").ok().filter(|u| !u.is_empty()),
+            smtp_password: env::var("SMTP_PASSWORD").ok().filter(|p| !p.is_empty()),
+            smtp_from: env::var("SMTP_FROM").ok().filter(|f| !f.is_empty()),
         })
     }
 
+    /// Si l'email és d'un administrador configurat a `ADMIN_EMAILS`
+    pub fn is_admin_email(&self, email: &str) -> bool {
+        self.admin_emails.iter().any(|e| e.eq_ignore_ascii_case(email))
+    }
+
+    /// Comprova que els valors per defecte de les regles siguin acceptables per una regla
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(max_hours) = self.default_max_hours
+            && !(1..=24).contains(&max_hours)
+        {
+            return Err("DEFAULT_MAX_HOURS must be between 1 and 24".to_string());
+        }
+
+        if !(1..=self.default_max_hours.unwrap_or(24)).contains(&self.default_min_continuous) {
+            return Err("DEFAULT_MIN_CONTINUOUS must be between 1 and DEFAULT_MAX_HOURS".to_string());
+        }
+
+        if self.encryption_key.as_ref().is_some_and(|k| k.len() < MIN_KEY_LEN) {
+            return Err(format!("ENCRYPTION_KEY must be at least {} characters", MIN_KEY_LEN));
+        }
+
+        if self.schedule_generation_hour > 23 || self.schedule_generation_minute > 59 {
+            return Err("SCHEDULE_GEN_HOUR must be 0-23 and SCHEDULE_GEN_MINUTE 0-59".to_string());
+        }
+
+        if self.jwt_access_ttl <= 0 || self.jwt_refresh_ttl <= self.jwt_access_ttl {
+            return Err("JWT_ACCESS_TTL must be positive and shorter than JWT_REFRESH_TTL".to_string());
+        }
+
+        if self.smtp_host.is_some() && self.smtp_from.is_none() {
+            return Err("SMTP_FROM is required when SMTP_HOST is set".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Hora de generació per defecte dels schedules de demà
+    pub fn schedule_generation_time(&self) -> NaiveTime {
+        NaiveTime::from_hms_opt(self.schedule_generation_hour.into(), self.schedule_generation_minute.into(), 0)
+            .unwrap_or_else(|| NaiveTime::from_hms_opt(20, 30, 0).unwrap())
+    }
+
     pub fn server_addr(&self) -> String {
         format!("{}:{}", self.server_host, self.server_port)
     }
 }
+
+/// Llista d'emails separats per comes, normalitzats a minúscules
+fn parse_emails(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim().to_lowercase())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::test_config;
+
+    #[test]
+    fn test_validate_rule_defaults() {
+        assert!(test_config().validate().is_ok());
+
+        let config = |default_max_hours, default_min_continuous| Config {
+            default_max_hours,
+            default_min_continuous,
+            ..test_config()
+        };
+        assert!(config(Some(6), 2).validate().is_ok());
+        assert!(config(None, 24).validate().is_ok());
+        assert!(config(Some(0), 1).validate().is_err());
+        assert!(config(Some(25), 1).validate().is_err());
+        assert!(config(Some(4), 5).validate().is_err());
+        assert!(config(None, 0).validate().is_err());
+
+        let with_key = |key: &str| Config { encryption_key: Some(key.to_string()), ..test_config() };
+        assert!(with_key("massa-curta").validate().is_err());
+        assert!(with_key(&"k".repeat(MIN_KEY_LEN)).validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_schedule_generation_time() {
+        let config = |schedule_generation_hour, schedule_generation_minute| Config {
+            schedule_generation_hour,
+            schedule_generation_minute,
+            ..test_config()
+        };
+        assert_eq!(config(19, 30).schedule_generation_time(), NaiveTime::from_hms_opt(19, 30, 0).unwrap());
+        assert!(config(0, 0).validate().is_ok());
+        assert!(config(24, 0).validate().is_err());
+        assert!(config(20, 60).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_smtp() {
+        let config = |smtp_from: Option<&str>| Config {
+            smtp_host: Some("smtp.example.com".to_string()),
+            smtp_from: smtp_from.map(str::to_string),
+            ..test_config()
+        };
+        assert!(config(Some("PVPC Cheap <noreply@example.com>")).validate().is_ok());
+        assert!(config(None).validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_jwt_ttls() {
+        let config = |jwt_access_ttl, jwt_refresh_ttl| Config { jwt_access_ttl, jwt_refresh_ttl, ..test_config() };
+        assert!(config(900, 7 * 24 * 3600).validate().is_ok());
+        assert!(config(0, 3600).validate().is_err());
+        assert!(config(3600, 3600).validate().is_err());
+    }
+
+    #[test]
+    fn test_admin_emails() {
+        let config = Config {
+            admin_emails: parse_emails(" Admin@Example.com, ,ops@example.com"),
+            ..test_config()
+        };
+        assert_eq!(config.admin_emails, vec!["admin@example.com", "ops@example.com"]);
+        assert!(config.is_admin_email("admin@example.COM"));
+        assert!(!config.is_admin_email("user@example.com"));
+        assert!(parse_emails("").is_empty());
+    }
+}
@@ -1,7 +1,33 @@
+//! Accés a la base de dades
+//!
+//! Les consultes amb forma fixa fan servir les macros `sqlx::query!`, `query_as!` i
+//! `query_scalar!`, que comproven l'SQL i els tipus contra l'esquema en compilar. Sense
+//! `DATABASE_URL` (o amb `SQLX_OFFLINE=true`) les macros llegeixen la descripció de cada
+//! consulta de `.sqlx/`, a l'arrel del workspace, que és al repositori: així es compila
+//! sense base de dades (contenidor, CI).
+//!
+//! Quan s'afegeix o es canvia una consulta amb macro, o una migració, s'ha de regenerar
+//! `.sqlx/` contra una base de dades amb totes les migracions aplicades:
+//!
+//! ```sh
+//! cargo install sqlx-cli --no-default-features --features postgres
+//! export DATABASE_URL=postgres://...
+//! sqlx migrate run --source migrations
+//! cargo sqlx prepare --workspace
+//! ```
+//!
+//! La CI executa `cargo sqlx prepare --workspace --check` i falla si `.sqlx/` no està al dia.
+//!
+//! Les consultes que llegeixen `Rule` o structs amb `#[sqlx(flatten)]` continuen sent
+//! `query_as::<_, T>`: les macros no admeten ni `flatten` ni els camps `#[sqlx(default)]`,
+//! i aquestes consultes només es comproven en temps d'execució (tests de BD).
+
 pub mod models;
 
+use chrono::NaiveDate;
 use sqlx::postgres::PgPoolOptions;
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
 
 pub async fn create_pool(database_url: &str) -> Result<PgPool, sqlx::Error> {
     PgPoolOptions::new()
@@ -13,3 +39,23 @@ pub async fn create_pool(database_url: &str) -> Result<PgPool, sqlx::Error> {
 pub async fn run_migrations(pool: &PgPool) -> Result<(), sqlx::migrate::MigrateError> {
     sqlx::migrate!("../migrations").run(pool).await
 }
+
+/// Bloqueja la generació de schedules d'un usuari per una data fins que acabi la transacció
+///
+/// Dues generacions simultànies del mateix usuari i data (la tasca diària i una crida manual)
+/// s'executen una darrere l'altra: la segona espera i després troba les accions de la primera.
+pub async fn lock_schedule_generation(
+    tx: &mut PgConnection,
+    user_id: Uuid,
+    date: NaiveDate,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "SELECT pg_advisory_xact_lock(hashtext('schedule-generation:' || $1::text), $2::date - DATE '2000-01-01')"
+    )
+    .bind(user_id)
+    .bind(date)
+    .execute(tx)
+    .await?;
+
+    Ok(())
+}
@@ -1,17 +1,77 @@
-use chrono::{DateTime, NaiveDate, NaiveTime, Utc};
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, Utc};
 use serde::{Deserialize, Serialize};
+use shared::{BlackoutWindow, DaysOfWeek, SubBudget};
+use sqlx::types::Json;
 use sqlx::FromRow;
 use uuid::Uuid;
 
+/// Proveïdor d'identitat amb què l'usuari inicia sessió
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "varchar", rename_all = "lowercase")]
+pub enum IdentityProvider {
+    Google,
+    Apple,
+}
+
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct User {
     pub id: Uuid,
-    pub google_id: String,
+    pub provider: IdentityProvider,
+    /// `sub` del token ID del proveïdor (únic per proveïdor)
+    pub provider_subject: String,
     pub email: String,
     pub name: Option<String>,
     pub picture_url: Option<String>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Màxim de dispositius encesos alhora (None = sense límit)
+    pub max_concurrent_devices: Option<i32>,
+    /// Pot consultar els endpoints d'administració
+    pub is_admin: bool,
+    /// Token personal de ESIOS, xifrat (veure `services::secrets`)
+    #[serde(skip)]
+    pub esios_token: Option<Vec<u8>>,
+    /// Hora de generació dels schedules de demà (None = la del servidor)
+    pub schedule_gen_time: Option<NaiveTime>,
+    /// Recàrrec fix (€/kWh) que se suma al preu de ESIOS abans d'impostos
+    pub energy_surcharge_eur_kwh: f64,
+    /// Multiplicador dels impostos (p. ex. impost elèctric i IVA) sobre el preu amb recàrrec
+    pub tax_multiplier: f64,
+}
+
+/// Preferències d'un usuari (taula `user_preferences`)
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct UserPreferences {
+    pub user_id: Uuid,
+    /// Zona del peatge (None = la del servidor)
+    pub preferred_geo_zone: Option<String>,
+    /// `max_hours` de les regles noves que no l'indiquen (None = el del servidor)
+    pub default_max_hours: Option<i32>,
+    /// `days_of_week` de les regles noves que no l'indiquen
+    pub default_days_of_week: i32,
+    pub email_notifications: bool,
+    pub push_notification_token: Option<String>,
+    /// "ca", "es" o "en"
+    pub language: String,
+    /// None si l'usuari encara no les ha desat mai
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl UserPreferences {
+    /// Preferències d'un usuari sense fila: les mateixes que els valors per defecte de la taula
+    pub fn defaults(user_id: Uuid) -> Self {
+        Self {
+            user_id,
+            preferred_geo_zone: None,
+            default_max_hours: None,
+            default_days_of_week: 127,
+            email_notifications: true,
+            push_notification_token: None,
+            language: "ca".to_string(),
+            updated_at: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
@@ -24,34 +84,193 @@ pub struct Device {
     pub room: Option<String>,
     pub is_active: bool,
     pub created_at: DateTime<Utc>,
+    /// Esborrat lògic: el dispositiu no surt als llistats però se'n conserva l'historial
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Augmenta cada cop que canvia una acció programada del dispositiu (trigger)
+    pub plan_version: i64,
+    /// Potència nominal (W), per calcular el cost de les hores programades
+    pub wattage_watts: Option<f64>,
+    /// Capacitats del dispositiu a Google Home (p. ex. "OnOff")
+    pub capabilities: Vec<String>,
+}
+
+/// Acció que programa una regla sobre el dispositiu
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum ActionType {
+    /// Encendre durant les hores més barates
+    #[default]
+    TurnOn,
+    /// Apagar durant les hores més cares
+    TurnOff,
+}
+
+impl ActionType {
+    /// Valor de la columna `action` de scheduled_actions ("on" o "off")
+    pub fn action(&self) -> &'static str {
+        match self {
+            Self::TurnOn => "on",
+            Self::TurnOff => "off",
+        }
+    }
+}
+
+/// Què fer quan una regla amb `min_continuous_hours > 1` no troba cap bloc continu
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum ContinuityPreference {
+    /// Només blocs continus: si no n'hi ha cap, no es programa res
+    #[default]
+    Required,
+    /// Blocs continus si n'hi ha; si no, les hores saltejades més bones
+    Preferred,
+}
+
+/// Com tria les hores una regla
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum RuleMode {
+    /// Les hores més barates segons el PVPC
+    #[default]
+    Optimize,
+    /// Sempre les mateixes hores (`Rule::fixed_hours` o tota la finestra horària), sense mirar el preu
+    Fixed,
+}
+
+/// Finestra horària amb nom que l'usuari reutilitza entre regles
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct NamedWindow {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    /// Si és posterior a `end_time`, la finestra creua mitjanit
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Grup de dispositius que es programen igual amb una sola regla
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct DeviceGroup {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    /// Màxim de dispositius del grup encesos alhora (None = sense límit)
+    pub max_simultaneous: Option<i32>,
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct Rule {
     pub id: Uuid,
-    pub device_id: Uuid,
+    /// Dispositiu de la regla (None si és d'un grup)
+    pub device_id: Option<Uuid>,
+    /// Grup de dispositius de la regla (None si és d'un sol dispositiu)
+    pub device_group_id: Option<Uuid>,
     pub name: String,
     pub max_hours: i32,
     pub time_window_start: Option<NaiveTime>,
     pub time_window_end: Option<NaiveTime>,
+    /// Finestra amb nom d'on surten `time_window_start` i `time_window_end`. Mentre hi és,
+    /// les hores de la regla són les de la finestra.
+    pub named_window_id: Option<Uuid>,
     pub min_continuous_hours: i32,
+    /// Hores mínimes entre l'inici de dues hores saltejades (None = sense separació).
+    /// Només amb `min_continuous_hours` 1: no es pot combinar amb blocs continus.
+    pub min_gap_hours: Option<i32>,
+    /// Hores màximes a les quals s'amplia `max_hours` els dies amb preus negatius (None = sense
+    /// ampliació). Només en regles d'encendre.
+    pub negative_price_boost: Option<i32>,
+    pub continuity_preference: ContinuityPreference,
+    pub mode: RuleMode,
+    /// Hores del dia (0-23) d'una regla `Fixed`. None: tota la finestra horària.
+    pub fixed_hours: Option<Vec<i32>>,
+    /// Cost màxim (€) de les hores d'un dia (None = sense límit). Necessita la potència dels dispositius.
+    pub max_cost_eur: Option<f64>,
     pub days_of_week: i32,
     pub is_enabled: bool,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Dies anteriors per calcular el preu de referència (None = sense porta)
+    pub baseline_days: Option<i32>,
+    /// Estalvi mínim (%) respecte la referència per programar
+    pub baseline_margin_pct: f64,
+    pub action_type: ActionType,
+    /// Franges en què no es pot programar cap acció
+    pub blackout_windows: Json<Vec<BlackoutWindow>>,
+    /// Si n'hi ha, substitueixen max_hours i la finestra de la regla
+    pub sub_budgets: Json<Vec<SubBudget>>,
+    /// Prioritat en cas de conflicte (1 = màxima)
+    pub priority: i32,
+    /// Primer dia en què s'aplica la regla (None = sense límit)
+    pub active_from: Option<NaiveDate>,
+    /// Últim dia en què s'aplica la regla (None = sense límit)
+    pub active_until: Option<NaiveDate>,
+    /// S'incrementa a cada actualització (control de concurrència optimista)
+    pub version: i64,
+    /// Pes relatiu del consum per cada hora del dia (taula `rule_load_profiles`)
+    #[sqlx(default)]
+    pub load_profile: Option<Vec<f64>>,
+    /// Dispositius on s'apliquen les accions (vista `rule_devices`). Només el carreguen
+    /// les consultes que el necessiten.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub device_ids: Vec<Uuid>,
+    /// Potència total dels dispositius de la regla (W). Només la carreguen les consultes
+    /// que calculen hores; None si algun dispositiu no la té informada.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub wattage_watts: Option<f64>,
+    /// Màxim de dispositius del grup de la regla encesos alhora (`DeviceGroup::max_simultaneous`).
+    /// Només el carreguen les consultes que generen accions, juntament amb `device_ids`.
+    #[sqlx(default)]
+    #[serde(skip)]
+    pub max_simultaneous: Option<i32>,
+}
+
+impl Rule {
+    /// Si `date` és dins la temporada de la regla (`active_from`..=`active_until`)
+    pub fn is_in_season(&self, date: NaiveDate) -> bool {
+        self.active_from.is_none_or(|from| date >= from) && self.active_until.is_none_or(|until| date <= until)
+    }
+
+    /// Dies de la setmana en què s'aplica la regla
+    pub fn days(&self) -> DaysOfWeek {
+        DaysOfWeek::new(self.days_of_week as u8)
+    }
+
+    /// Si el dia de la setmana de `date` és a `days_of_week`
+    pub fn is_scheduled_weekday(&self, date: NaiveDate) -> bool {
+        self.days().includes(date.weekday())
+    }
+
+    /// Si la regla s'aplica a `date`: el dia de la setmana és a `days_of_week` i és dins la temporada
+    pub fn runs_on(&self, date: NaiveDate) -> bool {
+        self.is_scheduled_weekday(date) && self.is_in_season(date)
+    }
 }
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct ScheduledAction {
     pub id: Uuid,
     pub rule_id: Uuid,
+    pub device_id: Uuid,
     pub scheduled_date: NaiveDate,
     pub start_time: NaiveTime,
     pub end_time: NaiveTime,
+    /// L'acció acaba l'endemà (p. ex. 23:00-00:00)
+    pub crosses_midnight: bool,
     pub price_per_kwh: Option<f64>,
     pub status: String,
+    pub action: String,
     pub executed_at: Option<DateTime<Utc>>,
     pub created_at: DateTime<Utc>,
+    pub actual_power_watts: Option<f64>,
+    pub error_message: Option<String>,
+    pub client_executed_at: Option<DateTime<Utc>>,
 }
 
 /// Vista que uneix scheduled_action amb device info
@@ -63,7 +282,31 @@ pub struct ScheduledActionWithDevice {
     pub start_time: NaiveTime,
     pub end_time: NaiveTime,
     pub status: String,
+    pub action: String,
     pub device_id: Uuid,
     pub device_name: String,
     pub google_device_id: String,
 }
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct AuditLogEntry {
+    pub id: Uuid,
+    pub user_id: Option<Uuid>,
+    pub api_key_id: Option<Uuid>,
+    pub method: String,
+    pub path: String,
+    pub request_body: Option<serde_json::Value>,
+    pub response_status: i16,
+    pub duration_ms: i32,
+    pub created_at: DateTime<Utc>,
+}
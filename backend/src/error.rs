@@ -1,49 +1,165 @@
+use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
 use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
 
+/// Cos de totes les respostes d'error de l'API
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+    /// Codi estable per als clients (p. ex. "RULE_NOT_FOUND"); el missatge pot canviar
+    pub code: &'static str,
+    /// Identificador de la petició (capçalera `X-Request-Id`), per trobar-la als logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<Uuid>,
+    /// Errors per camp, només en errors de validació
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+    /// Versió actual del recurs, només en conflictes de versió
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_version: Option<i64>,
+}
+
+/// Error de validació d'un camp del cos de la petició
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct FieldError {
+    /// Nom del camp tal com surt al JSON (p. ex. "max_hours" o "devices[0].name")
+    pub field: String,
+    pub message: String,
+    /// Codi estable del tipus d'error (p. ex. "OUT_OF_RANGE"); el missatge pot canviar
+    pub code: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, code: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+            code: code.into(),
+        }
+    }
+}
+
+/// Errors de l'API. Cada variant porta un codi (veure `ErrorResponse::code`) i el missatge.
 #[derive(Debug)]
 pub enum AppError {
     Database(sqlx::Error),
-    NotFound(String),
-    Unauthorized(String),
-    BadRequest(String),
-    Internal(String),
-    ExternalApi(String),
+    NotFound(&'static str, String),
+    Unauthorized(&'static str, String),
+    Forbidden(&'static str, String),
+    BadRequest(&'static str, String),
+    TooManyRequests(&'static str, String),
+    Internal(&'static str, String),
+    ExternalApi(&'static str, String),
+    PricesUnavailable(&'static str, String),
+    /// Cos ben format però incomplet (p. ex. un PUT sense tots els camps obligatoris)
+    Unprocessable(&'static str, String),
+    /// Un o més camps invàlids, perquè el client els pugui marcar al formulari
+    Validation(Vec<FieldError>),
+    /// El recurs ha canviat des que el client el va llegir (la versió enviada ja no és l'actual)
+    Conflict { current_version: i64 },
+}
+
+impl AppError {
+    /// Codi d'error que es retorna al client
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Database(_) => "DATABASE_ERROR",
+            Self::Validation(_) => "validation",
+            Self::Conflict { .. } => "CONFLICT",
+            Self::NotFound(code, _)
+            | Self::Unauthorized(code, _)
+            | Self::Forbidden(code, _)
+            | Self::BadRequest(code, _)
+            | Self::TooManyRequests(code, _)
+            | Self::Internal(code, _)
+            | Self::ExternalApi(code, _)
+            | Self::PricesUnavailable(code, _)
+            | Self::Unprocessable(code, _) => code,
+        }
+    }
+
+    /// Resposta d'error amb l'identificador de la petició (el posa el middleware `RequestId`)
+    pub fn response_with_request_id(&self, request_id: Option<Uuid>) -> HttpResponse {
+        let message = match self {
+            // Els detalls de la BD no surten mai al client
+            Self::Database(_) => "Database error".to_string(),
+            Self::Validation(_) => "validation".to_string(),
+            Self::Conflict { .. } => "CONFLICT".to_string(),
+            Self::NotFound(_, msg)
+            | Self::Unauthorized(_, msg)
+            | Self::Forbidden(_, msg)
+            | Self::BadRequest(_, msg)
+            | Self::TooManyRequests(_, msg)
+            | Self::Internal(_, msg)
+            | Self::ExternalApi(_, msg)
+            | Self::PricesUnavailable(_, msg)
+            | Self::Unprocessable(_, msg) => msg.clone(),
+        };
+
+        let errors = match self {
+            Self::Validation(errors) => errors.clone(),
+            _ => Vec::new(),
+        };
+
+        let current_version = match self {
+            Self::Conflict { current_version } => Some(*current_version),
+            _ => None,
+        };
+
+        HttpResponse::build(self.status_code()).json(ErrorResponse {
+            error: message,
+            code: self.code(),
+            request_id,
+            errors,
+            current_version,
+        })
+    }
 }
 
 impl fmt::Display for AppError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Database(e) => write!(f, "Database error: {}", e),
-            Self::NotFound(msg) => write!(f, "Not found: {}", msg),
-            Self::Unauthorized(msg) => write!(f, "Unauthorized: {}", msg),
-            Self::BadRequest(msg) => write!(f, "Bad request: {}", msg),
-            Self::Internal(msg) => write!(f, "Internal error: {}", msg),
-            Self::ExternalApi(msg) => write!(f, "External API error: {}", msg),
+            Self::NotFound(_, msg) => write!(f, "Not found: {}", msg),
+            Self::Unauthorized(_, msg) => write!(f, "Unauthorized: {}", msg),
+            Self::Forbidden(_, msg) => write!(f, "Forbidden: {}", msg),
+            Self::BadRequest(_, msg) => write!(f, "Bad request: {}", msg),
+            Self::TooManyRequests(_, msg) => write!(f, "Too many requests: {}", msg),
+            Self::Internal(_, msg) => write!(f, "Internal error: {}", msg),
+            Self::ExternalApi(_, msg) => write!(f, "External API error: {}", msg),
+            Self::PricesUnavailable(_, msg) => write!(f, "Prices unavailable: {}", msg),
+            Self::Unprocessable(_, msg) => write!(f, "Unprocessable entity: {}", msg),
+            Self::Conflict { current_version } => write!(f, "Conflict: current version is {}", current_version),
+            Self::Validation(errors) => {
+                let fields: Vec<String> = errors.iter().map(|e| format!("{}: {}", e.field, e.message)).collect();
+                write!(f, "Validation failed: {}", fields.join(", "))
+            }
         }
     }
 }
 
 impl ResponseError for AppError {
-    fn error_response(&self) -> HttpResponse {
-        let (status, message) = match self {
-            Self::Database(_) => (
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-                "Database error".to_string(),
-            ),
-            Self::NotFound(msg) => (actix_web::http::StatusCode::NOT_FOUND, msg.clone()),
-            Self::Unauthorized(msg) => (actix_web::http::StatusCode::UNAUTHORIZED, msg.clone()),
-            Self::BadRequest(msg) => (actix_web::http::StatusCode::BAD_REQUEST, msg.clone()),
-            Self::Internal(msg) => (
-                actix_web::http::StatusCode::INTERNAL_SERVER_ERROR,
-                msg.clone(),
-            ),
-            Self::ExternalApi(msg) => (actix_web::http::StatusCode::BAD_GATEWAY, msg.clone()),
-        };
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Database(_) | Self::Internal(..) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotFound(..) => StatusCode::NOT_FOUND,
+            Self::Unauthorized(..) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(..) => StatusCode::FORBIDDEN,
+            Self::BadRequest(..) => StatusCode::BAD_REQUEST,
+            Self::Conflict { .. } => StatusCode::CONFLICT,
+            Self::TooManyRequests(..) => StatusCode::TOO_MANY_REQUESTS,
+            Self::ExternalApi(..) => StatusCode::BAD_GATEWAY,
+            Self::PricesUnavailable(..) | Self::Unprocessable(..) | Self::Validation(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+        }
+    }
 
-        HttpResponse::build(status).json(serde_json::json!({
-            "error": message
-        }))
+    fn error_response(&self) -> HttpResponse {
+        self.response_with_request_id(None)
     }
 }
 
@@ -54,17 +170,23 @@ impl From<sqlx::Error> for AppError {
     }
 }
 
+impl From<FieldError> for AppError {
+    fn from(e: FieldError) -> Self {
+        Self::Validation(vec![e])
+    }
+}
+
 impl From<jsonwebtoken::errors::Error> for AppError {
     fn from(e: jsonwebtoken::errors::Error) -> Self {
         tracing::error!("JWT error: {:?}", e);
-        Self::Unauthorized(format!("Invalid token: {}", e))
+        Self::Unauthorized("INVALID_TOKEN", format!("Invalid token: {}", e))
     }
 }
 
 impl From<reqwest::Error> for AppError {
     fn from(e: reqwest::Error) -> Self {
         tracing::error!("HTTP client error: {:?}", e);
-        Self::ExternalApi(format!("External API error: {}", e))
+        Self::ExternalApi("EXTERNAL_API_ERROR", format!("External API error: {}", e))
     }
 }
 
@@ -3,17 +3,30 @@ mod background_tasks;
 mod config;
 mod db;
 mod error;
+mod middleware;
 mod services;
+#[cfg(test)]
+mod test_utils;
 
 use std::sync::Arc;
+use std::time::Instant;
 
 use actix_cors::Cors;
-use actix_web::{middleware, web, App, HttpServer};
+use actix_web::{web, App, HttpServer};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::OpenApi;
+use utoipa_swagger_ui::SwaggerUi;
 
+use crate::api::openapi::ApiDoc;
 use crate::config::Config;
+use crate::middleware::audit::AuditLogger;
+use crate::middleware::request_id::{RequestIdMiddleware, REQUEST_ID_HEADER};
+use crate::services::apple::AppleAuthService;
 use crate::services::google::GoogleAuthService;
+use crate::services::notifications::{NoopNotifier, Notifier, SmtpNotifier};
 use crate::services::pvpc::PvpcClient;
+use crate::services::schedule_events::ScheduleEvents;
+use crate::services::webhooks::WebhookDispatcher;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -29,6 +42,7 @@ async fn main() -> std::io::Result<()> {
 
     // Carregar configuració
     let config = Config::from_env().expect("Failed to load configuration");
+    config.validate().expect("Invalid configuration");
     let server_addr = config.server_addr();
 
     tracing::info!("Starting server at http://{}", server_addr);
@@ -45,22 +59,57 @@ async fn main() -> std::io::Result<()> {
 
     tracing::info!("Database migrations completed");
 
+    // Els tokens desats dels usuaris s'han de poder desxifrar amb la clau configurada
+    services::secrets::check_stored_secrets(&pool, &config)
+        .await
+        .expect("Invalid configuration");
+
+    // Instal·lar el recorder de mètriques abans de res que en generi
+    services::metrics::init();
+
     // Crear client HTTP compartit
     let http_client = reqwest::Client::new();
 
     // Crear client PVPC
     let pvpc_client = PvpcClient::new();
 
-    // Crear servei d'autenticació de Google
-    let google_auth = GoogleAuthService::new(http_client);
+    // Crear dispatcher de webhooks
+    let webhooks = WebhookDispatcher::new(http_client.clone());
+
+    // Crear serveis d'autenticació de Google i Apple
+    let google_auth = GoogleAuthService::new(http_client.clone());
+    let apple_auth = AppleAuthService::new(http_client);
+
+    // Canal de difusió dels canvis de schedules i preus (SSE i WebSocket)
+    let schedule_events = ScheduleEvents::new();
+
+    // Correus als usuaris (resum setmanal)
+    let notifier: Arc<dyn Notifier> = match SmtpNotifier::from_config(&config).expect("Invalid SMTP configuration") {
+        Some(smtp) => Arc::new(smtp),
+        None => {
+            tracing::info!("SMTP no configurat: no s'enviaran resums setmanals");
+            Arc::new(NoopNotifier)
+        }
+    };
+
+    // Moment d'arrencada, per calcular el temps en marxa a /health
+    let started_at = Arc::new(Instant::now());
 
     // Encapsular amb Arc per compartir entre threads
     let config = Arc::new(config);
     let pool_arc = Arc::new(pool.clone());
     let pvpc_arc = Arc::new(pvpc_client.clone());
+    let webhooks_arc = Arc::new(webhooks.clone());
 
     // Iniciar background tasks (scheduler diari)
-    background_tasks::start_background_tasks(pool_arc, pvpc_arc);
+    background_tasks::start_background_tasks(
+        pool_arc,
+        pvpc_arc,
+        webhooks_arc,
+        schedule_events.clone(),
+        notifier.clone(),
+        config.clone(),
+    );
     tracing::info!("Background tasks started");
 
     // Iniciar servidor
@@ -72,6 +121,7 @@ async fn main() -> std::io::Result<()> {
                 actix_web::http::header::CONTENT_TYPE,
                 actix_web::http::header::ACCEPT,
             ])
+            .expose_headers(vec![REQUEST_ID_HEADER])
             .max_age(3600);
 
         // Configurar orígens permesos
@@ -83,22 +133,29 @@ async fn main() -> std::io::Result<()> {
             }
         }
 
+        // El darrer `wrap` és el més extern: l'auditoria s'executa dins del Logger i el CORS
         App::new()
-            .wrap(middleware::Logger::default())
+            .wrap(AuditLogger::new(pool.clone(), &config.jwt_secret))
+            .wrap(RequestIdMiddleware)
+            .wrap(actix_web::middleware::Logger::default())
             .wrap(tracing_actix_web::TracingLogger::default())
             .wrap(cors)
             .app_data(web::Data::new(pool.clone()))
             .app_data(web::Data::from(config.clone()))
             .app_data(web::Data::new(pvpc_client.clone()))
             .app_data(web::Data::new(google_auth.clone()))
+            .app_data(web::Data::new(apple_auth.clone()))
+            .app_data(web::Data::new(schedule_events.clone()))
+            .app_data(web::Data::new(webhooks.clone()))
+            .app_data(web::Data::from(started_at.clone()))
+            .app_data(web::Data::from(notifier.clone()))
             .configure(api::configure)
-            .route("/health", web::get().to(health_check))
+            .service(
+                SwaggerUi::new("/swagger-ui/{_:.*}")
+                    .url("/api-docs/openapi.json", ApiDoc::openapi()),
+            )
     })
     .bind(&server_addr)?
     .run()
     .await
 }
-
-async fn health_check() -> &'static str {
-    "OK"
-}
@@ -0,0 +1,520 @@
+//! Registre d'auditoria de les crides que modifiquen dades
+//!
+//! Només es registren les peticions POST, PUT, PATCH i DELETE amb un JWT o una clau d'API
+//! vàlids; les crides anònimes o amb credencials invàlides no tenen usuari a qui atribuir-les.
+//! Les fetes amb una clau d'API també en desen l'id.
+
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
+
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::error::PayloadError;
+use actix_web::http::Method;
+use actix_web::web::{Bytes, BytesMut};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+use futures_util::stream::{self, Stream, StreamExt};
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::api::api_keys::{api_key_owner, API_KEY_HEADER};
+use crate::api::auth::decode_user_id;
+
+/// Bytes del cos que es desen com a màxim
+pub const MAX_AUDIT_BODY: usize = 8 * 1024;
+
+/// Un camp del cos no es desa mai si el seu nom (en minúscules) conté alguna d'aquestes paraules
+/// (`esios_token`, `refresh_token`, `client_secret`, `api_key`...)
+const REDACTED_FIELD_PARTS: &[&str] = &["token", "secret", "password", "key"];
+
+/// Valor amb què se substitueixen els camps sensibles
+const REDACTED: &str = "[REDACTED]";
+
+/// Middleware que desa a `audit_log` cada crida autenticada que modifica dades
+pub struct AuditLogger {
+    pool: PgPool,
+    jwt_secret: Rc<str>,
+}
+
+impl AuditLogger {
+    pub fn new(pool: PgPool, jwt_secret: &str) -> Self {
+        Self {
+            pool,
+            jwt_secret: Rc::from(jwt_secret),
+        }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for AuditLogger
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = AuditLoggerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuditLoggerMiddleware {
+            service: Rc::new(service),
+            pool: self.pool.clone(),
+            jwt_secret: self.jwt_secret.clone(),
+        }))
+    }
+}
+
+pub struct AuditLoggerMiddleware<S> {
+    service: Rc<S>,
+    pool: PgPool,
+    jwt_secret: Rc<str>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuditLoggerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        let Some(credentials) = is_mutating(req.method()).then(|| credentials(&req, &self.jwt_secret)).flatten()
+        else {
+            return Box::pin(async move { service.call(req).await });
+        };
+
+        let pool = self.pool.clone();
+        Box::pin(async move {
+            let (user_id, api_key_id) = match credentials {
+                Credentials::Jwt(user_id) => (user_id, None),
+                Credentials::ApiKey(key) => match api_key_owner(&key, &pool).await {
+                    Ok(Some((key_id, user_id))) => (user_id, Some(key_id)),
+                    Ok(None) => return service.call(req).await,
+                    Err(e) => {
+                        tracing::warn!("No s'ha pogut identificar la clau d'API per l'auditoria: {}", e);
+                        return service.call(req).await;
+                    }
+                },
+            };
+
+            let started = Instant::now();
+            let method = req.method().to_string();
+            let path = req.path().to_string();
+
+            let (captured, truncated) = capture_body(&mut req).await;
+
+            let result = service.call(req).await;
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+
+            let entry = AuditEntry {
+                user_id,
+                api_key_id,
+                method,
+                path,
+                request_body: body_to_json(&captured, truncated),
+                response_status: status.as_u16() as i16,
+                duration_ms: started.elapsed().as_millis().min(i32::MAX as u128) as i32,
+            };
+            // L'escriptura no ha de retardar la resposta
+            tokio::spawn(record(pool, entry));
+
+            result
+        })
+    }
+}
+
+/// Credencials amb què es pot atribuir una crida a un usuari
+enum Credentials {
+    /// Usuari d'un JWT vàlid
+    Jwt(Uuid),
+    /// Clau d'API (es valida contra la BD)
+    ApiKey(String),
+}
+
+/// Credencials de la petició, amb la mateixa prioritat que `extract_user_from_request`:
+/// primer la clau d'API i, si no n'hi ha, el JWT
+fn credentials(req: &ServiceRequest, jwt_secret: &str) -> Option<Credentials> {
+    if let Some(key) = req.headers().get(API_KEY_HEADER).and_then(|v| v.to_str().ok()) {
+        return Some(Credentials::ApiKey(key.to_string()));
+    }
+    bearer_token(req)
+        .and_then(|token| decode_user_id(token, jwt_secret).ok())
+        .map(Credentials::Jwt)
+}
+
+struct AuditEntry {
+    user_id: Uuid,
+    api_key_id: Option<Uuid>,
+    method: String,
+    path: String,
+    request_body: Option<Value>,
+    response_status: i16,
+    duration_ms: i32,
+}
+
+async fn record(pool: PgPool, entry: AuditEntry) {
+    let result = sqlx::query(
+        r#"
+        INSERT INTO audit_log (user_id, api_key_id, method, path, request_body, response_status, duration_ms)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#
+    )
+    .bind(entry.user_id)
+    .bind(entry.api_key_id)
+    .bind(&entry.method)
+    .bind(&entry.path)
+    .bind(&entry.request_body)
+    .bind(entry.response_status)
+    .bind(entry.duration_ms)
+    .execute(&pool)
+    .await;
+
+    if let Err(e) = result {
+        tracing::warn!("No s'ha pogut desar el registre d'auditoria de {} {}: {}", entry.method, entry.path, e);
+    }
+}
+
+fn is_mutating(method: &Method) -> bool {
+    matches!(*method, Method::POST | Method::PUT | Method::PATCH | Method::DELETE)
+}
+
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    req.headers()
+        .get("Authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// Llegeix fins a `MAX_AUDIT_BODY` bytes del cos i torna a posar el cos sencer a la petició.
+/// La resta del cos no es llegeix aquí: el handler el continua rebent en streaming.
+async fn capture_body(req: &mut ServiceRequest) -> (Bytes, bool) {
+    let mut payload = req.take_payload();
+    let mut captured = BytesMut::new();
+    let mut chunks = Vec::new();
+
+    // Es llegeix un byte més del límit per saber si el cos s'ha tallat
+    while captured.len() <= MAX_AUDIT_BODY {
+        match payload.next().await {
+            Some(Ok(chunk)) => {
+                let take = chunk.len().min(MAX_AUDIT_BODY + 1 - captured.len());
+                captured.extend_from_slice(&chunk[..take]);
+                chunks.push(Ok(chunk));
+            }
+            Some(Err(e)) => {
+                chunks.push(Err(e));
+                break;
+            }
+            None => break,
+        }
+    }
+
+    let body: Pin<Box<dyn Stream<Item = Result<Bytes, PayloadError>>>> =
+        Box::pin(stream::iter(chunks).chain(payload));
+    req.set_payload(Payload::from(body));
+
+    let truncated = captured.len() > MAX_AUDIT_BODY;
+    captured.truncate(MAX_AUDIT_BODY);
+    (captured.freeze(), truncated)
+}
+
+/// Converteix el cos capturat a JSON amb els camps sensibles ocults. Un cos tallat o que no és
+/// JSON no es pot ocultar: se'n desa només una marca (`{"_truncated": true}` o `{"_not_json": true}`).
+fn body_to_json(body: &[u8], truncated: bool) -> Option<Value> {
+    if body.is_empty() {
+        return None;
+    }
+    if truncated {
+        return Some(serde_json::json!({ "_truncated": true }));
+    }
+
+    match serde_json::from_slice::<Value>(body) {
+        Ok(mut json) => {
+            redact(&mut json);
+            Some(json)
+        }
+        Err(_) => Some(serde_json::json!({ "_not_json": true })),
+    }
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if is_sensitive(key) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(items) => items.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+fn is_sensitive(field: &str) -> bool {
+    let field = field.to_lowercase();
+    REDACTED_FIELD_PARTS.iter().any(|part| field.contains(part))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, read_body_json, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+    use std::time::Duration;
+
+    use crate::test_utils::{auth_header, create_user, test_config};
+
+    #[test]
+    fn test_body_to_json() {
+        assert_eq!(body_to_json(b"", false), None);
+
+        let json = body_to_json(br#"{"url":"https://x","secret":"s3cr3t","nested":[{"token":"t"}]}"#, false);
+        assert_eq!(
+            json,
+            Some(serde_json::json!({ "url": "https://x", "secret": REDACTED, "nested": [{ "token": REDACTED }] }))
+        );
+
+        // Qualsevol camp que contingui una paraula sensible, sense distingir majúscules
+        let json = body_to_json(br#"{"esios_token":"t","Client_Secret":"s","apiKey":"k","name":"Rentadora"}"#, false);
+        assert_eq!(
+            json,
+            Some(serde_json::json!({ "esios_token": REDACTED, "Client_Secret": REDACTED, "apiKey": REDACTED,
+                                     "name": "Rentadora" }))
+        );
+
+        // Ni un cos tallat ni un que no és JSON es poden ocultar: no se'n desa el text
+        assert_eq!(body_to_json(br#"{"token":"t"#, true), Some(serde_json::json!({ "_truncated": true })));
+        assert_eq!(body_to_json(b"token=t", false), Some(serde_json::json!({ "_not_json": true })));
+    }
+
+    #[actix_web::test]
+    async fn test_large_body_reaches_handler() {
+        let config = test_config();
+        // La inserció fallarà (no hi ha base de dades), però la petició no se n'ha d'assabentar
+        let pool = PgPool::connect_lazy("postgres://localhost/unused").unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(AuditLogger::new(pool, &config.jwt_secret))
+                .route("/echo", web::post().to(|body: Bytes| async move { HttpResponse::Ok().body(body) })),
+        )
+        .await;
+
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(jsonwebtoken::Algorithm::HS256),
+            &crate::api::auth::Claims {
+                sub: Uuid::new_v4().to_string(),
+                email: "audit@example.com".to_string(),
+                iat: chrono::Utc::now().timestamp(),
+                exp: chrono::Utc::now().timestamp() + 60,
+            },
+            &jsonwebtoken::EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+        )
+        .unwrap();
+
+        let body = "x".repeat(3 * MAX_AUDIT_BODY);
+        let req = TestRequest::post()
+            .uri("/echo")
+            .insert_header(("Authorization", format!("Bearer {}", token)))
+            .set_payload(body.clone())
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(read_body(resp).await, body);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_mutating_calls_are_audited(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "audit").await;
+        let admin = create_user(&pool, "admin").await;
+        sqlx::query("UPDATE users SET is_admin = true WHERE id = $1")
+            .bind(admin.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let app = init_service(
+            App::new()
+                .wrap(AuditLogger::new(pool.clone(), &config.jwt_secret))
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let webhook = serde_json::json!({ "url": "https://example.com/hook", "secret": "s3cr3t" });
+        let req = TestRequest::post()
+            .uri("/api/webhooks")
+            .insert_header(auth_header(&user, &config))
+            .set_json(&webhook)
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 201);
+
+        // Ni les crides anònimes ni les de lectura es registren
+        let req = TestRequest::post().uri("/api/webhooks").set_json(&webhook).to_request();
+        assert_eq!(call_service(&app, req).await.status(), 401);
+        let req = TestRequest::get()
+            .uri("/api/devices")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        // L'escriptura és asíncrona
+        wait_for_audit_rows(&pool, 1).await;
+        let logged: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM audit_log").fetch_one(&pool).await.unwrap();
+        assert_eq!(logged, 1);
+
+        let req = TestRequest::get()
+            .uri("/api/admin/audit-log")
+            .insert_header(auth_header(&user, &config))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 403);
+
+        let req = TestRequest::get()
+            .uri("/api/admin/audit-log?limit=10")
+            .insert_header(auth_header(&admin, &config))
+            .to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["total"], 1);
+        let entry = &body["items"][0];
+        assert_eq!(entry["user_id"], user.id.to_string());
+        assert_eq!(entry["method"], "POST");
+        assert_eq!(entry["path"], "/api/webhooks");
+        assert_eq!(entry["response_status"], 201);
+        assert_eq!(entry["request_body"]["url"], "https://example.com/hook");
+        assert_eq!(entry["request_body"]["secret"], REDACTED);
+    }
+
+    /// Espera que `audit_log` tingui `count` files
+    async fn wait_for_audit_rows(pool: &PgPool, count: i64) {
+        for _ in 0..50 {
+            let logged = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM audit_log")
+                .fetch_one(pool)
+                .await
+                .unwrap();
+            if logged >= count {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        panic!("audit_log no té {} files", count);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_esios_token_is_redacted(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "esios").await;
+        let app = init_service(
+            App::new()
+                .wrap(AuditLogger::new(pool.clone(), &config.jwt_secret))
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let req = TestRequest::patch()
+            .uri("/api/auth/me")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "esios_token": "plaintext-esios-token" }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        wait_for_audit_rows(&pool, 1).await;
+        let body: Value = sqlx::query_scalar("SELECT request_body FROM audit_log WHERE path = '/api/auth/me'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(body["esios_token"], REDACTED);
+        assert!(!body.to_string().contains("plaintext-esios-token"));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_refresh_token_is_redacted(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "refresh").await;
+        let app = init_service(
+            App::new()
+                .wrap(AuditLogger::new(pool.clone(), &config.jwt_secret))
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        // El client encara envia l'access token: la crida s'atribueix a l'usuari i es registra
+        let req = TestRequest::post()
+            .uri("/api/auth/refresh")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "refresh_token": "plaintext-refresh-token" }))
+            .to_request();
+        call_service(&app, req).await;
+
+        wait_for_audit_rows(&pool, 1).await;
+        let body: Value = sqlx::query_scalar("SELECT request_body FROM audit_log WHERE path = '/api/auth/refresh'")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(body, serde_json::json!({ "refresh_token": REDACTED }));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_api_key_calls_are_audited(pool: PgPool) {
+        let config = test_config();
+        let user = create_user(&pool, "script").await;
+        let app = init_service(
+            App::new()
+                .wrap(AuditLogger::new(pool.clone(), &config.jwt_secret))
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .configure(crate::api::configure),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/api/auth/api-keys")
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "name": "cron" }))
+            .to_request();
+        let created: Value = read_body_json(call_service(&app, req).await).await;
+
+        let req = TestRequest::patch()
+            .uri("/api/auth/me")
+            .insert_header((API_KEY_HEADER, created["key"].as_str().unwrap()))
+            .set_json(serde_json::json!({ "max_concurrent_devices": 3 }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        wait_for_audit_rows(&pool, 2).await;
+        let rows: Vec<(String, Option<Uuid>, Option<Uuid>)> =
+            sqlx::query_as("SELECT path, user_id, api_key_id FROM audit_log ORDER BY path")
+                .fetch_all(&pool)
+                .await
+                .unwrap();
+        let key_id: Uuid = created["id"].as_str().unwrap().parse().unwrap();
+        assert_eq!(rows[0], ("/api/auth/api-keys".to_string(), Some(user.id), None));
+        assert_eq!(rows[1], ("/api/auth/me".to_string(), Some(user.id), Some(key_id)));
+    }
+}
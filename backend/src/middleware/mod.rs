@@ -0,0 +1,3 @@
+pub mod audit;
+pub mod rate_limit;
+pub mod request_id;
@@ -0,0 +1,196 @@
+//! Limitació de peticions per IP o per clau d'API
+//!
+//! Finestra fixa: cada IP pot fer `limit` peticions per `window`; a partir d'aquí es
+//! respon 429 amb `Retry-After` fins que comença la finestra següent. L'estat és en
+//! memòria i es comparteix entre tots els workers que fan servir el mateix `RateLimiter`.
+//!
+//! La IP és la de la connexió: el servidor no és darrere d'un proxy, i `X-Forwarded-For`
+//! el podria falsejar qualsevol client.
+//!
+//! Les peticions amb `X-API-Key` es limiten per clau i no per IP (un script pot canviar
+//! d'IP). Com que la clau només es coneix després de validar-la a la BD, aquest límit no
+//! el fa el middleware sinó `extract_user_from_request`, amb `RateLimiter::check`.
+
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use uuid::Uuid;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderValue, RETRY_AFTER};
+use actix_web::{Error, ResponseError};
+use futures_util::future::LocalBoxFuture;
+
+use crate::error::AppError;
+
+/// Finestres a partir de les quals es netegen les ja acabades
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// A qui es comptabilitzen les peticions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RateKey {
+    Ip(Option<IpAddr>),
+    /// Id de la clau d'API (no el de l'usuari: cada clau té el seu límit)
+    ApiKey(Uuid),
+}
+
+/// Peticions d'una IP o clau dins la finestra en curs
+struct WindowCount {
+    started_at: Instant,
+    count: u32,
+}
+
+/// Middleware que limita les peticions per IP. Es clona compartint l'estat.
+/// Fora del middleware, `check` limita per qualsevol `RateKey`.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    windows: Arc<Mutex<HashMap<RateKey, WindowCount>>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            windows: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Compta una petició de `key`. Si ja ha arribat al límit, retorna el temps fins a la finestra següent.
+    pub fn check(&self, key: RateKey, now: Instant) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() > PRUNE_THRESHOLD {
+            windows.retain(|_, w| now.duration_since(w.started_at) < self.window);
+        }
+
+        let entry = windows.entry(key).or_insert(WindowCount { started_at: now, count: 0 });
+        if now.duration_since(entry.started_at) >= self.window {
+            *entry = WindowCount { started_at: now, count: 0 };
+        }
+
+        if entry.count >= self.limit {
+            return Err(self.window - now.duration_since(entry.started_at));
+        }
+        entry.count += 1;
+        Ok(())
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterService {
+            service: Rc::new(service),
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimiterService<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let ip = req.peer_addr().map(|addr| addr.ip());
+
+        if let Err(retry_after) = self.limiter.check(RateKey::Ip(ip), Instant::now()) {
+            tracing::warn!("Massa peticions de {:?} a {}", ip, req.path());
+
+            let seconds = retry_after_seconds(retry_after);
+            let mut response = rate_limited(retry_after).error_response();
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(seconds));
+
+            return Box::pin(ready(Ok(req.into_response(response).map_into_right_body())));
+        }
+
+        let service = Rc::clone(&self.service);
+        Box::pin(async move { service.call(req).await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+/// Arrodonit amunt: amb 0 el client tornaria a provar abans d'hora
+fn retry_after_seconds(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+/// Error 429 per una petició que ha superat el límit
+pub fn rate_limited(retry_after: Duration) -> AppError {
+    AppError::TooManyRequests(
+        "RATE_LIMITED",
+        format!("Too many requests. Retry in {} seconds.", retry_after_seconds(retry_after)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{post, App, HttpResponse};
+
+    #[post("/login")]
+    async fn login() -> HttpResponse {
+        HttpResponse::Ok().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_eleventh_request_is_rejected() {
+        let app = init_service(App::new().wrap(RateLimiter::new(10, Duration::from_secs(60))).service(login)).await;
+        let request = |ip: &str| TestRequest::post().uri("/login").peer_addr(ip.parse().unwrap()).to_request();
+
+        for _ in 0..10 {
+            assert_eq!(call_service(&app, request("10.0.0.1:1000")).await.status(), 200);
+        }
+
+        let resp = call_service(&app, request("10.0.0.1:1001")).await;
+        assert_eq!(resp.status(), 429);
+        let retry_after: u64 = resp.headers().get(RETRY_AFTER).unwrap().to_str().unwrap().parse().unwrap();
+        assert!((1..=60).contains(&retry_after));
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["code"], "RATE_LIMITED");
+
+        // Cada IP té el seu límit
+        assert_eq!(call_service(&app, request("10.0.0.2:1000")).await.status(), 200);
+    }
+
+    #[test]
+    fn test_window_resets() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+        let start = Instant::now();
+        let ip = RateKey::Ip(Some("10.0.0.1".parse().unwrap()));
+
+        assert!(limiter.check(ip, start).is_ok());
+        assert_eq!(limiter.check(ip, start + Duration::from_secs(20)), Err(Duration::from_secs(40)));
+        // Una clau d'API té la seva pròpia finestra
+        assert!(limiter.check(RateKey::ApiKey(Uuid::new_v4()), start + Duration::from_secs(20)).is_ok());
+        assert!(limiter.check(ip, start + Duration::from_secs(60)).is_ok());
+    }
+}
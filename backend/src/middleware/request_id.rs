@@ -0,0 +1,138 @@
+//! Identificador únic de cada petició
+//!
+//! Es desa a les extensions de la petició (`RequestId`), es retorna a la capçalera
+//! `X-Request-Id` i s'afegeix al cos de les respostes d'error de l'API (`AppError`),
+//! de manera que un client pot donar-lo per trobar la petició als logs. Mentre s'atén la
+//! petició també és a `current_request_id`, per enviar-lo a les crides a ESIOS.
+
+use std::future::{ready, Ready};
+use std::rc::Rc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{Error, HttpMessage};
+use futures_util::future::LocalBoxFuture;
+use uuid::Uuid;
+
+use crate::error::AppError;
+
+/// Capçalera de resposta amb l'identificador de la petició
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Identificador de la petició en curs (a les extensions de la petició)
+#[derive(Debug, Clone, Copy)]
+pub struct RequestId(pub Uuid);
+
+tokio::task_local! {
+    static CURRENT_REQUEST_ID: Uuid;
+}
+
+/// Identificador de la petició que s'està atenent, si n'hi ha cap (fora de les tasques de fons)
+pub fn current_request_id() -> Option<Uuid> {
+    CURRENT_REQUEST_ID.try_with(|id| *id).ok()
+}
+
+/// Middleware que assigna un `RequestId` a cada petició
+pub struct RequestIdMiddleware;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestIdMiddleware
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RequestIdService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestIdService {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestIdService<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestIdService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+        let id = Uuid::new_v4();
+        req.extensions_mut().insert(RequestId(id));
+
+        Box::pin(async move {
+            let res = CURRENT_REQUEST_ID.scope(id, service.call(req)).await?;
+            let request_id = res.request().extensions().get::<RequestId>().map(|id| id.0);
+
+            // Els errors de l'API es tornen a generar amb l'identificador al cos
+            let error_response = res
+                .response()
+                .error()
+                .and_then(|e| e.as_error::<AppError>())
+                .map(|e| e.response_with_request_id(request_id));
+
+            let mut res = match error_response {
+                Some(response) => res.into_response(response).map_into_right_body(),
+                None => res.map_into_left_body(),
+            };
+
+            if let Some(value) = request_id.and_then(|id| HeaderValue::from_str(&id.to_string()).ok()) {
+                res.headers_mut().insert(REQUEST_ID_HEADER, value);
+            }
+
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{get, App, HttpResponse};
+
+    use crate::error::AppResult;
+
+    #[get("/rule")]
+    async fn missing_rule() -> AppResult<HttpResponse> {
+        Err(AppError::NotFound("RULE_NOT_FOUND", "Rule not found".to_string()))
+    }
+
+    #[get("/ok")]
+    async fn ok() -> HttpResponse {
+        HttpResponse::Ok().body("ok")
+    }
+
+    #[actix_web::test]
+    async fn test_error_response_has_code_and_request_id() {
+        let app = init_service(App::new().wrap(RequestIdMiddleware).service(missing_rule).service(ok)).await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/rule").to_request()).await;
+        assert_eq!(resp.status(), 404);
+        let header = resp.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap().to_string();
+
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["error"], "Rule not found");
+        assert_eq!(body["code"], "RULE_NOT_FOUND");
+        assert_eq!(body["request_id"], header);
+
+        // Les respostes correctes només porten la capçalera, i cada petició té el seu id
+        let resp = call_service(&app, TestRequest::get().uri("/ok").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        assert_ne!(resp.headers().get(REQUEST_ID_HEADER).unwrap().to_str().unwrap(), header);
+    }
+}
@@ -0,0 +1,72 @@
+//! Data i hora locals
+//!
+//! Les dates i hores de les accions (`scheduled_date`, `start_time`, `end_time`), de les
+//! regles i dels preus són hora local de `Config::timezone` (per defecte Europe/Madrid, la
+//! dels preus PVPC), sigui quina sigui la zona horària del servidor. Els instants
+//! (`executed_at`, `created_at`...) es desen en UTC: per saber a quin dia pertanyen s'han de
+//! passar a hora local amb `local_date`. No s'ha de fer servir `chrono::Local`.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+
+/// Zona horària per defecte: la dels preus PVPC
+pub const DEFAULT_TIMEZONE: Tz = chrono_tz::Europe::Madrid;
+
+/// Data i hora actuals a `tz`
+pub fn now(tz: Tz) -> DateTime<Tz> {
+    Utc::now().with_timezone(&tz)
+}
+
+/// Data d'avui a `tz`
+pub fn today(tz: Tz) -> NaiveDate {
+    local_date(Utc::now(), tz)
+}
+
+/// Dia local al qual pertany un instant
+pub fn local_date(instant: DateTime<Utc>, tz: Tz) -> NaiveDate {
+    instant.with_timezone(&tz).date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[rstest]
+    // Hivern (UTC+1): mitjanit local són les 23:00 UTC del dia abans
+    #[case("2024-01-15T22:59:59Z", "2024-01-15")]
+    #[case("2024-01-15T23:00:00Z", "2024-01-16")]
+    // Estiu (UTC+2): mitjanit local són les 22:00 UTC
+    #[case("2024-07-15T21:59:59Z", "2024-07-15")]
+    #[case("2024-07-15T22:00:00Z", "2024-07-16")]
+    // Canvi a l'hora d'estiu (31/03, 02:00 → 03:00): la mitjanit anterior encara és UTC+1
+    #[case("2024-03-30T22:59:59Z", "2024-03-30")]
+    #[case("2024-03-30T23:00:00Z", "2024-03-31")]
+    #[case("2024-03-31T01:00:00Z", "2024-03-31")]
+    #[case("2024-03-31T21:59:59Z", "2024-03-31")]
+    #[case("2024-03-31T22:00:00Z", "2024-04-01")]
+    // Canvi a l'hora d'hivern (27/10, 03:00 → 02:00): la mitjanit anterior encara és UTC+2
+    #[case("2024-10-26T21:59:59Z", "2024-10-26")]
+    #[case("2024-10-26T22:00:00Z", "2024-10-27")]
+    #[case("2024-10-27T00:30:00Z", "2024-10-27")]
+    #[case("2024-10-27T01:30:00Z", "2024-10-27")]
+    #[case("2024-10-27T22:59:59Z", "2024-10-27")]
+    #[case("2024-10-27T23:00:00Z", "2024-10-28")]
+    fn test_local_date_around_midnight_in_madrid(#[case] instant: &str, #[case] expected: &str) {
+        let instant: DateTime<Utc> = instant.parse().unwrap();
+        assert_eq!(local_date(instant, DEFAULT_TIMEZONE), date(expected));
+    }
+
+    #[test]
+    fn test_local_date_differs_from_utc_date() {
+        // Les 00:30 a Madrid encara són el dia abans en UTC
+        let instant: DateTime<Utc> = "2024-07-15T22:30:00Z".parse().unwrap();
+        assert_eq!(instant.date_naive(), date("2024-07-15"));
+        assert_eq!(local_date(instant, DEFAULT_TIMEZONE), date("2024-07-16"));
+        assert_eq!(local_date(instant, chrono_tz::UTC), date("2024-07-15"));
+    }
+}
@@ -0,0 +1,225 @@
+//! Pla d'execució d'un dispositiu
+//!
+//! Converteix les accions programades d'un dispositiu en una seqüència de trams
+//! sense solapaments: les accions contigües amb la mateixa acció s'uneixen i, si
+//! dues regles demanen coses diferents a la mateixa hora, guanya la de més prioritat
+//! (a igual prioritat, apagar). Els trams es divideixen a mitjanit.
+
+use chrono::{Duration, NaiveDate, NaiveDateTime, NaiveTime};
+use serde::Serialize;
+use sqlx::FromRow;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// Acció programada amb la prioritat de la seva regla
+#[derive(Debug, Clone, FromRow)]
+pub struct PlannedAction {
+    pub id: Uuid,
+    pub scheduled_date: NaiveDate,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    /// "on" o "off"
+    pub action: String,
+    /// Prioritat de la regla (1 = màxima)
+    pub priority: i32,
+}
+
+/// Tram del pla dins d'un sol dia
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct PlanEntry {
+    pub date: NaiveDate,
+    pub start_time: String,
+    /// 23:59:59 si el tram continua l'endemà
+    pub end_time: String,
+    /// Acció a executar: "on" o "off"
+    pub action: String,
+    /// Accions programades que cobreixen el tram
+    pub action_ids: Vec<Uuid>,
+}
+
+/// Tram absolut abans de dividir-lo per dies
+struct Span {
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+    action: String,
+    action_ids: Vec<Uuid>,
+}
+
+impl PlannedAction {
+    /// Inici i final absoluts. Les accions que creuen mitjanit acaben l'endemà, i
+    /// 23:59:59 es tracta com a mitjanit.
+    fn interval(&self) -> (NaiveDateTime, NaiveDateTime) {
+        let start = self.scheduled_date.and_time(self.start_time);
+        let next_day = self.scheduled_date + Duration::days(1);
+        let end = if self.end_time == end_of_day() {
+            next_day.and_time(midnight())
+        } else if self.end_time <= self.start_time {
+            next_day.and_time(self.end_time)
+        } else {
+            self.scheduled_date.and_time(self.end_time)
+        };
+        (start, end)
+    }
+}
+
+fn midnight() -> NaiveTime {
+    NaiveTime::from_hms_opt(0, 0, 0).unwrap()
+}
+
+fn end_of_day() -> NaiveTime {
+    NaiveTime::from_hms_opt(23, 59, 59).unwrap()
+}
+
+/// Construeix el pla dels `days` dies que comencen a `from`
+pub fn build_plan(actions: &[PlannedAction], from: NaiveDate, days: i64) -> Vec<PlanEntry> {
+    let window_start = from.and_time(midnight());
+    let window_end = (from + Duration::days(days)).and_time(midnight());
+
+    let intervals: Vec<(NaiveDateTime, NaiveDateTime, &PlannedAction)> = actions
+        .iter()
+        .map(|a| {
+            let (start, end) = a.interval();
+            (start.max(window_start), end.min(window_end), a)
+        })
+        .filter(|(start, end, _)| start < end)
+        .collect();
+
+    let mut boundaries: Vec<NaiveDateTime> = intervals.iter().flat_map(|(s, e, _)| [*s, *e]).collect();
+    boundaries.sort();
+    boundaries.dedup();
+
+    let mut spans: Vec<Span> = Vec::new();
+    for pair in boundaries.windows(2) {
+        let (start, end) = (pair[0], pair[1]);
+        let covering: Vec<&PlannedAction> = intervals
+            .iter()
+            .filter(|(s, e, _)| *s <= start && *e >= end)
+            .map(|(_, _, a)| *a)
+            .collect();
+
+        let Some(winner) = covering.iter().min_by_key(|a| (a.priority, a.action != "off")) else {
+            continue;
+        };
+        let mut action_ids: Vec<Uuid> = covering
+            .iter()
+            .filter(|a| a.action == winner.action)
+            .map(|a| a.id)
+            .collect();
+
+        match spans.last_mut() {
+            Some(last) if last.end == start && last.action == winner.action => {
+                last.end = end;
+                action_ids.retain(|id| !last.action_ids.contains(id));
+                last.action_ids.extend(action_ids);
+            }
+            _ => spans.push(Span {
+                start,
+                end,
+                action: winner.action.clone(),
+                action_ids,
+            }),
+        }
+    }
+
+    spans.into_iter().flat_map(split_by_day).collect()
+}
+
+/// Divideix un tram absolut en un tram per cada dia que toca
+fn split_by_day(span: Span) -> Vec<PlanEntry> {
+    let mut entries = Vec::new();
+    let mut start = span.start;
+
+    while start < span.end {
+        let next_midnight = (start.date() + Duration::days(1)).and_time(midnight());
+        let end = span.end.min(next_midnight);
+        entries.push(PlanEntry {
+            date: start.date(),
+            start_time: start.time().to_string(),
+            end_time: if end == next_midnight { end_of_day() } else { end.time() }.to_string(),
+            action: span.action.clone(),
+            action_ids: span.action_ids.clone(),
+        });
+        start = end;
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(2030, 1, day).unwrap()
+    }
+
+    fn action(day: u32, start: u32, end: u32, action: &str, priority: i32) -> PlannedAction {
+        PlannedAction {
+            id: Uuid::new_v4(),
+            scheduled_date: date(day),
+            start_time: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+            action: action.to_string(),
+            priority,
+        }
+    }
+
+    fn summary(plan: &[PlanEntry]) -> Vec<(u32, &str, &str, &str)> {
+        use chrono::Datelike;
+        plan.iter()
+            .map(|e| (e.date.day(), e.start_time.as_str(), e.end_time.as_str(), e.action.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_contiguous_actions_are_merged() {
+        let first = action(1, 2, 3, "on", 1);
+        let second = action(1, 3, 4, "on", 1);
+        let plan = build_plan(&[second.clone(), first.clone()], date(1), 2);
+
+        assert_eq!(summary(&plan), vec![(1, "02:00:00", "04:00:00", "on")]);
+        assert_eq!(plan[0].action_ids, vec![first.id, second.id]);
+    }
+
+    #[test]
+    fn test_conflicts_resolved_by_priority_then_off() {
+        let on = action(1, 2, 5, "on", 2);
+        let urgent_off = action(1, 3, 4, "off", 1);
+        let plan = build_plan(&[on.clone(), urgent_off], date(1), 1);
+        assert_eq!(
+            summary(&plan),
+            vec![
+                (1, "02:00:00", "03:00:00", "on"),
+                (1, "03:00:00", "04:00:00", "off"),
+                (1, "04:00:00", "05:00:00", "on"),
+            ]
+        );
+        assert_eq!(plan[0].action_ids, vec![on.id]);
+
+        // A igual prioritat, apagar
+        let plan = build_plan(&[action(1, 2, 3, "on", 1), action(1, 2, 3, "off", 1)], date(1), 1);
+        assert_eq!(summary(&plan), vec![(1, "02:00:00", "03:00:00", "off")]);
+    }
+
+    #[test]
+    fn test_midnight_crossing_is_split_and_clipped() {
+        // 23:00-01:00 de dia 1, 23:00-23:59:59 de dia 2 i una de dia 3, fora de la finestra
+        let mut last_hour = action(2, 23, 0, "on", 1);
+        last_hour.end_time = end_of_day();
+        let actions = [action(1, 23, 1, "on", 1), last_hour, action(3, 2, 3, "on", 1)];
+
+        let plan = build_plan(&actions, date(1), 2);
+        assert_eq!(
+            summary(&plan),
+            vec![
+                (1, "23:00:00", "23:59:59", "on"),
+                (2, "00:00:00", "01:00:00", "on"),
+                (2, "23:00:00", "23:59:59", "on"),
+            ]
+        );
+
+        // Una acció d'ahir que acaba avui també hi surt
+        let plan = build_plan(&actions, date(2), 1);
+        assert_eq!(summary(&plan)[0], (2, "00:00:00", "01:00:00", "on"));
+    }
+}
@@ -0,0 +1,317 @@
+//! Períodes horaris del peatge 2.0TD (Circular 3/2020 de la CNMC)
+//!
+//! Els dies laborables tenen tres períodes: P1 (punta), P2 (pla) i P3 (vall). Els caps de
+//! setmana i els festius nacionals són vall tot el dia. A diferència dels peatges 3.0TD i
+//! 6.1TD, al 2.0TD els períodes no canvien amb la temporada; només Ceuta i Melilla tenen
+//! les puntes desplaçades una hora.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use shared::DailyPrices;
+
+use crate::db::models::User;
+use crate::services::scheduler::PriceSlots;
+
+/// Període del peatge 2.0TD
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, ToSchema)]
+pub enum TariffPeriod {
+    /// Punta
+    P1,
+    /// Pla
+    P2,
+    /// Vall
+    P3,
+}
+
+impl TariffPeriod {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::P1 => "P1",
+            Self::P2 => "P2",
+            Self::P3 => "P3",
+        }
+    }
+}
+
+/// Zona geogràfica del subministrament
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoZone {
+    Peninsula,
+    Baleares,
+    Canarias,
+    Ceuta,
+    Melilla,
+}
+
+impl FromStr for GeoZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "peninsula" => Ok(Self::Peninsula),
+            "baleares" => Ok(Self::Baleares),
+            "canarias" => Ok(Self::Canarias),
+            "ceuta" => Ok(Self::Ceuta),
+            "melilla" => Ok(Self::Melilla),
+            other => Err(format!("Zona desconeguda: {}", other)),
+        }
+    }
+}
+
+/// Festius nacionals de data fixa (mes, dia). La CNMC només compta aquests com a dies de
+/// vall: els festius de data variable (Divendres Sant) i els autonòmics no hi entren.
+const NATIONAL_HOLIDAYS: [(u32, u32); 9] = [
+    (1, 1),   // Any Nou
+    (1, 6),   // Reis
+    (5, 1),   // Festa del Treball
+    (8, 15),  // Assumpció
+    (10, 12), // Festa Nacional
+    (11, 1),  // Tots Sants
+    (12, 6),  // Constitució
+    (12, 8),  // Immaculada
+    (12, 25), // Nadal
+];
+
+/// Si el dia és vall sencer: dissabte, diumenge o festiu nacional
+pub fn is_off_peak_day(date: NaiveDate) -> bool {
+    matches!(date.weekday(), Weekday::Sat | Weekday::Sun) || NATIONAL_HOLIDAYS.contains(&(date.month(), date.day()))
+}
+
+/// Període de l'hora local `hour` (0-23) del dia `date`
+pub fn classify_hour(hour: u8, date: NaiveDate, zone: GeoZone) -> TariffPeriod {
+    if is_off_peak_day(date) {
+        return TariffPeriod::P3;
+    }
+
+    // Dues puntes de 4 hores separades per 4 hores de pla; a Ceuta i Melilla, una hora més tard
+    let peak_start = match zone {
+        GeoZone::Ceuta | GeoZone::Melilla => 11,
+        GeoZone::Peninsula | GeoZone::Baleares | GeoZone::Canarias => 10,
+    };
+    match hour {
+        0..=7 => TariffPeriod::P3,
+        h if (peak_start..peak_start + 4).contains(&h) || (peak_start + 8..peak_start + 12).contains(&h) => {
+            TariffPeriod::P1
+        }
+        _ => TariffPeriod::P2,
+    }
+}
+
+/// Suma dels preus de les franges seleccionades per període ("P1", "P2", "P3").
+/// Només hi surten els períodes amb alguna franja.
+pub fn cost_by_period(slots: &PriceSlots, selected: &[u8], date: NaiveDate, zone: GeoZone) -> HashMap<String, f64> {
+    let mut breakdown = HashMap::new();
+    for slot in slots.prices.iter().filter(|p| selected.contains(&p.hour)) {
+        let hour = (slot.hour as u32 * slots.slot_minutes / 60) as u8;
+        let period = classify_hour(hour, date, zone);
+        *breakdown.entry(period.as_str().to_string()).or_insert(0.0) += slot.price;
+    }
+    breakdown
+}
+
+/// Impostos i càrrecs que converteixen el terme d'energia del PVPC (indicador 1001) en el
+/// preu final que paga l'usuari: `(preu + surcharge_eur_kwh) * tax_multiplier`
+///
+/// Per exemple, amb l'impost elèctric (5,11269632%) i l'IVA (21%) el multiplicador és
+/// 1,0511269632 × 1,21. El recàrrec fix recull els conceptes per kWh que no inclou el PVPC.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PriceAdjustment {
+    pub surcharge_eur_kwh: f64,
+    pub tax_multiplier: f64,
+}
+
+impl Default for PriceAdjustment {
+    fn default() -> Self {
+        Self { surcharge_eur_kwh: 0.0, tax_multiplier: 1.0 }
+    }
+}
+
+impl PriceAdjustment {
+    /// L'ajust configurat per l'usuari (cap, si no n'ha configurat)
+    pub fn for_user(user: &User) -> Self {
+        Self { surcharge_eur_kwh: user.energy_surcharge_eur_kwh, tax_multiplier: user.tax_multiplier }
+    }
+
+    /// Si no canvia cap preu
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Preu final d'un preu de ESIOS (€/kWh)
+    pub fn price(&self, raw: f64) -> f64 {
+        (raw + self.surcharge_eur_kwh) * self.tax_multiplier
+    }
+
+    /// Aplica l'ajust als preus d'un dia. El preu de ESIOS es conserva a `raw_price`.
+    pub fn apply(&self, mut day: DailyPrices) -> DailyPrices {
+        if self.is_identity() {
+            return day;
+        }
+
+        for p in &mut day.prices {
+            let raw = p.raw_price.unwrap_or(p.price);
+            p.raw_price = Some(raw);
+            p.price = self.price(raw);
+        }
+        for p in &mut day.quarter_hours {
+            let raw = p.raw_price.unwrap_or(p.price);
+            p.raw_price = Some(raw);
+            p.price = self.price(raw);
+        }
+        day
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+    use shared::HourlyPrice;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[rstest]
+    #[case(0, TariffPeriod::P3)]
+    #[case(7, TariffPeriod::P3)]
+    #[case(8, TariffPeriod::P2)]
+    #[case(9, TariffPeriod::P2)]
+    #[case(10, TariffPeriod::P1)]
+    #[case(13, TariffPeriod::P1)]
+    #[case(14, TariffPeriod::P2)]
+    #[case(17, TariffPeriod::P2)]
+    #[case(18, TariffPeriod::P1)]
+    #[case(21, TariffPeriod::P1)]
+    #[case(22, TariffPeriod::P2)]
+    #[case(23, TariffPeriod::P2)]
+    fn test_weekday_periods(#[case] hour: u8, #[case] expected: TariffPeriod) {
+        // Dimecres
+        let day = date("2025-03-12");
+        assert_eq!(classify_hour(hour, day, GeoZone::Peninsula), expected);
+        // Illes: el mateix horari, en hora local
+        assert_eq!(classify_hour(hour, day, GeoZone::Canarias), expected);
+        assert_eq!(classify_hour(hour, day, GeoZone::Baleares), expected);
+    }
+
+    #[rstest]
+    #[case(7, TariffPeriod::P3)]
+    #[case(8, TariffPeriod::P2)]
+    #[case(10, TariffPeriod::P2)]
+    #[case(11, TariffPeriod::P1)]
+    #[case(14, TariffPeriod::P1)]
+    #[case(15, TariffPeriod::P2)]
+    #[case(19, TariffPeriod::P1)]
+    #[case(22, TariffPeriod::P1)]
+    #[case(23, TariffPeriod::P2)]
+    fn test_ceuta_melilla_periods_shifted(#[case] hour: u8, #[case] expected: TariffPeriod) {
+        let day = date("2025-03-12");
+        assert_eq!(classify_hour(hour, day, GeoZone::Ceuta), expected);
+        assert_eq!(classify_hour(hour, day, GeoZone::Melilla), expected);
+    }
+
+    #[rstest]
+    // Dissabte i diumenge
+    #[case("2025-03-15")]
+    #[case("2025-03-16")]
+    // Festius nacionals de data fixa en dia laborable
+    #[case("2025-01-01")]
+    #[case("2025-01-06")]
+    #[case("2025-05-01")]
+    #[case("2025-08-15")]
+    #[case("2025-12-08")]
+    #[case("2025-12-25")]
+    #[case("2026-10-12")]
+    fn test_off_peak_days_are_valley_all_day(#[case] day: &str) {
+        for hour in 0..24 {
+            assert_eq!(classify_hour(hour, date(day), GeoZone::Peninsula), TariffPeriod::P3);
+        }
+    }
+
+    #[rstest]
+    // Divendres Sant: festiu de data variable, no compta
+    #[case("2025-04-18")]
+    // Sant Joan: festiu autonòmic
+    #[case("2025-06-24")]
+    fn test_other_holidays_keep_weekday_periods(#[case] day: &str) {
+        assert!(!is_off_peak_day(date(day)));
+        assert_eq!(classify_hour(12, date(day), GeoZone::Peninsula), TariffPeriod::P1);
+    }
+
+    #[test]
+    fn test_parse_geo_zone() {
+        assert_eq!(" Canarias ".parse::<GeoZone>(), Ok(GeoZone::Canarias));
+        assert_eq!("melilla".parse::<GeoZone>(), Ok(GeoZone::Melilla));
+        assert!("portugal".parse::<GeoZone>().is_err());
+    }
+
+    #[test]
+    fn test_cost_by_period_hourly() {
+        let prices: Vec<HourlyPrice> = (0..24).map(|hour| HourlyPrice { hour, price: 0.1, raw_price: None }).collect();
+        let slots = PriceSlots::hourly(&prices);
+
+        let breakdown = cost_by_period(&slots, &[3, 9, 12, 22], date("2025-03-12"), GeoZone::Peninsula);
+        assert_eq!(breakdown.len(), 3);
+        assert!((breakdown["P1"] - 0.1).abs() < 1e-9);
+        assert!((breakdown["P2"] - 0.2).abs() < 1e-9);
+        assert!((breakdown["P3"] - 0.1).abs() < 1e-9);
+
+        // En cap de setmana tot és vall
+        let breakdown = cost_by_period(&slots, &[3, 9, 12, 22], date("2025-03-15"), GeoZone::Peninsula);
+        assert_eq!(breakdown.keys().collect::<Vec<_>>(), ["P3"]);
+    }
+
+    #[test]
+    fn test_cost_by_period_quarter_hours() {
+        // Quarts d'hora 39 (09:45) i 40 (10:00): un de pla i un de punta
+        let prices: Vec<HourlyPrice> = (0..96).map(|hour| HourlyPrice { hour, price: 0.05, raw_price: None }).collect();
+        let slots = PriceSlots { prices, slot_minutes: 15 };
+
+        let breakdown = cost_by_period(&slots, &[39, 40], date("2025-03-12"), GeoZone::Peninsula);
+        assert!((breakdown["P1"] - 0.05).abs() < 1e-9);
+        assert!((breakdown["P2"] - 0.05).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_price_adjustment_spanish_taxes() {
+        // Impost elèctric del 5,11269632% i IVA del 21%, sense recàrrec
+        let taxes = PriceAdjustment { surcharge_eur_kwh: 0.0, tax_multiplier: 1.0511269632 * 1.21 };
+        assert!((taxes.price(0.10) - 0.127186).abs() < 1e-6);
+
+        // Amb 0,02 €/kWh de peatges i càrrecs abans d'impostos
+        let all_in = PriceAdjustment { surcharge_eur_kwh: 0.02, ..taxes };
+        assert!((all_in.price(0.10) - 0.152623).abs() < 1e-6);
+        // IVA reduït del 10%
+        let reduced = PriceAdjustment { surcharge_eur_kwh: 0.02, tax_multiplier: 1.0511269632 * 1.10 };
+        assert!((reduced.price(0.10) - 0.138749).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_apply_keeps_raw_price() {
+        let day = DailyPrices {
+            date: date("2025-03-12"),
+            prices: vec![HourlyPrice { hour: 0, price: 0.10, raw_price: None }],
+            quarter_hours: vec![shared::PricePoint { minute_of_day: 15, price: 0.20, raw_price: None }],
+        };
+        let adjustment = PriceAdjustment { surcharge_eur_kwh: 0.01, tax_multiplier: 1.21 };
+
+        let adjusted = adjustment.apply(day.clone());
+        assert!((adjusted.prices[0].price - 0.1331).abs() < 1e-9);
+        assert_eq!(adjusted.prices[0].raw_price, Some(0.10));
+        assert!((adjusted.quarter_hours[0].price - 0.2541).abs() < 1e-9);
+        assert_eq!(adjusted.quarter_hours[0].raw_price, Some(0.20));
+
+        // Aplicar-lo dues vegades parteix del preu de ESIOS
+        let twice = adjustment.apply(adjusted);
+        assert!((twice.prices[0].price - 0.1331).abs() < 1e-9);
+
+        // Sense ajust, els preus no canvien ni porten `raw_price`
+        let unchanged = PriceAdjustment::default().apply(day);
+        assert_eq!((unchanged.prices[0].price, unchanged.prices[0].raw_price), (0.10, None));
+    }
+}
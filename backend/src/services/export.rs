@@ -0,0 +1,177 @@
+//! Exportació de totes les dades d'un usuari (RGPD)
+//!
+//! Genera un ZIP amb un fitxer JSON per taula. El ZIP s'escriu en streaming:
+//! les files es llegeixen de la BD una a una i el comprimit s'envia al client
+//! a trossos, de manera que un historial llarg no es carrega mai sencer a memòria.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use actix_web::web::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use serde::Serialize;
+use sqlx::PgPool;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use zip::write::{SimpleFileOptions, StreamWriter};
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::db::models::{Device, Rule, ScheduledAction, User};
+
+/// Fitxers del ZIP, en l'ordre en què s'escriuen
+pub const EXPORT_FILES: [&str; 4] = ["users.json", "devices.json", "rules.json", "scheduled_actions.json"];
+
+/// Mida mínima d'un tros abans d'enviar-lo al client
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Trossos pendents d'enviar abans que l'escriptura s'esperi pel client
+const CHANNEL_CAPACITY: usize = 4;
+
+/// Buffer compartit entre el `ZipWriter` i la tasca que n'envia el contingut
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl SharedBuffer {
+    fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Destí del ZIP: el buffer on escriu el `ZipWriter` i el canal cap al client
+struct ExportSink {
+    buffer: SharedBuffer,
+    tx: mpsc::Sender<io::Result<Bytes>>,
+}
+
+impl ExportSink {
+    /// Envia el contingut del buffer al client. Falla amb `BrokenPipe` si el client ha tancat.
+    async fn send(&self) -> io::Result<()> {
+        let chunk = self.buffer.take();
+        if chunk.is_empty() {
+            return Ok(());
+        }
+
+        self.tx
+            .send(Ok(Bytes::from(chunk)))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+/// Stream amb el ZIP de les dades de l'usuari
+///
+/// La generació es fa en una tasca a part; si falla a mig camí, el stream acaba amb error.
+pub fn export_user_data(pool: PgPool, user_id: Uuid) -> impl Stream<Item = io::Result<Bytes>> {
+    let (tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+    tokio::spawn(async move {
+        let sink = ExportSink {
+            buffer: SharedBuffer::default(),
+            tx,
+        };
+
+        match write_export(&pool, user_id, &sink).await {
+            Ok(()) => tracing::info!("Dades exportades per l'usuari {}", user_id),
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                tracing::debug!("Exportació de l'usuari {} interrompuda pel client", user_id);
+            }
+            Err(e) => {
+                tracing::error!("Error exportant les dades de l'usuari {}: {}", user_id, e);
+                let _ = sink.tx.send(Err(e)).await;
+            }
+        }
+    });
+
+    futures_util::stream::unfold(rx, |mut rx| async move { rx.recv().await.map(|chunk| (chunk, rx)) })
+}
+
+async fn write_export(pool: &PgPool, user_id: Uuid, sink: &ExportSink) -> io::Result<()> {
+    let mut zip = ZipWriter::new_stream(sink.buffer.clone());
+
+    let users = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch(pool);
+    write_table(&mut zip, sink, EXPORT_FILES[0], users).await?;
+
+    let devices = sqlx::query_as::<_, Device>("SELECT * FROM devices WHERE user_id = $1 ORDER BY created_at")
+        .bind(user_id)
+        .fetch(pool);
+    write_table(&mut zip, sink, EXPORT_FILES[1], devices).await?;
+
+    let rules = sqlx::query_as::<_, Rule>(
+        r#"
+        SELECT r.*, lp.weights as load_profile
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        LEFT JOIN rule_load_profiles lp ON lp.rule_id = r.id
+        WHERE COALESCE(d.user_id, g.user_id) = $1
+        ORDER BY r.created_at
+        "#
+    )
+    .bind(user_id)
+    .fetch(pool);
+    write_table(&mut zip, sink, EXPORT_FILES[2], rules).await?;
+
+    let actions = sqlx::query_as::<_, ScheduledAction>(
+        r#"
+        SELECT sa.*
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        WHERE d.user_id = $1
+        ORDER BY sa.scheduled_date, sa.start_time
+        "#
+    )
+    .bind(user_id)
+    .fetch(pool);
+    write_table(&mut zip, sink, EXPORT_FILES[3], actions).await?;
+
+    zip.finish()?;
+    sink.send().await
+}
+
+/// Escriu les files d'una taula com un array JSON dins d'un fitxer nou del ZIP
+async fn write_table<T>(
+    zip: &mut ZipWriter<StreamWriter<SharedBuffer>>,
+    sink: &ExportSink,
+    name: &str,
+    mut rows: impl Stream<Item = Result<T, sqlx::Error>> + Unpin,
+) -> io::Result<()>
+where
+    T: Serialize,
+{
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+    zip.start_file(name, options)?;
+    zip.write_all(b"[")?;
+
+    let mut first = true;
+    while let Some(row) = rows.try_next().await.map_err(io::Error::other)? {
+        if !first {
+            zip.write_all(b",")?;
+        }
+        first = false;
+        serde_json::to_writer(&mut *zip, &row)?;
+
+        if sink.buffer.len() >= CHUNK_SIZE {
+            sink.send().await?;
+        }
+    }
+
+    zip.write_all(b"]")?;
+    sink.send().await
+}
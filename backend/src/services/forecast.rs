@@ -0,0 +1,140 @@
+//! Estimació dels preus de demà abans que ESIOS els publiqui (cap a les 20:00)
+//!
+//! Per cada hora, mitjana ponderada dels preus dels darrers dies de l'historial: els dies
+//! amb el mateix dia de la setmana que demà pesen més (els caps de setmana i els dies
+//! laborables tenen perfils diferents).
+
+use std::collections::BTreeMap;
+
+use chrono::{Datelike, Duration, NaiveDate};
+use shared::{DailyPrices, HourlyPrice};
+use sqlx::types::Json;
+use sqlx::PgPool;
+
+/// Dies de l'historial amb què s'estima
+pub const FORECAST_HISTORY_DAYS: i64 = 7;
+
+/// Pes dels dies amb el mateix dia de la setmana que el dia estimat (la resta pesen 1)
+const SAME_WEEKDAY_WEIGHT: f64 = 3.0;
+
+/// Minuts durant els quals es reaprofita una estimació desada
+pub const FORECAST_CACHE_MINUTES: i64 = 30;
+
+/// Estima els preus del dia següent al més recent de `historical`.
+/// Sense historial no hi ha res a estimar: retorna un dia sense preus.
+pub fn estimate_tomorrow_prices(historical: &[DailyPrices]) -> DailyPrices {
+    let Some(last) = historical.iter().map(|day| day.date).max() else {
+        return DailyPrices {
+            date: NaiveDate::MIN,
+            prices: vec![],
+            quarter_hours: vec![],
+        };
+    };
+    let date = last + Duration::days(1);
+
+    // hora -> (suma ponderada, suma de pesos)
+    let mut hours: BTreeMap<u8, (f64, f64)> = BTreeMap::new();
+    for day in historical {
+        let weight = if day.date.weekday() == date.weekday() { SAME_WEEKDAY_WEIGHT } else { 1.0 };
+        for price in day.prices.iter().filter(|p| p.price.is_finite()) {
+            let (sum, weights) = hours.entry(price.hour).or_default();
+            *sum += price.price * weight;
+            *weights += weight;
+        }
+    }
+
+    DailyPrices {
+        date,
+        prices: hours
+            .into_iter()
+            .map(|(hour, (sum, weights))| HourlyPrice { hour, price: sum / weights, raw_price: None })
+            .collect(),
+        quarter_hours: vec![],
+    }
+}
+
+/// Estimació desada per `date` si té menys de `FORECAST_CACHE_MINUTES` minuts
+pub async fn cached_forecast(pool: &PgPool, date: NaiveDate) -> Result<Option<DailyPrices>, sqlx::Error> {
+    let prices: Option<Json<Vec<HourlyPrice>>> = sqlx::query_scalar(
+        r#"
+        SELECT prices FROM price_forecasts
+        WHERE price_date = $1 AND created_at > NOW() - make_interval(mins => $2)
+        "#
+    )
+    .bind(date)
+    .bind(FORECAST_CACHE_MINUTES as i32)
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(prices.map(|Json(prices)| DailyPrices { date, prices, quarter_hours: vec![] }))
+}
+
+/// Desa una estimació (substitueix l'anterior del mateix dia)
+pub async fn store_forecast(pool: &PgPool, forecast: &DailyPrices) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO price_forecasts (price_date, prices)
+        VALUES ($1, $2)
+        ON CONFLICT (price_date) DO UPDATE SET prices = EXCLUDED.prices, created_at = NOW()
+        "#
+    )
+    .bind(forecast.date)
+    .bind(Json(&forecast.prices))
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn day(date: NaiveDate, price: impl Fn(u8) -> f64) -> DailyPrices {
+        DailyPrices {
+            date,
+            prices: (0..24).map(|hour| HourlyPrice { hour, price: price(hour), raw_price: None }).collect(),
+            quarter_hours: vec![],
+        }
+    }
+
+    fn week(price: impl Fn(NaiveDate, u8) -> f64) -> Vec<DailyPrices> {
+        // De dimecres 2025-03-05 a dimarts 2025-03-11: demà és dimecres
+        let first = NaiveDate::from_ymd_opt(2025, 3, 5).unwrap();
+        (0..FORECAST_HISTORY_DAYS).map(|i| first + Duration::days(i)).map(|d| day(d, |h| price(d, h))).collect()
+    }
+
+    #[test]
+    fn test_constant_prices_are_reproduced() {
+        let forecast = estimate_tomorrow_prices(&week(|_, _| 0.12));
+        assert_eq!(forecast.date, NaiveDate::from_ymd_opt(2025, 3, 12).unwrap());
+        assert_eq!(forecast.prices.len(), 24);
+        assert!(forecast.prices.iter().all(|p| (p.price - 0.12).abs() < 1e-12));
+
+        // El mateix perfil horari cada dia: es reprodueix hora a hora
+        let forecast = estimate_tomorrow_prices(&week(|_, hour| 0.05 + hour as f64 / 100.0));
+        for p in &forecast.prices {
+            assert!((p.price - (0.05 + p.hour as f64 / 100.0)).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_same_weekday_weighs_more() {
+        // El dimecres anterior val 0.20, la resta de dies 0.10: (3 * 0.20 + 6 * 0.10) / 9
+        let wednesday = NaiveDate::from_ymd_opt(2025, 3, 5).unwrap();
+        let forecast = estimate_tomorrow_prices(&week(|d, _| if d == wednesday { 0.20 } else { 0.10 }));
+        assert!((forecast.prices[0].price - 1.2 / 9.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_missing_hours_and_empty_history() {
+        let mut history = week(|_, _| 0.10);
+        history[6].prices.retain(|p| p.hour != 5);
+        history.iter_mut().for_each(|d| d.prices.retain(|p| p.hour != 23));
+        let forecast = estimate_tomorrow_prices(&history);
+        assert_eq!(forecast.prices.len(), 23);
+        assert!((forecast.prices[5].price - 0.10).abs() < 1e-12);
+
+        assert!(estimate_tomorrow_prices(&[]).prices.is_empty());
+    }
+}
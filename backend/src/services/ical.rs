@@ -0,0 +1,167 @@
+//! Exportació de les accions programades en format iCalendar (RFC 5545)
+//!
+//! Cada acció és un VEVENT. Les hores de les accions són hora local de `Config::timezone`
+//! (vegeu `services::clock`); al calendari s'escriuen en UTC perquè qualsevol aplicació les
+//! mostri bé sense haver de conèixer la zona horària. Les accions que creuen mitjanit
+//! (`end_time` <= `start_time`) acaben l'endemà.
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use sqlx::FromRow;
+use uuid::Uuid;
+
+/// Longitud màxima d'una línia, en octets (sense el salt de línia)
+const MAX_LINE_OCTETS: usize = 75;
+
+/// Acció programada tal com surt al calendari
+#[derive(Debug, Clone, FromRow)]
+pub struct CalendarAction {
+    pub id: Uuid,
+    pub device_name: String,
+    /// "on" o "off"
+    pub action: String,
+    pub scheduled_date: NaiveDate,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub price_per_kwh: Option<f64>,
+}
+
+impl CalendarAction {
+    /// Inici i final locals; si l'acció creua mitjanit, el final és l'endemà
+    fn local_span(&self) -> (NaiveDateTime, NaiveDateTime) {
+        let start = self.scheduled_date.and_time(self.start_time);
+        let mut end = self.scheduled_date.and_time(self.end_time);
+        if self.end_time <= self.start_time {
+            end += Duration::days(1);
+        }
+        (start, end)
+    }
+}
+
+/// Calendari amb un VEVENT per cada acció. `now` és el DTSTAMP dels esdeveniments.
+pub fn schedule_calendar(actions: &[CalendarAction], tz: Tz, now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//PVPC Cheap//Schedule//CA".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "METHOD:PUBLISH".to_string(),
+    ];
+
+    for action in actions {
+        let (start, end) = action.local_span();
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}@pvpccheap", action.id));
+        lines.push(format!("DTSTAMP:{}", utc_stamp(now)));
+        lines.push(format!("DTSTART:{}", utc_stamp(to_utc(start, tz))));
+        lines.push(format!("DTEND:{}", utc_stamp(to_utc(end, tz))));
+        lines.push(format!(
+            "SUMMARY:{}",
+            escape_text(&format!("{} {}", action.device_name, action.action.to_uppercase()))
+        ));
+        if let Some(price) = action.price_per_kwh {
+            lines.push(format!("DESCRIPTION:{}", escape_text(&format!("Preu: {:.5} €/kWh", price))));
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines.iter().map(|line| fold_line(line)).collect()
+}
+
+/// Instant UTC d'una hora local. Les hores que no existeixen (canvi d'horari de març)
+/// es prenen com si encara fos l'horari d'hivern.
+fn to_utc(local: NaiveDateTime, tz: Tz) -> DateTime<Utc> {
+    tz.from_local_datetime(&local)
+        .earliest()
+        .or_else(|| tz.from_local_datetime(&(local - Duration::hours(1))).earliest().map(|t| t + Duration::hours(1)))
+        .map_or_else(|| Utc.from_utc_datetime(&local), |t| t.with_timezone(&Utc))
+}
+
+fn utc_stamp(instant: DateTime<Utc>) -> String {
+    instant.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Escapa un valor de text (RFC 5545, 3.3.11)
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Línia acabada en CRLF i, si és massa llarga, partida en línies de continuació
+/// (que comencen amb un espai) sense tallar cap caràcter UTF-8
+fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut octets = 0;
+    for c in line.chars() {
+        if octets + c.len_utf8() > MAX_LINE_OCTETS {
+            folded.push_str("\r\n ");
+            // L'espai de la continuació compta
+            octets = 1;
+        }
+        folded.push(c);
+        octets += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::clock::DEFAULT_TIMEZONE;
+
+    fn action(device: &str, date: &str, start: u32, end: u32) -> CalendarAction {
+        CalendarAction {
+            id: Uuid::new_v4(),
+            device_name: device.to_string(),
+            action: "on".to_string(),
+            scheduled_date: date.parse().unwrap(),
+            start_time: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+            price_per_kwh: Some(0.08123),
+        }
+    }
+
+    #[test]
+    fn test_calendar_structure_and_events() {
+        let actions = vec![
+            action("Termo", "2025-01-15", 3, 4),
+            // Creua mitjanit: acaba l'endemà
+            action("Rentadora, planta baixa", "2025-01-15", 23, 0),
+        ];
+        let now = "2025-01-14T20:30:00Z".parse().unwrap();
+        let ics = schedule_calendar(&actions, DEFAULT_TIMEZONE, now);
+
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+        assert_eq!(ics.matches("BEGIN:VEVENT\r\n").count(), 2);
+        assert_eq!(ics.matches("END:VEVENT\r\n").count(), 2);
+        // Totes les línies acaben en CRLF
+        assert!(!ics.replace("\r\n", "").contains(['\r', '\n']));
+
+        // Hivern: UTC+1
+        assert!(ics.contains("DTSTART:20250115T020000Z\r\nDTEND:20250115T030000Z\r\n"));
+        assert!(ics.contains("DTSTART:20250115T220000Z\r\nDTEND:20250115T230000Z\r\n"));
+        assert!(ics.contains("SUMMARY:Termo ON\r\n"));
+        assert!(ics.contains("SUMMARY:Rentadora\\, planta baixa ON\r\n"));
+        assert!(ics.contains("DESCRIPTION:Preu: 0.08123 €/kWh\r\n"));
+        assert!(ics.contains(&format!("UID:{}@pvpccheap\r\n", actions[0].id)));
+    }
+
+    #[test]
+    fn test_long_lines_are_folded() {
+        let device = "Radiador ".repeat(12);
+        let ics = schedule_calendar(&[action(&device, "2025-07-01", 10, 11)], DEFAULT_TIMEZONE, Utc::now());
+
+        assert!(ics.split("\r\n").all(|line| line.len() <= MAX_LINE_OCTETS));
+        assert!(ics.contains("\r\n "));
+        // En desplegar les línies es recupera el resum sencer
+        let unfolded = ics.replace("\r\n ", "");
+        assert!(unfolded.contains(&format!("SUMMARY:{} ON\r\n", device)));
+        // Estiu: UTC+2
+        assert!(unfolded.contains("DTSTART:20250701T080000Z"));
+    }
+}
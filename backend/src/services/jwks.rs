@@ -0,0 +1,245 @@
+//! Claus públiques (JWKS) amb què els proveïdors d'identitat signen els seus tokens
+//!
+//! Es descarreguen quan cal i es desen en cache el temps que indica el `Cache-Control`
+//! de la resposta.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::DecodingKey;
+use reqwest::header::{HeaderMap, CACHE_CONTROL};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::RwLock;
+
+use crate::error::{AppError, AppResult};
+
+/// Durada de la cache si el proveïdor no envia un `Cache-Control: max-age` vàlid
+const DEFAULT_CERTS_CACHE_DURATION: Duration = Duration::from_secs(3600); // 1 hora
+
+/// Claus públiques en format JWK
+#[derive(Debug, Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kid: String,
+    n: String,  // RSA modulus
+    e: String,  // RSA exponent
+}
+
+/// Cache de claus d'un proveïdor
+struct CertsCache {
+    certs: Vec<Jwk>,
+    fetched_at: Instant,
+    /// Durada indicada pel proveïdor al `Cache-Control` de la resposta
+    ttl: Duration,
+}
+
+impl CertsCache {
+    fn is_fresh_at(&self, now: Instant) -> bool {
+        now.duration_since(self.fetched_at) < self.ttl
+    }
+}
+
+/// Durada de la cache segons el `Cache-Control` de la resposta
+///
+/// `no-cache` i `no-store` obliguen a tornar a descarregar les claus a la següent verificació.
+fn cache_ttl(headers: &HeaderMap) -> Duration {
+    let Some(value) = headers.get(CACHE_CONTROL).and_then(|v| v.to_str().ok()) else {
+        return DEFAULT_CERTS_CACHE_DURATION;
+    };
+
+    let mut max_age = None;
+    for directive in value.split(',').map(str::trim) {
+        let (name, arg) = directive.split_once('=').unwrap_or((directive, ""));
+        match name.to_ascii_lowercase().as_str() {
+            "no-cache" | "no-store" => return Duration::ZERO,
+            "max-age" => max_age = arg.trim_matches('"').parse().ok(),
+            _ => {}
+        }
+    }
+
+    max_age.map(Duration::from_secs).unwrap_or(DEFAULT_CERTS_CACHE_DURATION)
+}
+
+/// Claus d'un proveïdor (amb cache). Es clona compartint la cache.
+#[derive(Clone)]
+pub struct Jwks {
+    client: Client,
+    url: String,
+    /// Nom del proveïdor pels errors ("Google")
+    provider: &'static str,
+    /// Codi d'error si no es poden obtenir les claus
+    unavailable_code: &'static str,
+    cache: Arc<RwLock<Option<CertsCache>>>,
+}
+
+impl Jwks {
+    pub fn new(client: Client, url: impl Into<String>, provider: &'static str, unavailable_code: &'static str) -> Self {
+        Self {
+            client,
+            url: url.into(),
+            provider,
+            unavailable_code,
+            cache: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Canvia l'URL de les claus (p. ex. per apuntar a un servidor de proves)
+    #[cfg(test)]
+    pub fn with_url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    /// Clau amb què s'ha signat `token` (segons el `kid` de la capçalera).
+    /// Els errors del token es retornen com a `Unauthorized` amb `invalid_code`.
+    pub async fn decoding_key(&self, token: &str, invalid_code: &'static str) -> AppResult<DecodingKey> {
+        let certs = self.get_certs().await?;
+
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|_| AppError::Unauthorized(invalid_code, "Invalid token header".to_string()))?;
+
+        let kid = header
+            .kid
+            .ok_or_else(|| AppError::Unauthorized(invalid_code, "Token missing kid".to_string()))?;
+
+        let jwk = certs
+            .iter()
+            .find(|k| k.kid == kid)
+            .ok_or_else(|| AppError::Unauthorized(invalid_code, "Unknown signing key".to_string()))?;
+
+        DecodingKey::from_rsa_components(&jwk.n, &jwk.e)
+            .map_err(|_| AppError::Unauthorized(invalid_code, "Invalid key format".to_string()))
+    }
+
+    /// Obté les claus públiques (amb cache)
+    async fn get_certs(&self) -> AppResult<Vec<Jwk>> {
+        // Comprovar cache
+        {
+            let cache = self.cache.read().await;
+            if let Some(ref cached) = *cache
+                && cached.is_fresh_at(Instant::now())
+            {
+                return Ok(cached.certs.clone());
+            }
+        }
+
+        // Obtenir noves claus
+        let (certs, ttl) = self.fetch_certs().await?;
+
+        // Actualitzar cache
+        {
+            let mut cache = self.cache.write().await;
+            *cache = Some(CertsCache {
+                certs: certs.clone(),
+                fetched_at: Instant::now(),
+                ttl,
+            });
+        }
+
+        Ok(certs)
+    }
+
+    /// Descarrega les claus públiques i la durada de cache que n'indica la resposta
+    async fn fetch_certs(&self) -> AppResult<(Vec<Jwk>, Duration)> {
+        let response = self
+            .client
+            .get(&self.url)
+            .send()
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to fetch {} certs: {:?}", self.provider, e);
+                AppError::ExternalApi(self.unavailable_code, format!("Failed to fetch {} certificates", self.provider))
+            })?;
+
+        if !response.status().is_success() {
+            return Err(AppError::ExternalApi(self.unavailable_code, format!(
+                "{} certs API returned {}",
+                self.provider,
+                response.status()
+            )));
+        }
+
+        let ttl = cache_ttl(response.headers());
+
+        let certs: JwkSet = response.json().await.map_err(|e| {
+            tracing::error!("Failed to parse {} certs: {:?}", self.provider, e);
+            AppError::ExternalApi(self.unavailable_code, format!("Failed to parse {} certificates", self.provider))
+        })?;
+
+        Ok((certs.keys, ttl))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use reqwest::header::HeaderValue;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn headers(cache_control: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CACHE_CONTROL, HeaderValue::from_str(cache_control).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_cache_ttl_from_cache_control() {
+        assert_eq!(cache_ttl(&HeaderMap::new()), DEFAULT_CERTS_CACHE_DURATION);
+        assert_eq!(cache_ttl(&headers("public, max-age=21972, must-revalidate")), Duration::from_secs(21972));
+        assert_eq!(cache_ttl(&headers("Max-Age=60")), Duration::from_secs(60));
+        assert_eq!(cache_ttl(&headers("max-age=soon")), DEFAULT_CERTS_CACHE_DURATION);
+        assert_eq!(cache_ttl(&headers("max-age=600, no-cache")), Duration::ZERO);
+        assert_eq!(cache_ttl(&headers("no-store")), Duration::ZERO);
+    }
+
+    async fn certs_server(cache_control: &str, expected_fetches: u64) -> MockServer {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/certs"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("Cache-Control", cache_control)
+                    .set_body_json(serde_json::json!({ "keys": [{ "kid": "k1", "n": "AQAB", "e": "AQAB" }] })),
+            )
+            .expect(expected_fetches)
+            .mount(&server)
+            .await;
+        server
+    }
+
+    fn jwks(server: &MockServer) -> Jwks {
+        Jwks::new(Client::new(), format!("{}/certs", server.uri()), "Test", "TEST_CERTS_UNAVAILABLE")
+    }
+
+    #[tokio::test]
+    async fn test_certs_cache_expires_at_max_age() {
+        let server = certs_server("public, max-age=120", 1).await;
+        let jwks = jwks(&server);
+
+        jwks.get_certs().await.unwrap();
+        // Dins del max-age no es torna a descarregar
+        jwks.get_certs().await.unwrap();
+
+        let cache = jwks.cache.read().await;
+        let cached = cache.as_ref().unwrap();
+        assert_eq!(cached.ttl, Duration::from_secs(120));
+        assert!(cached.is_fresh_at(cached.fetched_at + Duration::from_secs(119)));
+        assert!(!cached.is_fresh_at(cached.fetched_at + Duration::from_secs(120)));
+    }
+
+    #[tokio::test]
+    async fn test_certs_no_cache_forces_refetch() {
+        let server = certs_server("no-cache", 2).await;
+        let jwks = jwks(&server);
+
+        jwks.get_certs().await.unwrap();
+        jwks.get_certs().await.unwrap();
+    }
+}
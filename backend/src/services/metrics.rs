@@ -0,0 +1,60 @@
+//! Mètriques de Prometheus (servides a `/metrics`)
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+
+use crate::error::{AppError, AppResult};
+
+pub const SCHEDULES_GENERATED: &str = "pvpc_schedules_generated_total";
+pub const ESIOS_REQUESTS: &str = "pvpc_esios_requests_total";
+pub const ESIOS_REQUEST_DURATION: &str = "pvpc_esios_request_duration_seconds";
+pub const ACTIONS_MISSED: &str = "pvpc_actions_missed_total";
+pub const AUTH_ATTEMPTS: &str = "pvpc_auth_attempts_total";
+
+/// Límits (en segons) dels buckets de l'histograma de latència d'ESIOS
+const ESIOS_DURATION_BUCKETS: [f64; 9] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Instal·la el recorder global (només la primera vegada) i en retorna el handle
+pub fn init() -> PrometheusHandle {
+    HANDLE
+        .get_or_init(|| {
+            PrometheusBuilder::new()
+                .set_buckets_for_metric(Matcher::Full(ESIOS_REQUEST_DURATION.to_string()), &ESIOS_DURATION_BUCKETS)
+                .expect("ESIOS duration buckets are not empty")
+                .install_recorder()
+                .expect("Failed to install Prometheus recorder")
+        })
+        .clone()
+}
+
+/// Schedules (scheduled_actions) creats, tant pel scheduler diari com per l'API
+pub fn record_schedules_generated(count: usize) {
+    ::metrics::counter!(SCHEDULES_GENERATED).increment(count as u64);
+}
+
+/// Resultat i durada d'una petició HTTP a ESIOS (cada reintent compta per separat)
+pub fn record_esios_request(success: bool, elapsed: Duration) {
+    let result = if success { "success" } else { "failure" };
+    ::metrics::counter!(ESIOS_REQUESTS, "result" => result).increment(1);
+    ::metrics::histogram!(ESIOS_REQUEST_DURATION).record(elapsed.as_secs_f64());
+}
+
+/// Accions pendents que han passat la seva hora sense executar-se
+pub fn record_actions_missed(count: u64) {
+    ::metrics::counter!(ACTIONS_MISSED).increment(count);
+}
+
+/// Intent d'autenticació (`method`: google, refresh o token). Els errors que no són
+/// d'autenticació (p. ex. de base de dades) no compten com a intent fallit.
+pub fn record_auth<T>(method: &'static str, result: &AppResult<T>) {
+    let result = match result {
+        Ok(_) => "success",
+        Err(AppError::Unauthorized(..)) => "failure",
+        Err(_) => return,
+    };
+    ::metrics::counter!(AUTH_ATTEMPTS, "method" => method, "result" => result).increment(1);
+}
@@ -1,3 +1,18 @@
+pub mod apple;
+pub mod clock;
+pub mod device_plan;
+pub mod energy_tariff;
+pub mod export;
+pub mod forecast;
 pub mod google;
+pub mod ical;
+pub mod jwks;
+pub mod metrics;
+pub mod notifications;
+pub mod price_history;
 pub mod pvpc;
+pub mod schedule_events;
+pub mod scheduled_actions;
 pub mod scheduler;
+pub mod secrets;
+pub mod webhooks;
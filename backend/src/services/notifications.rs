@@ -0,0 +1,371 @@
+//! Notificacions per correu als usuaris
+//!
+//! El resum setmanal es calcula a partir de les accions dels darrers 7 dies i s'envia
+//! amb un `Notifier`. En producció és `SmtpNotifier` (si hi ha SMTP configurat);
+//! sense SMTP, i als tests, `NoopNotifier` no envia res.
+
+use chrono::{Duration, NaiveDate, NaiveTime};
+use futures_util::future::BoxFuture;
+use lettre::message::header::ContentType;
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use serde::Serialize;
+use sqlx::{FromRow, PgPool};
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::User;
+
+/// Dies que cobreix el resum setmanal
+pub const SUMMARY_DAYS: i64 = 7;
+
+/// Hora més barata de la setmana
+#[derive(Debug, Clone, PartialEq, Serialize, FromRow)]
+pub struct CheapestHour {
+    pub date: NaiveDate,
+    pub hour: i16,
+    /// €/kWh
+    pub price: f64,
+}
+
+/// Resum de la setmana d'un usuari
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WeeklySummary {
+    /// Primer i darrer dia del resum (inclosos)
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub actions_executed: u32,
+    pub actions_missed: u32,
+    /// Energia de les engegades executades amb potència reportada
+    pub total_kwh: f64,
+    /// Estalvi d'aquesta energia respecte al preu mitjà de cada dia (€)
+    pub total_eur_saved: f64,
+    pub cheapest_hour_this_week: Option<CheapestHour>,
+}
+
+/// Envia notificacions als usuaris
+pub trait Notifier: Send + Sync {
+    fn send_weekly_summary<'a>(&'a self, user: &'a User, summary: WeeklySummary) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Notifier que no envia res (tests i servidors sense SMTP)
+pub struct NoopNotifier;
+
+impl Notifier for NoopNotifier {
+    fn send_weekly_summary<'a>(&'a self, user: &'a User, _summary: WeeklySummary) -> BoxFuture<'a, anyhow::Result<()>> {
+        tracing::debug!("Resum setmanal de l'usuari {} no enviat: no hi ha SMTP configurat", user.id);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// Notifier que envia correus per SMTP (STARTTLS)
+pub struct SmtpNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpNotifier {
+    /// Crea el notifier a partir de la configuració. `None` si no hi ha `SMTP_HOST`.
+    pub fn from_config(config: &Config) -> anyhow::Result<Option<Self>> {
+        let Some(host) = &config.smtp_host else {
+            return Ok(None);
+        };
+        let from = config
+            .smtp_from
+            .as_deref()
+            .ok_or_else(|| anyhow::anyhow!("SMTP_FROM is required when SMTP_HOST is set"))?
+            .parse()?;
+
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?.port(config.smtp_port);
+        if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+            builder = builder.credentials(Credentials::new(username.clone(), password.clone()));
+        }
+
+        Ok(Some(Self { transport: builder.build(), from }))
+    }
+}
+
+impl Notifier for SmtpNotifier {
+    fn send_weekly_summary<'a>(&'a self, user: &'a User, summary: WeeklySummary) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let message = Message::builder()
+                .from(self.from.clone())
+                .to(Mailbox::new(user.name.clone(), user.email.parse()?))
+                .subject(format!("Resum setmanal PVPC Cheap ({} - {})", summary.from, summary.to))
+                .header(ContentType::TEXT_PLAIN)
+                .body(summary_text(&summary))?;
+
+            self.transport.send(message).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Cos del correu del resum setmanal
+fn summary_text(summary: &WeeklySummary) -> String {
+    let cheapest = match &summary.cheapest_hour_this_week {
+        Some(c) => format!("{} a les {:02}:00 ({:.4} €/kWh)", c.date, c.hour, c.price),
+        None => "sense preus".to_string(),
+    };
+
+    format!(
+        "Resum del {} al {}\n\n\
+         Accions executades: {}\n\
+         Accions perdudes: {}\n\
+         Energia consumida: {:.2} kWh\n\
+         Estalvi respecte al preu mitjà: {:.2} €\n\
+         Hora més barata: {}\n",
+        summary.from,
+        summary.to,
+        summary.actions_executed,
+        summary.actions_missed,
+        summary.total_kwh,
+        summary.total_eur_saved,
+        cheapest
+    )
+}
+
+/// Acció de la setmana amb el que cal per calcular-ne l'energia i l'estalvi
+#[derive(Debug, FromRow)]
+struct SummaryActionRow {
+    status: String,
+    action: String,
+    start_time: NaiveTime,
+    end_time: NaiveTime,
+    price_per_kwh: Option<f64>,
+    /// Preu mitjà del dia segons l'historial de preus
+    day_average_price: Option<f64>,
+    actual_power_watts: Option<f64>,
+}
+
+/// Agrega les accions de la setmana. L'energia només es compta de les engegades
+/// executades amb potència reportada, i l'estalvi només si se'n coneixen els preus.
+fn summarize(
+    from: NaiveDate,
+    to: NaiveDate,
+    rows: &[SummaryActionRow],
+    cheapest: Option<CheapestHour>,
+) -> WeeklySummary {
+    let mut summary = WeeklySummary {
+        from,
+        to,
+        actions_executed: 0,
+        actions_missed: 0,
+        total_kwh: 0.0,
+        total_eur_saved: 0.0,
+        cheapest_hour_this_week: cheapest,
+    };
+
+    for row in rows {
+        if row.status == "missed" {
+            summary.actions_missed += 1;
+            continue;
+        }
+        if !row.status.starts_with("executed") {
+            continue;
+        }
+        summary.actions_executed += 1;
+
+        let Some(watts) = row.actual_power_watts.filter(|_| row.action == "on") else {
+            continue;
+        };
+        let mut duration = row.end_time - row.start_time;
+        if duration <= Duration::zero() {
+            duration += Duration::days(1);
+        }
+        let kwh = watts / 1000.0 * duration.num_seconds() as f64 / 3600.0;
+        summary.total_kwh += kwh;
+
+        if let (Some(price), Some(average)) = (row.price_per_kwh, row.day_average_price) {
+            summary.total_eur_saved += kwh * (average - price);
+        }
+    }
+
+    summary
+}
+
+/// Hora més barata entre dues dates (incloses) segons l'historial de preus
+pub async fn cheapest_hour(pool: &PgPool, from: NaiveDate, to: NaiveDate) -> Result<Option<CheapestHour>, sqlx::Error> {
+    sqlx::query_as::<_, CheapestHour>(
+        r#"
+        SELECT price_date as date, hour, price
+        FROM cached_prices
+        WHERE price_date BETWEEN $1 AND $2
+        ORDER BY price, price_date, hour
+        LIMIT 1
+        "#
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_optional(pool)
+    .await
+}
+
+/// Resum de l'usuari dels `SUMMARY_DAYS` dies que acaben a `to`
+pub async fn weekly_summary(
+    pool: &PgPool,
+    user_id: Uuid,
+    to: NaiveDate,
+    cheapest: Option<CheapestHour>,
+) -> Result<WeeklySummary, sqlx::Error> {
+    let from = to - Duration::days(SUMMARY_DAYS - 1);
+
+    let rows = sqlx::query_as::<_, SummaryActionRow>(
+        r#"
+        SELECT
+            sa.status, sa.action, sa.start_time, sa.end_time,
+            sa.price_per_kwh::float8 as price_per_kwh,
+            day.average as day_average_price,
+            sa.actual_power_watts
+        FROM scheduled_actions sa
+        JOIN devices d ON sa.device_id = d.id
+        LEFT JOIN LATERAL (
+            SELECT AVG(cp.price) as average FROM cached_prices cp WHERE cp.price_date = sa.scheduled_date
+        ) day ON true
+        WHERE d.user_id = $1 AND sa.scheduled_date BETWEEN $2 AND $3
+        "#
+    )
+    .bind(user_id)
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(summarize(from, to, &rows, cheapest))
+}
+
+/// Envia el resum de la setmana que acaba a `to` als usuaris que hi han tingut accions
+/// executades o perdudes i no han desactivat `email_notifications`. Retorna quants resums s'han enviat.
+pub async fn send_weekly_summaries(pool: &PgPool, notifier: &dyn Notifier, to: NaiveDate) -> Result<usize, sqlx::Error> {
+    let from = to - Duration::days(SUMMARY_DAYS - 1);
+    let cheapest = cheapest_hour(pool, from, to).await?;
+
+    let users = sqlx::query_as::<_, User>(
+        r#"
+        SELECT u.*
+        FROM users u
+        WHERE EXISTS (
+            SELECT 1
+            FROM scheduled_actions sa
+            JOIN devices d ON sa.device_id = d.id
+            WHERE d.user_id = u.id AND sa.scheduled_date BETWEEN $1 AND $2
+        )
+        AND NOT EXISTS (
+            SELECT 1 FROM user_preferences p WHERE p.user_id = u.id AND NOT p.email_notifications
+        )
+        ORDER BY u.created_at
+        "#
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(pool)
+    .await?;
+
+    let mut sent = 0;
+    for user in users {
+        let summary = weekly_summary(pool, user.id, to, cheapest.clone()).await?;
+        if summary.actions_executed == 0 && summary.actions_missed == 0 {
+            continue;
+        }
+
+        match notifier.send_weekly_summary(&user, summary).await {
+            Ok(()) => sent += 1,
+            Err(e) => tracing::error!("Error enviant el resum setmanal a l'usuari {}: {}", user.id, e),
+        }
+    }
+
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::{create_device, create_rule, create_user};
+
+    fn time(hour: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, 0, 0).unwrap()
+    }
+
+    fn row(status: &str, action: &str, start: u32, end: u32, watts: Option<f64>) -> SummaryActionRow {
+        SummaryActionRow {
+            status: status.to_string(),
+            action: action.to_string(),
+            start_time: time(start),
+            end_time: time(end),
+            price_per_kwh: Some(0.10),
+            day_average_price: Some(0.15),
+            actual_power_watts: watts,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let date = NaiveDate::from_ymd_opt(2030, 1, 7).unwrap();
+        let rows = [
+            // 2 kW durant 2 hores (creua mitjanit): 4 kWh, 0.05 €/kWh més barat que la mitjana
+            row("executed_on", "on", 23, 1, Some(2000.0)),
+            // Sense potència o apagades: compten com a executades però no sumen energia
+            row("executed", "on", 3, 4, None),
+            row("executed_off", "off", 4, 5, Some(2000.0)),
+            row("missed", "on", 5, 6, Some(2000.0)),
+            row("pending", "on", 6, 7, Some(2000.0)),
+        ];
+
+        let summary = summarize(date - Duration::days(6), date, &rows, None);
+        assert_eq!((summary.actions_executed, summary.actions_missed), (3, 1));
+        assert!((summary.total_kwh - 4.0).abs() < 1e-9);
+        assert!((summary.total_eur_saved - 0.2).abs() < 1e-9);
+        assert!(summary_text(&summary).contains("Energia consumida: 4.00 kWh"));
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_send_weekly_summaries_skips_inactive_users(pool: PgPool) {
+        let active = create_user(&pool, "active").await;
+        create_user(&pool, "inactive").await;
+        let device = create_device(&pool, active.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+
+        let to = NaiveDate::from_ymd_opt(2030, 1, 7).unwrap();
+        sqlx::query(
+            r#"
+            INSERT INTO scheduled_actions
+                (rule_id, device_id, scheduled_date, start_time, end_time, price_per_kwh, status, actual_power_watts)
+            VALUES ($1, $2, $3, '02:00', '03:00', 0.10, 'executed_on', 1000)
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .bind(to)
+        .execute(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO cached_prices (price_date, hour, price) VALUES ($1, 2, 0.10), ($1, 3, 0.20)")
+            .bind(to)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let summary = weekly_summary(&pool, active.id, to, None).await.unwrap();
+        assert_eq!(summary.actions_executed, 1);
+        assert!((summary.total_kwh - 1.0).abs() < 1e-9);
+        assert!((summary.total_eur_saved - 0.05).abs() < 1e-9);
+
+        let cheapest = cheapest_hour(&pool, to - Duration::days(6), to).await.unwrap().unwrap();
+        assert_eq!((cheapest.hour, cheapest.price), (2, 0.10));
+
+        assert_eq!(send_weekly_summaries(&pool, &NoopNotifier, to).await.unwrap(), 1);
+        // La setmana anterior no hi ha res a resumir
+        assert_eq!(send_weekly_summaries(&pool, &NoopNotifier, to - Duration::days(7)).await.unwrap(), 0);
+
+        // Amb les notificacions per correu desactivades no se li envia
+        sqlx::query("INSERT INTO user_preferences (user_id, email_notifications) VALUES ($1, false)")
+            .bind(active.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(send_weekly_summaries(&pool, &NoopNotifier, to).await.unwrap(), 0);
+    }
+}
@@ -0,0 +1,227 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use shared::{DailyPrices, HourlyPrice};
+use sqlx::{FromRow, PgConnection, PgExecutor, PgPool};
+
+use crate::db::models::{ActionType, Rule, RuleMode};
+use crate::services::energy_tariff::PriceAdjustment;
+use crate::services::scheduler::{
+    calculate_baseline_price, is_cheaper_than_baseline, optimal_hours_for_rule, OptimalHours, PriceSlots,
+};
+
+#[derive(Debug, FromRow)]
+struct CachedPriceRow {
+    price_date: NaiveDate,
+    hour: i16,
+    price: f64,
+}
+
+/// Estat de l'obtenció dels preus d'un dia (taula `pvpc_fetch_log`)
+#[derive(Debug, FromRow)]
+pub struct FetchLog {
+    pub price_date: NaiveDate,
+    /// Últim cop que es van obtenir els preus del dia
+    pub fetched_at: Option<DateTime<Utc>>,
+    pub hours_received: i32,
+    /// Error de l'últim intent, si ha fallat després de l'últim èxit
+    pub last_error: Option<String>,
+    pub last_error_at: Option<DateTime<Utc>>,
+}
+
+/// Desa els preus d'un dia a l'historial (sobreescriu els que ja existien) i en registra l'obtenció
+pub async fn store_prices(pool: &PgPool, prices: &DailyPrices) -> Result<(), sqlx::Error> {
+    if prices.prices.is_empty() {
+        return Ok(());
+    }
+
+    let hours: Vec<i16> = prices.prices.iter().map(|p| p.hour as i16).collect();
+    // L'historial és compartit entre usuaris: sempre el preu de ESIOS, sense impostos
+    let values: Vec<f64> = prices.prices.iter().map(|p| p.raw_price.unwrap_or(p.price)).collect();
+
+    sqlx::query(
+        r#"
+        INSERT INTO cached_prices (price_date, hour, price)
+        SELECT $1, u.hour, u.price
+        FROM UNNEST($2::smallint[], $3::float8[]) AS u(hour, price)
+        ON CONFLICT (price_date, hour)
+        DO UPDATE SET price = EXCLUDED.price, fetched_at = NOW()
+        "#
+    )
+    .bind(prices.date)
+    .bind(&hours)
+    .bind(&values)
+    .execute(pool)
+    .await?;
+
+    sqlx::query(
+        r#"
+        INSERT INTO pvpc_fetch_log (price_date, fetched_at, hours_received)
+        VALUES ($1, NOW(), $2)
+        ON CONFLICT (price_date)
+        DO UPDATE SET fetched_at = NOW(), hours_received = EXCLUDED.hours_received,
+                      last_error = NULL, last_error_at = NULL
+        "#
+    )
+    .bind(prices.date)
+    .bind(prices.prices.len() as i32)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Registra que no s'han pogut obtenir els preus d'un dia (es manté l'últim èxit)
+pub async fn record_fetch_failure(pool: &PgPool, date: NaiveDate, error: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO pvpc_fetch_log (price_date, last_error, last_error_at)
+        VALUES ($1, $2, NOW())
+        ON CONFLICT (price_date)
+        DO UPDATE SET last_error = EXCLUDED.last_error, last_error_at = NOW()
+        "#
+    )
+    .bind(date)
+    .bind(error)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Estat de l'obtenció dels preus dels dies indicats (els dies que no s'han intentat no hi surten)
+pub async fn fetch_logs(pool: &PgPool, dates: &[NaiveDate]) -> Result<Vec<FetchLog>, sqlx::Error> {
+    sqlx::query_as::<_, FetchLog>("SELECT * FROM pvpc_fetch_log WHERE price_date = ANY($1)")
+        .bind(dates)
+        .fetch_all(pool)
+        .await
+}
+
+/// Temps (segons) des que es van desar els últims preus, o None si l'historial és buit
+pub async fn latest_prices_age(pool: &PgPool) -> Result<Option<u64>, sqlx::Error> {
+    let age: Option<i64> =
+        sqlx::query_scalar("SELECT EXTRACT(EPOCH FROM NOW() - MAX(fetched_at))::bigint FROM cached_prices")
+            .fetch_one(pool)
+            .await?;
+
+    Ok(age.map(|secs| secs.max(0) as u64))
+}
+
+/// Si hi ha preus desats per `date`
+pub async fn has_prices_for(pool: &PgPool, date: NaiveDate) -> Result<bool, sqlx::Error> {
+    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM cached_prices WHERE price_date = $1)")
+        .bind(date)
+        .fetch_one(pool)
+        .await
+}
+
+/// Obté els preus desats entre dues dates (incloses), agrupats per dia
+pub async fn get_prices_between(
+    executor: impl PgExecutor<'_>,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<DailyPrices>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, CachedPriceRow>(
+        r#"
+        SELECT price_date, hour, price
+        FROM cached_prices
+        WHERE price_date BETWEEN $1 AND $2
+        ORDER BY price_date, hour
+        "#
+    )
+    .bind(from)
+    .bind(to)
+    .fetch_all(executor)
+    .await?;
+
+    let mut days: Vec<DailyPrices> = Vec::new();
+    for row in rows {
+        let price = HourlyPrice {
+            hour: row.hour as u8,
+            price: row.price,
+            raw_price: None,
+        };
+        match days.last_mut() {
+            Some(day) if day.date == row.price_date => day.prices.push(price),
+            _ => days.push(DailyPrices {
+                date: row.price_date,
+                prices: vec![price],
+                quarter_hours: vec![],
+            }),
+        }
+    }
+
+    Ok(days)
+}
+
+/// Impostos i càrrecs del propietari de la regla
+async fn owner_price_adjustment(conn: &mut PgConnection, rule: &Rule) -> Result<PriceAdjustment, sqlx::Error> {
+    let owner = sqlx::query_as::<_, (f64, f64)>(
+        r#"
+        SELECT u.energy_surcharge_eur_kwh, u.tax_multiplier
+        FROM rules r
+        LEFT JOIN devices d ON r.device_id = d.id
+        LEFT JOIN device_groups g ON r.device_group_id = g.id
+        JOIN users u ON u.id = COALESCE(d.user_id, g.user_id)
+        WHERE r.id = $1
+        "#
+    )
+    .bind(rule.id)
+    .fetch_optional(conn)
+    .await?;
+
+    Ok(owner
+        .map(|(surcharge_eur_kwh, tax_multiplier)| PriceAdjustment { surcharge_eur_kwh, tax_multiplier })
+        .unwrap_or_default())
+}
+
+/// Comprova la porta "només si és més barat que els darrers dies" d'una regla
+///
+/// Compara el preu mitjà de les hores calculades amb el que la mateixa regla
+/// hauria obtingut els `baseline_days` dies anteriors. Les regles sense
+/// `baseline_days` i les d'apagar (`TurnOff`) sempre passen.
+pub async fn passes_baseline_gate(
+    conn: &mut PgConnection,
+    rule: &Rule,
+    date: NaiveDate,
+    optimal: &OptimalHours,
+) -> Result<bool, sqlx::Error> {
+    let Some(days) = rule.baseline_days else {
+        return Ok(true);
+    };
+
+    // Les regles d'apagar i les d'hores fixes no depenen del preu
+    if rule.action_type == ActionType::TurnOff || rule.mode == RuleMode::Fixed {
+        return Ok(true);
+    }
+
+    // L'historial desa el preu de ESIOS: s'hi apliquen els impostos i càrrecs de l'usuari
+    // perquè sigui comparable amb les hores calculades
+    let adjustment = owner_price_adjustment(&mut *conn, rule).await?;
+    let history: Vec<DailyPrices> = get_prices_between(
+        &mut *conn,
+        date - chrono::Duration::days(days as i64),
+        date - chrono::Duration::days(1),
+    )
+    .await?
+    .into_iter()
+    .map(|day| adjustment.apply(day))
+    .collect();
+
+    // Els dies anteriors es calculen amb la mateixa regla (finestres, blackouts, sub-pressupostos)
+    // L'historial només desa preus horaris
+    let baseline =
+        calculate_baseline_price(&history, |prices| optimal_hours_for_rule(&PriceSlots::hourly(prices), rule));
+
+    let passes = is_cheaper_than_baseline(optimal, baseline, rule.baseline_margin_pct);
+    if !passes {
+        tracing::info!(
+            "Regla '{}' omesa per {}: preu mitjà {:.5} no prou inferior a la referència {:.5} ({} dies)",
+            rule.name,
+            date,
+            optimal.average_price().unwrap_or_default(),
+            baseline.unwrap_or_default(),
+            days
+        );
+    }
+
+    Ok(passes)
+}
@@ -1,19 +1,56 @@
-use chrono::NaiveDate;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{Datelike, NaiveDate, Weekday};
+use chrono_tz::Tz;
 use reqwest::Client;
-use serde::Deserialize;
-use shared::{DailyPrices, HourlyPrice};
+use serde::{Deserialize, Serialize};
+use shared::{DailyPrices, HourlyPrice, PricePoint};
+use tokio::sync::Semaphore;
+use uuid::Uuid;
 
 use crate::error::{AppError, AppResult};
+use crate::middleware::request_id::current_request_id;
+use crate::services::energy_tariff::PriceAdjustment;
+use crate::services::{clock, metrics};
 
 /// API oficial de ESIOS (Red Eléctrica de España)
-/// Indicador 1001 = PVPC (Precio Voluntario para el Pequeño Consumidor)
 /// Documentació: https://api.esios.ree.es/
 /// Per obtenir el token, enviar email a consultasios@ree.es
-const ESIOS_API_URL: &str = "https://api.esios.ree.es/indicators/1001";
+const ESIOS_API_URL: &str = "https://api.esios.ree.es";
+
+/// Indicador 1001 = PVPC (Precio Voluntario para el Pequeño Consumidor)
+pub const INDICATOR_PVPC: u32 = 1001;
+
+/// Indicador 600 = preu del mercat diari (spot)
+pub const INDICATOR_SPOT: u32 = 600;
+
+/// Capçalera amb l'identificador de la petició, en les dues direccions
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Peticions simultànies a ESIOS per defecte (per no superar els límits de l'API)
+const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 2;
+
+/// Reintents per defecte després del primer intent fallit
+const DEFAULT_MAX_RETRIES: u8 = 3;
+
+/// Espera abans del primer reintent per defecte
+const DEFAULT_BASE_DELAY: Duration = Duration::from_secs(5);
+
+/// Multiplicador de `base_delay` per cada reintent: 5 s, 30 s i 120 s amb els valors per defecte.
+/// A partir del tercer reintent es manté l'últim.
+const BACKOFF_FACTORS: [u32; 3] = [1, 6, 24];
+
+/// Hores mínimes per considerar publicats els preus de demà per defecte (el dia sencer)
+const DEFAULT_MIN_TOMORROW_HOURS: usize = 24;
 
 /// GeoID per la península (8741)
 const GEO_ID_PENINSULA: i32 = 8741;
 
+/// GeoID d'Espanya (3) als indicadors de mercat, que inclouen altres països
+const GEO_ID_SPAIN: i32 = 3;
+
 /// Resposta de l'API ESIOS
 #[derive(Debug, Deserialize)]
 struct EsiosResponse {
@@ -33,10 +70,57 @@ struct EsiosValue {
     geo_id: Option<i32>,
 }
 
+/// Què fer amb els preus repetits d'una mateixa hora (l'hora que es repeteix el
+/// darrer diumenge d'octubre, quan s'endarrereix el rellotge)
+/// Es serialitza amb els mateixos noms que `PVPC_DUPLICATE_HOURS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum DuplicateHourPolicy {
+    /// Es queda el primer preu (el de l'horari d'estiu)
+    #[serde(rename = "first")]
+    KeepFirst,
+    /// Un sol preu amb la mitjana dels repetits
+    #[default]
+    #[serde(rename = "average")]
+    Average,
+    /// Es mantenen tots, en ordre cronològic i amb la mateixa hora. El càlcul d'hores
+    /// òptimes no els distingeix: pot seleccionar-los tots dos o quedar-se'n només un.
+    #[serde(rename = "both")]
+    KeepBoth,
+}
+
+impl DuplicateHourPolicy {
+    /// Llegeix la política d'un valor de configuració: "first", "average" o "both"
+    fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "first" => Some(Self::KeepFirst),
+            "average" => Some(Self::Average),
+            "both" => Some(Self::KeepBoth),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct PvpcClient {
     client: Client,
+    base_url: String,
     token: Option<String>,
+    /// Si és cert, un dia normal amb menys de 24 preus es considera un error
+    strict: bool,
+    /// Com es tracten les hores repetides del canvi d'horari
+    duplicate_hours: DuplicateHourPolicy,
+    /// Hores a partir de les quals els preus de demà es donen per publicats (vegeu `is_complete`)
+    min_tomorrow_hours: usize,
+    /// Limita les peticions simultànies a ESIOS (compartit entre clons)
+    request_permits: Arc<Semaphore>,
+    /// Reintents si ESIOS no respon o retorna un error 5xx
+    max_retries: u8,
+    /// Espera abans del primer reintent (les següents creixen segons `BACKOFF_FACTORS`)
+    base_delay: Duration,
+    /// Impostos i càrrecs de l'usuari que s'apliquen als preus PVPC (vegeu `with_price_adjustment`)
+    price_adjustment: PriceAdjustment,
+    /// Identificador que s'envia a ESIOS a `X-Request-ID` (vegeu `with_request_id`)
+    request_id: Option<Uuid>,
 }
 
 impl PvpcClient {
@@ -51,29 +135,163 @@ impl PvpcClient {
             );
         }
 
+        // Mode estricte opcional (per defecte, permissiu)
+        let strict = std::env::var("PVPC_STRICT_PRICES")
+            .map(|v| matches!(v.trim().to_lowercase().as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+
+        let duplicate_hours = match std::env::var("PVPC_DUPLICATE_HOURS") {
+            Ok(value) => DuplicateHourPolicy::parse(&value).unwrap_or_else(|| {
+                tracing::warn!("PVPC_DUPLICATE_HOURS invàlid ({}): es fa la mitjana", value);
+                DuplicateHourPolicy::default()
+            }),
+            Err(_) => DuplicateHourPolicy::default(),
+        };
+
+        let min_tomorrow_hours = std::env::var("PVPC_MIN_TOMORROW_HOURS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MIN_TOMORROW_HOURS);
+
+        let max_concurrent_requests = std::env::var("ESIOS_MAX_CONCURRENT_REQUESTS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|n| *n > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_REQUESTS);
+
+        let max_retries = std::env::var("ESIOS_MAX_RETRIES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_MAX_RETRIES);
+
+        let base_delay = std::env::var("ESIOS_BASE_DELAY_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_BASE_DELAY);
+
         Self {
             client: Client::new(),
+            base_url: ESIOS_API_URL.to_string(),
             token,
+            strict,
+            duplicate_hours,
+            min_tomorrow_hours,
+            request_permits: Arc::new(Semaphore::new(max_concurrent_requests)),
+            max_retries,
+            base_delay,
+            price_adjustment: PriceAdjustment::default(),
+            request_id: None,
         }
     }
 
     /// Crea un client amb un token específic
+    #[cfg(test)]
     pub fn with_token(token: String) -> Self {
         Self {
             client: Client::new(),
+            base_url: ESIOS_API_URL.to_string(),
             token: Some(token),
+            strict: false,
+            duplicate_hours: DuplicateHourPolicy::default(),
+            min_tomorrow_hours: DEFAULT_MIN_TOMORROW_HOURS,
+            request_permits: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_REQUESTS)),
+            max_retries: DEFAULT_MAX_RETRIES,
+            base_delay: DEFAULT_BASE_DELAY,
+            price_adjustment: PriceAdjustment::default(),
+            request_id: None,
+        }
+    }
+
+    /// Si hi ha un token de ESIOS (global o de l'usuari) configurat
+    pub fn has_token(&self) -> bool {
+        self.token.is_some()
+    }
+
+    /// Identificador per relacionar les peticions a ESIOS amb la feina que les origina
+    ///
+    /// S'envia a la capçalera `X-Request-ID`. Sense, s'envia el de la petició HTTP en curs
+    /// (`current_request_id`) o, fora d'una petició, un de nou per cada consulta.
+    pub fn with_request_id(mut self, request_id: Option<Uuid>) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    /// Canvia la política de reintents (0 = un sol intent)
+    pub fn with_retries(mut self, max_retries: u8, base_delay: Duration) -> Self {
+        self.max_retries = max_retries;
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Còpia del client que fa servir el token d'un usuari, si en té, en lloc del global.
+    /// Comparteix el límit de peticions simultànies amb l'original.
+    pub fn with_user_token(&self, token: Option<String>) -> Self {
+        let mut client = self.clone();
+        if token.is_some() {
+            client.token = token;
         }
+        client
+    }
+
+    /// Els preus PVPC (avui, demà i per data) inclouran els impostos i càrrecs de l'usuari,
+    /// amb el preu de ESIOS a `raw_price`. Els altres indicadors no es modifiquen.
+    pub fn with_price_adjustment(mut self, adjustment: PriceAdjustment) -> Self {
+        self.price_adjustment = adjustment;
+        self
+    }
+
+    /// Canvia el màxim de peticions simultànies a ESIOS
+    #[cfg(test)]
+    pub fn with_max_concurrent_requests(mut self, max: usize) -> Self {
+        self.request_permits = Arc::new(Semaphore::new(max.max(1)));
+        self
+    }
+
+    /// Canvia l'URL base de l'API (p. ex. per apuntar a un servidor de proves)
+    #[cfg(test)]
+    pub fn with_base_url(mut self, base_url: impl Into<String>) -> Self {
+        self.base_url = base_url.into();
+        self
+    }
+
+    /// Canvia com es tracten les hores repetides del canvi d'horari
+    #[cfg(test)]
+    pub fn with_duplicate_hours(mut self, policy: DuplicateHourPolicy) -> Self {
+        self.duplicate_hours = policy;
+        self
+    }
+
+    /// Activa o desactiva el mode estricte
+    #[cfg(test)]
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Canvia les hores mínimes per donar per publicats els preus de demà
+    #[cfg(test)]
+    pub fn with_min_tomorrow_hours(mut self, hours: usize) -> Self {
+        self.min_tomorrow_hours = hours;
+        self
     }
 
-    /// Obté els preus PVPC per avui
-    pub async fn get_today_prices(&self) -> AppResult<DailyPrices> {
-        let today = chrono::Local::now().date_naive();
-        self.fetch_prices_for_date(today).await
+    /// Si els preus d'un dia futur ja es poden fer servir per programar-lo. ESIOS pot
+    /// publicar-los a mitges; amb una part de les hores sortiria un horari incomplet.
+    /// Els dies de canvi d'hora en tenen 23 o 25: mai se n'exigeixen més de les del dia.
+    pub fn is_complete(&self, prices: &DailyPrices) -> bool {
+        let required = self.min_tomorrow_hours.min(expected_hours_for_date(prices.date)).max(1);
+        prices.prices.len() >= required
+    }
+
+    /// Obté els preus PVPC per avui (data local de `tz`)
+    pub async fn get_today_prices(&self, tz: Tz) -> AppResult<DailyPrices> {
+        self.fetch_prices_for_date(clock::today(tz)).await
     }
 
     /// Obté els preus PVPC per demà (disponible a partir de ~20:00)
-    pub async fn get_tomorrow_prices(&self) -> AppResult<DailyPrices> {
-        let tomorrow = chrono::Local::now().date_naive() + chrono::Duration::days(1);
+    pub async fn get_tomorrow_prices(&self, tz: Tz) -> AppResult<DailyPrices> {
+        let tomorrow = clock::today(tz) + chrono::Duration::days(1);
         self.fetch_prices_for_date(tomorrow).await
     }
 
@@ -83,90 +301,368 @@ impl PvpcClient {
     }
 
     async fn fetch_prices_for_date(&self, date: NaiveDate) -> AppResult<DailyPrices> {
+        let prices = self.get_indicator_prices(INDICATOR_PVPC, date).await?;
+        Ok(self.price_adjustment.apply(prices))
+    }
+
+    /// Obté els preus horaris (€/kWh) de qualsevol indicador de ESIOS per una data.
+    /// Els indicadors amb valors quart-horaris es promitgen per hora i, a més, es retornen
+    /// sense promitjar a `quarter_hours`.
+    pub async fn get_indicator_prices(&self, indicator: u32, date: NaiveDate) -> AppResult<DailyPrices> {
+        let values = self.fetch_indicator_values(indicator, date, date).await?;
+        self.daily_prices(date, &values)
+    }
+
+    /// Obté els preus PVPC de tots els dies entre `start` i `end` (inclosos) amb una sola
+    /// petició a ESIOS. Retorna un `DailyPrices` per dia, ordenats per data; cada dia es
+    /// valida per separat com si s'hagués demanat sol.
+    pub async fn fetch_prices_for_range(&self, start: NaiveDate, end: NaiveDate) -> AppResult<Vec<DailyPrices>> {
+        let values = self.fetch_indicator_values(INDICATOR_PVPC, start, end).await?;
+
+        // Els valors venen en ordre cronològic: es reparteixen per la data local del datetime
+        let mut by_date: BTreeMap<NaiveDate, Vec<EsiosValue>> = BTreeMap::new();
+        for value in values {
+            match value.datetime.get(..10).and_then(|d| d.parse().ok()) {
+                Some(date) => by_date.entry(date).or_default().push(value),
+                None => tracing::warn!("Valor de ESIOS amb data invàlida: {}", value.datetime),
+            }
+        }
+
+        start
+            .iter_days()
+            .take_while(|date| *date <= end)
+            .map(|date| self.daily_prices(date, by_date.get(&date).map_or(&[], Vec::as_slice)))
+            .collect()
+    }
+
+    /// Valors d'un indicador entre les 00:00 de `start` i les 23:59:59 de `end`, només de la
+    /// zona que ens interessa
+    async fn fetch_indicator_values(
+        &self,
+        indicator: u32,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> AppResult<Vec<EsiosValue>> {
         let token = self.token.as_ref().ok_or_else(|| {
             AppError::ExternalApi(
+                "ESIOS_TOKEN_MISSING",
                 "ESIOS_TOKEN no configurat. Necessites un token de l'API de ESIOS.".to_string()
             )
         })?;
 
         // Construir les dates en format ISO 8601 amb timezone d'Espanya
         // L'API espera dates en format: 2024-01-15T00:00:00+01:00
-        let start_date = format!("{}T00:00:00", date);
-        let end_date = format!("{}T23:59:59", date);
+        let start_date = format!("{}T00:00:00", start);
+        let end_date = format!("{}T23:59:59", end);
 
+        let geo_id = geo_id_for_indicator(indicator);
         let url = format!(
-            "{}?start_date={}&end_date={}&geo_ids={}",
-            ESIOS_API_URL, start_date, end_date, GEO_ID_PENINSULA
+            "{}/indicators/{}?start_date={}&end_date={}&geo_ids={}",
+            self.base_url, indicator, start_date, end_date, geo_id
         );
 
-        tracing::debug!("Obtenint preus PVPC de: {}", url);
+        let body = self.fetch_with_retry(&url, token).await?;
+
+        let data: EsiosResponse = serde_json::from_str(&body).map_err(|e| {
+            tracing::error!("Error parsejant resposta ESIOS: {:?}", e);
+            AppError::ExternalApi("ESIOS_ERROR", format!("Error parsejant resposta ESIOS: {}", e))
+        })?;
+
+        Ok(data
+            .indicator
+            .values
+            .into_iter()
+            .filter(|v| v.geo_id == Some(geo_id) || v.geo_id.is_none())
+            .collect())
+    }
+
+    /// Converteix els valors d'un dia al nostre format i comprova que hi són totes les hores
+    fn daily_prices(&self, date: NaiveDate, values: &[EsiosValue]) -> AppResult<DailyPrices> {
+        let mut prices = hourly_prices(values);
+        let mut quarter_hours = quarter_hour_prices(values);
+
+        prices.sort_by_key(|p| p.hour);
+        quarter_hours.sort_by_key(|p| p.minute_of_day);
+
+        check_price_count(date, prices.len(), self.strict)?;
+
+        let prices = merge_duplicates(prices, self.duplicate_hours, |p| p.hour, |p| &mut p.price);
+        let quarter_hours =
+            merge_duplicates(quarter_hours, self.duplicate_hours, |p| p.minute_of_day, |p| &mut p.price);
+
+        Ok(DailyPrices { date, prices, quarter_hours })
+    }
+
+    /// Fa la petició a ESIOS i retorna el cos de la resposta.
+    /// Els errors de xarxa i els 5xx es reintenten amb espera exponencial; els 4xx no.
+    async fn fetch_with_retry(&self, url: &str, token: &str) -> AppResult<String> {
+        // Els reintents duen el mateix identificador
+        let request_id = self.request_id.or_else(current_request_id).unwrap_or_else(Uuid::new_v4);
+        let mut retry = 0;
+        loop {
+            let error = match self.fetch_once(url, token, request_id).await {
+                Ok(body) => return Ok(body),
+                Err(FetchError::Permanent(e)) => return Err(e),
+                Err(FetchError::Retryable(e)) => e,
+            };
+
+            if retry >= self.max_retries {
+                return Err(error);
+            }
+
+            let delay = retry_delay(self.base_delay, retry);
+            retry += 1;
+            tracing::warn!(
+                "Error consultant ESIOS ({}). Reintent {} de {} en {:?}",
+                error,
+                retry,
+                self.max_retries,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Un sol intent. El permís de concurrència només es manté durant la petició, no durant l'espera.
+    async fn fetch_once(&self, url: &str, token: &str, request_id: Uuid) -> Result<String, FetchError> {
+        // Esperar torn si ja hi ha massa peticions en curs (el permís s'allibera en sortir)
+        let _permit = self
+            .request_permits
+            .acquire()
+            .await
+            .map_err(|_| FetchError::Permanent(AppError::Internal(
+                "INTERNAL_ERROR",
+                "ESIOS request limiter closed".to_string(),
+            )))?;
+
+        tracing::debug!("Obtenint preus de: {} (request_id {})", url, request_id);
+
+        let started = Instant::now();
+        let result = self.send_request(url, token, request_id).await;
+        metrics::record_esios_request(result.is_ok(), started.elapsed());
+        result
+    }
 
+    async fn send_request(&self, url: &str, token: &str, request_id: Uuid) -> Result<String, FetchError> {
         let response = self
             .client
-            .get(&url)
+            .get(url)
             .header("Accept", "application/json")
             .header("x-api-key", token)
+            .header(REQUEST_ID_HEADER, request_id.to_string())
             .send()
             .await
             .map_err(|e| {
                 tracing::error!("Error connectant amb ESIOS: {:?}", e);
-                AppError::ExternalApi(format!("Error connectant amb ESIOS: {}", e))
+                FetchError::Retryable(AppError::ExternalApi(
+                    "ESIOS_ERROR",
+                    format!("Error connectant amb ESIOS: {}", e),
+                ))
             })?;
 
-        if !response.status().is_success() {
-            let status = response.status();
+        // Identificador de ESIOS, per poder-los demanar una petició concreta
+        let esios_request_id = response
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("-")
+            .to_string();
+
+        let status = response.status();
+        if !status.is_success() {
             let body = response.text().await.unwrap_or_default();
-            tracing::error!("ESIOS API error: {} - {}", status, body);
-            return Err(AppError::ExternalApi(format!(
+            tracing::error!(
+                "ESIOS API error: {} - {} (request_id {}, X-Request-ID de ESIOS {})",
+                status,
+                body,
+                request_id,
+                esios_request_id
+            );
+            let error = AppError::ExternalApi("ESIOS_ERROR", format!(
                 "ESIOS API returned status {}: {}",
                 status, body
-            )));
+            ));
+            return Err(if status.is_server_error() {
+                FetchError::Retryable(error)
+            } else {
+                FetchError::Permanent(error)
+            });
         }
 
-        let data: EsiosResponse = response.json().await.map_err(|e| {
-            tracing::error!("Error parsejant resposta ESIOS: {:?}", e);
-            AppError::ExternalApi(format!("Error parsejant resposta ESIOS: {}", e))
-        })?;
+        tracing::debug!(
+            "ESIOS ha respost {} (request_id {}, X-Request-ID de ESIOS {})",
+            status,
+            request_id,
+            esios_request_id
+        );
 
-        // Convertir la resposta al nostre format
-        let mut prices: Vec<HourlyPrice> = data
-            .indicator
-            .values
-            .into_iter()
-            .filter(|v| v.geo_id == Some(GEO_ID_PENINSULA) || v.geo_id.is_none())
-            .filter_map(|v| {
-                // El datetime ve en format ISO 8601: "2024-01-15T00:00:00.000+01:00"
-                // Extreure l'hora
-                let hour = extract_hour_from_datetime(&v.datetime)?;
-                Some(HourlyPrice {
-                    hour,
-                    // El preu ve en €/MWh, convertim a €/kWh
-                    price: v.value / 1000.0,
-                })
-            })
-            .collect();
+        response.text().await.map_err(|e| {
+            tracing::error!("Error llegint resposta ESIOS: {:?}", e);
+            FetchError::Retryable(AppError::ExternalApi("ESIOS_ERROR", format!("Error llegint resposta ESIOS: {}", e)))
+        })
+    }
+}
 
-        prices.sort_by_key(|p| p.hour);
+/// Resultat d'un intent fallit de petició a ESIOS
+enum FetchError {
+    /// Error de xarxa o 5xx: es pot tornar a provar
+    Retryable(AppError),
+    /// Error del client (4xx) o intern: reintentar no canviaria res
+    Permanent(AppError),
+}
 
-        // Verificar que tenim les 24 hores
-        if prices.len() != 24 {
-            tracing::warn!(
-                "S'esperaven 24 preus per {}, però s'han obtingut {}",
-                date,
-                prices.len()
-            );
+/// Espera abans del reintent `retry` (0 = primer reintent)
+fn retry_delay(base_delay: Duration, retry: u8) -> Duration {
+    let factor = BACKOFF_FACTORS[usize::from(retry).min(BACKOFF_FACTORS.len() - 1)];
+    base_delay * factor
+}
+
+/// Nombre d'hores d'un dia a l'horari peninsular: 23 l'últim diumenge de març,
+/// 25 l'últim diumenge d'octubre i 24 la resta
+fn expected_hours_for_date(date: NaiveDate) -> usize {
+    let is_last_sunday = date.weekday() == Weekday::Sun
+        && (date + chrono::Duration::days(7)).month() != date.month();
+
+    match (date.month(), is_last_sunday) {
+        (3, true) => 23,
+        (10, true) => 25,
+        _ => 24,
+    }
+}
+
+/// Verifica que tenim totes les hores del dia.
+/// En mode estricte, un dia normal (sense canvi d'hora) amb menys de 24 preus
+/// retorna error perquè el bucle de reintents torni a provar-ho més tard.
+fn check_price_count(date: NaiveDate, count: usize, strict: bool) -> AppResult<()> {
+    let expected = expected_hours_for_date(date);
+    if count == expected {
+        return Ok(());
+    }
+
+    tracing::warn!(
+        "S'esperaven {} preus per {}, però s'han obtingut {}",
+        expected,
+        date,
+        count
+    );
+
+    if strict && expected == 24 && count < 24 {
+        return Err(AppError::ExternalApi("ESIOS_INCOMPLETE_PRICES", format!(
+            "Preus incomplets per {}: {} de 24 hores",
+            date, count
+        )));
+    }
+
+    Ok(())
+}
+
+/// Zona dels valors que ens interessen de cada indicador
+fn geo_id_for_indicator(indicator: u32) -> i32 {
+    match indicator {
+        INDICATOR_SPOT => GEO_ID_SPAIN,
+        _ => GEO_ID_PENINSULA,
+    }
+}
+
+/// Converteix els valors de ESIOS (€/MWh) a preus horaris (€/kWh)
+///
+/// Els valors consecutius de la mateixa hora (quarts d'hora) es promitgen. L'hora repetida
+/// del canvi d'horari d'octubre té un desplaçament diferent i es manté per separat.
+fn hourly_prices(values: &[EsiosValue]) -> Vec<HourlyPrice> {
+    // (hora, desplaçament horari, suma, nombre de valors)
+    let mut groups: Vec<(u8, &str, f64, usize)> = Vec::new();
+
+    for v in values {
+        // El datetime ve en format ISO 8601: "2024-01-15T00:00:00.000+01:00"
+        let Some(hour) = extract_hour_from_datetime(&v.datetime) else {
+            continue;
+        };
+        let offset = v.datetime.get(v.datetime.len().saturating_sub(6)..).unwrap_or_default();
+
+        match groups.last_mut() {
+            Some((h, o, sum, count)) if *h == hour && *o == offset => {
+                *sum += v.value;
+                *count += 1;
+            }
+            _ => groups.push((hour, offset, v.value, 1)),
         }
+    }
+
+    groups
+        .into_iter()
+        .map(|(hour, _, sum, count)| HourlyPrice {
+            hour,
+            // El preu ve en €/MWh, convertim a €/kWh
+            price: sum / count as f64 / 1000.0,
+            raw_price: None,
+        })
+        .collect()
+}
+
+/// Converteix els valors de ESIOS (€/MWh) a preus quart-horaris (€/kWh)
+///
+/// Només si la resposta és quart-horària (algun valor no comença en punt); amb valors
+/// horaris retorna una llista buida i la programació es fa per hores.
+fn quarter_hour_prices(values: &[EsiosValue]) -> Vec<PricePoint> {
+    let points: Vec<PricePoint> = values
+        .iter()
+        .filter_map(|v| {
+            Some(PricePoint {
+                minute_of_day: extract_minute_of_day(&v.datetime)?,
+                price: v.value / 1000.0,
+                raw_price: None,
+            })
+        })
+        .collect();
+
+    if points.iter().all(|p| p.minute_of_day % 60 == 0) {
+        return Vec::new();
+    }
+    points
+}
 
-        Ok(DailyPrices { date, prices })
+/// Aplica la política d'hores repetides a uns preus ordenats per `key`
+fn merge_duplicates<T, K: PartialEq>(
+    items: Vec<T>,
+    policy: DuplicateHourPolicy,
+    key: impl Fn(&T) -> K,
+    price: impl Fn(&mut T) -> &mut f64,
+) -> Vec<T> {
+    if policy == DuplicateHourPolicy::KeepBoth {
+        return items;
     }
+
+    // Cada preu amb el nombre de valors que s'hi han ajuntat
+    let mut merged: Vec<(T, usize)> = Vec::with_capacity(items.len());
+    for mut item in items {
+        match merged.last_mut() {
+            Some((last, count)) if key(last) == key(&item) => {
+                if policy == DuplicateHourPolicy::Average {
+                    let total = *price(last) * *count as f64 + *price(&mut item);
+                    *count += 1;
+                    *price(last) = total / *count as f64;
+                }
+            }
+            _ => merged.push((item, 1)),
+        }
+    }
+
+    merged.into_iter().map(|(item, _)| item).collect()
 }
 
 /// Extreu l'hora d'un datetime en format ISO 8601
 fn extract_hour_from_datetime(datetime: &str) -> Option<u8> {
-    // Format esperat: "2024-01-15T14:00:00.000+01:00" o similar
+    extract_minute_of_day(datetime).map(|minute| (minute / 60) as u8)
+}
+
+/// Extreu el minut del dia (hora * 60 + minuts) d'un datetime en format ISO 8601
+fn extract_minute_of_day(datetime: &str) -> Option<u16> {
+    // Format esperat: "2024-01-15T14:15:00.000+01:00" o similar
     let time_part = datetime.split('T').nth(1)?;
-    let hour_str = time_part.split(':').next()?;
-    hour_str.parse().ok()
+    let mut parts = time_part.split(':');
+    let hour: u16 = parts.next()?.parse().ok()?;
+    let minute: u16 = parts.next()?.parse().ok()?;
+    (hour < 24 && minute < 60).then_some(hour * 60 + minute)
 }
 
 impl Default for PvpcClient {
@@ -184,6 +680,411 @@ mod tests {
         assert_eq!(extract_hour_from_datetime("2024-01-15T00:00:00.000+01:00"), Some(0));
         assert_eq!(extract_hour_from_datetime("2024-01-15T14:00:00.000+01:00"), Some(14));
         assert_eq!(extract_hour_from_datetime("2024-01-15T23:00:00.000+01:00"), Some(23));
+        assert_eq!(extract_minute_of_day("2025-11-03T14:45:00.000+01:00"), Some(14 * 60 + 45));
+        assert_eq!(extract_minute_of_day("2025-11-03T14"), None);
+    }
+
+    fn value(datetime: &str, value: f64) -> EsiosValue {
+        EsiosValue {
+            value,
+            datetime: datetime.to_string(),
+            geo_id: Some(GEO_ID_SPAIN),
+        }
+    }
+
+    #[test]
+    fn test_hourly_prices_averages_quarter_hours() {
+        let values = [
+            value("2025-11-03T00:00:00.000+01:00", 100.0),
+            value("2025-11-03T00:15:00.000+01:00", 110.0),
+            value("2025-11-03T00:30:00.000+01:00", 120.0),
+            value("2025-11-03T00:45:00.000+01:00", 130.0),
+            value("2025-11-03T01:00:00.000+01:00", 80.0),
+        ];
+
+        let prices = hourly_prices(&values);
+        assert_eq!(prices.len(), 2);
+        assert_eq!(prices[0].hour, 0);
+        assert!((prices[0].price - 0.115).abs() < 1e-9);
+        assert_eq!(prices[1].hour, 1);
+        assert!((prices[1].price - 0.08).abs() < 1e-9);
+
+        // La resposta és quart-horària: també es conserven els quarts d'hora
+        let quarters = quarter_hour_prices(&values);
+        let minutes: Vec<u16> = quarters.iter().map(|p| p.minute_of_day).collect();
+        assert_eq!(minutes, vec![0, 15, 30, 45, 60]);
+        assert!((quarters[1].price - 0.11).abs() < 1e-9);
+
+        // Amb valors horaris no n'hi ha
+        let hourly = [
+            value("2025-11-03T00:00:00.000+01:00", 100.0),
+            value("2025-11-03T01:00:00.000+01:00", 80.0),
+        ];
+        assert!(quarter_hour_prices(&hourly).is_empty());
+    }
+
+    #[test]
+    fn test_hourly_prices_keeps_repeated_dst_hour() {
+        let values = [
+            value("2024-10-27T02:00:00.000+02:00", 50.0),
+            value("2024-10-27T02:00:00.000+01:00", 60.0),
+        ];
+
+        let prices = hourly_prices(&values);
+        assert_eq!(prices.len(), 2);
+        assert!(prices.iter().all(|p| p.hour == 2));
+    }
+
+    #[test]
+    fn test_duplicate_hour_policies() {
+        // Dia del canvi d'horari d'octubre: l'hora 2 es repeteix
+        let values = [
+            value("2024-10-27T01:00:00.000+02:00", 40.0),
+            value("2024-10-27T02:00:00.000+02:00", 50.0),
+            value("2024-10-27T02:00:00.000+01:00", 70.0),
+            value("2024-10-27T03:00:00.000+01:00", 30.0),
+        ];
+        let merged = |policy| -> Vec<(u8, f64)> {
+            merge_duplicates(hourly_prices(&values), policy, |p| p.hour, |p| &mut p.price)
+                .iter()
+                .map(|p| (p.hour, (p.price * 1000.0).round()))
+                .collect()
+        };
+
+        assert_eq!(merged(DuplicateHourPolicy::KeepFirst), vec![(1, 40.0), (2, 50.0), (3, 30.0)]);
+        assert_eq!(merged(DuplicateHourPolicy::Average), vec![(1, 40.0), (2, 60.0), (3, 30.0)]);
+        assert_eq!(merged(DuplicateHourPolicy::KeepBoth), vec![(1, 40.0), (2, 50.0), (2, 70.0), (3, 30.0)]);
+
+        assert_eq!(DuplicateHourPolicy::parse(" Both "), Some(DuplicateHourPolicy::KeepBoth));
+        assert_eq!(serde_json::to_value(DuplicateHourPolicy::KeepBoth).unwrap(), "both");
+        assert_eq!(
+            serde_json::from_value::<DuplicateHourPolicy>(serde_json::json!("first")).unwrap(),
+            DuplicateHourPolicy::KeepFirst
+        );
+        assert_eq!(DuplicateHourPolicy::parse("last"), None);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_applies_duplicate_hour_policy() {
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // Dia del canvi d'horari d'octubre: ESIOS retorna l'hora 2 dues vegades
+        let values: Vec<_> = [
+            ("2024-10-27T01:00:00.000+02:00", 40.0),
+            ("2024-10-27T02:00:00.000+02:00", 50.0),
+            ("2024-10-27T02:00:00.000+01:00", 70.0),
+            ("2024-10-27T03:00:00.000+01:00", 30.0),
+        ]
+        .iter()
+        .map(|(datetime, value)| serde_json::json!({ "value": value, "datetime": datetime, "geo_id": 8741 }))
+        .collect();
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": values } })),
+            )
+            .mount(&server)
+            .await;
+
+        let client = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let date = NaiveDate::from_ymd_opt(2024, 10, 27).unwrap();
+        let fetched = |policy| {
+            let client = client.clone().with_duplicate_hours(policy);
+            async move {
+                let prices = client.get_prices_for_date(date).await.unwrap();
+                prices.prices.iter().map(|p| (p.hour, (p.price * 1000.0).round())).collect::<Vec<_>>()
+            }
+        };
+
+        assert_eq!(fetched(DuplicateHourPolicy::KeepFirst).await, vec![(1, 40.0), (2, 50.0), (3, 30.0)]);
+        assert_eq!(fetched(DuplicateHourPolicy::Average).await, vec![(1, 40.0), (2, 60.0), (3, 30.0)]);
+        assert_eq!(fetched(DuplicateHourPolicy::KeepBoth).await, vec![(1, 40.0), (2, 50.0), (2, 70.0), (3, 30.0)]);
+    }
+
+    #[test]
+    fn test_expected_hours_for_dst_days() {
+        assert_eq!(expected_hours_for_date(NaiveDate::from_ymd_opt(2024, 3, 31).unwrap()), 23);
+        assert_eq!(expected_hours_for_date(NaiveDate::from_ymd_opt(2024, 10, 27).unwrap()), 25);
+        assert_eq!(expected_hours_for_date(NaiveDate::from_ymd_opt(2024, 3, 24).unwrap()), 24);
+        assert_eq!(expected_hours_for_date(NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()), 24);
+    }
+
+    #[test]
+    fn test_check_price_count_lenient() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        assert!(check_price_count(date, 24, false).is_ok());
+        assert!(check_price_count(date, 20, false).is_ok());
+    }
+
+    #[test]
+    fn test_check_price_count_strict() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        assert!(check_price_count(date, 24, true).is_ok());
+        assert!(matches!(
+            check_price_count(date, 20, true),
+            Err(AppError::ExternalApi(..))
+        ));
+
+        // Els dies amb canvi d'hora no es rebutgen
+        let dst = NaiveDate::from_ymd_opt(2024, 3, 31).unwrap();
+        assert!(check_price_count(dst, 23, true).is_ok());
+        assert!(check_price_count(dst, 22, true).is_ok());
+    }
+
+    #[test]
+    fn test_is_complete_threshold() {
+        let day = |date: &str, hours: u8| DailyPrices {
+            date: date.parse().unwrap(),
+            prices: (0..hours).map(|hour| HourlyPrice { hour, price: 0.1, raw_price: None }).collect(),
+            quarter_hours: vec![],
+        };
+        let client = PvpcClient::with_token("test".to_string());
+
+        assert!(client.is_complete(&day("2024-01-15", 24)));
+        assert!(!client.is_complete(&day("2024-01-15", 12)));
+        assert!(!client.is_complete(&day("2024-01-15", 0)));
+        // El dia del canvi d'hora de març només en té 23
+        assert!(client.is_complete(&day("2024-03-31", 23)));
+
+        let client = client.with_min_tomorrow_hours(12);
+        assert!(client.is_complete(&day("2024-01-15", 12)));
+        assert!(!client.is_complete(&day("2024-01-15", 11)));
+        assert!(!client.with_min_tomorrow_hours(0).is_complete(&day("2024-01-15", 0)));
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_requests_are_limited() {
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // Cada resposta triga 100 ms: amb 2 peticions alhora, les 8 necessiten 4 tandes
+        let delay = Duration::from_millis(100);
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(serde_json::json!({ "indicator": { "values": [] } }))
+                    .set_delay(delay),
+            )
+            .expect(8)
+            .mount(&server)
+            .await;
+
+        let client = PvpcClient::with_token("test".to_string())
+            .with_base_url(server.uri())
+            .with_max_concurrent_requests(2);
+
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        let requests = (0..8).map(|i| {
+            let client = client.clone();
+            async move { client.get_prices_for_date(date - chrono::Duration::days(i)).await }
+        });
+        let started = std::time::Instant::now();
+        let results = futures_util::future::join_all(requests).await;
+        let elapsed = started.elapsed();
+
+        assert!(results.iter().all(|r| r.is_ok()));
+        // Ni totes alhora (1 tanda) ni una a una (8 tandes)
+        assert!(elapsed >= delay * 4, "{:?}", elapsed);
+        assert!(elapsed < delay * 8, "{:?}", elapsed);
+        server.verify().await;
+    }
+
+    #[test]
+    fn test_retry_delay_backoff() {
+        assert_eq!(retry_delay(DEFAULT_BASE_DELAY, 0), Duration::from_secs(5));
+        assert_eq!(retry_delay(DEFAULT_BASE_DELAY, 1), Duration::from_secs(30));
+        assert_eq!(retry_delay(DEFAULT_BASE_DELAY, 2), Duration::from_secs(120));
+        assert_eq!(retry_delay(DEFAULT_BASE_DELAY, 5), Duration::from_secs(120));
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors_but_not_client_errors() {
+        use wiremock::matchers::path;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // Mock d'ESIOS: PVPC falla dues vegades amb 503 i després respon; spot sempre 403
+        let server = MockServer::start().await;
+        let pvpc_path = format!("/indicators/{}", INDICATOR_PVPC);
+        Mock::given(path(pvpc_path.as_str()))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&server)
+            .await;
+        Mock::given(path(pvpc_path.as_str()))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": [] } })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(path(format!("/indicators/{}", INDICATOR_SPOT)))
+            .respond_with(ResponseTemplate::new(403).set_body_string("invalid token"))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let client = PvpcClient::with_token("test".to_string())
+            .with_base_url(server.uri())
+            .with_retries(3, Duration::from_millis(1));
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+
+        assert!(client.get_indicator_prices(INDICATOR_PVPC, date).await.is_ok());
+        assert!(client.get_indicator_prices(INDICATOR_SPOT, date).await.is_err());
+        server.verify().await;
+
+        // Sense reintents, el primer 503 és definitiu
+        server.reset().await;
+        Mock::given(path(pvpc_path.as_str()))
+            .respond_with(ResponseTemplate::new(503))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let client = client.with_retries(0, Duration::ZERO);
+        assert!(client.get_indicator_prices(INDICATOR_PVPC, date).await.is_err());
+        server.verify().await;
+    }
+
+    #[tokio::test]
+    async fn test_network_errors_are_retried() {
+        // Ningú escolta al port: error de connexió a cada intent
+        let client = PvpcClient::with_token("test".to_string())
+            .with_base_url("http://127.0.0.1:9")
+            .with_retries(2, Duration::from_millis(20));
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+
+        let started = std::time::Instant::now();
+        assert!(matches!(client.get_prices_for_date(date).await, Err(AppError::ExternalApi(..))));
+        // Dues esperes: 20 ms + 120 ms
+        assert!(started.elapsed() >= Duration::from_millis(140));
+    }
+
+    #[tokio::test]
+    async fn test_user_token_overrides_global() {
+        use wiremock::matchers::header;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let server = MockServer::start().await;
+        Mock::given(header("x-api-key", "user-token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "indicator": { "values": [] } })))
+            .mount(&server)
+            .await;
+        Mock::given(header("x-api-key", "global-token"))
+            .respond_with(ResponseTemplate::new(403))
+            .mount(&server)
+            .await;
+
+        let global = PvpcClient::with_token("global-token".to_string())
+            .with_base_url(server.uri())
+            .with_retries(0, Duration::ZERO);
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+
+        let user = global.with_user_token(Some("user-token".to_string()));
+        assert!(user.get_prices_for_date(date).await.is_ok());
+        // Sense token propi, el global
+        assert!(global.with_user_token(None).get_prices_for_date(date).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_request_id_is_sent_to_esios() {
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use actix_web::{get, web, App, HttpResponse};
+        use wiremock::matchers::method;
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        use crate::middleware::request_id::RequestIdMiddleware;
+
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .insert_header("x-request-id", "esios-1")
+                    .set_body_json(serde_json::json!({ "indicator": { "values": [] } })),
+            )
+            .mount(&server)
+            .await;
+        let client = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        // Identificadors enviats a ESIOS, en ordre
+        let sent_ids = || async {
+            let requests = server.received_requests().await.unwrap();
+            requests
+                .iter()
+                .map(|r| r.headers.get("x-request-id").unwrap().to_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        // Identificador explícit
+        let id = Uuid::new_v4();
+        client.clone().with_request_id(Some(id)).get_prices_for_date(date).await.unwrap();
+        assert_eq!(sent_ids().await, vec![id.to_string()]);
+
+        // Fora d'una petició, un de nou per cada consulta
+        client.get_prices_for_date(date).await.unwrap();
+        client.get_prices_for_date(date).await.unwrap();
+        let ids = sent_ids().await;
+        assert!(ids[1..].iter().all(|id| id.parse::<Uuid>().is_ok()));
+        assert_ne!(ids[1], ids[2]);
+
+        // Dins una petició, el de la petició
+        #[get("/prices")]
+        async fn prices(pvpc: web::Data<PvpcClient>) -> AppResult<HttpResponse> {
+            pvpc.get_prices_for_date(NaiveDate::from_ymd_opt(2024, 1, 15).unwrap()).await?;
+            Ok(HttpResponse::Ok().finish())
+        }
+        let app = init_service(
+            App::new().wrap(RequestIdMiddleware).app_data(web::Data::new(client)).service(prices),
+        )
+        .await;
+        let resp = call_service(&app, TestRequest::get().uri("/prices").to_request()).await;
+        assert_eq!(resp.status(), 200);
+        let request_id = resp.headers().get("x-request-id").unwrap().to_str().unwrap().to_string();
+        assert_eq!(sent_ids().await[3], request_id);
+    }
+
+    #[tokio::test]
+    async fn test_range_is_fetched_once_and_split_by_day() {
+        use wiremock::matchers::{path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        // Tres dies: 15 i 16 complets, el 17 amb només 20 hores. L'1 de gener cau fora del rang.
+        let mut values: Vec<serde_json::Value> = Vec::new();
+        for (day, hours) in [(15, 24), (16, 24), (17, 20)] {
+            for hour in 0..hours {
+                values.push(serde_json::json!({
+                    "value": f64::from(day * 10 + hour),
+                    "datetime": format!("2024-01-{}T{:02}:00:00.000+01:00", day, hour),
+                    "geo_id": GEO_ID_PENINSULA,
+                }));
+            }
+        }
+        values.push(serde_json::json!({ "value": 1.0, "datetime": "2024-01-15T05:00:00.000+01:00", "geo_id": 8742 }));
+        let body = serde_json::json!({ "indicator": { "values": values } });
+
+        let server = MockServer::start().await;
+        Mock::given(path("/indicators/1001"))
+            .and(query_param("start_date", "2024-01-15T00:00:00"))
+            .and(query_param("end_date", "2024-01-18T23:59:59"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .expect(2)
+            .mount(&server)
+            .await;
+
+        let client = PvpcClient::with_token("test".to_string()).with_base_url(server.uri());
+        let date = |d| NaiveDate::from_ymd_opt(2024, 1, d).unwrap();
+
+        let days = client.fetch_prices_for_range(date(15), date(18)).await.unwrap();
+        let summary: Vec<(NaiveDate, usize)> = days.iter().map(|d| (d.date, d.prices.len())).collect();
+        assert_eq!(summary, vec![(date(15), 24), (date(16), 24), (date(17), 20), (date(18), 0)]);
+        // Cada preu va al seu dia (i els d'altres zones es descarten)
+        assert!((days[1].prices[5].price - 0.165).abs() < 1e-9);
+        assert!((days[0].prices[5].price - 0.155).abs() < 1e-9);
+
+        // En mode estricte, un dia incomplet fa fallar la consulta sencera
+        let strict = client.with_strict(true);
+        assert!(matches!(
+            strict.fetch_prices_for_range(date(15), date(18)).await,
+            Err(AppError::ExternalApi("ESIOS_INCOMPLETE_PRICES", _))
+        ));
     }
 
     #[tokio::test]
@@ -191,7 +1092,7 @@ mod tests {
     async fn test_get_today_prices() {
         let token = std::env::var("ESIOS_TOKEN").expect("ESIOS_TOKEN requerit per aquest test");
         let client = PvpcClient::with_token(token);
-        let result = client.get_today_prices().await;
+        let result = client.get_today_prices(clock::DEFAULT_TIMEZONE).await;
 
         match result {
             Ok(prices) => {
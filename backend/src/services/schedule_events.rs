@@ -0,0 +1,74 @@
+use chrono::NaiveDate;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::api::schedule::ScheduleResponse;
+
+/// Capacitat del canal de difusió (esdeveniments pendents per subscriptor)
+const CHANNEL_CAPACITY: usize = 256;
+
+/// Esdeveniment en temps real per als clients connectats (SSE i WebSocket)
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ScheduleEvent {
+    /// Una acció programada ha canviat d'estat
+    StatusChanged {
+        #[serde(skip)]
+        user_id: Uuid,
+        // En un Box: és molt més gran que la resta de variants
+        action: Box<ScheduleResponse>,
+    },
+    /// S'han (re)generat les accions d'una data. `user_id` = None afecta tots els usuaris.
+    ScheduleGenerated {
+        #[serde(skip)]
+        user_id: Option<Uuid>,
+        date: NaiveDate,
+    },
+    /// Hi ha preus nous per una data (afecta tots els usuaris)
+    PricesUpdated { date: NaiveDate },
+}
+
+impl ScheduleEvent {
+    /// Indica si l'esdeveniment s'ha d'enviar a l'usuari
+    pub fn is_for(&self, user: Uuid) -> bool {
+        match self {
+            Self::StatusChanged { user_id, .. } => *user_id == user,
+            Self::ScheduleGenerated { user_id, .. } => user_id.is_none_or(|id| id == user),
+            Self::PricesUpdated { .. } => true,
+        }
+    }
+}
+
+/// Difon els esdeveniments a tots els clients connectats
+#[derive(Clone)]
+pub struct ScheduleEvents {
+    sender: broadcast::Sender<ScheduleEvent>,
+}
+
+impl ScheduleEvents {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    /// Publica un esdeveniment. Si no hi ha cap client connectat, es descarta.
+    pub fn send(&self, event: ScheduleEvent) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Publica un canvi d'estat d'una acció
+    pub fn publish(&self, user_id: Uuid, action: ScheduleResponse) {
+        self.send(ScheduleEvent::StatusChanged { user_id, action: Box::new(action) });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ScheduleEvent> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for ScheduleEvents {
+    fn default() -> Self {
+        Self::new()
+    }
+}
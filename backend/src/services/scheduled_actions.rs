@@ -0,0 +1,357 @@
+//! Creació de les accions programades d'una regla
+//!
+//! Cada franja d'una regla crea una acció per cada dispositiu de la regla. Si una altra
+//! regla del mateix dispositiu té una acció pendent a la mateixa franja, guanya la de
+//! prioritat més alta (1 = màxima): l'acció de l'altra regla queda `superseded` o no es
+//! crea. A igual prioritat es creen totes dues i el pla del dispositiu decideix.
+//!
+//! Si una regla no programa cap hora per un dia, se'n desa el motiu a `skipped_actions`.
+//!
+//! Les accions pendents que encara no han començat es poden cancel·lar de cop, les d'una
+//! regla o les d'un dispositiu (`cancel_pending_actions`).
+//!
+//! Les franges on han quedat accions de més d'una regla pel mateix dispositiu es poden
+//! consultar després de generar (`find_conflicts`).
+
+use chrono::{NaiveDate, NaiveTime};
+use chrono_tz::Tz;
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::db::models::{Rule, ScheduledAction};
+use crate::services::clock;
+use crate::services::scheduler::{detect_conflicts, ScheduleConflict};
+
+/// Estat d'una acció que ha perdut la franja davant d'una regla de més prioritat
+pub const SUPERSEDED_STATUS: &str = "superseded";
+
+/// Motiu pel qual una regla no ha programat cap hora per un dia
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type, ToSchema)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "varchar", rename_all = "snake_case")]
+pub enum SkipReason {
+    /// El dia de la setmana no és a `days_of_week`
+    NotScheduledDay,
+    /// La data és fora de la temporada de la regla
+    OutOfSeason,
+    /// Cap hora compleix la regla (finestra, franges bloquejades, continuïtat o cost màxim)
+    NoEligibleHours,
+    /// Les hores no són prou més barates que els darrers dies (`baseline_days`)
+    AboveBaseline,
+    /// Totes les hores seleccionades ja havien començat
+    HoursPassed,
+    /// Les hores les han ocupat regles de més prioritat (màxim de dispositius simultanis)
+    DeviceLimit,
+}
+
+impl SkipReason {
+    /// Motiu si la regla no s'aplica a `date` (vegeu `Rule::runs_on`)
+    pub fn for_day(rule: &Rule, date: NaiveDate) -> Option<Self> {
+        if !rule.is_scheduled_weekday(date) {
+            Some(Self::NotScheduledDay)
+        } else if !rule.is_in_season(date) {
+            Some(Self::OutOfSeason)
+        } else {
+            None
+        }
+    }
+}
+
+/// Desa el resultat de la generació d'una regla per un dia: amb `reason`, el motiu pel qual
+/// no ha programat res (substitueix l'anterior); sense, esborra el motiu que hi pogués haver
+pub async fn record_skip(
+    conn: &mut PgConnection,
+    rule_id: Uuid,
+    date: NaiveDate,
+    reason: Option<SkipReason>,
+) -> Result<(), sqlx::Error> {
+    match reason {
+        Some(reason) => {
+            sqlx::query(
+                r#"
+                INSERT INTO skipped_actions (rule_id, scheduled_date, reason)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (rule_id, scheduled_date)
+                DO UPDATE SET reason = EXCLUDED.reason, created_at = NOW()
+                "#
+            )
+            .bind(rule_id)
+            .bind(date)
+            .bind(reason)
+            .execute(conn)
+            .await?;
+        }
+        None => {
+            sqlx::query("DELETE FROM skipped_actions WHERE rule_id = $1 AND scheduled_date = $2")
+                .bind(rule_id)
+                .bind(date)
+                .execute(conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Crea les accions d'una franja d'una regla i retorna quantes se n'han creat. `end` és
+/// l'hora de final i si creua mitjanit (`PriceSlots::end_time`).
+///
+/// Amb `device_ids`, només pels dispositius indicats de la regla (None = tots).
+/// No es crea res pels dispositius on una regla de més prioritat ja té una acció pendent
+/// a `start_time`; les accions pendents de regles de menys prioritat passen a `superseded`.
+pub async fn insert_rule_actions(
+    conn: &mut PgConnection,
+    rule: &Rule,
+    device_ids: Option<&[Uuid]>,
+    date: NaiveDate,
+    start_time: NaiveTime,
+    end: (NaiveTime, bool),
+    price: Option<f64>,
+) -> Result<usize, sqlx::Error> {
+    let (end_time, crosses_midnight) = end;
+    let result = sqlx::query(
+        r#"
+        WITH superseded AS (
+            UPDATE scheduled_actions sa
+            SET status = $7
+            FROM rules r
+            WHERE sa.rule_id = r.id
+              AND r.priority > $8
+              AND sa.status = 'pending'
+              AND sa.scheduled_date = $2
+              AND sa.start_time = $3
+              AND sa.device_id IN (
+                  SELECT device_id FROM rule_devices WHERE rule_id = $1 AND ($9::uuid[] IS NULL OR device_id = ANY($9))
+              )
+        )
+        INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time, crosses_midnight,
+                                       price_per_kwh, status, action)
+        SELECT rd.rule_id, rd.device_id, $2, $3, $4, $10, $5, 'pending', $6
+        FROM rule_devices rd
+        WHERE rd.rule_id = $1
+          AND ($9::uuid[] IS NULL OR rd.device_id = ANY($9))
+          AND NOT EXISTS (
+              SELECT 1
+              FROM scheduled_actions o
+              JOIN rules r ON o.rule_id = r.id
+              WHERE o.device_id = rd.device_id
+                AND o.scheduled_date = $2
+                AND o.start_time = $3
+                AND o.status = 'pending'
+                AND r.priority < $8
+          )
+        ON CONFLICT (rule_id, device_id, scheduled_date, start_time) DO NOTHING
+        "#
+    )
+    .bind(rule.id)
+    .bind(date)
+    .bind(start_time)
+    .bind(end_time)
+    .bind(price)
+    .bind(rule.action_type.action())
+    .bind(SUPERSEDED_STATUS)
+    .bind(rule.priority)
+    .bind(device_ids)
+    .bind(crosses_midnight)
+    .execute(conn)
+    .await?;
+
+    Ok(result.rows_affected() as usize)
+}
+
+/// Dispositius pels quals `insert_rule_actions` crearia una acció, sense crear-ne cap
+/// (mateixes condicions: ni accions pendents de més prioritat ni acció ja existent)
+pub async fn proposed_rule_action_devices(
+    conn: &mut PgConnection,
+    rule: &Rule,
+    device_ids: Option<&[Uuid]>,
+    date: NaiveDate,
+    start_time: NaiveTime,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query_scalar(
+        r#"
+        SELECT rd.device_id
+        FROM rule_devices rd
+        WHERE rd.rule_id = $1
+          AND ($5::uuid[] IS NULL OR rd.device_id = ANY($5))
+          AND NOT EXISTS (
+              SELECT 1
+              FROM scheduled_actions o
+              JOIN rules r ON o.rule_id = r.id
+              WHERE o.device_id = rd.device_id
+                AND o.scheduled_date = $2
+                AND o.start_time = $3
+                AND o.status = 'pending'
+                AND r.priority < $4
+          )
+          AND NOT EXISTS (
+              SELECT 1
+              FROM scheduled_actions e
+              WHERE e.rule_id = rd.rule_id
+                AND e.device_id = rd.device_id
+                AND e.scheduled_date = $2
+                AND e.start_time = $3
+          )
+        ORDER BY rd.device_id
+        "#
+    )
+    .bind(rule.id)
+    .bind(date)
+    .bind(start_time)
+    .bind(rule.priority)
+    .bind(device_ids)
+    .fetch_all(conn)
+    .await
+}
+
+/// Conflictes (`detect_conflicts`) entre les accions pendents de `rule_ids` per una data
+pub async fn find_conflicts(
+    conn: &mut PgConnection,
+    rule_ids: &[Uuid],
+    date: NaiveDate,
+) -> Result<Vec<ScheduleConflict>, sqlx::Error> {
+    let actions = sqlx::query_as::<_, ScheduledAction>(
+        r#"
+        SELECT id, rule_id, device_id, scheduled_date, start_time, end_time, crosses_midnight,
+               price_per_kwh::float8 as price_per_kwh, status, action, executed_at, created_at,
+               actual_power_watts, error_message, client_executed_at
+        FROM scheduled_actions
+        WHERE rule_id = ANY($1) AND scheduled_date = $2 AND status = 'pending'
+        "#
+    )
+    .bind(rule_ids)
+    .bind(date)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(detect_conflicts(&actions))
+}
+
+/// Carrega el màxim de dispositius alhora del grup d'una regla i, si en té, els seus
+/// dispositius (`Rule::max_simultaneous` i `Rule::device_ids`), per repartir-ne les hores
+pub async fn load_group_limit(pool: &PgPool, rule: &mut Rule) -> Result<(), sqlx::Error> {
+    let Some(group_id) = rule.device_group_id else {
+        return Ok(());
+    };
+
+    rule.max_simultaneous = sqlx::query_scalar("SELECT max_simultaneous FROM device_groups WHERE id = $1")
+        .bind(group_id)
+        .fetch_optional(pool)
+        .await?
+        .flatten();
+
+    if rule.max_simultaneous.is_some() {
+        rule.device_ids = sqlx::query_scalar(
+            "SELECT device_id FROM rule_devices WHERE rule_id = $1 ORDER BY device_id"
+        )
+        .bind(rule.id)
+        .fetch_all(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Accions pendents que es cancel·len plegades
+#[derive(Debug, Clone, Copy)]
+pub enum PendingScope {
+    /// Les d'una regla (p. ex. quan es desactiva)
+    Rule(Uuid),
+    /// Les d'un dispositiu, sigui quina sigui la regla que les ha programat
+    Device(Uuid),
+}
+
+/// Cancel·la les accions pendents de `scope` que encara no han començat. Retorna quantes.
+pub async fn cancel_pending_actions(pool: &PgPool, scope: PendingScope, tz: Tz) -> Result<u64, sqlx::Error> {
+    let now = clock::now(tz);
+    let today = now.date_naive();
+    let current_time = now.time();
+    let (rule_id, device_id) = match scope {
+        PendingScope::Rule(id) => (Some(id), None),
+        PendingScope::Device(id) => (None, Some(id)),
+    };
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE scheduled_actions
+        SET status = 'cancelled'
+        WHERE ($1::uuid IS NULL OR rule_id = $1)
+          AND ($2::uuid IS NULL OR device_id = $2)
+          AND status = 'pending'
+          AND (scheduled_date > $3 OR (scheduled_date = $3 AND start_time > $4))
+        "#,
+        rule_id,
+        device_id,
+        today,
+        current_time
+    )
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() > 0 {
+        match scope {
+            PendingScope::Rule(id) => {
+                tracing::info!("Cancel·lats {} schedules pendents per la regla {}", result.rows_affected(), id)
+            }
+            PendingScope::Device(id) => {
+                tracing::info!("Cancel·lats {} schedules pendents del dispositiu {}", result.rows_affected(), id)
+            }
+        }
+    }
+
+    Ok(result.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_utils::{create_device, create_rule, create_user};
+
+    async fn statuses(pool: &PgPool, rule: &Rule) -> Vec<(NaiveTime, String)> {
+        sqlx::query_as("SELECT start_time, status FROM scheduled_actions WHERE rule_id = $1 ORDER BY start_time")
+            .bind(rule.id)
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_higher_priority_rule_wins_slot(pool: PgPool) {
+        let user = create_user(&pool, "priority").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let mut urgent = create_rule(&pool, device.id, "Urgent", true).await;
+        let mut normal = create_rule(&pool, device.id, "Normal", true).await;
+        let mut same = create_rule(&pool, device.id, "Igual", true).await;
+        (urgent.priority, normal.priority, same.priority) = (1, 2, 2);
+        sqlx::query("UPDATE rules SET priority = 2 WHERE id = ANY($1)")
+            .bind(vec![normal.id, same.id])
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let date = NaiveDate::from_ymd_opt(2030, 1, 1).unwrap();
+        let at = |h| NaiveTime::from_hms_opt(h, 0, 0).unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        let mut insert = async |rule: &Rule, h| {
+            insert_rule_actions(&mut conn, rule, None, date, at(h), (at(h + 1), false), Some(0.05)).await.unwrap()
+        };
+
+        // La de menys prioritat primer: queda superseded quan arriba la urgent
+        assert_eq!(insert(&normal, 3).await, 1);
+        assert_eq!(insert(&urgent, 3).await, 1);
+        // La urgent primer: la de menys prioritat no es crea
+        assert_eq!(insert(&urgent, 4).await, 1);
+        assert_eq!(insert(&normal, 4).await, 0);
+        // A igual prioritat es creen totes dues
+        assert_eq!(insert(&normal, 5).await, 1);
+        assert_eq!(insert(&same, 5).await, 1);
+
+        let pending = |h| (at(h), "pending".to_string());
+        assert_eq!(statuses(&pool, &urgent).await, vec![pending(3), pending(4)]);
+        assert_eq!(statuses(&pool, &normal).await, vec![(at(3), SUPERSEDED_STATUS.to_string()), pending(5)]);
+        assert_eq!(statuses(&pool, &same).await, vec![pending(5)]);
+    }
+}
@@ -1,23 +1,226 @@
-use chrono::{NaiveTime, Timelike};
-use shared::HourlyPrice;
+use chrono::{NaiveDate, NaiveTime, Timelike};
+use serde::Serialize;
+use shared::{BlackoutWindow, DailyPrices, HourlyPrice, SubBudget};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use uuid::Uuid;
+
+use crate::db::models::{ActionType, ContinuityPreference, Rule, RuleMode, ScheduledAction};
+
+/// Durada (minuts) de cada franja amb preus quart-horaris
+pub const QUARTER_HOUR_MINUTES: u32 = 15;
 
 /// Resultat del càlcul d'hores òptimes
 #[derive(Debug, Clone)]
 pub struct OptimalHours {
+    /// Franges seleccionades (vegeu `PriceSlots`): hores o, amb preus quart-horaris, quarts d'hora
     pub hours: Vec<u8>,
     pub total_price: f64,
 }
 
-/// Calcula les hores òptimes (més barates) per una regla
-pub fn calculate_optimal_hours(
-    prices: &[HourlyPrice],
-    max_hours: i32,
-    min_continuous_hours: i32,
-    time_window_start: Option<NaiveTime>,
-    time_window_end: Option<NaiveTime>,
-) -> OptimalHours {
+impl OptimalHours {
+    /// Preu mitjà de les hores seleccionades (None si no n'hi ha cap)
+    pub fn average_price(&self) -> Option<f64> {
+        if self.hours.is_empty() {
+            None
+        } else {
+            Some(self.total_price / self.hours.len() as f64)
+        }
+    }
+}
+
+/// Preus d'un dia a la resolució amb què es programa
+///
+/// Si ESIOS ha publicat preus quart-horaris, cada franja és un quart d'hora i `hour` és
+/// l'índex del quart dins el dia (0-95); si no, les franges són les hores del dia.
+#[derive(Debug, Clone)]
+pub struct PriceSlots {
+    pub prices: Vec<HourlyPrice>,
+    /// Durada de cada franja: 60 o `QUARTER_HOUR_MINUTES`
+    pub slot_minutes: u32,
+}
+
+impl PriceSlots {
+    /// Franges d'un dia: quarts d'hora si n'hi ha, hores si no
+    pub fn from_day(day: &DailyPrices) -> Self {
+        if day.quarter_hours.is_empty() {
+            return Self::hourly(&day.prices);
+        }
+
+        Self {
+            prices: day
+                .quarter_hours
+                .iter()
+                .map(|p| HourlyPrice {
+                    hour: (p.minute_of_day as u32 / QUARTER_HOUR_MINUTES) as u8,
+                    price: p.price,
+                    raw_price: p.raw_price,
+                })
+                .collect(),
+            slot_minutes: QUARTER_HOUR_MINUTES,
+        }
+    }
+
+    /// Franges d'una hora
+    pub fn hourly(prices: &[HourlyPrice]) -> Self {
+        Self {
+            prices: prices.to_vec(),
+            slot_minutes: 60,
+        }
+    }
+
+    /// Hora d'inici d'una franja
+    pub fn start_time(&self, slot: u8) -> NaiveTime {
+        slot_time(slot as u32 * self.slot_minutes)
+    }
+
+    /// Hora de final d'una franja i si creua mitjanit (vegeu `slot_end_time`)
+    pub fn end_time(&self, slot: u8) -> (NaiveTime, bool) {
+        slot_end_time(slot, self.slot_minutes)
+    }
+
+    /// Preu d'una franja
+    pub fn price(&self, slot: u8) -> Option<f64> {
+        self.prices.iter().find(|p| p.hour == slot).map(|p| p.price)
+    }
+}
+
+/// Hora de final d'una franja de `slot_minutes` i si l'acció creua mitjanit. L'última franja
+/// del dia (l'hora 23) no acaba a les 23:59:59 sinó a les 00:00 de l'endemà, quan s'apaga el
+/// dispositiu: `(00:00, true)`.
+pub fn slot_end_time(slot: u8, slot_minutes: u32) -> (NaiveTime, bool) {
+    let minutes = (slot as u32 + 1) * slot_minutes;
+    (slot_time(minutes), minutes >= 24 * 60)
+}
+
+fn slot_time(minutes: u32) -> NaiveTime {
+    let minutes = minutes % (24 * 60);
+    NaiveTime::from_hms_opt(minutes / 60, minutes % 60, 0).unwrap()
+}
+
+/// Hores planificades per una regla abans de desar-les
+#[derive(Debug, Clone)]
+pub struct SchedulePlan {
+    pub rule_id: Uuid,
+    pub hours: Vec<u8>,
+    /// Dispositius de la regla als quals s'apliquen les hores (None = tots, `Rule::device_ids`)
+    pub device_ids: Option<Vec<Uuid>>,
+}
+
+impl SchedulePlan {
+    /// Pla amb les mateixes hores per tots els dispositius de la regla
+    pub fn new(rule_id: Uuid, hours: Vec<u8>) -> Self {
+        Self { rule_id, hours, device_ids: None }
+    }
+}
+
+/// Paràmetres del càlcul d'hores òptimes (els d'una regla, sense blackouts ni sub-pressupostos)
+#[derive(Debug, Clone, Copy)]
+pub struct SchedulerParams<'a> {
+    pub action_type: ActionType,
+    pub max_hours: i32,
+    pub min_continuous_hours: i32,
+    /// Separació mínima en hores entre l'inici de dues hores saltejades (`Rule::min_gap_hours`)
+    pub min_gap_hours: Option<i32>,
+    /// Límit fins al qual s'amplia `max_hours` els dies amb preus negatius (`Rule::negative_price_boost`)
+    pub negative_price_boost: Option<i32>,
+    pub continuity: ContinuityPreference,
+    pub time_window_start: Option<NaiveTime>,
+    pub time_window_end: Option<NaiveTime>,
+    /// Pes relatiu del consum per cada hora del dia
+    pub load_profile: Option<&'a [f64]>,
+    /// Cost màxim (€) de les hores seleccionades. Només s'aplica a `TurnOn` i amb `wattage_watts`.
+    pub max_cost_eur: Option<f64>,
+    /// Potència dels dispositius de la regla (W), per convertir preus en cost
+    pub wattage_watts: Option<f64>,
+    /// Durada de cada franja dels preus (`PriceSlots::slot_minutes`). Les hores dels altres
+    /// paràmetres es converteixen a franges.
+    pub slot_minutes: u32,
+}
+
+impl<'a> SchedulerParams<'a> {
+    /// Paràmetres d'una regla
+    pub fn from_rule(rule: &'a Rule) -> Self {
+        Self {
+            action_type: rule.action_type,
+            max_hours: rule.max_hours,
+            min_continuous_hours: rule.min_continuous_hours,
+            min_gap_hours: rule.min_gap_hours,
+            negative_price_boost: rule.negative_price_boost,
+            continuity: rule.continuity_preference,
+            time_window_start: rule.time_window_start,
+            time_window_end: rule.time_window_end,
+            load_profile: rule.load_profile.as_deref(),
+            max_cost_eur: rule.max_cost_eur,
+            wattage_watts: rule.wattage_watts,
+            slot_minutes: 60,
+        }
+    }
+
+    /// Pressupost (€) i energia consumida en una franja (kWh) si cal limitar el cost
+    fn budget(&self) -> Option<(f64, f64)> {
+        if self.action_type != ActionType::TurnOn {
+            return None;
+        }
+        Some((self.max_cost_eur?, self.wattage_watts? / 1000.0 * self.slot_minutes as f64 / 60.0))
+    }
+
+    /// Franges que hi ha en una hora
+    fn slots_per_hour(&self) -> usize {
+        (60 / self.slot_minutes.clamp(1, 60)) as usize
+    }
+
+    /// Nombre de franges equivalent a un nombre d'hores
+    fn slots(&self, hours: i32) -> usize {
+        hours.max(0) as usize * self.slots_per_hour()
+    }
+
+    /// Franges a seleccionar d'entre `prices` (preus reals, ja filtrats per la finestra)
+    ///
+    /// Són les de `max_hours` però, per encendre amb `negative_price_boost`, si hi ha més
+    /// franges de preu negatiu s'hi arriba, com a màxim fins al límit del boost.
+    fn max_slots(&self, prices: &[HourlyPrice]) -> usize {
+        let max_slots = self.slots(self.max_hours);
+        let Some(boost) = self.negative_price_boost.filter(|_| self.action_type == ActionType::TurnOn) else {
+            return max_slots;
+        };
+        let negative = prices.iter().filter(|p| p.price < 0.0).count();
+        max_slots.max(negative.min(self.slots(boost)))
+    }
+}
+
+/// Calcula les hores òptimes per una regla
+///
+/// Per `TurnOn` selecciona les hores més barates; per `TurnOff`, les més cares.
+/// Amb `load_profile` (un pes per hora del dia) es compara `preu * pes` en lloc del preu;
+/// `total_price` continua sent la suma dels preus reals de les hores seleccionades.
+/// Amb `ContinuityPreference::Preferred`, si no hi ha cap bloc de `min_continuous_hours`
+/// hores es seleccionen les millors hores saltejades.
+/// Amb `min_gap_hours`, les hores saltejades comencen com a mínim aquestes hores després
+/// de l'anterior (amb 2, les 1 i 3 sí), encara que no se n'arribin a seleccionar `max_hours`.
+/// Amb pressupost (`max_cost_eur`), de les hores seleccionades es queden les més barates
+/// que hi caben (`calculate_hours_within_budget`); amb `min_continuous_hours`, blocs sencers.
+/// Amb `negative_price_boost`, els dies amb hores de preu negatiu dins la finestra se'n
+/// seleccionen més de `max_hours` si cal per cobrir-les totes, fins al límit indicat.
+/// Amb preus quart-horaris (`slot_minutes`), `prices` i el resultat són quarts d'hora, i
+/// `max_hours`, `min_continuous_hours` i `min_gap_hours` es compten en quarts.
+pub fn calculate_optimal_hours(prices: &[HourlyPrice], params: &SchedulerParams) -> OptimalHours {
+    let SchedulerParams {
+        action_type,
+        min_continuous_hours,
+        min_gap_hours,
+        continuity,
+        time_window_start,
+        time_window_end,
+        load_profile,
+        slot_minutes,
+        ..
+    } = *params;
+    let min_continuous_slots = params.slots(min_continuous_hours);
+    let min_gap_slots = params.slots(min_gap_hours.unwrap_or(0));
+
     // Filtrar hores dins la finestra temporal
-    let filtered_prices = filter_by_time_window(prices, time_window_start, time_window_end);
+    let mut filtered_prices = filter_by_time_window(prices, time_window_start, time_window_end, slot_minutes);
 
     if filtered_prices.is_empty() {
         return OptimalHours {
@@ -26,26 +229,753 @@ pub fn calculate_optimal_hours(
         };
     }
 
-    if min_continuous_hours <= 1 {
+    let max_slots = params.max_slots(&filtered_prices);
+
+    if let Some(weights) = load_profile {
+        apply_load_profile(&mut filtered_prices, weights, slot_minutes);
+    }
+
+    // Per apagar busquem les hores més cares: invertint el signe dels preus,
+    // els mateixos algorismes seleccionen les hores de preu més alt
+    let invert = action_type == ActionType::TurnOff;
+    if invert {
+        for p in &mut filtered_prices {
+            p.price = -p.price;
+        }
+    }
+
+    let mut result = if min_continuous_hours <= 1 {
         // Algorisme simple: seleccionar les hores més barates
-        calculate_scattered_hours(&filtered_prices, max_hours as usize)
+        calculate_scattered_hours(&filtered_prices, max_slots, min_gap_slots)
     } else {
         // Algorisme de blocs: seleccionar blocs continus
-        calculate_continuous_blocks(&filtered_prices, max_hours as usize, min_continuous_hours as usize)
+        let blocks = calculate_continuous_blocks(&filtered_prices, max_slots, min_continuous_slots);
+        if blocks.hours.is_empty() && continuity == ContinuityPreference::Preferred {
+            calculate_scattered_hours(&filtered_prices, max_slots, min_gap_slots)
+        } else {
+            blocks
+        }
+    };
+
+    if load_profile.is_some() {
+        result.total_price = sum_prices(prices, &result.hours);
+    } else if invert {
+        result.total_price = -result.total_price;
+    }
+
+    apply_budget(prices, result, params, max_slots)
+}
+
+/// Limita unes hores ja seleccionades al pressupost dels paràmetres, si n'hi ha
+///
+/// Amb `min_continuous_hours` es treuen blocs sencers, perquè no quedin trossos més curts del mínim.
+fn apply_budget(
+    prices: &[HourlyPrice],
+    result: OptimalHours,
+    params: &SchedulerParams,
+    max_slots: usize,
+) -> OptimalHours {
+    let Some((max_cost_eur, kwh)) = params.budget() else {
+        return result;
+    };
+
+    let selected: Vec<HourlyPrice> = prices.iter().filter(|p| result.hours.contains(&p.hour)).cloned().collect();
+    if params.min_continuous_hours > 1 {
+        calculate_blocks_within_budget(&selected, params.slots(params.min_continuous_hours), max_cost_eur, kwh)
+    } else {
+        calculate_hours_within_budget(&selected, max_slots, max_cost_eur, kwh)
+    }
+}
+
+/// Com `calculate_hours_within_budget`, però amb blocs de franges seguides en lloc d'hores soltes
+///
+/// Cada tram seguit de `selected` es parteix en blocs de `min_continuous` franges (el sobrant
+/// va a l'últim bloc), de manera que qualsevol combinació de blocs respecta el mínim. Els blocs
+/// s'afegeixen del preu mitjà més barat al més car mentre hi càpiguen; els que no hi caben
+/// se salten.
+fn calculate_blocks_within_budget(
+    selected: &[HourlyPrice],
+    min_continuous: usize,
+    max_cost_eur: f64,
+    kw: f64,
+) -> OptimalHours {
+    let mut sorted = selected.to_vec();
+    sorted.sort_by_key(|p| p.hour);
+
+    let mut runs: Vec<Vec<HourlyPrice>> = Vec::new();
+    for p in sorted {
+        match runs.last_mut() {
+            Some(run) if run.last().is_some_and(|last| last.hour + 1 == p.hour) => run.push(p),
+            _ => runs.push(vec![p]),
+        }
+    }
+
+    let mut blocks: Vec<Vec<HourlyPrice>> = Vec::new();
+    for run in runs {
+        let count = (run.len() / min_continuous).max(1);
+        let mut rest = run.as_slice();
+        for _ in 1..count {
+            let (block, tail) = rest.split_at(min_continuous);
+            blocks.push(block.to_vec());
+            rest = tail;
+        }
+        blocks.push(rest.to_vec());
+    }
+
+    let average = |block: &[HourlyPrice]| block.iter().map(|p| p.price).sum::<f64>() / block.len() as f64;
+    blocks.sort_by(|a, b| compare_prices(average(a), average(b)).then(a[0].hour.cmp(&b[0].hour)));
+
+    let mut hours = Vec::new();
+    let mut total_price = 0.0;
+    let mut cost = 0.0;
+    for block in blocks {
+        let block_price: f64 = block.iter().map(|p| p.price).sum();
+        if block_price.is_nan() || cost + block_price * kw > max_cost_eur {
+            continue;
+        }
+        cost += block_price * kw;
+        total_price += block_price;
+        hours.extend(block.iter().map(|p| p.hour));
+    }
+    hours.sort_unstable();
+
+    if hours.is_empty() && !selected.is_empty() {
+        tracing::warn!(
+            "Cap bloc de {} franges dins el pressupost de {:.2} € ({:.2} kW): no es programa res",
+            min_continuous,
+            max_cost_eur,
+            kw
+        );
+    }
+
+    OptimalHours { hours, total_price }
+}
+
+/// Selecciona les hores més barates sense superar un cost màxim
+///
+/// Algorisme voraç: ordena les hores per preu i les afegeix mentre no s'arribi a `max_hours`
+/// ni el cost acumulat (preu * `kw` per cada hora) superi `max_cost_eur`. Si no hi cap
+/// cap hora, retorna un resultat buit. Amb franges més curtes d'una hora, `kw` és l'energia
+/// consumida en una franja (kWh).
+pub fn calculate_hours_within_budget(
+    prices: &[HourlyPrice],
+    max_hours: usize,
+    max_cost_eur: f64,
+    kw: f64,
+) -> OptimalHours {
+    let mut sorted_prices = prices.to_vec();
+    sorted_prices.sort_by(|a, b| compare_prices(a.price, b.price).then(a.hour.cmp(&b.hour)));
+
+    let mut hours = Vec::new();
+    let mut total_price = 0.0;
+    let mut cost = 0.0;
+    for p in sorted_prices.into_iter().take(max_hours) {
+        let hour_cost = p.price * kw;
+        if p.price.is_nan() || cost + hour_cost > max_cost_eur {
+            break;
+        }
+        cost += hour_cost;
+        total_price += p.price;
+        hours.push(p.hour);
+    }
+
+    if hours.is_empty() && !prices.is_empty() {
+        tracing::warn!(
+            "Cap hora dins el pressupost de {:.2} € ({:.2} kW): no es programa res",
+            max_cost_eur,
+            kw
+        );
+    }
+
+    hours.sort();
+    OptimalHours { hours, total_price }
+}
+
+/// Motiu pel qual una hora s'ha seleccionat o no
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, utoipa::ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum HourReason {
+    /// Seleccionada: és de les millors hores per l'acció (les més barates; per apagar, les més cares)
+    SelectedCheap,
+    /// Fora de la finestra temporal o dins d'una franja de blackout
+    ExcludedWindow,
+    /// Més cara que la mitjana de les hores disponibles
+    ExcludedExpensive,
+    /// Prou barata, però no forma part de cap bloc continu seleccionat
+    NotInBlock,
+    /// Per sota de la mitjana, però les `max_hours` ja s'han cobert amb hores millors
+    OverBudget,
+}
+
+/// Decisió presa sobre una hora del dia
+#[derive(Debug, Clone, Serialize, utoipa::ToSchema)]
+pub struct HourDecision {
+    /// Hora (o quart d'hora del dia, amb preus quart-horaris)
+    pub hour: u8,
+    /// Preu real de l'hora (€/kWh)
+    pub price: f64,
+    pub selected: bool,
+    pub reason: HourReason,
+}
+
+/// Igual que `calculate_optimal_hours` (amb franges de blackout), però explica hora a hora
+/// per què s'ha seleccionat o descartat cada una.
+///
+/// Les hores descartades dins la finestra es classifiquen comparant el seu preu efectiu
+/// (amb el pes del perfil de càrrega i invertit per `TurnOff`) amb les hores disponibles:
+/// amb blocs continus, les que serien de les `max_hours` millors són `NotInBlock`;
+/// la resta són `OverBudget` si no superen la mitjana i `ExcludedExpensive` si la superen.
+pub fn explain_optimal_hours(
+    prices: &[HourlyPrice],
+    params: &SchedulerParams,
+    blackout_windows: &[BlackoutWindow],
+) -> (OptimalHours, Vec<HourDecision>) {
+    let SchedulerParams {
+        action_type,
+        max_hours,
+        min_continuous_hours,
+        time_window_start,
+        time_window_end,
+        load_profile,
+        slot_minutes,
+        ..
+    } = *params;
+
+    let available = apply_blackout_windows(prices, blackout_windows, slot_minutes);
+    let optimal = calculate_optimal_hours(&available, params);
+
+    // Preus efectius de les hores candidates, tal com els compara l'algorisme
+    let mut candidates = filter_by_time_window(&available, time_window_start, time_window_end, slot_minutes);
+    if let Some(weights) = load_profile {
+        apply_load_profile(&mut candidates, weights, slot_minutes);
+    }
+    if action_type == ActionType::TurnOff {
+        for p in &mut candidates {
+            p.price = -p.price;
+        }
+    }
+    let effective: HashMap<u8, f64> = candidates.iter().map(|p| (p.hour, p.price)).collect();
+
+    let mut ranked: Vec<f64> = candidates.iter().map(|p| p.price).collect();
+    ranked.sort_by(|a, b| compare_prices(*a, *b));
+    let cutoff = ranked.get(params.slots(max_hours.max(1)).min(ranked.len()).saturating_sub(1)).copied();
+    let mean = ranked.iter().sum::<f64>() / ranked.len().max(1) as f64;
+
+    let decisions = prices
+        .iter()
+        .map(|p| {
+            let selected = optimal.hours.contains(&p.hour);
+            let reason = match effective.get(&p.hour) {
+                None => HourReason::ExcludedWindow,
+                Some(_) if selected => HourReason::SelectedCheap,
+                Some(&price) if min_continuous_hours > 1 && cutoff.is_some_and(|c| price <= c) => {
+                    HourReason::NotInBlock
+                }
+                Some(&price) if price <= mean => HourReason::OverBudget,
+                Some(_) => HourReason::ExcludedExpensive,
+            };
+
+            HourDecision {
+                hour: p.hour,
+                price: p.price,
+                selected,
+                reason,
+            }
+        })
+        .collect();
+
+    (optimal, decisions)
+}
+
+/// Multiplica el preu de cada hora pel seu pes. Les hores sense pes (p. ex. la 25a hora
+/// del canvi d'horari) es mantenen amb pes 1. Els quarts d'hora fan servir el pes de la seva hora.
+fn apply_load_profile(prices: &mut [HourlyPrice], weights: &[f64], slot_minutes: u32) {
+    for p in prices {
+        let hour = (p.hour as u32 * slot_minutes / 60) as usize;
+        p.price *= weights.get(hour).copied().unwrap_or(1.0);
+    }
+}
+
+/// Suma dels preus de les hores indicades
+fn sum_prices(prices: &[HourlyPrice], hours: &[u8]) -> f64 {
+    prices
+        .iter()
+        .filter(|p| hours.contains(&p.hour))
+        .map(|p| p.price)
+        .sum()
+}
+
+/// Calcula les hores òptimes d'una regla amb totes les seves opcions:
+/// franges de blackout i, si n'hi ha, sub-pressupostos. Les regles `Fixed` no optimitzen:
+/// vegeu `fixed_hours`.
+pub fn optimal_hours_for_rule(slots: &PriceSlots, rule: &Rule) -> OptimalHours {
+    let prices = apply_blackout_windows(&slots.prices, &rule.blackout_windows, slots.slot_minutes);
+    if rule.mode == RuleMode::Fixed {
+        return fixed_hours(&prices, rule, slots.slot_minutes);
+    }
+
+    let params = SchedulerParams {
+        slot_minutes: slots.slot_minutes,
+        ..SchedulerParams::from_rule(rule)
+    };
+
+    if rule.sub_budgets.is_empty() {
+        calculate_optimal_hours(&prices, &params)
+    } else {
+        calculate_sub_budget_hours(&prices, &params, &rule.sub_budgets)
+    }
+}
+
+/// Franges d'una regla `Fixed`: les de les hores de `Rule::fixed_hours` o, si no n'hi ha,
+/// totes les de la finestra horària. No es mira el preu, i `max_hours`, el cost màxim i els
+/// sub-pressupostos no s'apliquen; les franges de blackout ja s'han tret de `prices`.
+fn fixed_hours(prices: &[HourlyPrice], rule: &Rule, slot_minutes: u32) -> OptimalHours {
+    let selected = match rule.fixed_hours.as_deref().filter(|hours| !hours.is_empty()) {
+        Some(hours) => prices
+            .iter()
+            .filter(|p| hours.contains(&((p.hour as u32 * slot_minutes / 60) as i32)))
+            .cloned()
+            .collect(),
+        None => filter_by_time_window(prices, rule.time_window_start, rule.time_window_end, slot_minutes),
+    };
+
+    let mut hours: Vec<u8> = selected.iter().map(|p| p.hour).collect();
+    hours.sort_unstable();
+    OptimalHours {
+        total_price: selected.iter().map(|p| p.price).sum(),
+        hours,
+    }
+}
+
+/// Hores que una regla programa en tota una setmana (`calculate_weekly_optimal`):
+/// `max_hours * 7 / dies actius de days_of_week`
+pub fn weekly_hours(rule: &Rule) -> usize {
+    let active_days = rule.days().count().max(1) as usize;
+    rule.max_hours.max(0) as usize * 7 / active_days
+}
+
+/// Planifica una setmana sencera d'una regla en lloc de dia a dia
+///
+/// En comptes de `max_hours` cada dia, reparteix `weekly_hours(rule)` hores entre tots els
+/// dies de `prices_by_date` triant les més barates de la setmana (les més cares amb
+/// `TurnOff`). Només hi entren els dies en què s'aplica la regla (`Rule::runs_on`: dies de la
+/// setmana i temporada). Cada dia respecta la finestra horària, les franges de blackout i
+/// `min_continuous_hours` o `min_gap_hours`; els blocs no passen d'un dia a l'altre. El cost màxim i els
+/// sub-pressupostos són diaris i aquí no s'apliquen, i les regles `Fixed` tenen les seves
+/// hores fixes cada dia. El resultat té una entrada per cada dia, encara que no tingui hores.
+pub fn calculate_weekly_optimal(
+    prices_by_date: &[(NaiveDate, &[HourlyPrice])],
+    rule: &Rule,
+) -> HashMap<NaiveDate, OptimalHours> {
+    if rule.mode == RuleMode::Fixed {
+        return prices_by_date
+            .iter()
+            .map(|(date, prices)| {
+                let optimal = if rule.runs_on(*date) {
+                    optimal_hours_for_rule(&PriceSlots::hourly(prices), rule)
+                } else {
+                    OptimalHours { hours: vec![], total_price: 0.0 }
+                };
+                (*date, optimal)
+            })
+            .collect();
+    }
+
+    // Franges candidates de cada dia, amb el preu que es compara (cap els dies que no s'aplica)
+    let candidates: Vec<Vec<HourlyPrice>> = prices_by_date
+        .iter()
+        .map(|(date, prices)| {
+            if !rule.runs_on(*date) {
+                return vec![];
+            }
+            let prices = apply_blackout_windows(prices, &rule.blackout_windows, 60);
+            let mut candidates = filter_by_time_window(&prices, rule.time_window_start, rule.time_window_end, 60);
+            if let Some(weights) = rule.load_profile.as_deref() {
+                apply_load_profile(&mut candidates, weights, 60);
+            }
+            if rule.action_type == ActionType::TurnOff {
+                for p in &mut candidates {
+                    p.price = -p.price;
+                }
+            }
+            candidates
+        })
+        .collect();
+
+    let target = weekly_hours(rule);
+    let min_continuous = rule.min_continuous_hours.max(1) as usize;
+    let min_gap = rule.min_gap_hours.unwrap_or(0).max(0) as usize;
+    let mut selected = if min_continuous == 1 {
+        weekly_scattered_hours(&candidates, target, min_gap)
+    } else {
+        weekly_continuous_blocks(&candidates, target, min_continuous)
+    };
+    if min_continuous > 1
+        && selected.iter().all(|hours| hours.is_empty())
+        && rule.continuity_preference == ContinuityPreference::Preferred
+    {
+        selected = weekly_scattered_hours(&candidates, target, min_gap);
+    }
+
+    prices_by_date
+        .iter()
+        .zip(selected)
+        .map(|((date, prices), mut hours)| {
+            hours.sort_unstable();
+            let total_price = sum_prices(prices, &hours);
+            (*date, OptimalHours { hours, total_price })
+        })
+        .collect()
+}
+
+/// Les `target` franges més barates de tota la setmana, per dia. A igual preu guanya la
+/// més primerenca. Dins de cada dia, les franges comencen com a mínim `min_gap` franges després de l'anterior.
+fn weekly_scattered_hours(candidates: &[Vec<HourlyPrice>], target: usize, min_gap: usize) -> Vec<Vec<u8>> {
+    let mut all: Vec<(usize, &HourlyPrice)> = candidates
+        .iter()
+        .enumerate()
+        .flat_map(|(day, prices)| prices.iter().map(move |p| (day, p)))
+        .collect();
+    all.sort_by(|a, b| compare_prices(a.1.price, b.1.price).then(a.0.cmp(&b.0)).then(a.1.hour.cmp(&b.1.hour)));
+
+    let mut selected: Vec<Vec<u8>> = vec![Vec::new(); candidates.len()];
+    let mut total = 0;
+    for (day, p) in all {
+        if total >= target {
+            break;
+        }
+        if selected[day].iter().all(|h| h.abs_diff(p.hour) as usize >= min_gap.max(1)) {
+            selected[day].push(p.hour);
+            total += 1;
+        }
+    }
+    selected
+}
+
+/// Com `calculate_continuous_blocks`, però amb els blocs de tots els dies alhora
+fn weekly_continuous_blocks(candidates: &[Vec<HourlyPrice>], target: usize, min_continuous: usize) -> Vec<Vec<u8>> {
+    let mut blocks: Vec<(usize, Vec<u8>, f64)> = candidates
+        .iter()
+        .enumerate()
+        .flat_map(|(day, prices)| {
+            continuous_blocks(prices, min_continuous).into_iter().map(move |(hours, avg)| (day, hours, avg))
+        })
+        .collect();
+    blocks.sort_by(|a, b| compare_prices(a.2, b.2).then(a.0.cmp(&b.0)).then_with(|| a.1.cmp(&b.1)));
+
+    let mut selected: Vec<Vec<u8>> = vec![Vec::new(); candidates.len()];
+    let mut total = 0;
+    for (day, block_hours, _avg_price) in blocks {
+        let overlaps = block_hours.iter().any(|h| selected[day].contains(h));
+        if !overlaps && total + block_hours.len() <= target {
+            total += block_hours.len();
+            selected[day].extend(block_hours);
+
+            if total >= target {
+                break;
+            }
+        }
+    }
+    selected
+}
+
+/// Reparteix les hores d'una regla de grup perquè no hi hagi més de `Rule::max_simultaneous`
+/// dispositius del grup encesos alhora
+///
+/// `hours` són les hores ja calculades per la regla (`optimal_hours_for_rule`). Els
+/// dispositius es divideixen en tandes de `max_simultaneous`: la primera es queda `hours` i
+/// cada tanda següent fa el mateix càlcul sense les franges de les anteriors. Si no queden
+/// prou franges, les últimes tandes tenen menys hores. Les regles d'apagar i les que no
+/// superen el límit tornen un sol pla per tots els dispositius.
+pub fn stagger_group_hours(slots: &PriceSlots, rule: &Rule, hours: Vec<u8>) -> Vec<SchedulePlan> {
+    let max = rule.max_simultaneous.map_or(usize::MAX, |max| max.max(1) as usize);
+    if rule.action_type == ActionType::TurnOff || rule.device_ids.len() <= max {
+        return vec![SchedulePlan::new(rule.id, hours)];
+    }
+
+    let mut taken: HashSet<u8> = hours.iter().copied().collect();
+    let mut waves = rule.device_ids.chunks(max);
+    let mut plans = vec![SchedulePlan {
+        rule_id: rule.id,
+        hours,
+        device_ids: waves.next().map(<[Uuid]>::to_vec),
+    }];
+
+    for devices in waves {
+        let remaining = PriceSlots {
+            prices: slots.prices.iter().filter(|p| !taken.contains(&p.hour)).cloned().collect(),
+            slot_minutes: slots.slot_minutes,
+        };
+        let hours = optimal_hours_for_rule(&remaining, rule).hours;
+        if hours.len() < wave_slots(slots, rule) {
+            tracing::warn!(
+                "Regla '{}': només {} franges lliures per {} dispositius més del grup (màxim {} alhora)",
+                rule.name,
+                hours.len(),
+                devices.len(),
+                max
+            );
+        }
+
+        taken.extend(hours.iter().copied());
+        plans.push(SchedulePlan {
+            rule_id: rule.id,
+            hours,
+            device_ids: Some(devices.to_vec()),
+        });
+    }
+
+    plans
+}
+
+/// Franges que hauria de tenir cada tanda d'una regla de grup: `max_hours` en franges de `slots`
+fn wave_slots(slots: &PriceSlots, rule: &Rule) -> usize {
+    let params = SchedulerParams {
+        slot_minutes: slots.slot_minutes,
+        ..SchedulerParams::from_rule(rule)
+    };
+    params.slots(rule.max_hours)
+}
+
+/// Optimitza cada sub-pressupost dins la seva finestra i uneix els resultats
+///
+/// Les hores que surten a més d'un sub-pressupost (finestres solapades) només es compten una vegada.
+/// `min_continuous_hours` s'aplica a cada sub-pressupost, limitat a les seves hores. `max_hours`
+/// i la finestra de `params` s'ignoren; el cost màxim s'aplica al conjunt.
+pub fn calculate_sub_budget_hours(
+    prices: &[HourlyPrice],
+    params: &SchedulerParams,
+    sub_budgets: &[SubBudget],
+) -> OptimalHours {
+    let hours: BTreeSet<u8> = sub_budgets
+        .iter()
+        .flat_map(|budget| {
+            let budget_params = SchedulerParams {
+                max_hours: budget.hours,
+                min_continuous_hours: params.min_continuous_hours.min(budget.hours),
+                time_window_start: Some(budget.start),
+                time_window_end: Some(budget.end),
+                max_cost_eur: None,
+                // El límit és de tot el dia: no es multiplica per cada sub-pressupost
+                negative_price_boost: None,
+                ..*params
+            };
+            calculate_optimal_hours(prices, &budget_params).hours
+        })
+        .collect();
+
+    let hours: Vec<u8> = hours.into_iter().collect();
+    let total_price = sum_prices(prices, &hours);
+    let result = OptimalHours { hours, total_price };
+
+    let total_slots = result.hours.len();
+    apply_budget(prices, result, params, total_slots)
+}
+
+/// Limita els dispositius encesos alhora segons la prioritat de les regles
+///
+/// Les regles es processen per prioritat (1 = màxima; a igual prioritat, la més antiga).
+/// Si afegir una hora d'una regla superaria `max_concurrent_devices` dispositius encesos
+/// en aquella hora, l'hora es descarta. Les regles d'apagar no compten com a càrrega, i
+/// dues regles del mateix dispositiu compten com un sol dispositiu. Una regla de grup
+/// encén tots els dispositius del pla alhora (`SchedulePlan::device_ids`).
+pub fn resolve_conflicts(rules: &[Rule], schedules: &mut [SchedulePlan], max_concurrent_devices: Option<i32>) {
+    let Some(max) = max_concurrent_devices else {
+        return;
+    };
+    let max = max.max(0) as usize;
+
+    let rules_by_id: HashMap<Uuid, &Rule> = rules.iter().map(|r| (r.id, r)).collect();
+
+    schedules.sort_by_key(|plan| {
+        rules_by_id
+            .get(&plan.rule_id)
+            .map(|r| (r.priority, r.created_at))
+    });
+
+    let mut devices_per_hour: HashMap<u8, HashSet<Uuid>> = HashMap::new();
+
+    for plan in schedules.iter_mut() {
+        let Some(rule) = rules_by_id.get(&plan.rule_id) else {
+            continue;
+        };
+        if rule.action_type == ActionType::TurnOff {
+            continue;
+        }
+
+        let plan_devices = plan.device_ids.as_deref().unwrap_or(&rule.device_ids);
+        let before = plan.hours.len();
+        plan.hours.retain(|hour| {
+            let devices = devices_per_hour.entry(*hour).or_default();
+            let added = plan_devices.iter().filter(|id| !devices.contains(*id)).count();
+            if devices.len() + added <= max {
+                devices.extend(plan_devices.iter().copied());
+                true
+            } else {
+                false
+            }
+        });
+
+        if plan.hours.len() < before {
+            tracing::info!(
+                "Regla '{}' (prioritat {}): {} hores descartades per superar {} dispositius simultanis",
+                rule.name,
+                rule.priority,
+                before - plan.hours.len(),
+                max
+            );
+        }
+    }
+}
+
+/// Hores en què diverses regles tenen una acció pendent pel mateix dispositiu
+///
+/// Passa amb regles d'igual prioritat (les de menys prioritat no arriben a crear l'acció):
+/// l'app rep més d'una acció pel dispositiu a la mateixa hora.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ScheduleConflict {
+    pub device_id: Uuid,
+    pub date: NaiveDate,
+    /// Hores del dia (0-23) amb accions de més d'una regla a la mateixa franja
+    pub conflicting_hours: Vec<u8>,
+    /// Regles amb accions en alguna d'aquestes hores
+    pub rule_ids: Vec<Uuid>,
+}
+
+/// Conflictes entre les accions pendents de `schedules` (vegeu `ScheduleConflict`),
+/// ordenats per data i dispositiu
+pub fn detect_conflicts(schedules: &[ScheduledAction]) -> Vec<ScheduleConflict> {
+    detect_action_conflicts(
+        schedules
+            .iter()
+            .filter(|action| action.status == "pending")
+            .map(|action| (action.rule_id, action.device_id, action.scheduled_date, action.start_time)),
+    )
+}
+
+/// Conflictes entre accions (regla, dispositiu, data, inici), p. ex. les d'una simulació
+pub fn detect_action_conflicts(
+    actions: impl IntoIterator<Item = (Uuid, Uuid, NaiveDate, NaiveTime)>,
+) -> Vec<ScheduleConflict> {
+    let mut rules_per_slot: HashMap<(Uuid, NaiveDate, NaiveTime), BTreeSet<Uuid>> = HashMap::new();
+    for (rule_id, device_id, date, start_time) in actions {
+        rules_per_slot.entry((device_id, date, start_time)).or_default().insert(rule_id);
+    }
+
+    let mut conflicts: BTreeMap<(NaiveDate, Uuid), (BTreeSet<u8>, BTreeSet<Uuid>)> = BTreeMap::new();
+    for ((device_id, date, start_time), rule_ids) in rules_per_slot {
+        if rule_ids.len() < 2 {
+            continue;
+        }
+        let (hours, rules) = conflicts.entry((date, device_id)).or_default();
+        hours.insert(start_time.hour() as u8);
+        rules.extend(rule_ids);
+    }
+
+    conflicts
+        .into_iter()
+        .map(|((date, device_id), (hours, rule_ids))| ScheduleConflict {
+            device_id,
+            date,
+            conflicting_hours: hours.into_iter().collect(),
+            rule_ids: rule_ids.into_iter().collect(),
+        })
+        .collect()
+}
+
+/// Calcula el preu de referència d'una regla a partir de l'historial
+///
+/// Aplica `calculate` (el càlcul d'hores de la regla) a cada dia anterior i fa la
+/// mitjana dels preus mitjans que s'haurien obtingut. Retorna None si no hi ha historial aprofitable.
+pub fn calculate_baseline_price(
+    history: &[DailyPrices],
+    calculate: impl Fn(&[HourlyPrice]) -> OptimalHours,
+) -> Option<f64> {
+    let daily_averages: Vec<f64> = history
+        .iter()
+        .filter_map(|day| calculate(&day.prices).average_price())
+        .collect();
+
+    if daily_averages.is_empty() {
+        return None;
+    }
+
+    Some(daily_averages.iter().sum::<f64>() / daily_averages.len() as f64)
+}
+
+/// Comprova si les hores calculades són prou barates respecte la referència
+///
+/// El preu mitjà ha de ser inferior a la referència menys `margin_pct` %.
+/// Sense referència (no hi ha historial) no es bloqueja la programació.
+pub fn is_cheaper_than_baseline(optimal: &OptimalHours, baseline: Option<f64>, margin_pct: f64) -> bool {
+    let (Some(average), Some(baseline)) = (optimal.average_price(), baseline) else {
+        return true;
+    };
+
+    // Usem abs() perquè el marge també tingui sentit amb preus negatius
+    average < baseline - baseline.abs() * margin_pct / 100.0
+}
+
+/// Exclou les hores que se solapen amb alguna franja de blackout
+///
+/// Una hora (ex: 13:00-14:00) s'exclou si qualsevol part d'ella cau dins d'una franja.
+/// Amb preus quart-horaris (`slot_minutes`), el mateix per cada quart d'hora.
+pub fn apply_blackout_windows(
+    prices: &[HourlyPrice],
+    windows: &[BlackoutWindow],
+    slot_minutes: u32,
+) -> Vec<HourlyPrice> {
+    prices
+        .iter()
+        .filter(|p| !windows.iter().any(|w| slot_overlaps_window(p.hour, slot_minutes, w)))
+        .cloned()
+        .collect()
+}
+
+fn slot_overlaps_window(slot: u8, slot_minutes: u32, window: &BlackoutWindow) -> bool {
+    const DAY_SECONDS: u32 = 24 * 3600;
+    let slot_start = slot as u32 * slot_minutes * 60;
+    let slot_end = slot_start + slot_minutes * 60;
+    let start = window.start.num_seconds_from_midnight();
+    let end = window.end.num_seconds_from_midnight();
+
+    let overlaps = |from: u32, to: u32| slot_start < to && slot_end > from;
+
+    if start <= end {
+        overlaps(start, end)
+    } else {
+        // Franja que creua mitjanit: ex. 23:00-01:00
+        overlaps(start, DAY_SECONDS) || overlaps(0, end)
     }
 }
 
+/// Hores senceres dins d'una finestra, amb el mateix criteri que `filter_by_time_window`
+///
+/// Si l'inici és posterior al final, la finestra creua mitjanit (22:00-06:00 són 8 hores).
+/// Sense inici o final, la finestra arriba al principi o al final del dia.
+pub fn time_window_hours(start: Option<NaiveTime>, end: Option<NaiveTime>) -> u32 {
+    let start = start.map_or(0, |t| t.hour());
+    let end = end.map_or(24, |t| t.hour());
+
+    if start <= end { end - start } else { 24 - start + end }
+}
+
 /// Filtra les hores dins d'una finestra temporal
+///
+/// Els límits de la finestra s'arrodoneixen a l'inici de la seva franja (hora o quart d'hora).
 fn filter_by_time_window(
     prices: &[HourlyPrice],
     start: Option<NaiveTime>,
     end: Option<NaiveTime>,
+    slot_minutes: u32,
 ) -> Vec<HourlyPrice> {
+    let slot_of = |t: NaiveTime| ((t.hour() * 60 + t.minute()) / slot_minutes) as u8;
+
     match (start, end) {
         (None, None) => prices.to_vec(),
         (Some(start), Some(end)) => {
-            let start_hour = start.hour() as u8;
-            let end_hour = end.hour() as u8;
+            let start_hour = slot_of(start);
+            let end_hour = slot_of(end);
 
             prices
                 .iter()
@@ -63,22 +993,44 @@ fn filter_by_time_window(
         }
         // Si només hi ha un dels dos, assumim tota la nit/dia
         (Some(start), None) => {
-            let start_hour = start.hour() as u8;
+            let start_hour = slot_of(start);
             prices.iter().filter(|p| p.hour >= start_hour).cloned().collect()
         }
         (None, Some(end)) => {
-            let end_hour = end.hour() as u8;
+            let end_hour = slot_of(end);
             prices.iter().filter(|p| p.hour < end_hour).cloned().collect()
         }
     }
 }
 
+/// Ordre total de preus, del més barat al més car. Un preu NaN (dada corrupta) es
+/// tracta com el més car perquè no se seleccioni mai abans d'un preu vàlid.
+fn compare_prices(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (false, false) => a.total_cmp(&b),
+        (a_nan, b_nan) => a_nan.cmp(&b_nan),
+    }
+}
+
 /// Algorisme per hores saltejades (min_continuous = 1)
-fn calculate_scattered_hours(prices: &[HourlyPrice], max_hours: usize) -> OptimalHours {
+///
+/// A igual preu guanya l'hora més primerenca, perquè el resultat no depengui de l'ordre d'entrada.
+/// Amb `min_gap` > 1 se salten les hores que quedarien a menys de `min_gap` hores d'una de ja
+/// triada (exactament `min_gap` hores sí que val), i pot ser que no se'n triïn `max_hours`.
+fn calculate_scattered_hours(prices: &[HourlyPrice], max_hours: usize, min_gap: usize) -> OptimalHours {
     let mut sorted_prices = prices.to_vec();
-    sorted_prices.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap());
+    sorted_prices.sort_by(|a, b| compare_prices(a.price, b.price).then(a.hour.cmp(&b.hour)));
 
-    let selected: Vec<_> = sorted_prices.into_iter().take(max_hours).collect();
+    let mut selected: Vec<HourlyPrice> = Vec::new();
+    for p in sorted_prices {
+        if selected.len() >= max_hours {
+            break;
+        }
+        // Com a mínim 1: una mateixa franja no es tria dues vegades
+        if selected.iter().all(|s| s.hour.abs_diff(p.hour) as usize >= min_gap.max(1)) {
+            selected.push(p);
+        }
+    }
     let total_price: f64 = selected.iter().map(|p| p.price).sum();
 
     let mut hours: Vec<u8> = selected.iter().map(|p| p.hour).collect();
@@ -93,7 +1045,8 @@ fn calculate_continuous_blocks(
     max_hours: usize,
     min_continuous: usize,
 ) -> OptimalHours {
-    if prices.len() < min_continuous {
+    let mut blocks = continuous_blocks(prices, min_continuous);
+    if blocks.is_empty() {
         return OptimalHours {
             hours: vec![],
             total_price: 0.0,
@@ -101,59 +1054,18 @@ fn calculate_continuous_blocks(
     }
 
     // Crear un mapa d'hora -> preu per accés ràpid
-    let price_map: std::collections::HashMap<u8, f64> =
-        prices.iter().map(|p| (p.hour, p.price)).collect();
+    let price_map: HashMap<u8, f64> = prices.iter().map(|p| (p.hour, p.price)).collect();
 
-    // Obtenir les hores disponibles ordenades
-    let mut available_hours: Vec<u8> = prices.iter().map(|p| p.hour).collect();
-    available_hours.sort();
-
-    // Generar tots els blocs possibles de min_continuous hores consecutives
-    let mut blocks: Vec<(Vec<u8>, f64)> = Vec::new();
+    // Ordenar blocs per preu mitjà (a igual preu, el que comença abans)
+    blocks.sort_by(|a, b| compare_prices(a.1, b.1).then_with(|| a.0.cmp(&b.0)));
 
-    for i in 0..available_hours.len() {
-        let mut block_hours = vec![available_hours[i]];
-        let mut block_price = price_map[&available_hours[i]];
+    // Seleccionar blocs sense solapament fins arribar a max_hours
+    let mut selected_hours: Vec<u8> = Vec::new();
+    let mut total_price = 0.0;
 
-        for j in (i + 1)..available_hours.len() {
-            let prev_hour = block_hours.last().unwrap();
-            let curr_hour = available_hours[j];
-
-            // Comprovar si és consecutiu (considerant el wrap-around a mitjanit)
-            let is_consecutive = (curr_hour == prev_hour + 1)
-                || (*prev_hour == 23 && curr_hour == 0);
-
-            if !is_consecutive {
-                break;
-            }
-
-            block_hours.push(curr_hour);
-            block_price += price_map[&curr_hour];
-
-            if block_hours.len() >= min_continuous {
-                let avg_price = block_price / block_hours.len() as f64;
-                blocks.push((block_hours.clone(), avg_price));
-            }
-        }
-    }
-
-    if blocks.is_empty() {
-        return OptimalHours {
-            hours: vec![],
-            total_price: 0.0,
-        };
-    }
-
-    // Ordenar blocs per preu mitjà
-    blocks.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-
-    // Seleccionar blocs sense solapament fins arribar a max_hours
-    let mut selected_hours: Vec<u8> = Vec::new();
-    let mut total_price = 0.0;
-
-    for (block_hours, _avg_price) in blocks {
-        // Comprovar si aquest bloc solapa amb els ja seleccionats
-        let overlaps = block_hours.iter().any(|h| selected_hours.contains(h));
+    for (block_hours, _avg_price) in blocks {
+        // Comprovar si aquest bloc solapa amb els ja seleccionats
+        let overlaps = block_hours.iter().any(|h| selected_hours.contains(h));
 
         if !overlaps && selected_hours.len() + block_hours.len() <= max_hours {
             for hour in &block_hours {
@@ -175,9 +1087,74 @@ fn calculate_continuous_blocks(
     }
 }
 
+/// Tots els blocs d'hores consecutives de com a mínim `min_continuous` hores, amb el seu preu mitjà
+fn continuous_blocks(prices: &[HourlyPrice], min_continuous: usize) -> Vec<(Vec<u8>, f64)> {
+    if prices.len() < min_continuous {
+        return vec![];
+    }
+
+    // Crear un mapa d'hora -> preu per accés ràpid
+    let price_map: HashMap<u8, f64> = prices.iter().map(|p| (p.hour, p.price)).collect();
+
+    // Obtenir les hores disponibles ordenades
+    let mut available_hours: Vec<u8> = prices.iter().map(|p| p.hour).collect();
+    available_hours.sort();
+
+    // Generar tots els blocs possibles de min_continuous hores consecutives
+    let mut blocks: Vec<(Vec<u8>, f64)> = Vec::new();
+
+    for i in 0..available_hours.len() {
+        let mut block_hours = vec![available_hours[i]];
+        let mut block_price = price_map[&available_hours[i]];
+
+        for &curr_hour in &available_hours[(i + 1)..] {
+            let prev_hour = block_hours.last().unwrap();
+
+            // Comprovar si és consecutiu (considerant el wrap-around a mitjanit)
+            let is_consecutive = (curr_hour == prev_hour + 1)
+                || (*prev_hour == 23 && curr_hour == 0);
+
+            if !is_consecutive {
+                break;
+            }
+
+            block_hours.push(curr_hour);
+            block_price += price_map[&curr_hour];
+
+            if block_hours.len() >= min_continuous {
+                let avg_price = block_price / block_hours.len() as f64;
+                blocks.push((block_hours.clone(), avg_price));
+            }
+        }
+    }
+
+    blocks
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::models::ContinuityPreference::{Preferred, Required};
+    use rstest::rstest;
+    use shared::PricePoint;
+
+    /// Paràmetres sense finestra, perfil ni pressupost
+    fn params(action_type: ActionType, max_hours: i32, min_continuous_hours: i32) -> SchedulerParams<'static> {
+        SchedulerParams {
+            action_type,
+            max_hours,
+            min_continuous_hours,
+            min_gap_hours: None,
+            negative_price_boost: None,
+            continuity: Required,
+            time_window_start: None,
+            time_window_end: None,
+            load_profile: None,
+            max_cost_eur: None,
+            wattage_watts: None,
+            slot_minutes: 60,
+        }
+    }
 
     fn create_test_prices() -> Vec<HourlyPrice> {
         // Preus de prova: més barat a la matinada, més car a la tarda
@@ -192,14 +1169,61 @@ mod tests {
                     18..=21 => 0.25 - (hour as f64 * 0.002),// Molt car
                     _ => 0.08,                               // Nit
                 },
+                raw_price: None,
             })
             .collect()
     }
 
+    fn window(start: u32, end: u32) -> BlackoutWindow {
+        BlackoutWindow {
+            start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_blackout_excludes_cheapest_hours() {
+        let prices = create_test_prices();
+        // Sense blackout, les 4 més barates són 00:00-03:00
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 4, 1));
+        assert_eq!(result.hours, vec![0, 1, 2, 3]);
+
+        let filtered = apply_blackout_windows(&prices, &[window(0, 2)], 60);
+        let result = calculate_optimal_hours(&filtered, &params(ActionType::TurnOn, 4, 1));
+        assert_eq!(result.hours.len(), 4);
+        assert!(!result.hours.contains(&0));
+        assert!(!result.hours.contains(&1));
+        assert!(result.hours.contains(&2));
+    }
+
+    #[test]
+    fn test_blackout_partial_and_midnight_windows() {
+        let prices = create_test_prices();
+
+        // 13:30-14:00 exclou tota l'hora 13
+        let partial = BlackoutWindow {
+            start: NaiveTime::from_hms_opt(13, 30, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(14, 0, 0).unwrap(),
+        };
+        let filtered = apply_blackout_windows(&prices, &[partial], 60);
+        assert_eq!(filtered.len(), 23);
+        assert!(filtered.iter().all(|p| p.hour != 13));
+
+        // 23:00-01:00 exclou les hores 23 i 0, i els blocs continus les eviten
+        let filtered = apply_blackout_windows(&prices, &[window(23, 1), window(13, 15)], 60);
+        let hours: Vec<u8> = filtered.iter().map(|p| p.hour).collect();
+        assert!(!hours.contains(&23) && !hours.contains(&0));
+        assert!(!hours.contains(&13) && !hours.contains(&14));
+        assert_eq!(hours.len(), 20);
+
+        let result = calculate_optimal_hours(&filtered, &params(ActionType::TurnOn, 3, 3));
+        assert_eq!(result.hours, vec![1, 2, 3]);
+    }
+
     #[test]
     fn test_scattered_hours() {
         let prices = create_test_prices();
-        let result = calculate_optimal_hours(&prices, 6, 1, None, None);
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 6, 1));
 
         assert_eq!(result.hours.len(), 6);
         // Les primeres hores haurien de ser les de matinada (més barates)
@@ -207,13 +1231,193 @@ mod tests {
         assert!(result.hours.contains(&1));
     }
 
+    #[test]
+    fn test_scattered_hours_ties_prefer_earlier_hours() {
+        // Preus repetits (ESIOS sovint en repeteix) en ordre d'entrada desordenat
+        let tied = [(17, 0.05), (9, 0.05), (3, 0.05), (12, 0.05), (1, 0.20)];
+        let mut prices: Vec<HourlyPrice> =
+            tied.iter().map(|&(hour, price)| HourlyPrice { hour, price, raw_price: None }).collect();
+
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 2, 1));
+        assert_eq!(result.hours, vec![3, 9]);
+
+        prices.reverse();
+        let reversed = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 2, 1));
+        assert_eq!(reversed.hours, result.hours);
+    }
+
+    #[test]
+    fn test_scattered_hours_respect_min_gap() {
+        let prices = create_test_prices();
+        let gapped = SchedulerParams { min_gap_hours: Some(2), ..params(ActionType::TurnOn, 4, 1) };
+        let result = calculate_optimal_hours(&prices, &gapped);
+
+        // Les hores 1, 3 i 5 són barates però queden massa a prop de les ja triades;
+        // les que són exactament a 2 hores (0, 2 i 4) sí que valen
+        assert_eq!(result.hours, vec![0, 2, 4, 22]);
+        assert!(result.hours.windows(2).all(|pair| pair[1] - pair[0] >= 2));
+        let expected: f64 = [0, 2, 4, 22].iter().map(|&h| prices[h].price).sum();
+        assert!((result.total_price - expected).abs() < 1e-9);
+
+        // Amb una separació massa gran no es poden triar totes les hores
+        let wide = SchedulerParams { min_gap_hours: Some(12), ..params(ActionType::TurnOn, 4, 1) };
+        assert_eq!(calculate_optimal_hours(&prices, &wide).hours, vec![0, 22]);
+
+        // Una separació d'1 hora és com no tenir-ne
+        let adjacent = SchedulerParams { min_gap_hours: Some(1), ..params(ActionType::TurnOn, 4, 1) };
+        assert_eq!(calculate_optimal_hours(&prices, &adjacent).hours, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_negative_price_boost() {
+        // Migdia solar amb preus negatius de 11 a 15
+        let mut prices = create_test_prices();
+        for p in &mut prices[11..=15] {
+            p.price = -0.01 * (p.hour as f64 - 10.0);
+        }
+        let boosted = |action_type, max_hours, boost| SchedulerParams {
+            negative_price_boost: boost,
+            ..params(action_type, max_hours, 1)
+        };
+
+        // Sense boost, les 2 hores més negatives
+        assert_eq!(calculate_optimal_hours(&prices, &boosted(ActionType::TurnOn, 2, None)).hours, vec![14, 15]);
+
+        // Amb boost, totes les negatives fins al límit
+        let result = calculate_optimal_hours(&prices, &boosted(ActionType::TurnOn, 2, Some(8)));
+        assert_eq!(result.hours, vec![11, 12, 13, 14, 15]);
+        assert!((result.total_price - -0.15).abs() < 1e-9);
+        assert_eq!(calculate_optimal_hours(&prices, &boosted(ActionType::TurnOn, 2, Some(3))).hours, vec![13, 14, 15]);
+
+        // max_hours ja en cobreix més: el boost no en treu
+        assert_eq!(calculate_optimal_hours(&prices, &boosted(ActionType::TurnOn, 6, Some(3))).hours.len(), 6);
+
+        // Sense preus negatius, o per apagar, no hi ha ampliació
+        let positive = create_test_prices();
+        assert_eq!(calculate_optimal_hours(&positive, &boosted(ActionType::TurnOn, 2, Some(8))).hours, vec![0, 1]);
+        assert_eq!(calculate_optimal_hours(&prices, &boosted(ActionType::TurnOff, 2, Some(8))).hours.len(), 2);
+    }
+
+    #[test]
+    fn test_continuity_preferred_falls_back_to_scattered() {
+        // Només hores parelles (la resta en blackout): no hi ha cap bloc de 2 hores seguides
+        let prices: Vec<HourlyPrice> = create_test_prices().into_iter().filter(|p| p.hour % 2 == 0).collect();
+
+        let required = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 2, 2));
+        assert!(required.hours.is_empty());
+
+        let preferred = calculate_optimal_hours(&prices, &SchedulerParams { continuity: Preferred, ..params(ActionType::TurnOn, 2, 2) });
+        assert_eq!(preferred.hours, vec![0, 2]);
+        assert!((preferred.total_price - (prices[0].price + prices[1].price)).abs() < 1e-9);
+
+        // Si hi ha blocs, Preferred es comporta com Required
+        let prices = create_test_prices();
+        let preferred = calculate_optimal_hours(&prices, &SchedulerParams { continuity: Preferred, ..params(ActionType::TurnOn, 4, 2) });
+        let required = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 4, 2));
+        assert_eq!(preferred.hours, required.hours);
+    }
+
+    /// Amb 2 kW, les hores de la matinada costen ~0,10 € cadascuna (0,050, 0,051, 0,052... €/kWh)
+    #[rstest]
+    #[case::within_budget(1.0, 3, vec![0, 1, 2])]
+    #[case::budget_limits_hours(0.25, 4, vec![0, 1])]
+    #[case::single_hour(0.1005, 4, vec![0])]
+    #[case::nothing_fits(0.05, 4, vec![])]
+    fn test_hours_within_budget(#[case] max_cost_eur: f64, #[case] max_hours: i32, #[case] expected: Vec<u8>) {
+        let prices = create_test_prices();
+        let result = calculate_optimal_hours(
+            &prices,
+            &SchedulerParams {
+                max_cost_eur: Some(max_cost_eur),
+                wattage_watts: Some(2000.0),
+                ..params(ActionType::TurnOn, max_hours, 1)
+            },
+        );
+
+        assert_eq!(result.hours, expected);
+        let expected_price: f64 = expected.iter().map(|&h| prices[h as usize].price).sum();
+        assert!((result.total_price - expected_price).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_budget_drops_whole_blocks() {
+        // Dos blocs de 2 hores: 02-04 (0,11 €) i 10-12 (0,14 €) amb 1 kW
+        let prices: Vec<HourlyPrice> = (0..24)
+            .map(|hour| {
+                let price = match hour {
+                    2 => 0.05,
+                    3 => 0.06,
+                    10 | 11 => 0.07,
+                    _ => 0.50,
+                };
+                HourlyPrice { hour, price, raw_price: None }
+            })
+            .collect();
+        let budget = |max_cost_eur| SchedulerParams {
+            max_cost_eur: Some(max_cost_eur),
+            wattage_watts: Some(1000.0),
+            ..params(ActionType::TurnOn, 4, 2)
+        };
+
+        assert_eq!(calculate_optimal_hours(&prices, &budget(1.0)).hours, vec![2, 3, 10, 11]);
+        // Hi cabrien 3 hores (0,18 €), però l'hora 10 sola no arriba al mínim de 2: es treu el bloc sencer
+        let result = calculate_optimal_hours(&prices, &budget(0.2));
+        assert_eq!(result.hours, vec![2, 3]);
+        assert!((result.total_price - 0.11).abs() < 1e-9);
+        assert!(calculate_optimal_hours(&prices, &budget(0.1)).hours.is_empty());
+    }
+
+    #[rstest]
+    #[case::turn_off(ActionType::TurnOff, Some(2000.0))]
+    #[case::unknown_wattage(ActionType::TurnOn, None)]
+    fn test_budget_not_applied(#[case] action_type: ActionType, #[case] wattage_watts: Option<f64>) {
+        let result = calculate_optimal_hours(
+            &create_test_prices(),
+            &SchedulerParams { max_cost_eur: Some(0.01), wattage_watts, ..params(action_type, 3, 1) },
+        );
+        assert_eq!(result.hours.len(), 3);
+    }
+
+    #[test]
+    fn test_nan_prices_are_most_expensive() {
+        let mut prices = create_test_prices();
+        prices[0].price = f64::NAN;
+        prices[1].price = f64::NAN;
+
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 3, 1));
+        assert_eq!(result.hours, vec![2, 3, 4]);
+        assert!(result.total_price.is_finite());
+
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 2, 2));
+        assert_eq!(result.hours, vec![2, 3]);
+    }
+
+    #[rstest]
+    #[case::day((8, 0), (20, 0), 12)]
+    #[case::crosses_midnight((22, 0), (6, 0), 8)]
+    #[case::partial_hours((10, 30), (12, 15), 2)]
+    #[case::less_than_an_hour((10, 0), (10, 30), 0)]
+    fn test_time_window_hours(#[case] start: (u32, u32), #[case] end: (u32, u32), #[case] expected: u32) {
+        let start = NaiveTime::from_hms_opt(start.0, start.1, 0);
+        let end = NaiveTime::from_hms_opt(end.0, end.1, 0);
+        assert_eq!(time_window_hours(start, end), expected);
+        assert_eq!(filter_by_time_window(&create_test_prices(), start, end, 60).len() as u32, expected);
+    }
+
     #[test]
     fn test_time_window_night() {
         let prices = create_test_prices();
         let start = NaiveTime::from_hms_opt(20, 0, 0).unwrap();
         let end = NaiveTime::from_hms_opt(9, 0, 0).unwrap();
 
-        let result = calculate_optimal_hours(&prices, 4, 1, Some(start), Some(end));
+        let result = calculate_optimal_hours(
+            &prices,
+            &SchedulerParams {
+                time_window_start: Some(start),
+                time_window_end: Some(end),
+                ..params(ActionType::TurnOn, 4, 1)
+            },
+        );
 
         assert_eq!(result.hours.len(), 4);
         // Totes les hores haurien de ser entre 20:00-09:00
@@ -222,10 +1426,80 @@ mod tests {
         }
     }
 
+    /// Quarts d'hora a 0.20 €/kWh, excepte els de 03:15 a 04:15 (0.05) i el de 10:00 (0.01)
+    fn quarter_hour_day() -> DailyPrices {
+        DailyPrices {
+            date: "2030-01-01".parse().unwrap(),
+            prices: vec![],
+            quarter_hours: (0..96u16)
+                .map(|q| PricePoint {
+                    minute_of_day: q * 15,
+                    price: match q {
+                        13..=16 => 0.05,
+                        40 => 0.01,
+                        _ => 0.20,
+                    },
+                    raw_price: None,
+                })
+                .collect(),
+        }
+    }
+
+    #[rstest]
+    #[case(0, (1, 0), false)]
+    #[case(22, (23, 0), false)]
+    #[case(23, (0, 0), true)]
+    fn test_slot_end_time_hourly(#[case] hour: u8, #[case] end: (u32, u32), #[case] crosses_midnight: bool) {
+        let expected = NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap();
+        assert_eq!(slot_end_time(hour, 60), (expected, crosses_midnight));
+    }
+
+    #[test]
+    fn test_quarter_hour_slots() {
+        let slots = PriceSlots::from_day(&quarter_hour_day());
+        assert_eq!((slots.slot_minutes, slots.prices.len()), (15, 96));
+        assert_eq!(slots.start_time(13), NaiveTime::from_hms_opt(3, 15, 0).unwrap());
+        assert_eq!(slots.end_time(13), (NaiveTime::from_hms_opt(3, 30, 0).unwrap(), false));
+        assert_eq!(slots.end_time(95), (NaiveTime::MIN, true));
+        let quarters = |max_hours, min_continuous_hours| SchedulerParams {
+            slot_minutes: 15,
+            ..params(ActionType::TurnOn, max_hours, min_continuous_hours)
+        };
+
+        // Una hora són els quatre quarts més barats, encara que no siguin seguits
+        let result = calculate_optimal_hours(&slots.prices, &quarters(1, 1));
+        assert_eq!(result.hours, vec![13, 14, 15, 40]);
+
+        // Dues hores contínues són vuit quarts seguits, que inclouen el tram barat
+        let result = calculate_optimal_hours(&slots.prices, &quarters(2, 2));
+        assert_eq!(result.hours.len(), 8);
+        assert!(result.hours.windows(2).all(|w| w[1] == w[0] + 1));
+        assert!((13..=16).all(|q| result.hours.contains(&q)));
+
+        // Finestres i blackouts es comparen amb cada quart
+        let at = |h, m| NaiveTime::from_hms_opt(h, m, 0).unwrap();
+        let blackout = BlackoutWindow { start: at(10, 0), end: at(10, 15) };
+        let available = apply_blackout_windows(&slots.prices, &[blackout], 15);
+        let windowed = SchedulerParams {
+            time_window_start: Some(at(10, 0)),
+            time_window_end: Some(at(11, 0)),
+            ..quarters(1, 1)
+        };
+        assert_eq!(calculate_optimal_hours(&available, &windowed).hours, vec![41, 42, 43]);
+
+        // El cost de cada quart és el d'un quart d'hora de consum: 1 kW a 0.05 €/kWh són 0.0125 €
+        let budgeted = SchedulerParams {
+            max_cost_eur: Some(0.03),
+            wattage_watts: Some(1000.0),
+            ..quarters(1, 1)
+        };
+        assert_eq!(calculate_optimal_hours(&slots.prices, &budgeted).hours, vec![13, 14, 40]);
+    }
+
     #[test]
     fn test_continuous_blocks() {
         let prices = create_test_prices();
-        let result = calculate_optimal_hours(&prices, 4, 2, None, None);
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 4, 2));
 
         // Hauria de retornar 2 blocs de 2 hores
         assert!(result.hours.len() <= 4);
@@ -249,4 +1523,585 @@ mod tests {
         // Cada bloc hauria de tenir almenys 2 hores
         println!("Blocs: {}, Hores: {:?}", blocks, sorted);
     }
+
+    #[test]
+    fn test_turn_on_picks_cheapest_hours() {
+        let prices = create_test_prices();
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 3, 1));
+
+        assert_eq!(result.hours, vec![0, 1, 2]);
+        let expected: f64 = prices[0..3].iter().map(|p| p.price).sum();
+        assert!((result.total_price - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_turn_off_picks_most_expensive_hours() {
+        let prices = create_test_prices();
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOff, 3, 1));
+
+        // Les hores més cares són les de 18 a 20 (0.25 - hora * 0.002)
+        assert_eq!(result.hours, vec![18, 19, 20]);
+        // El preu total ha de ser positiu (preus reals, no invertits)
+        let expected: f64 = prices[18..21].iter().map(|p| p.price).sum();
+        assert!((result.total_price - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_turn_off_continuous_block() {
+        let prices = create_test_prices();
+        let result = calculate_optimal_hours(&prices, &params(ActionType::TurnOff, 2, 2));
+
+        assert_eq!(result.hours, vec![18, 19]);
+        assert!(result.total_price > 0.0);
+    }
+
+    #[test]
+    fn test_load_profile_shifts_selection() {
+        let prices = create_test_prices();
+        let unweighted = calculate_optimal_hours(&prices, &params(ActionType::TurnOn, 4, 1));
+        assert_eq!(unweighted.hours, vec![0, 1, 2, 3]);
+
+        // Consum alt a la matinada (arrencada en fred) i baix al vespre
+        let mut profile = [1.0; 24];
+        profile[0..6].fill(3.0);
+        profile[22..24].fill(0.5);
+
+        let weighted = calculate_optimal_hours(
+            &prices,
+            &SchedulerParams { load_profile: Some(&profile), ..params(ActionType::TurnOn, 4, 1) },
+        );
+        assert_eq!(weighted.hours, vec![6, 7, 22, 23]);
+
+        // El preu total és el real, sense ponderar
+        let expected: f64 = [6, 7, 22, 23].iter().map(|&h| prices[h].price).sum();
+        assert!((weighted.total_price - expected).abs() < 1e-9);
+    }
+
+    fn turn_on_4h(prices: &[HourlyPrice]) -> OptimalHours {
+        calculate_optimal_hours(prices, &params(ActionType::TurnOn, 4, 1))
+    }
+
+    fn budget(start: u32, end: u32, hours: i32) -> SubBudget {
+        SubBudget {
+            start: NaiveTime::from_hms_opt(start, 0, 0).unwrap(),
+            end: NaiveTime::from_hms_opt(end, 0, 0).unwrap(),
+            hours,
+        }
+    }
+
+    #[test]
+    fn test_sub_budgets_in_disjoint_windows() {
+        let prices = create_test_prices();
+        // 2 hores al matí i 2 al vespre
+        let result = calculate_sub_budget_hours(
+            &prices,
+            &params(ActionType::TurnOn, 4, 1),
+            &[budget(6, 12, 2), budget(18, 0, 2)],
+        );
+
+        assert_eq!(result.hours, vec![6, 7, 22, 23]);
+        let expected: f64 = [6, 7, 22, 23].iter().map(|&h| prices[h].price).sum();
+        assert!((result.total_price - expected).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_sub_budgets_deduplicate_overlapping_hours() {
+        let prices = create_test_prices();
+        // Les dues finestres comparteixen 00:00-06:00: les hores repetides es compten una vegada
+        let result = calculate_sub_budget_hours(
+            &prices,
+            &params(ActionType::TurnOn, 5, 1),
+            &[budget(0, 6, 2), budget(0, 8, 3)],
+        );
+
+        assert_eq!(result.hours, vec![0, 1, 2]);
+        let expected: f64 = prices[0..3].iter().map(|p| p.price).sum();
+        assert!((result.total_price - expected).abs() < 1e-9);
+    }
+
+    fn test_rule(priority: i32, device_id: Uuid, age_minutes: i64) -> Rule {
+        let created_at = chrono::Utc::now() - chrono::Duration::minutes(age_minutes);
+        Rule {
+            id: Uuid::new_v4(),
+            device_id: Some(device_id),
+            device_group_id: None,
+            name: format!("regla p{}", priority),
+            max_hours: 3,
+            time_window_start: None,
+            time_window_end: None,
+            named_window_id: None,
+            min_continuous_hours: 1,
+            min_gap_hours: None,
+            negative_price_boost: None,
+            continuity_preference: Required,
+            mode: RuleMode::Optimize,
+            fixed_hours: None,
+            max_cost_eur: None,
+            wattage_watts: None,
+            days_of_week: 127,
+            is_enabled: true,
+            created_at,
+            updated_at: created_at,
+            baseline_days: None,
+            baseline_margin_pct: 0.0,
+            action_type: ActionType::TurnOn,
+            blackout_windows: Default::default(),
+            sub_budgets: Default::default(),
+            priority,
+            active_from: None,
+            active_until: None,
+            version: 1,
+            load_profile: None,
+            device_ids: vec![device_id],
+            max_simultaneous: None,
+        }
+    }
+
+    fn plan(rule: &Rule, hours: &[u8]) -> SchedulePlan {
+        SchedulePlan::new(rule.id, hours.to_vec())
+    }
+
+    fn hours_of(plans: &[SchedulePlan], rule: &Rule) -> Vec<u8> {
+        plans.iter().find(|p| p.rule_id == rule.id).unwrap().hours.clone()
+    }
+
+    fn action(rule_id: Uuid, device_id: Uuid, date: NaiveDate, hour: u32, status: &str) -> ScheduledAction {
+        let start_time = NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        ScheduledAction {
+            id: Uuid::new_v4(),
+            rule_id,
+            device_id,
+            scheduled_date: date,
+            start_time,
+            end_time: start_time + chrono::Duration::hours(1),
+            crosses_midnight: false,
+            price_per_kwh: None,
+            status: status.to_string(),
+            action: "on".to_string(),
+            executed_at: None,
+            created_at: chrono::Utc::now(),
+            actual_power_watts: None,
+            error_message: None,
+            client_executed_at: None,
+        }
+    }
+
+    #[test]
+    fn test_detect_conflicts_overlapping_rules() {
+        let date = NaiveDate::from_ymd_opt(2024, 1, 15).unwrap();
+        let (termo, cotxe) = (Uuid::new_v4(), Uuid::new_v4());
+        let (nit, matinada, carrega) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+
+        // Dues regles del termo: 00-03 i 01-05 se solapen de 01 a 03
+        let mut schedules: Vec<ScheduledAction> = (0..=3).map(|h| action(nit, termo, date, h, "pending")).collect();
+        schedules.extend((1..=5).map(|h| action(matinada, termo, date, h, "pending")));
+        // Un altre dispositiu a les mateixes hores no és cap conflicte
+        schedules.extend((1..=3).map(|h| action(carrega, cotxe, date, h, "pending")));
+        // Les accions que ja no es faran tampoc
+        schedules.push(action(carrega, termo, date, 5, "superseded"));
+
+        let conflicts = detect_conflicts(&schedules);
+        let mut rule_ids = vec![nit, matinada];
+        rule_ids.sort();
+        let expected = ScheduleConflict { device_id: termo, date, conflicting_hours: vec![1, 2, 3], rule_ids };
+        assert_eq!(conflicts, vec![expected]);
+
+        // Sense solapament, cap conflicte
+        let separate: Vec<ScheduledAction> =
+            schedules.into_iter().filter(|a| a.rule_id != matinada || a.start_time.hour() > 3).collect();
+        assert!(detect_conflicts(&separate).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_conflicts_three_overlapping_rules() {
+        let high = test_rule(1, Uuid::new_v4(), 0);
+        let mid = test_rule(2, Uuid::new_v4(), 0);
+        let low = test_rule(3, Uuid::new_v4(), 0);
+        let rules = vec![low.clone(), high.clone(), mid.clone()];
+
+        // Les tres regles volen les hores 2 i 3; la de menor prioritat perd les conflictives
+        let mut plans = vec![plan(&low, &[1, 2, 3]), plan(&mid, &[2, 3, 4]), plan(&high, &[2, 3, 5])];
+        resolve_conflicts(&rules, &mut plans, Some(2));
+
+        assert_eq!(hours_of(&plans, &high), vec![2, 3, 5]);
+        assert_eq!(hours_of(&plans, &mid), vec![2, 3, 4]);
+        assert_eq!(hours_of(&plans, &low), vec![1]);
+    }
+
+    #[test]
+    fn test_resolve_conflicts_same_device_and_no_limit() {
+        let device = Uuid::new_v4();
+        let first = test_rule(1, Uuid::new_v4(), 0);
+        let second = test_rule(1, device, 10);
+        let same_device = test_rule(2, device, 0);
+        let rules = vec![first.clone(), second.clone(), same_device.clone()];
+
+        let original = vec![plan(&first, &[0, 1]), plan(&second, &[0, 1]), plan(&same_device, &[0, 1])];
+
+        // Sense límit no es toca res
+        let mut plans = original.clone();
+        resolve_conflicts(&rules, &mut plans, None);
+        assert!(plans.iter().all(|p| p.hours == vec![0, 1]));
+
+        // El mateix dispositiu no suma càrrega
+        let mut plans = original;
+        resolve_conflicts(&rules, &mut plans, Some(2));
+        assert_eq!(hours_of(&plans, &first), vec![0, 1]);
+        assert_eq!(hours_of(&plans, &second), vec![0, 1]);
+        assert_eq!(hours_of(&plans, &same_device), vec![0, 1]);
+
+        // A igual prioritat guanya la regla més antiga
+        let mut plans = vec![plan(&first, &[0]), plan(&second, &[0])];
+        resolve_conflicts(&rules, &mut plans, Some(1));
+        assert_eq!(hours_of(&plans, &second), vec![0]);
+        assert!(hours_of(&plans, &first).is_empty());
+    }
+
+    #[test]
+    fn test_resolve_conflicts_group_counts_every_member() {
+        let single = test_rule(1, Uuid::new_v4(), 10);
+        let mut group = test_rule(2, Uuid::new_v4(), 5);
+        group.device_ids.push(Uuid::new_v4());
+        let rules = vec![single.clone(), group.clone()];
+
+        // El grup encén dos dispositius: amb límit 2 no hi cap al costat de l'altra regla
+        let mut plans = vec![plan(&single, &[0]), plan(&group, &[0, 1])];
+        resolve_conflicts(&rules, &mut plans, Some(2));
+        assert_eq!(hours_of(&plans, &single), vec![0]);
+        assert_eq!(hours_of(&plans, &group), vec![1]);
+    }
+
+    #[test]
+    fn test_stagger_group_hours_max_one_at_a_time() {
+        let slots = PriceSlots::hourly(&create_test_prices());
+        let mut group = test_rule(1, Uuid::new_v4(), 0);
+        group.device_ids.extend([Uuid::new_v4(), Uuid::new_v4()]);
+        group.max_simultaneous = Some(1);
+
+        let hours = optimal_hours_for_rule(&slots, &group).hours;
+        let mut plans = stagger_group_hours(&slots, &group, hours);
+
+        // Cada radiador té les seves 3 hores i cap franja es repeteix
+        assert_eq!(plans.len(), 3);
+        assert_eq!(plans[0].hours, vec![0, 1, 2]);
+        assert_eq!(plans[1].hours, vec![3, 4, 5]);
+        assert_eq!(plans[2].hours, vec![6, 22, 23]);
+        for (plan, device) in plans.iter().zip(&group.device_ids) {
+            assert_eq!(plan.device_ids, Some(vec![*device]));
+        }
+
+        // Sense solapaments, el límit d'usuari d'un dispositiu alhora no descarta res
+        resolve_conflicts(std::slice::from_ref(&group), &mut plans, Some(1));
+        assert_eq!(plans.iter().map(|p| p.hours.len()).sum::<usize>(), 9);
+
+        // Blocs continus: cada tanda té el seu bloc
+        group.min_continuous_hours = 3;
+        let hours = optimal_hours_for_rule(&slots, &group).hours;
+        let plans = stagger_group_hours(&slots, &group, hours);
+        let blocks: Vec<Vec<u8>> = plans.into_iter().map(|p| p.hours).collect();
+        assert_eq!(blocks, vec![vec![0, 1, 2], vec![3, 4, 5], vec![21, 22, 23]]);
+    }
+
+    #[test]
+    fn test_stagger_group_hours_quarter_hours() {
+        // Cinc hores de preus quart-horaris, cada quart més car que l'anterior
+        let prices: Vec<HourlyPrice> =
+            (0..20).map(|hour| HourlyPrice { hour, price: 0.1 + f64::from(hour) / 100.0, raw_price: None }).collect();
+        let slots = PriceSlots { prices, slot_minutes: 15 };
+        let mut group = test_rule(1, Uuid::new_v4(), 0);
+        group.device_ids.extend([Uuid::new_v4(), Uuid::new_v4()]);
+        group.max_simultaneous = Some(1);
+        group.max_hours = 2;
+
+        // Cada tanda vol 2 hores (8 quarts); l'última només en troba 4 lliures
+        assert_eq!(wave_slots(&slots, &group), 8);
+        let hours = optimal_hours_for_rule(&slots, &group).hours;
+        let plans = stagger_group_hours(&slots, &group, hours);
+        let sizes: Vec<usize> = plans.iter().map(|p| p.hours.len()).collect();
+        assert_eq!(sizes, vec![8, 8, 4]);
+        assert_eq!(plans[2].hours, vec![16, 17, 18, 19]);
+    }
+
+    #[test]
+    fn test_stagger_group_hours_not_needed() {
+        let slots = PriceSlots::hourly(&create_test_prices());
+        let mut group = test_rule(1, Uuid::new_v4(), 0);
+        group.device_ids.extend([Uuid::new_v4(), Uuid::new_v4()]);
+
+        // Sense límit, o amb un límit que el grup no supera, un sol pla per tots
+        for max_simultaneous in [None, Some(3)] {
+            group.max_simultaneous = max_simultaneous;
+            let plans = stagger_group_hours(&slots, &group, vec![0, 1, 2]);
+            assert_eq!(plans.len(), 1);
+            assert_eq!(plans[0].device_ids, None);
+        }
+
+        // Amb límit 2, dues tandes: dos dispositius i un
+        group.max_simultaneous = Some(2);
+        let plans = stagger_group_hours(&slots, &group, vec![0, 1, 2]);
+        let sizes: Vec<usize> = plans.iter().map(|p| p.device_ids.as_ref().unwrap().len()).collect();
+        assert_eq!(sizes, vec![2, 1]);
+
+        // Apagar no suma càrrega
+        group.action_type = ActionType::TurnOff;
+        assert_eq!(stagger_group_hours(&slots, &group, vec![18, 19, 20]).len(), 1);
+    }
+
+    #[test]
+    fn test_fixed_rule_schedules_configured_hours() {
+        let slots = PriceSlots::hourly(&create_test_prices());
+        let mut rule = test_rule(1, Uuid::new_v4(), 0);
+        rule.mode = RuleMode::Fixed;
+        rule.fixed_hours = Some(vec![15, 14]);
+        rule.max_hours = 1;
+
+        // Les hores cares de la tarda, encara que n'hi hagi de més barates i superin max_hours
+        let result = optimal_hours_for_rule(&slots, &rule);
+        assert_eq!(result.hours, vec![14, 15]);
+        let expected = slots.prices[14].price + slots.prices[15].price;
+        assert!((result.total_price - expected).abs() < 1e-9);
+
+        // Sense hores configurades, tota la finestra horària
+        rule.fixed_hours = None;
+        rule.time_window_start = NaiveTime::from_hms_opt(14, 0, 0);
+        rule.time_window_end = NaiveTime::from_hms_opt(17, 0, 0);
+        assert_eq!(optimal_hours_for_rule(&slots, &rule).hours, vec![14, 15, 16]);
+
+        // Les franges de blackout es respecten
+        rule.blackout_windows.0 = vec![window(15, 16)];
+        assert_eq!(optimal_hours_for_rule(&slots, &rule).hours, vec![14, 16]);
+    }
+
+    #[test]
+    fn test_fixed_rule_quarter_hours() {
+        let prices: Vec<HourlyPrice> = (0..96).map(|hour| HourlyPrice { hour, price: 0.1, raw_price: None }).collect();
+        let slots = PriceSlots { prices, slot_minutes: 15 };
+        let mut rule = test_rule(1, Uuid::new_v4(), 0);
+        rule.mode = RuleMode::Fixed;
+        rule.fixed_hours = Some(vec![14]);
+
+        // Els quatre quarts d'hora de les 14:00
+        assert_eq!(optimal_hours_for_rule(&slots, &rule).hours, vec![56, 57, 58, 59]);
+    }
+
+    fn shifted_day(date: &str, delta: f64) -> DailyPrices {
+        DailyPrices {
+            date: date.parse().unwrap(),
+            prices: create_test_prices()
+                .into_iter()
+                .map(|p| HourlyPrice { hour: p.hour, price: p.price + delta, raw_price: None })
+                .collect(),
+            quarter_hours: vec![],
+        }
+    }
+
+    #[test]
+    fn test_baseline_day_below() {
+        let history = vec![shifted_day("2024-01-14", 0.02), shifted_day("2024-01-15", 0.04)];
+        let baseline = calculate_baseline_price(&history, turn_on_4h);
+        assert!(baseline.is_some());
+
+        // Avui és més barat que els dies anteriors: s'ha de programar
+        let today = turn_on_4h(&create_test_prices());
+        assert!(is_cheaper_than_baseline(&today, baseline, 0.0));
+        // Però no si demanem un estalvi mínim més gran que la diferència
+        assert!(!is_cheaper_than_baseline(&today, baseline, 90.0));
+    }
+
+    #[test]
+    fn test_baseline_day_above() {
+        let history = vec![shifted_day("2024-01-14", -0.01), shifted_day("2024-01-15", -0.02)];
+        let baseline = calculate_baseline_price(&history, turn_on_4h);
+
+        // Avui és més car que els dies anteriors: no s'ha de programar
+        let today = turn_on_4h(&create_test_prices());
+        assert!(!is_cheaper_than_baseline(&today, baseline, 0.0));
+    }
+
+    #[test]
+    fn test_baseline_without_history() {
+        let baseline = calculate_baseline_price(&[], turn_on_4h);
+        assert_eq!(baseline, None);
+
+        // Sense historial no es bloqueja res
+        let today = turn_on_4h(&create_test_prices());
+        assert!(is_cheaper_than_baseline(&today, baseline, 10.0));
+    }
+
+    fn reasons(decisions: &[HourDecision]) -> Vec<(u8, HourReason)> {
+        decisions.iter().map(|d| (d.hour, d.reason)).collect()
+    }
+
+    #[test]
+    fn test_explain_scattered_hours() {
+        let prices = create_test_prices();
+        // Finestra 00:00-12:00 amb la primera hora en blackout
+        let (optimal, decisions) = explain_optimal_hours(
+            &prices,
+            &SchedulerParams {
+                time_window_start: NaiveTime::from_hms_opt(0, 0, 0),
+                time_window_end: NaiveTime::from_hms_opt(12, 0, 0),
+                ..params(ActionType::TurnOn, 3, 1)
+            },
+            &[window(0, 1)],
+        );
+
+        assert_eq!(optimal.hours, vec![1, 2, 3]);
+        assert_eq!(decisions.len(), 24);
+        for (hour, reason) in reasons(&decisions) {
+            let expected = match hour {
+                0 | 12..=23 => HourReason::ExcludedWindow,
+                1..=3 => HourReason::SelectedCheap,
+                // Per sota de la mitjana de la finestra (~0.105), però ja hi ha 3 hores
+                4 | 5 => HourReason::OverBudget,
+                _ => HourReason::ExcludedExpensive,
+            };
+            assert_eq!(reason, expected, "hora {}", hour);
+        }
+        assert!(decisions.iter().all(|d| d.selected == (d.reason == HourReason::SelectedCheap)));
+        assert!((decisions[1].price - 0.051).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_explain_continuous_blocks() {
+        // L'hora 1 és la més barata però està aïllada entre hores cares
+        let prices: Vec<HourlyPrice> = [0.20, 0.01, 0.20, 0.05, 0.05, 0.05, 0.20, 0.30, 0.30, 0.10]
+            .iter()
+            .enumerate()
+            .map(|(hour, &price)| HourlyPrice { hour: hour as u8, price, raw_price: None })
+            .collect();
+
+        let (optimal, decisions) =
+            explain_optimal_hours(&prices, &params(ActionType::TurnOn, 3, 3), &[]);
+
+        assert_eq!(optimal.hours, vec![3, 4, 5]);
+        assert_eq!(
+            reasons(&decisions),
+            vec![
+                (0, HourReason::ExcludedExpensive),
+                (1, HourReason::NotInBlock),
+                (2, HourReason::ExcludedExpensive),
+                (3, HourReason::SelectedCheap),
+                (4, HourReason::SelectedCheap),
+                (5, HourReason::SelectedCheap),
+                (6, HourReason::ExcludedExpensive),
+                (7, HourReason::ExcludedExpensive),
+                (8, HourReason::ExcludedExpensive),
+                (9, HourReason::OverBudget),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explain_turn_off_inverts_reasons() {
+        let prices = create_test_prices();
+        let (optimal, decisions) =
+            explain_optimal_hours(&prices, &params(ActionType::TurnOff, 2, 1), &[]);
+
+        // Per apagar es trien les més cares, i les barates queden descartades com a "cares"
+        assert_eq!(optimal.hours, vec![18, 19]);
+        assert_eq!(decisions[18].reason, HourReason::SelectedCheap);
+        assert_eq!(decisions[0].reason, HourReason::ExcludedExpensive);
+        assert_eq!(decisions[20].reason, HourReason::OverBudget);
+    }
+
+    #[test]
+    fn test_hour_reason_serialization() {
+        assert_eq!(serde_json::to_value(HourReason::SelectedCheap).unwrap(), "selected-cheap");
+        assert_eq!(serde_json::to_value(HourReason::NotInBlock).unwrap(), "not-in-block");
+        assert_eq!(serde_json::to_value(HourReason::OverBudget).unwrap(), "over-budget");
+    }
+
+    /// Preus d'una setmana a partir del 2024-03-04 (dilluns): 0,10 €/kWh més 0,001 per hora,
+    /// amb els preus de `overrides` (dia, hora, preu)
+    fn week(overrides: &[(usize, u8, f64)]) -> Vec<(NaiveDate, Vec<HourlyPrice>)> {
+        let monday = NaiveDate::from_ymd_opt(2024, 3, 4).unwrap();
+        (0..7)
+            .map(|day| {
+                let prices = (0..24)
+                    .map(|hour| {
+                        let price = overrides
+                            .iter()
+                            .find(|(d, h, _)| *d == day && *h == hour)
+                            .map_or(0.10 + hour as f64 * 0.001, |(_, _, price)| *price);
+                        HourlyPrice { hour, price, raw_price: None }
+                    })
+                    .collect();
+                (monday + chrono::Duration::days(day as i64), prices)
+            })
+            .collect()
+    }
+
+    fn plan_week(week: &[(NaiveDate, Vec<HourlyPrice>)], rule: &Rule) -> Vec<Vec<u8>> {
+        let prices: Vec<(NaiveDate, &[HourlyPrice])> =
+            week.iter().map(|(date, prices)| (*date, prices.as_slice())).collect();
+        let plan = calculate_weekly_optimal(&prices, rule);
+        assert_eq!(plan.len(), 7);
+        week.iter().map(|(date, _)| plan[date].hours.clone()).collect()
+    }
+
+    #[test]
+    fn test_weekly_plan_concentrates_hours_on_cheapest_day() {
+        // El dimecres té tres hores molt barates: les 3 hores de la setmana hi van a parar
+        let week = week(&[(2, 2, 0.01), (2, 3, 0.01), (2, 4, 0.01), (5, 6, 0.02)]);
+        let rule = test_rule(1, Uuid::new_v4(), 0);
+
+        let plan = plan_week(&week, &rule);
+        assert_eq!(plan[2], vec![2, 3, 4]);
+        assert_eq!(plan.iter().map(Vec::len).sum::<usize>(), 3);
+
+        let prices: Vec<(NaiveDate, &[HourlyPrice])> = week.iter().map(|(d, p)| (*d, p.as_slice())).collect();
+        let wednesday = &calculate_weekly_optimal(&prices, &rule)[&week[2].0];
+        assert!((wednesday.total_price - 0.03).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_weekly_hours_scale_with_active_days() {
+        // 5 hores els 5 dies laborables són 7 hores en tota la setmana
+        let rule = Rule { max_hours: 5, days_of_week: 0b0011111, ..test_rule(1, Uuid::new_v4(), 0) };
+        assert_eq!(weekly_hours(&rule), 7);
+        assert_eq!(weekly_hours(&test_rule(1, Uuid::new_v4(), 0)), 3);
+
+        // Les 7 hores només van als dies laborables: les 00:00 de cada dia i, després, les 01:00
+        // dels primers dies (a igual preu guanya el dia més primerenc)
+        let plan = plan_week(&week(&[]), &rule);
+        assert_eq!(plan, vec![vec![0, 1], vec![0, 1], vec![0], vec![0], vec![0], vec![], vec![]]);
+
+        // Fora de temporada tampoc: la regla s'acaba el dimecres
+        let week = week(&[]);
+        let rule = Rule { active_until: Some(week[2].0), ..rule };
+        let plan = plan_week(&week, &rule);
+        assert_eq!(plan.iter().map(Vec::len).sum::<usize>(), 7);
+        assert!(plan[3..].iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn test_weekly_plan_respects_window_and_continuity() {
+        // L'hora més barata de la setmana (dijous 12:00) és fora de la finestra 00:00-06:00
+        let week = week(&[(1, 0, 0.02), (1, 1, 0.02), (4, 3, 0.03), (4, 4, 0.03), (3, 12, 0.001), (6, 5, 0.01)]);
+        let rule = Rule {
+            max_hours: 4,
+            min_continuous_hours: 2,
+            time_window_start: Some(NaiveTime::from_hms_opt(0, 0, 0).unwrap()),
+            time_window_end: Some(NaiveTime::from_hms_opt(6, 0, 0).unwrap()),
+            ..test_rule(1, Uuid::new_v4(), 0)
+        };
+
+        // L'hora barata del diumenge (05:00) no forma cap bloc de 2 hores tan barat
+        let plan = plan_week(&week, &rule);
+        assert_eq!(plan[1], vec![0, 1]);
+        assert_eq!(plan[4], vec![3, 4]);
+        assert!(plan[3].is_empty());
+        assert!(plan[6].is_empty());
+    }
+
+    #[test]
+    fn test_weekly_plan_turn_off_picks_most_expensive() {
+        let week = week(&[(3, 19, 0.90), (3, 20, 0.80)]);
+        let rule = Rule { max_hours: 2, action_type: ActionType::TurnOff, ..test_rule(1, Uuid::new_v4(), 0) };
+
+        let plan = plan_week(&week, &rule);
+        assert_eq!(plan[3], vec![19, 20]);
+        assert_eq!(plan.iter().map(Vec::len).sum::<usize>(), 2);
+    }
 }
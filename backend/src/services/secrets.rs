@@ -0,0 +1,163 @@
+//! Xifratge dels secrets dels usuaris desats a la BD (p. ex. el token de ESIOS)
+//!
+//! AES-256-GCM amb una clau derivada (SHA-256) de `ENCRYPTION_KEY`. Cada valor xifrat
+//! és el nonce aleatori (12 bytes) seguit del text xifrat amb l'etiqueta d'autenticació.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::Aes256Gcm;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::config::Config;
+use crate::db::models::User;
+use crate::services::energy_tariff::PriceAdjustment;
+use crate::services::pvpc::PvpcClient;
+
+/// Mida del nonce d'AES-GCM
+const NONCE_LEN: usize = 12;
+
+/// Longitud mínima de `ENCRYPTION_KEY`
+pub const MIN_KEY_LEN: usize = 32;
+
+pub struct SecretBox {
+    cipher: Aes256Gcm,
+}
+
+impl SecretBox {
+    pub fn new(key: &str) -> Self {
+        let key = Sha256::digest(key.as_bytes());
+        Self {
+            cipher: Aes256Gcm::new(&key),
+        }
+    }
+
+    /// Caixa amb la clau configurada, si n'hi ha
+    pub fn from_config(config: &Config) -> Option<Self> {
+        config.encryption_key.as_deref().map(Self::new)
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> Vec<u8> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("AES-GCM encryption cannot fail for in-memory buffers");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        sealed
+    }
+
+    /// None si el valor no s'ha xifrat amb aquesta clau o s'ha modificat
+    pub fn decrypt(&self, sealed: &[u8]) -> Option<String> {
+        if sealed.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = self.cipher.decrypt(nonce.into(), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+/// Comprova en arrencar que els secrets desats es poden desxifrar: si n'hi ha, cal
+/// `ENCRYPTION_KEY` i ha de ser la mateixa amb què es van xifrar
+pub async fn check_stored_secrets(pool: &PgPool, config: &Config) -> Result<(), String> {
+    let sample: Option<Vec<u8>> =
+        sqlx::query_scalar("SELECT esios_token FROM users WHERE esios_token IS NOT NULL LIMIT 1")
+            .fetch_optional(pool)
+            .await
+            .map_err(|e| format!("Error checking stored secrets: {}", e))?;
+
+    let Some(sample) = sample else {
+        return Ok(());
+    };
+
+    let secrets = SecretBox::from_config(config)
+        .ok_or("ENCRYPTION_KEY is required: there are encrypted user tokens stored")?;
+    if secrets.decrypt(&sample).is_none() {
+        return Err("ENCRYPTION_KEY does not match the key used to encrypt the stored user tokens".to_string());
+    }
+
+    Ok(())
+}
+
+/// Client de ESIOS per les peticions d'un usuari: amb el seu token si en té un (si no, amb
+/// el global) i amb els preus ajustats amb els seus impostos i càrrecs
+pub fn pvpc_client_for_user(pvpc: &PvpcClient, config: &Config, user: &User) -> PvpcClient {
+    let pvpc = pvpc.clone().with_price_adjustment(PriceAdjustment::for_user(user));
+    let Some(sealed) = user.esios_token.as_deref() else {
+        return pvpc;
+    };
+
+    pvpc.with_user_token(open_esios_token(config, user.id, sealed))
+}
+
+/// Token de ESIOS desxifrat d'un usuari; None (i un avís) si no es pot desxifrar, perquè
+/// s'usi el global
+pub fn open_esios_token(config: &Config, user_id: Uuid, sealed: &[u8]) -> Option<String> {
+    let token = SecretBox::from_config(config).and_then(|secrets| secrets.decrypt(sealed));
+    if token.is_none() {
+        tracing::warn!(
+            "No s'ha pogut desxifrar el token de ESIOS de l'usuari {}. S'usa el token global.",
+            user_id
+        );
+    }
+    token
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_and_wrong_key() {
+        let secrets = SecretBox::new("clau");
+        let sealed = secrets.encrypt("token-esios");
+
+        assert!(!sealed.windows(b"token-esios".len()).any(|w| w == b"token-esios"));
+        assert_eq!(secrets.decrypt(&sealed).as_deref(), Some("token-esios"));
+        // El nonce és aleatori: el mateix valor es xifra diferent cada vegada
+        assert_ne!(secrets.encrypt("token-esios"), sealed);
+
+        assert_eq!(SecretBox::new("una altra").decrypt(&sealed), None);
+        assert_eq!(secrets.decrypt(&sealed[..NONCE_LEN - 1]), None);
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(secrets.decrypt(&tampered), None);
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_startup_check_requires_matching_key(pool: PgPool) {
+        use crate::test_utils::{create_user, test_config};
+
+        let config = test_config();
+        // Sense tokens desats, la clau no és necessària
+        let without_key = Config { encryption_key: None, ..test_config() };
+        assert!(check_stored_secrets(&pool, &without_key).await.is_ok());
+
+        let user = create_user(&pool, "secrets").await;
+        let sealed = SecretBox::from_config(&config).unwrap().encrypt("token-esios");
+        sqlx::query("UPDATE users SET esios_token = $1 WHERE id = $2")
+            .bind(&sealed)
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        // A la BD només hi ha el text xifrat
+        let stored: Vec<u8> = sqlx::query_scalar("SELECT esios_token FROM users WHERE id = $1")
+            .bind(user.id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&stored).contains("token-esios"));
+
+        assert!(check_stored_secrets(&pool, &config).await.is_ok());
+        assert!(check_stored_secrets(&pool, &without_key).await.is_err());
+        let other_key = Config { encryption_key: Some("x".repeat(MIN_KEY_LEN)), ..test_config() };
+        assert!(check_stored_secrets(&pool, &other_key).await.is_err());
+    }
+}
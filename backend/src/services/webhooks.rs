@@ -0,0 +1,392 @@
+use chrono::{NaiveDate, Utc};
+use hmac::{Hmac, Mac};
+use reqwest::Client;
+use serde::Serialize;
+use sha2::Sha256;
+use sqlx::PgPool;
+use tokio::time::Duration;
+use uuid::Uuid;
+
+use crate::db::models::{ScheduledActionWithDevice, Webhook};
+
+/// Nombre màxim d'intents d'enviament per webhook
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Espera entre intents (es multiplica pel número d'intent)
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Temps màxim d'espera de la resposta del receptor
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Capçalera amb la signatura HMAC-SHA256 del cos
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// Esdeveniments als quals es pot subscriure un webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebhookEvent {
+    /// S'han generat accions programades per una data
+    ScheduleGenerated,
+    /// Una acció programada ha canviat d'estat
+    StatusChanged,
+}
+
+impl WebhookEvent {
+    pub const ALL: [WebhookEvent; 2] = [Self::ScheduleGenerated, Self::StatusChanged];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ScheduleGenerated => "schedule.generated",
+            Self::StatusChanged => "schedule.status_changed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.as_str() == value)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a, T: Serialize> {
+    event: &'a str,
+    timestamp: chrono::DateTime<Utc>,
+    data: T,
+}
+
+#[derive(Debug, Serialize)]
+struct ScheduleGeneratedData {
+    date: NaiveDate,
+    actions: Vec<ScheduledActionWithDevice>,
+}
+
+/// Envia els esdeveniments als webhooks registrats pels usuaris
+#[derive(Clone)]
+pub struct WebhookDispatcher {
+    client: Client,
+}
+
+impl WebhookDispatcher {
+    pub fn new(client: Client) -> Self {
+        Self { client }
+    }
+
+    /// Notifica un canvi d'estat als webhooks de l'usuari (en segon pla)
+    pub fn status_changed<T: Serialize>(&self, pool: &PgPool, user_id: Uuid, action: &T) {
+        let data = match serde_json::to_value(action) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::error!("Error serialitzant l'acció pel webhook: {}", e);
+                return;
+            }
+        };
+
+        let dispatcher = self.clone();
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let webhooks = match subscribed_webhooks(&pool, Some(user_id), WebhookEvent::StatusChanged).await {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    tracing::error!("Error obtenint webhooks: {}", e);
+                    return;
+                }
+            };
+
+            for webhook in webhooks {
+                dispatcher.send(&webhook, WebhookEvent::StatusChanged, &data).await;
+            }
+        });
+    }
+
+    /// Notifica les accions generades per una data (en segon pla).
+    /// Amb `user_id` = None, es notifica a tots els usuaris subscrits.
+    pub fn schedule_generated(&self, pool: &PgPool, user_id: Option<Uuid>, date: NaiveDate) {
+        let dispatcher = self.clone();
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            if let Err(e) = dispatcher.notify_schedule_generated(&pool, user_id, date).await {
+                tracing::error!("Error notificant webhooks de generació ({}): {}", date, e);
+            }
+        });
+    }
+
+    async fn notify_schedule_generated(
+        &self,
+        pool: &PgPool,
+        user_id: Option<Uuid>,
+        date: NaiveDate,
+    ) -> Result<(), sqlx::Error> {
+        let webhooks = subscribed_webhooks(pool, user_id, WebhookEvent::ScheduleGenerated).await?;
+
+        for webhook in webhooks {
+            let actions = sqlx::query_as::<_, ScheduledActionWithDevice>(
+                r#"
+                SELECT
+                    sa.id, sa.rule_id, sa.scheduled_date, sa.start_time, sa.end_time, sa.status, sa.action,
+                    d.id as device_id, d.name as device_name, d.google_device_id
+                FROM scheduled_actions sa
+                JOIN devices d ON sa.device_id = d.id
+                WHERE d.user_id = $1 AND sa.scheduled_date = $2
+                ORDER BY sa.start_time
+                "#
+            )
+            .bind(webhook.user_id)
+            .bind(date)
+            .fetch_all(pool)
+            .await?;
+
+            if actions.is_empty() {
+                continue;
+            }
+
+            let data = ScheduleGeneratedData { date, actions };
+            self.send(&webhook, WebhookEvent::ScheduleGenerated, &data).await;
+        }
+
+        Ok(())
+    }
+
+    /// Envia un esdeveniment a un webhook, reintentant si falla
+    async fn send<T: Serialize>(&self, webhook: &Webhook, event: WebhookEvent, data: &T) {
+        let payload = WebhookPayload {
+            event: event.as_str(),
+            timestamp: Utc::now(),
+            data,
+        };
+
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!("Error serialitzant el payload del webhook: {}", e);
+                return;
+            }
+        };
+
+        if let Err(e) = self.deliver(&webhook.url, &webhook.secret, body).await {
+            tracing::warn!(
+                "Webhook {} ({}) ha fallat després de {} intents: {}",
+                webhook.id,
+                event.as_str(),
+                MAX_ATTEMPTS,
+                e
+            );
+        }
+    }
+
+    /// Fa el POST signat, amb fins a `MAX_ATTEMPTS` intents
+    async fn deliver(&self, url: &str, secret: &str, body: Vec<u8>) -> Result<(), String> {
+        let signature = sign_payload(secret, &body);
+        let mut last_error = String::new();
+
+        for attempt in 1..=MAX_ATTEMPTS {
+            let result = self
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .timeout(REQUEST_TIMEOUT)
+                .body(body.clone())
+                .send()
+                .await;
+
+            match result {
+                Ok(response) if response.status().is_success() => return Ok(()),
+                Ok(response) => last_error = format!("status {}", response.status()),
+                Err(e) => last_error = e.to_string(),
+            }
+
+            tracing::debug!("Intent {} del webhook {} fallit: {}", attempt, url, last_error);
+
+            if attempt < MAX_ATTEMPTS {
+                tokio::time::sleep(RETRY_DELAY * attempt).await;
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// Webhooks subscrits a un esdeveniment (de l'usuari, o de tots si és None)
+async fn subscribed_webhooks(
+    pool: &PgPool,
+    user_id: Option<Uuid>,
+    event: WebhookEvent,
+) -> Result<Vec<Webhook>, sqlx::Error> {
+    sqlx::query_as::<_, Webhook>(
+        r#"
+        SELECT * FROM webhooks
+        WHERE $1 = ANY(events) AND ($2::uuid IS NULL OR user_id = $2)
+        "#
+    )
+    .bind(event.as_str())
+    .bind(user_id)
+    .fetch_all(pool)
+    .await
+}
+
+/// Signatura HMAC-SHA256 del cos en format `sha256=<hex>`
+pub fn sign_payload(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepta claus de qualsevol longitud");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_parse_events() {
+        assert_eq!(WebhookEvent::parse("schedule.generated"), Some(WebhookEvent::ScheduleGenerated));
+        assert_eq!(WebhookEvent::parse("schedule.status_changed"), Some(WebhookEvent::StatusChanged));
+        assert_eq!(WebhookEvent::parse("unknown"), None);
+    }
+
+    #[test]
+    fn test_sign_payload() {
+        // Vector de prova de RFC 4231 (cas 2)
+        assert_eq!(
+            sign_payload("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_status_change_payload_is_signed() {
+        let (tx, mut rx) = mpsc::unbounded_channel::<(Option<String>, web::Bytes)>();
+
+        let server = HttpServer::new(move || {
+            let tx = tx.clone();
+            App::new().route(
+                "/hook",
+                web::post().to(move |req: HttpRequest, body: web::Bytes| {
+                    let signature = req
+                        .headers()
+                        .get(SIGNATURE_HEADER)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string);
+                    let _ = tx.send((signature, body));
+                    async { HttpResponse::Ok().finish() }
+                }),
+            )
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let webhook = Webhook {
+            id: Uuid::new_v4(),
+            user_id: Uuid::new_v4(),
+            url: format!("http://{}/hook", addr),
+            secret: "s3cret".to_string(),
+            events: vec![WebhookEvent::StatusChanged.as_str().to_string()],
+            created_at: Utc::now(),
+        };
+        let action_id = Uuid::new_v4();
+
+        let dispatcher = WebhookDispatcher::new(Client::new());
+        dispatcher
+            .send(
+                &webhook,
+                WebhookEvent::StatusChanged,
+                &serde_json::json!({ "id": action_id, "status": "executed" }),
+            )
+            .await;
+
+        let (signature, body) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(signature.as_deref(), Some(sign_payload("s3cret", &body).as_str()));
+
+        let payload: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(payload["event"], "schedule.status_changed");
+        assert_eq!(payload["data"]["id"], action_id.to_string());
+        assert_eq!(payload["data"]["status"], "executed");
+
+        handle.stop(false).await;
+    }
+
+    #[sqlx::test(migrations = "../migrations")]
+    #[ignore] // Necessita DATABASE_URL
+    async fn test_registered_webhook_receives_signed_status_change(pool: PgPool) {
+        use actix_web::test::{call_service, init_service, TestRequest};
+        use wiremock::matchers::{method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        use crate::services::schedule_events::ScheduleEvents;
+        use crate::test_utils::{auth_header, create_device, create_rule, create_user, test_config};
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+
+        let config = test_config();
+        let user = create_user(&pool, "hooks").await;
+        let device = create_device(&pool, user.id, "Termo").await;
+        let rule = create_rule(&pool, device.id, "Nit", true).await;
+        let action_id: Uuid = sqlx::query_scalar(
+            r#"
+            INSERT INTO scheduled_actions (rule_id, device_id, scheduled_date, start_time, end_time)
+            VALUES ($1, $2, '2030-01-01', '02:00', '03:00')
+            RETURNING id
+            "#
+        )
+        .bind(rule.id)
+        .bind(device.id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO webhooks (user_id, url, secret, events) VALUES ($1, $2, 's3cret', $3)")
+            .bind(user.id)
+            .bind(format!("{}/hook", server.uri()))
+            .bind(vec![WebhookEvent::StatusChanged.as_str()])
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::new(pool.clone()))
+                .app_data(web::Data::new(config.clone()))
+                .app_data(web::Data::new(ScheduleEvents::new()))
+                .app_data(web::Data::new(WebhookDispatcher::new(Client::new())))
+                .configure(crate::api::configure),
+        )
+        .await;
+        let req = TestRequest::patch()
+            .uri(&format!("/api/schedule/{}/status", action_id))
+            .insert_header(auth_header(&user, &config))
+            .set_json(serde_json::json!({ "status": "executed_on" }))
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), 200);
+
+        // L'enviament es fa en segon pla
+        let request = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Some(request) = server.received_requests().await.unwrap().pop() {
+                    return request;
+                }
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        let signature = request.headers.get(SIGNATURE_HEADER).and_then(|v| v.to_str().ok());
+        assert_eq!(signature, Some(sign_payload("s3cret", &request.body).as_str()));
+
+        let payload: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(payload["event"], "schedule.status_changed");
+        assert_eq!(payload["data"]["id"], action_id.to_string());
+        assert_eq!(payload["data"]["status"], "executed_on");
+    }
+}
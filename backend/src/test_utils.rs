@@ -0,0 +1,86 @@
+//! Utilitats per als tests d'integració amb base de dades
+//!
+//! Els tests que les fan servir estan marcats amb `#[ignore]` i necessiten `DATABASE_URL`:
+//! `DATABASE_URL=postgres://... cargo test -- --ignored`
+
+use actix_web::http::header;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::api::auth::generate_jwt;
+use crate::config::Config;
+use crate::db::models::{Device, Rule, User};
+use crate::services::clock::DEFAULT_TIMEZONE;
+use crate::services::energy_tariff::GeoZone;
+
+pub fn test_config() -> Config {
+    Config {
+        database_url: String::new(),
+        jwt_secret: "test-secret".to_string(),
+        jwt_access_ttl: 24 * 3600,
+        jwt_refresh_ttl: 30 * 24 * 3600,
+        google_client_id: "test-client-id".to_string(),
+        apple_client_ids: vec![],
+        server_host: "127.0.0.1".to_string(),
+        server_port: 0,
+        allowed_origins: vec![],
+        price_horizon_days: 1,
+        price_history_days: 365,
+        metrics_token: None,
+        default_max_hours: None,
+        default_min_continuous: 1,
+        admin_emails: vec![],
+        encryption_key: Some("test-encryption-key-0123456789abcdef".to_string()),
+        timezone: DEFAULT_TIMEZONE,
+        geo_zone: GeoZone::Peninsula,
+        schedule_generation_hour: 20,
+        schedule_generation_minute: 30,
+        smtp_host: None,
+        smtp_port: 587,
+        smtp_username: None,
+        smtp_password: None,
+        smtp_from: None,
+    }
+}
+
+/// Capçalera Authorization amb un JWT vàlid per l'usuari
+pub fn auth_header(user: &User, config: &Config) -> (header::HeaderName, String) {
+    let (token, _) = generate_jwt(user, config).unwrap();
+    (header::AUTHORIZATION, format!("Bearer {}", token))
+}
+
+pub async fn create_user(pool: &PgPool, name: &str) -> User {
+    sqlx::query_as::<_, User>(
+        "INSERT INTO users (provider, provider_subject, email, name) VALUES ('google', $1, $2, $3) RETURNING *"
+    )
+    .bind(Uuid::new_v4().to_string())
+    .bind(format!("{}@example.com", name))
+    .bind(name)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+pub async fn create_device(pool: &PgPool, user_id: Uuid, name: &str) -> Device {
+    sqlx::query_as::<_, Device>(
+        "INSERT INTO devices (user_id, google_device_id, name) VALUES ($1, $2, $3) RETURNING *"
+    )
+    .bind(user_id)
+    .bind(Uuid::new_v4().to_string())
+    .bind(name)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
+
+pub async fn create_rule(pool: &PgPool, device_id: Uuid, name: &str, is_enabled: bool) -> Rule {
+    sqlx::query_as::<_, Rule>(
+        "INSERT INTO rules (device_id, name, max_hours, is_enabled) VALUES ($1, $2, 2, $3) RETURNING *"
+    )
+    .bind(device_id)
+    .bind(name)
+    .bind(is_enabled)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+}
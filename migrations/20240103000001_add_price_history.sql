@@ -0,0 +1,17 @@
+-- Historial de preus PVPC obtinguts de ESIOS
+-- Serveix de referència per comparar el preu d'un dia amb els dies anteriors
+CREATE TABLE cached_prices (
+    price_date DATE NOT NULL,
+    hour SMALLINT NOT NULL CHECK (hour >= 0 AND hour <= 23),
+    price DOUBLE PRECISION NOT NULL,
+    fetched_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (price_date, hour)
+);
+
+-- Porta "només si és més barat que els darrers dies"
+-- baseline_days: nombre de dies anteriors per calcular la referència (NULL = desactivat)
+-- baseline_margin_pct: estalvi mínim (%) respecte la referència per programar
+ALTER TABLE rules
+ADD COLUMN baseline_days INTEGER CHECK (baseline_days >= 1 AND baseline_days <= 30),
+ADD COLUMN baseline_margin_pct DOUBLE PRECISION DEFAULT 0 NOT NULL
+    CHECK (baseline_margin_pct >= 0 AND baseline_margin_pct < 100);
@@ -0,0 +1,9 @@
+-- Acció que programa una regla: encendre a les hores barates o apagar a les cares
+ALTER TABLE rules
+ADD COLUMN action_type VARCHAR(20) DEFAULT 'turn_on' NOT NULL
+    CHECK (action_type IN ('turn_on', 'turn_off'));
+
+-- Acció a executar per cada scheduled_action ("on" o "off")
+ALTER TABLE scheduled_actions
+ADD COLUMN action VARCHAR(10) DEFAULT 'on' NOT NULL
+    CHECK (action IN ('on', 'off'));
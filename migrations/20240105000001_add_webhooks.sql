@@ -0,0 +1,13 @@
+-- Webhooks per notificar sistemes externs (ex: Home Assistant)
+CREATE TABLE webhooks (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    url TEXT NOT NULL,
+    -- Clau per signar els payloads (HMAC-SHA256)
+    secret TEXT NOT NULL,
+    -- Esdeveniments subscrits: 'schedule.generated', 'schedule.status_changed'
+    events TEXT[] NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX idx_webhooks_user_id ON webhooks(user_id);
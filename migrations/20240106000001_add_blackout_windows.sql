@@ -0,0 +1,4 @@
+-- Franges horàries en què una regla no ha de programar mai cap acció
+-- Format: [{"start": "13:00:00", "end": "15:00:00"}, ...]
+ALTER TABLE rules
+    ADD COLUMN blackout_windows JSONB DEFAULT '[]'::jsonb NOT NULL;
@@ -0,0 +1,4 @@
+-- Sub-pressupostos d'hores: cada element s'optimitza per separat dins la seva finestra
+-- Format: [{"start": "06:00:00", "end": "12:00:00", "hours": 2}, ...]
+ALTER TABLE rules
+    ADD COLUMN sub_budgets JSONB DEFAULT '[]'::jsonb NOT NULL;
@@ -0,0 +1,7 @@
+-- Prioritat de les regles (1 = màxima) per resoldre conflictes de càrrega
+ALTER TABLE rules
+    ADD COLUMN priority INTEGER DEFAULT 1 NOT NULL CHECK (priority >= 1);
+
+-- Màxim de dispositius encesos alhora per usuari (NULL = sense límit)
+ALTER TABLE users
+    ADD COLUMN max_concurrent_devices INTEGER CHECK (max_concurrent_devices >= 1);
@@ -0,0 +1,7 @@
+-- Perfil de consum per hora d'una regla (pesos relatius, un per cada hora del dia)
+-- El càlcul d'hores minimitza preu * pes en lloc del preu sol
+CREATE TABLE rule_load_profiles (
+    rule_id UUID PRIMARY KEY REFERENCES rules(id) ON DELETE CASCADE,
+    weights DOUBLE PRECISION[] NOT NULL CHECK (array_length(weights, 1) = 24),
+    updated_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
@@ -0,0 +1,19 @@
+-- Administradors (s'assignen manualment: UPDATE users SET is_admin = true WHERE email = '...')
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN DEFAULT false NOT NULL;
+
+-- Registre d'auditoria de les crides autenticades que modifiquen dades
+CREATE TABLE audit_log (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    -- Es manté el registre encara que l'usuari s'esborri
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL,
+    method VARCHAR(10) NOT NULL,
+    path TEXT NOT NULL,
+    -- Cos de la petició (fins a 8 KB, amb els secrets ocults)
+    request_body JSONB,
+    response_status SMALLINT NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX idx_audit_log_created_at ON audit_log(created_at DESC);
+CREATE INDEX idx_audit_log_user_id ON audit_log(user_id);
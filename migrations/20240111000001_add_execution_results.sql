@@ -0,0 +1,8 @@
+-- Resultat real de l'execució reportat per l'app Android
+ALTER TABLE scheduled_actions
+    -- Potència mesurada per l'endoll en executar l'acció
+    ADD COLUMN actual_power_watts DOUBLE PRECISION,
+    -- Motiu de l'error quan l'status és 'failed'
+    ADD COLUMN error_message TEXT,
+    -- Hora d'execució segons el client (executed_at és l'hora en què el servidor rep l'estat)
+    ADD COLUMN client_executed_at TIMESTAMPTZ;
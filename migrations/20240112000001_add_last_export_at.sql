@@ -0,0 +1,2 @@
+-- Última exportació de dades de l'usuari (RGPD), per limitar-ne una cada 24 hores
+ALTER TABLE users ADD COLUMN last_export_at TIMESTAMPTZ;
@@ -0,0 +1,53 @@
+-- Grups de dispositius: una regla pot programar igual tots els dispositius d'un grup
+-- (ex: dos termos idèntics)
+CREATE TABLE device_groups (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    name VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX idx_device_groups_user_id ON device_groups(user_id);
+
+CREATE TABLE device_group_members (
+    group_id UUID REFERENCES device_groups(id) ON DELETE CASCADE NOT NULL,
+    device_id UUID REFERENCES devices(id) ON DELETE CASCADE NOT NULL,
+    PRIMARY KEY (group_id, device_id)
+);
+
+CREATE INDEX idx_device_group_members_device_id ON device_group_members(device_id);
+
+-- Una regla és d'un dispositiu o d'un grup, mai de tots dos
+ALTER TABLE rules
+    ALTER COLUMN device_id DROP NOT NULL,
+    ADD COLUMN device_group_id UUID REFERENCES device_groups(id) ON DELETE CASCADE,
+    ADD CONSTRAINT rules_device_or_group CHECK ((device_id IS NULL) <> (device_group_id IS NULL));
+
+CREATE INDEX idx_rules_device_group_id ON rules(device_group_id);
+
+-- Cada acció programada és d'un dispositiu concret: una regla de grup en crea una per membre
+ALTER TABLE scheduled_actions ADD COLUMN device_id UUID REFERENCES devices(id) ON DELETE CASCADE;
+
+UPDATE scheduled_actions sa
+SET device_id = r.device_id
+FROM rules r
+WHERE sa.rule_id = r.id;
+
+ALTER TABLE scheduled_actions
+    ALTER COLUMN device_id SET NOT NULL,
+    DROP CONSTRAINT IF EXISTS scheduled_actions_rule_id_scheduled_date_start_time_key,
+    DROP CONSTRAINT IF EXISTS scheduled_actions_rule_date_time_unique,
+    ADD CONSTRAINT scheduled_actions_rule_device_date_time_unique
+        UNIQUE (rule_id, device_id, scheduled_date, start_time);
+
+CREATE INDEX idx_scheduled_actions_device_id ON scheduled_actions(device_id);
+
+-- Dispositius als quals s'apliquen les accions de cada regla
+CREATE VIEW rule_devices AS
+    SELECT id AS rule_id, device_id
+    FROM rules
+    WHERE device_id IS NOT NULL
+    UNION ALL
+    SELECT r.id AS rule_id, m.device_id
+    FROM rules r
+    JOIN device_group_members m ON m.group_id = r.device_group_id;
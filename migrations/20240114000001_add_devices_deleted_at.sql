@@ -0,0 +1,17 @@
+-- Esborrat lògic de dispositius: es conserva l'historial de regles i accions programades
+ALTER TABLE devices ADD COLUMN deleted_at TIMESTAMPTZ;
+
+CREATE INDEX idx_devices_user_id_active ON devices(user_id) WHERE deleted_at IS NULL;
+
+-- Els dispositius esborrats ja no reben accions noves
+CREATE OR REPLACE VIEW rule_devices AS
+    SELECT r.id AS rule_id, r.device_id
+    FROM rules r
+    JOIN devices d ON d.id = r.device_id
+    WHERE d.deleted_at IS NULL
+    UNION ALL
+    SELECT r.id AS rule_id, m.device_id
+    FROM rules r
+    JOIN device_group_members m ON m.group_id = r.device_group_id
+    JOIN devices d ON d.id = m.device_id
+    WHERE d.deleted_at IS NULL;
@@ -0,0 +1,21 @@
+-- Versió del pla de cada dispositiu: augmenta cada cop que canvia alguna de les seves
+-- accions programades, perquè el controlador només torni a llegir el pla si ha canviat
+ALTER TABLE devices ADD COLUMN plan_version BIGINT DEFAULT 0 NOT NULL;
+
+CREATE OR REPLACE FUNCTION bump_device_plan_version()
+RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP <> 'INSERT' THEN
+        UPDATE devices SET plan_version = plan_version + 1 WHERE id = OLD.device_id;
+    END IF;
+    IF TG_OP <> 'DELETE' AND (TG_OP = 'INSERT' OR NEW.device_id <> OLD.device_id) THEN
+        UPDATE devices SET plan_version = plan_version + 1 WHERE id = NEW.device_id;
+    END IF;
+    RETURN NULL;
+END;
+$$ language 'plpgsql';
+
+CREATE TRIGGER bump_scheduled_actions_plan_version
+    AFTER INSERT OR UPDATE OR DELETE ON scheduled_actions
+    FOR EACH ROW
+    EXECUTE FUNCTION bump_device_plan_version();
@@ -0,0 +1,6 @@
+-- Temporada de la regla: fora de [active_from, active_until] no es generen accions
+-- (NULL = sense límit per aquell costat)
+ALTER TABLE rules
+    ADD COLUMN active_from DATE,
+    ADD COLUMN active_until DATE,
+    ADD CONSTRAINT rules_season_order CHECK (active_from IS NULL OR active_until IS NULL OR active_from <= active_until);
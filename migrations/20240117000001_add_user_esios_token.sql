@@ -0,0 +1,3 @@
+-- Token personal de ESIOS de l'usuari. Es desa xifrat (AES-256-GCM amb ENCRYPTION_KEY):
+-- nonce de 12 bytes seguit del text xifrat
+ALTER TABLE users ADD COLUMN esios_token BYTEA;
@@ -0,0 +1,3 @@
+-- Hora (local del servidor) a la qual es generen els schedules de demà de l'usuari.
+-- NULL = l'hora per defecte del servidor (SCHEDULE_GEN_HOUR:SCHEDULE_GEN_MINUTE)
+ALTER TABLE users ADD COLUMN schedule_gen_time TIME;
@@ -0,0 +1,5 @@
+-- Si una regla amb min_continuous_hours > 1 exigeix blocs continus ('required') o,
+-- quan no n'hi ha cap, accepta hores saltejades ('preferred')
+ALTER TABLE rules
+ADD COLUMN continuity_preference VARCHAR(20) DEFAULT 'required' NOT NULL
+    CHECK (continuity_preference IN ('required', 'preferred'));
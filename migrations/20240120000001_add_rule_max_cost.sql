@@ -0,0 +1,5 @@
+-- Potència nominal del dispositiu (W), per estimar el cost de les hores programades
+ALTER TABLE devices ADD COLUMN wattage_watts DOUBLE PRECISION CHECK (wattage_watts > 0);
+
+-- Cost màxim (€) de les hores d'una regla per dia (NULL = sense límit)
+ALTER TABLE rules ADD COLUMN max_cost_eur DOUBLE PRECISION CHECK (max_cost_eur > 0);
@@ -0,0 +1,2 @@
+-- Capacitats del dispositiu a Google Home (p. ex. "OnOff"), informades per l'app en sincronitzar
+ALTER TABLE devices ADD COLUMN capabilities TEXT[] NOT NULL DEFAULT '{}';
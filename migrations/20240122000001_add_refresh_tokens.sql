@@ -0,0 +1,13 @@
+-- Refresh tokens per renovar l'access token sense tornar a fer login amb Google.
+-- Només se'n desa el hash (SHA-256); cada ús el revoca i n'emet un de nou (rotació).
+CREATE TABLE refresh_tokens (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    expires_at TIMESTAMPTZ NOT NULL,
+    -- Quan s'ha fet servir (o s'ha revocat): tornar-lo a presentar és un reús
+    revoked_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX idx_refresh_tokens_user_id ON refresh_tokens(user_id);
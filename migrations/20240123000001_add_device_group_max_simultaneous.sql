@@ -0,0 +1,3 @@
+-- Màxim de dispositius d'un grup encesos alhora (ex: tres radiadors que no poden anar tots junts).
+-- NULL = sense límit
+ALTER TABLE device_groups ADD COLUMN max_simultaneous INTEGER CHECK (max_simultaneous >= 1);
@@ -0,0 +1,3 @@
+-- Versió de la regla per al control de concurrència optimista: cada actualització
+-- l'incrementa, i només s'aplica si el client envia la versió que havia llegit
+ALTER TABLE rules ADD COLUMN version BIGINT NOT NULL DEFAULT 1;
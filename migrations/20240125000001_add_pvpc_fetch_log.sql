@@ -0,0 +1,15 @@
+-- Estat de l'obtenció dels preus PVPC de cada dia: l'últim èxit (amb les hores rebudes)
+-- i l'últim error, per saber si els schedules s'han fet amb preus reals
+CREATE TABLE pvpc_fetch_log (
+    price_date DATE PRIMARY KEY,
+    fetched_at TIMESTAMPTZ,
+    hours_received INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    last_error_at TIMESTAMPTZ
+);
+
+-- Els dies que ja són a l'historial
+INSERT INTO pvpc_fetch_log (price_date, fetched_at, hours_received)
+SELECT price_date, MAX(fetched_at), COUNT(*)
+FROM cached_prices
+GROUP BY price_date;
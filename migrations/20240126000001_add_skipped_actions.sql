@@ -0,0 +1,9 @@
+-- Regles que no han programat cap hora per un dia, amb el motiu, per distingir
+-- "s'ha omès a propòsit" de "no s'ha generat". Una fila per regla i dia (l'últim motiu)
+CREATE TABLE skipped_actions (
+    rule_id UUID NOT NULL REFERENCES rules(id) ON DELETE CASCADE,
+    scheduled_date DATE NOT NULL,
+    reason VARCHAR(30) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW() NOT NULL,
+    PRIMARY KEY (rule_id, scheduled_date)
+);
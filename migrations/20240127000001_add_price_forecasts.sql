@@ -0,0 +1,7 @@
+-- Estimació dels preus d'un dia encara no publicat (vegeu services::forecast).
+-- Es recalcula si té més de 30 minuts
+CREATE TABLE price_forecasts (
+    price_date DATE PRIMARY KEY,
+    prices JSONB NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
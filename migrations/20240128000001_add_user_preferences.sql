@@ -0,0 +1,15 @@
+-- Preferències de cada usuari. Si un usuari no en té fila, s'apliquen els valors per
+-- defecte (els mateixos que les columnes): el comportament d'abans d'aquesta taula.
+CREATE TABLE user_preferences (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    -- NULL = la zona del servidor (GEO_ZONE)
+    preferred_geo_zone VARCHAR(20),
+    -- max_hours de les regles noves que no l'indiquen. NULL = el del servidor (DEFAULT_MAX_HOURS)
+    default_max_hours INTEGER,
+    -- days_of_week de les regles noves que no l'indiquen (bitmask, 127 = cada dia)
+    default_days_of_week INTEGER NOT NULL DEFAULT 127,
+    email_notifications BOOLEAN NOT NULL DEFAULT TRUE,
+    push_notification_token TEXT,
+    language VARCHAR(5) NOT NULL DEFAULT 'ca',
+    updated_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
@@ -0,0 +1,6 @@
+-- Mode de la regla: 'optimize' tria les hores més barates; 'fixed' programa sempre les
+-- mateixes hores (fixed_hours o, si és null, tota la finestra horària) sense mirar el preu
+ALTER TABLE rules
+ADD COLUMN mode VARCHAR(20) DEFAULT 'optimize' NOT NULL
+    CHECK (mode IN ('optimize', 'fixed')),
+ADD COLUMN fixed_hours INTEGER[];
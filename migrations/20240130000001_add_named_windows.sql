@@ -0,0 +1,17 @@
+-- Finestres horàries amb nom que l'usuari reutilitza entre regles (p. ex. "nit" = 22:00-08:00)
+CREATE TABLE named_windows (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(100) NOT NULL,
+    start_time TIME NOT NULL,
+    end_time TIME NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    UNIQUE (user_id, name)
+);
+
+-- Finestra de la qual surten time_window_start i time_window_end de la regla. Si la
+-- finestra s'esborra, la regla es queda les hores que tenia.
+ALTER TABLE rules
+ADD COLUMN named_window_id UUID REFERENCES named_windows(id) ON DELETE SET NULL;
+
+CREATE INDEX idx_rules_named_window ON rules(named_window_id) WHERE named_window_id IS NOT NULL;
@@ -0,0 +1,7 @@
+-- Accions que acaben l'endemà: l'última franja del dia (23:00-00:00) s'apaga a mitjanit
+ALTER TABLE scheduled_actions
+ADD COLUMN crosses_midnight BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Abans, algunes accions de l'última franja es desaven fins a les 23:59:59
+UPDATE scheduled_actions SET end_time = '00:00:00' WHERE end_time = '23:59:59';
+UPDATE scheduled_actions SET crosses_midnight = TRUE WHERE end_time <= start_time;
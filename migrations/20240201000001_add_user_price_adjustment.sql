@@ -0,0 +1,5 @@
+-- Impostos i càrrecs de cada usuari per passar del terme d'energia del PVPC al preu final:
+-- (preu + energy_surcharge_eur_kwh) * tax_multiplier. Per defecte, el preu de ESIOS tal qual.
+ALTER TABLE users
+ADD COLUMN energy_surcharge_eur_kwh DOUBLE PRECISION NOT NULL DEFAULT 0,
+ADD COLUMN tax_multiplier DOUBLE PRECISION NOT NULL DEFAULT 1 CHECK (tax_multiplier > 0);
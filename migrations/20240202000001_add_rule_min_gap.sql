@@ -0,0 +1,5 @@
+-- Hores mínimes sense programar entre dues hores saltejades d'una regla (p. ex. un compressor
+-- que no ha d'arrencar i aturar-se seguit). Null: sense separació. Incompatible amb blocs
+-- continus (min_continuous_hours > 1).
+ALTER TABLE rules
+ADD COLUMN min_gap_hours INTEGER CHECK (min_gap_hours BETWEEN 1 AND 23);
@@ -0,0 +1,12 @@
+-- Claus d'API per scripts i tasques programades (capçalera X-API-Key), alternativa al JWT.
+-- Només es desa el hash: la clau en clar es retorna una sola vegada, quan es crea.
+CREATE TABLE api_keys (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    key_hash TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    last_used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT NOW() NOT NULL
+);
+
+CREATE INDEX idx_api_keys_user_id ON api_keys(user_id);
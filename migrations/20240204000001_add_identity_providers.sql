@@ -0,0 +1,12 @@
+-- Inici de sessió amb diversos proveïdors d'identitat (Google, Apple). Cada usuari
+-- s'identifica pel proveïdor i pel `sub` del token ID; els usuaris existents són de Google.
+ALTER TABLE users RENAME COLUMN google_id TO provider_subject;
+
+ALTER TABLE users
+    ADD COLUMN provider VARCHAR(20) NOT NULL DEFAULT 'google' CHECK (provider IN ('google', 'apple')),
+    DROP CONSTRAINT users_google_id_key,
+    ADD CONSTRAINT users_provider_subject_unique UNIQUE (provider, provider_subject);
+
+ALTER TABLE users ALTER COLUMN provider DROP DEFAULT;
+
+DROP INDEX idx_users_google_id;
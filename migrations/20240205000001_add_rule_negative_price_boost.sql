@@ -0,0 +1,4 @@
+-- Dies amb preus negatius: les regles d'encendre amb aquest límit amplien max_hours fins a
+-- les hores negatives de la finestra, com a màxim aquestes hores. Null: sense ampliació.
+ALTER TABLE rules
+ADD COLUMN negative_price_boost INTEGER CHECK (negative_price_boost BETWEEN 1 AND 24);
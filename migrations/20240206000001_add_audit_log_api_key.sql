@@ -0,0 +1,3 @@
+-- Clau d'API amb què s'ha fet la crida (null si s'ha fet amb el JWT). Sense clau forana:
+-- l'id s'ha de poder consultar encara que la clau s'hagi revocat.
+ALTER TABLE audit_log ADD COLUMN api_key_id UUID;
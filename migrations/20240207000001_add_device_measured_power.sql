@@ -0,0 +1,7 @@
+-- Potència mitjana mesurada de cada dispositiu en les execucions correctes, per estimar el
+-- consum de les accions sense potència reportada
+CREATE VIEW device_measured_power AS
+    SELECT device_id, AVG(actual_power_watts) AS watts
+    FROM scheduled_actions
+    WHERE status LIKE 'executed%' AND actual_power_watts > 0
+    GROUP BY device_id;
@@ -0,0 +1,10 @@
+-- Potència total dels dispositius d'una regla (el dispositiu o els membres del grup), o NULL si
+-- algun no la té configurada. És una funció i no una vista perquè també s'ha de poder calcular
+-- per a les files que acaba d'inserir o actualitzar la mateixa sentència (RETURNING).
+CREATE OR REPLACE FUNCTION rule_wattage(rule_device_id UUID, rule_device_group_id UUID)
+RETURNS DOUBLE PRECISION AS $$
+    SELECT CASE WHEN bool_and(d.wattage_watts IS NOT NULL) THEN SUM(d.wattage_watts) END
+    FROM devices d
+    WHERE d.id = rule_device_id
+       OR d.id IN (SELECT m.device_id FROM device_group_members m WHERE m.group_id = rule_device_group_id)
+$$ LANGUAGE sql STABLE;
@@ -4,16 +4,56 @@ use uuid::Uuid;
 
 /// Preu d'una hora específica
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct HourlyPrice {
     pub hour: u8,
     pub price: f64,  // €/kWh
+    /// Preu de ESIOS abans dels impostos i càrrecs de l'usuari. Només hi és si `price` els inclou.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_price: Option<f64>,
+}
+
+/// Preu d'un quart d'hora
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct PricePoint {
+    /// Minut del dia en què comença el quart d'hora (0, 15, ..., 1425)
+    pub minute_of_day: u16,
+    pub price: f64,  // €/kWh
+    /// Preu de ESIOS abans dels impostos i càrrecs de l'usuari. Només hi és si `price` els inclou.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_price: Option<f64>,
 }
 
 /// Preus PVPC d'un dia complet
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
 pub struct DailyPrices {
     pub date: NaiveDate,
+    /// Preus horaris (els quarts d'hora promitjats per hora)
     pub prices: Vec<HourlyPrice>,
+    /// Preus quart-horaris, només si ESIOS els publica amb aquesta resolució
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quarter_hours: Vec<PricePoint>,
+}
+
+/// Franja horària en què una regla no pot programar accions.
+/// Si `start` > `end`, la franja creua mitjanit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct BlackoutWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+/// Pressupost d'hores dins d'una finestra, optimitzat independentment
+/// (ex: 2 hores entre 06:00 i 12:00). Si `start` > `end`, la finestra creua mitjanit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct SubBudget {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    pub hours: i32,
 }
 
 /// Tipus de dispositiu
@@ -26,10 +66,53 @@ pub enum DeviceType {
     Other(String),
 }
 
-/// Dies de la setmana com a bitmask
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+impl DeviceType {
+    /// Tipus a partir del text del client, sense distingir majúscules.
+    /// "other:<original>" (la forma normalitzada) es torna a llegir com a `Other`.
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.trim();
+        match raw.to_ascii_lowercase().as_str() {
+            "switch" => Self::Switch,
+            "thermostat" => Self::Thermostat,
+            "light" => Self::Light,
+            "other" => Self::Other(String::new()),
+            lower if lower.starts_with("other:") => Self::Other(raw["other:".len()..].to_string()),
+            _ => Self::Other(raw.to_string()),
+        }
+    }
+
+    /// Forma canònica: "switch", "thermostat", "light" o "other:<original>"
+    pub fn canonical(&self) -> String {
+        match self {
+            Self::Switch => "switch".to_string(),
+            Self::Thermostat => "thermostat".to_string(),
+            Self::Light => "light".to_string(),
+            Self::Other(original) if original.is_empty() => "other".to_string(),
+            Self::Other(original) => format!("other:{}", original),
+        }
+    }
+}
+
+/// Normalitza el tipus de dispositiu que envia el client (veure `DeviceType::canonical`)
+pub fn normalize_device_type(raw: &str) -> String {
+    DeviceType::parse(raw).canonical()
+}
+
+/// Dies de la setmana com a bitmask (bit 0 = dilluns, ..., bit 6 = diumenge)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DaysOfWeek(pub u8);
 
+/// Dies de la setmana en l'ordre dels bits de `DaysOfWeek`
+const WEEKDAYS_IN_ORDER: [chrono::Weekday; 7] = [
+    chrono::Weekday::Mon,
+    chrono::Weekday::Tue,
+    chrono::Weekday::Wed,
+    chrono::Weekday::Thu,
+    chrono::Weekday::Fri,
+    chrono::Weekday::Sat,
+    chrono::Weekday::Sun,
+];
+
 impl DaysOfWeek {
     pub const MONDAY: u8 = 1;
     pub const TUESDAY: u8 = 2;
@@ -50,17 +133,41 @@ impl DaysOfWeek {
         Self(Self::ALL_DAYS)
     }
 
+    /// Bit d'un dia de la setmana
+    fn bit(day: chrono::Weekday) -> u8 {
+        1 << day.num_days_from_monday()
+    }
+
     pub fn includes(&self, day: chrono::Weekday) -> bool {
-        let bit = match day {
-            chrono::Weekday::Mon => Self::MONDAY,
-            chrono::Weekday::Tue => Self::TUESDAY,
-            chrono::Weekday::Wed => Self::WEDNESDAY,
-            chrono::Weekday::Thu => Self::THURSDAY,
-            chrono::Weekday::Fri => Self::FRIDAY,
-            chrono::Weekday::Sat => Self::SATURDAY,
-            chrono::Weekday::Sun => Self::SUNDAY,
-        };
-        (self.0 & bit) != 0
+        (self.0 & Self::bit(day)) != 0
+    }
+
+    /// Dies actius, de dilluns a diumenge
+    pub fn days(&self) -> impl Iterator<Item = chrono::Weekday> {
+        let days = *self;
+        WEEKDAYS_IN_ORDER.into_iter().filter(move |day| days.includes(*day))
+    }
+
+    /// Nombre de dies actius
+    pub fn count(&self) -> u8 {
+        (self.0 & Self::ALL_DAYS).count_ones() as u8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count() == 0
+    }
+
+    pub fn union(&self, other: DaysOfWeek) -> DaysOfWeek {
+        Self(self.0 | other.0)
+    }
+
+    pub fn intersection(&self, other: DaysOfWeek) -> DaysOfWeek {
+        Self(self.0 & other.0)
+    }
+
+    /// Dies que no són actius
+    pub fn complement(&self) -> DaysOfWeek {
+        Self(!self.0 & Self::ALL_DAYS)
     }
 }
 
@@ -70,6 +177,55 @@ impl Default for DaysOfWeek {
     }
 }
 
+/// Dies actius separats per comes ("Mon,Wed,Fri"); buit si no n'hi ha cap
+impl std::fmt::Display for DaysOfWeek {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, day) in self.days().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", day)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error en llegir uns `DaysOfWeek`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseDaysOfWeekError(String);
+
+impl std::fmt::Display for ParseDaysOfWeekError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid days of week: {}", self.0)
+    }
+}
+
+impl std::error::Error for ParseDaysOfWeekError {}
+
+/// Llegeix el bitmask com a enter ("31") o com a noms de dia separats per comes
+/// ("Mon,Wed,Fri", "monday, friday"). Una cadena buida no té cap dia.
+impl std::str::FromStr for DaysOfWeek {
+    type Err = ParseDaysOfWeekError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(mask) = s.parse::<u8>() {
+            if mask > Self::ALL_DAYS {
+                return Err(ParseDaysOfWeekError(s.to_string()));
+            }
+            return Ok(Self(mask));
+        }
+
+        s.split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .try_fold(Self(0), |days, name| {
+                let day: chrono::Weekday = name.parse().map_err(|_| ParseDaysOfWeekError(name.to_string()))?;
+                Ok(Self(days.0 | Self::bit(day)))
+            })
+    }
+}
+
 /// Estat d'una acció programada
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -83,13 +239,26 @@ pub enum ActionStatus {
 /// DTO per crear una regla
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CreateRuleRequest {
-    pub device_id: Uuid,
+    pub device_id: Option<Uuid>,
+    pub device_group_id: Option<Uuid>,  // alternativa a device_id: una acció per cada dispositiu del grup
     pub name: String,
-    pub max_hours: i32,
+    pub max_hours: Option<i32>,  // per defecte, DEFAULT_MAX_HOURS del servidor
     pub time_window_start: Option<NaiveTime>,
     pub time_window_end: Option<NaiveTime>,
     pub min_continuous_hours: Option<i32>,
+    pub continuity_preference: Option<String>,  // "required" o "preferred" (hores saltejades si no hi ha blocs)
+    pub max_cost_eur: Option<f64>,  // cost màxim diari (€), amb la potència dels dispositius
     pub days_of_week: Option<u8>,
+    pub baseline_days: Option<i32>,
+    pub baseline_margin_pct: Option<f64>,
+    pub action_type: Option<String>,  // "turn_on" o "turn_off"
+    pub blackout_windows: Option<Vec<BlackoutWindow>>,
+    pub sub_budgets: Option<Vec<SubBudget>>,
+    pub priority: Option<i32>,  // 1 = màxima
+    pub load_profile: Option<Vec<f64>>,  // 24 pesos relatius de consum (un per hora)
+    pub active_from: Option<NaiveDate>,  // temporada de la regla (None = sense límit)
+    pub active_until: Option<NaiveDate>,
+    pub generate_now: Option<bool>,  // false = esperar a la generació diària
 }
 
 /// DTO per actualitzar una regla
@@ -100,8 +269,26 @@ pub struct UpdateRuleRequest {
     pub time_window_start: Option<NaiveTime>,
     pub time_window_end: Option<NaiveTime>,
     pub min_continuous_hours: Option<i32>,
+    pub continuity_preference: Option<String>,  // "required" o "preferred"
+    pub max_cost_eur: Option<f64>,
     pub days_of_week: Option<u8>,
     pub is_enabled: Option<bool>,
+    pub baseline_days: Option<i32>,
+    pub baseline_margin_pct: Option<f64>,
+    pub action_type: Option<String>,  // "turn_on" o "turn_off"
+    pub blackout_windows: Option<Vec<BlackoutWindow>>,
+    pub sub_budgets: Option<Vec<SubBudget>>,
+    pub priority: Option<i32>,  // 1 = màxima
+    pub load_profile: Option<Vec<f64>>,  // [] elimina el perfil
+    pub active_from: Option<NaiveDate>,
+    pub active_until: Option<NaiveDate>,
+}
+
+/// DTO per activar o desactivar diverses regles alhora
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchUpdateRulesRequest {
+    pub rule_ids: Vec<Uuid>,
+    pub is_enabled: bool,
 }
 
 /// DTO per sincronitzar dispositius des de l'app Android
@@ -111,6 +298,8 @@ pub struct SyncDeviceRequest {
     pub name: String,
     pub device_type: Option<String>,
     pub room: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
 }
 
 /// Acció programada per enviar a l'app Android
@@ -124,3 +313,72 @@ pub struct ScheduledActionResponse {
     pub scheduled_time: NaiveTime,
     pub status: ActionStatus,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_device_type() {
+        assert_eq!(normalize_device_type("SWITCH"), "switch");
+        assert_eq!(normalize_device_type("Thermostat"), "thermostat");
+        assert_eq!(normalize_device_type(" light "), "light");
+        assert_eq!(normalize_device_type("other"), "other");
+        assert_eq!(normalize_device_type("OUTLET"), "other:OUTLET");
+        // Ja normalitzat: no canvia
+        assert_eq!(normalize_device_type("other:OUTLET"), "other:OUTLET");
+    }
+
+    #[test]
+    fn test_days_of_week_iterates_active_days() {
+        use chrono::Weekday::*;
+
+        let days = DaysOfWeek::new(DaysOfWeek::MONDAY | DaysOfWeek::WEDNESDAY | DaysOfWeek::SUNDAY);
+        assert_eq!(days.days().collect::<Vec<_>>(), [Mon, Wed, Sun]);
+        assert_eq!(days.count(), 3);
+        assert!(days.includes(Sun));
+        assert!(!days.includes(Tue));
+
+        assert_eq!(DaysOfWeek::all().days().count(), 7);
+        assert_eq!(DaysOfWeek::new(0).days().count(), 0);
+        assert!(DaysOfWeek::new(0).is_empty());
+        // El bit 7 no és cap dia
+        assert!(DaysOfWeek::new(128).is_empty());
+    }
+
+    #[test]
+    fn test_days_of_week_set_operations() {
+        let weekdays = DaysOfWeek::new(DaysOfWeek::WEEKDAYS);
+        let weekend = DaysOfWeek::new(DaysOfWeek::WEEKEND);
+        let fri_sat = DaysOfWeek::new(DaysOfWeek::FRIDAY | DaysOfWeek::SATURDAY);
+
+        assert_eq!(weekdays.union(weekend), DaysOfWeek::all());
+        assert_eq!(weekdays.intersection(weekend), DaysOfWeek::new(0));
+        assert_eq!(weekdays.intersection(fri_sat), DaysOfWeek::new(DaysOfWeek::FRIDAY));
+        assert_eq!(weekdays.complement(), weekend);
+        assert_eq!(DaysOfWeek::all().complement(), DaysOfWeek::new(0));
+        assert_eq!(DaysOfWeek::new(0).complement(), DaysOfWeek::all());
+    }
+
+    #[test]
+    fn test_days_of_week_display_and_parse() {
+        let days = DaysOfWeek::new(DaysOfWeek::MONDAY | DaysOfWeek::WEDNESDAY | DaysOfWeek::FRIDAY);
+        assert_eq!(days.to_string(), "Mon,Wed,Fri");
+        assert_eq!(DaysOfWeek::new(0).to_string(), "");
+        assert_eq!(DaysOfWeek::all().to_string(), "Mon,Tue,Wed,Thu,Fri,Sat,Sun");
+
+        assert_eq!("Mon,Wed,Fri".parse::<DaysOfWeek>(), Ok(days));
+        assert_eq!(" friday, mon ,WED".parse::<DaysOfWeek>(), Ok(days));
+        assert_eq!("21".parse::<DaysOfWeek>(), Ok(days));
+        assert_eq!("127".parse::<DaysOfWeek>(), Ok(DaysOfWeek::all()));
+        assert_eq!("".parse::<DaysOfWeek>(), Ok(DaysOfWeek::new(0)));
+        assert!("128".parse::<DaysOfWeek>().is_err());
+        assert!("Mon,Funday".parse::<DaysOfWeek>().is_err());
+
+        // Anada i tornada per a tots els bitmasks
+        for mask in 0..=DaysOfWeek::ALL_DAYS {
+            let days = DaysOfWeek::new(mask);
+            assert_eq!(days.to_string().parse::<DaysOfWeek>(), Ok(days));
+        }
+    }
+}